@@ -0,0 +1,2477 @@
+//! The remote editor behind `--connect`. It shows a file picker until a
+//! file is open, draws the frames the server sends and turns key presses
+//! into protocol messages. When the connection drops it keeps trying to
+//! reconnect and then takes its session over again.
+
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
+use ghostwriter_core::{Backoff, tls};
+use ghostwriter_proto::{
+    Ack, AddCursor, Attach, Auth, Bookmark, BookmarkList, BufferList, ConnectionState, Copy,
+    Delete, Dialog, DialogButton, DialogResult, DirList, Direction, Envelope, ErrorCode, ErrorMsg,
+    Frame, FrameDiff, GotoLine, Granularity, Insert, Jump, MessageType, Move, PickerAction, Range,
+    Replace, RequestFrame, Role, Scroll, ScrollUnit, Search, SearchDir, SearchResultChunk, Select,
+    SelectMode, SessionList, Status, Tab, decode, encode, peek_type,
+};
+use ratatui::backend::{Backend, CrosstermBackend};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+use crate::bookmarks::{BookmarkChoice, BookmarkView};
+use crate::clipboard::{self, Mux};
+use crate::dialog::DialogView;
+use crate::history::Ring;
+use crate::keymap::{Command, Keymap};
+use crate::latency::{Latency, Rtt};
+use crate::picker::{self, PickerChoice, PickerView};
+use crate::remote::{AuthError, BATCH_TICK, WsClient};
+use crate::search::{SearchChoice, SearchView};
+use crate::secret;
+use crate::ssh::{SshTarget, Tunnel};
+use crate::status::StatusLayout;
+use crate::tui::Tui;
+
+/// Wait before the first reconnection attempt; it doubles up to
+/// [`MAX_RECONNECT_DELAY`] while the server stays away.
+pub const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Longest wait between reconnection attempts.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Wait before asking again for a session the server still thinks is
+/// attached to the dropped connection.
+const REATTACH_RETRY: Duration = Duration::from_millis(500);
+
+/// Stop holding viewport requests back for a frame that has not come
+/// after this long.
+const VIEWPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `--connect auto` listens for servers.
+#[cfg(feature = "mdns")]
+const DISCOVERY_WAIT: Duration = Duration::from_secs(2);
+
+/// Lines one notch of the mouse wheel scrolls.
+const WHEEL_LINES: i64 = 3;
+
+/// Id of the local dialog showing server errors; it is not answered.
+const ERROR_DIALOG: &str = "error";
+
+/// Id of the local dialogs asking for the details of a picker operation.
+const PICKER_DIALOG: &str = "picker";
+
+/// Id of the local dialog asking what to find in the open file.
+const FIND_DIALOG: &str = "find";
+
+/// Ids of the local dialogs asking what to replace in the open file, and
+/// then with what.
+const REPLACE_DIALOG: &str = "replace";
+const REPLACE_WITH_DIALOG: &str = "replace-with";
+
+/// Where and how to connect, kept for reconnecting.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// `ws://`, `wss://`, `quic://` or `ssh://` URL, or `auto` to use the
+    /// first server advertised on the local network.
+    pub url: String,
+    /// Follow the session another client is editing.
+    pub follow: bool,
+    /// Detached session to take over instead of opening a file.
+    pub attach: Option<u64>,
+    pub auth: Option<Auth>,
+    /// PEM certificates to trust instead of the system's.
+    pub ca: Option<PathBuf>,
+    /// PEM certificate and key presented for mutual TLS.
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// Keys bound to saving, quitting and the other commands.
+    pub keymap: Keymap,
+    /// Templates of the status bar.
+    pub status_layout: StatusLayout,
+    /// Most times a second to repaint the screen, for slow links.
+    pub render_budget: Option<u32>,
+    /// Place the cursor, select and pick files with the mouse.
+    pub mouse: bool,
+}
+
+/// How to set up the terminal once logged in.
+#[derive(Debug, Clone)]
+struct Screen {
+    status_layout: StatusLayout,
+    render_budget: Option<u32>,
+    mouse: bool,
+}
+
+/// Connect as `options` say and edit until the user quits.
+pub async fn run(options: ConnectOptions) -> Result<()> {
+    let url = resolve_url(&options.url).await?;
+    // Kept open until the editor quits.
+    let tunnel = if url.starts_with("ssh://") {
+        Some(Tunnel::open(&SshTarget::parse(&url)?).await?)
+    } else {
+        None
+    };
+    let url = tunnel.as_ref().map_or(url, |tunnel| tunnel.url.clone());
+    if url.starts_with("quic://") {
+        if !cfg!(feature = "quic") {
+            bail!("QUIC transport is not available; rebuild with `--features quic`");
+        }
+        if options.follow {
+            bail!("--follow is not supported over QUIC");
+        }
+    }
+    let role = if options.follow {
+        Role::Follower
+    } else {
+        Role::Editor
+    };
+    let size = crossterm::terminal::size()?;
+    let app = App {
+        keymap: options.keymap,
+        clipboard: clipboard::detect_terminal(),
+        searches: Ring::load_named("searches"),
+        replacements: Ring::load_named("replacements"),
+        ..App::new(role, options.attach)
+    };
+    let auth = options.auth;
+    let screen = Screen {
+        status_layout: options.status_layout,
+        render_budget: options.render_budget,
+        mouse: options.mouse,
+    };
+    if url.starts_with("quic://") {
+        #[cfg(feature = "quic")]
+        {
+            let ca = (options.ca).ok_or_else(|| anyhow!("quic:// URLs need --tls-ca"))?;
+            let roots = tls::load_certs(&ca)?;
+            let connect = async |(cols, rows): (u16, u16), auth: Option<Auth>| {
+                let rows = text_rows(rows);
+                WsClient::connect_quic(&url, cols, rows, auth, &roots).await
+            };
+            return edit(app, size, auth, screen, connect).await;
+        }
+    }
+    let config = match (&options.ca, &options.identity) {
+        (None, None) => None,
+        (ca, identity) => {
+            let roots = ca.as_deref().map(tls::load_certs).transpose()?;
+            let identity = (identity.as_ref())
+                .map(|(cert, key)| tls::ClientIdentity::load(cert, key))
+                .transpose()?;
+            Some(tls::client_config(roots.as_deref(), identity)?)
+        }
+    };
+    let connect = async |(cols, rows): (u16, u16), auth: Option<Auth>| {
+        let rows = text_rows(rows);
+        WsClient::connect_as(&url, cols, rows, auth, role, config.clone()).await
+    };
+    edit(app, size, auth, screen, connect).await
+}
+
+/// Log in over a connection made by `connect`, then edit on the terminal
+/// until the user quits, reconnecting with the credentials that worked.
+async fn edit<S>(
+    mut app: App,
+    size: (u16, u16),
+    auth: Option<Auth>,
+    screen: Screen,
+    connect: impl AsyncFn((u16, u16), Option<Auth>) -> Result<WsClient<S>>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, auth) = login(size, auth, &connect).await?;
+    let mut tui = Tui::new(CrosstermBackend::new(io::stdout()))?;
+    tui.set_status_layout(screen.status_layout);
+    tui.set_render_budget(screen.render_budget);
+    if screen.mouse {
+        tui.capture_mouse()?;
+    }
+    let mut events = read_events();
+    let reconnect = async |size| connect(size, auth.clone()).await;
+    drive(&mut app, &mut tui, &mut events, size, client, reconnect).await
+}
+
+/// First connection made by `connect`, asking for the secret at a hidden
+/// prompt for as long as the server wants one that was not given, or
+/// refuses the one that was. Returns it with the credentials it took.
+async fn login<S>(
+    size: (u16, u16),
+    mut auth: Option<Auth>,
+    connect: &impl AsyncFn((u16, u16), Option<Auth>) -> Result<WsClient<S>>,
+) -> Result<(WsClient<S>, Option<Auth>)> {
+    loop {
+        let err = match connect(size, auth.clone()).await {
+            Ok(client) => return Ok((client, auth)),
+            Err(err) => err,
+        };
+        let label = match err.downcast_ref::<AuthError>() {
+            Some(AuthError::Required) => "Secret: ",
+            Some(AuthError::Refused) => "Wrong secret, try again: ",
+            None => return Err(err),
+        };
+        let secret = secret::prompt(label)?;
+        let token_id = auth.and_then(|auth| auth.token_id);
+        auth = Some(Auth { secret, token_id });
+    }
+}
+
+/// `url`, or the first server on the local network for `auto`.
+async fn resolve_url(url: &str) -> Result<String> {
+    if url != "auto" {
+        return Ok(url.to_string());
+    }
+    #[cfg(feature = "mdns")]
+    {
+        crate::remote::discover(DISCOVERY_WAIT).await
+    }
+    #[cfg(not(feature = "mdns"))]
+    {
+        Err(anyhow!(
+            "server discovery is not available; rebuild with `--features mdns`"
+        ))
+    }
+}
+
+/// Rows left for text below a terminal `rows` high, after the status line.
+fn text_rows(rows: u16) -> u16 {
+    rows.saturating_sub(1).max(1)
+}
+
+/// Terminal events, read on a thread of their own since crossterm blocks.
+fn read_events() -> mpsc::UnboundedReceiver<Event> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = crossterm::event::read() {
+            if tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Edit over `client`, making a new connection with `connect` whenever
+/// the current one drops, until the user quits.
+async fn drive<B, S>(
+    app: &mut App,
+    tui: &mut Tui<B>,
+    events: &mut mpsc::UnboundedReceiver<Event>,
+    mut size: (u16, u16),
+    client: WsClient<S>,
+    mut connect: impl AsyncFnMut((u16, u16)) -> Result<WsClient<S>>,
+) -> Result<()>
+where
+    B: Backend,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut client = Some(client);
+    app.connected()?;
+    let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    let mut tick = tokio::time::interval(BATCH_TICK);
+    while !app.quit() {
+        let Some(conn) = client.as_mut() else {
+            tokio::select! {
+                event = events.recv() => {
+                    let Some(event) = event else { break };
+                    if let Event::Resize(cols, rows) = event {
+                        size = (cols, rows);
+                    }
+                    app.handle_event(event, tui)?;
+                }
+                _ = tokio::time::sleep(backoff.delay()) => match connect(size).await {
+                    Ok(conn) => {
+                        client = Some(conn);
+                        backoff.reset();
+                        app.connected()?;
+                    }
+                    Err(_) => backoff.failed(),
+                },
+            }
+            continue;
+        };
+        for data in app.take_outbox() {
+            conn.queue_encoded(data);
+        }
+        let mut lost = false;
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                if let Event::Resize(cols, rows) = event {
+                    size = (cols, rows);
+                    lost = conn.resize(cols, text_rows(rows)).await.is_err();
+                }
+                app.handle_event(event, tui)?;
+            }
+            msg = conn.recv() => match msg {
+                Ok(Some(data)) => app.handle_message(&data, tui)?,
+                _ => lost = true,
+            },
+            _ = tick.tick() => {
+                app.tick(tui)?;
+                lost = conn.flush().await.is_err();
+            }
+        }
+        if lost {
+            client = None;
+            app.disconnected();
+            tui.set_rtt(None);
+            tui.set_echo(String::new());
+            tui.set_connection(ConnectionState::Reconnecting)?;
+        }
+    }
+    if let Some(mut conn) = client {
+        for data in app.take_outbox() {
+            conn.queue_encoded(data);
+        }
+        let _ = conn.close().await;
+    }
+    Ok(())
+}
+
+/// Insert drawn at the cursor until a frame shows it.
+struct Echo {
+    seq: u64,
+    text: String,
+    /// Version the server's `Ack` gave the document after the insert.
+    doc_v: Option<u64>,
+}
+
+/// State of the remote editor between messages.
+pub struct App {
+    role: Role,
+    keymap: Keymap,
+    /// How to reach the user's clipboard, if the terminal has one.
+    clipboard: Option<Mux>,
+    /// Session to take over on the first connection.
+    attach: Option<u64>,
+    /// Workspace-relative path of the open file.
+    path: Option<String>,
+    /// Selection of the last frame; `None` while nothing is open or the
+    /// connection is down.
+    selection: Option<Range>,
+    /// Set while a delete key waits for the frame showing the character it
+    /// selected.
+    delete_pending: bool,
+    /// Version of the last frame, which edits are based on.
+    doc_v: Option<u64>,
+    /// Version of the last frame drawn; an older one means frames arrived
+    /// out of order. Forgotten when asking for a full frame.
+    frame_v: Option<u64>,
+    /// Text of inserts not yet acknowledged, by `seq`, to type again if
+    /// the server refuses them.
+    typed: Vec<(u64, String)>,
+    /// Inserts drawn at the cursor before the server shows them.
+    echo: Vec<Echo>,
+    /// Refused text to type once a fresh frame shows where the cursor is
+    /// now; also collects what is typed until then.
+    retype: Option<String>,
+    picker: Option<PickerView>,
+    /// Workspace search panel, shown over the picker.
+    search: Option<SearchView>,
+    /// Bookmarks the server listed, shown until one is picked.
+    bookmarks: Option<BookmarkView>,
+    dialog: Option<DialogView>,
+    /// Picker operation the open local dialog asks about.
+    asking: Option<PickerChoice>,
+    /// Last text searched for in the open file, for finding it again.
+    query: String,
+    /// Whether the last replace took `query` as a regex.
+    regex: bool,
+    /// Whether finding and replacing skip matches inside longer words, and
+    /// those outside the selection; toggled in the find and replace
+    /// dialogs.
+    whole_word: bool,
+    in_selection: bool,
+    /// Last text matches were replaced with.
+    replacement: String,
+    /// Recent queries and replacements, offered in the find and replace
+    /// dialogs.
+    searches: Ring,
+    replacements: Ring,
+    /// Stepping through the matches of `query`, asking whether to replace
+    /// each with `replacement`.
+    confirming: bool,
+    /// Set between reconnecting and taking the session over again; holds
+    /// when to ask for the session list again, if the server did not yet
+    /// notice the old connection dropped.
+    reattaching: Option<Option<Instant>>,
+    /// Set from a dropped connection until the server shows the session
+    /// again; keys other than quitting are ignored meanwhile.
+    offline: bool,
+    /// When the `Scroll` or `RequestFrame` still waiting for a frame was
+    /// sent; more are held back until one arrives, so holding PageDown
+    /// does not flood the link.
+    viewport_sent: Option<Instant>,
+    /// Pages scrolled while a viewport request was in flight, to send as
+    /// one `Scroll`.
+    scroll: i64,
+    /// Lines scrolled by the mouse wheel meanwhile.
+    wheel: i64,
+    /// Columns to scroll sideways, sent along with `scroll`.
+    hscroll: i64,
+    /// First column of the frame `hscroll` was asked from; frames still
+    /// starting there do not ask again.
+    hscroll_from: Option<u16>,
+    /// Reason of a `RequestFrame` held back meanwhile.
+    frame_due: Option<String>,
+    latency: Latency,
+    /// Round trip last shown in the status line.
+    rtt: Option<Rtt>,
+    seq: u64,
+    outbox: Vec<Vec<u8>>,
+    quit: bool,
+}
+
+impl App {
+    /// Editor or follower state, taking over session `attach` once
+    /// connected if given.
+    pub fn new(role: Role, attach: Option<u64>) -> Self {
+        Self {
+            role,
+            keymap: Keymap::default(),
+            clipboard: None,
+            attach,
+            path: None,
+            selection: None,
+            delete_pending: false,
+            doc_v: None,
+            frame_v: None,
+            typed: Vec::new(),
+            echo: Vec::new(),
+            retype: None,
+            picker: None,
+            search: None,
+            bookmarks: None,
+            dialog: None,
+            asking: None,
+            query: String::new(),
+            regex: false,
+            whole_word: false,
+            in_selection: false,
+            replacement: String::new(),
+            searches: Ring::default(),
+            replacements: Ring::default(),
+            confirming: false,
+            reattaching: None,
+            offline: false,
+            viewport_sent: None,
+            scroll: 0,
+            wheel: 0,
+            hscroll: 0,
+            hscroll_from: None,
+            frame_due: None,
+            latency: Latency::default(),
+            rtt: None,
+            seq: 0,
+            outbox: Vec::new(),
+            quit: false,
+        }
+    }
+
+    /// Whether the user asked to leave.
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Workspace-relative path of the open file, if any.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Messages to send, encoded as envelopes.
+    pub fn take_outbox(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Start on a new connection: take over a session, reopen the file
+    /// after a reconnect, or list the workspace root in a picker.
+    pub fn connected(&mut self) -> Result<()> {
+        if self.role == Role::Follower {
+            return Ok(());
+        }
+        if let Some(id) = self.attach.take() {
+            self.send(MessageType::Attach, Attach { id })?;
+            // The reply names the file, for reattaching later.
+            return self.send(MessageType::ListBuffers, ());
+        }
+        if self.path.is_some() {
+            self.reattaching = Some(None);
+            return self.send(MessageType::ListSessions, ());
+        }
+        self.send(
+            MessageType::PickerAction,
+            PickerAction::Expand {
+                path: String::new(),
+            },
+        )
+    }
+
+    /// Forget what the dropped connection was doing; the editor is read
+    /// only until the server shows the session again.
+    pub fn disconnected(&mut self) {
+        self.offline = true;
+        self.selection = None;
+        self.delete_pending = false;
+        self.typed.clear();
+        self.echo.clear();
+        self.retype = None;
+        self.reattaching = None;
+        self.frame_v = None;
+        self.viewport_sent = None;
+        self.scroll = 0;
+        self.wheel = 0;
+        self.hscroll = 0;
+        self.hscroll_from = None;
+        self.frame_due = None;
+        self.latency = Latency::default();
+        self.rtt = None;
+        self.outbox.clear();
+    }
+
+    /// Run timers; call once per [`BATCH_TICK`].
+    pub fn tick<B: Backend>(&mut self, tui: &mut Tui<B>) -> Result<()> {
+        let now = Instant::now();
+        if let Some(Some(at)) = self.reattaching
+            && at <= now
+        {
+            self.reattaching = Some(None);
+            self.send(MessageType::ListSessions, ())?;
+        }
+        if self
+            .viewport_sent
+            .is_some_and(|at| now.duration_since(at) >= VIEWPORT_TIMEOUT)
+        {
+            self.viewport_sent = None;
+            self.send_viewport()?;
+        }
+        if self.latency.ping(now) {
+            self.send(MessageType::Ping, ())?;
+        }
+        self.show_rtt(now, tui)?;
+        tui.flush()
+    }
+
+    /// Stop drawing the echo of inserts a frame at `doc_v` shows.
+    fn settle_echo<B: Backend>(&mut self, doc_v: u64, tui: &mut Tui<B>) {
+        self.echo.retain(|e| e.doc_v.is_none_or(|v| v > doc_v));
+        tui.set_echo(self.echo.iter().map(|e| e.text.as_str()).collect());
+    }
+
+    /// Draw the inserts the server has not shown yet.
+    fn show_echo<B: Backend>(&mut self, tui: &mut Tui<B>) -> Result<()> {
+        self.settle_echo(0, tui);
+        self.draw_modal(tui)
+    }
+
+    /// Make the editor writable again once the server shows the session
+    /// after a reconnect.
+    fn resumed<B: Backend>(&mut self, tui: &mut Tui<B>) -> Result<()> {
+        if std::mem::take(&mut self.offline) {
+            tui.set_connection(ConnectionState::Connected)?;
+        }
+        Ok(())
+    }
+
+    /// Redraw the status line if the round trip shown in it changed.
+    fn show_rtt<B: Backend>(&mut self, now: Instant, tui: &mut Tui<B>) -> Result<()> {
+        let rtt = self.latency.rtt(now);
+        let shown =
+            |rtt: Option<Rtt>| rtt.map(|r| (r.rtt.as_millis(), r.jitter.as_millis(), r.degraded));
+        if shown(rtt) == shown(self.rtt) {
+            return Ok(());
+        }
+        self.rtt = rtt;
+        tui.set_rtt(rtt);
+        self.draw_modal(tui)
+    }
+
+    /// Handle a terminal event. Resizes are left to the caller, which
+    /// tells the server.
+    pub fn handle_event<B: Backend>(&mut self, event: Event, tui: &mut Tui<B>) -> Result<()> {
+        match event {
+            Event::Key(ev) if ev.kind == KeyEventKind::Press => self.handle_key(ev, tui),
+            Event::Mouse(ev) => self.handle_mouse(ev, tui),
+            Event::Paste(text) => self.paste(text, tui),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle one message from the server, drawing what it shows. Fails on
+    /// errors that reconnecting cannot fix.
+    pub fn handle_message<B: Backend>(&mut self, data: &[u8], tui: &mut Tui<B>) -> Result<()> {
+        match peek_type(data)? {
+            MessageType::Frame => {
+                let frame = decode::<Frame>(data)?.data;
+                self.resumed(tui)?;
+                self.viewport_sent = None;
+                self.send_viewport()?;
+                if self.is_stale(frame.doc_v) {
+                    return self.desync("frame older than the last one", frame.doc_v);
+                }
+                if !frame.checksum_matches() {
+                    return self.desync("frame does not match its checksum", frame.doc_v);
+                }
+                self.frame_v = Some(frame.doc_v);
+                self.update_status(frame.doc_v, frame.status.as_ref())?;
+                self.settle_echo(frame.doc_v, tui);
+                tui.draw(&frame)?;
+                self.follow_cursor(tui)?;
+                self.draw_overlay(tui)?;
+            }
+            MessageType::FrameDiff => {
+                let diff = decode::<FrameDiff>(data)?.data;
+                self.resumed(tui)?;
+                self.viewport_sent = None;
+                self.send_viewport()?;
+                if self.is_stale(diff.doc_v) {
+                    return self.desync("frame diff older than the last frame", diff.doc_v);
+                }
+                self.update_status(diff.doc_v, diff.status.as_ref())?;
+                self.settle_echo(diff.doc_v, tui);
+                if tui.draw_diff(&diff)? {
+                    self.frame_v = Some(diff.doc_v);
+                    self.follow_cursor(tui)?;
+                    self.draw_overlay(tui)?;
+                } else {
+                    self.desync("frame diff does not apply to the last frame", diff.doc_v)?;
+                }
+            }
+            MessageType::DirList => {
+                let list = decode::<DirList>(data)?.data;
+                self.resumed(tui)?;
+                // Search results are not shown in the picker tree.
+                if list.query.is_none() {
+                    (self
+                        .picker
+                        .get_or_insert_with(|| PickerView::new(self.path.clone())))
+                    .update(list);
+                    self.draw_modal(tui)?;
+                }
+            }
+            MessageType::Copy => {
+                let copy = decode::<Copy>(data)?.data;
+                if let Some(mux) = self.clipboard
+                    && let Err(err) = tui.copy(&copy.text, mux)
+                {
+                    let err = ErrorMsg::new(ErrorCode::TooLarge, err.to_string());
+                    self.show_error(err, tui)?;
+                }
+            }
+            MessageType::SearchResults => {
+                let chunk = decode::<SearchResultChunk>(data)?.data;
+                if let Some(view) = &mut self.search {
+                    view.add(chunk);
+                    self.draw_modal(tui)?;
+                }
+            }
+            MessageType::SessionList if self.reattaching.is_some() => {
+                self.reattach(decode::<SessionList>(data)?.data)?;
+            }
+            MessageType::BufferList => {
+                let list = decode::<BufferList>(data)?.data;
+                self.path = list.paths.get(list.active as usize).cloned();
+            }
+            MessageType::BookmarkList => {
+                let list = decode::<BookmarkList>(data)?.data;
+                self.bookmarks = Some(BookmarkView::new(list));
+                self.draw_modal(tui)?;
+            }
+            MessageType::Pong => {
+                let now = Instant::now();
+                self.latency.pong(now);
+                self.show_rtt(now, tui)?;
+            }
+            MessageType::Ack => {
+                let ack = decode::<Ack>(data)?.data;
+                self.typed.retain(|(seq, _)| *seq > ack.seq);
+                for echo in self.echo.iter_mut().filter(|e| e.seq <= ack.seq) {
+                    echo.doc_v.get_or_insert(ack.doc_v);
+                }
+            }
+            MessageType::Error => {
+                let err = decode::<ErrorMsg>(data)?.data;
+                match err.seq {
+                    Some(seq) if err.code == ErrorCode::Conflict => self.refused(seq, tui)?,
+                    _ => self.show_error(err, tui)?,
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_key<B: Backend>(&mut self, ev: KeyEvent, tui: &mut Tui<B>) -> Result<()> {
+        if self.offline {
+            // Nothing typed now could reach the server.
+            self.quit = self.keymap.command(ev) == Some(Command::Quit);
+            return Ok(());
+        }
+        if self.toggle_search_option(ev) {
+            return self.draw_modal(tui);
+        }
+        if let Some(view) = &mut self.dialog {
+            let Some(result) = view.handle_key(ev) else {
+                return tui.draw_dialog(view);
+            };
+            self.dialog = None;
+            match result.id.as_str() {
+                ERROR_DIALOG => {}
+                PICKER_DIALOG => self.answer_picker(result)?,
+                FIND_DIALOG => self.answer_find(result)?,
+                REPLACE_DIALOG => self.answer_replace(result),
+                REPLACE_WITH_DIALOG => self.answer_replace_with(result)?,
+                _ => self.send(MessageType::DialogResult, result)?,
+            }
+            return self.draw_modal(tui);
+        }
+        if let Some(view) = &mut self.search {
+            match view.handle_key(ev) {
+                Some(SearchChoice::Search(req)) if req.pattern.is_empty() => {
+                    self.send(MessageType::CancelSearch, ())?;
+                }
+                Some(SearchChoice::Search(req)) => self.send(MessageType::SearchFiles, req)?,
+                Some(SearchChoice::Open { path, line }) => {
+                    self.search = None;
+                    self.picker = None;
+                    self.open_at(path, line)?;
+                }
+                Some(SearchChoice::Cancel) => {
+                    self.search = None;
+                    self.send(MessageType::CancelSearch, ())?;
+                }
+                None => {}
+            }
+            return self.draw_modal(tui);
+        }
+        if let Some(view) = &mut self.bookmarks {
+            match view.handle_key(ev) {
+                Some(BookmarkChoice::Open { path, line }) => {
+                    self.bookmarks = None;
+                    self.open_at(path, line)?;
+                }
+                Some(BookmarkChoice::Cancel) => self.bookmarks = None,
+                None => {}
+            }
+            return self.draw_modal(tui);
+        }
+        if self.confirming {
+            self.confirm_replace(ev)?;
+            return self.draw_modal(tui);
+        }
+        let command = self.keymap.command(ev);
+        if command == Some(Command::SearchWorkspace) && self.role == Role::Editor {
+            self.search = Some(SearchView::default());
+            return self.draw_modal(tui);
+        }
+        if let Some(view) = &mut self.picker {
+            let choice = view.handle_key(ev);
+            self.picked(choice)?;
+            return self.draw_modal(tui);
+        }
+        let Some(command) = command else {
+            return Ok(());
+        };
+        if self.role == Role::Follower {
+            self.quit = command == Command::Quit;
+            return Ok(());
+        }
+        match command {
+            Command::Quit => self.quit = true,
+            Command::Save => self.send(MessageType::Save, ())?,
+            Command::Undo => self.send(MessageType::Undo, ())?,
+            Command::Redo => self.send(MessageType::Redo, ())?,
+            // The server replies with the text of the selection.
+            Command::Copy if self.clipboard.is_some() => self.send(
+                MessageType::Copy,
+                Copy {
+                    text: String::new(),
+                },
+            )?,
+            Command::Copy => {}
+            // Opened above, so it also shows over the picker.
+            Command::SearchWorkspace => {}
+            Command::OpenFile => {
+                // List the root and every directory down to the open file.
+                let mut dirs = Vec::new();
+                let mut dir = self.path.as_deref().map_or("", picker::parent_of);
+                while !dir.is_empty() {
+                    dirs.push(dir.to_string());
+                    dir = picker::parent_of(dir);
+                }
+                dirs.push(String::new());
+                self.picker = Some(PickerView::new(self.path.clone()));
+                for path in dirs.into_iter().rev() {
+                    self.send(MessageType::PickerAction, PickerAction::Expand { path })?;
+                }
+            }
+            Command::Insert(text) => {
+                self.insert(text)?;
+                self.show_echo(tui)?;
+            }
+            // Held back text is typed first, so the tab goes after it.
+            Command::Tab { .. } if self.retype.is_some() => self.insert("\t".into())?,
+            Command::Tab { back } => self.send(MessageType::Tab, Tab { back })?,
+            Command::Delete(dir, granularity) => self.delete(dir, granularity)?,
+            Command::Move(dir, granularity) => self.send_move(dir, granularity, false)?,
+            Command::Select(dir, granularity) => self.send_move(dir, granularity, true)?,
+            Command::SelectBlock(dir) => {
+                let mv = Move {
+                    dir,
+                    granularity: Granularity::Grapheme,
+                    extend: true,
+                    block: true,
+                };
+                self.send(MessageType::Move, mv)?;
+            }
+            Command::Find => {
+                self.ask_find();
+                return self.draw_modal(tui);
+            }
+            Command::FindAgain(dir) if !self.query.is_empty() => {
+                let query = self.query.clone();
+                let search = Search {
+                    query,
+                    dir,
+                    regex: false,
+                    replacement: None,
+                    whole_word: self.whole_word,
+                    in_selection: self.in_selection,
+                    start_confirm: false,
+                };
+                self.send(MessageType::Search, search)?;
+            }
+            Command::FindAgain(_) => {}
+            Command::Replace => {
+                self.ask_replace();
+                return self.draw_modal(tui);
+            }
+            Command::ToggleMatches => {
+                tui.toggle_matches();
+                return self.draw_modal(tui);
+            }
+            Command::AddCursor(target) => {
+                self.send(MessageType::AddCursor, AddCursor { target })?;
+            }
+            Command::Jump(dir) => self.send(MessageType::Jump, Jump { dir })?,
+            Command::Bookmark(action) => self.send(MessageType::Bookmark, Bookmark { action })?,
+            // Esc is pressed idly; nothing to drop before a file is open.
+            Command::SingleCursor if self.selection.is_some() => {
+                let select = Select {
+                    anchor: 0,
+                    head: 0,
+                    mode: SelectMode::Clear,
+                };
+                self.send(MessageType::Select, select)?;
+            }
+            Command::SingleCursor => {}
+            Command::Scroll(pages) => {
+                self.scroll += pages;
+                self.send_viewport()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle pasted `text` as a whole: it goes into the open dialog or
+    /// search panel, or replaces the selection in one insert, without
+    /// any of the per-key handling typing it would get.
+    fn paste<B: Backend>(&mut self, text: String, tui: &mut Tui<B>) -> Result<()> {
+        if self.offline {
+            return Ok(());
+        }
+        if let Some(view) = &mut self.dialog {
+            view.paste(&text);
+            return tui.draw_dialog(view);
+        }
+        if let Some(view) = &mut self.search {
+            if let Some(SearchChoice::Search(req)) = view.paste(&text) {
+                self.send(MessageType::SearchFiles, req)?;
+            }
+            return self.draw_modal(tui);
+        }
+        let modal = self.picker.is_some() || self.bookmarks.is_some();
+        if modal || self.role == Role::Follower || text.is_empty() {
+            return Ok(());
+        }
+        // Terminals send line breaks in pastes as carriage returns.
+        self.insert(text.replace("\r\n", "\n").replace('\r', "\n"))?;
+        self.show_echo(tui)
+    }
+
+    /// Handle a mouse event: clicks place the cursor, drags and
+    /// shift-clicks select, and the wheel scrolls. Over the picker, clicks
+    /// pick entries and the wheel moves the highlight.
+    fn handle_mouse<B: Backend>(&mut self, ev: MouseEvent, tui: &mut Tui<B>) -> Result<()> {
+        let modal = self.dialog.is_some() || self.search.is_some() || self.bookmarks.is_some();
+        if self.offline || modal || self.role == Role::Follower {
+            return Ok(());
+        }
+        let (x, y) = (ev.column, ev.row);
+        if let Some(view) = &mut self.picker {
+            match ev.kind {
+                MouseEventKind::Down(MouseButton::Left) => {
+                    let choice = tui.picker_row(view, x, y).and_then(|idx| view.click(idx));
+                    self.picked(choice)?;
+                }
+                MouseEventKind::ScrollUp => view.scroll(-1),
+                MouseEventKind::ScrollDown => view.scroll(1),
+                _ => return Ok(()),
+            }
+            return self.draw_modal(tui);
+        }
+        match ev.kind {
+            MouseEventKind::Down(MouseButton::Left) | MouseEventKind::Drag(MouseButton::Left) => {
+                let Some((line, col)) = tui.text_position(x, y) else {
+                    return Ok(());
+                };
+                let extend = matches!(ev.kind, MouseEventKind::Drag(_))
+                    || ev.modifiers.contains(KeyModifiers::SHIFT);
+                // Dragging with Alt selects a rectangle.
+                let block = ev.modifiers.contains(KeyModifiers::ALT);
+                let goto = GotoLine {
+                    line,
+                    col,
+                    extend,
+                    block,
+                };
+                self.send(MessageType::GotoLine, goto)
+            }
+            MouseEventKind::ScrollUp => {
+                self.wheel -= WHEEL_LINES;
+                self.send_viewport()
+            }
+            MouseEventKind::ScrollDown => {
+                self.wheel += WHEEL_LINES;
+                self.send_viewport()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Act on what the user picked in the picker, if anything.
+    fn picked(&mut self, choice: Option<PickerChoice>) -> Result<()> {
+        match choice {
+            Some(PickerChoice::Open(path)) => {
+                self.picker = None;
+                let open = PickerAction::Open { path: path.clone() };
+                self.send(MessageType::PickerAction, open)?;
+                self.path = Some(path);
+            }
+            Some(PickerChoice::Expand(path)) => {
+                self.send(MessageType::PickerAction, PickerAction::Expand { path })?;
+            }
+            Some(PickerChoice::Cancel) => {
+                self.picker = None;
+                self.quit = self.path.is_none();
+            }
+            Some(choice) => self.ask_picker(choice),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Whether a frame at `doc_v` is older than the last one drawn.
+    fn is_stale(&self, doc_v: u64) -> bool {
+        self.frame_v.is_some_and(|v| doc_v < v)
+    }
+
+    /// Skip a frame at `doc_v` that does not fit what is drawn and ask for
+    /// a full one, which is drawn whatever its version.
+    fn desync(&mut self, why: &str, doc_v: u64) -> Result<()> {
+        tracing::warn!(doc_v, last = self.frame_v, "{why}; asking for a full frame");
+        self.frame_v = None;
+        self.request_frame("desync")
+    }
+
+    /// Ask for a full frame, once the viewport request in flight has its
+    /// frame.
+    fn request_frame(&mut self, reason: &str) -> Result<()> {
+        self.frame_due = Some(reason.into());
+        self.send_viewport()
+    }
+
+    /// Send the viewport requests held back, unless one still waits for
+    /// its frame.
+    fn send_viewport(&mut self) -> Result<()> {
+        if self.viewport_sent.is_some() {
+            return Ok(());
+        }
+        if let Some(reason) = self.frame_due.take() {
+            self.send(MessageType::RequestFrame, RequestFrame { reason })?;
+            self.viewport_sent = Some(Instant::now());
+        }
+        if self.scroll != 0 || self.hscroll != 0 {
+            let scroll = Scroll {
+                delta: std::mem::take(&mut self.scroll),
+                unit: ScrollUnit::Page,
+                dx: std::mem::take(&mut self.hscroll),
+            };
+            self.send(MessageType::Scroll, scroll)?;
+            self.viewport_sent = Some(Instant::now());
+        }
+        if self.wheel != 0 {
+            let scroll = Scroll {
+                delta: std::mem::take(&mut self.wheel),
+                unit: ScrollUnit::Line,
+                dx: 0,
+            };
+            self.send(MessageType::Scroll, scroll)?;
+            self.viewport_sent = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Scroll sideways when the viewer's cursor in the frame just drawn is
+    /// past its left or right edge, so it lands a quarter of the width in.
+    fn follow_cursor<B: Backend>(&mut self, tui: &Tui<B>) -> Result<()> {
+        let Some(frame) = tui.frame() else {
+            return Ok(());
+        };
+        if self
+            .hscroll_from
+            .is_some_and(|from| from != frame.first_col)
+        {
+            self.hscroll_from = None;
+        }
+        let Some(cur) = frame.cursors.iter().find(|c| c.user_id.is_none()) else {
+            return Ok(());
+        };
+        let rows = frame.first_line..frame.first_line + u64::from(frame.rows);
+        if self.role == Role::Follower || self.hscroll_from.is_some() || !rows.contains(&cur.line) {
+            return Ok(());
+        }
+        let (col, first) = (i64::from(cur.col), i64::from(frame.first_col));
+        let cols = i64::from(frame.cols.max(1));
+        let dx = if col < first {
+            (col - cols / 4).max(0) - first
+        } else if col >= first + cols {
+            col - (first + cols) + 1 + cols / 4
+        } else {
+            return Ok(());
+        };
+        self.hscroll += dx;
+        self.hscroll_from = Some(frame.first_col);
+        self.send_viewport()
+    }
+
+    /// Replace the selection with `text`.
+    fn insert(&mut self, text: String) -> Result<()> {
+        if let Some(retype) = &mut self.retype {
+            retype.push_str(&text);
+            return Ok(());
+        }
+        // Text typed at a bare cursor is drawn before the server shows it.
+        let echo = (self.selection.as_ref()).is_some_and(|sel| sel.from == sel.to)
+            && !text.contains(char::is_control);
+        let Some(pos) = self.delete_selection()? else {
+            return Ok(());
+        };
+        let seq = self.next_seq();
+        let end = pos + text.len() as u64;
+        self.typed.push((seq, text.clone()));
+        if echo {
+            let text = text.clone();
+            self.echo.push(Echo {
+                seq,
+                text,
+                doc_v: None,
+            });
+        }
+        let insert = Insert {
+            pos,
+            text,
+            seq,
+            base_doc_v: self.doc_v,
+        };
+        self.send(MessageType::Insert, insert)?;
+        self.selection = Some(Range { from: end, to: end });
+        Ok(())
+    }
+
+    /// Delete the selection or, if it is empty, the character or word in
+    /// `dir`. That text is selected first and deleted once the frame shows
+    /// where it ends, so the server decides what a character or word is.
+    fn delete(&mut self, dir: Direction, granularity: Granularity) -> Result<()> {
+        let Some(sel) = &self.selection else {
+            return Ok(());
+        };
+        if sel.from != sel.to {
+            return self.delete_selection().map(drop);
+        }
+        self.delete_pending = true;
+        self.send_move(dir, granularity, true)
+    }
+
+    /// Delete the selection if it is not empty, returning where it started;
+    /// `None` if there is no selection to edit.
+    fn delete_selection(&mut self) -> Result<Option<u64>> {
+        let Some(sel) = &self.selection else {
+            return Ok(None);
+        };
+        let (from, to) = (sel.from.min(sel.to), sel.from.max(sel.to));
+        if from != to {
+            let seq = self.next_seq();
+            let delete = Delete {
+                range: Range { from, to },
+                seq,
+                base_doc_v: self.doc_v,
+            };
+            self.send(MessageType::Delete, delete)?;
+        }
+        self.selection = Some(Range { from, to: from });
+        Ok(Some(from))
+    }
+
+    fn send_move(&mut self, dir: Direction, granularity: Granularity, extend: bool) -> Result<()> {
+        let mv = Move {
+            dir,
+            granularity,
+            extend,
+            block: false,
+        };
+        self.send(MessageType::Move, mv)
+    }
+
+    /// Take in the status of a new frame, finishing a pending delete.
+    fn update_status(&mut self, doc_v: u64, status: Option<&Status>) -> Result<()> {
+        let Some(status) = status else {
+            return Ok(());
+        };
+        self.doc_v = Some(doc_v);
+        self.selection = status.selection.clone();
+        // Nothing left to replace once the selection is off every match.
+        if (status.search.as_ref()).is_none_or(|s| s.current == 0) {
+            self.confirming = false;
+        }
+        if std::mem::take(&mut self.delete_pending) {
+            self.delete_selection()?;
+        }
+        if let Some(text) = self.retype.take() {
+            self.insert(text)?;
+        }
+        Ok(())
+    }
+
+    /// The server refused edit `seq` because the document changed under
+    /// it. Typed text is typed again at the cursor of a fresh frame; a
+    /// deletion is dropped, as what it meant to delete may be gone.
+    fn refused<B: Backend>(&mut self, seq: u64, tui: &mut Tui<B>) -> Result<()> {
+        let Some(i) = self.typed.iter().position(|(s, _)| *s == seq) else {
+            let msg = "the file changed before a deletion reached the server; it was not made";
+            return self.show_error(ErrorMsg::new(ErrorCode::Conflict, msg), tui);
+        };
+        let (_, text) = self.typed.remove(i);
+        self.echo.retain(|e| e.seq != seq);
+        self.show_echo(tui)?;
+        if self.retype.is_none() {
+            self.request_frame("conflict")?;
+        }
+        self.retype.get_or_insert_default().push_str(&text);
+        Ok(())
+    }
+
+    /// Take over the session left behind by the dropped connection, or
+    /// open the file again if it is gone.
+    fn reattach(&mut self, list: SessionList) -> Result<()> {
+        let path = self.path.clone().unwrap_or_default();
+        let ours = list.sessions.iter().filter(|s| s.path == path);
+        match ours.clone().find(|s| !s.attached) {
+            Some(session) => {
+                self.reattaching = None;
+                self.send(MessageType::Attach, Attach { id: session.id })
+            }
+            None if ours.count() > 0 => {
+                self.reattaching = Some(Some(Instant::now() + REATTACH_RETRY));
+                Ok(())
+            }
+            None => {
+                self.reattaching = None;
+                self.send(MessageType::PickerAction, PickerAction::Open { path })
+            }
+        }
+    }
+
+    fn show_error<B: Backend>(&mut self, err: ErrorMsg, tui: &mut Tui<B>) -> Result<()> {
+        if matches!(
+            err.code,
+            ErrorCode::Unauthorized | ErrorCode::ProtocolMismatch
+        ) {
+            bail!("{}", err.msg);
+        }
+        self.dialog = Some(DialogView::new(Dialog {
+            id: ERROR_DIALOG.into(),
+            title: "Error".into(),
+            body: err.msg,
+            buttons: vec![DialogButton {
+                id: "ok".into(),
+                label: "OK".into(),
+            }],
+            input: None,
+        }));
+        self.draw_modal(tui)
+    }
+
+    /// Ask for what a picker operation needs: a name for new and renamed
+    /// entries, or confirmation before deleting.
+    fn ask_picker(&mut self, choice: PickerChoice) {
+        let (title, body, button, input) = match &choice {
+            PickerChoice::Create { parent, dir } => {
+                let title = if *dir { "New folder" } else { "New file" };
+                (title, format!("Name in /{parent}"), "Create", Some(""))
+            }
+            PickerChoice::Rename(path) => {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                (
+                    "Rename",
+                    format!("New name for /{path}"),
+                    "Rename",
+                    Some(name),
+                )
+            }
+            PickerChoice::Delete(path) => ("Delete", format!("Delete /{path}?"), "Delete", None),
+            _ => return,
+        };
+        self.dialog = Some(DialogView::new(Dialog {
+            id: PICKER_DIALOG.into(),
+            title: title.into(),
+            body,
+            buttons: vec![
+                DialogButton {
+                    id: "ok".into(),
+                    label: button.into(),
+                },
+                DialogButton {
+                    id: "cancel".into(),
+                    label: "Cancel".into(),
+                },
+            ],
+            input: input.map(Into::into),
+        }));
+        self.asking = Some(choice);
+    }
+
+    /// Ask what to find in the open file, offering the last search.
+    fn ask_find(&mut self) {
+        let view = DialogView::new(Dialog {
+            id: FIND_DIALOG.into(),
+            title: self.search_title("Find"),
+            body: "Text to find; empty to stop searching. Alt+W: whole words, Alt+S: in selection"
+                .into(),
+            buttons: vec![
+                DialogButton {
+                    id: "ok".into(),
+                    label: "Find".into(),
+                },
+                DialogButton {
+                    id: "cancel".into(),
+                    label: "Cancel".into(),
+                },
+            ],
+            input: Some(self.query.clone()),
+        })
+        .with_history(self.searches.entries());
+        self.dialog = Some(view);
+    }
+
+    /// Select the first match of the text the find dialog was answered
+    /// with; an empty answer ends the search.
+    fn answer_find(&mut self, result: DialogResult) -> Result<()> {
+        if result.button.as_deref() != Some("ok") {
+            return Ok(());
+        }
+        self.query = result.input.unwrap_or_default();
+        self.searches.push(&self.query);
+        self.forget_search_range()?;
+        let search = Search {
+            query: self.query.clone(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: None,
+            whole_word: self.whole_word,
+            in_selection: self.in_selection,
+            start_confirm: false,
+        };
+        self.send(MessageType::Search, search)
+    }
+
+    /// Title of the find or replace dialog, marking the options set.
+    fn search_title(&self, title: &str) -> String {
+        let mut title = title.to_string();
+        if self.whole_word {
+            title += " [word]";
+        }
+        if self.in_selection {
+            title += " [selection]";
+        }
+        title
+    }
+
+    /// Toggle whole words with Alt+W or matching inside the selection with
+    /// Alt+S while the find or replace dialog is open. Returns whether `ev`
+    /// was one of them.
+    fn toggle_search_option(&mut self, ev: KeyEvent) -> bool {
+        let Some(view) = &self.dialog else {
+            return false;
+        };
+        let title = match view.dialog.id.as_str() {
+            FIND_DIALOG => "Find",
+            REPLACE_DIALOG => "Replace",
+            _ => return false,
+        };
+        if !ev.modifiers.contains(KeyModifiers::ALT) {
+            return false;
+        }
+        match ev.code {
+            KeyCode::Char('w') => self.whole_word = !self.whole_word,
+            KeyCode::Char('s') => self.in_selection = !self.in_selection,
+            _ => return false,
+        }
+        let title = self.search_title(title);
+        if let Some(view) = &mut self.dialog {
+            view.dialog.title = title;
+        }
+        true
+    }
+
+    /// End the active search before starting one confined to the
+    /// selection, which the server would otherwise keep confining to the
+    /// selection the last such search started in.
+    fn forget_search_range(&mut self) -> Result<()> {
+        if !self.in_selection {
+            return Ok(());
+        }
+        let end = Search {
+            query: String::new(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: None,
+            whole_word: false,
+            in_selection: false,
+            start_confirm: false,
+        };
+        self.send(MessageType::Search, end)
+    }
+
+    /// Ask what to replace in the open file, offering the last search, and
+    /// whether it is plain text or a regex.
+    fn ask_replace(&mut self) {
+        let view = DialogView::new(Dialog {
+            id: REPLACE_DIALOG.into(),
+            title: self.search_title("Replace"),
+            body: "Text or regex to replace. Alt+W: whole words, Alt+S: in selection".into(),
+            buttons: vec![
+                DialogButton {
+                    id: "text".into(),
+                    label: "Text".into(),
+                },
+                DialogButton {
+                    id: "regex".into(),
+                    label: "Regex".into(),
+                },
+                DialogButton {
+                    id: "cancel".into(),
+                    label: "Cancel".into(),
+                },
+            ],
+            input: Some(self.query.clone()),
+        })
+        .with_history(self.searches.entries());
+        self.dialog = Some(view);
+    }
+
+    /// Ask what to replace the text the replace dialog was answered with
+    /// by, offering the last replacement.
+    fn answer_replace(&mut self, result: DialogResult) {
+        let regex = match result.button.as_deref() {
+            Some("text") => false,
+            Some("regex") => true,
+            _ => return,
+        };
+        let query = result.input.unwrap_or_default();
+        if query.is_empty() {
+            return;
+        }
+        let body = if regex {
+            format!("Replace /{query}/ with; $1 inserts the first group")
+        } else {
+            format!("Replace \"{query}\" with")
+        };
+        self.searches.push(&query);
+        (self.query, self.regex) = (query, regex);
+        let view = DialogView::new(Dialog {
+            id: REPLACE_WITH_DIALOG.into(),
+            title: "Replace with".into(),
+            body,
+            buttons: vec![
+                DialogButton {
+                    id: "ask".into(),
+                    label: "Ask".into(),
+                },
+                DialogButton {
+                    id: "all".into(),
+                    label: "All".into(),
+                },
+                DialogButton {
+                    id: "cancel".into(),
+                    label: "Cancel".into(),
+                },
+            ],
+            input: Some(self.replacement.clone()),
+        })
+        .with_history(self.replacements.entries());
+        self.dialog = Some(view);
+    }
+
+    /// Replace every match with the text the second replace dialog was
+    /// answered with, or step through the matches asking about each.
+    fn answer_replace_with(&mut self, result: DialogResult) -> Result<()> {
+        let all = match result.button.as_deref() {
+            Some("ask") => false,
+            Some("all") => true,
+            _ => return Ok(()),
+        };
+        self.replacement = result.input.unwrap_or_default();
+        self.replacements.push(&self.replacement);
+        self.forget_search_range()?;
+        if all {
+            return self.send_replace(true, false);
+        }
+        self.confirming = true;
+        self.next_to_replace(true)
+    }
+
+    /// Answer the replace prompt about the selected match: `y` replaces it
+    /// and `n` skips it, moving on to the next match, `a` replaces it and
+    /// every match not asked about yet, and `q` or Esc stops asking. The
+    /// server ends the prompt, selecting no match, once stepping comes
+    /// around to where it started.
+    fn confirm_replace(&mut self, ev: KeyEvent) -> Result<()> {
+        match ev.code {
+            KeyCode::Char('y') => self.send_replace(false, false)?,
+            KeyCode::Char('n') => self.next_to_replace(false)?,
+            KeyCode::Char('a') => {
+                self.confirming = false;
+                self.send_replace(true, true)?;
+            }
+            KeyCode::Char('q') | KeyCode::Esc => self.confirming = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Select the next match of the replace query, with the status
+    /// previewing its replacement, from where asking started with `start`.
+    fn next_to_replace(&mut self, start: bool) -> Result<()> {
+        let search = Search {
+            query: self.query.clone(),
+            dir: SearchDir::Next,
+            regex: self.regex,
+            replacement: Some(self.replacement.clone()),
+            whole_word: self.whole_word,
+            in_selection: self.in_selection,
+            start_confirm: start,
+        };
+        self.send(MessageType::Search, search)
+    }
+
+    fn send_replace(&mut self, all: bool, rest: bool) -> Result<()> {
+        let replace = Replace {
+            query: self.query.clone(),
+            replacement: self.replacement.clone(),
+            regex: self.regex,
+            all,
+            rest,
+            whole_word: self.whole_word,
+            in_selection: self.in_selection,
+        };
+        self.send(MessageType::Replace, replace)
+    }
+
+    /// The replace prompt, showing what the selected match becomes.
+    fn replace_prompt<B: Backend>(&self, tui: &Tui<B>) -> String {
+        let preview = (tui.frame())
+            .and_then(|frame| frame.status.as_ref()?.search.as_ref()?.preview.as_deref())
+            .unwrap_or(&self.replacement);
+        format!("Replace with \"{preview}\"? (y)es (n)o (a)ll (q)uit")
+    }
+
+    /// Carry out the picker operation the user just answered a dialog
+    /// about. The server replies with the changed directory's listing.
+    fn answer_picker(&mut self, result: DialogResult) -> Result<()> {
+        let Some(choice) = self.asking.take() else {
+            return Ok(());
+        };
+        if result.button.as_deref() != Some("ok") {
+            return Ok(());
+        }
+        let name = result.input.as_deref().unwrap_or_default().trim();
+        let action = match choice {
+            PickerChoice::Create { .. } | PickerChoice::Rename(_) if name.is_empty() => {
+                return Ok(());
+            }
+            PickerChoice::Create { parent, dir } => PickerAction::Create {
+                path: picker::join(&parent, name),
+                dir,
+            },
+            PickerChoice::Rename(from) => PickerAction::Rename {
+                to: picker::join(picker::parent_of(&from), name),
+                from,
+            },
+            PickerChoice::Delete(path) => PickerAction::Delete { path },
+            _ => return Ok(()),
+        };
+        if let (
+            Some(view),
+            PickerAction::Create { path, .. } | PickerAction::Rename { to: path, .. },
+        ) = (&mut self.picker, &action)
+        {
+            view.focus(path.clone());
+        }
+        self.send(MessageType::PickerAction, action)
+    }
+
+    /// Open `path` unless it is open already, and move to zero-based `line`.
+    fn open_at(&mut self, path: String, line: u64) -> Result<()> {
+        if self.path.as_ref() != Some(&path) {
+            let open = PickerAction::Open { path: path.clone() };
+            self.send(MessageType::PickerAction, open)?;
+            self.path = Some(path);
+        }
+        let goto = GotoLine {
+            line,
+            col: 0,
+            extend: false,
+            block: false,
+        };
+        self.send(MessageType::GotoLine, goto)
+    }
+
+    /// Draw the dialog, search panel, bookmark list or picker over the last
+    /// frame, or the frame alone.
+    fn draw_modal<B: Backend>(&self, tui: &mut Tui<B>) -> Result<()> {
+        match (&self.dialog, &self.search, &self.bookmarks, &self.picker) {
+            (Some(view), _, _, _) => tui.draw_dialog(view),
+            (None, Some(view), _, _) => tui.draw_search(view),
+            (None, None, Some(view), _) => tui.draw_bookmarks(view),
+            (None, None, None, Some(view)) => tui.draw_picker(view),
+            (None, None, None, None) if self.confirming => {
+                let prompt = self.replace_prompt(tui);
+                tui.draw_prompt(&prompt)
+            }
+            (None, None, None, None) => tui.redraw(),
+        }
+    }
+
+    /// Draw the dialog, panel or prompt again after a new frame covered
+    /// it.
+    fn draw_overlay<B: Backend>(&self, tui: &mut Tui<B>) -> Result<()> {
+        if self.dialog.is_none()
+            && self.search.is_none()
+            && self.bookmarks.is_none()
+            && self.picker.is_none()
+            && !self.confirming
+        {
+            return Ok(());
+        }
+        self.draw_modal(tui)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn send<T: Serialize>(&mut self, ty: MessageType, data: T) -> Result<()> {
+        self.outbox.push(encode(&Envelope::new(ty, data))?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostwriter_proto::{Cursor, DirEntry, SearchStatus, SessionInfo};
+    use ratatui::backend::TestBackend;
+    use serde::de::DeserializeOwned;
+
+    fn tui() -> Tui<TestBackend> {
+        Tui::new_for_test(TestBackend::new(20, 5)).unwrap()
+    }
+
+    fn key(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn message<T: Serialize>(ty: MessageType, data: T) -> Vec<u8> {
+        encode(&Envelope::new(ty, data)).unwrap()
+    }
+
+    /// A frame whose selection runs from `from` to `to`.
+    fn frame(from: u64, to: u64) -> Vec<u8> {
+        let mut status = crate::status::tests::status();
+        status.selection = Some(Range { from, to });
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 20,
+            rows: 4,
+            lines: Vec::new(),
+            cursors: Vec::new(),
+            status_left: String::new(),
+            status_right: String::new(),
+            status: Some(status),
+            classes: Vec::new(),
+            checksum: None,
+        };
+        message(MessageType::Frame, frame)
+    }
+
+    fn sent<T: DeserializeOwned>(data: &[u8], ty: MessageType) -> T {
+        let env = decode::<T>(data).unwrap();
+        assert_eq!(env.ty, ty);
+        env.data
+    }
+
+    #[test]
+    fn replaces_the_selection_when_typing() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_event(key(KeyCode::Char('w')), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let insert: Insert = sent(&out[0], MessageType::Insert);
+        assert_eq!((insert.pos, insert.text.as_str(), insert.seq), (2, "x", 1));
+
+        app.handle_message(&frame(5, 1), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('y')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let delete: Delete = sent(&out[0], MessageType::Delete);
+        assert_eq!((delete.range, delete.seq), (Range { from: 1, to: 5 }, 2));
+        let insert: Insert = sent(&out[1], MessageType::Insert);
+        assert_eq!((insert.pos, insert.seq), (1, 3));
+    }
+
+    #[test]
+    fn leaves_tab_to_the_server() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Tab), &mut tui).unwrap();
+        let back = KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT);
+        app.handle_event(Event::Key(back), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let tab: Tab = sent(&out[0], MessageType::Tab);
+        assert!(!tab.back);
+        let tab: Tab = sent(&out[1], MessageType::Tab);
+        assert!(tab.back);
+    }
+
+    #[test]
+    fn pastes_as_one_insert() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        let text = "fn main() {\r\n\tx\r}".to_string();
+        app.handle_event(Event::Paste(text), &mut tui).unwrap();
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let insert: Insert = sent(&out[0], MessageType::Insert);
+        assert_eq!(
+            (insert.pos, insert.text.as_str()),
+            (2, "fn main() {\n\tx\n}")
+        );
+
+        // A dialog takes the first line.
+        let find = KeyEvent::new(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(find), &mut tui).unwrap();
+        app.handle_event(Event::Paste("needle\nhay".into()), &mut tui)
+            .unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let search: Search = sent(&app.take_outbox()[0], MessageType::Search);
+        assert_eq!(search.query, "needle");
+    }
+
+    #[test]
+    fn copies_the_selection_to_the_clipboard() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 2), &mut tui).unwrap();
+        let copy = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(copy), &mut tui).unwrap();
+        // Without a clipboard nothing is asked for.
+        assert!(app.take_outbox().is_empty());
+
+        app.clipboard = Some(Mux::None);
+        app.handle_event(Event::Key(copy), &mut tui).unwrap();
+        let request: Copy = sent(&app.take_outbox()[0], MessageType::Copy);
+        assert!(request.text.is_empty());
+        let reply = Copy { text: "hi".into() };
+        app.handle_message(&message(MessageType::Copy, reply), &mut tui)
+            .unwrap();
+        assert_eq!(tui.escapes(), ["\x1b]52;c;aGk=\x07"]);
+
+        let huge = Copy {
+            text: "x".repeat(clipboard::MAX_COPY + 1),
+        };
+        app.handle_message(&message(MessageType::Copy, huge), &mut tui)
+            .unwrap();
+        assert_eq!(tui.escapes().len(), 1);
+        assert_eq!(app.dialog.as_ref().unwrap().dialog.id, ERROR_DIALOG);
+    }
+
+    #[test]
+    fn deletes_the_character_the_server_selects() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(3, 3), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Backspace), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let mv: Move = sent(&out[0], MessageType::Move);
+        assert_eq!((mv.dir, mv.extend), (Direction::Left, true));
+
+        app.handle_message(&frame(3, 1), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let delete: Delete = sent(&out[0], MessageType::Delete);
+        assert_eq!(delete.range, Range { from: 1, to: 3 });
+        app.handle_message(&frame(1, 1), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn finds_again_what_was_last_found() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::F(3)), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        let find = KeyEvent::new(KeyCode::Char('f'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(find), &mut tui).unwrap();
+        for c in "ab".chars() {
+            app.handle_event(key(KeyCode::Char(c)), &mut tui).unwrap();
+        }
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let search: Search = sent(&out[0], MessageType::Search);
+        assert_eq!((search.query.as_str(), search.dir), ("ab", SearchDir::Next));
+
+        let again = KeyEvent::new(KeyCode::F(3), KeyModifiers::SHIFT);
+        app.handle_event(Event::Key(again), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let search: Search = sent(&out[0], MessageType::Search);
+        assert_eq!((search.query.as_str(), search.dir), ("ab", SearchDir::Prev));
+
+        // Alt+W and Alt+S narrow the search; one confined to the selection
+        // ends the last search first, so it starts from the selection now.
+        app.handle_event(Event::Key(find), &mut tui).unwrap();
+        for c in ['w', 's'] {
+            let toggle = KeyEvent::new(KeyCode::Char(c), KeyModifiers::ALT);
+            app.handle_event(Event::Key(toggle), &mut tui).unwrap();
+        }
+        let title = &app.dialog.as_ref().unwrap().dialog.title;
+        assert_eq!(title, "Find [word] [selection]");
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let end: Search = sent(&out[0], MessageType::Search);
+        assert!(end.query.is_empty());
+        let search: Search = sent(&out[1], MessageType::Search);
+        assert_eq!(search.query, "ab");
+        assert!(search.whole_word && search.in_selection);
+        app.handle_event(key(KeyCode::F(3)), &mut tui).unwrap();
+        let search: Search = sent(&app.take_outbox()[0], MessageType::Search);
+        assert!(search.whole_word && search.in_selection);
+    }
+
+    #[test]
+    fn recalls_earlier_searches() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        let find = Event::Key(KeyEvent::new(KeyCode::Char('f'), KeyModifiers::CONTROL));
+        for query in ["ab", "cd"] {
+            app.handle_event(find.clone(), &mut tui).unwrap();
+            app.handle_event(key(KeyCode::Backspace), &mut tui).unwrap();
+            app.handle_event(key(KeyCode::Backspace), &mut tui).unwrap();
+            for c in query.chars() {
+                app.handle_event(key(KeyCode::Char(c)), &mut tui).unwrap();
+            }
+            app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        }
+        app.take_outbox();
+
+        // The dialog offers "cd" again; Up goes back to "ab".
+        app.handle_event(find, &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Up), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let search: Search = sent(&app.take_outbox()[0], MessageType::Search);
+        assert_eq!(search.query, "ab");
+        assert_eq!(app.searches.entries(), ["ab", "cd"]);
+    }
+
+    #[test]
+    fn asks_what_to_replace_and_about_each_match() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        let type_text = |app: &mut App, tui: &mut Tui<TestBackend>, text: &str| {
+            for c in text.chars() {
+                app.handle_event(key(KeyCode::Char(c)), tui).unwrap();
+            }
+        };
+        let replace = KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(replace), &mut tui).unwrap();
+        type_text(&mut app, &mut tui, "(a+)");
+        // Regex, the second button.
+        app.handle_event(key(KeyCode::Tab), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let body = &app.dialog.as_ref().unwrap().dialog.body;
+        assert!(body.contains("/(a+)/"));
+        type_text(&mut app, &mut tui, "<$1>");
+        app.handle_event(key(KeyCode::Tab), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let answer: Replace = sent(&out[0], MessageType::Replace);
+        assert_eq!(
+            answer,
+            Replace {
+                query: "(a+)".into(),
+                replacement: "<$1>".into(),
+                regex: true,
+                all: true,
+                rest: false,
+                whole_word: false,
+                in_selection: false,
+            }
+        );
+
+        // Both answers are offered again; asking selects the first match.
+        app.handle_event(Event::Key(replace), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let search: Search = sent(&out[0], MessageType::Search);
+        assert_eq!((search.query.as_str(), search.regex), ("(a+)", false));
+        assert_eq!(search.replacement.as_deref(), Some("<$1>"));
+        assert!(search.start_confirm);
+
+        let found = |current, preview: Option<&str>| {
+            let mut frame = decode::<Frame>(&frame(0, 4)).unwrap().data;
+            frame.status.as_mut().unwrap().search = Some(SearchStatus {
+                current,
+                total: 2,
+                preview: preview.map(Into::into),
+                searching: false,
+            });
+            message(MessageType::Frame, frame)
+        };
+        app.handle_message(&found(1, Some("<$1>")), &mut tui)
+            .unwrap();
+        let bottom: String = (0..20)
+            .map(|x| tui.backend().buffer()[(x, 4)].symbol().to_string())
+            .collect();
+        assert_eq!(bottom, "Replace with \"<$1>\"?");
+
+        // y replaces and n skips, each moving on to the next match.
+        app.handle_event(key(KeyCode::Char('n')), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('y')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let search: Search = sent(&out[0], MessageType::Search);
+        assert_eq!(search.replacement.as_deref(), Some("<$1>"));
+        assert!(!search.start_confirm);
+        let answer: Replace = sent(&out[1], MessageType::Replace);
+        assert_eq!((answer.all, answer.rest), (false, false));
+
+        // a replaces the rest and stops asking.
+        app.handle_event(key(KeyCode::Char('a')), &mut tui).unwrap();
+        let answer: Replace = sent(&app.take_outbox()[0], MessageType::Replace);
+        assert_eq!((answer.all, answer.rest), (true, true));
+        app.handle_event(key(KeyCode::Char('y')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        assert_eq!(peek_type(&out[0]).unwrap(), MessageType::Delete);
+
+        // So does running out of matches.
+        app.handle_event(Event::Key(replace), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        app.handle_message(&found(0, None), &mut tui).unwrap();
+        assert!(!app.confirming);
+    }
+
+    #[test]
+    fn types_refused_text_again_at_the_fresh_cursor() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('y')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let insert: Insert = sent(&out[1], MessageType::Insert);
+        assert_eq!((insert.pos, insert.base_doc_v), (3, Some(1)));
+
+        let ack = message(MessageType::Ack, Ack { seq: 1, doc_v: 2 });
+        app.handle_message(&ack, &mut tui).unwrap();
+        let refused = ErrorMsg::new(ErrorCode::Conflict, "changed").with_seq(2);
+        let refused = message(MessageType::Error, refused);
+        app.handle_message(&refused, &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('z')), &mut tui).unwrap();
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let _: RequestFrame = sent(&out[0], MessageType::RequestFrame);
+
+        app.handle_message(&frame(6, 6), &mut tui).unwrap();
+        let out = app.take_outbox();
+        let insert: Insert = sent(&out[0], MessageType::Insert);
+        assert_eq!((insert.pos, insert.text.as_str()), (6, "yz"));
+
+        // A refused deletion is not made again; the user is told instead.
+        let refused = ErrorMsg::new(ErrorCode::Conflict, "changed").with_seq(1);
+        app.handle_message(&message(MessageType::Error, refused), &mut tui)
+            .unwrap();
+        app.handle_event(key(KeyCode::Char('w')), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+    }
+
+    #[test]
+    fn asks_for_a_full_frame_after_a_desync() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        let mut stale = decode::<Frame>(&frame(0, 0)).unwrap().data;
+        stale.doc_v = 0;
+        app.handle_message(&message(MessageType::Frame, &stale), &mut tui)
+            .unwrap();
+        let out = app.take_outbox();
+        let req: RequestFrame = sent(&out[0], MessageType::RequestFrame);
+        assert_eq!(req.reason, "desync");
+
+        // The full frame answering it is drawn even if older.
+        app.handle_message(&message(MessageType::Frame, &stale), &mut tui)
+            .unwrap();
+        assert!(app.take_outbox().is_empty());
+        assert_eq!(app.frame_v, Some(0));
+
+        let mut corrupt = stale.clone();
+        corrupt.checksum = Some(corrupt.content_checksum() ^ 1);
+        app.handle_message(&message(MessageType::Frame, &corrupt), &mut tui)
+            .unwrap();
+        let out = app.take_outbox();
+        let _: RequestFrame = sent(&out[0], MessageType::RequestFrame);
+    }
+
+    #[test]
+    fn scrolls_sideways_to_follow_the_cursor() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let mut wide = decode::<Frame>(&frame(0, 0)).unwrap().data;
+        wide.cursors = vec![Cursor::new(0, 25)];
+        app.handle_message(&message(MessageType::Frame, &wide), &mut tui)
+            .unwrap();
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let scroll: Scroll = sent(&out[0], MessageType::Scroll);
+        // A quarter of the 20 columns past the cursor.
+        assert_eq!((scroll.delta, scroll.dx), (0, 11));
+
+        // A frame from before the scroll does not ask again.
+        app.handle_message(&message(MessageType::Frame, &wide), &mut tui)
+            .unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        wide.first_col = 11;
+        app.handle_message(&message(MessageType::Frame, &wide), &mut tui)
+            .unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        wide.cursors = vec![Cursor::new(0, 2)];
+        app.handle_message(&message(MessageType::Frame, &wide), &mut tui)
+            .unwrap();
+        let scroll: Scroll = sent(&app.take_outbox()[0], MessageType::Scroll);
+        assert_eq!(scroll.dx, -11);
+    }
+
+    #[test]
+    fn holds_scrolling_back_until_a_frame_arrives() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        for _ in 0..3 {
+            app.handle_event(key(KeyCode::PageDown), &mut tui).unwrap();
+        }
+        app.handle_event(key(KeyCode::PageUp), &mut tui).unwrap();
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let scroll: Scroll = sent(&out[0], MessageType::Scroll);
+        assert_eq!((scroll.delta, scroll.unit), (1, ScrollUnit::Page));
+
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let scroll: Scroll = sent(&out[0], MessageType::Scroll);
+        assert_eq!(scroll.delta, 1);
+
+        // A frame that never comes does not hold scrolling back for good.
+        app.handle_event(key(KeyCode::PageDown), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+        app.viewport_sent = Some(Instant::now() - VIEWPORT_TIMEOUT);
+        app.tick(&mut tui).unwrap();
+        let scroll: Scroll = sent(&app.take_outbox()[0], MessageType::Scroll);
+        assert_eq!(scroll.delta, 1);
+    }
+
+    #[test]
+    fn shows_the_round_trip_of_pings() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        tui.set_status_layout(StatusLayout {
+            left: "{rtt}".into(),
+            right: String::new(),
+        });
+        app.handle_message(&frame(0, 0), &mut tui).unwrap();
+        app.tick(&mut tui).unwrap();
+        let out = app.take_outbox();
+        sent::<()>(&out[0], MessageType::Ping);
+        // One Ping at a time.
+        app.tick(&mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        app.handle_message(&message(MessageType::Pong, ()), &mut tui)
+            .unwrap();
+        let buffer = tui.backend().buffer().clone();
+        let status: String = (0..20).map(|x| buffer[(x, 4)].symbol()).collect();
+        assert!(status.trim_end().ends_with(" ms"), "{status:?}");
+        app.disconnected();
+        assert_eq!(app.rtt, None);
+    }
+
+    #[test]
+    fn echoes_typing_until_a_frame_shows_it() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let text_frame = |doc_v, text: &str, col| {
+            let mut status = crate::status::tests::status();
+            status.selection = Some(Range {
+                from: col as u64,
+                to: col as u64,
+            });
+            let frame = Frame {
+                id: "editor".into(),
+                kind: "editor".into(),
+                doc_v,
+                first_line: 0,
+                first_col: 0,
+                cols: 20,
+                rows: 4,
+                lines: vec![ghostwriter_proto::Line {
+                    text: text.into(),
+                    spans: Vec::new(),
+                    more: false,
+                    marked: false,
+                }],
+                cursors: vec![ghostwriter_proto::Cursor::new(0, col)],
+                status_left: String::new(),
+                status_right: String::new(),
+                status: Some(status),
+                classes: Vec::new(),
+                checksum: None,
+            };
+            message(MessageType::Frame, frame)
+        };
+        let first_row = |tui: &mut Tui<TestBackend>| {
+            let buffer = tui.backend().buffer().clone();
+            let row = (0..20).map(|x| buffer[(x, 0)].symbol()).collect::<String>();
+            row.trim_end().to_string()
+        };
+        app.handle_message(&text_frame(1, "hello", 2), &mut tui)
+            .unwrap();
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Char('y')), &mut tui).unwrap();
+        assert_eq!(first_row(&mut tui), "hexyllo");
+
+        let ack = message(MessageType::Ack, Ack { seq: 1, doc_v: 2 });
+        app.handle_message(&ack, &mut tui).unwrap();
+        app.handle_message(&text_frame(1, "hello", 2), &mut tui)
+            .unwrap();
+        assert_eq!(first_row(&mut tui), "hexyllo");
+        app.handle_message(&text_frame(2, "hexllo", 3), &mut tui)
+            .unwrap();
+        assert_eq!(first_row(&mut tui), "hexyllo");
+
+        // A refused insert is taken back until it is typed again.
+        let refused = ErrorMsg::new(ErrorCode::Conflict, "changed").with_seq(2);
+        app.handle_message(&message(MessageType::Error, refused), &mut tui)
+            .unwrap();
+        assert_eq!(first_row(&mut tui), "hexllo");
+    }
+
+    #[test]
+    fn places_the_cursor_and_scrolls_with_the_mouse() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let mut text = decode::<Frame>(&frame(0, 0)).unwrap().data;
+        text.first_line = 10;
+        text.lines = vec![ghostwriter_proto::Line {
+            text: "héllo".into(),
+            spans: Vec::new(),
+            more: false,
+            marked: false,
+        }];
+        app.handle_message(&message(MessageType::Frame, &text), &mut tui)
+            .unwrap();
+        let mouse = |kind, column, row| {
+            Event::Mouse(MouseEvent {
+                kind,
+                column,
+                row,
+                modifiers: KeyModifiers::NONE,
+            })
+        };
+        let click = MouseEventKind::Down(MouseButton::Left);
+        app.handle_event(mouse(click, 2, 0), &mut tui).unwrap();
+        let drag = MouseEventKind::Drag(MouseButton::Left);
+        app.handle_event(mouse(drag, 9, 0), &mut tui).unwrap();
+        let alt_drag = MouseEvent {
+            kind: drag,
+            column: 4,
+            row: 0,
+            modifiers: KeyModifiers::ALT,
+        };
+        app.handle_event(Event::Mouse(alt_drag), &mut tui).unwrap();
+        // Below the text nothing moves.
+        app.handle_event(mouse(click, 2, 3), &mut tui).unwrap();
+        let gotos: Vec<GotoLine> = (app.take_outbox().iter())
+            .map(|data| sent(data, MessageType::GotoLine))
+            .collect();
+        let goto = |col, extend, block| GotoLine {
+            line: 10,
+            col,
+            extend,
+            block,
+        };
+        assert_eq!(
+            gotos,
+            [
+                goto(3, false, false),
+                goto(6, true, false),
+                goto(5, true, true)
+            ]
+        );
+
+        for kind in [MouseEventKind::ScrollDown, MouseEventKind::ScrollDown] {
+            app.handle_event(mouse(kind, 0, 0), &mut tui).unwrap();
+        }
+        let out = app.take_outbox();
+        assert_eq!(out.len(), 1);
+        let scroll: Scroll = sent(&out[0], MessageType::Scroll);
+        assert_eq!((scroll.delta, scroll.unit), (WHEEL_LINES, ScrollUnit::Line));
+        app.handle_message(&message(MessageType::Frame, &text), &mut tui)
+            .unwrap();
+        let scroll: Scroll = sent(&app.take_outbox()[0], MessageType::Scroll);
+        assert_eq!(scroll.delta, WHEEL_LINES);
+
+        // Clicks in the picker pick the entry under them.
+        let list = DirList {
+            path: String::new(),
+            query: None,
+            entries: vec![DirEntry {
+                path: "a.txt".into(),
+                is_dir: false,
+            }],
+        };
+        app.handle_message(&message(MessageType::DirList, list), &mut tui)
+            .unwrap();
+        app.handle_event(mouse(click, 4, 2), &mut tui).unwrap();
+        app.handle_event(mouse(click, 4, 2), &mut tui).unwrap();
+        let open: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        let path = "a.txt".into();
+        assert_eq!(open, PickerAction::Open { path });
+    }
+
+    #[test]
+    fn manages_files_from_the_picker() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let entry = |path: &str, is_dir| DirEntry {
+            path: path.into(),
+            is_dir,
+        };
+        let list = DirList {
+            path: String::new(),
+            query: None,
+            entries: vec![entry("src", true)],
+        };
+        app.handle_message(&message(MessageType::DirList, list), &mut tui)
+            .unwrap();
+        for code in [KeyCode::Char('n'), KeyCode::Char('a'), KeyCode::Enter] {
+            app.handle_event(key(code), &mut tui).unwrap();
+        }
+        let create: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        let path = "src/a".into();
+        assert_eq!(create, PickerAction::Create { path, dir: false });
+
+        // Cancelling a prompt sends nothing.
+        app.handle_event(key(KeyCode::Delete), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Esc), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+        app.handle_event(key(KeyCode::Delete), &mut tui).unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let delete: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        assert_eq!(delete, PickerAction::Delete { path: "src".into() });
+
+        // Opening the picker again lists the way down to the open file.
+        app.path = Some("src/bin/main.rs".into());
+        app.picker = None;
+        let open = KeyEvent::new(KeyCode::Char('o'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(open), &mut tui).unwrap();
+        let listed: Vec<String> = (app.take_outbox().iter())
+            .map(|data| match sent(data, MessageType::PickerAction) {
+                PickerAction::Expand { path } => path,
+                other => panic!("expected expand, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(listed, ["", "src", "src/bin"]);
+    }
+
+    #[test]
+    fn reattaches_after_reconnecting() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.connected().unwrap();
+        let expand: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        assert_eq!(expand, PickerAction::Expand { path: "".into() });
+        let list = DirList {
+            path: String::new(),
+            query: None,
+            entries: vec![DirEntry {
+                path: "a.txt".into(),
+                is_dir: false,
+            }],
+        };
+        app.handle_message(&message(MessageType::DirList, list), &mut tui)
+            .unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        let open: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        assert_eq!(
+            open,
+            PickerAction::Open {
+                path: "a.txt".into()
+            }
+        );
+
+        app.disconnected();
+        app.connected().unwrap();
+        let _: () = sent(&app.take_outbox()[0], MessageType::ListSessions);
+        let session = |id, path: &str, attached| SessionInfo {
+            id,
+            path: path.into(),
+            attached,
+        };
+        let sessions = vec![session(1, "a.txt", true)];
+        let list = message(MessageType::SessionList, SessionList { sessions });
+        app.handle_message(&list, &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+        let sessions = vec![session(2, "b.txt", false), session(1, "a.txt", false)];
+        let list = message(MessageType::SessionList, SessionList { sessions });
+        app.handle_message(&list, &mut tui).unwrap();
+        let attach: Attach = sent(&app.take_outbox()[0], MessageType::Attach);
+        assert_eq!(attach.id, 1);
+
+        app.disconnected();
+        app.connected().unwrap();
+        app.take_outbox();
+        let sessions = Vec::new();
+        let list = message(MessageType::SessionList, SessionList { sessions });
+        app.handle_message(&list, &mut tui).unwrap();
+        let open: PickerAction = sent(&app.take_outbox()[0], MessageType::PickerAction);
+        assert_eq!(
+            open,
+            PickerAction::Open {
+                path: "a.txt".into()
+            }
+        );
+    }
+
+    #[test]
+    fn is_read_only_until_the_session_resumes() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        app.disconnected();
+        tui.set_connection(ConnectionState::Reconnecting).unwrap();
+        let banner = |tui: &mut Tui<TestBackend>| {
+            let buffer = tui.backend().buffer().clone();
+            (0..20).map(|x| buffer[(x, 0)].symbol()).collect::<String>()
+        };
+        assert!(banner(&mut tui).contains("reconnecting"));
+
+        app.connected().unwrap();
+        app.take_outbox();
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        let save = Event::Key(KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL));
+        app.handle_event(save, &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+
+        app.handle_message(&frame(2, 2), &mut tui).unwrap();
+        assert!(!banner(&mut tui).contains("reconnecting"));
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        let insert: Insert = sent(&app.take_outbox()[0], MessageType::Insert);
+        assert_eq!(insert.text, "x");
+    }
+
+    #[test]
+    fn stops_on_errors_reconnecting_cannot_fix() {
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let err = ErrorMsg::new(ErrorCode::NotFound, "gone");
+        app.handle_message(&message(MessageType::Error, err), &mut tui)
+            .unwrap();
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        assert!(app.take_outbox().is_empty());
+        let err = ErrorMsg::new(ErrorCode::Unauthorized, "unauthorized");
+        let msg = message(MessageType::Error, err);
+        assert!(app.handle_message(&msg, &mut tui).is_err());
+    }
+
+    /// Handle messages from `conn` until `done` holds.
+    async fn pump<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+        app: &mut App,
+        tui: &mut Tui<TestBackend>,
+        conn: &mut WsClient<S>,
+        done: impl Fn(&App) -> bool,
+    ) {
+        let run = async {
+            while !done(app) {
+                for data in app.take_outbox() {
+                    conn.queue_encoded(data);
+                }
+                conn.flush().await.unwrap();
+                let data = conn.recv().await.unwrap().unwrap();
+                app.handle_message(&data, tui).unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn edits_a_file_opened_in_the_picker() {
+        use ghostwriter_server::{acceptor, workspace::Workspace};
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "hi").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let workspace = Workspace::new(dir.path()).unwrap();
+        tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let mut conn = WsClient::connect_as(&url, 20, 4, None, Role::Editor, None)
+            .await
+            .unwrap();
+        app.connected().unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| app.picker.is_some()).await;
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| app.selection.is_some()).await;
+        assert_eq!(app.path(), Some("a.txt"));
+
+        app.handle_event(key(KeyCode::Right), &mut tui).unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| {
+            app.selection == Some(Range { from: 1, to: 1 })
+        })
+        .await;
+        app.handle_event(key(KeyCode::Char('x')), &mut tui).unwrap();
+        let save = KeyEvent::new(KeyCode::Char('s'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(save), &mut tui).unwrap();
+        for data in app.take_outbox() {
+            conn.queue_encoded(data);
+        }
+        conn.close().await.unwrap();
+
+        let saved = async {
+            while std::fs::read_to_string(&file).unwrap() != "hxi" {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), saved)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn opens_search_matches_at_their_line() {
+        use ghostwriter_server::{acceptor, workspace::Workspace};
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "four\n").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let workspace = Workspace::new(dir.path()).unwrap();
+        tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let mut conn = WsClient::connect_as(&url, 20, 4, None, Role::Editor, None)
+            .await
+            .unwrap();
+        app.connected().unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| app.picker.is_some()).await;
+        let search = KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(search), &mut tui).unwrap();
+        for c in "thr".chars() {
+            app.handle_event(key(KeyCode::Char(c)), &mut tui).unwrap();
+        }
+        pump(&mut app, &mut tui, &mut conn, |app| {
+            app.search.as_ref().is_some_and(|view| view.done)
+        })
+        .await;
+        let view = app.search.as_ref().unwrap();
+        assert_eq!(view.matches.len(), 1);
+        assert_eq!(
+            (view.matches[0].path.as_str(), view.matches[0].line),
+            ("a.txt", 2)
+        );
+
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        assert!(app.search.is_none() && app.picker.is_none());
+        pump(&mut app, &mut tui, &mut conn, |app| {
+            app.selection == Some(Range { from: 8, to: 8 })
+        })
+        .await;
+        assert_eq!(app.path(), Some("a.txt"));
+    }
+
+    #[tokio::test]
+    async fn lists_bookmarks_and_opens_them() {
+        use ghostwriter_server::{acceptor, workspace::Workspace};
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let workspace = Workspace::new(dir.path()).unwrap();
+        tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+
+        let (mut app, mut tui) = (App::new(Role::Editor, None), tui());
+        let mut conn = WsClient::connect_as(&url, 20, 4, None, Role::Editor, None)
+            .await
+            .unwrap();
+        app.connected().unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| app.picker.is_some()).await;
+        app.picker = None;
+        app.open_at("a.txt".into(), 1).unwrap();
+        let at = |from| move |app: &App| app.selection == Some(Range { from, to: from });
+        pump(&mut app, &mut tui, &mut conn, at(4)).await;
+        let toggle = KeyEvent::new(KeyCode::F(2), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(toggle), &mut tui).unwrap();
+        app.open_at("a.txt".into(), 0).unwrap();
+        pump(&mut app, &mut tui, &mut conn, at(0)).await;
+
+        let list = KeyEvent::new(KeyCode::Char('b'), KeyModifiers::CONTROL);
+        app.handle_event(Event::Key(list), &mut tui).unwrap();
+        pump(&mut app, &mut tui, &mut conn, |app| app.bookmarks.is_some()).await;
+        let marks = &app.bookmarks.as_ref().unwrap().marks;
+        let marks: Vec<_> = (marks.iter())
+            .map(|m| (m.path.as_str(), m.line, m.text.as_str()))
+            .collect();
+        assert_eq!(marks, [("a.txt", 1, "two")]);
+        app.handle_event(key(KeyCode::Enter), &mut tui).unwrap();
+        assert!(app.bookmarks.is_none());
+        pump(&mut app, &mut tui, &mut conn, at(4)).await;
+    }
+}
@@ -0,0 +1,94 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ghostwriter_proto::{BookmarkList, Mark};
+
+/// What the user did in a [`BookmarkView`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BookmarkChoice {
+    /// Open `path` at the zero-based `line`.
+    Open { path: String, line: u64 },
+    /// Close the list.
+    Cancel,
+}
+
+/// Client-side list of the bookmarks in the files opened, shown over the
+/// editor until one is picked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookmarkView {
+    pub marks: Vec<Mark>,
+    /// Index of the highlighted bookmark.
+    pub selected: usize,
+}
+
+impl BookmarkView {
+    pub fn new(list: BookmarkList) -> Self {
+        BookmarkView {
+            marks: list.marks,
+            selected: 0,
+        }
+    }
+
+    /// Handle a key press. Up and Down move through the bookmarks, Enter
+    /// opens one and Esc closes the list.
+    pub fn handle_key(&mut self, ev: KeyEvent) -> Option<BookmarkChoice> {
+        let count = self.marks.len();
+        match ev.code {
+            KeyCode::Up if count > 0 => {
+                self.selected = (self.selected + count - 1) % count;
+                None
+            }
+            KeyCode::Down if count > 0 => {
+                self.selected = (self.selected + 1) % count;
+                None
+            }
+            KeyCode::Enter => (self.marks.get(self.selected)).map(|m| BookmarkChoice::Open {
+                path: m.path.clone(),
+                line: m.line,
+            }),
+            KeyCode::Esc => Some(BookmarkChoice::Cancel),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn mark(path: &str, line: u64) -> Mark {
+        Mark {
+            path: path.into(),
+            line,
+            text: "fn main() {}".into(),
+        }
+    }
+
+    #[test]
+    fn opens_the_highlighted_bookmark() {
+        let mut view = BookmarkView::new(BookmarkList {
+            marks: vec![mark("a.rs", 3), mark("b.rs", 0)],
+        });
+        assert_eq!(view.handle_key(key(KeyCode::Up)), None);
+        assert_eq!(view.selected, 1);
+        assert_eq!(view.handle_key(key(KeyCode::Char('x'))), None);
+        let open = BookmarkChoice::Open {
+            path: "b.rs".into(),
+            line: 0,
+        };
+        assert_eq!(view.handle_key(key(KeyCode::Enter)), Some(open));
+        view.handle_key(key(KeyCode::Down));
+        assert_eq!(view.selected, 0);
+        assert_eq!(
+            view.handle_key(key(KeyCode::Esc)),
+            Some(BookmarkChoice::Cancel)
+        );
+
+        let mut empty = BookmarkView::default();
+        assert_eq!(empty.handle_key(key(KeyCode::Down)), None);
+        assert_eq!(empty.handle_key(key(KeyCode::Enter)), None);
+    }
+}
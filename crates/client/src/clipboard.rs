@@ -0,0 +1,94 @@
+//! Copying to the user's own clipboard through the terminal with OSC 52,
+//! which reaches it over SSH and through tmux or screen.
+
+use anyhow::{Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// Most bytes of text copied at once; many terminals drop OSC 52
+/// sequences carrying more than 100 000 bytes of base64.
+pub const MAX_COPY: usize = 74_994;
+
+/// Bytes of a sequence screen passes through in one DCS string.
+const SCREEN_CHUNK: usize = 76;
+
+/// Terminal multiplexer between the client and the terminal, which passes
+/// OSC 52 on only when wrapped for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mux {
+    None,
+    Tmux,
+    Screen,
+}
+
+/// How to reach the clipboard of a terminal of type `term`, given the
+/// `$TMUX` and `$STY` variables tmux and screen set, or `None` if the
+/// terminal is not expected to take OSC 52 writes.
+pub fn detect(term: Option<&str>, tmux: Option<&str>, sty: Option<&str>) -> Option<Mux> {
+    let term = term.filter(|term| !matches!(*term, "" | "dumb" | "linux"))?;
+    Some(if tmux.is_some_and(|v| !v.is_empty()) {
+        Mux::Tmux
+    } else if sty.is_some_and(|v| !v.is_empty()) || term.starts_with("screen") {
+        Mux::Screen
+    } else {
+        Mux::None
+    })
+}
+
+/// [`detect`] for the terminal the client runs in.
+pub fn detect_terminal() -> Option<Mux> {
+    let var = |name| std::env::var(name).ok();
+    detect(
+        var("TERM").as_deref(),
+        var("TMUX").as_deref(),
+        var("STY").as_deref(),
+    )
+}
+
+/// Escape sequence putting `text` on the clipboard through `mux`. Fails
+/// for text over [`MAX_COPY`] bytes.
+pub fn osc52(text: &str, mux: Mux) -> Result<String> {
+    if text.len() > MAX_COPY {
+        bail!(
+            "the selection is too large to copy ({} bytes, at most {MAX_COPY})",
+            text.len()
+        );
+    }
+    let seq = format!("\x1b]52;c;{}\x07", STANDARD.encode(text));
+    Ok(match mux {
+        Mux::None => seq,
+        Mux::Tmux => format!("\x1bPtmux;{}\x1b\\", seq.replace('\x1b', "\x1b\x1b")),
+        Mux::Screen => (seq.as_bytes().chunks(SCREEN_CHUNK))
+            .map(|chunk| format!("\x1bP{}\x1b\\", String::from_utf8_lossy(chunk)))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_terminals_and_multiplexers() {
+        assert_eq!(detect(Some("xterm-256color"), None, None), Some(Mux::None));
+        assert_eq!(
+            detect(Some("tmux-256color"), Some("/tmp/tmux-0/default,1,0"), None),
+            Some(Mux::Tmux)
+        );
+        assert_eq!(detect(Some("screen"), None, None), Some(Mux::Screen));
+        assert_eq!(detect(Some("linux"), None, None), None);
+        assert_eq!(detect(None, Some("x"), None), None);
+    }
+
+    #[test]
+    fn wraps_the_sequence_for_multiplexers() {
+        assert_eq!(osc52("hi", Mux::None).unwrap(), "\x1b]52;c;aGk=\x07");
+        assert_eq!(
+            osc52("hi", Mux::Tmux).unwrap(),
+            "\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\"
+        );
+        let screen = osc52(&"x".repeat(100), Mux::Screen).unwrap();
+        assert_eq!(screen.matches("\x1bP").count(), 2);
+        assert!(osc52(&"x".repeat(MAX_COPY + 1), Mux::None).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ghostwriter_proto::{Dialog, DialogResult};
+
+/// Client-side state of a server-driven [`Dialog`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DialogView {
+    pub dialog: Dialog,
+    /// Index of the focused button.
+    pub selected: usize,
+    /// Current text input, if the dialog has one.
+    pub input: Option<String>,
+    /// Earlier answers Up and Down recall into the input, most recent
+    /// first.
+    pub history: Vec<String>,
+    /// Index in `history` of the answer in the input, with what was typed
+    /// before recalling it.
+    recalled: Option<(usize, String)>,
+}
+
+impl DialogView {
+    /// Start showing `dialog` with the first button focused.
+    pub fn new(dialog: Dialog) -> Self {
+        let input = dialog.input.clone();
+        Self {
+            dialog,
+            selected: 0,
+            input,
+            history: Vec::new(),
+            recalled: None,
+        }
+    }
+
+    /// This view with `history` to recall into its input.
+    pub fn with_history(mut self, history: Vec<String>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Handle a key press, returning the result once the dialog is answered
+    /// (Enter) or dismissed (Esc).
+    pub fn handle_key(&mut self, ev: KeyEvent) -> Option<DialogResult> {
+        let buttons = self.dialog.buttons.len();
+        match ev.code {
+            KeyCode::Left | KeyCode::BackTab if buttons > 0 => {
+                self.selected = (self.selected + buttons - 1) % buttons;
+                None
+            }
+            KeyCode::Right | KeyCode::Tab if buttons > 0 => {
+                self.selected = (self.selected + 1) % buttons;
+                None
+            }
+            KeyCode::Enter => {
+                Some(self.result(self.dialog.buttons.get(self.selected).map(|b| b.id.clone())))
+            }
+            KeyCode::Esc => Some(self.result(None)),
+            KeyCode::Up => {
+                self.recall(true);
+                None
+            }
+            KeyCode::Down => {
+                self.recall(false);
+                None
+            }
+            KeyCode::Backspace => {
+                if let Some(input) = &mut self.input {
+                    input.pop();
+                }
+                None
+            }
+            KeyCode::Char(c)
+                if !ev
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                if let Some(input) = &mut self.input {
+                    input.push(c);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Add pasted `text` to the input, up to its first line break.
+    pub fn paste(&mut self, text: &str) {
+        if let Some(input) = &mut self.input {
+            input.push_str(text.lines().next().unwrap_or_default());
+        }
+    }
+
+    /// Put the next `older` or newer answer from the history in the input;
+    /// going past the most recent brings back what was typed. Answers the
+    /// same as the input when recalling starts are skipped.
+    fn recall(&mut self, older: bool) {
+        let Some(input) = &mut self.input else {
+            return;
+        };
+        let next = match (&self.recalled, older) {
+            (None, true) => match self.history.iter().position(|e| e != input) {
+                Some(i) => i,
+                None => return,
+            },
+            (Some((i, _)), true) => i + 1,
+            (Some((0, _)), false) => {
+                *input = self.recalled.take().unwrap().1;
+                return;
+            }
+            (Some((i, _)), false) => i - 1,
+            (None, false) => return,
+        };
+        let Some(entry) = self.history.get(next) else {
+            return;
+        };
+        let typed = match self.recalled.take() {
+            Some((_, typed)) => typed,
+            None => input.clone(),
+        };
+        *input = entry.clone();
+        self.recalled = Some((next, typed));
+    }
+
+    fn result(&self, button: Option<String>) -> DialogResult {
+        DialogResult {
+            id: self.dialog.id.clone(),
+            button,
+            input: self.input.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostwriter_proto::DialogButton;
+
+    fn dialog(input: Option<&str>) -> Dialog {
+        Dialog {
+            id: "d".into(),
+            title: "T".into(),
+            body: "B".into(),
+            buttons: vec![
+                DialogButton {
+                    id: "yes".into(),
+                    label: "Yes".into(),
+                },
+                DialogButton {
+                    id: "no".into(),
+                    label: "No".into(),
+                },
+            ],
+            input: input.map(Into::into),
+        }
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn navigates_buttons_and_answers() {
+        let mut view = DialogView::new(dialog(None));
+        assert_eq!(view.handle_key(key(KeyCode::Right)), None);
+        assert_eq!(view.selected, 1);
+        assert_eq!(view.handle_key(key(KeyCode::Right)), None);
+        assert_eq!(view.selected, 0);
+        assert_eq!(view.handle_key(key(KeyCode::Left)), None);
+        let result = view.handle_key(key(KeyCode::Enter)).unwrap();
+        assert_eq!(result.button.as_deref(), Some("no"));
+        assert_eq!(result.input, None);
+    }
+
+    #[test]
+    fn edits_input_and_dismisses() {
+        let mut view = DialogView::new(dialog(Some("a.t")));
+        view.handle_key(key(KeyCode::Backspace));
+        view.handle_key(key(KeyCode::Char('x')));
+        view.handle_key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL));
+        let result = view.handle_key(key(KeyCode::Esc)).unwrap();
+        assert_eq!(result.button, None);
+        assert_eq!(result.input.as_deref(), Some("a.x"));
+    }
+
+    #[test]
+    fn recalls_earlier_answers() {
+        let history = vec!["typed".into(), "new".into(), "old".into()];
+        let mut view = DialogView::new(dialog(Some("typed"))).with_history(history);
+        let input = |view: &DialogView| view.input.clone().unwrap();
+        view.handle_key(key(KeyCode::Up));
+        assert_eq!(input(&view), "new");
+        view.handle_key(key(KeyCode::Up));
+        view.handle_key(key(KeyCode::Up));
+        assert_eq!(input(&view), "old");
+        view.handle_key(key(KeyCode::Down));
+        assert_eq!(input(&view), "new");
+        view.handle_key(key(KeyCode::Down));
+        assert_eq!(input(&view), "typed");
+        view.handle_key(key(KeyCode::Down));
+        assert_eq!(input(&view), "typed");
+    }
+}
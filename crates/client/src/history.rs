@@ -0,0 +1,105 @@
+//! Recent searches and replacements, recalled with Up and Down in the
+//! find and replace dialogs. Each ring is kept between runs as a file of
+//! one entry per line, most recent first, in the user's data directory.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Entries a [`Ring`] keeps; older ones are forgotten.
+pub const CAPACITY: usize = 100;
+
+/// Where the client keeps state between runs: `ghostwriter` in the
+/// platform's data directory, e.g. `~/.local/share/ghostwriter`.
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("ghostwriter"))
+}
+
+/// Recently used entries, most recent first and without repeats.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ring {
+    entries: VecDeque<String>,
+    /// File the ring is saved to after each change; `None` keeps it in
+    /// memory only.
+    path: Option<PathBuf>,
+}
+
+impl Ring {
+    /// The ring saved at `path`, or an empty one saved there from now on
+    /// if it cannot be read.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = fs::read_to_string(&path)
+            .map(|text| text.lines().take(CAPACITY).map(Into::into).collect())
+            .unwrap_or_default();
+        Self {
+            entries,
+            path: Some(path),
+        }
+    }
+
+    /// The ring named `name` in [`data_dir`], or one kept in memory if
+    /// there is no data directory.
+    pub fn load_named(name: &str) -> Self {
+        data_dir().map_or_else(Self::default, |dir| Self::load(dir.join(name)))
+    }
+
+    /// Entries, most recent first.
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// Make `entry` the most recent, forgetting the oldest past
+    /// [`CAPACITY`], and save the ring. Empty entries and ones spanning
+    /// lines are not kept.
+    pub fn push(&mut self, entry: &str) {
+        if entry.is_empty() || entry.contains('\n') {
+            return;
+        }
+        self.entries.retain(|e| e != entry);
+        self.entries.push_front(entry.into());
+        self.entries.truncate(CAPACITY);
+        if let Some(path) = &self.path
+            && let Err(err) = save(path, &self.entries)
+        {
+            tracing::warn!(path = %path.display(), "could not save history: {err}");
+        }
+    }
+}
+
+fn save(path: &Path, entries: &VecDeque<String>) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text: String = entries.iter().map(|e| format!("{e}\n")).collect();
+    fs::write(path, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_recent_entries_first_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("searches");
+        let mut ring = Ring::load(path.clone());
+        for entry in ["a", "b", "", "a", "c\nd"] {
+            ring.push(entry);
+        }
+        assert_eq!(ring.entries(), ["a", "b"]);
+        assert_eq!(Ring::load(path).entries(), ["a", "b"]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_entries() {
+        let mut ring = Ring::default();
+        for i in 0..=CAPACITY {
+            ring.push(&i.to_string());
+        }
+        let entries = ring.entries();
+        assert_eq!(entries.len(), CAPACITY);
+        assert_eq!(entries[0], CAPACITY.to_string());
+        assert_eq!(entries.last().unwrap(), "1");
+    }
+}
@@ -1,68 +1,393 @@
-use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+//! Turns key presses into editor commands. Commands without text, like
+//! saving or quitting, come from a [`Keymap`] the `[keys]` table of the
+//! config file can rebind; typing, deleting and moving are fixed.
 
-/// Direction for cursor movement or selection.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Direction {
-    Left,
-    Right,
-    Up,
-    Down,
-}
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ghostwriter_proto::{BookmarkAction, CursorTarget, Direction, Granularity, JumpDir, SearchDir};
 
 /// High-level editor command derived from a key event.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     /// Insert the given text at the cursor position.
     Insert(String),
-    /// Delete the character before the cursor.
-    DeletePrev,
-    /// Delete the character at the cursor.
-    DeleteNext,
-    /// Move the cursor in the given direction without modifying the selection.
-    Move(Direction),
-    /// Extend the selection in the given direction.
-    Select(Direction),
+    /// Expand the snippet named by the word before the cursor, or move to
+    /// the next tab stop of the one expanded, or else insert a tab (Tab);
+    /// with `back`, move to the previous tab stop (Shift+Tab).
+    Tab { back: bool },
+    /// Delete the selection or, if it is empty, from the cursor by one
+    /// step in the given direction (Backspace and Delete; with Ctrl or Alt
+    /// a word).
+    Delete(Direction, Granularity),
+    /// Move the cursor by one step without modifying the selection.
+    Move(Direction, Granularity),
+    /// Extend the selection by one step (the movement keys with Shift).
+    Select(Direction, Granularity),
+    /// Grow the rectangular selection by a line or character, a cursor on
+    /// each of its lines (Alt+Shift with the arrow keys).
+    SelectBlock(Direction),
+    /// Scroll the viewport by this many pages, negative up (PageUp and
+    /// PageDown).
+    Scroll(i64),
+    /// Write the file to disk (Ctrl+S).
+    Save,
+    /// Copy the selection to the user's clipboard (Ctrl+C).
+    Copy,
+    /// Revert the last edit (Ctrl+Z).
+    Undo,
+    /// Reapply the last undone edit (Ctrl+Y).
+    Redo,
+    /// Ask for text to find in the open file (Ctrl+F).
+    Find,
+    /// Select the next or previous match of the last search (F3 and
+    /// Shift+F3).
+    FindAgain(SearchDir),
+    /// Ask what to replace in the open file and with what (Ctrl+R).
+    Replace,
+    /// Turn highlighting every search match on or off (Alt+H).
+    ToggleMatches,
+    /// Add a cursor above or below the others (Ctrl+Alt+Up and
+    /// Ctrl+Alt+Down), or at the next occurrence of the selection (Ctrl+D).
+    AddCursor(CursorTarget),
+    /// Go back to a single cursor (Esc).
+    SingleCursor,
+    /// Return to where the cursor was before the last jump to a search
+    /// match, line or file (Alt+O), or forward again (Alt+I).
+    Jump(JumpDir),
+    /// Bookmark the cursor line or remove its bookmark (Ctrl+F2), go to
+    /// the next or previous bookmark (F2 and Shift+F2), or list them all
+    /// (Ctrl+B).
+    Bookmark(BookmarkAction),
+    /// Show the file picker (Ctrl+O).
+    OpenFile,
+    /// Search the contents of every workspace file (Ctrl+G).
+    SearchWorkspace,
+    /// Leave the editor (Ctrl+Q).
+    Quit,
 }
 
-/// Translate a crossterm [`KeyEvent`] into an editor [`Command`].
-///
-/// Returns `None` for keys that have no associated command.
-pub fn map_key_event(ev: KeyEvent) -> Option<Command> {
-    match ev.code {
-        KeyCode::Char(c) => {
-            if ev
-                .modifiers
-                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
-            {
-                None
-            } else {
-                Some(Command::Insert(c.to_string()))
+/// The commands a [`Keymap`] binds, by their name in the `[keys]` table,
+/// with their default keys.
+const ACTIONS: &[(&str, Command, &[&str])] = &[
+    ("save", Command::Save, &["ctrl+s"]),
+    ("quit", Command::Quit, &["ctrl+q"]),
+    ("undo", Command::Undo, &["ctrl+z"]),
+    ("redo", Command::Redo, &["ctrl+y"]),
+    ("copy", Command::Copy, &["ctrl+c"]),
+    ("find", Command::Find, &["ctrl+f"]),
+    ("find-next", Command::FindAgain(SearchDir::Next), &["f3"]),
+    (
+        "find-prev",
+        Command::FindAgain(SearchDir::Prev),
+        &["shift+f3"],
+    ),
+    ("replace", Command::Replace, &["ctrl+r"]),
+    ("highlight-matches", Command::ToggleMatches, &["alt+h"]),
+    (
+        "add-cursor-above",
+        Command::AddCursor(CursorTarget::Above),
+        &["ctrl+alt+up"],
+    ),
+    (
+        "add-cursor-below",
+        Command::AddCursor(CursorTarget::Below),
+        &["ctrl+alt+down"],
+    ),
+    (
+        "add-cursor-next-match",
+        Command::AddCursor(CursorTarget::NextMatch),
+        &["ctrl+d"],
+    ),
+    (
+        "select-block-up",
+        Command::SelectBlock(Direction::Up),
+        &["alt+shift+up"],
+    ),
+    (
+        "select-block-down",
+        Command::SelectBlock(Direction::Down),
+        &["alt+shift+down"],
+    ),
+    (
+        "select-block-left",
+        Command::SelectBlock(Direction::Left),
+        &["alt+shift+left"],
+    ),
+    (
+        "select-block-right",
+        Command::SelectBlock(Direction::Right),
+        &["alt+shift+right"],
+    ),
+    ("jump-back", Command::Jump(JumpDir::Back), &["alt+o"]),
+    ("jump-forward", Command::Jump(JumpDir::Forward), &["alt+i"]),
+    (
+        "toggle-bookmark",
+        Command::Bookmark(BookmarkAction::Toggle),
+        &["ctrl+f2"],
+    ),
+    (
+        "next-bookmark",
+        Command::Bookmark(BookmarkAction::Next),
+        &["f2"],
+    ),
+    (
+        "prev-bookmark",
+        Command::Bookmark(BookmarkAction::Prev),
+        &["shift+f2"],
+    ),
+    (
+        "list-bookmarks",
+        Command::Bookmark(BookmarkAction::List),
+        &["ctrl+b"],
+    ),
+    ("picker", Command::OpenFile, &["ctrl+o"]),
+    ("search", Command::SearchWorkspace, &["ctrl+g"]),
+    ("page-up", Command::Scroll(-1), &["pageup"]),
+    ("page-down", Command::Scroll(1), &["pagedown"]),
+];
+
+/// A key and the modifiers held with it, written like `ctrl+s`,
+/// `shift+f3` or `alt+pageup`. Letters are stored in lower case, with
+/// Shift among the modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl From<KeyEvent> for Chord {
+    fn from(ev: KeyEvent) -> Self {
+        let mut modifiers = ev.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT);
+        if ev.modifiers.contains(KeyModifiers::SHIFT) {
+            modifiers |= KeyModifiers::SHIFT;
+        }
+        let code = match ev.code {
+            KeyCode::Char(c) if c.is_uppercase() => {
+                modifiers |= KeyModifiers::SHIFT;
+                KeyCode::Char(c.to_lowercase().next().unwrap_or(c))
             }
+            KeyCode::BackTab => {
+                modifiers |= KeyModifiers::SHIFT;
+                KeyCode::Tab
+            }
+            code => code,
+        };
+        Self { code, modifiers }
+    }
+}
+
+/// Names of the keys that are not a single character.
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("enter", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("esc", KeyCode::Esc),
+    ("space", KeyCode::Char(' ')),
+    ("plus", KeyCode::Char('+')),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+];
+
+impl FromStr for Chord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let lower = s.to_lowercase();
+        let mut parts: Vec<&str> = lower.split('+').collect();
+        let key = parts.pop().unwrap_or_default();
+        let mut modifiers = KeyModifiers::NONE;
+        for part in parts {
+            modifiers |= match part {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => bail!("unknown modifier {part:?} in {s:?}; use ctrl, alt or shift"),
+            };
         }
-        KeyCode::Enter => Some(Command::Insert("\n".into())),
-        KeyCode::Tab => Some(Command::Insert("\t".into())),
-        KeyCode::Backspace => Some(Command::DeletePrev),
-        KeyCode::Delete => Some(Command::DeleteNext),
-        KeyCode::Left => Some(if ev.modifiers.contains(KeyModifiers::SHIFT) {
-            Command::Select(Direction::Left)
-        } else {
-            Command::Move(Direction::Left)
-        }),
-        KeyCode::Right => Some(if ev.modifiers.contains(KeyModifiers::SHIFT) {
-            Command::Select(Direction::Right)
-        } else {
-            Command::Move(Direction::Right)
-        }),
-        KeyCode::Up => Some(if ev.modifiers.contains(KeyModifiers::SHIFT) {
-            Command::Select(Direction::Up)
-        } else {
-            Command::Move(Direction::Up)
-        }),
-        KeyCode::Down => Some(if ev.modifiers.contains(KeyModifiers::SHIFT) {
-            Command::Select(Direction::Down)
+        let mut chars = key.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match KEY_NAMES.iter().find(|(name, _)| *name == key) {
+                Some((_, code)) => *code,
+                None => match key.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=24) => KeyCode::F(n),
+                    _ => bail!("unknown key {key:?} in {s:?}"),
+                },
+            },
+        };
+        Ok(Self { code, modifiers })
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "ctrl"),
+            (KeyModifiers::ALT, "alt"),
+            (KeyModifiers::SHIFT, "shift"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{name}+")?;
+            }
+        }
+        match self.code {
+            KeyCode::F(n) => write!(f, "f{n}"),
+            code => match KEY_NAMES.iter().find(|(_, c)| *c == code) {
+                Some((name, _)) => f.write_str(name),
+                None => match code {
+                    KeyCode::Char(c) => write!(f, "{c}"),
+                    code => write!(f, "{code:?}"),
+                },
+            },
+        }
+    }
+}
+
+/// The keys bound to each command of [`ACTIONS`], in its order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    keys: Vec<Vec<Chord>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let keys = ACTIONS
+            .iter()
+            .map(|(_, _, keys)| keys.iter().map(|key| key.parse().unwrap()).collect())
+            .collect();
+        Self { keys }
+    }
+}
+
+impl Keymap {
+    /// The default keys with the actions in `keys` bound to the chords
+    /// given instead; an empty list unbinds the action. Fails on unknown
+    /// actions or chords, and on a chord bound to two actions.
+    pub fn with_keys(keys: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        let mut keymap = Self::default();
+        for (action, chords) in keys {
+            let i = ACTIONS
+                .iter()
+                .position(|(name, _, _)| name == action)
+                .ok_or_else(|| anyhow!("unknown action {action:?} in [keys]"))?;
+            keymap.keys[i] = chords.iter().map(|c| c.parse()).collect::<Result<_>>()?;
+        }
+        for (i, chords) in keymap.keys.iter().enumerate() {
+            for chord in chords {
+                if let Some(j) = keymap.action(*chord).filter(|j| *j != i) {
+                    let (first, second) = (ACTIONS[i.min(j)].0, ACTIONS[i.max(j)].0);
+                    bail!("{chord} is bound to both {first} and {second}");
+                }
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Translate a crossterm [`KeyEvent`] into an editor [`Command`].
+    ///
+    /// Returns `None` for keys that have no associated command. Bound keys
+    /// win over typing and moving. Ctrl with a letter matches with or
+    /// without Shift, as terminals differ in reporting it.
+    pub fn command(&self, ev: KeyEvent) -> Option<Command> {
+        let chord = Chord::from(ev);
+        let unshifted = Chord {
+            modifiers: chord.modifiers - KeyModifiers::SHIFT,
+            ..chord
+        };
+        let ctrl_char = chord.modifiers.contains(KeyModifiers::CONTROL)
+            && matches!(chord.code, KeyCode::Char(_));
+        let action = self
+            .action(chord)
+            .or_else(|| ctrl_char.then(|| self.action(unshifted)).flatten());
+        match action {
+            Some(i) => Some(ACTIONS[i].1.clone()),
+            None => fixed_command(ev),
+        }
+    }
+
+    /// Index in [`ACTIONS`] of the action `chord` is bound to.
+    fn action(&self, chord: Chord) -> Option<usize> {
+        self.keys.iter().position(|keys| keys.contains(&chord))
+    }
+}
+
+/// The `[keys]` table binding what this keymap does, for `--dump-keys`.
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[keys]")?;
+        for ((name, _, _), keys) in ACTIONS.iter().zip(&self.keys) {
+            let keys: Vec<String> = keys.iter().map(|key| format!("\"{key}\"")).collect();
+            writeln!(f, "{name} = [{}]", keys.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The commands of keys a [`Keymap`] does not bind. Covers what common
+/// terminals send instead of the keys themselves: Ctrl+H for Backspace,
+/// Ctrl+J for Enter, Alt+B and Alt+F for Option+Left and Option+Right on
+/// macOS, and Ctrl+Alt for AltGr on Windows. Esc drops added cursors
+/// unless bound to something else.
+fn fixed_command(ev: KeyEvent) -> Option<Command> {
+    let ctrl = ev.modifiers.contains(KeyModifiers::CONTROL);
+    let alt = ev.modifiers.contains(KeyModifiers::ALT);
+    let shift = ev.modifiers.contains(KeyModifiers::SHIFT);
+    let step = |dir, granularity| {
+        Some(if shift {
+            Command::Select(dir, granularity)
         } else {
-            Command::Move(Direction::Down)
-        }),
+            Command::Move(dir, granularity)
+        })
+    };
+    match ev.code {
+        KeyCode::Char(c) if ctrl && alt => Some(Command::Insert(c.to_string())),
+        KeyCode::Char(c) if ctrl => match c.to_ascii_lowercase() {
+            'h' => Some(Command::Delete(Direction::Left, Granularity::Grapheme)),
+            'j' => Some(Command::Insert("\n".into())),
+            _ => None,
+        },
+        KeyCode::Char(c) if alt => match c.to_ascii_lowercase() {
+            'b' => step(Direction::Left, Granularity::Word),
+            'f' => step(Direction::Right, Granularity::Word),
+            _ => None,
+        },
+        KeyCode::Char(c) => Some(Command::Insert(c.to_string())),
+        KeyCode::Enter => Some(Command::Insert("\n".into())),
+        KeyCode::Tab => Some(Command::Tab { back: false }),
+        KeyCode::BackTab => Some(Command::Tab { back: true }),
+        KeyCode::Backspace if ctrl || alt => {
+            Some(Command::Delete(Direction::Left, Granularity::Word))
+        }
+        KeyCode::Backspace => Some(Command::Delete(Direction::Left, Granularity::Grapheme)),
+        KeyCode::Delete if ctrl || alt => {
+            Some(Command::Delete(Direction::Right, Granularity::Word))
+        }
+        KeyCode::Delete => Some(Command::Delete(Direction::Right, Granularity::Grapheme)),
+        KeyCode::Left if ctrl || alt => step(Direction::Left, Granularity::Word),
+        KeyCode::Right if ctrl || alt => step(Direction::Right, Granularity::Word),
+        KeyCode::Left => step(Direction::Left, Granularity::Grapheme),
+        KeyCode::Right => step(Direction::Right, Granularity::Grapheme),
+        KeyCode::Up => step(Direction::Up, Granularity::Grapheme),
+        KeyCode::Down => step(Direction::Down, Granularity::Grapheme),
+        KeyCode::Home if ctrl => step(Direction::Left, Granularity::Document),
+        KeyCode::End if ctrl => step(Direction::Right, Granularity::Document),
+        KeyCode::Home => step(Direction::Left, Granularity::Line),
+        KeyCode::End => step(Direction::Right, Granularity::Line),
+        KeyCode::PageUp if shift => step(Direction::Up, Granularity::Page),
+        KeyCode::PageDown if shift => step(Direction::Down, Granularity::Page),
+        KeyCode::Esc => Some(Command::SingleCursor),
         _ => None,
     }
 }
@@ -70,34 +395,204 @@ pub fn map_key_event(ev: KeyEvent) -> Option<Command> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use Direction::*;
+    use Granularity::*;
+
+    const NONE: KeyModifiers = KeyModifiers::NONE;
+    const CTRL: KeyModifiers = KeyModifiers::CONTROL;
+    const ALT: KeyModifiers = KeyModifiers::ALT;
+    const SHIFT: KeyModifiers = KeyModifiers::SHIFT;
+
+    fn insert(text: &str) -> Option<Command> {
+        Some(Command::Insert(text.into()))
+    }
+
+    #[test]
+    fn maps_keys_to_commands() {
+        let keymap = Keymap::default();
+        let cases = [
+            (KeyCode::Char('a'), NONE, insert("a")),
+            (KeyCode::Char('A'), SHIFT, insert("A")),
+            (KeyCode::Enter, NONE, insert("\n")),
+            (KeyCode::Tab, NONE, Some(Command::Tab { back: false })),
+            (KeyCode::BackTab, SHIFT, Some(Command::Tab { back: true })),
+            (
+                KeyCode::Backspace,
+                NONE,
+                Some(Command::Delete(Left, Grapheme)),
+            ),
+            (
+                KeyCode::Delete,
+                NONE,
+                Some(Command::Delete(Right, Grapheme)),
+            ),
+            (KeyCode::Backspace, CTRL, Some(Command::Delete(Left, Word))),
+            (KeyCode::Backspace, ALT, Some(Command::Delete(Left, Word))),
+            (KeyCode::Delete, CTRL, Some(Command::Delete(Right, Word))),
+            (KeyCode::Left, NONE, Some(Command::Move(Left, Grapheme))),
+            (KeyCode::Down, NONE, Some(Command::Move(Down, Grapheme))),
+            (KeyCode::Left, SHIFT, Some(Command::Select(Left, Grapheme))),
+            (KeyCode::Right, CTRL, Some(Command::Move(Right, Word))),
+            (
+                KeyCode::Left,
+                CTRL | SHIFT,
+                Some(Command::Select(Left, Word)),
+            ),
+            (KeyCode::Home, NONE, Some(Command::Move(Left, Line))),
+            (KeyCode::End, SHIFT, Some(Command::Select(Right, Line))),
+            (KeyCode::Home, CTRL, Some(Command::Move(Left, Document))),
+            (
+                KeyCode::End,
+                CTRL | SHIFT,
+                Some(Command::Select(Right, Document)),
+            ),
+            (KeyCode::PageDown, NONE, Some(Command::Scroll(1))),
+            (KeyCode::PageUp, NONE, Some(Command::Scroll(-1))),
+            (KeyCode::PageDown, SHIFT, Some(Command::Select(Down, Page))),
+            (KeyCode::Char('s'), CTRL, Some(Command::Save)),
+            (KeyCode::Char('S'), CTRL | SHIFT, Some(Command::Save)),
+            (KeyCode::Char('z'), CTRL, Some(Command::Undo)),
+            (KeyCode::Char('y'), CTRL, Some(Command::Redo)),
+            (KeyCode::Char('f'), CTRL, Some(Command::Find)),
+            (
+                KeyCode::F(3),
+                NONE,
+                Some(Command::FindAgain(SearchDir::Next)),
+            ),
+            (
+                KeyCode::F(3),
+                SHIFT,
+                Some(Command::FindAgain(SearchDir::Prev)),
+            ),
+            (KeyCode::Char('o'), CTRL, Some(Command::OpenFile)),
+            (KeyCode::Char('g'), CTRL, Some(Command::SearchWorkspace)),
+            (KeyCode::Char('q'), CTRL, Some(Command::Quit)),
+            (KeyCode::Char('x'), CTRL, None),
+            (KeyCode::Char('s'), ALT, None),
+            (
+                KeyCode::Char('d'),
+                CTRL,
+                Some(Command::AddCursor(CursorTarget::NextMatch)),
+            ),
+            (
+                KeyCode::Up,
+                CTRL | ALT,
+                Some(Command::AddCursor(CursorTarget::Above)),
+            ),
+            (KeyCode::Left, ALT | SHIFT, Some(Command::SelectBlock(Left))),
+            (KeyCode::Esc, NONE, Some(Command::SingleCursor)),
+            (KeyCode::Char('o'), ALT, Some(Command::Jump(JumpDir::Back))),
+            (
+                KeyCode::F(2),
+                CTRL,
+                Some(Command::Bookmark(BookmarkAction::Toggle)),
+            ),
+            (
+                KeyCode::Char('b'),
+                CTRL,
+                Some(Command::Bookmark(BookmarkAction::List)),
+            ),
+            (KeyCode::F(5), NONE, None),
+        ];
+        for (code, modifiers, command) in cases {
+            let ev = KeyEvent::new(code, modifiers);
+            assert_eq!(keymap.command(ev), command, "{code:?} with {modifiers:?}");
+        }
+    }
 
     #[test]
-    fn maps_char_to_insert() {
-        let ev = KeyEvent::new(KeyCode::Char('a'), KeyModifiers::NONE);
-        assert_eq!(map_key_event(ev), Some(Command::Insert("a".into())));
+    fn maps_what_terminals_send_instead() {
+        let keymap = Keymap::default();
+        let cases = [
+            // ^H and ^J from terminals without Backspace and Enter codes
+            (
+                KeyCode::Char('h'),
+                CTRL,
+                Some(Command::Delete(Left, Grapheme)),
+            ),
+            (KeyCode::Char('j'), CTRL, insert("\n")),
+            // Option+Left and Option+Right in macOS terminals
+            (KeyCode::Char('b'), ALT, Some(Command::Move(Left, Word))),
+            (
+                KeyCode::Char('F'),
+                ALT | SHIFT,
+                Some(Command::Select(Right, Word)),
+            ),
+            // AltGr on Windows
+            (KeyCode::Char('@'), CTRL | ALT, insert("@")),
+            (KeyCode::Char('q'), CTRL | ALT, insert("q")),
+        ];
+        for (code, modifiers, command) in cases {
+            let ev = KeyEvent::new(code, modifiers);
+            assert_eq!(keymap.command(ev), command, "{code:?} with {modifiers:?}");
+        }
     }
 
     #[test]
-    fn maps_enter_to_newline() {
-        let ev = KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE);
-        assert_eq!(map_key_event(ev), Some(Command::Insert("\n".into())));
+    fn parses_and_prints_chords() {
+        for (text, code, modifiers) in [
+            ("ctrl+s", KeyCode::Char('s'), CTRL),
+            ("Ctrl+Shift+S", KeyCode::Char('s'), CTRL | SHIFT),
+            ("shift+f3", KeyCode::F(3), SHIFT),
+            ("alt+pageup", KeyCode::PageUp, ALT),
+            ("ctrl+plus", KeyCode::Char('+'), CTRL),
+        ] {
+            let chord: Chord = text.parse().unwrap();
+            assert_eq!(chord, Chord { code, modifiers }, "{text}");
+            assert_eq!(chord.to_string(), text.to_lowercase());
+        }
+        for text in ["hyper+s", "ctrl+", "ctrl+nothing", "f25"] {
+            assert!(text.parse::<Chord>().is_err(), "{text}");
+        }
+        let ev = KeyEvent::new(KeyCode::Char('S'), CTRL);
+        assert_eq!(Chord::from(ev).to_string(), "ctrl+shift+s");
+    }
+
+    fn keys(pairs: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        let pairs = pairs.iter().map(|(action, chords)| {
+            let chords = chords.iter().map(|c| c.to_string()).collect();
+            (action.to_string(), chords)
+        });
+        pairs.collect()
     }
 
     #[test]
-    fn maps_backspace_to_delete_prev() {
-        let ev = KeyEvent::new(KeyCode::Backspace, KeyModifiers::NONE);
-        assert_eq!(map_key_event(ev), Some(Command::DeletePrev));
+    fn rebinds_actions() {
+        let keymap =
+            Keymap::with_keys(&keys(&[("quit", &["ctrl+w", "esc"]), ("undo", &[])])).unwrap();
+        let command = |code, modifiers| keymap.command(KeyEvent::new(code, modifiers));
+        assert_eq!(command(KeyCode::Char('w'), CTRL), Some(Command::Quit));
+        assert_eq!(command(KeyCode::Esc, NONE), Some(Command::Quit));
+        assert_eq!(command(KeyCode::Char('q'), CTRL), None);
+        assert_eq!(command(KeyCode::Char('z'), CTRL), None);
+        assert_eq!(command(KeyCode::Char('s'), CTRL), Some(Command::Save));
+        assert!(
+            keymap
+                .to_string()
+                .contains("quit = [\"ctrl+w\", \"esc\"]\nundo = []\n")
+        );
     }
 
     #[test]
-    fn maps_left_to_move_left() {
-        let ev = KeyEvent::new(KeyCode::Left, KeyModifiers::NONE);
-        assert_eq!(map_key_event(ev), Some(Command::Move(Direction::Left)));
+    fn rejects_conflicting_bindings() {
+        let err = Keymap::with_keys(&keys(&[("quit", &["ctrl+s"])])).unwrap_err();
+        assert_eq!(err.to_string(), "ctrl+s is bound to both save and quit");
+        let swapped = keys(&[("quit", &["ctrl+s"]), ("save", &["ctrl+q"])]);
+        assert!(Keymap::with_keys(&swapped).is_ok());
+        assert!(Keymap::with_keys(&keys(&[("exit", &["ctrl+w"])])).is_err());
+        assert!(Keymap::with_keys(&keys(&[("quit", &["ctrl+"])])).is_err());
     }
 
     #[test]
-    fn maps_shift_left_to_select_left() {
-        let ev = KeyEvent::new(KeyCode::Left, KeyModifiers::SHIFT);
-        assert_eq!(map_key_event(ev), Some(Command::Select(Direction::Left)));
+    fn dumps_the_default_keys() {
+        let dump = Keymap::default().to_string();
+        assert!(dump.starts_with("[keys]\nsave = [\"ctrl+s\"]\nquit = [\"ctrl+q\"]\n"));
+        assert!(dump.contains("find-prev = [\"shift+f3\"]\n"));
+        let mut table: toml::Table = toml::from_str(&dump).unwrap();
+        let toml::Value::Table(keys) = table.remove("keys").unwrap() else {
+            panic!("no [keys] table");
+        };
+        let keys = keys.try_into().unwrap();
+        assert_eq!(Keymap::with_keys(&keys).unwrap(), Keymap::default());
     }
 }
@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+/// Wait between the Pings that measure the round trip.
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Round trips slower than this mark the connection as degraded.
+pub const DEGRADED_RTT: Duration = Duration::from_millis(300);
+
+/// Round-trip figures shown in the status bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rtt {
+    /// Time the last Ping took to be answered.
+    pub rtt: Duration,
+    /// Smoothed difference between consecutive round trips, as RFC 3550
+    /// computes interarrival jitter.
+    pub jitter: Duration,
+    /// Whether the last round trip, or the Ping still unanswered, took
+    /// longer than [`DEGRADED_RTT`].
+    pub degraded: bool,
+}
+
+/// Round-trip times measured with Ping messages, which the server answers
+/// with Pong behind any frames it still has to send. Only one Ping is in
+/// flight at a time, so each Pong answers it.
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    /// When the unanswered Ping was sent.
+    waiting: Option<Instant>,
+    /// When the last Ping was sent.
+    last_ping: Option<Instant>,
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Latency {
+    /// Whether to send a Ping at `now`; if so it counts as sent.
+    pub fn ping(&mut self, now: Instant) -> bool {
+        let due = self.last_ping.is_none_or(|at| now >= at + PING_INTERVAL);
+        if self.waiting.is_some() || !due {
+            return false;
+        }
+        self.waiting = Some(now);
+        self.last_ping = Some(now);
+        true
+    }
+
+    /// Take in a Pong received at `now`.
+    pub fn pong(&mut self, now: Instant) {
+        let Some(sent) = self.waiting.take() else {
+            return;
+        };
+        let sample = now.saturating_duration_since(sent);
+        if let Some(rtt) = self.rtt {
+            self.jitter = (self.jitter * 15 + sample.abs_diff(rtt)) / 16;
+        }
+        self.rtt = Some(sample);
+    }
+
+    /// Round-trip figures at `now`, once a Pong has arrived.
+    pub fn rtt(&self, now: Instant) -> Option<Rtt> {
+        let rtt = self.rtt?;
+        let stalled = self
+            .waiting
+            .is_some_and(|at| now.saturating_duration_since(at) > DEGRADED_RTT);
+        Some(Rtt {
+            rtt,
+            jitter: self.jitter,
+            degraded: stalled || rtt > DEGRADED_RTT,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn measures_round_trips_and_jitter() {
+        let (mut latency, start) = (Latency::default(), Instant::now());
+        assert!(latency.ping(start));
+        assert!(!latency.ping(start + PING_INTERVAL));
+        assert_eq!(latency.rtt(start), None);
+        latency.pong(start + ms(40));
+        let rtt = latency.rtt(start + ms(40)).unwrap();
+        assert_eq!((rtt.rtt, rtt.jitter, rtt.degraded), (ms(40), ms(0), false));
+
+        assert!(!latency.ping(start + ms(100)));
+        let next = start + PING_INTERVAL;
+        assert!(latency.ping(next));
+        latency.pong(next + ms(72));
+        let rtt = latency.rtt(next + ms(72)).unwrap();
+        assert_eq!((rtt.rtt, rtt.jitter), (ms(72), ms(2)));
+        // A Pong nobody asked for is ignored.
+        latency.pong(next + ms(80));
+        assert_eq!(latency.rtt(next + ms(80)).unwrap().rtt, ms(72));
+    }
+
+    #[test]
+    fn flags_slow_and_stalled_round_trips() {
+        let (mut latency, start) = (Latency::default(), Instant::now());
+        latency.ping(start);
+        latency.pong(start + DEGRADED_RTT + ms(1));
+        assert!(latency.rtt(start + PING_INTERVAL).unwrap().degraded);
+
+        let next = start + PING_INTERVAL * 2;
+        latency.ping(next);
+        latency.pong(next + ms(20));
+        assert!(!latency.rtt(next + ms(20)).unwrap().degraded);
+        let last = next + PING_INTERVAL;
+        latency.ping(last);
+        assert!(!latency.rtt(last + DEGRADED_RTT).unwrap().degraded);
+        assert!(latency.rtt(last + DEGRADED_RTT * 2).unwrap().degraded);
+    }
+}
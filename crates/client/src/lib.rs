@@ -1,6 +1,18 @@
+pub mod app;
+pub mod bookmarks;
+pub mod clipboard;
+pub mod dialog;
+pub mod history;
 pub mod keymap;
+pub mod latency;
 pub mod local;
+pub mod picker;
 pub mod remote;
+pub mod search;
+pub mod secret;
+pub mod ssh;
+pub mod status;
+pub mod theme;
 pub mod tui;
 
 /// Client entry point.
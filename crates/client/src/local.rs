@@ -21,7 +21,12 @@ impl LocalClient {
         let _ = self
             .handle
             .cmd
-            .send(SessionCmd::Insert { text: text.into() })
+            .send(SessionCmd::Insert {
+                text: text.into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
             .await;
     }
 
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ghostwriter_proto::{DirEntry, DirList};
+
+/// What the user picked in a [`PickerView`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PickerChoice {
+    /// Open this file.
+    Open(String),
+    /// List this directory, which has not been listed yet.
+    Expand(String),
+    /// Ask for the name of a file, or a folder when `dir` is set, to create
+    /// in `parent`.
+    Create { parent: String, dir: bool },
+    /// Ask for a new name for this entry.
+    Rename(String),
+    /// Ask whether to delete this entry.
+    Delete(String),
+    /// Close the picker without choosing.
+    Cancel,
+}
+
+/// Entry shown on one row of a [`PickerView`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PickerRow<'a> {
+    pub entry: &'a DirEntry,
+    /// Number of directories above the entry.
+    pub depth: usize,
+    /// Whether the entry is a directory shown with its children.
+    pub expanded: bool,
+}
+
+/// Client-side file picker showing the workspace as a tree. Directories
+/// are listed by the server the first time they are expanded and kept
+/// until the picker closes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PickerView {
+    /// Listings received so far, by directory; `""` is the workspace root.
+    dirs: HashMap<String, Vec<DirEntry>>,
+    /// Directories shown with their children.
+    expanded: HashSet<String>,
+    /// Index of the highlighted row.
+    pub selected: usize,
+    /// Entry to highlight once it is listed.
+    focus: Option<String>,
+}
+
+impl PickerView {
+    /// Empty picker that highlights `focus`, if given, once it is listed.
+    pub fn new(focus: Option<String>) -> Self {
+        Self {
+            focus,
+            ..Self::default()
+        }
+    }
+
+    /// Highlight `path` once a listing shows it.
+    pub fn focus(&mut self, path: String) {
+        self.focus = Some(path);
+    }
+
+    /// Take in a listing from the server and show the directory expanded.
+    /// Search results are not part of the tree and are ignored.
+    pub fn update(&mut self, list: DirList) {
+        if list.query.is_some() {
+            return;
+        }
+        let highlighted = self.entry().map(|entry| entry.path.clone());
+        if !list.path.is_empty() {
+            self.expanded.insert(list.path.clone());
+        }
+        self.dirs.insert(list.path, list.entries);
+        let rows = self.rows();
+        let find = |path: &Option<String>| {
+            let path = path.as_deref()?;
+            rows.iter().position(|row| row.entry.path == path)
+        };
+        let (focused, kept, count) = (find(&self.focus), find(&highlighted), rows.len());
+        if focused.is_some() {
+            self.focus = None;
+        }
+        let selected = focused.or(kept).unwrap_or(self.selected);
+        self.selected = selected.min(count.saturating_sub(1));
+    }
+
+    /// Entries in display order: each expanded directory is followed by
+    /// its children.
+    pub fn rows(&self) -> Vec<PickerRow<'_>> {
+        let mut rows = Vec::new();
+        self.push_rows("", 0, &mut rows);
+        rows
+    }
+
+    fn push_rows<'a>(&'a self, dir: &str, depth: usize, rows: &mut Vec<PickerRow<'a>>) {
+        for entry in self.dirs.get(dir).into_iter().flatten() {
+            let expanded = entry.is_dir
+                && self.expanded.contains(&entry.path)
+                && self.dirs.contains_key(&entry.path);
+            rows.push(PickerRow {
+                entry,
+                depth,
+                expanded,
+            });
+            if expanded {
+                self.push_rows(&entry.path, depth + 1, rows);
+            }
+        }
+    }
+
+    /// Highlighted entry, if anything is listed.
+    pub fn entry(&self) -> Option<&DirEntry> {
+        self.rows().get(self.selected).map(|row| row.entry)
+    }
+
+    /// Handle a key press, returning a choice once the user makes one.
+    /// Enter opens a file or expands and collapses a directory, Right and
+    /// Left do the latter, `n`, `m`, `r` and Delete create a file, make a
+    /// folder, rename and delete, and Esc cancels.
+    pub fn handle_key(&mut self, ev: KeyEvent) -> Option<PickerChoice> {
+        let rows = self.rows();
+        let count = rows.len();
+        let row = (rows.get(self.selected)).map(|row| (row.entry.clone(), row.expanded));
+        if ev
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            return None;
+        }
+        match (ev.code, row) {
+            (KeyCode::Up, _) if count > 0 => {
+                self.selected = (self.selected + count - 1) % count;
+                None
+            }
+            (KeyCode::Down, _) if count > 0 => {
+                self.selected = (self.selected + 1) % count;
+                None
+            }
+            (KeyCode::Enter, Some((entry, _))) if !entry.is_dir => {
+                Some(PickerChoice::Open(entry.path))
+            }
+            (KeyCode::Enter, Some((entry, expanded))) => self.toggle(entry.path, expanded),
+            (KeyCode::Right, Some((entry, false))) if entry.is_dir => {
+                self.toggle(entry.path, false)
+            }
+            (KeyCode::Left | KeyCode::Backspace, Some((entry, true))) => {
+                self.toggle(entry.path, true)
+            }
+            (KeyCode::Left | KeyCode::Backspace, Some((entry, false))) => {
+                let parent = parent_of(&entry.path);
+                if let Some(idx) = self.rows().iter().position(|row| row.entry.path == parent) {
+                    self.selected = idx;
+                }
+                None
+            }
+            (KeyCode::Char(c @ ('n' | 'm')), row) => {
+                let parent = match row {
+                    Some((entry, _)) if entry.is_dir => entry.path,
+                    Some((entry, _)) => parent_of(&entry.path).to_string(),
+                    None => String::new(),
+                };
+                let dir = c == 'm';
+                Some(PickerChoice::Create { parent, dir })
+            }
+            (KeyCode::Char('r'), Some((entry, _))) => Some(PickerChoice::Rename(entry.path)),
+            (KeyCode::Delete, Some((entry, _))) => Some(PickerChoice::Delete(entry.path)),
+            (KeyCode::Esc, _) => Some(PickerChoice::Cancel),
+            _ => None,
+        }
+    }
+
+    /// Handle a click on row `idx`: highlight it, expand or collapse a
+    /// directory, and open a file that was highlighted already.
+    pub fn click(&mut self, idx: usize) -> Option<PickerChoice> {
+        let (entry, expanded) =
+            (self.rows().get(idx)).map(|row| (row.entry.clone(), row.expanded))?;
+        let again = std::mem::replace(&mut self.selected, idx) == idx;
+        if entry.is_dir {
+            self.toggle(entry.path, expanded)
+        } else if again {
+            Some(PickerChoice::Open(entry.path))
+        } else {
+            None
+        }
+    }
+
+    /// Move the highlight `delta` rows down, or up if negative, stopping
+    /// at the first and last row.
+    pub fn scroll(&mut self, delta: isize) {
+        let last = self.rows().len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Collapse directory `path` if `expanded`, otherwise expand it or ask
+    /// for its listing.
+    fn toggle(&mut self, path: String, expanded: bool) -> Option<PickerChoice> {
+        if expanded {
+            self.expanded.remove(&path);
+            None
+        } else if self.dirs.contains_key(&path) {
+            self.expanded.insert(path);
+            None
+        } else {
+            Some(PickerChoice::Expand(path))
+        }
+    }
+}
+
+/// Directory holding the workspace-relative `path`; `""` is the root.
+pub fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Workspace-relative path of `name` inside directory `dir`.
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn list(path: &str, entries: &[(&str, bool)]) -> DirList {
+        DirList {
+            path: path.into(),
+            query: None,
+            entries: (entries.iter())
+                .map(|&(path, is_dir)| DirEntry {
+                    path: path.into(),
+                    is_dir,
+                })
+                .collect(),
+        }
+    }
+
+    fn view() -> PickerView {
+        let mut view = PickerView::new(None);
+        view.update(list("", &[("src", true), ("README", false)]));
+        view
+    }
+
+    fn paths(view: &PickerView) -> Vec<&str> {
+        view.rows()
+            .iter()
+            .map(|row| row.entry.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn expands_directories_once_listed() {
+        let mut view = view();
+        let expand = PickerChoice::Expand("src".into());
+        assert_eq!(view.handle_key(key(KeyCode::Enter)), Some(expand));
+        view.update(list("src", &[("src/main.rs", false)]));
+        assert_eq!(paths(&view), ["src", "src/main.rs", "README"]);
+        assert_eq!(view.rows()[1].depth, 1);
+        assert_eq!(view.selected, 0);
+
+        assert_eq!(view.handle_key(key(KeyCode::Down)), None);
+        let open = PickerChoice::Open("src/main.rs".into());
+        assert_eq!(view.handle_key(key(KeyCode::Enter)), Some(open));
+        assert_eq!(view.handle_key(key(KeyCode::Left)), None);
+        assert_eq!(view.selected, 0);
+        assert_eq!(view.handle_key(key(KeyCode::Left)), None);
+        assert_eq!(paths(&view), ["src", "README"]);
+        // The listing is kept, so expanding again needs no request.
+        assert_eq!(view.handle_key(key(KeyCode::Right)), None);
+        assert_eq!(paths(&view).len(), 3);
+        assert_eq!(view.handle_key(key(KeyCode::Up)), None);
+        assert_eq!(view.entry().unwrap().path, "README");
+    }
+
+    #[test]
+    fn follows_clicks_and_the_wheel() {
+        let mut view = view();
+        let expand = PickerChoice::Expand("src".into());
+        assert_eq!(view.click(0), Some(expand));
+        view.update(list("src", &[("src/main.rs", false)]));
+        assert_eq!(view.click(2), None);
+        assert_eq!(view.entry().unwrap().path, "README");
+        let open = PickerChoice::Open("README".into());
+        assert_eq!(view.click(2), Some(open));
+        assert_eq!(view.click(3), None);
+        assert_eq!(view.click(0), None);
+        assert_eq!(paths(&view), ["src", "README"]);
+
+        view.scroll(3);
+        assert_eq!(view.selected, 1);
+        view.scroll(-3);
+        assert_eq!(view.selected, 0);
+    }
+
+    #[test]
+    fn keeps_the_highlight_across_listings() {
+        let mut view = PickerView::new(Some("src/lib.rs".into()));
+        view.update(list("", &[("src", true), ("README", false)]));
+        assert_eq!(view.selected, 0);
+        view.update(list(
+            "src",
+            &[("src/lib.rs", false), ("src/main.rs", false)],
+        ));
+        assert_eq!(view.entry().unwrap().path, "src/lib.rs");
+        view.update(list("", &[("a", false), ("src", true), ("README", false)]));
+        assert_eq!(view.entry().unwrap().path, "src/lib.rs");
+        view.update(list("src", &[]));
+        assert_eq!(view.entry().unwrap().path, "README");
+        let mut search = list("", &[]);
+        search.query = Some("x".into());
+        view.update(search);
+        assert_eq!(paths(&view), ["a", "src", "README"]);
+    }
+
+    #[test]
+    fn asks_for_file_operations() {
+        let mut view = view();
+        let create = |parent: &str, dir| PickerChoice::Create {
+            parent: parent.into(),
+            dir,
+        };
+        assert_eq!(
+            view.handle_key(key(KeyCode::Char('m'))),
+            Some(create("src", true))
+        );
+        view.handle_key(key(KeyCode::Down));
+        assert_eq!(
+            view.handle_key(key(KeyCode::Char('n'))),
+            Some(create("", false))
+        );
+        let rename = PickerChoice::Rename("README".into());
+        assert_eq!(view.handle_key(key(KeyCode::Char('r'))), Some(rename));
+        let delete = PickerChoice::Delete("README".into());
+        assert_eq!(view.handle_key(key(KeyCode::Delete)), Some(delete));
+        assert_eq!(
+            view.handle_key(key(KeyCode::Esc)),
+            Some(PickerChoice::Cancel)
+        );
+
+        let mut empty = PickerView::new(None);
+        assert_eq!(empty.handle_key(key(KeyCode::Enter)), None);
+        assert_eq!(empty.handle_key(key(KeyCode::Char('r'))), None);
+        assert_eq!(
+            empty.handle_key(key(KeyCode::Char('n'))),
+            Some(create("", false))
+        );
+    }
+
+    #[test]
+    fn joins_paths() {
+        assert_eq!(parent_of("src/bin/a.rs"), "src/bin");
+        assert_eq!(parent_of("a.rs"), "");
+        assert_eq!(join("", "a.rs"), "a.rs");
+        assert_eq!(join("src", "a.rs"), "src/a.rs");
+    }
+}
@@ -1,61 +1,434 @@
-use anyhow::Result;
-use futures_util::SinkExt;
-use ghostwriter_proto::{Auth, Envelope, Hello, MessageType, RequestFrame, Resize, encode};
-use tokio::net::TcpStream;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message};
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::{Result, bail};
+use futures_util::{SinkExt, StreamExt};
+use ghostwriter_core::{
+    Transport, TransportConfig, tls,
+    transport::{COMPRESSION_THRESHOLD, compress_payload, decompress_payload},
+};
+use ghostwriter_proto::{
+    Attach, Auth, Envelope, ErrorCode, ErrorMsg, FEATURES, Hello, HelloAck, MessageType,
+    RequestFrame, Resize, Role, SUPPORTED_VERSIONS, SearchRequest, Unwatch, WatchRequest, caps,
+    decode, encode, encode_batch, peek_type,
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    Connector, MaybeTlsStream, WebSocketStream, connect_async, connect_async_tls_with_config,
+    tungstenite::Message,
+};
 use url::Url;
 
+use crate::clipboard;
+
+/// Interval at which queued input should be flushed as one batch.
+pub const BATCH_TICK: Duration = Duration::from_millis(16);
+
+/// Interval between WebSocket pings keeping the connection alive.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Give up on a server that has not answered pings for this long.
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Capability bits for a terminal advertising `colorterm` via `$COLORTERM`,
+/// and taking OSC 52 clipboard writes if `clipboard` is set.
+pub fn caps_for_terminal(colorterm: Option<&str>, clipboard: bool) -> u32 {
+    let mut bits = caps::COMPRESSION;
+    if matches!(colorterm, Some("truecolor" | "24bit")) {
+        bits |= caps::TRUECOLOR;
+    }
+    if clipboard {
+        bits |= caps::OSC52;
+    }
+    bits
+}
+
+/// Why the server turned a connection away at the handshake, for asking
+/// the user for the secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The server needs credentials and none were given.
+    Required,
+    /// The server refused the credentials given.
+    Refused,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Required => write!(f, "the server requires a secret"),
+            Self::Refused => write!(f, "the server refused the secret"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 /// WebSocket client that communicates with the Ghostwriter server.
-pub struct WsClient {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+///
+/// After the handshake the connection runs on a core [`Transport`], whose
+/// pings notice a server that went away within [`KEEPALIVE_TIMEOUT`].
+/// `S` is the underlying byte stream: TCP for `ws://` URLs, or a QUIC
+/// stream with the `quic` feature.
+pub struct WsClient<S = MaybeTlsStream<TcpStream>> {
+    transport: Transport<S>,
+    pending: Vec<Vec<u8>>,
+    /// Message read while checking the credentials, handed out by the
+    /// first [`recv`](Self::recv).
+    first: Option<Vec<u8>>,
 }
 
 impl WsClient {
     /// Connect to `url` and perform the Hello handshake. Sends a `RequestFrame`
-    /// with reason `"initial"` after connecting. If `secret` is provided,
-    /// sends an `Auth` message after `Hello`.
-    pub async fn connect(url: &str, cols: u16, rows: u16, secret: Option<&str>) -> Result<Self> {
+    /// with reason `"initial"` after connecting. Sends `auth` after `Hello`
+    /// if given; fails with an [`AuthError`] if the server needs credentials
+    /// and none were given, or refuses them.
+    pub async fn connect(url: &str, cols: u16, rows: u16, auth: Option<Auth>) -> Result<Self> {
+        let url = Url::parse(url)?;
+        let (ws, _resp) = connect_async(url.as_str()).await?;
+        Self::handshake(ws, cols, rows, auth, Role::Editor).await
+    }
+
+    /// Like [`connect`](WsClient::connect), but as a read-only follower
+    /// that receives the frames of the session another client is editing.
+    pub async fn follow(url: &str, cols: u16, rows: u16, auth: Option<Auth>) -> Result<Self> {
+        let url = Url::parse(url)?;
+        let (ws, _resp) = connect_async(url.as_str()).await?;
+        Self::handshake(ws, cols, rows, auth, Role::Follower).await
+    }
+
+    /// Like [`connect`](WsClient::connect) for `wss://` URLs, trusting only
+    /// the certificates in `roots` instead of the system's, e.g. a private
+    /// CA or the server's self-signed certificate.
+    pub async fn connect_with_ca(
+        url: &str,
+        cols: u16,
+        rows: u16,
+        auth: Option<Auth>,
+        roots: &[tls::Certificate<'static>],
+    ) -> Result<Self> {
+        let config = tls::client_config(Some(roots), None)?;
+        Self::connect_with_tls(url, cols, rows, auth, config).await
+    }
+
+    /// Like [`connect`](WsClient::connect) for `wss://` URLs with a TLS
+    /// configuration from [`tls::client_config`], e.g. to present a client
+    /// certificate to a server that requires one.
+    pub async fn connect_with_tls(
+        url: &str,
+        cols: u16,
+        rows: u16,
+        auth: Option<Auth>,
+        config: Arc<tls::ClientConfig>,
+    ) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.scheme() != "wss" {
+            bail!("custom TLS settings need a wss:// URL");
+        }
+        let connector = Connector::Rustls(config);
+        let (ws, _resp) =
+            connect_async_tls_with_config(url.as_str(), None, false, Some(connector)).await?;
+        Self::handshake(ws, cols, rows, auth, Role::Editor).await
+    }
+
+    /// Connect to a `ws://` or `wss://` URL in `role`, with the TLS
+    /// configuration from [`tls::client_config`] if given and the system's
+    /// roots otherwise.
+    pub async fn connect_as(
+        url: &str,
+        cols: u16,
+        rows: u16,
+        auth: Option<Auth>,
+        role: Role,
+        config: Option<Arc<tls::ClientConfig>>,
+    ) -> Result<Self> {
         let url = Url::parse(url)?;
-        let (mut ws, _resp) = connect_async(url.as_str()).await?;
+        let connector = match config {
+            Some(_) if url.scheme() != "wss" => bail!("custom TLS settings need a wss:// URL"),
+            config => config.map(Connector::Rustls),
+        };
+        let (ws, _resp) =
+            connect_async_tls_with_config(url.as_str(), None, false, connector).await?;
+        Self::handshake(ws, cols, rows, auth, role).await
+    }
+}
+
+/// URL of the first server advertised on the local network within `wait`,
+/// for `--connect auto`.
+#[cfg(feature = "mdns")]
+pub async fn discover(wait: Duration) -> Result<String> {
+    let found = ghostwriter_core::mdns::discover(wait).await?;
+    match found.into_iter().next() {
+        Some(server) => Ok(server.url),
+        None => bail!("no server found on the local network"),
+    }
+}
+
+#[cfg(feature = "quic")]
+impl WsClient<ghostwriter_core::quic::QuicStream> {
+    /// Like [`connect`](WsClient::connect) for `quic://host:port` URLs,
+    /// trusting only the server certificates in `roots`.
+    pub async fn connect_quic(
+        url: &str,
+        cols: u16,
+        rows: u16,
+        auth: Option<Auth>,
+        roots: &[ghostwriter_core::quic::Certificate<'static>],
+    ) -> Result<Self> {
+        use anyhow::anyhow;
+        use ghostwriter_core::quic;
 
+        let url = Url::parse(url)?;
+        if url.scheme() != quic::SCHEME {
+            bail!("expected a {}:// URL", quic::SCHEME);
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host"))?;
+        let port = url.port().ok_or_else(|| anyhow!("missing port"))?;
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("{host} did not resolve"))?;
+        let endpoint = quic::client_endpoint(roots)?;
+        let ws = quic::connect(&endpoint, addr, host).await?;
+        Self::handshake(ws, cols, rows, auth, Role::Editor).await
+    }
+}
+
+impl<S> WsClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn handshake(
+        mut ws: WebSocketStream<S>,
+        cols: u16,
+        rows: u16,
+        auth: Option<Auth>,
+        role: Role,
+    ) -> Result<Self> {
+        let caps = caps_for_terminal(
+            std::env::var("COLORTERM").ok().as_deref(),
+            clipboard::detect_terminal().is_some(),
+        );
         let hello = Hello {
             client_name: "ghostwriter".into(),
             client_ver: env!("CARGO_PKG_VERSION").into(),
             cols,
             rows,
-            truecolor: true,
+            truecolor: caps & caps::TRUECOLOR != 0,
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+            caps,
+            role,
         };
         let env = Envelope::new(MessageType::Hello, hello);
         ws.send(Message::Binary(encode(&env)?.into())).await?;
 
-        if let Some(secret) = secret {
-            let auth = Auth {
-                secret: secret.into(),
-            };
-            let env = Envelope::new(MessageType::Auth, auth);
-            ws.send(Message::Binary(encode(&env)?.into())).await?;
+        let Some(data) = next_binary(&mut ws).await? else {
+            bail!("the server closed the connection during the handshake");
+        };
+        if peek_type(&data)? == MessageType::Error {
+            bail!("{}", decode::<ErrorMsg>(&data)?.data.msg);
+        }
+        let ack = decode::<HelloAck>(&data)?.data;
+        // Once `deflate` is agreed, every later message in either direction
+        // carries a compression flag.
+        let compression = ack.has(caps::COMPRESSION).then_some(COMPRESSION_THRESHOLD);
+        let pack = |data: Vec<u8>| -> Result<Message> {
+            Ok(Message::Binary(match compression {
+                Some(threshold) => compress_payload(&data, threshold)?.into(),
+                None => data.into(),
+            }))
+        };
+        let checked = ack.auth && auth.is_some();
+        match auth {
+            Some(auth) => {
+                let env = Envelope::new(MessageType::Auth, auth);
+                ws.send(pack(encode(&env)?)?).await?;
+            }
+            None if ack.auth => return Err(AuthError::Required.into()),
+            None => {}
         }
 
         let req = RequestFrame {
             reason: "initial".into(),
         };
         let env = Envelope::new(MessageType::RequestFrame, req);
-        ws.send(Message::Binary(encode(&env)?.into())).await?;
+        ws.send(pack(encode(&env)?)?).await?;
 
-        Ok(Self { ws })
+        // The server answers credentials only when it refuses them, so
+        // they passed once anything else arrives.
+        let first = if checked {
+            next_binary(&mut ws).await?
+        } else {
+            None
+        };
+        let first = match (first, compression) {
+            (Some(data), Some(_)) => Some(decompress_payload(&data)?),
+            (first, _) => first,
+        };
+        if let Some(data) = &first
+            && peek_type(data)? == MessageType::Error
+        {
+            let err = decode::<ErrorMsg>(data)?.data;
+            match err.code {
+                ErrorCode::Unauthorized => return Err(AuthError::Refused.into()),
+                ErrorCode::RateLimit => bail!("{}", err.msg),
+                _ => {}
+            }
+        }
+
+        let config = TransportConfig {
+            ping_interval: KEEPALIVE_INTERVAL,
+            pong_timeout: Some(KEEPALIVE_TIMEOUT),
+            ..TransportConfig::default()
+        };
+        let mut transport = Transport::with_config(ws, config);
+        transport.set_compression(compression);
+        Ok(Self {
+            transport,
+            pending: Vec::new(),
+            first,
+        })
+    }
+
+    /// Queue a message to be sent on the next [`flush`](Self::flush).
+    pub fn queue<T: Serialize>(&mut self, ty: MessageType, data: T) -> Result<()> {
+        self.pending.push(encode(&Envelope::new(ty, data))?);
+        Ok(())
+    }
+
+    /// Queue a message already encoded as an envelope.
+    pub fn queue_encoded(&mut self, data: Vec<u8>) {
+        self.pending.push(data);
+    }
+
+    /// Number of messages waiting for the next flush.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Send all queued messages in a single WebSocket frame. A lone message
+    /// is sent as-is; several are wrapped in a `Batch` envelope. Call this
+    /// once per [`BATCH_TICK`].
+    pub async fn flush(&mut self) -> Result<()> {
+        let data = match self.pending.len() {
+            0 => return Ok(()),
+            1 => self.pending.pop().unwrap_or_default(),
+            _ => encode_batch(std::mem::take(&mut self.pending))?,
+        };
+        self.transport.send(&data).await?;
+        Ok(())
+    }
+
+    /// Next binary message from the server, or `None` once the connection
+    /// is closed or the server stopped answering pings.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.first.take() {
+            return Ok(Some(data));
+        }
+        Ok(self.transport.recv().await)
+    }
+
+    /// Close the connection, letting the server save and end the session
+    /// rather than keep it for a later `Attach`.
+    pub async fn close(mut self) -> Result<()> {
+        self.flush().await?;
+        self.transport.close().await;
+        Ok(())
+    }
+
+    /// Send one message now, ahead of anything queued for the next flush.
+    async fn send<T: Serialize>(&self, ty: MessageType, data: T) -> Result<()> {
+        self.transport
+            .send(&encode(&Envelope::new(ty, data))?)
+            .await?;
+        Ok(())
+    }
+
+    /// Ask which sessions are running on the server; it replies with a
+    /// `SessionList`.
+    pub async fn list_sessions(&mut self) -> Result<()> {
+        self.send(MessageType::ListSessions, ()).await
+    }
+
+    /// Take over session `id`, left behind when an earlier connection
+    /// dropped, instead of opening a file.
+    pub async fn attach(&mut self, id: u64) -> Result<()> {
+        self.send(MessageType::Attach, Attach { id }).await
+    }
+
+    /// Subscribe to changes below the workspace directory `path`; the
+    /// server sends a `WatchEvent` whenever entries appear, change or go.
+    pub async fn watch(&mut self, path: &str, recursive: bool) -> Result<()> {
+        let path = path.to_string();
+        self.send(MessageType::Watch, WatchRequest { path, recursive })
+            .await
+    }
+
+    /// Stop the events started by [`watch`](Self::watch) for `path`.
+    pub async fn unwatch(&mut self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.send(MessageType::Unwatch, Unwatch { path }).await
+    }
+
+    /// Search the contents of every workspace file; matches arrive as
+    /// `SearchResults` chunks, the last one marked `done`.
+    pub async fn search_files(&mut self, req: SearchRequest) -> Result<()> {
+        self.send(MessageType::SearchFiles, req).await
+    }
+
+    /// Stop the search started by [`search_files`](Self::search_files).
+    pub async fn cancel_search(&mut self) -> Result<()> {
+        self.send(MessageType::CancelSearch, ()).await
     }
 
     /// Notify the server that the viewport has been resized and request a new frame.
     pub async fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
         let resize = Resize { cols, rows };
-        let env = Envelope::new(MessageType::Resize, resize);
-        self.ws.send(Message::Binary(encode(&env)?.into())).await?;
+        self.send(MessageType::Resize, resize).await?;
 
         let req = RequestFrame {
             reason: "resize".into(),
         };
-        let env = Envelope::new(MessageType::RequestFrame, req);
-        self.ws.send(Message::Binary(encode(&env)?.into())).await?;
-        Ok(())
+        self.send(MessageType::RequestFrame, req).await
+    }
+}
+
+/// Next binary message on `ws` before the transport takes over, or `None`
+/// once it is closed.
+async fn next_binary<S>(ws: &mut WebSocketStream<S>) -> Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(msg) = ws.next().await {
+        if let Message::Binary(data) = msg? {
+            return Ok(Some(data.into()));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_truecolor_terminals() {
+        assert_eq!(
+            caps_for_terminal(Some("truecolor"), false),
+            caps::TRUECOLOR | caps::COMPRESSION
+        );
+        assert_eq!(
+            caps_for_terminal(Some("24bit"), false) & caps::TRUECOLOR,
+            caps::TRUECOLOR
+        );
+        assert_eq!(caps_for_terminal(None, false), caps::COMPRESSION);
+        assert_eq!(
+            caps_for_terminal(None, true),
+            caps::COMPRESSION | caps::OSC52
+        );
     }
 }
@@ -0,0 +1,195 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ghostwriter_proto::{FileMatch, SearchRequest, SearchResultChunk};
+
+/// Most matches a search panel asks the server for.
+pub const SEARCH_LIMIT: u32 = 1000;
+
+/// What the user did in a [`SearchView`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchChoice {
+    /// Search for the edited query, replacing the running search; an
+    /// empty pattern only stops it.
+    Search(SearchRequest),
+    /// Open `path` at the zero-based `line`.
+    Open { path: String, line: u64 },
+    /// Close the panel, stopping the search.
+    Cancel,
+}
+
+/// Client-side panel searching the contents of every workspace file. The
+/// search is run again as the query is typed and matches are shown as the
+/// server streams them in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchView {
+    pub query: String,
+    /// Treat the query as a regular expression.
+    pub regex: bool,
+    /// Match case exactly.
+    pub case: bool,
+    /// Only match the query where it is not part of a longer word.
+    pub whole_word: bool,
+    /// Matches for `query` received so far.
+    pub matches: Vec<FileMatch>,
+    /// Whether the server has sent every match for `query`.
+    pub done: bool,
+    /// Index of the highlighted match.
+    pub selected: usize,
+}
+
+impl SearchView {
+    /// Take in results from the server; results for an earlier query are
+    /// dropped.
+    pub fn add(&mut self, chunk: SearchResultChunk) {
+        if chunk.pattern != self.query {
+            return;
+        }
+        self.matches.extend(chunk.matches);
+        self.done = chunk.done;
+    }
+
+    /// Highlighted match, if any.
+    pub fn entry(&self) -> Option<&FileMatch> {
+        self.matches.get(self.selected)
+    }
+
+    /// Handle a key press. Typing edits the query, Alt+R, Alt+C and Alt+W
+    /// toggle regular expressions, exact case and whole words, Up and Down move through the
+    /// matches, Enter opens one and Esc closes the panel.
+    pub fn handle_key(&mut self, ev: KeyEvent) -> Option<SearchChoice> {
+        let count = self.matches.len();
+        match ev.code {
+            KeyCode::Up if count > 0 => {
+                self.selected = (self.selected + count - 1) % count;
+                None
+            }
+            KeyCode::Down if count > 0 => {
+                self.selected = (self.selected + 1) % count;
+                None
+            }
+            KeyCode::Enter => self.entry().map(|m| SearchChoice::Open {
+                path: m.path.clone(),
+                line: m.line,
+            }),
+            KeyCode::Esc => Some(SearchChoice::Cancel),
+            KeyCode::Backspace => {
+                self.query.pop()?;
+                Some(self.restart())
+            }
+            KeyCode::Char(c) if ev.modifiers.contains(KeyModifiers::ALT) => {
+                match c.to_ascii_lowercase() {
+                    'r' => self.regex = !self.regex,
+                    'c' => self.case = !self.case,
+                    'w' => self.whole_word = !self.whole_word,
+                    _ => return None,
+                }
+                Some(self.restart())
+            }
+            KeyCode::Char(c) if !ev.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.query.push(c);
+                Some(self.restart())
+            }
+            _ => None,
+        }
+    }
+
+    /// Add pasted `text` to the query, up to its first line break, and
+    /// search again.
+    pub fn paste(&mut self, text: &str) -> Option<SearchChoice> {
+        let line = text.lines().next().filter(|line| !line.is_empty())?;
+        self.query.push_str(line);
+        Some(self.restart())
+    }
+
+    /// Forget the matches shown and search for the current query.
+    fn restart(&mut self) -> SearchChoice {
+        self.matches.clear();
+        self.selected = 0;
+        self.done = self.query.is_empty();
+        SearchChoice::Search(SearchRequest {
+            pattern: self.query.clone(),
+            regex: self.regex,
+            case: self.case,
+            limit: SEARCH_LIMIT,
+            no_ignore: false,
+            whole_word: self.whole_word,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostwriter_proto::Range;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn found(pattern: &str, path: &str, line: u64, done: bool) -> SearchResultChunk {
+        SearchResultChunk {
+            pattern: pattern.into(),
+            matches: vec![FileMatch {
+                path: path.into(),
+                line,
+                text: "fn main() {}".into(),
+                range: Range { from: 3, to: 7 },
+            }],
+            done,
+        }
+    }
+
+    #[test]
+    fn searches_as_the_query_is_typed() {
+        let mut view = SearchView::default();
+        view.handle_key(key(KeyCode::Char('m')));
+        let Some(SearchChoice::Search(req)) = view.handle_key(key(KeyCode::Char('a'))) else {
+            panic!("query edits search again");
+        };
+        assert_eq!((req.pattern.as_str(), req.regex), ("ma", false));
+        let regex = KeyEvent::new(KeyCode::Char('r'), KeyModifiers::ALT);
+        let Some(SearchChoice::Search(req)) = view.handle_key(regex) else {
+            panic!("toggling regex searches again");
+        };
+        assert!(req.regex);
+        let word = KeyEvent::new(KeyCode::Char('w'), KeyModifiers::ALT);
+        let Some(SearchChoice::Search(req)) = view.handle_key(word) else {
+            panic!("toggling whole words searches again");
+        };
+        assert!(req.whole_word && req.regex);
+
+        view.add(found("m", "old.rs", 0, true));
+        assert!(view.matches.is_empty());
+        view.add(found("ma", "src/main.rs", 4, false));
+        view.add(found("ma", "src/lib.rs", 9, true));
+        assert!(view.done);
+        assert_eq!(view.handle_key(key(KeyCode::Up)), None);
+        let open = SearchChoice::Open {
+            path: "src/lib.rs".into(),
+            line: 9,
+        };
+        assert_eq!(view.handle_key(key(KeyCode::Enter)), Some(open));
+
+        view.handle_key(key(KeyCode::Backspace));
+        assert!(view.matches.is_empty());
+        let Some(SearchChoice::Search(req)) = view.handle_key(key(KeyCode::Backspace)) else {
+            panic!("clearing the query stops the search");
+        };
+        assert!(req.pattern.is_empty() && view.done);
+        assert_eq!(view.handle_key(key(KeyCode::Backspace)), None);
+        assert_eq!(view.handle_key(key(KeyCode::Enter)), None);
+        assert_eq!(
+            view.handle_key(key(KeyCode::Esc)),
+            Some(SearchChoice::Cancel)
+        );
+    }
+
+    #[test]
+    fn searches_for_the_first_pasted_line() {
+        let mut view = SearchView::default();
+        let Some(SearchChoice::Search(req)) = view.paste("fn main\nmore") else {
+            panic!("pasting searches again");
+        };
+        assert_eq!(req.pattern, "fn main");
+        assert_eq!(view.paste("\nmore"), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Getting the secret a server asks for without putting it on the command
+//! line: typed at a hidden prompt, or kept in the OS keyring by server URL
+//! through the platform's command-line tool, `secret-tool` (libsecret) on
+//! Linux and `security` on macOS.
+
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
+
+/// Service the keyring entries are filed under.
+pub const KEYRING_SERVICE: &str = "ghostwriter";
+
+/// Ask for a secret on the terminal without echoing what is typed.
+pub fn prompt(label: &str) -> Result<String> {
+    let mut stderr = io::stderr();
+    write!(stderr, "{label}")?;
+    stderr.flush()?;
+    terminal::enable_raw_mode()?;
+    let typed = read_hidden();
+    terminal::disable_raw_mode()?;
+    writeln!(stderr)?;
+    typed?.ok_or_else(|| anyhow!("no secret entered"))
+}
+
+/// Keys typed up to Enter; `None` if the user gave up with Esc or Ctrl+C.
+fn read_hidden() -> Result<Option<String>> {
+    let mut typed = String::new();
+    loop {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event::read()?
+        else {
+            continue;
+        };
+        match code {
+            KeyCode::Enter => return Ok(Some(typed)),
+            KeyCode::Esc => return Ok(None),
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return Ok(None),
+            KeyCode::Backspace => {
+                typed.pop();
+            }
+            KeyCode::Char(c) => typed.push(c),
+            _ => {}
+        }
+    }
+}
+
+/// Secret saved for the server at `url`, if any.
+pub fn load(url: &str) -> Result<Option<String>> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("security");
+        cmd.args([
+            "find-generic-password",
+            "-s",
+            KEYRING_SERVICE,
+            "-a",
+            url,
+            "-w",
+        ]);
+        cmd
+    } else if cfg!(target_os = "linux") {
+        let mut cmd = Command::new("secret-tool");
+        cmd.args(["lookup", "service", KEYRING_SERVICE, "url", url]);
+        cmd
+    } else {
+        bail!("the OS keyring is not supported on this platform");
+    };
+    let out = cmd
+        .stderr(Stdio::null())
+        .output()
+        .context("cannot reach the OS keyring")?;
+    let secret = String::from_utf8(out.stdout)?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    Ok((out.status.success() && !secret.is_empty()).then(|| secret.to_string()))
+}
+
+/// Save `secret` for the server at `url`, replacing any saved before. The
+/// secret is passed to the keyring tool on its standard input, so it
+/// shows in no process list.
+pub fn store(url: &str, secret: &str) -> Result<()> {
+    let (mut cmd, input) = if cfg!(target_os = "macos") {
+        let mut cmd = Command::new("security");
+        cmd.arg("-i");
+        let line = format!(
+            "add-generic-password -U -s {} -a {} -w {}\n",
+            quote(KEYRING_SERVICE),
+            quote(url),
+            quote(secret)
+        );
+        (cmd, line)
+    } else if cfg!(target_os = "linux") {
+        let mut cmd = Command::new("secret-tool");
+        let label = format!("Ghostwriter secret for {url}");
+        cmd.args([
+            "store",
+            "--label",
+            &label,
+            "service",
+            KEYRING_SERVICE,
+            "url",
+            url,
+        ]);
+        (cmd, secret.to_string())
+    } else {
+        bail!("the OS keyring is not supported on this platform");
+    };
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("cannot reach the OS keyring")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("saving the secret in the OS keyring failed with {status}");
+    }
+    Ok(())
+}
+
+/// Quote `s` as one word for `security -i`.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_words_for_security() {
+        assert_eq!(quote("ws://h:1"), "\"ws://h:1\"");
+        assert_eq!(quote(r#"a"b\c"#), r#""a\"b\\c""#);
+    }
+}
@@ -0,0 +1,160 @@
+//! `ssh://[user@]host[:port]/path/to/workspace` connections for machines
+//! that only expose SSH. The system `ssh` forwards a local port to the
+//! Unix socket that a server for the workspace listens on, named
+//! [`SOCKET_NAME`] inside it (see `--ssh-socket`), and the client speaks
+//! WebSocket over that port.
+
+use std::{
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    process::{Child, Command},
+};
+use url::Url;
+
+/// URL scheme of SSH-tunneled connections.
+pub const SCHEME: &str = "ssh";
+
+/// Name of the socket, inside the workspace, a server for an
+/// SSH-reachable workspace listens on.
+pub const SOCKET_NAME: &str = ".ghostwriter.sock";
+
+/// How long `ssh` may take to log in and open the forwarded port.
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait between checks whether the forwarded port is open.
+const TUNNEL_POLL: Duration = Duration::from_millis(100);
+
+/// Where an `ssh://` URL points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshTarget {
+    /// `user@host` or `host`, as `ssh` takes it.
+    pub destination: String,
+    pub port: Option<u16>,
+    /// Absolute path of the workspace on the remote machine.
+    pub workspace: String,
+}
+
+impl SshTarget {
+    /// Parse `ssh://[user@]host[:port]/path/to/workspace`.
+    pub fn parse(url: &str) -> Result<Self> {
+        let url = Url::parse(url)?;
+        if url.scheme() != SCHEME {
+            bail!("expected an {SCHEME}:// URL");
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host"))?;
+        let destination = match url.username() {
+            "" => host.to_string(),
+            user => format!("{user}@{host}"),
+        };
+        // `ssh` would take it for an option, such as `-oProxyCommand=…`.
+        if destination.starts_with('-') {
+            bail!("invalid SSH destination {destination}");
+        }
+        let workspace = url.path().trim_end_matches('/');
+        if workspace.is_empty() {
+            bail!("missing workspace path in {url}");
+        }
+        Ok(Self {
+            destination,
+            port: url.port(),
+            workspace: workspace.to_string(),
+        })
+    }
+
+    /// Remote socket the tunnel leads to.
+    pub fn socket(&self) -> String {
+        format!("{}/{SOCKET_NAME}", self.workspace)
+    }
+
+    /// Arguments to `ssh` forwarding `local_port` to the socket.
+    fn args(&self, local_port: u16) -> Vec<String> {
+        let mut args = vec![
+            "-N".to_string(),
+            "-o".into(),
+            "ExitOnForwardFailure=yes".into(),
+            "-L".into(),
+            format!("127.0.0.1:{local_port}:{}", self.socket()),
+        ];
+        if let Some(port) = self.port {
+            args.extend(["-p".into(), port.to_string()]);
+        }
+        args.extend(["--".into(), self.destination.clone()]);
+        args
+    }
+}
+
+/// Running `ssh` port forward; dropping it ends `ssh`.
+pub struct Tunnel {
+    _ssh: Child,
+    /// `ws://` URL reaching the remote server through the tunnel.
+    pub url: String,
+}
+
+impl Tunnel {
+    /// Start `ssh` and wait until the forwarded port is open. `ssh` may
+    /// still ask for a password or passphrase on the terminal.
+    pub async fn open(target: &SshTarget) -> Result<Self> {
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let mut ssh = Command::new("ssh")
+            .args(target.args(port))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("cannot run ssh: {e}"))?;
+        let deadline = Instant::now() + TUNNEL_TIMEOUT;
+        loop {
+            if let Some(status) = ssh.try_wait()? {
+                bail!("ssh to {} exited with {status}", target.destination);
+            }
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                bail!("ssh to {} did not open a tunnel", target.destination);
+            }
+            tokio::time::sleep(TUNNEL_POLL).await;
+        }
+        Ok(Self {
+            _ssh: ssh,
+            url: format!("ws://127.0.0.1:{port}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ssh_urls() {
+        let target = SshTarget::parse("ssh://ana@build.example:2222/srv/app/").unwrap();
+        assert_eq!(target.destination, "ana@build.example");
+        assert_eq!(target.socket(), "/srv/app/.ghostwriter.sock");
+        assert_eq!(
+            target.args(9000),
+            [
+                "-N",
+                "-o",
+                "ExitOnForwardFailure=yes",
+                "-L",
+                "127.0.0.1:9000:/srv/app/.ghostwriter.sock",
+                "-p",
+                "2222",
+                "--",
+                "ana@build.example",
+            ]
+        );
+        let target = SshTarget::parse("ssh://build/home/me").unwrap();
+        assert_eq!((target.destination.as_str(), target.port), ("build", None));
+        assert!(SshTarget::parse("ssh://build").is_err());
+        assert!(SshTarget::parse("ssh://-oProxyCommand=x/srv").is_err());
+        assert!(SshTarget::parse("ssh://-oProxyCommand=x@build/srv").is_err());
+        assert!(SshTarget::parse("ws://build/x").is_err());
+    }
+}
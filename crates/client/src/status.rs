@@ -0,0 +1,401 @@
+use ghostwriter_proto::{ConnectionState, LockState, SearchStatus, Status};
+use ratatui::text::Span;
+
+use crate::latency::Rtt;
+
+/// Placeholders a [`StatusLayout`] fills in.
+const PLACEHOLDERS: [&str; 12] = [
+    "path", "dirty", "lock", "conn", "doc_v", "line", "col", "encoding", "eol", "search", "rtt",
+    "jitter",
+];
+
+/// Rank of a segment with no placeholder, dropped first.
+const LITERAL_RANK: u8 = 6;
+
+/// Templates used to format a structured [`Status`] into the status bar.
+///
+/// Placeholders: `{path}`, `{dirty}`, `{lock}`, `{conn}`, `{doc_v}`,
+/// `{line}`, `{col}` (both one-based), `{encoding}`, `{eol}`, `{search}`
+/// (`"n of m  "` while a search is active), `{rtt}` (`"42 ms  "` once
+/// measured, marked `slow` while the connection is degraded) and
+/// `{jitter}` (`"±3 ms"`).
+///
+/// Parts of a template separated by two spaces are segments. When the
+/// status line is too narrow for both sides, segments are dropped in
+/// order of [`rank`] until it fits, and the one with `{path}` last of
+/// all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLayout {
+    pub left: String,
+    pub right: String,
+}
+
+impl Default for StatusLayout {
+    fn default() -> Self {
+        Self {
+            left: "{path}{dirty}  {encoding} {eol}".into(),
+            right: "{search}{conn}{rtt}Ln {line}, Col {col}  {lock}".into(),
+        }
+    }
+}
+
+impl StatusLayout {
+    /// Format `status` and the measured round trip, if any, into left and
+    /// right status texts.
+    pub fn format(&self, status: &Status, rtt: Option<Rtt>) -> (String, String) {
+        (
+            render(&self.left, status, rtt),
+            render(&self.right, status, rtt),
+        )
+    }
+
+    /// The status line for `status`, exactly `width` columns wide if it
+    /// fits, dropping the least important segments if it does not.
+    pub fn line(&self, status: &Status, rtt: Option<Rtt>, width: u16) -> String {
+        let (left, right) = self.format(status, rtt);
+        if let Some(line) = pad(&left, &right, width) {
+            return line;
+        }
+        let mut kept = segments(&self.left, status, rtt);
+        for mut segment in segments(&self.right, status, rtt) {
+            segment.right = true;
+            kept.push(segment);
+        }
+        while kept.len() > 1 {
+            let left = join(kept.iter().filter(|s| !s.right));
+            let right = join(kept.iter().filter(|s| s.right));
+            if let Some(line) = pad(&left, &right, width) {
+                return line;
+            }
+            // The least important segment, the last of equally important
+            let (drop, _) = (kept.iter().enumerate())
+                .max_by_key(|(i, s)| (s.rank, *i))
+                .unwrap();
+            kept.remove(drop);
+        }
+        let rest = join(kept.iter());
+        fit(&rest, "", width)
+    }
+}
+
+/// A part of a rendered template, with how important what it shows is.
+struct Segment {
+    /// Whether it is from the right template.
+    right: bool,
+    rank: u8,
+    text: String,
+}
+
+/// How long `placeholder` stays in a status line too narrow for
+/// everything: the path and dirty flag longest, then the connection and
+/// lock state, the cursor position, the search, the round trip and
+/// version, and the encoding and line endings first.
+fn rank(placeholder: &str) -> u8 {
+    match placeholder {
+        "path" | "dirty" => 0,
+        "conn" | "lock" => 1,
+        "line" | "col" => 2,
+        "search" => 3,
+        "rtt" | "jitter" | "doc_v" => 4,
+        _ => 5,
+    }
+}
+
+/// The non-empty segments of `template` as rendered, marked as left ones.
+/// Placeholders that render with two trailing spaces, like `{search}`,
+/// end their segment.
+fn segments(template: &str, status: &Status, rtt: Option<Rtt>) -> Vec<Segment> {
+    let empty = || Segment {
+        right: false,
+        rank: LITERAL_RANK,
+        text: String::new(),
+    };
+    let mut segments = Vec::new();
+    let mut current = empty();
+    let mut rest = template;
+    while !rest.is_empty() {
+        let placeholder = PLACEHOLDERS.iter().find_map(|name| {
+            let after = rest
+                .strip_prefix('{')?
+                .strip_prefix(name)?
+                .strip_prefix('}')?;
+            Some((name, after))
+        });
+        let (text, rank) = match placeholder {
+            Some((name, after)) => {
+                rest = after;
+                (render(&format!("{{{name}}}"), status, rtt), rank(name))
+            }
+            None => {
+                let first = rest.chars().next().map_or(0, char::len_utf8);
+                let end = rest[first..].find('{').map_or(rest.len(), |i| i + first);
+                let (literal, after) = rest.split_at(end);
+                rest = after;
+                (literal.to_string(), LITERAL_RANK)
+            }
+        };
+        for (i, part) in text.split("  ").enumerate() {
+            if i > 0 {
+                let done = std::mem::replace(&mut current, empty());
+                if !done.text.trim().is_empty() {
+                    segments.push(done);
+                }
+            }
+            if !part.is_empty() {
+                current.text.push_str(part);
+                current.rank = current.rank.min(rank);
+            }
+        }
+    }
+    if !current.text.trim().is_empty() {
+        segments.push(current);
+    }
+    for segment in &mut segments {
+        segment.text = segment.text.trim().to_string();
+    }
+    segments
+}
+
+/// The texts of `segments` two spaces apart.
+fn join<'a>(segments: impl Iterator<Item = &'a Segment>) -> String {
+    let texts: Vec<&str> = segments.map(|s| s.text.as_str()).collect();
+    texts.join("  ")
+}
+
+/// `left` and `right` spaced to fill `width` columns, at least one column
+/// apart when both show; `None` if they do not fit.
+fn pad(left: &str, right: &str, width: u16) -> Option<String> {
+    let used = columns(left) + columns(right);
+    let gap = usize::from(!left.is_empty() && !right.is_empty());
+    let padding = (width as usize).checked_sub(used + gap)? + gap;
+    Some(format!("{left}{}{right}", " ".repeat(padding)))
+}
+
+/// A status line `width` columns wide from unstructured `left` and
+/// `right` texts: the right one is dropped if both do not fit, and the
+/// left one then loses its start to an ellipsis, keeping the end of a
+/// path.
+pub fn fit(left: &str, right: &str, width: u16) -> String {
+    if let Some(line) = pad(left, right, width) {
+        return line;
+    }
+    if let Some(line) = pad(left, "", width) {
+        return line;
+    }
+    let mut kept = String::new();
+    let room = (width as usize).saturating_sub(1);
+    for c in left.chars().rev() {
+        if columns(&kept) + columns(c.encode_utf8(&mut [0; 4])) > room {
+            break;
+        }
+        kept.insert(0, c);
+    }
+    match width {
+        0 => String::new(),
+        _ => format!("…{kept}"),
+    }
+}
+
+fn columns(text: &str) -> usize {
+    Span::raw(text).width()
+}
+
+fn render(template: &str, status: &Status, rtt: Option<Rtt>) -> String {
+    template
+        .replace("{path}", &status.path)
+        .replace("{dirty}", if status.dirty { " [+]" } else { "" })
+        .replace(
+            "{lock}",
+            match status.lock {
+                LockState::Writable => "RW",
+                LockState::ReadOnly => "RO",
+            },
+        )
+        .replace(
+            "{conn}",
+            match status.connection {
+                ConnectionState::Local | ConnectionState::Connected => "",
+                ConnectionState::Reconnecting => "reconnecting…  ",
+            },
+        )
+        .replace("{doc_v}", &status.doc_v.to_string())
+        .replace("{line}", &(status.line + 1).to_string())
+        .replace("{col}", &(status.col + 1).to_string())
+        .replace("{encoding}", &status.encoding)
+        .replace("{eol}", &status.eol)
+        .replace(
+            "{search}",
+            &match &status.search {
+                Some(SearchStatus {
+                    current,
+                    total,
+                    searching,
+                    ..
+                }) => {
+                    let more = if *searching { "+" } else { "" };
+                    format!("{current} of {total}{more}  ")
+                }
+                None => String::new(),
+            },
+        )
+        .replace(
+            "{rtt}",
+            &match rtt {
+                Some(Rtt { rtt, degraded, .. }) => {
+                    let slow = if degraded { " slow" } else { "" };
+                    format!("{} ms{slow}  ", rtt.as_millis())
+                }
+                None => String::new(),
+            },
+        )
+        .replace(
+            "{jitter}",
+            &(rtt.map(|rtt| format!("±{} ms", rtt.jitter.as_millis()))).unwrap_or_default(),
+        )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn status() -> Status {
+        Status {
+            path: "src/main.rs".into(),
+            dirty: true,
+            lock: LockState::ReadOnly,
+            connection: ConnectionState::Connected,
+            doc_v: 7,
+            line: 4,
+            col: 0,
+            encoding: "UTF-8".into(),
+            eol: "CRLF".into(),
+            search: None,
+            selection: None,
+        }
+    }
+
+    #[test]
+    fn formats_default_layout() {
+        let (left, right) = StatusLayout::default().format(&status(), None);
+        assert_eq!(left, "src/main.rs [+]  UTF-8 CRLF");
+        assert_eq!(right, "Ln 5, Col 1  RO");
+    }
+
+    #[test]
+    fn formats_custom_layout() {
+        let layout = StatusLayout {
+            left: "{lock} {path}".into(),
+            right: "v{doc_v} {conn}".into(),
+        };
+        let mut status = status();
+        status.connection = ConnectionState::Reconnecting;
+        let (left, right) = layout.format(&status, None);
+        assert_eq!(left, "RO src/main.rs");
+        assert_eq!(right, "v7 reconnecting…  ");
+    }
+
+    #[test]
+    fn formats_search_position() {
+        let mut status = status();
+        status.search = Some(SearchStatus {
+            current: 2,
+            total: 5,
+            preview: None,
+            searching: false,
+        });
+        let (_, right) = StatusLayout::default().format(&status, None);
+        assert_eq!(right, "2 of 5  Ln 5, Col 1  RO");
+        status.search.as_mut().unwrap().searching = true;
+        let (_, right) = StatusLayout::default().format(&status, None);
+        assert_eq!(right, "2 of 5+  Ln 5, Col 1  RO");
+    }
+
+    #[test]
+    fn drops_the_least_important_segments_first() {
+        let mut status = status();
+        status.search = Some(SearchStatus {
+            current: 2,
+            total: 5,
+            preview: None,
+            searching: false,
+        });
+        let layout = StatusLayout::default();
+        let full = "src/main.rs [+]  UTF-8 CRLF 2 of 5  Ln 5, Col 1  RO";
+        assert_eq!(layout.line(&status, None, 51), full);
+        assert_eq!(
+            layout.line(&status, None, 55),
+            "src/main.rs [+]  UTF-8 CRLF     2 of 5  Ln 5, Col 1  RO"
+        );
+        let lines = [
+            (40, "src/main.rs [+]  2 of 5  Ln 5, Col 1  RO"),
+            (35, "src/main.rs [+]     Ln 5, Col 1  RO"),
+            (20, "src/main.rs [+]   RO"),
+            (15, "src/main.rs [+]"),
+            (12, "…main.rs [+]"),
+            (1, "…"),
+        ];
+        for (width, line) in lines {
+            assert_eq!(layout.line(&status, None, width), line, "{width}");
+        }
+        assert_eq!(layout.line(&status, None, 0), "");
+    }
+
+    #[test]
+    fn drops_segments_around_non_ascii_literals() {
+        let layout = StatusLayout {
+            left: "{path}·{dirty}  {encoding}".into(),
+            right: "Ln {line}".into(),
+        };
+        let line = layout.line(&status(), None, 12);
+        assert_eq!(line, "…ain.rs· [+]");
+    }
+
+    #[test]
+    fn splits_templates_into_segments() {
+        let mut status = status();
+        status.connection = ConnectionState::Reconnecting;
+        let texts = |template: &str| -> Vec<(String, u8)> {
+            let segments = segments(template, &status, None);
+            segments.into_iter().map(|s| (s.text, s.rank)).collect()
+        };
+        assert_eq!(
+            texts("{search}{conn}Ln {line}  {lock}"),
+            [
+                ("reconnecting…".to_string(), 1),
+                ("Ln 5".to_string(), 2),
+                ("RO".to_string(), 1),
+            ]
+        );
+        assert_eq!(
+            texts("[{path}]  {x}"),
+            [("[src/main.rs]".into(), 0), ("{x}".into(), 6)]
+        );
+    }
+
+    #[test]
+    fn fits_unstructured_status() {
+        assert_eq!(fit("a", "b", 5), "a   b");
+        assert_eq!(fit("/home/me/notes.md", "RW", 12), "…me/notes.md");
+        assert_eq!(fit("日本語.md", "", 6), "…語.md");
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        use std::time::Duration;
+
+        let layout = StatusLayout {
+            left: "{path}".into(),
+            right: "{rtt}{jitter}".into(),
+        };
+        let mut rtt = Rtt {
+            rtt: Duration::from_millis(42),
+            jitter: Duration::from_millis(3),
+            degraded: false,
+        };
+        let (_, right) = layout.format(&status(), Some(rtt));
+        assert_eq!(right, "42 ms  ±3 ms");
+        rtt.degraded = true;
+        let (_, right) = StatusLayout::default().format(&status(), Some(rtt));
+        assert_eq!(right, "42 ms slow  Ln 5, Col 1  RO");
+    }
+}
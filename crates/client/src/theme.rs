@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use ratatui::style::{Color, Modifier, Style};
+
+/// Styles for the classes of the spans a frame's lines carry: `sel` for
+/// the viewer's selection, `match` for search matches, `ws` for visible
+/// whitespace, `err` for problems, `dir` and `picker-sel` in the picker,
+/// and syntax scopes like `keyword` or `string.escape`. `cursor` styles
+/// the viewer's cursors other than the terminal's, `more` the marks
+/// where a line goes on past the edge of the screen, and `bookmark` the
+/// first cell of bookmarked lines.
+///
+/// A dotted scope without a style of its own takes that of its parent,
+/// so `keyword.control` is drawn like `keyword`. Classes without a style
+/// are drawn as plain text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Theme {
+    styles: HashMap<String, Style>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let fg = |color| Style::default().fg(color);
+        Self::empty()
+            .with("sel", Style::default().add_modifier(Modifier::REVERSED))
+            .with("cursor", Style::default().fg(Color::Black).bg(Color::White))
+            .with("match", Style::default().fg(Color::Black).bg(Color::Yellow))
+            .with("ws", fg(Color::DarkGray))
+            .with("more", fg(Color::DarkGray))
+            .with("bookmark", Style::default().bg(Color::Blue))
+            .with("err", fg(Color::Red).add_modifier(Modifier::UNDERLINED))
+            .with("dir", fg(Color::Blue).add_modifier(Modifier::BOLD))
+            .with(
+                "picker-sel",
+                Style::default().add_modifier(Modifier::REVERSED),
+            )
+            .with(
+                "comment",
+                fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+            )
+            .with("keyword", fg(Color::Magenta))
+            .with("string", fg(Color::Green))
+            .with("number", fg(Color::Cyan))
+            .with("constant", fg(Color::Cyan))
+            .with("type", fg(Color::Yellow))
+            .with("function", fg(Color::Blue))
+    }
+}
+
+impl Theme {
+    /// A theme that draws every class as plain text.
+    pub fn empty() -> Self {
+        Self {
+            styles: HashMap::new(),
+        }
+    }
+
+    /// This theme with `class` drawn in `style`.
+    pub fn with(mut self, class: &str, style: Style) -> Self {
+        self.styles.insert(class.into(), style);
+        self
+    }
+
+    /// How to draw text of `class`.
+    pub fn style(&self, class: &str) -> Style {
+        let mut scope = class;
+        loop {
+            if let Some(style) = self.styles.get(scope) {
+                return *style;
+            }
+            match scope.rsplit_once('.') {
+                Some((parent, _)) => scope = parent,
+                None => return Style::default(),
+            }
+        }
+    }
+
+    /// Order in which spans of `class` are drawn: the selection over
+    /// search matches, and both over everything else.
+    pub fn layer(class: &str) -> u8 {
+        match class {
+            "sel" | "picker-sel" => 2,
+            "match" => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_parent_scopes() {
+        let theme = Theme::empty()
+            .with("keyword", Style::default().fg(Color::Magenta))
+            .with("keyword.control", Style::default().fg(Color::Red));
+        assert_eq!(theme.style("keyword.control.flow").fg, Some(Color::Red));
+        assert_eq!(theme.style("keyword.other").fg, Some(Color::Magenta));
+        assert_eq!(theme.style("keywords"), Style::default());
+        assert_eq!(
+            Theme::default().style("string.escape").fg,
+            Some(Color::Green)
+        );
+    }
+}
@@ -1,24 +1,95 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
+use crossterm::event::{
+    DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-use ghostwriter_proto::Frame;
-use ratatui::{Terminal, backend::Backend, prelude::*, widgets::Paragraph};
+use ghostwriter_proto::{ConnectionState, Cursor, Frame, FrameDiff, LockState};
+use ratatui::{
+    Terminal,
+    backend::Backend,
+    buffer::Buffer,
+    prelude::*,
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+};
+
+use crate::bookmarks::BookmarkView;
+use crate::clipboard::{self, Mux};
+use crate::dialog::DialogView;
+use crate::latency::Rtt;
+use crate::picker::PickerView;
+use crate::search::SearchView;
+use crate::status::{self, StatusLayout};
+use crate::theme::Theme;
+
+/// Shown across the top of the screen while reconnecting.
+const RECONNECT_BANNER: &str = "reconnecting… read-only";
+
+/// The last frame as painted, with its text rows and status line rendered
+/// without overlays, so the next frame renders only the rows it changes.
+struct Drawn {
+    frame: Frame,
+    status: String,
+    buffer: Buffer,
+}
 
 /// Terminal user interface renderer.
 pub struct Tui<B: Backend> {
     terminal: Terminal<B>,
     raw_mode: bool,
+    /// Whether the terminal reports mouse events.
+    mouse: bool,
+    /// Escape sequences written besides frames, kept instead in tests.
+    #[cfg(test)]
+    escapes: Vec<String>,
+    last: Option<Frame>,
+    layout: StatusLayout,
+    theme: Theme,
+    /// Round trip shown in the status line.
+    rtt: Option<Rtt>,
+    /// Whether the connection is down.
+    offline: bool,
+    /// Typed text the server has not shown yet, drawn at the cursor.
+    echo: String,
+    /// Whether search matches other than the selected one are drawn.
+    matches: bool,
+    drawn: Option<Drawn>,
+    /// Shortest time between paints of the frame, from the render budget.
+    interval: Option<Duration>,
+    /// When the terminal was last painted.
+    painted: Option<Instant>,
+    /// Whether the last frame waits for the render budget to be painted.
+    pending: bool,
 }
 
 impl<B: Backend> Tui<B> {
     /// Create a new instance using the provided backend.
-    /// Enables terminal raw mode and hides the cursor.
+    /// Enables terminal raw mode and bracketed paste, and hides the cursor.
     pub fn new(backend: B) -> Result<Self> {
         enable_raw_mode()?;
+        crossterm::execute!(io::stdout(), EnableBracketedPaste)?;
         let mut terminal = Terminal::new(backend)?;
         terminal.hide_cursor()?;
         Ok(Self {
             terminal,
             raw_mode: true,
+            mouse: false,
+            #[cfg(test)]
+            escapes: Vec::new(),
+            last: None,
+            layout: StatusLayout::default(),
+            theme: Theme::default(),
+            rtt: None,
+            offline: false,
+            echo: String::new(),
+            matches: true,
+            drawn: None,
+            interval: None,
+            painted: None,
+            pending: false,
         })
     }
 
@@ -29,60 +100,676 @@ impl<B: Backend> Tui<B> {
         Ok(Self {
             terminal,
             raw_mode: false,
+            mouse: false,
+            escapes: Vec::new(),
+            last: None,
+            layout: StatusLayout::default(),
+            theme: Theme::default(),
+            rtt: None,
+            offline: false,
+            echo: String::new(),
+            matches: true,
+            drawn: None,
+            interval: None,
+            painted: None,
+            pending: false,
         })
     }
 
-    /// Draw the given frame.
+    /// Have the terminal report clicks, drags and the wheel as events,
+    /// until the interface is dropped.
+    pub fn capture_mouse(&mut self) -> Result<()> {
+        crossterm::execute!(io::stdout(), EnableMouseCapture)?;
+        self.mouse = true;
+        Ok(())
+    }
+
+    /// Put `text` on the user's clipboard with OSC 52 through `mux`.
+    pub fn copy(&mut self, text: &str, mux: Mux) -> Result<()> {
+        let seq = clipboard::osc52(text, mux)?;
+        #[cfg(test)]
+        if !self.raw_mode {
+            self.escapes.push(seq);
+            return Ok(());
+        }
+        let mut out = io::stdout();
+        out.write_all(seq.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Use `layout` to format structured status in subsequent frames.
+    pub fn set_status_layout(&mut self, layout: StatusLayout) {
+        self.layout = layout;
+    }
+
+    /// Draw span classes in the styles of `theme` from the next draw on.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+        self.drawn = None;
+    }
+
+    /// Paint frames at most `per_second` times a second, for slow links;
+    /// frames drawn in between are held back and only the latest is
+    /// painted. `None` paints every frame.
+    pub fn set_render_budget(&mut self, per_second: Option<u32>) {
+        self.interval = per_second.map(|n| Duration::from_secs(1) / n.max(1));
+    }
+
+    /// Paint the frame held back by the render budget once it is due.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending { self.present() } else { Ok(()) }
+    }
+
+    /// Show `rtt` in the status line from the next draw on.
+    pub fn set_rtt(&mut self, rtt: Option<Rtt>) {
+        self.rtt = rtt;
+    }
+
+    /// Show `text` at the cursor of the frames drawn from now on, until
+    /// the server shows it itself.
+    pub fn set_echo(&mut self, text: String) {
+        self.echo = text;
+    }
+
+    /// Turn highlighting every search match on or off, returning whether
+    /// it is now on. The selected match always shows as the selection.
+    pub fn toggle_matches(&mut self) -> bool {
+        self.matches = !self.matches;
+        self.matches
+    }
+
+    /// Draw the given frame and remember it as the base for later diffs.
     pub fn draw(&mut self, frame: &Frame) -> Result<()> {
-        self.terminal.draw(|f| {
-            let size = f.area();
-            let text_height = size.height.saturating_sub(1);
-
-            // Text area
-            let lines: Vec<ratatui::text::Line<'static>> = frame
-                .lines
-                .iter()
-                .map(|l| ratatui::text::Line::raw(l.text.clone()))
-                .collect();
-            let text_area = Rect {
-                x: 0,
-                y: 0,
-                width: size.width,
-                height: text_height,
-            };
-            f.render_widget(Paragraph::new(lines), text_area);
-
-            // Status line
-            let mut status = frame.status_left.clone();
-            let right = frame.status_right.clone();
-            let total_width = size.width as usize;
-            if status.len() + right.len() < total_width {
-                let padding = total_width - status.len() - right.len();
-                status.push_str(&" ".repeat(padding));
+        self.last = Some(frame.clone());
+        self.present()
+    }
+
+    /// Apply `diff` to the last drawn frame and draw the result.
+    ///
+    /// Returns `Ok(false)` without drawing when the diff does not match the
+    /// last frame; the caller should then request a full frame.
+    pub fn draw_diff(&mut self, diff: &FrameDiff) -> Result<bool> {
+        let Some(frame) = self.last.as_ref().and_then(|last| diff.apply(last)) else {
+            self.last = None;
+            return Ok(false);
+        };
+        self.draw(&frame)?;
+        Ok(true)
+    }
+
+    /// The last frame drawn, with diffs applied.
+    pub fn frame(&self) -> Option<&Frame> {
+        self.last.as_ref()
+    }
+
+    /// Line and byte column of the last frame's text under screen cell
+    /// (`x`, `y`); a cell past the end of a line gives its end. `None`
+    /// off the text.
+    pub fn text_position(&self, x: u16, y: u16) -> Option<(u64, u64)> {
+        let frame = self.last.as_ref()?;
+        let line = frame.lines.get(y as usize)?;
+        let col = u64::from(frame.first_col) + byte_at(&line.text, x) as u64;
+        Some((frame.first_line + u64::from(y), col))
+    }
+
+    /// Index of the row of `view`, drawn as a modal, under screen cell
+    /// (`x`, `y`).
+    pub fn picker_row(&self, view: &PickerView, x: u16, y: u16) -> Option<usize> {
+        let size = self.terminal.size().ok()?;
+        let area = panel_area(Rect::new(0, 0, size.width, size.height));
+        let inner = area.inner(Margin::new(1, 1));
+        if !inner.contains(Position::new(x, y)) {
+            return None;
+        }
+        let idx = picker_first_row(view, area) + usize::from(y - inner.y);
+        (idx < view.rows().len()).then_some(idx)
+    }
+
+    /// Draw `view` as a modal on top of the last frame.
+    pub fn draw_dialog(&mut self, view: &DialogView) -> Result<()> {
+        self.paint(|f| render_dialog(f, view))
+    }
+
+    /// Draw the search panel `view` on top of the last frame.
+    pub fn draw_search(&mut self, view: &SearchView) -> Result<()> {
+        self.paint(|f| render_search(f, view))
+    }
+
+    /// Draw `view` as a panel on top of the last frame.
+    pub fn draw_bookmarks(&mut self, view: &BookmarkView) -> Result<()> {
+        self.paint(|f| render_bookmarks(f, view))
+    }
+
+    /// Draw `view` as a modal on top of the last frame.
+    pub fn draw_picker(&mut self, view: &PickerView) -> Result<()> {
+        self.paint(|f| render_picker(f, view))
+    }
+
+    /// Draw `text` as a prompt over the status line of the last frame,
+    /// leaving the text above in view.
+    pub fn draw_prompt(&mut self, text: &str) -> Result<()> {
+        self.paint(|f| render_prompt(f, text))
+    }
+
+    /// Draw the last frame again, e.g. once a modal is closed.
+    pub fn redraw(&mut self) -> Result<()> {
+        if self.last.is_none() && !self.offline {
+            self.terminal.clear()?;
+            return Ok(());
+        }
+        self.present()
+    }
+
+    /// Show `state` in the status line of the last frame; it lasts until
+    /// the server sends a new one. While reconnecting, a banner across the
+    /// top says edits are paused and the status shows the file read-only.
+    /// The change is painted at once, whatever the render budget.
+    pub fn set_connection(&mut self, state: ConnectionState) -> Result<()> {
+        self.offline = state == ConnectionState::Reconnecting;
+        self.painted = None;
+        if let Some(status) = self.last.as_mut().and_then(|f| f.status.as_mut()) {
+            status.connection = state;
+            if self.offline {
+                status.lock = LockState::ReadOnly;
             }
-            status.push_str(&right);
-            let status_area = Rect {
-                x: 0,
-                y: text_height,
-                width: size.width,
-                height: 1,
-            };
-            f.render_widget(Paragraph::new(status), status_area);
+        }
+        self.redraw()
+    }
+
+    /// Paint the last frame, or hold it back if the render budget is spent.
+    fn present(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let spent = (self.interval)
+            .zip(self.painted)
+            .is_some_and(|(interval, at)| now.duration_since(at) < interval);
+        if spent {
+            self.pending = true;
+            return Ok(());
+        }
+        self.paint(|_| {})
+    }
 
-            // Cursor placement
-            if let Some(cur) = frame.cursors.first() {
-                let x = cur.col;
-                let y = (cur.line - frame.first_line) as u16;
-                f.set_cursor_position((x, y));
+    /// Draw the last frame, then `overlay` and the reconnect banner.
+    fn paint(&mut self, overlay: impl FnOnce(&mut ratatui::Frame<'_>)) -> Result<()> {
+        let (last, layout, theme, rtt) = (&self.last, &self.layout, &self.theme, self.rtt);
+        let offline = self.offline;
+        let mut frame = match last {
+            Some(frame) if !self.echo.is_empty() => Some(Cow::Owned(echoed(frame, &self.echo))),
+            last => last.as_ref().map(Cow::Borrowed),
+        };
+        if let Some(frame) = frame.as_mut().filter(|_| !self.matches) {
+            strip_matches(frame.to_mut());
+        }
+        let drawn = &mut self.drawn;
+        self.painted = Some(Instant::now());
+        self.pending = false;
+        self.terminal.draw(|f| {
+            match &frame {
+                Some(frame) => {
+                    let status = status_text(frame, layout, rtt, f.area().width);
+                    let drawn = render_frame(drawn, frame, theme, status, f.area());
+                    f.buffer_mut().content.clone_from(&drawn.buffer.content);
+                    place_cursor(f, frame);
+                }
+                None => *drawn = None,
+            }
+            overlay(f);
+            if offline {
+                render_banner(f);
             }
         })?;
         Ok(())
     }
 }
 
+/// Remove the spans highlighting search matches from `frame`.
+fn strip_matches(frame: &mut Frame) {
+    let Some(class) = frame.classes.iter().position(|c| c == "match") else {
+        return;
+    };
+    for line in &mut frame.lines {
+        line.spans.retain(|s| s.class as usize != class);
+    }
+}
+
+/// `frame` with `text` typed at the viewer's cursor: what follows the
+/// cursor on its line, including styles and other cursors, moves right.
+fn echoed(frame: &Frame, text: &str) -> Frame {
+    let mut frame = frame.clone();
+    let Some(cur) = frame.cursors.iter().find(|c| c.user_id.is_none()) else {
+        return frame;
+    };
+    let (line, col) = (cur.line, cur.col);
+    let Some(row) = line.checked_sub(frame.first_line) else {
+        return frame;
+    };
+    let Some(x) = screen_col(&frame, cur) else {
+        return frame;
+    };
+    let Some(target) = frame.lines.get_mut(row as usize) else {
+        return frame;
+    };
+    let width = Span::raw(text).width() as u16;
+    let idx = byte_at(&target.text, x);
+    let pad = x.saturating_sub(Span::raw(&target.text[..idx]).width() as u16);
+    target
+        .text
+        .insert_str(idx, &(" ".repeat(pad as usize) + text));
+    for span in &mut target.spans {
+        if span.start_col >= x {
+            span.start_col += width;
+        }
+        if span.end_col > x {
+            span.end_col += width;
+        }
+    }
+    for cur in (frame.cursors.iter_mut()).filter(|c| c.line == line && c.col >= col) {
+        cur.col += width;
+    }
+    frame
+}
+
+/// Byte offset in `text` of the character drawn over cell `x`, or its
+/// length if the text ends before.
+fn byte_at(text: &str, x: u16) -> usize {
+    let mut at = 0;
+    (text.char_indices())
+        .find(|(_, c)| {
+            at += Span::raw(c.encode_utf8(&mut [0; 4]).to_string()).width() as u16;
+            at > x
+        })
+        .map_or(text.len(), |(i, _)| i)
+}
+
+/// Bring the cached rendering of the last frame up to date with `frame`
+/// in `area`: only text rows that differ, and the status line if it
+/// changed, are rendered again.
+fn render_frame<'a>(
+    drawn: &'a mut Option<Drawn>,
+    frame: &Frame,
+    theme: &Theme,
+    status: String,
+    area: Rect,
+) -> &'a Drawn {
+    let text_height = area.height.saturating_sub(1);
+    let rows: Vec<u16> = match drawn {
+        Some(drawn) if drawn.buffer.area == area => damaged_rows(&drawn.frame, frame, text_height),
+        _ => {
+            *drawn = None;
+            (0..text_height).collect()
+        }
+    };
+    let drawn = drawn.get_or_insert_with(|| Drawn {
+        frame: frame.clone(),
+        status: String::new(),
+        buffer: Buffer::empty(area),
+    });
+    for row in rows {
+        render_row(&mut drawn.buffer, frame, theme, row);
+    }
+    if status != drawn.status || drawn.status.is_empty() {
+        let status_area = Rect::new(0, text_height, area.width, 1.min(area.height));
+        clear(&mut drawn.buffer, status_area);
+        Paragraph::new(status.clone()).render(status_area, &mut drawn.buffer);
+        drawn.status = status;
+    }
+    drawn.frame.clone_from(frame);
+    drawn
+}
+
+/// Text rows below `height` that show something else in `new` than in
+/// `old`.
+fn damaged_rows(old: &Frame, new: &Frame, height: u16) -> Vec<u16> {
+    (0..height)
+        .filter(|&row| {
+            old.classes != new.classes
+                || old.first_col != new.first_col
+                || row_content(old, row) != row_content(new, row)
+        })
+        .collect()
+}
+
+/// What text row `row` of `frame` shows: its line and the cursors on it
+/// drawn as cells, which are all but the primary one.
+fn row_content(frame: &Frame, row: u16) -> (Option<&ghostwriter_proto::Line>, Vec<&Cursor>) {
+    let line = frame.first_line + u64::from(row);
+    let primary = primary_cursor(frame);
+    let cursors = (frame.cursors.iter())
+        .filter(|c| c.line == line && !primary.is_some_and(|p| std::ptr::eq(p, *c)))
+        .collect();
+    (frame.lines.get(row as usize), cursors)
+}
+
+/// Render text row `row` of `frame` into `buf`, replacing what was there.
+fn render_row(buf: &mut Buffer, frame: &Frame, theme: &Theme, row: u16) {
+    let width = buf.area.width;
+    let area = Rect::new(0, row, width, 1);
+    clear(buf, area);
+    if let Some(line) = frame.lines.get(row as usize) {
+        Paragraph::new(ratatui::text::Line::raw(line.text.clone())).render(area, buf);
+        // Classes of the viewer's own spans, the selection on top
+        let mut own: Vec<_> = (line.spans.iter())
+            .filter(|s| s.user_id.is_none())
+            .map(|s| (s, frame.class_name(s).unwrap_or_default()))
+            .collect();
+        own.sort_by_key(|(_, class)| Theme::layer(class));
+        for (span, class) in own {
+            let end = span.end_col.min(width);
+            if span.start_col < end {
+                let cells = Rect::new(span.start_col, row, end - span.start_col, 1);
+                buf.set_style(cells, theme.style(class));
+            }
+        }
+        // Other users' selections
+        for span in line.spans.iter().filter(|s| s.user_id.is_some()) {
+            let end = span.end_col.min(width);
+            if span.start_col < end {
+                let class = frame.class_name(span).unwrap_or_default();
+                buf.set_style(
+                    Rect::new(span.start_col, row, end - span.start_col, 1),
+                    Style::default().bg(peer_color(class)),
+                );
+            }
+        }
+        // Where the line goes on past either edge
+        let right = frame.cols.min(width);
+        if frame.first_col > 0 && !line.text.is_empty() && right > 0 {
+            buf[(0, row)].set_symbol("…").set_style(theme.style("more"));
+        }
+        if line.more && right > 0 {
+            buf[(right - 1, row)]
+                .set_symbol("$")
+                .set_style(theme.style("more"));
+        }
+        if line.marked && width > 0 {
+            buf[(0, row)].set_style(theme.style("bookmark"));
+        }
+    }
+    let (_, cursors) = row_content(frame, row);
+    let cells = cursors
+        .into_iter()
+        .filter_map(|c| Some((c, screen_col(frame, c)?)));
+    for (cur, x) in cells.filter(|&(_, x)| x < width) {
+        let style = match &cur.user_id {
+            Some(_) => {
+                let color = peer_color(cur.color_class.as_deref().unwrap_or_default());
+                Style::default().fg(Color::Black).bg(color)
+            }
+            None => theme.style("cursor"),
+        };
+        buf.set_style(Rect::new(x, row, 1, 1), style);
+    }
+}
+
+/// Reset every cell of `area` in `buf`.
+fn clear(buf: &mut Buffer, area: Rect) {
+    for y in area.top()..area.bottom() {
+        for x in area.left()..area.right() {
+            buf[(x, y)].reset();
+        }
+    }
+}
+
+/// The status line of `frame`, fitted to `width` so its right part ends
+/// there.
+fn status_text(frame: &Frame, layout: &StatusLayout, rtt: Option<Rtt>, width: u16) -> String {
+    match &frame.status {
+        Some(info) => layout.line(info, rtt, width),
+        None => status::fit(&frame.status_left, &frame.status_right, width),
+    }
+}
+
+/// The viewer's first cursor in `frame`, shown as the terminal cursor; any
+/// others are drawn as cells.
+fn primary_cursor(frame: &Frame) -> Option<&Cursor> {
+    frame.cursors.iter().find(|c| c.user_id.is_none())
+}
+
+/// Screen column of `cur` in `frame`, or `None` left of its first column.
+fn screen_col(frame: &Frame, cur: &Cursor) -> Option<u16> {
+    cur.col.checked_sub(frame.first_col)
+}
+
+/// Show the terminal cursor at the viewer's primary cursor in `frame`.
+fn place_cursor(f: &mut ratatui::Frame<'_>, frame: &Frame) {
+    if let Some(cur) = primary_cursor(frame) {
+        let Some(x) = screen_col(frame, cur) else {
+            return;
+        };
+        let y = (cur.line - frame.first_line) as u16;
+        f.set_cursor_position((x, y));
+    }
+}
+
+/// Banner across the top row while the connection is down.
+fn render_banner(f: &mut ratatui::Frame<'_>) {
+    let area = Rect {
+        height: 1.min(f.area().height),
+        ..f.area()
+    };
+    let banner = Paragraph::new(RECONNECT_BANNER)
+        .alignment(Alignment::Center)
+        .style(Style::default().fg(Color::Black).bg(Color::Yellow));
+    f.render_widget(Clear, area);
+    f.render_widget(banner, area);
+}
+
+/// `text` across the bottom row, where the status line is.
+fn render_prompt(f: &mut ratatui::Frame<'_>, text: &str) {
+    let size = f.area();
+    let area = Rect::new(
+        0,
+        size.height.saturating_sub(1),
+        size.width,
+        1.min(size.height),
+    );
+    let prompt = Paragraph::new(text).style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_widget(Clear, area);
+    f.render_widget(prompt, area);
+}
+
+/// Terminal color for a peer style class: a color name such as `"red"` or
+/// `"#ff8800"`, or any other class mapped onto a fixed palette.
+fn peer_color(class: &str) -> Color {
+    const PALETTE: [Color; 6] = [
+        Color::Magenta,
+        Color::Cyan,
+        Color::Yellow,
+        Color::Green,
+        Color::Blue,
+        Color::Red,
+    ];
+    class.parse().unwrap_or_else(|_| {
+        let hash = class
+            .bytes()
+            .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+        PALETTE[hash % PALETTE.len()]
+    })
+}
+
+fn render_dialog(f: &mut ratatui::Frame<'_>, view: &DialogView) {
+    let size = f.area();
+    let dialog = &view.dialog;
+    let mut lines = vec![ratatui::text::Line::raw(dialog.body.clone())];
+    if let Some(input) = &view.input {
+        lines.push(ratatui::text::Line::raw(format!("> {input}")));
+    }
+    let buttons: Vec<Span<'static>> = dialog
+        .buttons
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, button)| {
+            let style = if idx == view.selected {
+                Style::default().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            [
+                Span::styled(format!("[{}]", button.label), style),
+                Span::raw(" "),
+            ]
+        })
+        .collect();
+    lines.push(ratatui::text::Line::from(buttons));
+
+    let width = size.width.saturating_sub(4).clamp(1, 60);
+    let inner_width = width.saturating_sub(2).max(1) as usize;
+    let body_rows: u16 = lines
+        .iter()
+        .map(|l| l.width().max(1).div_ceil(inner_width) as u16)
+        .sum();
+    let height = (body_rows + 2).min(size.height);
+    let body_width = lines[0].width();
+    let area = Rect {
+        x: size.width.saturating_sub(width) / 2,
+        y: size.height.saturating_sub(height) / 2,
+        width,
+        height,
+    };
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(dialog.title.clone()),
+        ),
+        area,
+    );
+    if let Some(input) = &view.input {
+        // Both offsets are display columns so wide characters line up.
+        let input_width = ratatui::text::Line::raw(input.as_str()).width();
+        let x = area.x + 1 + 2 + input_width as u16;
+        let y = area.y + 1 + (body_width.max(1).div_ceil(inner_width)) as u16;
+        f.set_cursor_position((x.min(area.x + area.width.saturating_sub(2)), y));
+    }
+}
+
+/// Centered box for the picker, search and bookmark panels.
+fn panel_area(size: Rect) -> Rect {
+    let width = size.width.saturating_sub(4).clamp(1, 60);
+    let height = size.height.saturating_sub(2).clamp(1, 20);
+    Rect {
+        x: size.width.saturating_sub(width) / 2,
+        y: size.height.saturating_sub(height) / 2,
+        width,
+        height,
+    }
+}
+
+/// Highlight for the selected row of a panel.
+fn row_style(selected: bool) -> Style {
+    if selected {
+        Style::default().add_modifier(Modifier::REVERSED)
+    } else {
+        Style::default()
+    }
+}
+
+/// First row of `view` shown in the box `area`, scrolled so the
+/// highlighted entry stays inside.
+fn picker_first_row(view: &PickerView, area: Rect) -> usize {
+    let rows = area.height.saturating_sub(2).max(1) as usize;
+    view.selected.saturating_sub(rows - 1)
+}
+
+fn render_picker(f: &mut ratatui::Frame<'_>, view: &PickerView) {
+    let area = panel_area(f.area());
+    let rows = area.height.saturating_sub(2).max(1) as usize;
+    let first = picker_first_row(view, area);
+    let lines: Vec<ratatui::text::Line<'static>> = (view.rows().iter().enumerate())
+        .skip(first)
+        .take(rows)
+        .map(|(idx, row)| {
+            let name = row.entry.path.rsplit('/').next().unwrap_or_default();
+            let indent = "  ".repeat(row.depth);
+            let name = if row.entry.is_dir {
+                format!("{indent}{name}/")
+            } else {
+                format!("{indent}{name}")
+            };
+            ratatui::text::Line::styled(name, row_style(idx == view.selected))
+        })
+        .collect();
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Open")
+        .title_bottom("n new m folder r rename del delete");
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn render_search(f: &mut ratatui::Frame<'_>, view: &SearchView) {
+    let area = panel_area(f.area());
+    // The query takes the first row; scroll the matches below it.
+    let rows = area.height.saturating_sub(3).max(1) as usize;
+    let first = view.selected.saturating_sub(rows - 1);
+    let query = ratatui::text::Line::raw(format!("> {}", view.query));
+    let matches = (view.matches.iter().enumerate())
+        .skip(first)
+        .take(rows)
+        .map(|(idx, m)| {
+            let text = format!("{}:{}: {}", m.path, m.line + 1, m.text.trim_start());
+            ratatui::text::Line::styled(text, row_style(idx == view.selected))
+        });
+    let lines: Vec<_> = std::iter::once(query).chain(matches).collect();
+    let mut title = "Search".to_string();
+    if view.regex {
+        title += " [regex]";
+    }
+    if view.case {
+        title += " [case]";
+    }
+    if view.whole_word {
+        title += " [word]";
+    }
+    let found = match (view.matches.len(), view.done) {
+        _ if view.query.is_empty() => String::new(),
+        (n, false) => format!("{n} found, searching…"),
+        (0, true) => "no matches".to_string(),
+        (1, true) => "1 match".to_string(),
+        (n, true) => format!("{n} matches"),
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .title_bottom(found);
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn render_bookmarks(f: &mut ratatui::Frame<'_>, view: &BookmarkView) {
+    let area = panel_area(f.area());
+    let rows = area.height.saturating_sub(2).max(1) as usize;
+    let first = view.selected.saturating_sub(rows - 1);
+    let lines: Vec<_> = (view.marks.iter().enumerate())
+        .skip(first)
+        .take(rows)
+        .map(|(idx, m)| {
+            let text = format!("{}:{}: {}", m.path, m.line + 1, m.text.trim_start());
+            ratatui::text::Line::styled(text, row_style(idx == view.selected))
+        })
+        .collect();
+    let found = match view.marks.len() {
+        0 => "no bookmarks".to_string(),
+        1 => "1 bookmark".to_string(),
+        n => format!("{n} bookmarks"),
+    };
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Bookmarks")
+        .title_bottom(found);
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
 impl<B: Backend> Drop for Tui<B> {
     fn drop(&mut self) {
+        if self.mouse {
+            let _ = crossterm::execute!(io::stdout(), DisableMouseCapture);
+        }
         if self.raw_mode {
+            let _ = crossterm::execute!(io::stdout(), DisableBracketedPaste);
             let _ = self.terminal.show_cursor();
             let _ = disable_raw_mode();
         }
@@ -94,12 +781,16 @@ impl<B: Backend> Tui<B> {
     pub fn backend(&mut self) -> &mut B {
         self.terminal.backend_mut()
     }
+
+    pub fn escapes(&self) -> &[String] {
+        &self.escapes
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ghostwriter_proto::{Cursor, Line, StyleSpan};
+    use ghostwriter_proto::{Dialog, DialogButton, Line, StyleSpan};
     use ratatui::backend::TestBackend;
 
     #[test]
@@ -112,6 +803,7 @@ mod tests {
             kind: "editor".into(),
             doc_v: 1,
             first_line: 0,
+            first_col: 0,
             cols: 10,
             rows: 3,
             lines: vec![Line {
@@ -119,12 +811,18 @@ mod tests {
                 spans: vec![StyleSpan {
                     start_col: 0,
                     end_col: 5,
-                    class_name: "sel".into(),
+                    class: 0,
+                    user_id: None,
                 }],
+                more: false,
+                marked: false,
             }],
-            cursors: vec![Cursor { line: 0, col: 5 }],
+            cursors: vec![Cursor::new(0, 5)],
             status_left: "L".into(),
             status_right: "R".into(),
+            status: None,
+            classes: vec!["sel".into()],
+            checksum: None,
         };
 
         tui.draw(&frame).unwrap();
@@ -132,10 +830,562 @@ mod tests {
         let backend = tui.backend();
         let buffer = backend.buffer().clone();
         let cursor = backend.get_cursor_position().unwrap();
-        assert_eq!(
-            buffer,
-            Buffer::with_lines(vec!["hello     ", "          ", "L        R",]),
+        let mut expected = Buffer::with_lines(vec!["hello     ", "          ", "L        R"]);
+        expected.set_style(
+            Rect::new(0, 0, 5, 1),
+            Style::default().add_modifier(Modifier::REVERSED),
         );
+        assert_eq!(buffer, expected);
         assert_eq!(cursor, (5, 0).into());
     }
+
+    #[test]
+    fn styles_spans_by_class() {
+        let mut tui = Tui::new_for_test(TestBackend::new(8, 2)).unwrap();
+        let span = |start_col, end_col, class| StyleSpan {
+            start_col,
+            end_col,
+            class,
+            user_id: None,
+        };
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 8,
+            rows: 1,
+            lines: vec![Line {
+                text: "fn main".into(),
+                // The selection is sent before the match it covers.
+                spans: vec![span(0, 2, 2), span(3, 7, 0), span(3, 5, 1), span(2, 3, 3)],
+                more: false,
+                marked: false,
+            }],
+            cursors: Vec::new(),
+            status_left: String::new(),
+            status_right: String::new(),
+            status: None,
+            classes: vec![
+                "match".into(),
+                "sel".into(),
+                "keyword.function".into(),
+                "unknown".into(),
+            ],
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+
+        let mut expected = Buffer::with_lines(vec!["fn main ", "        "]);
+        let theme = Theme::default();
+        expected.set_style(Rect::new(0, 0, 2, 1), theme.style("keyword"));
+        expected.set_style(Rect::new(3, 0, 4, 1), theme.style("match"));
+        expected.set_style(Rect::new(3, 0, 2, 1), theme.style("sel"));
+        assert_eq!(tui.backend().buffer().clone(), expected);
+
+        // Without match highlighting only the selection shows.
+        assert!(!tui.toggle_matches());
+        tui.redraw().unwrap();
+        let mut unmatched = Buffer::with_lines(vec!["fn main ", "        "]);
+        unmatched.set_style(Rect::new(0, 0, 2, 1), theme.style("keyword"));
+        unmatched.set_style(Rect::new(3, 0, 2, 1), theme.style("sel"));
+        assert_eq!(tui.backend().buffer().clone(), unmatched);
+        assert!(tui.toggle_matches());
+
+        tui.set_theme(Theme::empty());
+        tui.redraw().unwrap();
+        let plain = Buffer::with_lines(vec!["fn main ", "        "]);
+        assert_eq!(tui.backend().buffer().clone(), plain);
+    }
+
+    #[test]
+    fn draws_peer_cursor_and_selection() {
+        let backend = TestBackend::new(6, 2);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 6,
+            rows: 1,
+            lines: vec![Line {
+                text: "hello".into(),
+                spans: vec![StyleSpan {
+                    start_col: 0,
+                    end_col: 2,
+                    class: 0,
+                    user_id: Some("u2".into()),
+                }],
+                more: false,
+                marked: false,
+            }],
+            cursors: vec![
+                Cursor {
+                    line: 0,
+                    col: 4,
+                    user_id: Some("u2".into()),
+                    label: Some("Ada".into()),
+                    color_class: Some("blue".into()),
+                },
+                Cursor::new(0, 1),
+            ],
+            status_left: String::new(),
+            status_right: String::new(),
+            status: None,
+            classes: vec!["red".into()],
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+
+        let backend = tui.backend();
+        let mut expected = Buffer::with_lines(vec!["hello ", "      "]);
+        expected.set_style(Rect::new(0, 0, 2, 1), Style::default().bg(Color::Red));
+        expected.set_style(
+            Rect::new(4, 0, 1, 1),
+            Style::default().fg(Color::Black).bg(Color::Blue),
+        );
+        assert_eq!(backend.buffer().clone(), expected);
+        assert_eq!(backend.get_cursor_position().unwrap(), (1, 0).into());
+    }
+
+    #[test]
+    fn marks_lines_scrolled_past_either_edge_and_bookmarked() {
+        let mut tui = Tui::new_for_test(TestBackend::new(6, 3)).unwrap();
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 3,
+            cols: 6,
+            rows: 2,
+            lines: vec![
+                Line {
+                    text: "defghi".into(),
+                    spans: Vec::new(),
+                    more: true,
+                    marked: false,
+                },
+                Line {
+                    text: String::new(),
+                    spans: Vec::new(),
+                    more: false,
+                    marked: true,
+                },
+            ],
+            cursors: vec![Cursor::new(0, 5), Cursor::new(0, 7), Cursor::new(1, 1)],
+            status_left: String::new(),
+            status_right: String::new(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+
+        let theme = Theme::default();
+        let mut expected = Buffer::with_lines(vec!["…efgh$", "      ", "      "]);
+        expected.set_style(Rect::new(0, 0, 1, 1), theme.style("more"));
+        expected.set_style(Rect::new(5, 0, 1, 1), theme.style("more"));
+        expected.set_style(Rect::new(0, 1, 1, 1), theme.style("bookmark"));
+        // The cursor left of the first column is not drawn.
+        expected.set_style(Rect::new(4, 0, 1, 1), theme.style("cursor"));
+        assert_eq!(tui.backend().buffer().clone(), expected);
+        assert_eq!(tui.backend().get_cursor_position().unwrap(), (2, 0).into());
+    }
+
+    #[test]
+    fn draws_secondary_cursors_as_cells() {
+        let mut tui = Tui::new_for_test(TestBackend::new(6, 3)).unwrap();
+        let line = |text: &str| Line {
+            text: text.into(),
+            spans: Vec::new(),
+            more: false,
+            marked: false,
+        };
+        let mut frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 6,
+            rows: 2,
+            lines: vec![line("one"), line("two")],
+            cursors: vec![Cursor::new(0, 1), Cursor::new(0, 3), Cursor::new(1, 0)],
+            status_left: String::new(),
+            status_right: String::new(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+
+        let cursor = Theme::default().style("cursor");
+        let mut expected = Buffer::with_lines(vec!["one   ", "two   ", "      "]);
+        expected.set_style(Rect::new(3, 0, 1, 1), cursor);
+        expected.set_style(Rect::new(0, 1, 1, 1), cursor);
+        assert_eq!(tui.backend().buffer().clone(), expected);
+        assert_eq!(tui.backend().get_cursor_position().unwrap(), (1, 0).into());
+
+        // The second row changes only by losing its cursor.
+        frame.cursors.pop();
+        tui.draw(&frame).unwrap();
+        let mut expected = Buffer::with_lines(vec!["one   ", "two   ", "      "]);
+        expected.set_style(Rect::new(3, 0, 1, 1), cursor);
+        assert_eq!(tui.backend().buffer().clone(), expected);
+    }
+
+    #[test]
+    fn formats_structured_status() {
+        let backend = TestBackend::new(12, 2);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+        tui.set_status_layout(StatusLayout {
+            left: "{path}{dirty}".into(),
+            right: "{line}:{col}".into(),
+        });
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 12,
+            rows: 1,
+            lines: Vec::new(),
+            cursors: Vec::new(),
+            status_left: "ignored".into(),
+            status_right: String::new(),
+            status: Some(ghostwriter_proto::Status {
+                path: "a.rs".into(),
+                dirty: true,
+                lock: ghostwriter_proto::LockState::Writable,
+                connection: ghostwriter_proto::ConnectionState::Local,
+                doc_v: 1,
+                line: 2,
+                col: 3,
+                encoding: "UTF-8".into(),
+                eol: "LF".into(),
+                search: None,
+                selection: None,
+            }),
+            classes: Vec::new(),
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+        assert_eq!(
+            tui.backend().buffer().clone(),
+            Buffer::with_lines(vec!["            ", "a.rs [+] 3:4"]),
+        );
+    }
+
+    #[test]
+    fn draws_dialog_over_frame() {
+        let backend = TestBackend::new(20, 6);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+        let view = DialogView::new(Dialog {
+            id: "quit".into(),
+            title: "Quit".into(),
+            body: "Save?".into(),
+            buttons: vec![
+                DialogButton {
+                    id: "y".into(),
+                    label: "Yes".into(),
+                },
+                DialogButton {
+                    id: "n".into(),
+                    label: "No".into(),
+                },
+            ],
+            input: None,
+        });
+        tui.draw_dialog(&view).unwrap();
+
+        let mut expected = Buffer::with_lines(vec![
+            "                    ",
+            "  ┌Quit──────────┐  ",
+            "  │Save?         │  ",
+            "  │[Yes] [No]    │  ",
+            "  └──────────────┘  ",
+            "                    ",
+        ]);
+        expected.set_style(
+            Rect::new(3, 3, 5, 1),
+            Style::default().add_modifier(Modifier::REVERSED),
+        );
+        assert_eq!(tui.backend().buffer().clone(), expected);
+    }
+
+    #[test]
+    fn places_dialog_cursor_by_display_width() {
+        let mut tui = Tui::new_for_test(TestBackend::new(20, 6)).unwrap();
+        let view = DialogView::new(Dialog {
+            id: "rename".into(),
+            title: "Rename".into(),
+            body: "名前".into(),
+            buttons: Vec::new(),
+            input: Some("日本".into()),
+        });
+        tui.draw_dialog(&view).unwrap();
+        // Box at column 2, then the border, "> " and two wide characters.
+        let cursor = tui.backend().get_cursor_position().unwrap();
+        assert_eq!((cursor.x, cursor.y), (2 + 1 + 2 + 4, 2));
+    }
+
+    #[test]
+    fn draws_panels_on_tiny_terminals() {
+        let mut tui = Tui::new_for_test(TestBackend::new(1, 1)).unwrap();
+        let view = DialogView::new(Dialog {
+            id: "quit".into(),
+            title: "Quit".into(),
+            body: "Save?".into(),
+            buttons: Vec::new(),
+            input: Some(String::new()),
+        });
+        tui.draw_dialog(&view).unwrap();
+        tui.draw_picker(&PickerView::new(None)).unwrap();
+    }
+
+    #[test]
+    fn draws_picker_and_connection_state() {
+        let backend = TestBackend::new(20, 6);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+        let entry = |path: &str, is_dir| ghostwriter_proto::DirEntry {
+            path: path.into(),
+            is_dir,
+        };
+        let list = |path: &str, entries| ghostwriter_proto::DirList {
+            path: path.into(),
+            query: None,
+            entries,
+        };
+        let mut view = PickerView::new(Some("src/b.rs".into()));
+        view.update(list("", vec![entry("src", true)]));
+        view.update(list(
+            "src",
+            vec![entry("src/a", true), entry("src/b.rs", false)],
+        ));
+        tui.draw_picker(&view).unwrap();
+
+        let mut expected = Buffer::with_lines(vec![
+            "                    ",
+            "  ┌Open──────────┐  ",
+            "  │  a/          │  ",
+            "  │  b.rs        │  ",
+            "  └n new m folder┘  ",
+            "                    ",
+        ]);
+        // Scrolled so the highlighted file, below `src/`, shows.
+        expected.set_style(
+            Rect::new(3, 3, 6, 1),
+            Style::default().add_modifier(Modifier::REVERSED),
+        );
+        assert_eq!(tui.backend().buffer().clone(), expected);
+
+        tui.set_status_layout(StatusLayout {
+            left: "{conn}{path}".into(),
+            right: String::new(),
+        });
+        let mut status = crate::status::tests::status();
+        status.path = "b.rs".into();
+        let frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 20,
+            rows: 5,
+            lines: Vec::new(),
+            cursors: Vec::new(),
+            status_left: String::new(),
+            status_right: String::new(),
+            status: Some(status),
+            classes: Vec::new(),
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+        tui.set_connection(ConnectionState::Reconnecting).unwrap();
+        let buffer = tui.backend().buffer().clone();
+        let last = (0..20).map(|x| buffer[(x, 5)].symbol()).collect::<String>();
+        assert_eq!(last, "reconnecting…  b.rs ");
+        let top = (0..20).map(|x| buffer[(x, 0)].symbol()).collect::<String>();
+        assert!(top.contains("reconnecting…"), "{top:?}");
+    }
+
+    #[test]
+    fn draws_search_panel() {
+        let backend = TestBackend::new(24, 6);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+        let found = |path: &str, line| ghostwriter_proto::FileMatch {
+            path: path.into(),
+            line,
+            text: "  let x".into(),
+            range: ghostwriter_proto::Range { from: 2, to: 5 },
+        };
+        let view = SearchView {
+            query: "let".into(),
+            regex: true,
+            matches: vec![found("a.rs", 0), found("b.rs", 9)],
+            selected: 1,
+            ..SearchView::default()
+        };
+        tui.draw_search(&view).unwrap();
+
+        let mut expected = Buffer::with_lines(vec![
+            "                        ",
+            "  ┌Search [regex]────┐  ",
+            "  │> let             │  ",
+            "  │b.rs:10: let x    │  ",
+            "  └2 found, searching┘  ",
+            "                        ",
+        ]);
+        // Scrolled so the highlighted match shows.
+        expected.set_style(
+            Rect::new(3, 3, 14, 1),
+            Style::default().add_modifier(Modifier::REVERSED),
+        );
+        assert_eq!(tui.backend().buffer().clone(), expected);
+    }
+
+    #[test]
+    fn holds_frames_back_within_the_render_budget() {
+        let mut tui = Tui::new_for_test(TestBackend::new(6, 2)).unwrap();
+        tui.set_render_budget(Some(1));
+        let line = |text: &str| Line {
+            text: text.into(),
+            spans: Vec::new(),
+            more: false,
+            marked: false,
+        };
+        let mut frame = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 6,
+            rows: 1,
+            lines: vec![line("one")],
+            cursors: Vec::new(),
+            status_left: String::new(),
+            status_right: String::new(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        tui.draw(&frame).unwrap();
+        frame.lines = vec![line("two")];
+        tui.draw(&frame).unwrap();
+        frame.lines = vec![line("three")];
+        tui.draw(&frame).unwrap();
+        tui.flush().unwrap();
+        let shown = Buffer::with_lines(vec!["one   ", "      "]);
+        assert_eq!(tui.backend().buffer().clone(), shown);
+
+        // Once the budget allows, only the latest frame is painted.
+        tui.painted = tui.painted.map(|at| at - Duration::from_secs(1));
+        tui.flush().unwrap();
+        let shown = Buffer::with_lines(vec!["three ", "      "]);
+        assert_eq!(tui.backend().buffer().clone(), shown);
+        assert!(!tui.pending);
+    }
+
+    #[test]
+    fn applies_diff_to_last_frame() {
+        let backend = TestBackend::new(10, 3);
+        let mut tui = Tui::new_for_test(backend).unwrap();
+
+        let base = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 10,
+            rows: 3,
+            lines: vec![Line {
+                text: "hello".into(),
+                spans: Vec::new(),
+                more: false,
+                marked: false,
+            }],
+            cursors: vec![Cursor::new(0, 5)],
+            status_left: "L".into(),
+            status_right: "R".into(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        let mut next = base.clone();
+        next.doc_v = 2;
+        next.lines[0].text = "hello!".into();
+        next.cursors = vec![Cursor::new(0, 6)];
+        let diff = FrameDiff::between(&base, &next).unwrap();
+
+        assert!(!tui.draw_diff(&diff).unwrap());
+        tui.draw(&base).unwrap();
+        assert!(tui.draw_diff(&diff).unwrap());
+
+        let backend = tui.backend();
+        assert_eq!(
+            backend.buffer().clone(),
+            Buffer::with_lines(vec!["hello!    ", "          ", "L        R",]),
+        );
+        assert_eq!(backend.get_cursor_position().unwrap(), (6, 0).into());
+
+        // Applying the same diff again is a desync.
+        assert!(!tui.draw_diff(&diff).unwrap());
+    }
+
+    #[test]
+    fn renders_only_the_rows_a_frame_changes() {
+        let mut tui = Tui::new_for_test(TestBackend::new(10, 4)).unwrap();
+        let line = |text: &str| Line {
+            text: text.into(),
+            spans: Vec::new(),
+            more: false,
+            marked: false,
+        };
+        let base = Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 10,
+            rows: 3,
+            lines: vec![line("hello"), line("world"), line("!")],
+            cursors: vec![Cursor::new(0, 0)],
+            status_left: "L".into(),
+            status_right: "R".into(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        let mut next = base.clone();
+        next.lines[1] = line("wo");
+        next.cursors = vec![Cursor::new(2, 1)];
+        assert_eq!(damaged_rows(&base, &next, 3), [1]);
+
+        let mut peer = Cursor::new(0, 1);
+        peer.user_id = Some("bob".into());
+        let mut moved = next.clone();
+        moved.cursors.push(peer.clone());
+        assert_eq!(damaged_rows(&next, &moved, 3), [0]);
+        peer.line = 2;
+        let mut scrolled = next.clone();
+        scrolled.cursors.push(peer);
+        assert_eq!(damaged_rows(&moved, &scrolled, 3), [0, 2]);
+
+        tui.draw(&base).unwrap();
+        tui.draw(&next).unwrap();
+        assert_eq!(
+            tui.backend().buffer().clone(),
+            Buffer::with_lines(vec!["hello     ", "wo        ", "!         ", "L        R"]),
+        );
+        assert_eq!(tui.backend().get_cursor_position().unwrap(), (1, 2).into());
+    }
 }
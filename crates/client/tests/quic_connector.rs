@@ -0,0 +1,45 @@
+#![cfg(feature = "quic")]
+
+use futures_util::{SinkExt, StreamExt};
+use ghostwriter_client::remote::WsClient;
+use ghostwriter_core::quic;
+use ghostwriter_core::transport::decompress_payload;
+use ghostwriter_proto::{Envelope, Hello, MessageType, RequestFrame, decode, encode, negotiate};
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn hello_and_request_frame_over_quic() {
+    let (cert, key) = quic::self_signed_cert(vec!["localhost".into()]).unwrap();
+    let endpoint = quic::server_endpoint(([127, 0, 0, 1], 0).into(), cert.clone(), key).unwrap();
+    let port = endpoint.local_addr().unwrap().port();
+
+    let server = tokio::spawn(async move {
+        let (_, mut ws) = quic::accept(&endpoint).await.unwrap().unwrap();
+
+        let msg = ws.next().await.unwrap().unwrap();
+        let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
+        assert_eq!(env.ty, MessageType::Hello);
+        let ack = Envelope::new(MessageType::HelloAck, negotiate(&env).unwrap());
+        ws.send(Message::Binary(encode(&ack).unwrap().into()))
+            .await
+            .unwrap();
+
+        // The client asked for `deflate`, so the request carries its flag.
+        let msg = ws.next().await.unwrap().unwrap();
+        let data = decompress_payload(&msg.into_data()).unwrap();
+        let env: Envelope<RequestFrame> = decode(&data).unwrap();
+        assert_eq!(env.data.reason, "initial");
+    });
+
+    let url = format!("quic://localhost:{port}");
+    let _client = WsClient::connect_quic(&url, 80, 24, None, &[cert])
+        .await
+        .unwrap();
+    server.await.unwrap();
+
+    assert!(
+        WsClient::connect_quic("ws://localhost:1", 80, 24, None, &[])
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,66 @@
+use futures_util::{SinkExt, StreamExt};
+use ghostwriter_client::remote::WsClient;
+use ghostwriter_core::tls::{self, TlsAcceptor};
+use ghostwriter_core::transport::decompress_payload;
+use ghostwriter_proto::{Envelope, Hello, MessageType, RequestFrame, decode, encode, negotiate};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+/// Acceptor presenting a fresh self-signed certificate for `localhost`,
+/// and that certificate.
+fn self_signed() -> (TlsAcceptor, tls::Certificate<'static>) {
+    let dir = tempfile::tempdir().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert = dir.path().join("cert.pem");
+    let key = dir.path().join("key.pem");
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let acceptor = tls::acceptor(&cert, &key, None).unwrap();
+    (acceptor, certified.cert.der().clone())
+}
+
+#[tokio::test]
+async fn hello_and_request_frame_over_wss() {
+    let (acceptor, cert) = self_signed();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+
+        let msg = ws.next().await.unwrap().unwrap();
+        let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
+        assert_eq!(env.ty, MessageType::Hello);
+        let ack = Envelope::new(MessageType::HelloAck, negotiate(&env).unwrap());
+        ws.send(Message::Binary(encode(&ack).unwrap().into()))
+            .await
+            .unwrap();
+
+        // The client asked for `deflate`, so the request carries its flag.
+        let msg = ws.next().await.unwrap().unwrap();
+        let data = decompress_payload(&msg.into_data()).unwrap();
+        let env: Envelope<RequestFrame> = decode(&data).unwrap();
+        assert_eq!(env.data.reason, "initial");
+
+        // The system roots do not vouch for a self-signed certificate.
+        let (stream, _) = listener.accept().await.unwrap();
+        assert!(acceptor.accept(stream).await.is_err());
+    });
+
+    let url = format!("wss://localhost:{port}");
+    let roots = [cert];
+    let _client = WsClient::connect_with_ca(&url, 80, 24, None, &roots)
+        .await
+        .unwrap();
+    assert!(WsClient::connect(&url, 80, 24, None).await.is_err());
+    server.await.unwrap();
+
+    let plain = format!("ws://localhost:{port}");
+    assert!(
+        WsClient::connect_with_ca(&plain, 80, 24, None, &roots)
+            .await
+            .is_err()
+    );
+}
@@ -1,8 +1,33 @@
-use futures_util::StreamExt;
-use ghostwriter_client::remote::WsClient;
-use ghostwriter_proto::{Auth, Envelope, Hello, MessageType, RequestFrame, Resize, decode};
-use tokio::net::TcpListener;
-use tokio_tungstenite::accept_async;
+use futures_util::{SinkExt, StreamExt};
+use ghostwriter_client::remote::{AuthError, WsClient};
+use ghostwriter_core::transport::{compress_payload, decompress_payload};
+use ghostwriter_proto::{
+    Auth, Envelope, ErrorCode, ErrorMsg, Hello, Insert, MessageType, RequestFrame, Resize, Role,
+    Scroll, caps, decode, encode, negotiate, peek_type, unbatch,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{WebSocketStream, accept_async, tungstenite::Message};
+
+type Server = WebSocketStream<TcpStream>;
+
+/// Read the client's `Hello` and acknowledge it without compression,
+/// asking for `Auth` next if `auth`.
+async fn accept_hello(ws: &mut Server, auth: bool) -> Envelope<Hello> {
+    let msg = ws.next().await.unwrap().unwrap();
+    let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
+    assert_eq!(env.ty, MessageType::Hello);
+    let mut ack = negotiate(&env).unwrap();
+    ack.auth = auth;
+    ack.caps &= !caps::COMPRESSION;
+    ack.features.retain(|f| f != "deflate");
+    send(ws, MessageType::HelloAck, ack).await;
+    env
+}
+
+async fn send<T: serde::Serialize>(ws: &mut Server, ty: MessageType, data: T) {
+    let data = encode(&Envelope::new(ty, data)).unwrap();
+    ws.send(Message::Binary(data.into())).await.unwrap();
+}
 
 #[tokio::test]
 async fn hello_and_request_frame_on_connect_and_resize() {
@@ -14,9 +39,8 @@ async fn hello_and_request_frame_on_connect_and_resize() {
         let mut ws = accept_async(stream).await.unwrap();
 
         // Hello
-        let msg = ws.next().await.unwrap().unwrap();
-        let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
-        assert_eq!(env.ty, MessageType::Hello);
+        let env = accept_hello(&mut ws, false).await;
+        assert_eq!(env.data.role, Role::Editor);
 
         // RequestFrame (initial)
         let msg = ws.next().await.unwrap().unwrap();
@@ -42,6 +66,27 @@ async fn hello_and_request_frame_on_connect_and_resize() {
     server.await.unwrap();
 }
 
+#[tokio::test]
+async fn follow_announces_the_follower_role() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+        let env = accept_hello(&mut ws, false).await;
+        assert_eq!(env.data.role, Role::Follower);
+        let msg = ws.next().await.unwrap().unwrap();
+        let env: Envelope<RequestFrame> = decode(&msg.into_data()).unwrap();
+        assert_eq!(env.data.reason, "initial");
+    });
+
+    let url = format!("ws://{addr}");
+    let _client = WsClient::follow(&url, 80, 24, None).await.unwrap();
+
+    server.await.unwrap();
+}
+
 #[tokio::test]
 async fn sends_auth_when_secret_provided() {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -52,9 +97,7 @@ async fn sends_auth_when_secret_provided() {
         let mut ws = accept_async(stream).await.unwrap();
 
         // Hello
-        let msg = ws.next().await.unwrap().unwrap();
-        let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
-        assert_eq!(env.ty, MessageType::Hello);
+        accept_hello(&mut ws, true).await;
 
         // Auth
         let msg = ws.next().await.unwrap().unwrap();
@@ -66,12 +109,158 @@ async fn sends_auth_when_secret_provided() {
         let msg = ws.next().await.unwrap().unwrap();
         let env: Envelope<RequestFrame> = decode(&msg.into_data()).unwrap();
         assert_eq!(env.data.reason, "initial");
+
+        // What passed credentials get first reaches the editor as usual.
+        send(&mut ws, MessageType::ListSessions, ()).await;
+        ws.next().await;
+    });
+
+    let url = format!("ws://{addr}");
+    let mut client = WsClient::connect(&url, 80, 24, Some(Auth::shared_secret("s3cr3t")))
+        .await
+        .unwrap();
+    let data = client.recv().await.unwrap().unwrap();
+    assert_eq!(peek_type(&data).unwrap(), MessageType::ListSessions);
+    client.close().await.unwrap();
+
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn compresses_everything_after_the_hello_once_agreed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+        let msg = ws.next().await.unwrap().unwrap();
+        let env: Envelope<Hello> = decode(&msg.into_data()).unwrap();
+        let mut ack = negotiate(&env).unwrap();
+        assert!(ack.has(caps::COMPRESSION));
+        ack.auth = true;
+        send(&mut ws, MessageType::HelloAck, ack).await;
+
+        let msg = ws.next().await.unwrap().unwrap();
+        let env: Envelope<Auth> = decode(&decompress_payload(&msg.into_data()).unwrap()).unwrap();
+        assert_eq!(env.data.secret, "s3cr3t");
+        let msg = ws.next().await.unwrap().unwrap();
+        let data = decompress_payload(&msg.into_data()).unwrap();
+        let env: Envelope<RequestFrame> = decode(&data).unwrap();
+        assert_eq!(env.data.reason, "initial");
+
+        let data = encode(&Envelope::new(MessageType::ListSessions, ())).unwrap();
+        let data = compress_payload(&data, 0).unwrap();
+        ws.send(Message::Binary(data.into())).await.unwrap();
+
+        // Large messages are deflated.
+        let data = ws.next().await.unwrap().unwrap().into_data();
+        let insert = decompress_payload(&data).unwrap();
+        assert!(data.len() < insert.len() / 10);
+        let env: Envelope<Insert> = decode(&insert).unwrap();
+        assert_eq!(env.data.text, "a".repeat(4096));
     });
 
     let url = format!("ws://{addr}");
-    let _client = WsClient::connect(&url, 80, 24, Some("s3cr3t"))
+    let mut client = WsClient::connect(&url, 80, 24, Some(Auth::shared_secret("s3cr3t")))
         .await
         .unwrap();
+    let data = client.recv().await.unwrap().unwrap();
+    assert_eq!(peek_type(&data).unwrap(), MessageType::ListSessions);
+    let insert = Insert {
+        pos: 0,
+        text: "a".repeat(4096),
+        seq: 1,
+        base_doc_v: None,
+    };
+    client.queue(MessageType::Insert, insert).unwrap();
+    client.flush().await.unwrap();
+
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_missing_and_refused_credentials() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+        accept_hello(&mut ws, true).await;
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+        accept_hello(&mut ws, true).await;
+        ws.next().await.unwrap().unwrap();
+        let err = ErrorMsg::new(ErrorCode::Unauthorized, "unauthorized");
+        send(&mut ws, MessageType::Error, err).await;
+    });
+
+    let url = format!("ws://{addr}");
+    let err = WsClient::connect(&url, 80, 24, None).await.err().unwrap();
+    assert_eq!(err.downcast_ref(), Some(&AuthError::Required));
+    let auth = Some(Auth::shared_secret("wrong"));
+    let err = WsClient::connect(&url, 80, 24, auth).await.err().unwrap();
+    assert_eq!(err.downcast_ref(), Some(&AuthError::Refused));
+
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn flush_coalesces_queued_input_into_batch() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(stream).await.unwrap();
+
+        // Hello + RequestFrame (initial)
+        accept_hello(&mut ws, false).await;
+        ws.next().await.unwrap().unwrap();
+
+        // Batched inserts
+        let data = ws.next().await.unwrap().unwrap().into_data();
+        assert_eq!(peek_type(&data).unwrap(), MessageType::Batch);
+        let texts: Vec<String> = unbatch(&data)
+            .unwrap()
+            .iter()
+            .map(|m| decode::<Insert>(m).unwrap().data.text)
+            .collect();
+        assert_eq!(texts, ["a", "b", "c"]);
+
+        // A lone message is sent unwrapped
+        let data = ws.next().await.unwrap().unwrap().into_data();
+        let env: Envelope<Scroll> = decode(&data).unwrap();
+        assert_eq!(env.data.delta, 1);
+    });
+
+    let url = format!("ws://{addr}");
+    let mut client = WsClient::connect(&url, 80, 24, None).await.unwrap();
+    for (seq, text) in ["a", "b", "c"].into_iter().enumerate() {
+        let insert = Insert {
+            pos: seq as u64,
+            text: text.into(),
+            seq: seq as u64,
+            base_doc_v: None,
+        };
+        client.queue(MessageType::Insert, insert).unwrap();
+    }
+    assert_eq!(client.pending(), 3);
+    client.flush().await.unwrap();
+    assert_eq!(client.pending(), 0);
+    client.flush().await.unwrap(); // nothing queued, nothing sent
+    client
+        .queue(
+            MessageType::Scroll,
+            Scroll {
+                delta: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    client.flush().await.unwrap();
 
     server.await.unwrap();
 }
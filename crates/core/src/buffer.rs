@@ -84,6 +84,29 @@ impl RopeBuffer {
         self.rope.len_lines()
     }
 
+    /// Total length of the buffer in bytes.
+    pub fn len_bytes(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    /// Length of `line` in bytes, excluding its trailing newline.
+    pub fn line_len(&self, line: usize) -> usize {
+        let slice = self.rope.line(line);
+        let len = slice.len_bytes();
+        if len > 0 && slice.byte(len - 1) == b'\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    /// Round `byte_idx` down to the nearest character boundary, clamped to
+    /// the buffer length.
+    pub fn floor_char_boundary(&self, byte_idx: usize) -> usize {
+        let idx = byte_idx.min(self.rope.len_bytes());
+        self.rope.char_to_byte(self.rope.byte_to_char(idx))
+    }
+
     /// Insert `text` at the given byte index.
     pub fn insert(&mut self, byte_idx: usize, text: &str) {
         let char_idx = self.rope.byte_to_char(byte_idx);
@@ -146,6 +169,57 @@ impl RopeBuffer {
             .map(|g| byte_idx + g.len())
     }
 
+    /// Return the byte index of the start of the word at or before
+    /// `byte_idx`. At the start of a line this moves to the end of the
+    /// previous line.
+    pub fn word_left(&self, byte_idx: usize) -> usize {
+        let (line, col) = self.byte_to_line_col(byte_idx);
+        if col == 0 {
+            return if line == 0 {
+                0
+            } else {
+                self.line_to_byte(line - 1) + self.line_len(line - 1)
+            };
+        }
+        let text = self.slice_lines(line, 1).pop().unwrap_or_default();
+        let start = self.line_to_byte(line);
+        text.split_word_bound_indices()
+            .rfind(|(idx, word)| *idx < col && is_word(word))
+            .map_or(start, |(idx, _)| start + idx)
+    }
+
+    /// Return the byte index of the end of the word at or after `byte_idx`.
+    /// At the end of a line this moves to the start of the next line.
+    pub fn word_right(&self, byte_idx: usize) -> usize {
+        let (line, col) = self.byte_to_line_col(byte_idx);
+        let len = self.line_len(line);
+        let start = self.line_to_byte(line);
+        if col >= len {
+            return if line + 1 < self.len_lines() {
+                self.line_to_byte(line + 1)
+            } else {
+                start + len
+            };
+        }
+        let text = self.slice_lines(line, 1).pop().unwrap_or_default();
+        text.split_word_bound_indices()
+            .find(|(idx, word)| idx + word.len() > col && is_word(word))
+            .map_or(start + len, |(idx, word)| start + idx + word.len())
+    }
+
+    /// Return the byte range of the word containing `byte_idx` or ending
+    /// there, or an empty range at `byte_idx` if there is none.
+    pub fn word_at(&self, byte_idx: usize) -> Range<usize> {
+        let (line, col) = self.byte_to_line_col(byte_idx);
+        let start = self.line_to_byte(line);
+        let text = self.slice_lines(line, 1).pop().unwrap_or_default();
+        text.split_word_bound_indices()
+            .find(|(idx, word)| *idx <= col && col <= idx + word.len() && is_word(word))
+            .map_or(byte_idx..byte_idx, |(idx, word)| {
+                start + idx..start + idx + word.len()
+            })
+    }
+
     /// Save the buffer to `path`, preserving original EOL style.
     pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
         let mut text = self.rope.to_string();
@@ -161,6 +235,10 @@ impl RopeBuffer {
     }
 }
 
+fn is_word(segment: &str) -> bool {
+    segment.chars().any(|c| c.is_alphanumeric() || c == '_')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,6 +295,39 @@ mod tests {
         assert_eq!(buf.grapheme_left(0), None);
     }
 
+    #[test]
+    fn word_navigation() {
+        let buf = RopeBuffer::from_text("foo bar_baz, qux\nnext");
+        assert_eq!(buf.word_right(0), 3);
+        assert_eq!(buf.word_right(3), 11);
+        assert_eq!(buf.word_right(11), 16);
+        assert_eq!(buf.word_right(16), 17);
+        assert_eq!(buf.word_left(17), 16);
+        assert_eq!(buf.word_left(16), 13);
+        assert_eq!(buf.word_left(13), 4);
+        assert_eq!(buf.word_left(2), 0);
+        assert_eq!(buf.word_left(0), 0);
+    }
+
+    #[test]
+    fn word_at_cursor() {
+        let buf = RopeBuffer::from_text("foo bar_baz, qux\nnext");
+        assert_eq!(buf.word_at(5), 4..11);
+        assert_eq!(buf.word_at(11), 4..11);
+        assert_eq!(buf.word_at(12), 12..12);
+        assert_eq!(buf.word_at(19), 17..21);
+    }
+
+    #[test]
+    fn line_len_and_char_boundary() {
+        let buf = RopeBuffer::from_text("héllo\nx");
+        assert_eq!(buf.line_len(0), 6);
+        assert_eq!(buf.line_len(1), 1);
+        assert_eq!(buf.len_bytes(), 8);
+        assert_eq!(buf.floor_char_boundary(2), 1);
+        assert_eq!(buf.floor_char_boundary(99), 8);
+    }
+
     #[test]
     fn open_and_save_preserves_crlf() {
         let dir = tempdir().unwrap();
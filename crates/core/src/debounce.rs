@@ -34,6 +34,13 @@ impl Debouncer {
             action();
         }));
     }
+
+    /// Drop the pending action, if any.
+    pub fn cancel(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+    }
 }
 
 impl Default for Debouncer {
@@ -65,6 +72,19 @@ mod tests {
         assert_eq!(*count.lock().unwrap(), 1);
     }
 
+    #[tokio::test]
+    async fn cancel_drops_the_pending_action() {
+        let called = Arc::new(Mutex::new(false));
+        let c = called.clone();
+        let mut d = Debouncer::new(Duration::from_millis(20));
+        d.call(move || {
+            *c.lock().unwrap() = true;
+        });
+        d.cancel();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(!*called.lock().unwrap());
+    }
+
     #[tokio::test]
     async fn default_delay_works() {
         let called = Arc::new(Mutex::new(false));
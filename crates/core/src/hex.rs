@@ -1,5 +1,93 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
 use ghostwriter_proto::{Frame, Line};
 
+/// Bytes shown on each row of a hex view.
+pub const BYTES_PER_ROW: usize = 16;
+
+/// Bytes inspected by [`looks_binary`].
+const SNIFF_BYTES: u64 = 8 * 1024;
+
+/// A file shown in hex, read from disk one window of rows at a time so
+/// files of any size can be paged through without loading them.
+#[derive(Debug)]
+pub struct HexFile {
+    file: File,
+    len: u64,
+}
+
+impl HexFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    /// File size in bytes when it was opened.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of hex rows needed for the whole file.
+    pub fn rows(&self) -> usize {
+        self.len.div_ceil(BYTES_PER_ROW as u64) as usize
+    }
+
+    /// Read the bytes of up to `rows` rows starting at `first_row`.
+    pub fn read_rows(&self, first_row: usize, rows: usize) -> io::Result<Vec<u8>> {
+        let start = (first_row as u64 * BYTES_PER_ROW as u64).min(self.len);
+        let want = (rows as u64 * BYTES_PER_ROW as u64).min(self.len - start);
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))?;
+        let mut window = Vec::with_capacity(want as usize);
+        file.take(want).read_to_end(&mut window)?;
+        Ok(window)
+    }
+
+    /// Compose the frame for rows `first_row..first_row + rows`, reading
+    /// only those bytes.
+    pub fn compose(
+        &self,
+        first_row: usize,
+        cols: u16,
+        rows: u16,
+        doc_v: u64,
+        status_left: &str,
+        status_right: &str,
+    ) -> io::Result<Frame> {
+        let window = self.read_rows(first_row, rows as usize)?;
+        Ok(compose_hex_window(
+            &window,
+            first_row,
+            cols,
+            rows,
+            doc_v,
+            status_left,
+            status_right,
+        ))
+    }
+}
+
+/// Whether the start of the file at `path` holds a NUL byte or invalid
+/// UTF-8, in which case it is shown in hex without being loaded as text.
+pub fn looks_binary<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut head = Vec::new();
+    File::open(path)?.take(SNIFF_BYTES).read_to_end(&mut head)?;
+    if head.contains(&0) {
+        return Ok(true);
+    }
+    // A sequence cut off by the end of the sample is not an error.
+    Ok(std::str::from_utf8(&head).is_err_and(|e| e.error_len().is_some()))
+}
+
 /// Compose a hex view frame for the given bytes.
 /// Each row displays 16 bytes in hexadecimal followed by an ASCII gutter.
 pub fn compose_hex(
@@ -10,17 +98,40 @@ pub fn compose_hex(
     doc_v: u64,
     status_left: &str,
     status_right: &str,
+) -> Frame {
+    let start = (first_row * BYTES_PER_ROW).min(bytes.len());
+    compose_hex_window(
+        &bytes[start..],
+        first_row,
+        cols,
+        rows,
+        doc_v,
+        status_left,
+        status_right,
+    )
+}
+
+/// Like [`compose_hex`], but `window` holds the bytes from `first_row`
+/// onwards rather than the whole file.
+pub fn compose_hex_window(
+    window: &[u8],
+    first_row: usize,
+    cols: u16,
+    rows: u16,
+    doc_v: u64,
+    status_left: &str,
+    status_right: &str,
 ) -> Frame {
     let mut lines = Vec::new();
-    let total_rows = bytes.len().div_ceil(16);
-    for row in first_row..std::cmp::min(first_row + rows as usize, total_rows) {
+    let total_rows = window.len().div_ceil(16);
+    for row in 0..std::cmp::min(rows as usize, total_rows) {
         let start = row * 16;
-        let end = std::cmp::min(start + 16, bytes.len());
+        let end = std::cmp::min(start + 16, window.len());
 
         let mut hex_part = String::new();
         for i in 0..16 {
             if start + i < end {
-                hex_part.push_str(&format!("{:02X}", bytes[start + i]));
+                hex_part.push_str(&format!("{:02X}", window[start + i]));
             } else {
                 hex_part.push_str("  ");
             }
@@ -36,7 +147,7 @@ pub fn compose_hex(
         }
 
         let mut ascii_part = String::new();
-        for &b in &bytes[start..end] {
+        for &b in &window[start..end] {
             if (0x20..=0x7E).contains(&b) {
                 ascii_part.push(b as char);
             } else {
@@ -48,6 +159,8 @@ pub fn compose_hex(
         lines.push(Line {
             text: line_text,
             spans: Vec::new(),
+            more: false,
+            marked: false,
         });
     }
 
@@ -56,12 +169,16 @@ pub fn compose_hex(
         kind: "hex".into(),
         doc_v,
         first_line: first_row as u64,
+        first_col: 0,
         cols,
         rows,
         lines,
         cursors: Vec::new(),
         status_left: status_left.into(),
         status_right: status_right.into(),
+        status: None,
+        classes: Vec::new(),
+        checksum: None,
     }
 }
 
@@ -78,4 +195,38 @@ mod tests {
             "68 65 6C 6C 6F 00 77 6F  72 6C 64 FF             |hello.world."
         );
     }
+
+    #[test]
+    fn pages_through_a_file_without_loading_it() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(100).collect();
+        std::io::Write::write_all(&mut file, &bytes).unwrap();
+        let hex = HexFile::open(file.path()).unwrap();
+        assert_eq!((hex.len(), hex.rows()), (100, 7));
+        assert_eq!(hex.read_rows(6, 4).unwrap(), &bytes[96..]);
+        assert!(hex.read_rows(9, 1).unwrap().is_empty());
+
+        let frame = hex.compose(2, 80, 2, 0, "", "").unwrap();
+        assert_eq!(frame, compose_hex(&bytes, 2, 80, 2, 0, "", ""));
+        assert_eq!(frame.first_line, 2);
+        assert!(frame.lines[0].text.starts_with("20 21 22"));
+        assert_eq!(frame.lines.len(), 2);
+    }
+
+    #[test]
+    fn sniffs_binary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let check = |name: &str, bytes: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            looks_binary(&path).unwrap()
+        };
+        assert!(!check("text", "héllo\n".as_bytes()));
+        assert!(check("nul", b"a\0b"));
+        assert!(check("latin1", b"caf\xe9 au lait"));
+        // "é" split by the end of the sample still counts as text.
+        let mut cut = vec![b'a'; SNIFF_BYTES as usize - 1];
+        cut.extend_from_slice("é".as_bytes());
+        assert!(!check("cut", &cut));
+    }
 }
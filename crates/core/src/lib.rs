@@ -7,19 +7,28 @@ pub mod buffer;
 pub mod debounce;
 pub mod fs;
 pub mod hex;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod motion;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod tls;
 pub mod transport;
 pub mod undo;
 pub mod viewport;
 pub mod wal;
+pub mod watcher;
 
-pub use buffer::RopeBuffer;
+pub use buffer::{Eol, RopeBuffer};
 pub use debounce::Debouncer;
 pub use fs::atomic_write;
-pub use hex::compose_hex;
-pub use transport::Transport;
-pub use undo::UndoStack;
-pub use viewport::{ViewportParams, compose as compose_viewport};
+pub use hex::{HexFile, compose_hex, compose_hex_window, looks_binary};
+pub use motion::move_cursor;
+pub use transport::{Backoff, DisconnectReason, LinkState, Priority, Transport, TransportConfig};
+pub use undo::{Edit, UndoStack};
+pub use viewport::{PeerCursor, ViewportParams, compose as compose_viewport};
 pub use wal::{EditOp, EditRecord, Wal};
+pub use watcher::{FileChange, FileWatcher};
 
 #[cfg(test)]
 mod tests {
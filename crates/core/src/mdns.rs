@@ -0,0 +1,95 @@
+//! Advertising servers on the local network over mDNS (DNS-SD) and finding
+//! them again, so clients need not know the address.
+
+use std::{io, net::IpAddr, time::Duration};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+/// DNS-SD service type servers are advertised under.
+pub const SERVICE_TYPE: &str = "_ghostwriter._tcp.local.";
+
+/// TXT record key holding the URL scheme to connect with.
+const SCHEME_KEY: &str = "scheme";
+
+/// A server announced on the network until dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+}
+
+impl Advertisement {
+    /// Announce instance `name` on `host`, reachable at `addrs` on `port`
+    /// with `scheme` (`ws`, `wss` or `quic`).
+    pub fn start(
+        name: &str,
+        host: &str,
+        addrs: &[IpAddr],
+        port: u16,
+        scheme: &str,
+    ) -> io::Result<Self> {
+        let daemon = ServiceDaemon::new().map_err(io::Error::other)?;
+        let host = format!("{host}.local.");
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &host,
+            addrs,
+            port,
+            &[(SCHEME_KEY, scheme)][..],
+        )
+        .map_err(io::Error::other)?;
+        daemon.register(info).map_err(io::Error::other)?;
+        Ok(Self { daemon })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A server found on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    /// Instance name it was advertised under.
+    pub name: String,
+    /// URL to pass to `--connect`.
+    pub url: String,
+}
+
+/// Servers that answer within `wait`, in the order they were resolved.
+pub async fn discover(wait: Duration) -> io::Result<Vec<Found>> {
+    let daemon = ServiceDaemon::new().map_err(io::Error::other)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(io::Error::other)?;
+    let mut found = Vec::new();
+    let collect = async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            let Some(url) = url_of(&info) else {
+                continue;
+            };
+            let name = info.get_fullname().trim_end_matches(SERVICE_TYPE);
+            found.push(Found {
+                name: name.trim_end_matches('.').to_string(),
+                url,
+            });
+        }
+    };
+    let _ = tokio::time::timeout(wait, collect).await;
+    let _ = daemon.shutdown();
+    Ok(found)
+}
+
+/// URL of an advertised server, preferring an IPv4 address.
+fn url_of(info: &ServiceInfo) -> Option<String> {
+    let scheme = info.get_property_val_str(SCHEME_KEY).unwrap_or("ws");
+    let addrs = info.get_addresses();
+    let addr = (addrs.iter().find(|a| a.is_ipv4())).or_else(|| addrs.iter().next())?;
+    let port = info.get_port();
+    Some(match addr {
+        IpAddr::V4(ip) => format!("{scheme}://{ip}:{port}"),
+        IpAddr::V6(ip) => format!("{scheme}://[{ip}]:{port}"),
+    })
+}
@@ -0,0 +1,93 @@
+use ghostwriter_proto::{Direction, Granularity};
+
+use crate::buffer::RopeBuffer;
+
+/// Compute the new cursor byte offset after moving from `pos`.
+///
+/// `page` is the number of lines moved by a `Page` step, usually the
+/// viewport height. Vertical moves keep the byte column where possible.
+pub fn move_cursor(
+    buf: &RopeBuffer,
+    pos: usize,
+    dir: Direction,
+    granularity: Granularity,
+    page: usize,
+) -> usize {
+    let pos = pos.min(buf.len_bytes());
+    match (granularity, dir) {
+        (Granularity::Document, Direction::Left | Direction::Up) => 0,
+        (Granularity::Document, Direction::Right | Direction::Down) => buf.len_bytes(),
+        (Granularity::Grapheme, Direction::Left) => buf.grapheme_left(pos).unwrap_or(pos),
+        (Granularity::Grapheme, Direction::Right) => buf.grapheme_right(pos).unwrap_or(pos),
+        (Granularity::Word, Direction::Left) => buf.word_left(pos),
+        (Granularity::Word, Direction::Right) => buf.word_right(pos),
+        (Granularity::Line, Direction::Left) => {
+            let (line, _) = buf.byte_to_line_col(pos);
+            buf.line_to_byte(line)
+        }
+        (Granularity::Line, Direction::Right) => {
+            let (line, _) = buf.byte_to_line_col(pos);
+            buf.line_to_byte(line) + buf.line_len(line)
+        }
+        (Granularity::Page, Direction::Up) => vertical(buf, pos, -(page.max(1) as i64)),
+        (Granularity::Page, Direction::Down) => vertical(buf, pos, page.max(1) as i64),
+        (_, Direction::Up) => vertical(buf, pos, -1),
+        (_, Direction::Down) => vertical(buf, pos, 1),
+        (Granularity::Page, Direction::Left) => buf.grapheme_left(pos).unwrap_or(pos),
+        (Granularity::Page, Direction::Right) => buf.grapheme_right(pos).unwrap_or(pos),
+    }
+}
+
+fn vertical(buf: &RopeBuffer, pos: usize, delta: i64) -> usize {
+    let (line, col) = buf.byte_to_line_col(pos);
+    let last = buf.len_lines().saturating_sub(1) as i64;
+    let target = (line as i64 + delta).clamp(0, last) as usize;
+    let col = col.min(buf.line_len(target));
+    buf.floor_char_boundary(buf.line_to_byte(target) + col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_by_each_granularity() {
+        let buf = RopeBuffer::from_text("one two\nthree\nfour five\n");
+        let mv = |pos, dir, g| move_cursor(&buf, pos, dir, g, 2);
+        assert_eq!(mv(0, Direction::Right, Granularity::Grapheme), 1);
+        assert_eq!(mv(0, Direction::Left, Granularity::Grapheme), 0);
+        assert_eq!(mv(0, Direction::Right, Granularity::Word), 3);
+        assert_eq!(mv(7, Direction::Left, Granularity::Word), 4);
+        assert_eq!(mv(5, Direction::Left, Granularity::Line), 0);
+        assert_eq!(mv(5, Direction::Right, Granularity::Line), 7);
+        assert_eq!(mv(6, Direction::Down, Granularity::Line), 13);
+        assert_eq!(mv(13, Direction::Up, Granularity::Line), 5);
+        assert_eq!(mv(1, Direction::Down, Granularity::Page), 15);
+        assert_eq!(mv(15, Direction::Up, Granularity::Page), 1);
+        assert_eq!(mv(9, Direction::Down, Granularity::Document), 24);
+        assert_eq!(mv(9, Direction::Up, Granularity::Document), 0);
+    }
+
+    #[test]
+    fn vertical_moves_clamp_to_buffer() {
+        let buf = RopeBuffer::from_text("ab\ncd");
+        assert_eq!(move_cursor(&buf, 1, Direction::Up, Granularity::Line, 1), 1);
+        assert_eq!(
+            move_cursor(&buf, 4, Direction::Down, Granularity::Page, 10),
+            4
+        );
+        assert_eq!(
+            move_cursor(&buf, 99, Direction::Left, Granularity::Grapheme, 1),
+            4
+        );
+    }
+
+    #[test]
+    fn vertical_moves_respect_char_boundaries() {
+        let buf = RopeBuffer::from_text("abc\né");
+        assert_eq!(
+            move_cursor(&buf, 1, Direction::Down, Granularity::Line, 1),
+            4
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! QUIC carriage for [`Transport`](crate::Transport).
+//!
+//! A connection opens a single bidirectional stream and runs the usual
+//! WebSocket framing over it, so `Transport<QuicStream>` offers the same
+//! send/recv/heartbeat API as the TCP transport while QUIC handles loss
+//! recovery and connection migration underneath.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use quinn::{
+    ClientConfig, Connection, RecvStream, SendStream, ServerConfig,
+    rustls::{
+        RootCertStore,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    },
+};
+use tokio::io::{AsyncRead, AsyncWrite, Join, ReadBuf};
+use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Role};
+
+pub use quinn::{Endpoint, Incoming, rustls::pki_types::CertificateDer as Certificate};
+
+/// URL scheme selecting the QUIC transport, as in `quic://host:port`.
+pub const SCHEME: &str = "quic";
+
+/// How long a dropped stream may keep its connection open so queued data
+/// reaches the peer.
+const LINGER: Duration = Duration::from_secs(5);
+
+/// Duplex byte stream over one QUIC bidirectional stream.
+///
+/// Dropping it finishes the stream and closes the connection once the peer
+/// has received everything, instead of discarding unsent data.
+pub struct QuicStream {
+    io: Option<Join<RecvStream, SendStream>>,
+    conn: Connection,
+}
+
+impl QuicStream {
+    fn new(conn: Connection, send: SendStream, recv: RecvStream) -> Self {
+        Self {
+            io: Some(tokio::io::join(recv, send)),
+            conn,
+        }
+    }
+
+    fn io(&mut self) -> Pin<&mut Join<RecvStream, SendStream>> {
+        Pin::new(self.io.as_mut().expect("stream used after drop"))
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().io().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().io().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().io().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().io().poll_shutdown(cx)
+    }
+}
+
+impl Drop for QuicStream {
+    fn drop(&mut self) {
+        let Some(io) = self.io.take() else {
+            return;
+        };
+        let (_recv, mut send) = io.into_inner();
+        // Fails harmlessly if the writer already shut the stream down.
+        let _ = send.finish();
+        let conn = self.conn.clone();
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            rt.spawn(async move {
+                let _ = tokio::time::timeout(LINGER, send.stopped()).await;
+                conn.close(0u32.into(), b"");
+            });
+        }
+    }
+}
+
+/// Generate a self-signed certificate for `names`, e.g. `["localhost"]`.
+pub fn self_signed_cert(
+    names: Vec<String>,
+) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    Ok((certified.cert.der().clone(), key.into()))
+}
+
+/// Bind a server endpoint on `addr` presenting `cert`.
+pub fn server_endpoint(
+    addr: SocketAddr,
+    cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Endpoint> {
+    bind_server(addr, vec![cert], key)
+}
+
+/// Like [`server_endpoint`] with the certificate chain and key read from
+/// PEM files, as for `wss://`.
+pub fn server_endpoint_pem(addr: SocketAddr, cert: &Path, key: &Path) -> io::Result<Endpoint> {
+    bind_server(
+        addr,
+        crate::tls::load_certs(cert)?,
+        crate::tls::load_key(key)?,
+    )
+}
+
+fn bind_server(
+    addr: SocketAddr,
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Endpoint> {
+    let config = ServerConfig::with_single_cert(certs, key).map_err(io::Error::other)?;
+    Endpoint::server(config, addr)
+}
+
+/// Bind a client endpoint on an ephemeral port that trusts only `roots`.
+pub fn client_endpoint(roots: &[CertificateDer<'static>]) -> io::Result<Endpoint> {
+    let mut store = RootCertStore::empty();
+    for cert in roots {
+        store.add(cert.clone()).map_err(io::Error::other)?;
+    }
+    let config = ClientConfig::with_root_certificates(Arc::new(store)).map_err(io::Error::other)?;
+    let bind: SocketAddr = ([0, 0, 0, 0], 0).into();
+    let mut endpoint = Endpoint::client(bind)?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint)
+}
+
+/// Connect to `addr` and open the stream carrying the session.
+///
+/// The peer only sees the stream once data is written on it, which the
+/// client's Hello does.
+pub async fn connect(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+) -> io::Result<WebSocketStream<QuicStream>> {
+    let conn = endpoint
+        .connect(addr, server_name)
+        .map_err(io::Error::other)?
+        .await?;
+    let (send, recv) = conn.open_bi().await?;
+    let stream = QuicStream::new(conn, send, recv);
+    Ok(WebSocketStream::from_raw_socket(stream, Role::Client, None).await)
+}
+
+/// Accept the next connection and its session stream. Returns `None` once
+/// the endpoint is closed.
+pub async fn accept(
+    endpoint: &Endpoint,
+) -> Option<io::Result<(SocketAddr, WebSocketStream<QuicStream>)>> {
+    let incoming = endpoint.accept().await?;
+    Some(handshake(incoming).await)
+}
+
+/// Complete the handshake of a connection from [`Endpoint::accept`] and
+/// wait for its session stream, which a slow or silent client may hold up
+/// indefinitely.
+pub async fn handshake(
+    incoming: Incoming,
+) -> io::Result<(SocketAddr, WebSocketStream<QuicStream>)> {
+    let conn = incoming.await?;
+    let (send, recv) = conn.accept_bi().await?;
+    let addr = conn.remote_address();
+    let stream = QuicStream::new(conn, send, recv);
+    Ok((
+        addr,
+        WebSocketStream::from_raw_socket(stream, Role::Server, None).await,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transport;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn transport_roundtrip_over_quic() {
+        let (cert, key) = self_signed_cert(vec!["localhost".into()]).unwrap();
+        let server = server_endpoint(([127, 0, 0, 1], 0).into(), cert.clone(), key).unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = client_endpoint(&[cert]).unwrap();
+
+        let accepted = tokio::spawn(async move {
+            let (_, ws) = accept(&server).await.unwrap().unwrap();
+            let mut t = Transport::new(ws, Duration::from_millis(50));
+            let msg = t.recv().await.unwrap();
+            t.send(&msg).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            (t.last_pong().await, server)
+        });
+
+        let ws = connect(&client, addr, "localhost").await.unwrap();
+        let mut t = Transport::new(ws, Duration::from_millis(50));
+        let start = t.last_pong().await;
+        t.send(b"hello").await.unwrap();
+        assert_eq!(t.recv().await.unwrap(), b"hello");
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(t.last_pong().await > start);
+        let (server_pong, _server) = accepted.await.unwrap();
+        assert!(server_pong > start);
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_server() {
+        let (cert, key) = self_signed_cert(vec!["localhost".into()]).unwrap();
+        let server = server_endpoint(([127, 0, 0, 1], 0).into(), cert, key).unwrap();
+        let addr = server.local_addr().unwrap();
+        let (other, _) = self_signed_cert(vec!["localhost".into()]).unwrap();
+        let client = client_endpoint(&[other]).unwrap();
+        tokio::spawn(async move { accept(&server).await });
+        assert!(connect(&client, addr, "localhost").await.is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! TLS for WebSocket connections (`wss://`), with certificates and keys
+//! read from PEM files. Servers may also require clients to authenticate
+//! with a certificate (mutual TLS).
+
+use std::{io, path::Path, sync::Arc};
+
+use tokio_rustls::rustls::{
+    RootCertStore, ServerConfig,
+    crypto::{CryptoProvider, ring},
+    pki_types::{
+        PrivateKeyDer,
+        pem::{self, PemObject},
+    },
+    server::WebPkiClientVerifier,
+};
+
+pub use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{ClientConfig, pki_types::CertificateDer as Certificate},
+    server::TlsStream,
+};
+
+/// Read every certificate in the PEM file at `path`.
+pub fn load_certs(path: &Path) -> io::Result<Vec<Certificate<'static>>> {
+    let certs = Certificate::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in the PEM file at `path`.
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(pem_error)
+}
+
+/// Acceptor presenting the certificate chain in `cert`, signed with the
+/// private key in `key`. With `client_ca`, clients must present a
+/// certificate issued by one of the CAs in that PEM file.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = match client_ca {
+        None => builder.with_no_client_auth(),
+        Some(ca) => {
+            let roots = root_store(&load_certs(ca)?)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Certificate chain and key a client authenticates with under mutual TLS.
+pub struct ClientIdentity {
+    certs: Vec<Certificate<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl ClientIdentity {
+    /// Read the identity from PEM files.
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Self> {
+        Ok(Self {
+            certs: load_certs(cert)?,
+            key: load_key(key)?,
+        })
+    }
+}
+
+/// Client configuration that trusts `roots`, e.g. a private CA or a
+/// server's self-signed certificate, or the system's roots when `None`.
+/// `identity` is presented to servers that ask for a client certificate.
+pub fn client_config(
+    roots: Option<&[Certificate<'static>]>,
+    identity: Option<ClientIdentity>,
+) -> io::Result<Arc<ClientConfig>> {
+    let store = match roots {
+        Some(roots) => root_store(roots)?,
+        None => {
+            let mut store = RootCertStore::empty();
+            // Unreadable system certificates are skipped, as browsers do.
+            store.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            store
+        }
+    };
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(store);
+    let config = match identity {
+        Some(ClientIdentity { certs, key }) => builder
+            .with_client_auth_cert(certs, key)
+            .map_err(io::Error::other)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Who is on the other end of a mutual TLS connection: the common name of
+/// the client certificate's subject, or the whole subject if it has none.
+/// `None` when the client presented no certificate.
+pub fn peer_identity<S>(stream: &TlsStream<S>) -> Option<String> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let subject = cert.subject();
+    let common_name = subject
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+        .map(str::to_string);
+    Some(common_name.unwrap_or_else(|| subject.to_string()))
+}
+
+fn root_store(roots: &[Certificate<'static>]) -> io::Result<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for cert in roots {
+        store.add(cert.clone()).map_err(io::Error::other)?;
+    }
+    Ok(store)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn pem_error(err: pem::Error) -> io::Error {
+    match err {
+        pem::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_pem_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        let certs = load_certs(&cert).unwrap();
+        assert_eq!(certs, [certified.cert.der().clone()]);
+        assert!(acceptor(&cert, &key, None).is_ok());
+        assert!(acceptor(&cert, &key, Some(&cert)).is_ok());
+        assert!(client_config(Some(&certs), None).is_ok());
+        let identity = ClientIdentity::load(&cert, &key).unwrap();
+        assert!(client_config(None, Some(identity)).is_ok());
+
+        assert_eq!(
+            load_certs(&key).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(load_key(&cert).is_err());
+        let missing = dir.path().join("missing.pem");
+        assert_eq!(
+            load_certs(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,6 +1,14 @@
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
 use futures_util::{SinkExt, StreamExt, stream::SplitSink, stream::SplitStream};
-use std::{sync::Arc, time::Instant};
-use tokio::sync::{Mutex, mpsc};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use std::time::Instant;
+use tokio::sync::{Mutex, Notify, mpsc, watch};
 use tokio::task::JoinHandle;
 use tokio::time::Duration;
 use tokio_tungstenite::{
@@ -8,13 +16,217 @@ use tokio_tungstenite::{
     tungstenite::{Error as WsError, Message},
 };
 
+/// Protocol feature name for deflate-compressed payloads.
+pub const COMPRESSION_FEATURE: &str = "deflate";
+
+/// Default payload size above which compressed transports deflate messages.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Largest payload [`decompress_payload`] inflates, the WebSocket message
+/// limit, so a small message cannot expand without bound.
+pub const MAX_PAYLOAD: usize = 64 << 20;
+
+const FLAG_PLAIN: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+
+/// Prefix `data` with a compression flag, deflating it when it is at least
+/// `threshold` bytes long.
+pub fn compress_payload(data: &[u8], threshold: usize) -> io::Result<Vec<u8>> {
+    if data.len() < threshold {
+        let mut out = Vec::with_capacity(data.len() + 1);
+        out.push(FLAG_PLAIN);
+        out.extend_from_slice(data);
+        return Ok(out);
+    }
+    let mut enc = DeflateEncoder::new(vec![FLAG_DEFLATE], Compression::fast());
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+/// Reverse [`compress_payload`], refusing payloads that inflate beyond
+/// [`MAX_PAYLOAD`].
+pub fn decompress_payload(data: &[u8]) -> io::Result<Vec<u8>> {
+    match data.split_first() {
+        Some((&FLAG_PLAIN, rest)) => Ok(rest.to_vec()),
+        Some((&FLAG_DEFLATE, rest)) => {
+            let mut out = Vec::new();
+            let limit = MAX_PAYLOAD as u64 + 1;
+            DeflateDecoder::new(rest)
+                .take(limit)
+                .read_to_end(&mut out)?;
+            if out.len() > MAX_PAYLOAD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decompressed payload too large",
+                ));
+            }
+            Ok(out)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown compression flag",
+        )),
+    }
+}
+
+/// Default number of messages that may wait in the outbound queue.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+/// Tunables for a [`Transport`].
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// Interval between heartbeat pings.
+    pub ping_interval: Duration,
+    /// Maximum number of queued outbound messages per [`Priority`] before
+    /// [`Transport::send`] waits for the writer to catch up. One replaceable
+    /// frame may be queued on top of this.
+    pub queue_capacity: usize,
+    /// Close the connection when no Pong has arrived for this long, or
+    /// never with `None`.
+    pub pong_timeout: Option<Duration>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            pong_timeout: Some(Duration::from_secs(90)),
+        }
+    }
+}
+
+/// Reconnection schedule for clients redialing a dropped [`Transport`]:
+/// the wait doubles from `min` up to `max` while attempts keep failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            delay: min,
+        }
+    }
+
+    /// Wait before the next attempt.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Wait longer after a failed attempt.
+    pub fn failed(&mut self) {
+        self.delay = (self.delay * 2).min(self.max);
+    }
+
+    /// Start over from the shortest wait once connected.
+    pub fn reset(&mut self) {
+        self.delay = self.min;
+    }
+}
+
+/// Why a transport stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer closed the connection.
+    Closed,
+    /// Reading from the socket failed.
+    Error,
+    /// The peer stopped answering pings.
+    PongTimeout,
+}
+
+/// Connection state reported by [`Transport::link_state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connected,
+    Disconnected(DisconnectReason),
+}
+
+/// How long the writer waits for a Close frame to go out when shutting down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outbound lane of a message.
+///
+/// Queued [`High`](Priority::High) messages are written before any queued
+/// [`Normal`](Priority::Normal) ones, so small input and control messages
+/// are not stuck behind bulky frames on a congested link. Messages within
+/// a lane keep their order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+/// Message waiting to be written.
+struct Outgoing {
+    data: Vec<u8>,
+    priority: Priority,
+    /// Full-state frames may be replaced by a newer one while queued.
+    replaceable: bool,
+}
+
+/// Bounded outbound queue shared between the transport and its writer task.
+struct Outbox {
+    queue: std::sync::Mutex<VecDeque<Outgoing>>,
+    capacity: usize,
+    /// Signalled when a message is queued or the transport is closed.
+    queued: Notify,
+    /// Signalled when the writer has taken a message off the queue.
+    space: Notify,
+    closed: AtomicBool,
+    dropped_frames: AtomicU64,
+}
+
+impl Outbox {
+    /// Stop accepting messages. With `discard`, queued messages are dropped
+    /// instead of flushed, e.g. when the peer is unreachable.
+    fn shutdown(&self, discard: bool) {
+        self.closed.store(true, Ordering::SeqCst);
+        if discard {
+            self.queue.lock().unwrap().clear();
+        }
+        self.queued.notify_one();
+        self.space.notify_waiters();
+    }
+
+    /// Take the oldest high-priority message, or else the oldest one.
+    fn pop(&self) -> Option<Outgoing> {
+        let item = {
+            let mut queue = self.queue.lock().unwrap();
+            match queue.iter().position(|m| m.priority == Priority::High) {
+                Some(i) => queue.remove(i),
+                None => queue.pop_front(),
+            }
+        };
+        if item.is_some() {
+            self.space.notify_one();
+        }
+        item
+    }
+}
+
 /// WebSocket transport wrapper providing binary send/recv and heartbeat.
+///
+/// Outgoing messages go through a bounded queue drained by a writer task,
+/// so a slow peer applies backpressure to [`send`](Self::send) instead of
+/// growing memory without limit.
 pub struct Transport<S> {
-    writer: Arc<Mutex<SplitSink<WebSocketStream<S>, Message>>>,
+    outbox: Arc<Outbox>,
     rx: mpsc::UnboundedReceiver<Vec<u8>>,
     last_pong: Arc<Mutex<Instant>>,
+    state: watch::Receiver<LinkState>,
+    compression: Option<usize>,
     _reader: JoinHandle<()>,
-    _pinger: JoinHandle<()>,
+    pinger: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    _stream: PhantomData<fn() -> S>,
 }
 
 impl<S> Transport<S>
@@ -23,50 +235,91 @@ where
 {
     /// Create a new transport and start heartbeat with the given interval.
     pub fn new(ws: WebSocketStream<S>, ping_interval: Duration) -> Self {
+        Self::with_config(
+            ws,
+            TransportConfig {
+                ping_interval,
+                ..TransportConfig::default()
+            },
+        )
+    }
+
+    /// Create a new transport with explicit tunables.
+    pub fn with_config(ws: WebSocketStream<S>, config: TransportConfig) -> Self {
         let (sink, mut stream): (
             SplitSink<WebSocketStream<S>, Message>,
             SplitStream<WebSocketStream<S>>,
         ) = ws.split();
-        let writer = Arc::new(Mutex::new(sink));
         let (tx, rx) = mpsc::unbounded_channel();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
         let last_pong = Arc::new(Mutex::new(Instant::now()));
+        let outbox = Arc::new(Outbox {
+            queue: std::sync::Mutex::new(VecDeque::new()),
+            capacity: config.queue_capacity.max(1),
+            queued: Notify::new(),
+            space: Notify::new(),
+            closed: AtomicBool::new(false),
+            dropped_frames: AtomicU64::new(0),
+        });
+        let (state_tx, state) = watch::channel(LinkState::Connected);
+        let state_tx = Arc::new(state_tx);
 
         // Reader task handles incoming messages, responding to pings and
-        // forwarding binary frames to the channel.
-        let reader_writer = Arc::clone(&writer);
+        // forwarding binary frames to the channel. It stops when the link is
+        // declared dead by the pinger.
+        let reader_control = control_tx.clone();
         let reader_last_pong = Arc::clone(&last_pong);
+        let reader_state = Arc::clone(&state_tx);
+        let mut reader_watch = state.clone();
         let reader_handle = tokio::spawn(async move {
-            while let Some(msg) = stream.next().await {
+            let reason = loop {
+                let msg = tokio::select! {
+                    msg = stream.next() => msg,
+                    _ = reader_watch.wait_for(|s| *s != LinkState::Connected) => return,
+                };
                 match msg {
-                    Ok(Message::Binary(data)) => {
+                    Some(Ok(Message::Binary(data))) => {
+                        // The owner is gone; nothing left to report to.
                         if tx.send(data.to_vec()).is_err() {
-                            break;
+                            return;
                         }
                     }
-                    Ok(Message::Ping(data)) => {
-                        let _ = reader_writer.lock().await.send(Message::Pong(data)).await;
+                    Some(Ok(Message::Ping(data))) => {
+                        let _ = reader_control.send(Message::Pong(data));
                     }
-                    Ok(Message::Pong(_)) => {
+                    Some(Ok(Message::Pong(_))) => {
                         *reader_last_pong.lock().await = Instant::now();
                     }
-                    Ok(Message::Close(_)) => break,
-                    Ok(_) => {}
-                    Err(_) => break,
+                    Some(Ok(Message::Close(_))) | None => break DisconnectReason::Closed,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) => break DisconnectReason::Error,
                 }
-            }
+            };
+            disconnect(&reader_state, reason);
         });
 
-        // Pinger task periodically sends Ping frames.
-        let pinger_writer = Arc::clone(&writer);
+        // Pinger task periodically sends Ping frames and declares the link
+        // dead once Pongs stop arriving.
+        let pinger_control = control_tx;
+        let pinger_last_pong = Arc::clone(&last_pong);
+        let pinger_outbox = Arc::clone(&outbox);
+        let pinger_state = Arc::clone(&state_tx);
+        let ping_interval = config.ping_interval;
+        let pong_timeout = config.pong_timeout;
         let pinger_handle = tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(ping_interval);
+            let start = tokio::time::Instant::now() + ping_interval;
+            let mut ticker = tokio::time::interval_at(start, ping_interval);
             loop {
                 ticker.tick().await;
-                if pinger_writer
-                    .lock()
-                    .await
+                if let Some(timeout) = pong_timeout
+                    && pinger_last_pong.lock().await.elapsed() > timeout
+                {
+                    pinger_outbox.shutdown(true);
+                    disconnect(&pinger_state, DisconnectReason::PongTimeout);
+                    break;
+                }
+                if pinger_control
                     .send(Message::Ping(Vec::new().into()))
-                    .await
                     .is_err()
                 {
                     break;
@@ -74,33 +327,218 @@ where
             }
         });
 
+        let writer_handle = tokio::spawn(write_loop(sink, Arc::clone(&outbox), control_rx));
+
         Self {
-            writer,
+            outbox,
             rx,
             last_pong,
+            state,
+            compression: None,
             _reader: reader_handle,
-            _pinger: pinger_handle,
+            pinger: pinger_handle,
+            writer: writer_handle,
+            _stream: PhantomData,
         }
     }
 
-    /// Send binary data over the WebSocket.
+    /// Enable payload compression for messages of at least `threshold` bytes,
+    /// or disable it with `None`.
+    ///
+    /// Both peers must switch at the same point in the stream, i.e. once the
+    /// `deflate` feature has been agreed in the Hello exchange.
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    fn encode_payload(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self.compression {
+            Some(threshold) => compress_payload(data, threshold)?,
+            None => data.to_vec(),
+        })
+    }
+
+    /// Queue binary data for sending, waiting while the outbound queue is
+    /// full.
     pub async fn send(&self, data: &[u8]) -> Result<(), WsError> {
-        self.writer
-            .lock()
-            .await
-            .send(Message::Binary(data.to_vec().into()))
-            .await
+        self.send_priority(data, Priority::Normal).await
+    }
+
+    /// Queue binary data in the given lane, waiting while that lane is full.
+    pub async fn send_priority(&self, data: &[u8], priority: Priority) -> Result<(), WsError> {
+        let mut item = Some(Outgoing {
+            data: self.encode_payload(data)?,
+            priority,
+            replaceable: false,
+        });
+        loop {
+            let space = self.outbox.space.notified();
+            {
+                if self.outbox.closed.load(Ordering::SeqCst) {
+                    return Err(WsError::AlreadyClosed);
+                }
+                let mut queue = self.outbox.queue.lock().unwrap();
+                let queued = queue
+                    .iter()
+                    .filter(|m| m.priority == priority && !m.replaceable)
+                    .count();
+                if queued < self.outbox.capacity {
+                    queue.extend(item.take());
+                    drop(queue);
+                    self.outbox.queued.notify_one();
+                    return Ok(());
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Queue a full-state frame in the normal lane. Never waits for queue
+    /// space.
+    ///
+    /// At most one frame is queued at a time: if an older one has not been
+    /// written yet it is replaced in place and counted in
+    /// [`dropped_frames`](Self::dropped_frames). Only send self-contained
+    /// payloads this way; diffs against earlier frames must use
+    /// [`send`](Self::send).
+    pub async fn send_frame(&self, data: &[u8]) -> Result<(), WsError> {
+        if self.outbox.closed.load(Ordering::SeqCst) {
+            return Err(WsError::AlreadyClosed);
+        }
+        let data = self.encode_payload(data)?;
+        let mut queue = self.outbox.queue.lock().unwrap();
+        match queue.iter_mut().find(|m| m.replaceable) {
+            Some(stale) => {
+                stale.data = data;
+                self.outbox.dropped_frames.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                queue.push_back(Outgoing {
+                    data,
+                    priority: Priority::Normal,
+                    replaceable: true,
+                });
+                drop(queue);
+                self.outbox.queued.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of messages waiting in the outbound queue.
+    pub fn queue_len(&self) -> usize {
+        self.outbox.queue.lock().unwrap().len()
+    }
+
+    /// Number of queued frames replaced by newer ones before being sent.
+    pub fn dropped_frames(&self) -> u64 {
+        self.outbox.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Send what is queued, close the socket and wait until that is done.
+    /// Dropping the transport does the same in the background.
+    pub async fn close(mut self) {
+        self.outbox.shutdown(false);
+        self.pinger.abort();
+        let _ = (&mut self.writer).await;
     }
 
     /// Receive the next binary message, if any.
+    ///
+    /// With compression enabled, messages that fail to decompress are dropped.
     pub async fn recv(&mut self) -> Option<Vec<u8>> {
-        self.rx.recv().await
+        loop {
+            let data = self.rx.recv().await?;
+            if self.compression.is_none() {
+                return Some(data);
+            }
+            if let Ok(data) = decompress_payload(&data) {
+                return Some(data);
+            }
+        }
     }
 
     /// Get the instant of the most recent Pong frame.
     pub async fn last_pong(&self) -> Instant {
         *self.last_pong.lock().await
     }
+
+    /// Watch the connection state, e.g. to react when the link drops.
+    pub fn link_state(&self) -> watch::Receiver<LinkState> {
+        self.state.clone()
+    }
+
+    /// Wait until the connection is lost and return why.
+    pub async fn disconnected(&self) -> DisconnectReason {
+        let mut state = self.state.clone();
+        match state.wait_for(|s| *s != LinkState::Connected).await {
+            Ok(s) => match *s {
+                LinkState::Disconnected(reason) => reason,
+                LinkState::Connected => unreachable!(),
+            },
+            // All reporters are gone, so the reader has stopped.
+            Err(_) => DisconnectReason::Closed,
+        }
+    }
+}
+
+/// Record the first disconnect reason; later ones are ignored.
+fn disconnect(state: &watch::Sender<LinkState>, reason: DisconnectReason) {
+    state.send_if_modified(|s| {
+        if *s == LinkState::Connected {
+            *s = LinkState::Disconnected(reason);
+            true
+        } else {
+            false
+        }
+    });
+}
+
+impl<S> Drop for Transport<S> {
+    fn drop(&mut self) {
+        // Let the writer flush what is queued, then close the socket.
+        self.outbox.shutdown(false);
+        self.pinger.abort();
+    }
+}
+
+/// Writer task: sends control frames as soon as they arrive and drains the
+/// outbound queue, high-priority lane first. Exits once the transport is
+/// dropped and the queue is empty, or when the socket fails.
+async fn write_loop<S>(
+    mut sink: SplitSink<WebSocketStream<S>, Message>,
+    outbox: Arc<Outbox>,
+    mut control: mpsc::UnboundedReceiver<Message>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        while let Ok(msg) = control.try_recv() {
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+        if let Some(item) = outbox.pop() {
+            if sink.send(Message::Binary(item.data.into())).await.is_err() {
+                outbox.shutdown(true);
+                return;
+            }
+            continue;
+        }
+        if outbox.closed.load(Ordering::SeqCst) {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.close()).await;
+            return;
+        }
+        tokio::select! {
+            biased;
+            Some(msg) = control.recv() => {
+                if sink.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            _ = outbox.queued.notified() => {}
+        }
+    }
 }
 
 #[cfg(test)]
@@ -109,6 +547,18 @@ mod tests {
     use tokio::io::duplex;
     use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Role};
 
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(1));
+        backoff.failed();
+        assert_eq!(backoff.delay(), Duration::from_millis(500));
+        backoff.failed();
+        backoff.failed();
+        assert_eq!(backoff.delay(), Duration::from_secs(1));
+        backoff.reset();
+        assert_eq!(backoff.delay(), Duration::from_millis(250));
+    }
+
     #[tokio::test]
     async fn binary_roundtrip_and_heartbeat() {
         let (a, b) = duplex(64);
@@ -128,4 +578,187 @@ mod tests {
         let msg = tb.recv().await.expect("recv");
         assert_eq!(msg, b"hello");
     }
+
+    #[test]
+    fn payload_compression_roundtrip() {
+        let small = compress_payload(b"hi", 16).unwrap();
+        assert_eq!(small, [FLAG_PLAIN, b'h', b'i']);
+        assert_eq!(decompress_payload(&small).unwrap(), b"hi");
+
+        let big = vec![b'a'; 4096];
+        let packed = compress_payload(&big, 16).unwrap();
+        assert_eq!(packed[0], FLAG_DEFLATE);
+        assert!(packed.len() < big.len() / 10);
+        assert_eq!(decompress_payload(&packed).unwrap(), big);
+
+        assert!(decompress_payload(&[7, 1, 2]).is_err());
+        assert!(decompress_payload(&[]).is_err());
+
+        let bomb = compress_payload(&vec![0; MAX_PAYLOAD + 1], 0).unwrap();
+        assert!(bomb.len() < 1 << 20);
+        assert!(decompress_payload(&bomb).is_err());
+    }
+
+    #[tokio::test]
+    async fn compressed_transport_roundtrip() {
+        let (a, b) = duplex(1 << 16);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+        let mut ta = Transport::new(ws_a, Duration::from_secs(60));
+        let mut tb = Transport::new(ws_b, Duration::from_secs(60));
+        ta.set_compression(Some(COMPRESSION_THRESHOLD));
+        tb.set_compression(Some(COMPRESSION_THRESHOLD));
+
+        let big = "line of text\n".repeat(500).into_bytes();
+        ta.send(&big).await.expect("send");
+        ta.send(b"small").await.expect("send");
+        assert_eq!(tb.recv().await.expect("recv"), big);
+        assert_eq!(tb.recv().await.expect("recv"), b"small");
+    }
+
+    #[tokio::test]
+    async fn bounded_queue_applies_backpressure_and_coalesces_frames() {
+        let (a, b) = duplex(64);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let ta = Transport::with_config(
+            ws_a,
+            TransportConfig {
+                ping_interval: Duration::from_secs(60),
+                queue_capacity: 2,
+                pong_timeout: None,
+            },
+        );
+
+        // Nobody reads the other end yet, so the writer stalls on the first
+        // message once the pipe is full.
+        ta.send(&[0; 256]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        ta.send(b"m1").await.unwrap();
+        ta.send(b"m2").await.unwrap();
+        assert_eq!(ta.queue_len(), 2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), ta.send(b"m3"))
+                .await
+                .is_err()
+        );
+
+        ta.send_frame(b"f1").await.unwrap();
+        ta.send_frame(b"f2").await.unwrap();
+        ta.send_frame(b"f3").await.unwrap();
+        assert_eq!(ta.queue_len(), 3);
+        assert_eq!(ta.dropped_frames(), 2);
+
+        let mut tb = Transport::new(ws_b, Duration::from_secs(60));
+        assert_eq!(tb.recv().await.unwrap(), vec![0; 256]);
+        assert_eq!(tb.recv().await.unwrap(), b"m1");
+        assert_eq!(tb.recv().await.unwrap(), b"m2");
+        assert_eq!(tb.recv().await.unwrap(), b"f3");
+        assert_eq!(ta.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn high_priority_messages_jump_the_queue() {
+        let (a, b) = duplex(64);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let ta = Transport::with_config(
+            ws_a,
+            TransportConfig {
+                ping_interval: Duration::from_secs(60),
+                queue_capacity: 1,
+                pong_timeout: None,
+            },
+        );
+
+        // Stall the writer on a large message, then fill the normal lane.
+        ta.send(&[0; 256]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        ta.send(b"diff").await.unwrap();
+        ta.send_frame(b"frame").await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), ta.send(b"blocked"))
+                .await
+                .is_err()
+        );
+
+        // The high lane has its own capacity and is drained first.
+        ta.send_priority(b"key1", Priority::High).await.unwrap();
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(20),
+                ta.send_priority(b"key2", Priority::High)
+            )
+            .await
+            .is_err()
+        );
+        assert_eq!(ta.queue_len(), 3);
+
+        let mut tb = Transport::new(ws_b, Duration::from_secs(60));
+        assert_eq!(tb.recv().await.unwrap(), vec![0; 256]);
+        assert_eq!(tb.recv().await.unwrap(), b"key1");
+        assert_eq!(tb.recv().await.unwrap(), b"diff");
+        assert_eq!(tb.recv().await.unwrap(), b"frame");
+    }
+
+    #[tokio::test]
+    async fn drop_flushes_queue_and_closes() {
+        let (a, b) = duplex(1 << 16);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let ta = Transport::new(ws_a, Duration::from_secs(60));
+        let mut tb = Transport::new(ws_b, Duration::from_secs(60));
+
+        ta.send(b"last").await.unwrap();
+        drop(ta);
+        assert_eq!(tb.recv().await.unwrap(), b"last");
+        assert_eq!(tb.recv().await, None);
+        assert_eq!(tb.disconnected().await, DisconnectReason::Closed);
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_the_queue_to_flush() {
+        let (a, b) = duplex(1 << 16);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let ta = Transport::new(ws_a, Duration::from_secs(60));
+        let mut tb = Transport::new(ws_b, Duration::from_secs(60));
+
+        ta.send(b"one").await.unwrap();
+        ta.send(b"two").await.unwrap();
+        ta.close().await;
+        assert_eq!(tb.recv().await.unwrap(), b"one");
+        assert_eq!(tb.recv().await.unwrap(), b"two");
+        assert_eq!(tb.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn missed_pongs_close_the_link() {
+        let (a, b) = duplex(1 << 16);
+        let ws_a = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        // The peer socket is never polled, so pings go unanswered.
+        let _ws_b = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let mut ta = Transport::with_config(
+            ws_a,
+            TransportConfig {
+                ping_interval: Duration::from_millis(20),
+                queue_capacity: 4,
+                pong_timeout: Some(Duration::from_millis(60)),
+            },
+        );
+        let state = ta.link_state();
+        assert_eq!(*state.borrow(), LinkState::Connected);
+
+        let reason = tokio::time::timeout(Duration::from_secs(1), ta.disconnected())
+            .await
+            .expect("timed out waiting for disconnect");
+        assert_eq!(reason, DisconnectReason::PongTimeout);
+        assert_eq!(
+            *state.borrow(),
+            LinkState::Disconnected(DisconnectReason::PongTimeout)
+        );
+        assert!(ta.send(b"late").await.is_err());
+        assert_eq!(ta.recv().await, None);
+    }
 }
@@ -1,17 +1,50 @@
+use std::io;
 use std::ops::Range;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::RopeBuffer;
+use crc32fast::Hasher;
+
+use crate::{RopeBuffer, atomic_write};
+
+const MAGIC: &[u8; 4] = b"GWUN";
+const VERSION: u8 = 1;
+const TYPE_INSERT: u8 = 1;
+const TYPE_DELETE: u8 = 2;
+const TYPE_REPLACE: u8 = 3;
+const TYPE_GROUP: u8 = 4;
+
+/// Characters typed within this long of the one before undo together.
+pub const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_secs(1);
 
 /// Edit operation that can be undone/redone.
 pub enum Edit {
-    Insert { idx: usize, text: String },
-    Delete { idx: usize, text: String },
+    Insert {
+        idx: usize,
+        text: String,
+    },
+    Delete {
+        idx: usize,
+        text: String,
+    },
+    /// `old` at `idx` replaced by `new`, undone in one step.
+    Replace {
+        idx: usize,
+        old: String,
+        new: String,
+    },
+    /// Edits made together in this order, undone in one step.
+    Group(Vec<Edit>),
 }
 
 /// Linear undo/redo stack.
 pub struct UndoStack {
     past: Vec<Edit>,
     future: Vec<Edit>,
+    /// When a character was last typed onto the most recent edit, while
+    /// more typing may still join it.
+    typed_at: Option<Instant>,
+    window: Duration,
 }
 
 impl UndoStack {
@@ -20,20 +53,39 @@ impl UndoStack {
         Self {
             past: Vec::new(),
             future: Vec::new(),
+            typed_at: None,
+            window: DEFAULT_COALESCE_WINDOW,
         }
     }
 
-    /// Apply an insert and record it.
+    /// Undo characters typed within `window` of each other together.
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Apply an insert and record it. A single character typed right
+    /// after the last one, soon enough, joins its edit unless it starts
+    /// the space after a word or a new line.
     pub fn insert(&mut self, buf: &mut RopeBuffer, idx: usize, text: &str) {
         buf.insert(idx, text);
+        self.future.clear();
+        let mut chars = text.chars();
+        let typed = chars.next().filter(|_| chars.next().is_none());
+        let now = Instant::now();
+        let recent = (self.typed_at).is_some_and(|at| now.duration_since(at) < self.window);
+        self.typed_at = typed.map(|_| now);
         match self.past.last_mut() {
             Some(Edit::Insert {
                 idx: last_idx,
                 text: last_text,
-            }) if idx == *last_idx + last_text.len() => {
-                last_text.push_str(text);
-                self.future.clear();
-                return;
+            }) if recent && idx == *last_idx + last_text.len() => {
+                let word_ends = (last_text.chars().last()).is_some_and(|c| !c.is_whitespace());
+                let joins = typed.is_some_and(|c| c != '\n' && !(word_ends && c.is_whitespace()));
+                if joins {
+                    last_text.push_str(text);
+                    return;
+                }
             }
             _ => {}
         }
@@ -41,7 +93,12 @@ impl UndoStack {
             idx,
             text: text.to_string(),
         });
-        self.future.clear();
+    }
+
+    /// Start a new edit with whatever is typed next, as when the cursor
+    /// moves away and back.
+    pub fn break_group(&mut self) {
+        self.typed_at = None;
     }
 
     /// Apply a delete and record it.
@@ -55,19 +112,58 @@ impl UndoStack {
             text: removed,
         });
         self.future.clear();
+        self.typed_at = None;
+    }
+
+    /// Replace `range` with `text` and record it as a single edit.
+    pub fn replace(&mut self, buf: &mut RopeBuffer, range: Range<usize>, text: &str) {
+        let old = buf.slice(range.clone());
+        buf.delete(range.clone());
+        buf.insert(range.start, text);
+        self.past.push(Edit::Replace {
+            idx: range.start,
+            old,
+            new: text.to_string(),
+        });
+        self.future.clear();
+        self.typed_at = None;
+    }
+
+    /// Replace each range with its text and record it all as a single edit.
+    /// The ranges must be ordered and not overlap; they are replaced from
+    /// the last so the offsets of earlier ones stay valid.
+    pub fn replace_all(&mut self, buf: &mut RopeBuffer, edits: &[(Range<usize>, String)]) {
+        let mut group = Vec::with_capacity(edits.len());
+        for (range, text) in edits.iter().rev() {
+            let old = buf.slice(range.clone());
+            buf.delete(range.clone());
+            buf.insert(range.start, text);
+            group.push(Edit::Replace {
+                idx: range.start,
+                old,
+                new: text.clone(),
+            });
+        }
+        self.past.push(Edit::Group(group));
+        self.future.clear();
+        self.typed_at = None;
+    }
+
+    /// The edit the next [`undo`](Self::undo) reverts.
+    pub fn peek_undo(&self) -> Option<&Edit> {
+        self.past.last()
+    }
+
+    /// The edit the next [`redo`](Self::redo) reapplies.
+    pub fn peek_redo(&self) -> Option<&Edit> {
+        self.future.last()
     }
 
     /// Undo the most recent edit. Returns `true` if an edit was undone.
     pub fn undo(&mut self, buf: &mut RopeBuffer) -> bool {
+        self.typed_at = None;
         if let Some(edit) = self.past.pop() {
-            match &edit {
-                Edit::Insert { idx, text } => {
-                    buf.delete(*idx..*idx + text.len());
-                }
-                Edit::Delete { idx, text } => {
-                    buf.insert(*idx, text);
-                }
-            }
+            revert(buf, &edit);
             self.future.push(edit);
             true
         } else {
@@ -77,21 +173,195 @@ impl UndoStack {
 
     /// Redo the most recently undone edit. Returns `true` if an edit was redone.
     pub fn redo(&mut self, buf: &mut RopeBuffer) -> bool {
+        self.typed_at = None;
         if let Some(edit) = self.future.pop() {
-            match &edit {
-                Edit::Insert { idx, text } => {
-                    buf.insert(*idx, text);
-                }
-                Edit::Delete { idx, text } => {
-                    buf.delete(*idx..*idx + text.len());
-                }
-            }
+            reapply(buf, &edit);
             self.past.push(edit);
             true
         } else {
             false
         }
     }
+
+    /// Write the history to `path` for [`load`](Self::load) to restore once
+    /// the text of `buf` is opened again. The oldest edits are left out to
+    /// stay within `max_size` bytes, then the undone ones furthest from
+    /// being redone.
+    pub fn save(&self, path: &Path, buf: &RopeBuffer, max_size: usize) -> io::Result<()> {
+        let mut past: Vec<Vec<u8>> = self.past.iter().map(encode).collect();
+        let mut future: Vec<Vec<u8>> = self.future.iter().map(encode).collect();
+        let mut size: usize = past.iter().chain(&future).map(Vec::len).sum();
+        let mut oldest_over = |edits: &[Vec<u8>]| {
+            let mut count = 0;
+            while size > max_size && count < edits.len() {
+                size -= edits[count].len();
+                count += 1;
+            }
+            count
+        };
+        past.drain(..oldest_over(&past));
+        future.drain(..oldest_over(&future));
+        let mut out = Vec::with_capacity(size + 21);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&checksum(buf).to_be_bytes());
+        out.extend_from_slice(&(buf.len_bytes() as u64).to_be_bytes());
+        for edits in [past, future] {
+            out.extend_from_slice(&(edits.len() as u32).to_be_bytes());
+            edits.iter().for_each(|edit| out.extend_from_slice(edit));
+        }
+        atomic_write(path, &out)
+    }
+
+    /// Read the history [`save`](Self::save) wrote to `path`, or `None` if
+    /// it was saved for other text than that of `buf`.
+    pub fn load(path: &Path, buf: &RopeBuffer) -> io::Result<Option<Self>> {
+        let bytes = std::fs::read(path)?;
+        let mut input = bytes.as_slice();
+        if take(&mut input, 5)? != [&MAGIC[..], &[VERSION]].concat() {
+            return Err(invalid());
+        }
+        let crc = u32::from_be_bytes(take(&mut input, 4)?.try_into().unwrap());
+        let len = u64::from_be_bytes(take(&mut input, 8)?.try_into().unwrap());
+        if len != buf.len_bytes() as u64 || crc != checksum(buf) {
+            return Ok(None);
+        }
+        let mut stacks = [Vec::new(), Vec::new()];
+        for edits in &mut stacks {
+            for _ in 0..read_u32(&mut input)? {
+                edits.push(decode(&mut input, true)?);
+            }
+        }
+        let [past, future] = stacks;
+        Ok(Some(Self {
+            past,
+            future,
+            ..Self::new()
+        }))
+    }
+}
+
+/// CRC32 of the text of `buf`, to tell whether saved history applies to
+/// it.
+fn checksum(buf: &RopeBuffer) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf.text().as_bytes());
+    hasher.finalize()
+}
+
+fn encode(edit: &Edit) -> Vec<u8> {
+    let mut out = Vec::new();
+    match edit {
+        Edit::Insert { idx, text } => {
+            out.push(TYPE_INSERT);
+            out.extend_from_slice(&(*idx as u64).to_be_bytes());
+            put_text(&mut out, text);
+        }
+        Edit::Delete { idx, text } => {
+            out.push(TYPE_DELETE);
+            out.extend_from_slice(&(*idx as u64).to_be_bytes());
+            put_text(&mut out, text);
+        }
+        Edit::Replace { idx, old, new } => {
+            out.push(TYPE_REPLACE);
+            out.extend_from_slice(&(*idx as u64).to_be_bytes());
+            put_text(&mut out, old);
+            put_text(&mut out, new);
+        }
+        Edit::Group(edits) => {
+            out.push(TYPE_GROUP);
+            out.extend_from_slice(&(edits.len() as u32).to_be_bytes());
+            edits.iter().for_each(|edit| out.extend(encode(edit)));
+        }
+    }
+    out
+}
+
+fn put_text(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(&(text.len() as u32).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Read an edit [`encode`] wrote from the start of `input`; groups only
+/// hold other edits when `group` allows one.
+fn decode(input: &mut &[u8], group: bool) -> io::Result<Edit> {
+    let ty = take(input, 1)?[0];
+    if ty == TYPE_GROUP && group {
+        let count = read_u32(input)?;
+        let edits = (0..count).map(|_| decode(input, false));
+        return Ok(Edit::Group(edits.collect::<io::Result<_>>()?));
+    }
+    let idx = u64::from_be_bytes(take(input, 8)?.try_into().unwrap()) as usize;
+    match ty {
+        TYPE_INSERT => Ok(Edit::Insert {
+            idx,
+            text: read_text(input)?,
+        }),
+        TYPE_DELETE => Ok(Edit::Delete {
+            idx,
+            text: read_text(input)?,
+        }),
+        TYPE_REPLACE => Ok(Edit::Replace {
+            idx,
+            old: read_text(input)?,
+            new: read_text(input)?,
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(invalid());
+    }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Ok(head)
+}
+
+fn read_u32(input: &mut &[u8]) -> io::Result<u32> {
+    Ok(u32::from_be_bytes(take(input, 4)?.try_into().unwrap()))
+}
+
+fn read_text(input: &mut &[u8]) -> io::Result<String> {
+    let len = read_u32(input)? as usize;
+    String::from_utf8(take(input, len)?.to_vec()).map_err(|_| invalid())
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt undo history")
+}
+
+fn revert(buf: &mut RopeBuffer, edit: &Edit) {
+    match edit {
+        Edit::Insert { idx, text } => {
+            buf.delete(*idx..*idx + text.len());
+        }
+        Edit::Delete { idx, text } => {
+            buf.insert(*idx, text);
+        }
+        Edit::Replace { idx, old, new } => {
+            buf.delete(*idx..*idx + new.len());
+            buf.insert(*idx, old);
+        }
+        Edit::Group(edits) => edits.iter().rev().for_each(|edit| revert(buf, edit)),
+    }
+}
+
+fn reapply(buf: &mut RopeBuffer, edit: &Edit) {
+    match edit {
+        Edit::Insert { idx, text } => {
+            buf.insert(*idx, text);
+        }
+        Edit::Delete { idx, text } => {
+            buf.delete(*idx..*idx + text.len());
+        }
+        Edit::Replace { idx, old, new } => {
+            buf.delete(*idx..*idx + old.len());
+            buf.insert(*idx, new);
+        }
+        Edit::Group(edits) => edits.iter().for_each(|edit| reapply(buf, edit)),
+    }
 }
 
 impl Default for UndoStack {
@@ -128,6 +398,96 @@ mod tests {
         assert_eq!(buf.text(), "hello");
     }
 
+    #[test]
+    fn undo_redo_replace() {
+        let mut buf = RopeBuffer::from_text("a cat sat");
+        let mut stack = UndoStack::new();
+        stack.replace(&mut buf, 2..9, "dog stood");
+        assert_eq!(buf.text(), "a dog stood");
+        stack.insert(&mut buf, 11, "!");
+        assert!(stack.undo(&mut buf));
+        assert!(stack.undo(&mut buf));
+        assert_eq!(buf.text(), "a cat sat");
+        assert!(stack.redo(&mut buf));
+        assert_eq!(buf.text(), "a dog stood");
+    }
+
+    #[test]
+    fn undo_redo_replace_all() {
+        let mut buf = RopeBuffer::from_text("a b c");
+        let mut stack = UndoStack::new();
+        let edits = [
+            (0..1, "xy".to_string()),
+            (2..2, "-".into()),
+            (4..5, String::new()),
+        ];
+        stack.replace_all(&mut buf, &edits);
+        assert_eq!(buf.text(), "xy -b ");
+        stack.insert(&mut buf, 6, "!");
+        assert!(stack.undo(&mut buf));
+        assert!(stack.undo(&mut buf));
+        assert_eq!(buf.text(), "a b c");
+        assert!(stack.redo(&mut buf));
+        assert_eq!(buf.text(), "xy -b ");
+    }
+
+    #[test]
+    fn peek_follows_undo_and_redo() {
+        let mut buf = RopeBuffer::from_text("ab");
+        let mut stack = UndoStack::new();
+        assert!(stack.peek_undo().is_none());
+        stack.delete(&mut buf, 0..1);
+        assert!(matches!(stack.peek_undo(), Some(Edit::Delete { idx: 0, text }) if text == "a"));
+        assert!(stack.peek_redo().is_none());
+        stack.undo(&mut buf);
+        assert!(stack.peek_undo().is_none());
+        assert!(matches!(
+            stack.peek_redo(),
+            Some(Edit::Delete { idx: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn saves_and_loads_history_for_the_same_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.undo");
+        let mut buf = RopeBuffer::from_text("a b c");
+        let mut stack = UndoStack::new();
+        stack.delete(&mut buf, 0..2);
+        stack.replace_all(&mut buf, &[(0..1, "x".into()), (2..3, "é".into())]);
+        stack.insert(&mut buf, 0, "!");
+        stack.undo(&mut buf);
+        stack.save(&path, &buf, 1 << 20).unwrap();
+
+        let mut loaded = UndoStack::load(&path, &buf).unwrap().unwrap();
+        assert!(loaded.redo(&mut buf));
+        assert_eq!(buf.text(), "!x é");
+        while loaded.undo(&mut buf) {}
+        assert_eq!(buf.text(), "a b c");
+        assert!(UndoStack::load(&path, &buf).unwrap().is_none());
+
+        std::fs::write(&path, b"GWUN\x01short").unwrap();
+        assert!(UndoStack::load(&path, &buf).is_err());
+    }
+
+    #[test]
+    fn saving_leaves_out_the_oldest_edits_beyond_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.undo");
+        let mut buf = RopeBuffer::from_text("");
+        let mut stack = UndoStack::new();
+        stack.insert(&mut buf, 0, "old");
+        stack.delete(&mut buf, 0..1);
+        stack.delete(&mut buf, 0..1);
+        // Each delete of one byte takes 14 bytes: type, offset and text.
+        stack.save(&path, &buf, 28).unwrap();
+
+        let mut loaded = UndoStack::load(&path, &buf).unwrap().unwrap();
+        assert!(loaded.undo(&mut buf) && loaded.undo(&mut buf));
+        assert!(!loaded.undo(&mut buf));
+        assert_eq!(buf.text(), "old");
+    }
+
     #[test]
     fn coalesce_adjacent_inserts() {
         let mut buf = RopeBuffer::from_text("");
@@ -141,6 +501,43 @@ mod tests {
         assert!(!stack.undo(&mut buf));
     }
 
+    #[test]
+    fn typing_undoes_a_word_at_a_time() {
+        let mut buf = RopeBuffer::from_text("");
+        let mut stack = UndoStack::new();
+        for (i, c) in "hello world\nbye".char_indices() {
+            stack.insert(&mut buf, i, &c.to_string());
+        }
+        for text in ["hello world", "hello", ""] {
+            assert!(stack.undo(&mut buf));
+            assert_eq!(buf.text(), text);
+        }
+        assert!(!stack.undo(&mut buf));
+
+        // Pasted text and typing that pauses or resumes elsewhere stand alone.
+        stack.insert(&mut buf, 0, "ab");
+        stack.insert(&mut buf, 2, "c");
+        stack.break_group();
+        stack.insert(&mut buf, 3, "d");
+        assert!(stack.undo(&mut buf) && stack.undo(&mut buf));
+        assert_eq!(buf.text(), "ab");
+        let mut slow = UndoStack::new().with_coalesce_window(Duration::ZERO);
+        slow.insert(&mut buf, 2, "c");
+        slow.insert(&mut buf, 3, "d");
+        assert!(slow.undo(&mut buf));
+        assert_eq!(buf.text(), "abc");
+    }
+
+    #[test]
+    fn pasting_after_typing_starts_a_new_edit() {
+        let mut buf = RopeBuffer::from_text("");
+        let mut stack = UndoStack::new();
+        stack.insert(&mut buf, 0, "a");
+        stack.insert(&mut buf, 1, "pasted\nblock");
+        assert!(stack.undo(&mut buf));
+        assert_eq!(buf.text(), "a");
+    }
+
     #[test]
     fn separate_non_adjacent_inserts() {
         let mut buf = RopeBuffer::from_text("ab");
@@ -4,12 +4,31 @@ use ghostwriter_proto::{Cursor, Frame, Line, StyleSpan};
 
 use crate::buffer::RopeBuffer;
 
+/// Another user's cursor and selection, rendered in their color.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCursor {
+    pub user_id: String,
+    pub label: String,
+    /// Style class for the cursor and selection spans.
+    pub color_class: String,
+    /// Cursor byte offset.
+    pub head: usize,
+    /// Selected byte range; empty when nothing is selected.
+    pub selection: Range<usize>,
+}
+
 /// Parameters controlling viewport composition.
 pub struct ViewportParams<'a> {
     /// Selections to highlight, expressed as byte ranges.
     pub selections: &'a [Range<usize>],
+    /// Search matches to highlight with the `match` class.
+    pub matches: &'a [Range<usize>],
     /// Cursor byte offsets to report in the frame.
     pub cursors: &'a [usize],
+    /// Bookmarked lines, sorted, to flag as [`Line::marked`].
+    pub marks: &'a [usize],
+    /// Other users' cursors and selections.
+    pub peers: &'a [PeerCursor],
     /// Document version included with the frame.
     pub doc_v: u64,
     /// Left status line text.
@@ -26,7 +45,22 @@ pub fn compose(
     hscroll: u16,
     params: ViewportParams<'_>,
 ) -> Frame {
-    let mut lines_out = Vec::new();
+    let mut frame = Frame {
+        id: "editor".into(),
+        kind: "editor".into(),
+        doc_v: params.doc_v,
+        first_line: first_line as u64,
+        first_col: hscroll,
+        cols,
+        rows,
+        lines: Vec::new(),
+        cursors: Vec::new(),
+        status_left: params.status_left.into(),
+        status_right: params.status_right.into(),
+        status: None,
+        classes: Vec::new(),
+        checksum: None,
+    };
     let raw_lines = buf.slice_lines(first_line, rows as usize);
     for (idx, mut line) in raw_lines.into_iter().enumerate() {
         let line_idx = first_line + idx;
@@ -34,8 +68,14 @@ pub fn compose(
         let line_end = line_start + line.len();
         let mut spans: Vec<StyleSpan> = Vec::new();
 
-        // Selection spans
-        for sel in params.selections {
+        // Selection spans, the viewer's own first, then search matches
+        let own = params.selections.iter().map(|sel| (sel, "sel", None));
+        let found = params.matches.iter().map(|m| (m, "match", None));
+        let peers = params
+            .peers
+            .iter()
+            .map(|p| (&p.selection, p.color_class.as_str(), Some(&p.user_id)));
+        for (sel, class, user_id) in own.chain(found).chain(peers) {
             let start = sel.start.max(line_start);
             let end = sel.end.min(line_end);
             if start < end {
@@ -48,7 +88,8 @@ pub fn compose(
                     spans.push(StyleSpan {
                         start_col: sc as u16,
                         end_col: ec as u16,
-                        class_name: "sel".into(),
+                        class: frame.intern_class(class),
+                        user_id: user_id.cloned(),
                     });
                 }
             }
@@ -66,44 +107,56 @@ pub fn compose(
                 spans.push(StyleSpan {
                     start_col: start as u16,
                     end_col: end as u16,
-                    class_name: "ws".into(),
+                    class: frame.intern_class("ws"),
+                    user_id: None,
                 });
             }
         }
 
         // Apply horizontal scroll to text
-        let start = hscroll as usize;
+        let start = floor_boundary(&line, hscroll as usize);
+        let mut more = false;
         if start < line.len() {
-            let end = std::cmp::min(line.len(), start + cols as usize);
+            let end = floor_boundary(&line, start + cols as usize);
+            more = end < line.len();
             line = line[start..end].to_string();
         } else {
             line.clear();
         }
 
-        lines_out.push(Line { text: line, spans });
+        frame.lines.push(Line {
+            text: line,
+            spans,
+            more,
+            marked: params.marks.binary_search(&line_idx).is_ok(),
+        });
     }
 
-    let mut cursor_out = Vec::new();
     for &c in params.cursors {
         let (line, col) = buf.byte_to_line_col(c);
-        cursor_out.push(Cursor {
+        frame.cursors.push(Cursor::new(line as u64, col as u16));
+    }
+    for peer in params.peers {
+        let (line, col) = buf.byte_to_line_col(peer.head);
+        frame.cursors.push(Cursor {
             line: line as u64,
             col: col as u16,
+            user_id: Some(peer.user_id.clone()),
+            label: Some(peer.label.clone()),
+            color_class: Some(peer.color_class.clone()),
         });
     }
 
-    Frame {
-        id: "editor".into(),
-        kind: "editor".into(),
-        doc_v: params.doc_v,
-        first_line: first_line as u64,
-        cols,
-        rows,
-        lines: lines_out,
-        cursors: cursor_out,
-        status_left: params.status_left.into(),
-        status_right: params.status_right.into(),
+    frame
+}
+
+/// Largest char boundary in `s` at or before byte `idx`.
+fn floor_boundary(s: &str, idx: usize) -> usize {
+    let mut idx = idx.min(s.len());
+    while !s.is_char_boundary(idx) {
+        idx -= 1;
     }
+    idx
 }
 
 #[cfg(test)]
@@ -117,7 +170,10 @@ mod tests {
         let cursors = vec![8];
         let params = ViewportParams {
             selections: &selections,
+            matches: &[],
             cursors: &cursors,
+            marks: &[],
+            peers: &[],
             doc_v: 1,
             status_left: "L",
             status_right: "R",
@@ -132,12 +188,14 @@ mod tests {
                 StyleSpan {
                     start_col: 3,
                     end_col: 6,
-                    class_name: "sel".into(),
+                    class: 0,
+                    user_id: None,
                 },
                 StyleSpan {
                     start_col: 5,
                     end_col: 6,
-                    class_name: "ws".into(),
+                    class: 1,
+                    user_id: None,
                 },
             ]
         );
@@ -147,17 +205,146 @@ mod tests {
                 StyleSpan {
                     start_col: 0,
                     end_col: 2,
-                    class_name: "sel".into(),
+                    class: 0,
+                    user_id: None,
                 },
                 StyleSpan {
                     start_col: 5,
                     end_col: 6,
-                    class_name: "ws".into(),
+                    class: 1,
+                    user_id: None,
                 },
             ]
         );
-        assert_eq!(frame.cursors, vec![Cursor { line: 1, col: 1 }]);
+        assert_eq!(frame.classes, vec!["sel".to_string(), "ws".to_string()]);
+        assert_eq!(frame.cursors, vec![Cursor::new(1, 1)]);
         assert_eq!(frame.status_left, "L");
         assert_eq!(frame.status_right, "R");
     }
+
+    #[test]
+    fn composes_search_matches() {
+        let buf = RopeBuffer::from_text("ab ab\nab");
+        let selections: Vec<Range<usize>> = std::iter::once(0..2).collect();
+        let params = ViewportParams {
+            selections: &selections,
+            matches: &[0..2, 3..5, 6..8],
+            cursors: &[2],
+            marks: &[],
+            peers: &[],
+            doc_v: 0,
+            status_left: "",
+            status_right: "",
+        };
+        let frame = compose(&buf, 0, 10, 2, 0, params);
+        let cols: Vec<_> = frame.lines[0]
+            .spans
+            .iter()
+            .map(|s| (s.start_col, s.end_col, frame.class_name(s).unwrap()))
+            .collect();
+        assert_eq!(cols, [(0, 2, "sel"), (0, 2, "match"), (3, 5, "match")]);
+        assert_eq!(frame.class_name(&frame.lines[1].spans[0]), Some("match"));
+    }
+
+    #[test]
+    fn hscroll_never_splits_a_char() {
+        let buf = RopeBuffer::from_text("aé€b\n");
+        let params = ViewportParams {
+            selections: &[],
+            matches: &[],
+            cursors: &[0],
+            marks: &[],
+            peers: &[],
+            doc_v: 0,
+            status_left: "",
+            status_right: "",
+        };
+        // Byte 2 is inside "é" and byte 5 inside "€".
+        let frame = compose(&buf, 0, 3, 1, 2, params);
+        assert_eq!(frame.lines[0].text, "é");
+        assert_eq!((frame.first_col, frame.lines[0].more), (2, true));
+    }
+
+    #[test]
+    fn marks_lines_going_past_the_right_edge() {
+        let buf = RopeBuffer::from_text("abcd\nab\n");
+        let params = ViewportParams {
+            selections: &[],
+            matches: &[],
+            cursors: &[0],
+            marks: &[],
+            peers: &[],
+            doc_v: 0,
+            status_left: "",
+            status_right: "",
+        };
+        let frame = compose(&buf, 0, 3, 2, 0, params);
+        let more: Vec<_> = frame
+            .lines
+            .iter()
+            .map(|l| (l.text.as_str(), l.more))
+            .collect();
+        assert_eq!(more, [("abc", true), ("ab", false)]);
+    }
+
+    #[test]
+    fn flags_bookmarked_lines() {
+        let buf = RopeBuffer::from_text("a\nb\nc\nd\n");
+        let params = ViewportParams {
+            selections: &[],
+            matches: &[],
+            cursors: &[0],
+            marks: &[0, 2, 3],
+            peers: &[],
+            doc_v: 0,
+            status_left: "",
+            status_right: "",
+        };
+        let frame = compose(&buf, 1, 10, 2, 0, params);
+        let marked: Vec<_> = frame.lines.iter().map(|l| l.marked).collect();
+        assert_eq!(marked, [false, true]);
+    }
+
+    #[test]
+    fn composes_peer_cursors_and_selections() {
+        let buf = RopeBuffer::from_text("hello\nworld\n");
+        let peers = vec![PeerCursor {
+            user_id: "u2".into(),
+            label: "Ada".into(),
+            color_class: "peer-1".into(),
+            head: 8,
+            selection: 1..3,
+        }];
+        let params = ViewportParams {
+            selections: &[],
+            matches: &[],
+            cursors: &[0],
+            marks: &[],
+            peers: &peers,
+            doc_v: 1,
+            status_left: "",
+            status_right: "",
+        };
+        let frame = compose(&buf, 0, 10, 2, 0, params);
+        assert_eq!(
+            frame.lines[0].spans,
+            vec![StyleSpan {
+                start_col: 1,
+                end_col: 3,
+                class: 0,
+                user_id: Some("u2".into()),
+            }]
+        );
+        assert_eq!(frame.cursors[0], Cursor::new(0, 0));
+        assert_eq!(
+            frame.cursors[1],
+            Cursor {
+                line: 1,
+                col: 2,
+                user_id: Some("u2".into()),
+                label: Some("Ada".into()),
+                color_class: Some("peer-1".into()),
+            }
+        );
+    }
 }
@@ -0,0 +1,123 @@
+//! Detect changes other processes make to a file by polling its metadata.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// What is known about a file on disk: enough to notice it was rewritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+/// Read the stamp of `path`, or `None` when it does not exist.
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some(Stamp {
+        modified: meta.modified().ok(),
+        len: meta.len(),
+    })
+}
+
+/// How a watched file changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    /// The file was created or rewritten.
+    Modified,
+    /// The file no longer exists.
+    Removed,
+}
+
+/// Watches one file for changes made behind our back. Clones share what
+/// has been seen, so a writer can vouch for its own writes.
+#[derive(Debug, Clone)]
+pub struct FileWatcher {
+    path: PathBuf,
+    seen: Arc<Mutex<Option<Stamp>>>,
+}
+
+impl FileWatcher {
+    /// Watch `path`, taking its current state as seen.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let seen = Arc::new(Mutex::new(stamp(&path)));
+        Self { path, seen }
+    }
+
+    /// Watched file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run `write`, which changes the file on our behalf, and take the
+    /// result as seen. Polls wait meanwhile, so they never report it.
+    pub fn write_with<R>(&self, write: impl FnOnce() -> R) -> R {
+        let mut seen = self.seen.lock().unwrap();
+        let result = write();
+        *seen = stamp(&self.path);
+        result
+    }
+
+    /// Like [`write_with`](Self::write_with), but only while nothing else
+    /// changed the file since it was last seen; `None` if something did.
+    pub fn write_if_unchanged<R>(&self, write: impl FnOnce() -> R) -> Option<R> {
+        let mut seen = self.seen.lock().unwrap();
+        if stamp(&self.path) != *seen {
+            return None;
+        }
+        let result = write();
+        *seen = stamp(&self.path);
+        Some(result)
+    }
+
+    /// Report a change since the last poll or own write, if any.
+    pub fn poll(&self) -> Option<FileChange> {
+        let mut seen = self.seen.lock().unwrap();
+        let now = stamp(&self.path);
+        if now == *seen {
+            return None;
+        }
+        *seen = now;
+        Some(match now {
+            Some(_) => FileChange::Modified,
+            None => FileChange::Removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_foreign_writes_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "one").unwrap();
+        let watcher = FileWatcher::new(&path);
+        assert_eq!(watcher.poll(), None);
+
+        fs::write(&path, "three").unwrap();
+        assert_eq!(watcher.poll(), Some(FileChange::Modified));
+        assert_eq!(watcher.poll(), None);
+
+        watcher
+            .clone()
+            .write_with(|| fs::write(&path, "own write").unwrap());
+        assert_eq!(watcher.poll(), None);
+
+        fs::write(&path, "foreign").unwrap();
+        assert_eq!(watcher.write_if_unchanged(|| unreachable!()), None::<()>);
+        assert_eq!(watcher.poll(), Some(FileChange::Modified));
+        assert_eq!(watcher.write_if_unchanged(|| 1), Some(1));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(watcher.poll(), Some(FileChange::Removed));
+        fs::write(&path, "back").unwrap();
+        assert_eq!(watcher.poll(), Some(FileChange::Modified));
+    }
+}
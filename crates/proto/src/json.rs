@@ -0,0 +1,113 @@
+//! JSON views of the msgpack envelope format, for clients that cannot
+//! link the Rust types directly (see the `wasm` feature).
+
+use std::fmt;
+
+use crate::{Envelope, MessageType, decode, encode, peek_type};
+
+/// Error converting between JSON and the wire encoding.
+#[derive(Debug)]
+pub enum JsonError {
+    Json(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Json(e) => write!(f, "invalid JSON envelope: {e}"),
+            JsonError::Encode(e) => write!(f, "encode failed: {e}"),
+            JsonError::Decode(e) => write!(f, "decode failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(e: serde_json::Error) -> Self {
+        JsonError::Json(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for JsonError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        JsonError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for JsonError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        JsonError::Decode(e)
+    }
+}
+
+/// Encode a JSON envelope (`{"v": 1, "type": "Insert", "data": {...}}`)
+/// into the msgpack wire format. The payload is checked against the type
+/// named by `type`, so struct fields end up in wire order.
+pub fn encode_json(json: &str) -> Result<Vec<u8>, JsonError> {
+    let env: Envelope<serde_json::Value> = serde_json::from_str(json)?;
+    let bytes = with_payload!(env.ty, T => {
+        let data: T = serde_json::from_value(env.data)?;
+        encode(&Envelope {
+            v: env.v,
+            ty: env.ty,
+            data,
+        })?
+    }, none => encode(&env)?);
+    Ok(bytes)
+}
+
+/// Decode a msgpack envelope into its JSON form, with named payload fields.
+pub fn decode_json(bytes: &[u8]) -> Result<String, JsonError> {
+    let ty: MessageType = peek_type(bytes)?;
+    let json = with_payload!(ty, T => {
+        let env: Envelope<T> = decode(bytes)?;
+        serde_json::to_string(&env)?
+    }, none => {
+        let env: Envelope<serde_json::Value> = decode(bytes)?;
+        serde_json::to_string(&env)?
+    });
+    Ok(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Batch, Insert, encode_batch};
+
+    #[test]
+    fn json_roundtrips_through_wire_format() {
+        let insert = Envelope::new(
+            MessageType::Insert,
+            Insert {
+                pos: 3,
+                text: "hi".into(),
+                seq: 9,
+                base_doc_v: Some(4),
+            },
+        );
+        let json = r#"{"v":1,"type":"Insert","data":{"pos":3,"text":"hi","seq":9,"base_doc_v":4}}"#;
+        let bytes = encode_json(json).unwrap();
+        assert_eq!(bytes, encode(&insert).unwrap());
+        assert_eq!(decode_json(&bytes).unwrap(), json);
+    }
+
+    #[test]
+    fn handles_payloadless_and_batched_messages() {
+        let json = r#"{"v":1,"type":"Ping","data":null}"#;
+        assert_eq!(decode_json(&encode_json(json).unwrap()).unwrap(), json);
+
+        let batch = encode_batch(vec![vec![1, 2]]).unwrap();
+        let decoded: Envelope<Batch> =
+            decode(&encode_json(&decode_json(&batch).unwrap()).unwrap()).unwrap();
+        assert_eq!(decoded.data.messages[0].as_ref(), &[1, 2]);
+    }
+
+    #[test]
+    fn rejects_payload_of_wrong_shape() {
+        let json = r#"{"v":1,"type":"Insert","data":{"pos":"three"}}"#;
+        assert!(matches!(encode_json(json), Err(JsonError::Json(_))));
+    }
+}
@@ -2,9 +2,219 @@
 
 use serde::{Deserialize, Serialize};
 
+/// Evaluate `$body` with `$T` naming the payload type of message type
+/// `$ty`, or `$none` for message types without a payload struct.
+#[cfg(any(feature = "schema", feature = "json"))]
+macro_rules! with_payload {
+    ($ty:expr, $T:ident => $body:expr, none => $none:expr) => {
+        match $ty {
+            $crate::MessageType::Hello => {
+                type $T = $crate::Hello;
+                $body
+            }
+            $crate::MessageType::HelloAck => {
+                type $T = $crate::HelloAck;
+                $body
+            }
+            $crate::MessageType::Auth => {
+                type $T = $crate::Auth;
+                $body
+            }
+            $crate::MessageType::Open => {
+                type $T = $crate::Open;
+                $body
+            }
+            $crate::MessageType::Insert => {
+                type $T = $crate::Insert;
+                $body
+            }
+            $crate::MessageType::Delete => {
+                type $T = $crate::Delete;
+                $body
+            }
+            $crate::MessageType::Move => {
+                type $T = $crate::Move;
+                $body
+            }
+            $crate::MessageType::Select => {
+                type $T = $crate::Select;
+                $body
+            }
+            $crate::MessageType::AddCursor => {
+                type $T = $crate::AddCursor;
+                $body
+            }
+            $crate::MessageType::Copy => {
+                type $T = $crate::Copy;
+                $body
+            }
+            $crate::MessageType::Scroll => {
+                type $T = $crate::Scroll;
+                $body
+            }
+            $crate::MessageType::Resize => {
+                type $T = $crate::Resize;
+                $body
+            }
+            $crate::MessageType::Search => {
+                type $T = $crate::Search;
+                $body
+            }
+            $crate::MessageType::Replace => {
+                type $T = $crate::Replace;
+                $body
+            }
+            $crate::MessageType::GotoLine => {
+                type $T = $crate::GotoLine;
+                $body
+            }
+            $crate::MessageType::Jump => {
+                type $T = $crate::Jump;
+                $body
+            }
+            $crate::MessageType::Bookmark => {
+                type $T = $crate::Bookmark;
+                $body
+            }
+            $crate::MessageType::BookmarkList => {
+                type $T = $crate::BookmarkList;
+                $body
+            }
+            $crate::MessageType::Tab => {
+                type $T = $crate::Tab;
+                $body
+            }
+            $crate::MessageType::RequestFrame => {
+                type $T = $crate::RequestFrame;
+                $body
+            }
+            $crate::MessageType::PickerAction => {
+                type $T = $crate::PickerAction;
+                $body
+            }
+            $crate::MessageType::DirList => {
+                type $T = $crate::DirList;
+                $body
+            }
+            $crate::MessageType::BufferList => {
+                type $T = $crate::BufferList;
+                $body
+            }
+            $crate::MessageType::Watch => {
+                type $T = $crate::WatchRequest;
+                $body
+            }
+            $crate::MessageType::Unwatch => {
+                type $T = $crate::Unwatch;
+                $body
+            }
+            $crate::MessageType::WatchEvent => {
+                type $T = $crate::WatchEvent;
+                $body
+            }
+            $crate::MessageType::SearchFiles => {
+                type $T = $crate::SearchRequest;
+                $body
+            }
+            $crate::MessageType::SearchResults => {
+                type $T = $crate::SearchResultChunk;
+                $body
+            }
+            $crate::MessageType::ExternalChange => {
+                type $T = $crate::ExternalChange;
+                $body
+            }
+            $crate::MessageType::SessionList => {
+                type $T = $crate::SessionList;
+                $body
+            }
+            $crate::MessageType::Attach => {
+                type $T = $crate::Attach;
+                $body
+            }
+            $crate::MessageType::Queued => {
+                type $T = $crate::Queued;
+                $body
+            }
+            $crate::MessageType::Ack => {
+                type $T = $crate::Ack;
+                $body
+            }
+            $crate::MessageType::Frame => {
+                type $T = $crate::Frame;
+                $body
+            }
+            $crate::MessageType::FrameDiff => {
+                type $T = $crate::FrameDiff;
+                $body
+            }
+            $crate::MessageType::Status => {
+                type $T = $crate::Status;
+                $body
+            }
+            $crate::MessageType::Dialog => {
+                type $T = $crate::Dialog;
+                $body
+            }
+            $crate::MessageType::DialogResult => {
+                type $T = $crate::DialogResult;
+                $body
+            }
+            $crate::MessageType::Error => {
+                type $T = $crate::ErrorMsg;
+                $body
+            }
+            $crate::MessageType::Batch => {
+                type $T = $crate::Batch;
+                $body
+            }
+            $crate::MessageType::DuplicateLine
+            | $crate::MessageType::DeleteLine
+            | $crate::MessageType::Undo
+            | $crate::MessageType::Redo
+            | $crate::MessageType::Save
+            | $crate::MessageType::ListBuffers
+            | $crate::MessageType::ListSessions
+            | $crate::MessageType::CancelSearch
+            | $crate::MessageType::Reload
+            | $crate::MessageType::Dirty
+            | $crate::MessageType::Ping
+            | $crate::MessageType::Pong => $none,
+        }
+    };
+}
+
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
 pub const PROTOCOL_VERSION: u16 = 1;
 
+/// Protocol versions this build can speak, oldest first.
+pub const SUPPORTED_VERSIONS: &[u16] = &[1];
+
+/// Optional protocol features this build can speak.
+pub const FEATURES: &[&str] = &["frame_diff", "deflate"];
+
+/// Capability bits carried in [`Hello::caps`] and [`HelloAck::caps`].
+pub mod caps {
+    /// Terminal renders 24-bit color.
+    pub const TRUECOLOR: u32 = 1 << 0;
+    /// Client can report mouse events.
+    pub const MOUSE: u32 = 1 << 1;
+    /// Client can decompress payloads; equivalent to the `deflate` feature.
+    pub const COMPRESSION: u32 = 1 << 2;
+    /// Terminal supports OSC 52 clipboard writes.
+    pub const OSC52: u32 = 1 << 3;
+    /// All capabilities known to this build.
+    pub const ALL: u32 = TRUECOLOR | MOUSE | COMPRESSION | OSC52;
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Envelope<T> {
     pub v: u16,
     #[serde(rename = "type")]
@@ -23,118 +233,1136 @@ impl<T> Envelope<T> {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum MessageType {
     Hello,
+    HelloAck,
     Auth,
     Open,
     Insert,
     Delete,
     Move,
     Select,
+    AddCursor,
     Copy,
     Scroll,
     Resize,
     Search,
+    Replace,
     GotoLine,
+    Jump,
+    Bookmark,
+    BookmarkList,
+    Tab,
     DuplicateLine,
     DeleteLine,
+    Undo,
+    Redo,
     Save,
     RequestFrame,
     PickerAction,
+    DirList,
+    ListBuffers,
+    BufferList,
+    ListSessions,
+    SessionList,
+    Attach,
+    Watch,
+    Unwatch,
+    WatchEvent,
+    /// Search the contents of every file in the workspace.
+    SearchFiles,
+    SearchResults,
+    /// Stop the running `SearchFiles`.
+    CancelSearch,
     Ack,
     Frame,
+    FrameDiff,
     Dirty,
+    /// The open file changed on disk behind the session's back.
+    ExternalChange,
+    /// Read the open file again, dropping unsaved edits.
+    Reload,
     Status,
     Dialog,
+    DialogResult,
     Error,
+    /// Sent periodically while the server holds a connection in its wait
+    /// queue.
+    Queued,
     Ping,
     Pong,
+    Batch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Hello {
     pub client_name: String,
     pub client_ver: String,
     pub cols: u16,
     pub rows: u16,
     pub truecolor: bool,
+    /// Protocol versions the client supports. Empty for clients predating
+    /// negotiation, which only speak the envelope version.
+    #[serde(default)]
+    pub versions: Vec<u16>,
+    /// Optional features the client would like to use.
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Client capability bits, see [`caps`].
+    #[serde(default)]
+    pub caps: u32,
+    /// Whether the client edits or follows another client's session.
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a client does once connected.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Role {
+    /// Opens files and edits them.
+    #[default]
+    Editor,
+    /// Watches the frames of a session another client is editing, without
+    /// changing it.
+    Follower,
+}
+
+impl Hello {
+    /// Capability bits including those implied by legacy fields.
+    pub fn effective_caps(&self) -> u32 {
+        let mut bits = self.caps & caps::ALL;
+        if self.truecolor {
+            bits |= caps::TRUECOLOR;
+        }
+        if self.features.iter().any(|f| f == "deflate") {
+            bits |= caps::COMPRESSION;
+        }
+        bits
+    }
+}
+
+/// Server reply to [`Hello`] with the negotiated protocol version.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct HelloAck {
+    /// Version both sides will use for the rest of the connection.
+    pub version: u16,
+    /// All versions supported by the server.
+    pub versions: Vec<u16>,
+    /// Features enabled for this connection.
+    pub features: Vec<String>,
+    /// Capabilities both sides will rely on, see [`caps`].
+    #[serde(default)]
+    pub caps: u32,
+    /// The server reads an [`Auth`] message next and drops clients that
+    /// send anything else.
+    #[serde(default)]
+    pub auth: bool,
+}
+
+impl HelloAck {
+    /// Returns true if every bit in `cap` was agreed.
+    pub fn has(&self, cap: u32) -> bool {
+        self.caps & cap == cap
+    }
+}
+
+/// Negotiate the protocol for a received `Hello`.
+///
+/// Picks the highest version supported by both sides and the features both
+/// sides know about. Returns an `Unsupported` error describing both version
+/// lists when there is no common version.
+pub fn negotiate(hello: &Envelope<Hello>) -> Result<HelloAck, ErrorMsg> {
+    let offered = if hello.data.versions.is_empty() {
+        vec![hello.v]
+    } else {
+        hello.data.versions.clone()
+    };
+    let version = SUPPORTED_VERSIONS
+        .iter()
+        .rev()
+        .find(|v| offered.contains(v))
+        .copied()
+        .ok_or_else(|| {
+            ErrorMsg::new(
+                ErrorCode::ProtocolMismatch,
+                format!(
+                    "no common protocol version (client supports {offered:?}, server supports {SUPPORTED_VERSIONS:?})"
+                ),
+            )
+        })?;
+    let caps = hello.data.effective_caps();
+    let mut features: Vec<String> = hello
+        .data
+        .features
+        .iter()
+        .filter(|f| FEATURES.contains(&f.as_str()))
+        .cloned()
+        .collect();
+    if caps & caps::COMPRESSION != 0 && !features.iter().any(|f| f == "deflate") {
+        features.push("deflate".into());
+    }
+    Ok(HelloAck {
+        version,
+        versions: SUPPORTED_VERSIONS.to_vec(),
+        features,
+        caps,
+        auth: false,
+    })
 }
 
+/// Credentials sent right after `Hello` when the server requires them:
+/// either the shared secret, or a named token and its secret.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Auth {
     pub secret: String,
+    /// Id of the token `secret` belongs to; `None` for the shared secret.
+    #[serde(default)]
+    pub token_id: Option<String>,
+}
+
+impl Auth {
+    pub fn shared_secret(secret: impl Into<String>) -> Self {
+        Self {
+            secret: secret.into(),
+            token_id: None,
+        }
+    }
+
+    pub fn token(id: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            secret: secret.into(),
+            token_id: Some(id.into()),
+        }
+    }
 }
 
+/// Open a workspace-relative file, starting the editing session.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Open {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Resize {
     pub cols: u16,
     pub rows: u16,
 }
 
+/// Direction of a cursor movement.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Unit of a cursor movement.
+///
+/// `Grapheme` and `Word` move horizontally, `Page` vertically. `Line` moves
+/// to the line start/end horizontally or by one line vertically, and
+/// `Document` jumps to the start or end of the buffer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Granularity {
+    Grapheme,
+    Word,
+    Line,
+    Page,
+    Document,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Move {
+    pub dir: Direction,
+    pub granularity: Granularity,
+    /// Keep the selection anchor, extending the selection to the new
+    /// cursor position.
+    #[serde(default)]
+    pub extend: bool,
+    /// Grow a rectangular selection instead; see [`GotoLine::block`].
+    #[serde(default)]
+    pub block: bool,
+}
+
+/// How a [`Select`] changes the selection.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SelectMode {
+    /// Select from `anchor` to `head`.
+    #[default]
+    Set,
+    /// Keep the current anchor and move the head to `head`.
+    Extend,
+    /// Collapse the selection to its head; both offsets are ignored.
+    Clear,
+}
+
+/// Change the selection; both ends are byte offsets and `anchor == head`
+/// collapses it to a cursor.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Select {
+    pub anchor: u64,
+    pub head: u64,
+    #[serde(default)]
+    pub mode: SelectMode,
+}
+
+/// Where an [`AddCursor`] puts the new cursor.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum CursorTarget {
+    /// A line above the topmost cursor.
+    Above,
+    /// A line below the bottommost cursor.
+    Below,
+    /// Select the word at the cursor if nothing is selected, otherwise the
+    /// next occurrence of the text selected last.
+    NextMatch,
+}
+
+/// Add a cursor as described by `target`. Inserts and deletes are then
+/// made at every cursor, and moves move them all, until a [`Select`] that
+/// sets or clears the selection leaves just the one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AddCursor {
+    pub target: CursorTarget,
+}
+
+/// Unit of the vertical `delta` in a [`Scroll`].
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ScrollUnit {
+    #[default]
+    Line,
+    /// One viewport height.
+    Page,
+}
+
+/// Scroll the viewport by `delta` lines or pages (negative scrolls up) and
+/// by `dx` columns (negative scrolls left).
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Scroll {
+    pub delta: i64,
+    #[serde(default)]
+    pub unit: ScrollUnit,
+    #[serde(default)]
+    pub dx: i64,
+}
+
+/// Which way a [`Search`] moves from the selection.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SearchDir {
+    #[default]
+    Next,
+    Prev,
+}
+
+/// Select the next or previous occurrence of `query` around the selection,
+/// wrapping at either end of the document. An empty query ends the search.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Search {
+    pub query: String,
+    #[serde(default)]
+    pub dir: SearchDir,
+    /// Take `query` as a regular expression, as [`Replace`] does.
+    #[serde(default)]
+    pub regex: bool,
+    /// Text a [`Replace`] of the selected match would insert, previewed
+    /// in [`SearchStatus::preview`] while stepping through matches.
+    #[serde(default)]
+    pub replacement: Option<String>,
+    /// Only match `query` where it is not part of a longer word.
+    #[serde(default)]
+    pub whole_word: bool,
+    /// Only match inside the selection made when the search started.
+    #[serde(default)]
+    pub in_selection: bool,
+    /// Start asking about each match in turn from the selection. Later
+    /// steps with `replacement` and single [`Replace`]s select nothing once
+    /// they would come around past where this one started.
+    #[serde(default)]
+    pub start_confirm: bool,
+}
+
+/// Replace the selection with `replacement` if it is a match of `query`,
+/// then select the next match, wrapping like [`Search`]. With `all`, every
+/// match is replaced at once, undone as one edit. With `regex`, `query` is
+/// a regular expression and `$1` or `${name}` in `replacement` insert what
+/// its groups captured.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Replace {
+    pub query: String,
+    pub replacement: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub all: bool,
+    /// With `all`, leave the matches before the selection alone, or while
+    /// asking about each match, replace those still to be asked about.
+    #[serde(default)]
+    pub rest: bool,
+    /// As in [`Search`].
+    #[serde(default)]
+    pub whole_word: bool,
+    /// As in [`Search`]: only replace matches inside the range the active
+    /// search is confined to, or else inside the selection.
+    #[serde(default)]
+    pub in_selection: bool,
+}
+
+/// Move the cursor to a zero-based line, at byte `col` of it clamped to
+/// its end; clicking the mouse sends one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct GotoLine {
+    pub line: u64,
+    #[serde(default)]
+    pub col: u64,
+    /// Keep the selection anchor, extending the selection to the new
+    /// cursor position.
+    #[serde(default)]
+    pub extend: bool,
+    /// With `extend`, select the rectangle between the anchor and the new
+    /// cursor position instead: a cursor on each line, selecting the same
+    /// columns, which edits are then made at.
+    #[serde(default)]
+    pub block: bool,
+}
+
+/// Way a [`Jump`] goes through the jump list.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum JumpDir {
+    Back,
+    Forward,
+}
+
+/// Go back to where the cursor was before an earlier jump, or forward
+/// again. Jumps are moves to a search match or line off the lines shown,
+/// and opening another file; the cursor, selection and scroll position
+/// are restored as they were, in whichever file that was.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Jump {
+    pub dir: JumpDir,
+}
+
+/// What a [`Bookmark`] does.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum BookmarkAction {
+    /// Bookmark the cursor line, or remove its bookmark.
+    Toggle,
+    /// Move the cursor to the next bookmarked line, wrapping around.
+    Next,
+    /// Move the cursor to the previous bookmarked line, wrapping around.
+    Prev,
+    /// Reply with a [`BookmarkList`] of the bookmarks in every file the
+    /// session opened.
+    List,
+}
+
+/// Work with the bookmarks of the open file. Bookmarks belong to lines,
+/// moving with them as text is edited above, and are kept between
+/// sessions.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Bookmark {
+    pub action: BookmarkAction,
+}
+
+/// A bookmarked line.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Mark {
+    pub path: String,
+    /// Zero-based line number.
+    pub line: u64,
+    pub text: String,
+}
+
+/// Bookmarks sent in reply to a [`Bookmark`] listing them, by file in the
+/// order the files were opened and by line within each.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BookmarkList {
+    pub marks: Vec<Mark>,
+}
+
+/// The Tab key: move to the next tab stop of the snippet expanded last,
+/// or else expand the snippet the word before the cursor names, or else
+/// insert a tab. With `back`, move to the previous tab stop instead.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Tab {
+    #[serde(default)]
+    pub back: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct RequestFrame {
     pub reason: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Ack {
     pub seq: u64,
     pub doc_v: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Range {
     pub from: u64,
     pub to: u64,
 }
 
+/// Insert `text` at byte offset `pos`. With more than one cursor, `text`
+/// replaces the selection at each of them instead and `pos` is ignored.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Insert {
     pub pos: u64,
     pub text: String,
     pub seq: u64,
+    /// Last document version the client saw; see [`Delete::base_doc_v`].
+    #[serde(default)]
+    pub base_doc_v: Option<u64>,
+}
+
+/// Delete the bytes in `range`, or with more than one cursor every
+/// selection, ignoring `range`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Delete {
+    pub range: Range,
+    pub seq: u64,
+    /// Last document version the client saw. The edit is refused with
+    /// `Conflict` if anything but the client's own edits has changed the
+    /// document since, as its offsets may no longer point where it meant;
+    /// `None` applies it regardless.
+    #[serde(default)]
+    pub base_doc_v: Option<u64>,
 }
 
+/// Text of the selection, sent in reply to a `Copy` request. Requests
+/// leave `text` empty.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Copy {
     pub text: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct StyleSpan {
     pub start_col: u16,
     pub end_col: u16,
-    #[serde(rename = "class")]
-    pub class_name: String,
+    /// Index into the frame's [`Frame::classes`] table.
+    pub class: u16,
+    /// Owner of a selection span; `None` for the viewer's own selection
+    /// and non-selection styling.
+    #[serde(default)]
+    pub user_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Line {
     pub text: String,
     pub spans: Vec<StyleSpan>,
+    /// Whether the line goes on past the right edge of the frame.
+    #[serde(default)]
+    pub more: bool,
+    /// Whether the line is bookmarked; clients mark it at its left edge.
+    #[serde(default)]
+    pub marked: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Cursor {
     pub line: u64,
     pub col: u16,
+    /// Owner of the cursor; `None` for the viewer's own cursor.
+    #[serde(default)]
+    pub user_id: Option<String>,
+    /// Name shown next to another user's cursor.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Style class used to color another user's cursor and selection.
+    #[serde(default)]
+    pub color_class: Option<String>,
+}
+
+impl Cursor {
+    /// The viewer's own cursor at `line`, `col`.
+    pub fn new(line: u64, col: u16) -> Self {
+        Self {
+            line,
+            col,
+            user_id: None,
+            label: None,
+            color_class: None,
+        }
+    }
+}
+
+/// Whether the open file can be written.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum LockState {
+    Writable,
+    ReadOnly,
+}
+
+/// State of the link between client and server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ConnectionState {
+    Local,
+    Connected,
+    Reconnecting,
 }
 
+/// Typed status bar contents; clients format these locally.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Status {
+    /// Path of the open file as shown to the user.
+    pub path: String,
+    pub dirty: bool,
+    pub lock: LockState,
+    pub connection: ConnectionState,
+    pub doc_v: u64,
+    /// Zero-based cursor line.
+    pub line: u64,
+    /// Zero-based cursor column in bytes.
+    pub col: u64,
+    pub encoding: String,
+    /// Line ending style, `"LF"` or `"CRLF"`.
+    pub eol: String,
+    /// Matches of the active search, if any.
+    #[serde(default)]
+    pub search: Option<SearchStatus>,
+    /// Selection as byte offsets, `from` the anchor `to` the cursor, for
+    /// edits at the cursor. `None` for binary files.
+    #[serde(default)]
+    pub selection: Option<Range>,
+}
+
+/// Position of the selection among the matches of the active search.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SearchStatus {
+    /// One-based index of the selected match, 0 if the selection is not
+    /// on a match.
+    pub current: u64,
+    pub total: u64,
+    /// What the selected match would be replaced with, if the search
+    /// was given a replacement.
+    #[serde(default)]
+    pub preview: Option<String>,
+    /// The document is still being searched; `total` counts the matches
+    /// found so far.
+    #[serde(default)]
+    pub searching: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Frame {
     pub id: String,
     pub kind: String,
     pub doc_v: u64,
     pub first_line: u64,
+    /// Column the frame starts at when scrolled horizontally. Line text
+    /// and span columns already start there; cursor columns do not, so a
+    /// cursor is drawn `first_col` cells left of its column.
+    #[serde(default)]
+    pub first_col: u16,
     pub cols: u16,
     pub rows: u16,
     pub lines: Vec<Line>,
     pub cursors: Vec<Cursor>,
     pub status_left: String,
     pub status_right: String,
+    /// Structured status; `status_left`/`status_right` remain as a
+    /// preformatted fallback.
+    #[serde(default)]
+    pub status: Option<Status>,
+    /// Style class names referenced by index from [`StyleSpan::class`].
+    #[serde(default)]
+    pub classes: Vec<String>,
+    /// [`content_checksum`](Frame::content_checksum) of the lines as the
+    /// server rendered them, for clients to notice they drew something
+    /// else.
+    #[serde(default)]
+    pub checksum: Option<u32>,
+}
+
+impl Frame {
+    /// FNV-1a hash of the text of every line.
+    pub fn content_checksum(&self) -> u32 {
+        let mut hash: u32 = 0x811c_9dc5;
+        for line in &self.lines {
+            for byte in line.text.bytes().chain([b'\n']) {
+                hash = (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193);
+            }
+        }
+        hash
+    }
+
+    /// Whether the lines match `checksum`, if the server sent one.
+    pub fn checksum_matches(&self) -> bool {
+        self.checksum
+            .is_none_or(|sum| sum == self.content_checksum())
+    }
+
+    /// Return the table index for `name`, adding it if not yet present.
+    pub fn intern_class(&mut self, name: &str) -> u16 {
+        match self.classes.iter().position(|c| c == name) {
+            Some(idx) => idx as u16,
+            None => {
+                self.classes.push(name.to_string());
+                (self.classes.len() - 1) as u16
+            }
+        }
+    }
+
+    /// Class name of `span`, or `None` if its index is out of range.
+    pub fn class_name(&self, span: &StyleSpan) -> Option<&str> {
+        self.classes.get(span.class as usize).map(String::as_str)
+    }
+}
+
+/// Replacement for a run of consecutive lines within a frame.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct LineChange {
+    /// Index of the first replaced line, relative to `first_line`.
+    pub start: u16,
+    pub lines: Vec<Line>,
 }
 
+/// Incremental update against the last full [`Frame`] sent to the client.
+///
+/// A diff only applies to a base frame with the same `id`, `first_line` and
+/// viewport size whose `doc_v` equals `base_doc_v`; anything else is a desync
+/// and the client must request a full frame.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FrameDiff {
+    pub id: String,
+    pub base_doc_v: u64,
+    pub doc_v: u64,
+    pub first_line: u64,
+    pub cols: u16,
+    pub rows: u16,
+    /// Number of lines in the resulting frame.
+    pub line_count: u16,
+    pub changes: Vec<LineChange>,
+    pub cursors: Option<Vec<Cursor>>,
+    pub status_left: Option<String>,
+    pub status_right: Option<String>,
+    #[serde(default)]
+    pub status: Option<Status>,
+    /// Replacement class table when classes were added.
+    #[serde(default)]
+    pub classes: Option<Vec<String>>,
+    /// Checksum of the resulting frame, see [`Frame::checksum`].
+    #[serde(default)]
+    pub checksum: Option<u32>,
+}
+
+impl FrameDiff {
+    /// Compute the diff turning `old` into `new`.
+    ///
+    /// Returns `None` when the frames are not comparable (different id, kind,
+    /// viewport size, first line or column, or a class table that is not an
+    /// extension of the old one) and a full frame has to be sent instead.
+    pub fn between(old: &Frame, new: &Frame) -> Option<Self> {
+        if old.id != new.id
+            || old.kind != new.kind
+            || old.cols != new.cols
+            || old.rows != new.rows
+            || old.first_line != new.first_line
+            || old.first_col != new.first_col
+            || (old.status.is_some() && new.status.is_none())
+            || !new.classes.starts_with(&old.classes)
+        {
+            return None;
+        }
+        let mut changes: Vec<LineChange> = Vec::new();
+        for (idx, line) in new.lines.iter().enumerate() {
+            if old.lines.get(idx) == Some(line) {
+                continue;
+            }
+            match changes.last_mut() {
+                Some(change) if change.start as usize + change.lines.len() == idx => {
+                    change.lines.push(line.clone());
+                }
+                _ => changes.push(LineChange {
+                    start: idx as u16,
+                    lines: vec![line.clone()],
+                }),
+            }
+        }
+        Some(Self {
+            id: new.id.clone(),
+            base_doc_v: old.doc_v,
+            doc_v: new.doc_v,
+            first_line: new.first_line,
+            cols: new.cols,
+            rows: new.rows,
+            line_count: new.lines.len() as u16,
+            changes,
+            cursors: (old.cursors != new.cursors).then(|| new.cursors.clone()),
+            status_left: (old.status_left != new.status_left).then(|| new.status_left.clone()),
+            status_right: (old.status_right != new.status_right).then(|| new.status_right.clone()),
+            status: if old.status != new.status {
+                new.status.clone()
+            } else {
+                None
+            },
+            classes: (old.classes != new.classes).then(|| new.classes.clone()),
+            checksum: new.checksum,
+        })
+    }
+
+    /// Returns true if applying the diff would not change the base frame.
+    pub fn is_empty(&self) -> bool {
+        self.base_doc_v == self.doc_v
+            && self.changes.is_empty()
+            && self.cursors.is_none()
+            && self.status_left.is_none()
+            && self.status_right.is_none()
+            && self.status.is_none()
+            && self.classes.is_none()
+    }
+
+    /// Apply the diff to `base`, returning the updated frame.
+    ///
+    /// Returns `None` if `base` is not the frame this diff was computed
+    /// against, including when the result does not match the checksum.
+    pub fn apply(&self, base: &Frame) -> Option<Frame> {
+        if base.id != self.id
+            || base.doc_v != self.base_doc_v
+            || base.first_line != self.first_line
+            || base.cols != self.cols
+            || base.rows != self.rows
+        {
+            return None;
+        }
+        let mut frame = base.clone();
+        let count = self.line_count as usize;
+        frame.lines.truncate(count);
+        for change in &self.changes {
+            let start = change.start as usize;
+            if start > frame.lines.len() || start + change.lines.len() > count {
+                return None;
+            }
+            for (offset, line) in change.lines.iter().enumerate() {
+                match frame.lines.get_mut(start + offset) {
+                    Some(slot) => *slot = line.clone(),
+                    None => frame.lines.push(line.clone()),
+                }
+            }
+        }
+        if frame.lines.len() != count {
+            return None;
+        }
+        frame.doc_v = self.doc_v;
+        if let Some(cursors) = &self.cursors {
+            frame.cursors = cursors.clone();
+        }
+        if let Some(left) = &self.status_left {
+            frame.status_left = left.clone();
+        }
+        if let Some(right) = &self.status_right {
+            frame.status_right = right.clone();
+        }
+        if let Some(status) = &self.status {
+            frame.status = Some(status.clone());
+        }
+        if let Some(classes) = &self.classes {
+            frame.classes = classes.clone();
+        }
+        frame.checksum = self.checksum;
+        frame.checksum_matches().then_some(frame)
+    }
+}
+
+/// File picker operation; paths are relative to the workspace root.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum PickerAction {
+    /// Open a file in the editor.
+    Open {
+        path: String,
+    },
+    /// Create a file, or a folder when `dir` is set.
+    Create {
+        path: String,
+        dir: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Delete {
+        path: String,
+    },
+    /// List the children of a directory (`""` is the workspace root).
+    Expand {
+        path: String,
+    },
+    /// Fuzzy-match file paths across the workspace.
+    Search {
+        query: String,
+        limit: u32,
+    },
+    /// Show the picker frame listing a directory. The actions below drive
+    /// it and are answered with picker frames rather than a [`DirList`].
+    Show {
+        path: String,
+    },
+    /// Narrow the shown listing to entries fuzzy-matching `query`.
+    Filter {
+        query: String,
+    },
+    /// Move the picker selection by `delta` entries.
+    MoveSelection {
+        delta: i32,
+    },
+    /// Open the selected file, or list the selected folder.
+    Accept,
+    /// Hide the picker and return to the editor.
+    Close,
+}
+
+/// Entry in a [`DirList`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DirEntry {
+    /// Workspace-relative path.
+    pub path: String,
+    pub is_dir: bool,
+}
+
+/// Directory listing or search results sent in reply to a [`PickerAction`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DirList {
+    /// Listed directory; empty for the workspace root and search results.
+    pub path: String,
+    /// Query the entries match, for search results.
+    pub query: Option<String>,
+    pub entries: Vec<DirEntry>,
+}
+
+/// Files opened in a session, sent in reply to `ListBuffers`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BufferList {
+    /// Workspace-relative paths in the order they were first opened.
+    pub paths: Vec<String>,
+    /// Index of the file being edited.
+    pub active: u32,
+}
+
+/// Subscribe to changes below a workspace directory, reported as
+/// [`WatchEvent`]s until the matching [`Unwatch`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WatchRequest {
+    /// Workspace-relative directory; empty for the root.
+    pub path: String,
+    /// Include changes in subdirectories, not just direct children.
+    pub recursive: bool,
+}
+
+/// End the subscription to `path` made with a [`WatchRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Unwatch {
+    pub path: String,
+}
+
+/// Entries that changed below a watched directory.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WatchEvent {
+    /// The watched directory, as given in the [`WatchRequest`].
+    pub path: String,
+    pub changes: Vec<DirChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DirChange {
+    /// Workspace-relative path of the entry.
+    pub path: String,
+    pub kind: ChangeKind,
+    pub is_dir: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// Search the contents of every file in the workspace. Matches arrive in
+/// [`SearchResultChunk`]s as they are found; a new request or
+/// `CancelSearch` stops the previous search.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SearchRequest {
+    pub pattern: String,
+    /// Treat `pattern` as a regular expression instead of literal text.
+    pub regex: bool,
+    /// Match case exactly.
+    pub case: bool,
+    /// Stop after this many matches; 0 for no limit.
+    pub limit: u32,
+    /// Also search files excluded by `.gitignore`/`.ignore` files and the
+    /// server's exclude globs.
+    #[serde(default)]
+    pub no_ignore: bool,
+    /// Only match `pattern` where it is not part of a longer word.
+    #[serde(default)]
+    pub whole_word: bool,
+}
+
+/// Matches found by a [`SearchRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SearchResultChunk {
+    /// Pattern of the request these results belong to.
+    pub pattern: String,
+    pub matches: Vec<FileMatch>,
+    /// Set on the last chunk, once every file was searched or the limit
+    /// was reached.
+    pub done: bool,
+}
+
+/// Line of a file containing a search match.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FileMatch {
+    /// Workspace-relative path.
+    pub path: String,
+    /// Zero-based line number.
+    pub line: u64,
+    /// The whole line, without its line break.
+    pub text: String,
+    /// Byte range of the match within `text`.
+    pub range: Range,
+}
+
+/// Another process changed or removed the open file. Clients typically
+/// offer to `Reload`, or warn of a conflict when there are unsaved edits.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ExternalChange {
+    /// Path of the file as shown to the user.
+    pub path: String,
+    /// The file no longer exists.
+    pub removed: bool,
+    /// The session has edits not yet written to disk.
+    pub conflict: bool,
+}
+
+/// Sessions running on the server, sent in reply to `ListSessions`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionList {
+    pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SessionInfo {
+    /// Id to pass to [`Attach`].
+    pub id: u64,
+    /// File being edited, workspace-relative.
+    pub path: String,
+    /// Whether a client is editing the session; only detached sessions can
+    /// be attached to.
+    pub attached: bool,
+}
+
+/// Take over a session whose client went away, with its cursor, scroll
+/// position and undo history.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Attach {
+    pub id: u64,
+}
+
+/// Place of a waiting connection while the server is at capacity.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Queued {
+    /// One-based position; 1 is admitted next.
+    pub position: u32,
+}
+
+/// Button shown in a [`Dialog`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DialogButton {
+    pub id: String,
+    pub label: String,
+}
+
+/// Modal prompt driven by the server, e.g. save on exit or conflict
+/// resolution.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Dialog {
+    pub id: String,
+    pub title: String,
+    pub body: String,
+    pub buttons: Vec<DialogButton>,
+    /// Initial value of the text input, or `None` for dialogs without one.
+    pub input: Option<String>,
+}
+
+/// Client answer to a [`Dialog`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct DialogResult {
+    /// Id of the dialog being answered.
+    pub id: String,
+    /// Id of the chosen button, or `None` if the dialog was dismissed.
+    pub button: Option<String>,
+    pub input: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum ErrorCode {
     Unauthorized,
     Invalid,
@@ -142,14 +1370,126 @@ pub enum ErrorCode {
     Busy,
     Io,
     Sandbox,
+    /// The document changed since the version the request was based on.
     Conflict,
     RateLimit,
+    NotFound,
+    /// The document cannot be edited, e.g. a binary file shown as hex.
+    Readonly,
+    /// The request would make a file larger than the server allows.
+    TooLarge,
+    /// The client or workspace has used up its write allowance.
+    QuotaExceeded,
+    /// No protocol version is supported by both peers.
+    ProtocolMismatch,
+    /// The server is going away; the session was saved first.
+    ShuttingDown,
+}
+
+impl ErrorCode {
+    /// Whether repeating the request later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Busy | ErrorCode::RateLimit | ErrorCode::Conflict | ErrorCode::ShuttingDown
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ErrorMsg {
     pub code: ErrorCode,
+    /// Human-readable description.
     pub msg: String,
+    /// Suggested delay before retrying, for transient errors.
+    #[serde(default)]
+    pub retry_after_ms: Option<u64>,
+    /// Workspace path the error refers to.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Document version the server was at when the error occurred.
+    #[serde(default)]
+    pub doc_v: Option<u64>,
+    /// `seq` of the request that failed, for requests that carry one.
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+impl ErrorMsg {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            retry_after_ms: None,
+            path: None,
+            doc_v: None,
+            seq: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, delay: std::time::Duration) -> Self {
+        self.retry_after_ms = Some(delay.as_millis().try_into().unwrap_or(u64::MAX));
+        self
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn with_doc_v(mut self, doc_v: u64) -> Self {
+        self.doc_v = Some(doc_v);
+        self
+    }
+
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// Delay the server asked for before retrying, if any.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        self.retry_after_ms.map(std::time::Duration::from_millis)
+    }
+}
+
+/// Several encoded envelopes sent as one message.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Batch {
+    #[cfg_attr(feature = "schema", schemars(with = "Vec<Vec<u8>>"))]
+    pub messages: Vec<serde_bytes::ByteBuf>,
+}
+
+/// Wrap already encoded envelopes in a single `Batch` envelope.
+pub fn encode_batch(messages: Vec<Vec<u8>>) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let batch = Batch {
+        messages: messages
+            .into_iter()
+            .map(serde_bytes::ByteBuf::from)
+            .collect(),
+    };
+    encode(&Envelope::new(MessageType::Batch, batch))
+}
+
+/// Return the message type of an encoded envelope without decoding its data.
+pub fn peek_type(bytes: &[u8]) -> Result<MessageType, rmp_serde::decode::Error> {
+    decode::<serde::de::IgnoredAny>(bytes).map(|env| env.ty)
+}
+
+/// Split an encoded message into its envelopes, unpacking `Batch` messages.
+pub fn unbatch(bytes: &[u8]) -> Result<Vec<Vec<u8>>, rmp_serde::decode::Error> {
+    if peek_type(bytes)? != MessageType::Batch {
+        return Ok(vec![bytes.to_vec()]);
+    }
+    let env: Envelope<Batch> = decode(bytes)?;
+    Ok(env
+        .data
+        .messages
+        .into_iter()
+        .map(serde_bytes::ByteBuf::into_vec)
+        .collect())
 }
 
 pub fn encode<T: Serialize>(envelope: &Envelope<T>) -> Result<Vec<u8>, rmp_serde::encode::Error> {
@@ -174,6 +1514,10 @@ mod tests {
             cols: 120,
             rows: 40,
             truecolor: true,
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            features: vec!["frame_diff".into()],
+            caps: caps::TRUECOLOR | caps::OSC52,
+            role: Role::Follower,
         };
         let env = Envelope::new(MessageType::Hello, hello.clone());
         let encoded = encode(&env).expect("encode");
@@ -183,16 +1527,132 @@ mod tests {
         assert_eq!(decoded.data, hello);
     }
 
+    fn hello_with(versions: Vec<u16>, features: Vec<String>) -> Envelope<Hello> {
+        Envelope::new(
+            MessageType::Hello,
+            Hello {
+                client_name: "c".into(),
+                client_ver: "1".into(),
+                cols: 80,
+                rows: 24,
+                truecolor: false,
+                versions,
+                features,
+                caps: 0,
+                role: Role::Editor,
+            },
+        )
+    }
+
+    #[test]
+    fn negotiates_common_version_and_features() {
+        let hello = hello_with(vec![1, 99], vec!["frame_diff".into(), "unknown".into()]);
+        let ack = negotiate(&hello).unwrap();
+        assert_eq!(ack.version, 1);
+        assert_eq!(ack.versions, SUPPORTED_VERSIONS);
+        assert_eq!(ack.features, vec!["frame_diff".to_string()]);
+
+        let env = Envelope::new(MessageType::HelloAck, ack.clone());
+        let decoded: Envelope<HelloAck> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.data, ack);
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let mut hello = hello_with(vec![1], Vec::new());
+        hello.data.truecolor = true;
+        hello.data.caps = caps::MOUSE | caps::COMPRESSION | (1 << 31);
+        let ack = negotiate(&hello).unwrap();
+        assert!(ack.has(caps::TRUECOLOR | caps::MOUSE | caps::COMPRESSION));
+        assert!(!ack.has(caps::OSC52));
+        assert_eq!(ack.caps & (1 << 31), 0);
+        assert_eq!(ack.features, vec!["deflate".to_string()]);
+
+        let hello = hello_with(vec![1], vec!["deflate".into()]);
+        assert!(negotiate(&hello).unwrap().has(caps::COMPRESSION));
+    }
+
+    #[test]
+    fn negotiation_falls_back_to_envelope_version() {
+        let hello = hello_with(Vec::new(), Vec::new());
+        assert_eq!(negotiate(&hello).unwrap().version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn negotiation_reports_mismatch() {
+        let hello = hello_with(vec![99], Vec::new());
+        let err = negotiate(&hello).unwrap_err();
+        assert_eq!(err.code, ErrorCode::ProtocolMismatch);
+        assert!(err.msg.contains("[99]"));
+    }
+
+    #[test]
+    fn decodes_hello_without_negotiation_fields() {
+        #[derive(Serialize)]
+        struct LegacyHello {
+            client_name: String,
+            client_ver: String,
+            cols: u16,
+            rows: u16,
+            truecolor: bool,
+        }
+        let legacy = LegacyHello {
+            client_name: "old".into(),
+            client_ver: "0.1".into(),
+            cols: 80,
+            rows: 24,
+            truecolor: true,
+        };
+        let bytes = encode(&Envelope::new(MessageType::Hello, legacy)).unwrap();
+        let env: Envelope<Hello> = decode(&bytes).unwrap();
+        assert_eq!(env.data.client_name, "old");
+        assert!(env.data.versions.is_empty());
+        assert!(env.data.features.is_empty());
+        assert_eq!(env.data.caps, 0);
+        assert_eq!(env.data.effective_caps(), caps::TRUECOLOR);
+    }
+
     #[test]
     fn auth_roundtrip() {
-        let auth = Auth {
-            secret: "topsecret".into(),
+        for auth in [Auth::shared_secret("topsecret"), Auth::token("ci", "s")] {
+            let env = Envelope::new(MessageType::Auth, auth.clone());
+            let encoded = encode(&env).expect("encode");
+            let decoded: Envelope<Auth> = decode(&encoded).expect("decode");
+            assert_eq!(decoded.ty, MessageType::Auth);
+            assert_eq!(decoded.data, auth);
+        }
+    }
+
+    #[test]
+    fn delete_roundtrip() {
+        let del = Delete {
+            range: Range { from: 3, to: 7 },
+            seq: 43,
+            base_doc_v: None,
         };
-        let env = Envelope::new(MessageType::Auth, auth.clone());
-        let encoded = encode(&env).expect("encode");
-        let decoded: Envelope<Auth> = decode(&encoded).expect("decode");
-        assert_eq!(decoded.ty, MessageType::Auth);
-        assert_eq!(decoded.data, auth);
+        let env = Envelope::new(MessageType::Delete, del.clone());
+        let decoded: Envelope<Delete> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Delete);
+        assert_eq!(decoded.data, del);
+    }
+
+    #[test]
+    fn batch_roundtrip() {
+        let first = encode(&Envelope::new(MessageType::Copy, Copy { text: "a".into() })).unwrap();
+        let second = encode(&Envelope::new(
+            MessageType::Scroll,
+            Scroll {
+                delta: 1,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        let batch = encode_batch(vec![first.clone(), second.clone()]).unwrap();
+        assert_eq!(peek_type(&batch).unwrap(), MessageType::Batch);
+        assert_eq!(unbatch(&batch).unwrap(), vec![first.clone(), second]);
+        assert_eq!(unbatch(&first).unwrap(), vec![first.clone()]);
+        assert_eq!(peek_type(&first).unwrap(), MessageType::Copy);
+        assert!(unbatch(b"junk").is_err());
     }
 
     #[test]
@@ -214,6 +1674,7 @@ mod tests {
             kind: "editor".into(),
             doc_v: 1,
             first_line: 0,
+            first_col: 0,
             cols: 80,
             rows: 2,
             lines: vec![Line {
@@ -221,12 +1682,18 @@ mod tests {
                 spans: vec![StyleSpan {
                     start_col: 0,
                     end_col: 5,
-                    class_name: "sel".into(),
+                    class: 0,
+                    user_id: None,
                 }],
+                more: false,
+                marked: false,
             }],
-            cursors: vec![Cursor { line: 0, col: 5 }],
+            cursors: vec![Cursor::new(0, 5)],
             status_left: "L".into(),
             status_right: "R".into(),
+            status: None,
+            classes: vec!["sel".into()],
+            checksum: None,
         };
         let env = Envelope::new(MessageType::Frame, frame.clone());
         let encoded = encode(&env).expect("encode");
@@ -235,6 +1702,186 @@ mod tests {
         assert_eq!(decoded.data, frame);
     }
 
+    fn sample_frame(lines: &[&str]) -> Frame {
+        Frame {
+            id: "editor".into(),
+            kind: "editor".into(),
+            doc_v: 1,
+            first_line: 0,
+            first_col: 0,
+            cols: 80,
+            rows: 4,
+            lines: lines
+                .iter()
+                .map(|t| Line {
+                    text: (*t).into(),
+                    spans: Vec::new(),
+                    more: false,
+                    marked: false,
+                })
+                .collect(),
+            cursors: vec![Cursor::new(0, 0)],
+            status_left: "L".into(),
+            status_right: "R".into(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn frame_diff_roundtrip() {
+        let old = sample_frame(&["a", "b", "c"]);
+        let mut new = sample_frame(&["a", "B", "c", "d"]);
+        new.doc_v = 2;
+        new.cursors = vec![Cursor::new(3, 1)];
+        let diff = FrameDiff::between(&old, &new).expect("diff");
+        assert_eq!(
+            diff.changes,
+            vec![
+                LineChange {
+                    start: 1,
+                    lines: vec![new.lines[1].clone()],
+                },
+                LineChange {
+                    start: 3,
+                    lines: vec![new.lines[3].clone()],
+                },
+            ]
+        );
+        assert_eq!(diff.status_left, None);
+        let env = Envelope::new(MessageType::FrameDiff, diff.clone());
+        let encoded = encode(&env).expect("encode");
+        let decoded: Envelope<FrameDiff> = decode(&encoded).expect("decode");
+        assert_eq!(decoded.ty, MessageType::FrameDiff);
+        assert_eq!(decoded.data, diff);
+        assert_eq!(diff.apply(&old), Some(new));
+    }
+
+    #[test]
+    fn diffs_check_the_resulting_lines() {
+        let old = sample_frame(&["a", "b"]);
+        let mut new = sample_frame(&["a", "c"]);
+        new.checksum = Some(new.content_checksum());
+        let diff = FrameDiff::between(&old, &new).expect("diff");
+        assert_eq!(diff.apply(&old), Some(new.clone()));
+
+        // A base with the right version but other lines, e.g. after a lost
+        // frame, fails the checksum.
+        let stale = sample_frame(&["x", "b"]);
+        assert_eq!(diff.apply(&stale), None);
+        assert_ne!(
+            sample_frame(&["ab"]).content_checksum(),
+            old.content_checksum()
+        );
+    }
+
+    #[test]
+    fn interns_classes() {
+        let mut frame = sample_frame(&["a"]);
+        assert_eq!(frame.intern_class("sel"), 0);
+        assert_eq!(frame.intern_class("ws"), 1);
+        assert_eq!(frame.intern_class("sel"), 0);
+        let span = StyleSpan {
+            start_col: 0,
+            end_col: 1,
+            class: 1,
+            user_id: None,
+        };
+        assert_eq!(frame.class_name(&span), Some("ws"));
+        assert_eq!(frame.class_name(&StyleSpan { class: 9, ..span }), None);
+    }
+
+    #[test]
+    fn frame_diff_extends_class_table() {
+        let mut old = sample_frame(&["a", "b"]);
+        let sel = old.intern_class("sel");
+        old.lines[0].spans.push(StyleSpan {
+            start_col: 0,
+            end_col: 1,
+            class: sel,
+            user_id: None,
+        });
+        let mut new = old.clone();
+        let ws = new.intern_class("ws");
+        new.lines[1].spans.push(StyleSpan {
+            start_col: 0,
+            end_col: 1,
+            class: ws,
+            user_id: None,
+        });
+        let diff = FrameDiff::between(&old, &new).unwrap();
+        assert_eq!(diff.classes, Some(vec!["sel".into(), "ws".into()]));
+        assert_eq!(diff.apply(&old), Some(new.clone()));
+
+        // A reordered table would change the meaning of unchanged lines.
+        let mut reordered = new.clone();
+        reordered.classes.swap(0, 1);
+        assert_eq!(FrameDiff::between(&new, &reordered), None);
+    }
+
+    #[test]
+    fn frame_diff_carries_status_changes() {
+        let status = Status {
+            path: "a.txt".into(),
+            dirty: false,
+            lock: LockState::Writable,
+            connection: ConnectionState::Connected,
+            doc_v: 1,
+            line: 0,
+            col: 0,
+            encoding: "UTF-8".into(),
+            eol: "LF".into(),
+            search: Some(SearchStatus {
+                current: 1,
+                total: 2,
+                preview: Some("b".into()),
+                searching: false,
+            }),
+            selection: Some(Range { from: 0, to: 0 }),
+        };
+        let mut old = sample_frame(&["a"]);
+        old.status = Some(status.clone());
+        let mut new = old.clone();
+        new.status = Some(Status {
+            dirty: true,
+            ..status
+        });
+        let diff = FrameDiff::between(&old, &new).unwrap();
+        assert_eq!(diff.status, new.status);
+        let env = Envelope::new(MessageType::FrameDiff, diff.clone());
+        let decoded: Envelope<FrameDiff> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.data.apply(&old), Some(new.clone()));
+
+        let mut cleared = new.clone();
+        cleared.status = None;
+        assert_eq!(FrameDiff::between(&new, &cleared), None);
+    }
+
+    #[test]
+    fn frame_diff_truncates_lines() {
+        let old = sample_frame(&["a", "b", "c"]);
+        let mut new = sample_frame(&["a"]);
+        new.status_right = "R2".into();
+        let diff = FrameDiff::between(&old, &new).unwrap();
+        assert!(diff.changes.is_empty());
+        assert!(!diff.is_empty());
+        assert_eq!(diff.apply(&old), Some(new));
+    }
+
+    #[test]
+    fn frame_diff_requires_matching_base() {
+        let old = sample_frame(&["a"]);
+        let mut new = sample_frame(&["b"]);
+        new.doc_v = 2;
+        let diff = FrameDiff::between(&old, &new).unwrap();
+        assert_eq!(diff.apply(&new), None);
+
+        let mut scrolled = new.clone();
+        scrolled.first_line = 1;
+        assert_eq!(FrameDiff::between(&old, &scrolled), None);
+    }
+
     #[test]
     fn resize_roundtrip() {
         let resize = Resize { cols: 80, rows: 24 };
@@ -245,6 +1892,123 @@ mod tests {
         assert_eq!(decoded.data, resize);
     }
 
+    #[test]
+    fn move_roundtrip() {
+        let mv = Move {
+            dir: Direction::Left,
+            granularity: Granularity::Word,
+            extend: true,
+            block: false,
+        };
+        let env = Envelope::new(MessageType::Move, mv.clone());
+        let decoded: Envelope<Move> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Move);
+        assert_eq!(decoded.data, mv);
+    }
+
+    #[test]
+    fn select_roundtrip() {
+        let sel = Select {
+            anchor: 7,
+            head: 2,
+            mode: SelectMode::Extend,
+        };
+        let env = Envelope::new(MessageType::Select, sel.clone());
+        let decoded: Envelope<Select> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Select);
+        assert_eq!(decoded.data, sel);
+    }
+
+    #[test]
+    fn add_cursor_roundtrip() {
+        let add = AddCursor {
+            target: CursorTarget::NextMatch,
+        };
+        let env = Envelope::new(MessageType::AddCursor, add.clone());
+        let decoded: Envelope<AddCursor> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::AddCursor);
+        assert_eq!(decoded.data, add);
+    }
+
+    #[test]
+    fn scroll_roundtrip() {
+        let scroll = Scroll {
+            delta: -3,
+            unit: ScrollUnit::Page,
+            dx: 4,
+        };
+        let env = Envelope::new(MessageType::Scroll, scroll.clone());
+        let decoded: Envelope<Scroll> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Scroll);
+        assert_eq!(decoded.data, scroll);
+    }
+
+    #[test]
+    fn replace_roundtrip() {
+        let replace = Replace {
+            query: "a(b)".into(),
+            replacement: "$1".into(),
+            regex: true,
+            all: true,
+            rest: true,
+            whole_word: true,
+            in_selection: true,
+        };
+        let env = Envelope::new(MessageType::Replace, replace.clone());
+        let decoded: Envelope<Replace> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Replace);
+        assert_eq!(decoded.data, replace);
+    }
+
+    #[test]
+    fn goto_line_roundtrip() {
+        let goto = GotoLine {
+            line: 42,
+            col: 3,
+            extend: true,
+            block: true,
+        };
+        let env = Envelope::new(MessageType::GotoLine, goto.clone());
+        let decoded: Envelope<GotoLine> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::GotoLine);
+        assert_eq!(decoded.data, goto);
+    }
+
+    #[test]
+    fn jump_roundtrip() {
+        let jump = Jump {
+            dir: JumpDir::Forward,
+        };
+        let env = Envelope::new(MessageType::Jump, jump.clone());
+        let decoded: Envelope<Jump> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Jump);
+        assert_eq!(decoded.data, jump);
+    }
+
+    #[test]
+    fn tab_roundtrip() {
+        let tab = Tab { back: true };
+        let env = Envelope::new(MessageType::Tab, tab.clone());
+        let decoded: Envelope<Tab> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Tab);
+        assert_eq!(decoded.data, tab);
+    }
+
+    #[test]
+    fn bookmark_list_roundtrip() {
+        let list = BookmarkList {
+            marks: vec![Mark {
+                path: "src/main.rs".into(),
+                line: 41,
+                text: "fn main() {".into(),
+            }],
+        };
+        let env = Envelope::new(MessageType::BookmarkList, list.clone());
+        let decoded: Envelope<BookmarkList> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::BookmarkList);
+        assert_eq!(decoded.data, list);
+    }
+
     #[test]
     fn request_frame_roundtrip() {
         let req = RequestFrame {
@@ -258,15 +2022,123 @@ mod tests {
     }
 
     #[test]
-    fn error_roundtrip() {
-        let err = ErrorMsg {
-            code: ErrorCode::Busy,
-            msg: "busy".into(),
+    fn picker_action_roundtrip() {
+        let actions = vec![
+            PickerAction::Open {
+                path: "src/main.rs".into(),
+            },
+            PickerAction::Create {
+                path: "notes".into(),
+                dir: true,
+            },
+            PickerAction::Rename {
+                from: "a".into(),
+                to: "b".into(),
+            },
+            PickerAction::Delete { path: "b".into() },
+            PickerAction::Expand { path: "".into() },
+            PickerAction::Search {
+                query: "mn".into(),
+                limit: 10,
+            },
+        ];
+        for action in actions {
+            let env = Envelope::new(MessageType::PickerAction, action.clone());
+            let decoded: Envelope<PickerAction> = decode(&encode(&env).unwrap()).unwrap();
+            assert_eq!(decoded.data, action);
+        }
+
+        let list = DirList {
+            path: "src".into(),
+            query: None,
+            entries: vec![DirEntry {
+                path: "src/main.rs".into(),
+                is_dir: false,
+            }],
         };
+        let env = Envelope::new(MessageType::DirList, list.clone());
+        let decoded: Envelope<DirList> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::DirList);
+        assert_eq!(decoded.data, list);
+    }
+
+    #[test]
+    fn dialog_roundtrip() {
+        let dialog = Dialog {
+            id: "save-on-exit".into(),
+            title: "Unsaved changes".into(),
+            body: "Save before closing?".into(),
+            buttons: vec![
+                DialogButton {
+                    id: "save".into(),
+                    label: "Save".into(),
+                },
+                DialogButton {
+                    id: "discard".into(),
+                    label: "Discard".into(),
+                },
+            ],
+            input: None,
+        };
+        let env = Envelope::new(MessageType::Dialog, dialog.clone());
+        let decoded: Envelope<Dialog> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Dialog);
+        assert_eq!(decoded.data, dialog);
+
+        let result = DialogResult {
+            id: "save-on-exit".into(),
+            button: Some("save".into()),
+            input: None,
+        };
+        let env = Envelope::new(MessageType::DialogResult, result.clone());
+        let decoded: Envelope<DialogResult> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.ty, MessageType::DialogResult);
+        assert_eq!(decoded.data, result);
+    }
+
+    #[test]
+    fn error_roundtrip() {
+        let err = ErrorMsg::new(ErrorCode::Busy, "busy");
         let env = Envelope::new(MessageType::Error, err.clone());
         let encoded = encode(&env).expect("encode");
         let decoded: Envelope<ErrorMsg> = decode(&encoded).expect("decode");
         assert_eq!(decoded.ty, MessageType::Error);
         assert_eq!(decoded.data, err);
     }
+
+    #[test]
+    fn error_details_roundtrip() {
+        let err = ErrorMsg::new(ErrorCode::Conflict, "stale edit")
+            .with_retry_after(std::time::Duration::from_millis(250))
+            .with_path("src/main.rs")
+            .with_doc_v(7)
+            .with_seq(3);
+        let env = Envelope::new(MessageType::Error, err.clone());
+        let decoded: Envelope<ErrorMsg> = decode(&encode(&env).unwrap()).unwrap();
+        assert_eq!(decoded.data, err);
+        assert_eq!(
+            decoded.data.retry_after(),
+            Some(std::time::Duration::from_millis(250))
+        );
+        assert!(decoded.data.code.is_transient());
+        assert!(!ErrorCode::NotFound.is_transient());
+    }
+
+    #[test]
+    fn decodes_error_without_details() {
+        #[derive(Serialize)]
+        struct Legacy {
+            code: ErrorCode,
+            msg: String,
+        }
+        let legacy = Envelope::new(
+            MessageType::Error,
+            Legacy {
+                code: ErrorCode::Busy,
+                msg: "busy".into(),
+            },
+        );
+        let decoded: Envelope<ErrorMsg> = decode(&encode(&legacy).unwrap()).unwrap();
+        assert_eq!(decoded.data, ErrorMsg::new(ErrorCode::Busy, "busy"));
+    }
 }
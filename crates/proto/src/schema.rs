@@ -0,0 +1,135 @@
+//! Machine-readable description of the wire protocol for third-party
+//! clients.
+
+use schemars::{SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+
+use crate::*;
+
+/// How payloads are laid out on the wire; JSON Schema alone cannot express
+/// that structs travel as positional arrays.
+const ENCODING: &str = "MessagePack. Structs, including the envelope, are encoded as arrays \
+    in the order their properties are listed; trailing fields marked optional may be absent \
+    in messages from older peers. Unit enum variants are encoded as strings, other variants \
+    as single-key maps.";
+
+/// Schema of the payload of `ty`, or `None` for messages that carry no
+/// payload yet.
+fn payload_schema(ty: &MessageType, generator: &mut SchemaGenerator) -> Option<Value> {
+    with_payload!(ty, T => Some(generator.subschema_for::<T>().to_value()), none => None)
+}
+
+/// All message types, in declaration order.
+const MESSAGE_TYPES: &[MessageType] = &[
+    MessageType::Hello,
+    MessageType::HelloAck,
+    MessageType::Auth,
+    MessageType::Open,
+    MessageType::Insert,
+    MessageType::Delete,
+    MessageType::Move,
+    MessageType::Select,
+    MessageType::AddCursor,
+    MessageType::Copy,
+    MessageType::Scroll,
+    MessageType::Resize,
+    MessageType::Search,
+    MessageType::Replace,
+    MessageType::GotoLine,
+    MessageType::Jump,
+    MessageType::Bookmark,
+    MessageType::BookmarkList,
+    MessageType::Tab,
+    MessageType::DuplicateLine,
+    MessageType::DeleteLine,
+    MessageType::Undo,
+    MessageType::Redo,
+    MessageType::Save,
+    MessageType::RequestFrame,
+    MessageType::PickerAction,
+    MessageType::DirList,
+    MessageType::ListBuffers,
+    MessageType::BufferList,
+    MessageType::ListSessions,
+    MessageType::SessionList,
+    MessageType::Attach,
+    MessageType::Watch,
+    MessageType::Unwatch,
+    MessageType::WatchEvent,
+    MessageType::SearchFiles,
+    MessageType::SearchResults,
+    MessageType::CancelSearch,
+    MessageType::Ack,
+    MessageType::Frame,
+    MessageType::FrameDiff,
+    MessageType::Dirty,
+    MessageType::ExternalChange,
+    MessageType::Reload,
+    MessageType::Status,
+    MessageType::Dialog,
+    MessageType::DialogResult,
+    MessageType::Error,
+    MessageType::Queued,
+    MessageType::Ping,
+    MessageType::Pong,
+    MessageType::Batch,
+];
+
+/// Build a JSON Schema (draft 2020-12) document describing the envelope
+/// and the payload of every message type. Shared types live under `$defs`.
+pub fn protocol_schema() -> Value {
+    let mut generator = SchemaGenerator::new(SchemaSettings::draft2020_12());
+    let envelope = generator
+        .subschema_for::<Envelope<serde_json::Value>>()
+        .to_value();
+    let mut messages = Map::new();
+    for ty in MESSAGE_TYPES {
+        let name = serde_json::to_value(ty)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_owned))
+            .expect("message type names are strings");
+        messages.insert(
+            name,
+            payload_schema(ty, &mut generator).unwrap_or(Value::Null),
+        );
+    }
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "ghostwriter protocol",
+        "protocol_version": PROTOCOL_VERSION,
+        "supported_versions": SUPPORTED_VERSIONS,
+        "features": FEATURES,
+        "encoding": ENCODING,
+        "envelope": envelope,
+        "messages": messages,
+        "$defs": generator.take_definitions(true),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_every_message_type() {
+        let schema = protocol_schema();
+        let messages = schema["messages"].as_object().unwrap();
+        assert_eq!(messages.len(), MESSAGE_TYPES.len());
+        assert_eq!(messages["Ping"], Value::Null);
+        assert_eq!(messages["Frame"]["$ref"], "#/$defs/Frame");
+
+        let frame = &schema["$defs"]["Frame"]["properties"];
+        let fields: Vec<&str> = frame
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(&fields[..3], ["id", "kind", "doc_v"]);
+        assert!(schema["$defs"]["StyleSpan"].is_object());
+        assert_eq!(
+            schema["$defs"]["Envelope"]["properties"]["type"]["$ref"],
+            "#/$defs/MessageType"
+        );
+    }
+}
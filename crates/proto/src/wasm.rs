@@ -0,0 +1,28 @@
+//! `wasm-bindgen` exports so browser clients can share the envelope
+//! format. Messages cross the JS boundary as JSON strings.
+//!
+//! The crate stays a plain rlib; build the module with
+//! `cargo rustc -p ghostwriter-proto --target wasm32-unknown-unknown
+//! --features wasm --crate-type cdylib`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::json::{decode_json, encode_json};
+
+/// Encode a JSON envelope into a msgpack message.
+#[wasm_bindgen(js_name = encodeEnvelope)]
+pub fn encode_envelope(json: &str) -> Result<Vec<u8>, JsError> {
+    encode_json(json).map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// Decode a msgpack message into a JSON envelope.
+#[wasm_bindgen(js_name = decodeEnvelope)]
+pub fn decode_envelope(bytes: &[u8]) -> Result<String, JsError> {
+    decode_json(bytes).map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// Protocol version to put in outgoing envelopes.
+#[wasm_bindgen(js_name = protocolVersion)]
+pub fn protocol_version() -> u16 {
+    crate::PROTOCOL_VERSION
+}
@@ -1,199 +1,1510 @@
 use std::{
     collections::{HashMap, VecDeque},
+    future::Future,
+    net::SocketAddr,
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
-use futures_util::{SinkExt, StreamExt};
-use ghostwriter_proto::{Auth, Envelope, ErrorCode, ErrorMsg, Hello, MessageType, decode, encode};
+use futures_util::{SinkExt, StreamExt, future::select_all};
+use ghostwriter_core::{
+    DisconnectReason, Priority, Transport,
+    tls::{self, TlsAcceptor},
+    transport::{COMPRESSION_THRESHOLD, compress_payload, decompress_payload},
+};
+use ghostwriter_proto::{
+    AddCursor, Attach, Auth, Bookmark, Delete, DirList, Envelope, ErrorCode, ErrorMsg, Frame,
+    GotoLine, Hello, Insert, Jump, MessageType, Move, Open, PickerAction, Queued, Replace,
+    RequestFrame, Resize, Role, Scroll, Search, SearchRequest, SearchResultChunk, Select,
+    SessionList, Tab, Unwatch, WatchEvent, WatchRequest, caps, decode, encode, negotiate,
+    peek_type, unbatch,
+};
+use serde::{Serialize, de::DeserializeOwned};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc, watch};
+use tokio::task::JoinSet;
 use tokio_tungstenite::{WebSocketStream, accept_async, tungstenite::Message};
+use tracing::Instrument;
+
+use crate::access::AccessList;
+use crate::announce;
+use crate::audit::{AuditEvent, AuditLog, FileAudit, Peer};
+use crate::auth::TokenStore;
+use crate::dirwatch::{DirWatcher, Subscription};
+use crate::history::UndoHistory;
+use crate::index::SearchIndex;
+use crate::metrics::Metrics;
+use crate::quota::WriteLimits;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::registry::{Detached, Followed, SessionId, SessionRegistry};
+use crate::search;
+use crate::session::{
+    self, FrameDiffer, FrameUpdate, SessionCmd, SessionEvent, autosave_on_disconnect, parent_of,
+    picker_error,
+};
+use crate::snippets::Snippets;
+use crate::workspace::Workspace;
+
+/// Interval between heartbeat pings once a client is authenticated.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long shutdown waits for clients to save and disconnect before
+/// dropping them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a client gets to finish the TLS and WebSocket handshakes before
+/// it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a session whose client dropped off waits to be attached again.
+const DETACHED_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Connection attempts per address unless configured otherwise.
+pub const DEFAULT_CONNECT_LIMIT: RateLimit = RateLimit::new(3, Duration::from_secs(60));
+
+/// Failed logins per address unless configured otherwise.
+pub const DEFAULT_AUTH_LIMIT: RateLimit = RateLimit::new(5, Duration::from_secs(5 * 60));
+
+/// Tunables for the `run_*_until` acceptors.
+#[derive(Debug, Clone)]
+pub struct AcceptorConfig {
+    /// Clients served at once.
+    pub max_clients: usize,
+    /// Connections held while all client slots are taken; any more are
+    /// turned away with `Busy`.
+    pub wait_queue: usize,
+    /// Interval between `Queued` messages telling a waiting connection its
+    /// position.
+    pub queue_notice_interval: Duration,
+    /// Gitignore-style globs left out of workspace searches, on top of
+    /// what `.gitignore` and `.ignore` files exclude.
+    pub search_exclude: Vec<String>,
+    /// Keep an in-memory index of the workspace so literal searches only
+    /// read files that can match.
+    pub search_index: bool,
+    /// Where connections and failed logins are recorded.
+    pub audit: AuditLog,
+    /// Where clients' file reads, writes, listings, locks and changes are
+    /// recorded.
+    pub file_audit: FileAudit,
+    /// Named tokens clients may authenticate with, besides the shared
+    /// secret.
+    pub tokens: Option<TokenStore>,
+    /// Addresses allowed to connect over TCP, TLS and QUIC. Unix sockets
+    /// are not filtered.
+    pub access: AccessList,
+    /// Connection attempts allowed per address. Clone one limiter into
+    /// several configs to share it between listeners.
+    pub connect_limit: RateLimiter,
+    /// Failed logins allowed per address; once used up, logins are turned
+    /// away unchecked until the limit refills.
+    pub auth_limit: RateLimiter,
+    /// Counters and histograms for [`metrics::serve`](crate::metrics::serve).
+    pub metrics: Metrics,
+    /// File size, per-connection and workspace limits on saves.
+    pub write_limits: WriteLimits,
+    /// Serve the workspace for viewing only; see
+    /// [`Workspace::with_readonly`].
+    pub readonly: bool,
+    /// Where sessions keep undo history between them; off by default.
+    pub undo_history: UndoHistory,
+    /// Snippets sessions expand on Tab; none by default.
+    pub snippets: Arc<Snippets>,
+    /// New settings to switch to while running, e.g. after the config file
+    /// changed. Each value sent replaces the current ones.
+    pub reload: Option<watch::Receiver<Reload>>,
+    /// Announce TCP and TLS listeners over mDNS so `--connect auto` finds
+    /// them. Needs the `mdns` feature; without it a warning is logged.
+    pub advertise: bool,
+}
+
+/// Settings a running acceptor can change without dropping its clients.
+#[derive(Debug, Clone)]
+pub struct Reload {
+    pub tokens: Option<TokenStore>,
+    pub access: AccessList,
+    pub connect_limit: RateLimit,
+    pub auth_limit: RateLimit,
+    /// Also applied to the next saves of open sessions.
+    pub write_limits: WriteLimits,
+}
+
+impl Default for AcceptorConfig {
+    /// One client at a time and no wait queue.
+    fn default() -> Self {
+        Self {
+            max_clients: 1,
+            wait_queue: 0,
+            queue_notice_interval: Duration::from_secs(5),
+            search_exclude: Vec::new(),
+            search_index: false,
+            audit: AuditLog::default(),
+            file_audit: FileAudit::default(),
+            tokens: None,
+            access: AccessList::default(),
+            connect_limit: RateLimiter::new(DEFAULT_CONNECT_LIMIT),
+            auth_limit: RateLimiter::new(DEFAULT_AUTH_LIMIT),
+            metrics: Metrics::default(),
+            write_limits: WriteLimits::default(),
+            readonly: false,
+            undo_history: UndoHistory::default(),
+            snippets: Arc::default(),
+            reload: None,
+            advertise: false,
+        }
+    }
+}
 
 async fn handle_busy<S>(mut ws: WebSocketStream<S>)
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let env = Envelope::new(
-        MessageType::Error,
-        ErrorMsg {
-            code: ErrorCode::Busy,
-            msg: "busy".into(),
-        },
-    );
+    let env = Envelope::new(MessageType::Error, ErrorMsg::new(ErrorCode::Busy, "busy"));
     if let Ok(data) = encode(&env) {
         let _ = ws.send(Message::Binary(data.into())).await;
     }
     let _ = ws.close(None).await;
 }
 
-async fn handle_rate_limited<S>(mut ws: WebSocketStream<S>, retry_after: Duration)
+async fn handle_rate_limited<S>(ws: WebSocketStream<S>, retry_after: Duration)
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let env = Envelope::new(
-        MessageType::Error,
-        ErrorMsg {
-            code: ErrorCode::RateLimit,
-            msg: format!("rate limited; retry in {}s", retry_after.as_secs()),
-        },
-    );
-    if let Ok(data) = encode(&env) {
+    refuse(ws, rate_limited(retry_after), None).await;
+}
+
+fn rate_limited(retry_after: Duration) -> ErrorMsg {
+    ErrorMsg::new(
+        ErrorCode::RateLimit,
+        format!("rate limited; retry in {}s", retry_after.as_secs()),
+    )
+    .with_retry_after(retry_after)
+}
+
+/// Send `err` and close, deflating with `compression` as agreed in the
+/// Hello exchange.
+async fn refuse<S>(mut ws: WebSocketStream<S>, err: ErrorMsg, compression: Option<usize>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let data = encode(&Envelope::new(MessageType::Error, err)).ok();
+    let data = data.and_then(|data| match compression {
+        Some(threshold) => compress_payload(&data, threshold).ok(),
+        None => Some(data),
+    });
+    if let Some(data) = data {
         let _ = ws.send(Message::Binary(data.into())).await;
     }
     let _ = ws.close(None).await;
 }
 
-struct RateLimiter {
-    limit: usize,
-    window: Duration,
-    peers: HashMap<String, VecDeque<Instant>>,
+/// State every connection of a listener shares.
+#[derive(Clone)]
+struct Shared {
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    sessions: SessionRegistry,
+    watcher: DirWatcher,
+    search_exclude: Arc<[String]>,
+    index: Option<SearchIndex>,
+    audit: AuditLog,
+    tokens: Option<TokenStore>,
+    auth_limit: RateLimiter,
+    metrics: Metrics,
 }
 
-impl RateLimiter {
-    fn new(limit: usize, window: Duration) -> Self {
-        Self {
-            limit,
-            window,
-            peers: HashMap::new(),
-        }
-    }
-
-    fn check(&mut self, peer: String) -> Option<Duration> {
-        let now = Instant::now();
-        let entry = self.peers.entry(peer).or_default();
-        while let Some(&t) = entry.front() {
-            if now.duration_since(t) > self.window {
-                entry.pop_front();
-            } else {
-                break;
-            }
-        }
-        if entry.len() >= self.limit {
-            let retry = self.window - now.duration_since(*entry.front().unwrap());
-            Some(retry)
-        } else {
-            entry.push_back(now);
-            None
-        }
+/// Rate limiting key of `peer`: its IP, without the port.
+fn limit_key(peer: &Peer) -> String {
+    match peer.addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.addr.clone(),
     }
 }
 
+/// Directory change events a connection can have queued before more are
+/// dropped.
+const WATCH_BACKLOG: usize = 32;
+
 async fn handle_connection<S>(
     mut ws: WebSocketStream<S>,
-    active: Arc<AtomicBool>,
-    secret_hash: Option<String>,
+    _slot: OwnedSemaphorePermit,
+    shared: Shared,
+    mut peer: Peer,
+    shutdown: watch::Receiver<bool>,
 ) where
-    S: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    // Expect Hello first
-    if let Some(Ok(Message::Binary(data))) = ws.next().await {
-        let _env: Envelope<Hello> = match decode(&data) {
+    let open = shared.secret_hash.is_none() && shared.tokens.is_none();
+    // Whether an `Auth` message must follow the handshake.
+    let reads_auth = !open || shared.workspace.has_root_secrets();
+
+    // Expect Hello first. Once `deflate` is agreed, every later message
+    // in either direction carries a compression flag.
+    let (size, diffs, role, compression) = if let Some(Ok(Message::Binary(data))) = ws.next().await
+    {
+        let env: Envelope<Hello> = match decode(&data) {
             Ok(env) => env,
             Err(_) => {
                 let _ = ws.close(None).await;
-                active.store(false, Ordering::SeqCst);
                 return;
             }
         };
+        let mut ack = match negotiate(&env) {
+            Ok(ack) => ack,
+            Err(err) => {
+                if let Ok(data) = encode(&Envelope::new(MessageType::Error, err)) {
+                    let _ = ws.send(Message::Binary(data.into())).await;
+                }
+                let _ = ws.close(None).await;
+                return;
+            }
+        };
+        tracing::Span::current().record("client", env.data.client_name.as_str());
+        ack.auth = reads_auth;
+        let diffs = ack.features.iter().any(|f| f == "frame_diff");
+        let compression = ack.has(caps::COMPRESSION).then_some(COMPRESSION_THRESHOLD);
+        if let Ok(data) = encode(&Envelope::new(MessageType::HelloAck, ack)) {
+            let _ = ws.send(Message::Binary(data.into())).await;
+        }
+        let size = (env.data.cols, env.data.rows);
+        (size, diffs, env.data.role, compression)
     } else {
         let _ = ws.close(None).await;
-        active.store(false, Ordering::SeqCst);
         return;
-    }
+    };
 
-    if let Some(hash) = secret_hash {
+    // Secret the client authenticated with; it may unlock workspace roots.
+    let mut secret = None;
+    // Whether the server's own secret or a token let the client in, which
+    // opens the roots without a secret of their own.
+    let mut server_admitted = !reads_auth;
+    if reads_auth {
         match ws.next().await {
             Some(Ok(Message::Binary(data))) => {
-                let env: Envelope<Auth> = match decode(&data) {
-                    Ok(env) => env,
-                    Err(_) => {
-                        let _ = ws.close(None).await;
-                        active.store(false, Ordering::SeqCst);
-                        return;
-                    }
+                let data = match compression {
+                    Some(_) => decompress_payload(&data).ok(),
+                    None => Some(data.to_vec()),
                 };
-                let parsed = PasswordHash::new(&hash).expect("valid hash");
-                let argon2 = Argon2::default();
-                if argon2
-                    .verify_password(env.data.secret.as_bytes(), &parsed)
-                    .is_err()
-                {
-                    let env = Envelope::new(
-                        MessageType::Error,
-                        ErrorMsg {
-                            code: ErrorCode::Unauthorized,
-                            msg: "unauthorized".into(),
-                        },
-                    );
-                    if let Ok(data) = encode(&env) {
-                        let _ = ws.send(Message::Binary(data.into())).await;
-                    }
+                let Some(env) = data.and_then(|data| decode::<Auth>(&data).ok()) else {
                     let _ = ws.close(None).await;
-                    active.store(false, Ordering::SeqCst);
                     return;
+                };
+                let key = limit_key(&peer);
+                if let Some(retry) = shared.auth_limit.retry_after(&key) {
+                    tracing::warn!("login refused, too many failures");
+                    shared.audit.record(AuditEvent::AuthFailed, &peer);
+                    shared.metrics.auth_failed();
+                    refuse(ws, rate_limited(retry), compression).await;
+                    return;
+                }
+                let auth = env.data;
+                let admitted = match (&auth.token_id, &shared.tokens, &shared.secret_hash) {
+                    (Some(id), Some(tokens), _) => tokens.verify(id, &auth.secret),
+                    (None, _, Some(hash)) => {
+                        let parsed = PasswordHash::new(hash).expect("valid hash");
+                        Argon2::default()
+                            .verify_password(auth.secret.as_bytes(), &parsed)
+                            .is_ok()
+                    }
+                    _ => false,
+                } || open;
+                let authorized = admitted || shared.workspace.unlocks(&auth.secret);
+                server_admitted = admitted;
+                // Name token holders in the audit log unless their
+                // certificate already does.
+                if authorized && let Some(id) = &auth.token_id {
+                    peer.identity.get_or_insert(format!("token:{id}"));
                 }
+                if !authorized {
+                    tracing::warn!(token = auth.token_id.as_deref(), "authentication failed");
+                    shared.audit.record(AuditEvent::AuthFailed, &peer);
+                    shared.metrics.auth_failed();
+                    let _ = shared.auth_limit.check(&key);
+                    let err = ErrorMsg::new(ErrorCode::Unauthorized, "unauthorized");
+                    refuse(ws, err, compression).await;
+                    return;
+                }
+                secret = Some(auth.secret);
             }
             _ => {
                 let _ = ws.close(None).await;
-                active.store(false, Ordering::SeqCst);
                 return;
             }
         }
     }
 
-    while let Some(msg) = ws.next().await {
-        if msg.is_err() {
-            break;
+    tracing::info!(identity = peer.identity.as_deref(), "connected");
+    shared.audit.record(AuditEvent::Connected, &peer);
+    let _connected = shared.metrics.connected();
+    let (watch_tx, watch_rx) = mpsc::channel(WATCH_BACKLOG);
+    let mut transport = Transport::new(ws, PING_INTERVAL);
+    transport.set_compression(compression);
+    let conn = Connection {
+        transport,
+        workspace: (shared.workspace.for_client(&peer))
+            .for_secret(secret.as_deref(), server_admitted),
+        sessions: shared.sessions,
+        watcher: shared.watcher,
+        watches: HashMap::new(),
+        watch_tx,
+        watch_rx,
+        search: None,
+        search_exclude: shared.search_exclude,
+        index: shared.index,
+        metrics: shared.metrics,
+        identity: peer.identity.clone(),
+        role,
+        following: None,
+        session: None,
+        size,
+        differ: diffs.then(FrameDiffer::default),
+        cmd: None,
+        frames: None,
+        events: None,
+        shutdown,
+    };
+    conn.run().await;
+    tracing::info!("disconnected");
+    shared.audit.record(AuditEvent::Disconnected, &peer);
+}
+
+/// Authenticated client and the editing session it opened, if any.
+struct Connection<S> {
+    transport: Transport<S>,
+    workspace: Workspace,
+    sessions: SessionRegistry,
+    watcher: DirWatcher,
+    /// Directories this client subscribed to, by the path it gave.
+    watches: HashMap<String, Subscription>,
+    watch_tx: mpsc::Sender<WatchEvent>,
+    watch_rx: mpsc::Receiver<WatchEvent>,
+    /// Results of the running `SearchFiles`.
+    search: Option<mpsc::Receiver<SearchResultChunk>>,
+    search_exclude: Arc<[String]>,
+    index: Option<SearchIndex>,
+    metrics: Metrics,
+    /// Identity the client authenticated as, owning the sessions it opens.
+    identity: Option<String>,
+    role: Role,
+    /// Session a follower is watching.
+    following: Option<Followed>,
+    /// Registry id of the session this client edits.
+    session: Option<SessionId>,
+    /// Viewport size for the session, from `Hello` and later `Resize`.
+    size: (u16, u16),
+    /// Present when the client agreed to `frame_diff`.
+    differ: Option<FrameDiffer>,
+    cmd: Option<mpsc::Sender<SessionCmd>>,
+    frames: Option<mpsc::Receiver<Frame>>,
+    events: Option<mpsc::Receiver<SessionEvent>>,
+    /// Set once the server starts shutting down.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Relay messages until the client goes away or the server shuts down.
+    /// Dropping the session handle afterwards makes the session save and
+    /// exit.
+    async fn run(mut self) {
+        let shutting_down = loop {
+            tokio::select! {
+                _ = shutdown_requested(&mut self.shutdown) => break true,
+                msg = self.transport.recv() => {
+                    let Some(data) = msg else { break false };
+                    self.metrics.received(data.len());
+                    let Ok(messages) = unbatch(&data) else {
+                        self.reply(MessageType::Error, malformed()).await;
+                        continue;
+                    };
+                    for msg in messages {
+                        if let Err(err) = self.dispatch(&msg).await {
+                            tracing::debug!(code = ?err.code, "request failed: {}", err.msg);
+                            self.reply(MessageType::Error, err).await;
+                        }
+                    }
+                }
+                Some(frame) = recv_opt(self.frames.as_mut()) => {
+                    let frame = self.newest_frame(frame);
+                    self.send_frame(frame).await
+                }
+                Some(event) = self.watch_rx.recv() => {
+                    self.reply(MessageType::WatchEvent, event).await
+                }
+                chunk = recv_opt(self.search.as_mut()) => match chunk {
+                    Some(chunk) => {
+                        if chunk.done {
+                            self.search = None;
+                        }
+                        self.reply(MessageType::SearchResults, chunk).await
+                    }
+                    None => self.search = None,
+                },
+                followed = recv_followed(self.following.as_mut()) => match followed {
+                    Ok(frame) => self.send_frame(frame).await,
+                    // The next frame is sent in full or diffed against the
+                    // last one this client got, so skipping is harmless.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        self.following = None;
+                        let err = ErrorMsg::new(ErrorCode::NotFound, "followed session ended");
+                        self.reply(MessageType::Error, err).await;
+                    }
+                },
+                Some(event) = recv_opt(self.events.as_mut()) => match event {
+                    SessionEvent::Ack(ack) => self.reply(MessageType::Ack, ack).await,
+                    SessionEvent::Buffers(list) => {
+                        self.reply(MessageType::BufferList, list).await
+                    }
+                    SessionEvent::Bookmarks(list) => {
+                        self.reply(MessageType::BookmarkList, list).await
+                    }
+                    SessionEvent::Copy(copy) => self.reply(MessageType::Copy, copy).await,
+                    SessionEvent::DirList(list) => self.reply(MessageType::DirList, list).await,
+                    SessionEvent::Error(err) => self.reply(MessageType::Error, err).await,
+                    SessionEvent::ExternalChange(change) => {
+                        self.reply(MessageType::ExternalChange, change).await
+                    }
+                },
+            }
+        };
+        if shutting_down {
+            self.shut_down().await;
+        } else {
+            self.detach().await;
+        }
+    }
+
+    /// Keep the session running for a later `Attach` if the link dropped
+    /// rather than the client closing it. Otherwise the session saves and
+    /// ends along with the connection.
+    async fn detach(mut self) {
+        if self.transport.disconnected().await == DisconnectReason::Closed {
+            return;
+        }
+        if let (Some(id), Some(cmd), Some(frames), Some(events)) = (
+            self.session,
+            self.cmd.take(),
+            self.frames.take(),
+            self.events.take(),
+        ) {
+            let detached = Detached {
+                cmd,
+                frames,
+                events,
+            };
+            self.sessions.detach(id, detached, DETACHED_TTL);
+        }
+    }
+
+    /// Tell the client the server is going away, let the session save, then
+    /// flush and close the transport.
+    async fn shut_down(mut self) {
+        self.reply(
+            MessageType::Error,
+            ErrorMsg::new(ErrorCode::ShuttingDown, "server is shutting down"),
+        )
+        .await;
+        self.cmd = None;
+        // The session drops its frame sender once it has saved and exited.
+        if let Some(mut frames) = self.frames.take() {
+            while frames.recv().await.is_some() {}
+        }
+        self.transport.close().await;
+    }
+
+    /// Turn one client message into a session command.
+    async fn dispatch(&mut self, msg: &[u8]) -> Result<(), ErrorMsg> {
+        let ty = peek_type(msg).map_err(|_| malformed())?;
+        match ty {
+            MessageType::Watch => {
+                let watch = payload::<WatchRequest>(msg)?;
+                // The watcher reaches every root; this client may not.
+                self.workspace
+                    .resolve(&watch.path)
+                    .map_err(picker_error(&watch.path))?;
+                let sub = self
+                    .watcher
+                    .subscribe(&watch.path, watch.recursive, self.watch_tx.clone())
+                    .map_err(picker_error(&watch.path))?;
+                self.watches.insert(watch.path, sub);
+                return Ok(());
+            }
+            MessageType::Unwatch => {
+                let path = payload::<Unwatch>(msg)?.path;
+                self.watches.remove(&path);
+                return Ok(());
+            }
+            MessageType::SearchFiles => {
+                let req = payload::<SearchRequest>(msg)?;
+                // Replacing the receiver cancels the previous search.
+                self.search = Some(search::spawn(
+                    self.workspace.clone(),
+                    &self.search_exclude,
+                    self.index.as_ref(),
+                    req,
+                )?);
+                return Ok(());
+            }
+            MessageType::CancelSearch => {
+                self.search = None;
+                return Ok(());
+            }
+            MessageType::Ping => {
+                // Queued behind pending frames, so the client measures the
+                // delay its edits see.
+                self.reply(MessageType::Pong, ()).await;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if self.role == Role::Follower {
+            return self.dispatch_follower(ty).await;
+        }
+        let cmd = match ty {
+            MessageType::ListSessions => {
+                let workspace = &self.workspace;
+                let reaches = |path: &str| workspace.resolve(path).is_ok();
+                let sessions = self.sessions.list(self.identity.as_deref(), reaches);
+                self.reply(MessageType::SessionList, SessionList { sessions })
+                    .await;
+                return Ok(());
+            }
+            MessageType::Attach => {
+                let id = payload::<Attach>(msg)?.id;
+                if self.cmd.is_some() {
+                    return Err(ErrorMsg::new(
+                        ErrorCode::Invalid,
+                        "a session is already open",
+                    ));
+                }
+                return self.attach(id).await;
+            }
+            MessageType::Open => {
+                let path = payload::<Open>(msg)?.path;
+                if self.cmd.is_none() {
+                    return self.open(&path).await;
+                }
+                SessionCmd::Open { path }
+            }
+            MessageType::Insert => {
+                let insert = payload::<Insert>(msg)?;
+                SessionCmd::Insert {
+                    text: insert.text,
+                    pos: Some(insert.pos as usize),
+                    seq: Some(insert.seq),
+                    base_doc_v: insert.base_doc_v,
+                }
+            }
+            MessageType::Delete => {
+                let delete = payload::<Delete>(msg)?;
+                SessionCmd::Delete {
+                    range: delete.range.from as usize..delete.range.to as usize,
+                    seq: Some(delete.seq),
+                    base_doc_v: delete.base_doc_v,
+                }
+            }
+            MessageType::Move => {
+                let mv = payload::<Move>(msg)?;
+                SessionCmd::Move {
+                    dir: mv.dir,
+                    granularity: mv.granularity,
+                    extend: mv.extend,
+                    block: mv.block,
+                }
+            }
+            MessageType::Select => {
+                let select = payload::<Select>(msg)?;
+                SessionCmd::Select {
+                    anchor: select.anchor as usize,
+                    head: select.head as usize,
+                    mode: select.mode,
+                }
+            }
+            MessageType::AddCursor => {
+                let AddCursor { target } = payload(msg)?;
+                SessionCmd::AddCursor { target }
+            }
+            MessageType::Scroll => {
+                let Scroll { delta, unit, dx } = payload(msg)?;
+                SessionCmd::Scroll { delta, unit, dx }
+            }
+            MessageType::Resize => {
+                let Resize { cols, rows } = payload(msg)?;
+                self.size = (cols, rows);
+                if self.cmd.is_none() {
+                    return Ok(());
+                }
+                SessionCmd::Resize { cols, rows }
+            }
+            MessageType::GotoLine => {
+                let goto = payload::<GotoLine>(msg)?;
+                SessionCmd::GotoLine {
+                    line: goto.line as usize,
+                    col: goto.col as usize,
+                    extend: goto.extend,
+                    block: goto.block,
+                }
+            }
+            MessageType::Jump => {
+                let Jump { dir } = payload(msg)?;
+                SessionCmd::Jump { dir }
+            }
+            MessageType::Bookmark => {
+                let Bookmark { action } = payload(msg)?;
+                SessionCmd::Bookmark { action }
+            }
+            MessageType::Tab => {
+                let Tab { back } = payload(msg)?;
+                SessionCmd::Tab { back }
+            }
+            MessageType::RequestFrame => {
+                payload::<RequestFrame>(msg)?;
+                // Nothing is drawn until a file is open.
+                if self.cmd.is_none() {
+                    return Ok(());
+                }
+                // The client may have lost track; start over with a full frame.
+                if let Some(differ) = &mut self.differ {
+                    differ.reset();
+                }
+                SessionCmd::RequestFrame
+            }
+            MessageType::DuplicateLine => SessionCmd::DuplicateLine,
+            MessageType::DeleteLine => SessionCmd::DeleteLine,
+            MessageType::Undo => SessionCmd::Undo,
+            MessageType::Redo => SessionCmd::Redo,
+            MessageType::Search => {
+                let Search {
+                    query,
+                    dir,
+                    regex,
+                    replacement,
+                    whole_word,
+                    in_selection,
+                    start_confirm,
+                } = payload(msg)?;
+                SessionCmd::Search {
+                    query,
+                    dir,
+                    regex,
+                    replacement,
+                    whole_word,
+                    in_selection,
+                    start_confirm,
+                }
+            }
+            MessageType::Replace => {
+                let Replace {
+                    query,
+                    replacement,
+                    regex,
+                    all,
+                    rest,
+                    whole_word,
+                    in_selection,
+                } = payload(msg)?;
+                SessionCmd::Replace {
+                    query,
+                    replacement,
+                    regex,
+                    all,
+                    rest,
+                    whole_word,
+                    in_selection,
+                }
+            }
+            MessageType::Save => SessionCmd::Save,
+            MessageType::Reload => SessionCmd::Reload,
+            MessageType::ListBuffers => SessionCmd::ListBuffers,
+            MessageType::Copy => SessionCmd::Copy,
+            MessageType::PickerAction if self.cmd.is_none() => {
+                return self.browse(payload(msg)?).await;
+            }
+            MessageType::PickerAction => SessionCmd::Picker {
+                action: payload(msg)?,
+            },
+            other => {
+                return Err(ErrorMsg::new(
+                    ErrorCode::Unsupported,
+                    format!("unexpected {other:?} message"),
+                ));
+            }
+        };
+        let Some(tx) = &self.cmd else {
+            return Err(ErrorMsg::new(ErrorCode::Invalid, "no file is open"));
+        };
+        if cmd.is_edit() {
+            self.metrics.edit_op();
+        }
+        tx.send(cmd)
+            .await
+            .map_err(|_| ErrorMsg::new(ErrorCode::Io, "session ended"))
+    }
+
+    /// Followers only ask for frames, which also attaches them to the
+    /// newest session they may read. The viewport is the editor's, so resizes are
+    /// ignored.
+    async fn dispatch_follower(&mut self, ty: MessageType) -> Result<(), ErrorMsg> {
+        match ty {
+            MessageType::RequestFrame => {
+                if self.following.is_none() {
+                    let workspace = &self.workspace;
+                    let reaches = |path: &str| workspace.resolve(path).is_ok();
+                    let followed = self.sessions.follow_latest(reaches);
+                    self.following = Some(followed.ok_or_else(|| {
+                        ErrorMsg::new(ErrorCode::NotFound, "no session to follow")
+                    })?);
+                }
+                let cmd = self.following.as_ref().and_then(|f| f.cmd.upgrade());
+                if let Some(cmd) = cmd {
+                    let _ = cmd.send(SessionCmd::RequestFrame).await;
+                }
+                Ok(())
+            }
+            MessageType::Resize => Ok(()),
+            _ => Err(ErrorMsg::new(ErrorCode::Readonly, "followers cannot edit")),
+        }
+    }
+
+    /// Start the session on `path`; it replies with the first frame.
+    async fn open(&mut self, path: &str) -> Result<(), ErrorMsg> {
+        let (cols, rows) = self.size;
+        let handle = session::open_in(self.workspace.clone(), path, cols, rows)
+            .map_err(picker_error(path))?;
+        autosave_on_disconnect(handle.cmd.clone(), self.transport.link_state());
+        self.session = Some(self.sessions.register(&handle, self.identity.clone()));
+        // `open_in` does not render on its own.
+        let _ = handle.cmd.send(SessionCmd::RequestFrame).await;
+        self.cmd = Some(handle.cmd);
+        self.frames = Some(handle.frames);
+        self.events = Some(handle.events);
+        Ok(())
+    }
+
+    /// Picker actions before a file is open: list, create, rename and
+    /// delete entries and open the chosen file, so clients can start out in
+    /// a picker. Changes are answered with the changed directory's listing.
+    async fn browse(&mut self, action: PickerAction) -> Result<(), ErrorMsg> {
+        let ws = &self.workspace;
+        let listed = match action {
+            PickerAction::Open { path } => return self.open(&path).await,
+            PickerAction::Expand { path } => path,
+            PickerAction::Create { path, dir } => {
+                ws.create(&path, dir).map_err(picker_error(&path))?;
+                parent_of(&path).to_string()
+            }
+            PickerAction::Rename { from, to } => {
+                ws.rename(&from, &to).map_err(picker_error(&from))?;
+                parent_of(&to).to_string()
+            }
+            PickerAction::Delete { path } => {
+                ws.delete(&path).map_err(picker_error(&path))?;
+                parent_of(&path).to_string()
+            }
+            _ => return Err(ErrorMsg::new(ErrorCode::Invalid, "no file is open")),
+        };
+        let entries = ws.list_dir(&listed).map_err(picker_error(&listed))?;
+        let list = DirList {
+            path: listed,
+            query: None,
+            entries,
+        };
+        self.reply(MessageType::DirList, list).await;
+        Ok(())
+    }
+
+    /// Take over detached session `id`, sized to this client's viewport;
+    /// the resize replies with a frame.
+    async fn attach(&mut self, id: SessionId) -> Result<(), ErrorMsg> {
+        let workspace = &self.workspace;
+        let reaches = |path: &str| workspace.resolve(path).is_ok();
+        let detached = (self.sessions).attach(id, self.identity.as_deref(), reaches)?;
+        autosave_on_disconnect(detached.cmd.clone(), self.transport.link_state());
+        let workspace = self.workspace.clone();
+        let _ = detached.cmd.send(SessionCmd::Attached { workspace }).await;
+        let (cols, rows) = self.size;
+        let _ = detached.cmd.send(SessionCmd::Resize { cols, rows }).await;
+        self.cmd = Some(detached.cmd);
+        self.frames = Some(detached.frames);
+        self.events = Some(detached.events);
+        self.session = Some(id);
+        Ok(())
+    }
+
+    /// `frame`, or the newest of the frames the session rendered after it.
+    /// Each shows the whole viewport, so the ones in between are skipped
+    /// rather than sent only to be drawn over, e.g. while scrolling fast.
+    fn newest_frame(&mut self, mut frame: Frame) -> Frame {
+        if let Some(frames) = &mut self.frames {
+            while let Ok(newer) = frames.try_recv() {
+                frame = newer;
+            }
+        }
+        frame
+    }
+
+    /// Send `frame`, as a diff when the client supports them. Full frames
+    /// without diffs may be coalesced when the link is congested.
+    async fn send_frame(&mut self, frame: Frame) {
+        self.metrics.frame_sent();
+        let Some(differ) = &mut self.differ else {
+            if let Ok(data) = encode(&Envelope::new(MessageType::Frame, frame)) {
+                self.metrics.sent(data.len());
+                let _ = self.transport.send_frame(&data).await;
+            }
+            return;
+        };
+        match differ.update(frame) {
+            FrameUpdate::Full(frame) => self.reply(MessageType::Frame, frame).await,
+            FrameUpdate::Diff(diff) if diff.is_empty() => {}
+            FrameUpdate::Diff(diff) => self.reply(MessageType::FrameDiff, diff).await,
+        }
+    }
+
+    /// Send a message; small replies go ahead of queued frames.
+    async fn reply<T: Serialize>(&self, ty: MessageType, data: T) {
+        let priority = match ty {
+            MessageType::Ack | MessageType::Error => Priority::High,
+            _ => Priority::Normal,
+        };
+        if let Ok(data) = encode(&Envelope::new(ty, data)) {
+            self.metrics.sent(data.len());
+            let _ = self.transport.send_priority(&data, priority).await;
+        }
+    }
+}
+
+/// Decode the payload of an envelope whose type is already known.
+fn payload<T: DeserializeOwned>(msg: &[u8]) -> Result<T, ErrorMsg> {
+    decode::<T>(msg)
+        .map(|env| env.data)
+        .map_err(|_| malformed())
+}
+
+fn malformed() -> ErrorMsg {
+    ErrorMsg::new(ErrorCode::Invalid, "malformed message")
+}
+
+/// Receive the next frame of a followed session, or wait forever while
+/// not following one.
+async fn recv_followed(
+    followed: Option<&mut Followed>,
+) -> Result<Frame, broadcast::error::RecvError> {
+    match followed {
+        Some(followed) => followed.frames.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolve once shutdown is signalled; wait forever if the server dropped
+/// the signal without shutting down.
+async fn shutdown_requested(rx: &mut watch::Receiver<bool>) {
+    if rx.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Connection tasks of one listener and the signal that stops them.
+struct Clients {
+    config: AcceptorConfig,
+    shared: Shared,
+    /// One permit per client that may be served at once.
+    slots: Arc<Semaphore>,
+    queue: Arc<WaitQueue>,
+    shutdown: watch::Sender<bool>,
+    tasks: JoinSet<()>,
+    /// Id of the next connection's tracing span.
+    next_connection: u64,
+}
+
+impl Clients {
+    fn new(config: AcceptorConfig, workspace: Workspace, secret_hash: Option<String>) -> Self {
+        let workspace = workspace
+            .with_audit(config.file_audit.clone())
+            .with_metrics(config.metrics.clone())
+            .with_limits(config.write_limits)
+            .with_readonly(config.readonly)
+            .with_undo_history(config.undo_history.clone())
+            .with_snippets(config.snippets.clone());
+        let watcher = DirWatcher::new(workspace.clone());
+        // Bad exclude globs leave the index off; searches report them.
+        let index = config
+            .search_index
+            .then(|| SearchIndex::spawn(workspace.clone(), &config.search_exclude, &watcher).ok())
+            .flatten();
+        Self {
+            shared: Shared {
+                secret_hash,
+                sessions: SessionRegistry::default(),
+                watcher,
+                search_exclude: config.search_exclude.clone().into(),
+                index,
+                audit: config.audit.clone(),
+                tokens: config.tokens.clone(),
+                auth_limit: config.auth_limit.clone(),
+                metrics: config.metrics.clone(),
+                workspace,
+            },
+            slots: Arc::new(Semaphore::new(config.max_clients.max(1))),
+            queue: Arc::default(),
+            config,
+            shutdown: watch::channel(false).0,
+            tasks: JoinSet::new(),
+            next_connection: 0,
+        }
+    }
+
+    /// Wait for settings sent through [`AcceptorConfig::reload`]. Never
+    /// resolves without a reload channel or once its sender is gone.
+    async fn reloaded(&mut self) -> Reload {
+        let Some(rx) = &mut self.config.reload else {
+            return std::future::pending().await;
+        };
+        match rx.changed().await {
+            Ok(()) => rx.borrow_and_update().clone(),
+            Err(_) => {
+                self.config.reload = None;
+                std::future::pending().await
+            }
+        }
+    }
+
+    /// Switch to the settings in `reload`. Connected clients stay, and
+    /// their sessions check the new write limits from their next save.
+    fn apply(&mut self, reload: Reload) {
+        self.config.access = reload.access;
+        self.config.connect_limit.set_limit(reload.connect_limit);
+        self.shared.auth_limit.set_limit(reload.auth_limit);
+        self.config.tokens = reload.tokens.clone();
+        self.shared.tokens = reload.tokens;
+        self.config.write_limits = reload.write_limits;
+        self.shared
+            .workspace
+            .quota()
+            .set_limits(reload.write_limits);
+        tracing::info!("settings reloaded");
+    }
+
+    /// Whether `addr` is kept out by the access lists. Refusals are
+    /// audited.
+    fn refuses(&self, addr: SocketAddr) -> bool {
+        if self.config.access.permits(addr) {
+            return false;
+        }
+        tracing::info!(%addr, "refused by the access list");
+        let peer = Peer {
+            addr: addr.to_string(),
+            identity: None,
+        };
+        self.shared.audit.record(AuditEvent::Refused, &peer);
+        true
+    }
+
+    /// Serve `ws` if a slot is free, otherwise queue it or turn it away
+    /// when the queue is full too.
+    async fn admit<S>(&mut self, ws: WebSocketStream<S>, peer: Peer)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // Forget connections that already ended.
+        while self.tasks.try_join_next().is_some() {}
+        let shared = self.shared.clone();
+        let shutdown = self.shutdown.subscribe();
+        self.next_connection += 1;
+        let span = tracing::info_span!(
+            "connection",
+            id = self.next_connection,
+            peer = %peer.addr,
+            client = tracing::field::Empty,
+        );
+        // Free slots go to queued connections first, so this only succeeds
+        // while nobody is waiting.
+        if let Ok(slot) = Arc::clone(&self.slots).try_acquire_owned() {
+            let serve = handle_connection(ws, slot, shared, peer, shutdown);
+            self.tasks.spawn(serve.instrument(span));
+        } else if self.queue.len() < self.config.wait_queue {
+            let ticket = WaitQueue::join(&self.queue);
+            let slots = Arc::clone(&self.slots);
+            let interval = self.config.queue_notice_interval;
+            let wait = wait_for_slot(ws, ticket, slots, interval, shared, peer, shutdown);
+            self.tasks.spawn(wait.instrument(span));
+        } else {
+            span.in_scope(|| tracing::info!("turned away, all slots and the queue are full"));
+            handle_busy(ws).await;
+        }
+    }
+
+    /// Ask every connection and detached session to save and disconnect,
+    /// dropping those that take longer than [`SHUTDOWN_GRACE`].
+    async fn shut_down(mut self) {
+        let _ = self.shutdown.send(true);
+        let sessions = self.shared.sessions.clone();
+        let drained = async {
+            sessions.close_detached().await;
+            while self.tasks.join_next().await.is_some() {}
+        };
+        if tokio::time::timeout(SHUTDOWN_GRACE, drained).await.is_err() {
+            self.tasks.abort_all();
+        }
+    }
+}
+
+/// Connections waiting for a client slot, oldest first.
+#[derive(Debug, Default)]
+struct WaitQueue {
+    next_id: AtomicU64,
+    waiting: Mutex<VecDeque<u64>>,
+}
+
+impl WaitQueue {
+    fn len(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    /// Add a connection at the back; dropping the ticket removes it.
+    fn join(queue: &Arc<Self>) -> Ticket {
+        let id = queue.next_id.fetch_add(1, Ordering::Relaxed);
+        queue.waiting.lock().unwrap().push_back(id);
+        Ticket {
+            queue: Arc::clone(queue),
+            id,
+        }
+    }
+}
+
+/// A connection's place in the [`WaitQueue`].
+struct Ticket {
+    queue: Arc<WaitQueue>,
+    id: u64,
+}
+
+impl Ticket {
+    /// One-based position in the queue.
+    fn position(&self) -> u32 {
+        let waiting = self.queue.waiting.lock().unwrap();
+        let ahead = waiting.iter().take_while(|&&id| id != self.id).count();
+        ahead as u32 + 1
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.queue
+            .waiting
+            .lock()
+            .unwrap()
+            .retain(|&id| id != self.id);
+    }
+}
+
+/// Hold `ws` until a client slot frees up, telling it its position every
+/// `interval`, then serve it. Gives up when the client goes away or the
+/// server shuts down.
+async fn wait_for_slot<S>(
+    mut ws: WebSocketStream<S>,
+    ticket: Ticket,
+    slots: Arc<Semaphore>,
+    interval: Duration,
+    shared: Shared,
+    peer: Peer,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Created once so the connection keeps its turn at the semaphore.
+    let acquire = slots.acquire_owned();
+    tokio::pin!(acquire);
+    let mut notices = tokio::time::interval(interval);
+    let slot = loop {
+        tokio::select! {
+            slot = &mut acquire => break slot.expect("slots are never closed"),
+            _ = notices.tick() => {
+                let queued = Queued { position: ticket.position() };
+                let Ok(data) = encode(&Envelope::new(MessageType::Queued, queued)) else {
+                    return;
+                };
+                // A failed send means the client gave up waiting.
+                if ws.send(Message::Binary(data.into())).await.is_err() {
+                    return;
+                }
+            }
+            _ = shutdown_requested(&mut shutdown) => {
+                let err = ErrorMsg::new(ErrorCode::ShuttingDown, "server is shutting down");
+                if let Ok(data) = encode(&Envelope::new(MessageType::Error, err)) {
+                    let _ = ws.send(Message::Binary(data.into())).await;
+                }
+                let _ = ws.close(None).await;
+                return;
+            }
+        }
+    };
+    drop(ticket);
+    handle_connection(ws, slot, shared, peer, shutdown).await;
+}
+
+/// Handshakes of accepted connections, each in a task of its own so a
+/// slow or broken client holds up neither the accept loop nor the other
+/// clients. Dropping it abandons those still in progress.
+struct Handshakes<S> {
+    pending: JoinSet<Option<(WebSocketStream<S>, Peer)>>,
+}
+
+impl<S: Send + 'static> Handshakes<S> {
+    fn new() -> Self {
+        Self {
+            pending: JoinSet::new(),
+        }
+    }
+
+    /// Run `handshake` for the client at `addr`, giving up after
+    /// [`HANDSHAKE_TIMEOUT`]. Failures are logged and the client dropped.
+    fn spawn<E: std::fmt::Display>(
+        &mut self,
+        addr: String,
+        handshake: impl Future<Output = Result<(WebSocketStream<S>, Peer), E>> + Send + 'static,
+    ) {
+        self.pending.spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(done)) => Some(done),
+                Ok(Err(e)) => {
+                    tracing::info!(%addr, error = %e, "handshake failed");
+                    None
+                }
+                Err(_) => {
+                    tracing::info!(%addr, "handshake timed out");
+                    None
+                }
+            }
+        });
+    }
+
+    /// The next client whose handshake succeeded. Never resolves while
+    /// none are in progress.
+    async fn next(&mut self) -> (WebSocketStream<S>, Peer) {
+        loop {
+            match self.pending.join_next().await {
+                Some(Ok(Some(done))) => return done,
+                Some(_) => continue,
+                None => std::future::pending().await,
+            }
         }
     }
-    active.store(false, Ordering::SeqCst);
 }
 
-pub async fn run_tcp(listener: TcpListener, secret_hash: Option<String>) -> tokio::io::Result<()> {
-    let active = Arc::new(AtomicBool::new(false));
-    let mut rl = RateLimiter::new(3, Duration::from_secs(60));
+/// Receive from `rx`, or wait forever while no session is open.
+async fn recv_opt<T>(rx: Option<&mut mpsc::Receiver<T>>) -> Option<T> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Serve clients over WebSocket on `listener`, confining file access to
+/// `workspace`. The listener may come from systemd socket activation; see
+/// [`Activation::take_tcp`](crate::activation::Activation::take_tcp).
+pub async fn run_tcp(
+    listener: TcpListener,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+) -> tokio::io::Result<()> {
+    let config = AcceptorConfig::default();
+    run_tcp_until(
+        listener,
+        workspace,
+        secret_hash,
+        config,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Like [`run_tcp`] with explicit tunables, until `shutdown` resolves.
+/// Connected clients are then told, their sessions saved and their
+/// connections closed before this returns.
+pub async fn run_tcp_until(
+    listener: TcpListener,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    config: AcceptorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> tokio::io::Result<()> {
+    run_tcp_all_until(vec![listener], workspace, secret_hash, config, shutdown).await
+}
+
+/// Like [`run_tcp_until`], accepting clients on every listener, e.g. from
+/// [`listen::bind_all`](crate::listen::bind_all) for an IPv4 and an IPv6
+/// address. The clients share one workspace and one set of limits.
+pub async fn run_tcp_all_until(
+    listeners: Vec<TcpListener>,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    config: AcceptorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> tokio::io::Result<()> {
+    if listeners.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no address to listen on",
+        ));
+    }
+    tokio::pin!(shutdown);
+    let _announced = (listeners.iter())
+        .map(|listener| {
+            Ok(announce::listening(
+                "ws",
+                listener.local_addr()?,
+                config.advertise,
+            ))
+        })
+        .collect::<tokio::io::Result<Vec<_>>>()?;
+    let mut clients = Clients::new(config, workspace, secret_hash);
+    let mut handshakes = Handshakes::new();
     loop {
-        let (stream, addr) = listener.accept().await?;
-        if let Some(retry) = rl.check(addr.ip().to_string()) {
-            let ws = accept_async(stream).await.map_err(std::io::Error::other)?;
+        let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+        let (ws, peer) = tokio::select! {
+            (accepted, _, _) = select_all(accepts) => {
+                let (stream, addr) = accepted?;
+                if !clients.refuses(addr) {
+                    let peer = Peer {
+                        addr: addr.to_string(),
+                        identity: None,
+                    };
+                    let handshake = async move { accept_async(stream).await.map(|ws| (ws, peer)) };
+                    handshakes.spawn(addr.to_string(), handshake);
+                }
+                continue;
+            }
+            handshaken = handshakes.next() => handshaken,
+            reload = clients.reloaded() => {
+                clients.apply(reload);
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+        if let Err(retry) = clients.config.connect_limit.check(&limit_key(&peer)) {
+            tracing::info!(addr = %peer.addr, "connection rate limited");
             handle_rate_limited(ws, retry).await;
             continue;
         }
-        let ws = accept_async(stream).await.map_err(std::io::Error::other)?;
-        if active.load(Ordering::SeqCst) {
-            handle_busy(ws).await;
-        } else {
-            active.store(true, Ordering::SeqCst);
-            let active_clone = Arc::clone(&active);
-            let hash = secret_hash.clone();
-            tokio::spawn(async move { handle_connection(ws, active_clone, hash).await });
+        clients.admit(ws, peer).await;
+    }
+    clients.shut_down().await;
+    Ok(())
+}
+
+/// Like [`run_tcp`], with every connection wrapped in TLS by `tls`. When
+/// `tls` requires client certificates, their subjects name the clients in
+/// the audit log.
+pub async fn run_tls(
+    listener: TcpListener,
+    tls: TlsAcceptor,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+) -> tokio::io::Result<()> {
+    let config = AcceptorConfig::default();
+    run_tls_until(
+        listener,
+        tls,
+        workspace,
+        secret_hash,
+        config,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Like [`run_tls`] with explicit tunables, until `shutdown` resolves;
+/// see [`run_tcp_until`].
+pub async fn run_tls_until(
+    listener: TcpListener,
+    tls: TlsAcceptor,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    config: AcceptorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> tokio::io::Result<()> {
+    tokio::pin!(shutdown);
+    let _announced = announce::listening("wss", listener.local_addr()?, config.advertise);
+    let mut clients = Clients::new(config, workspace, secret_hash);
+    let mut handshakes = Handshakes::new();
+    loop {
+        let (ws, peer) = tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted?;
+                // A failed or stalled handshake only affects that client.
+                if !clients.refuses(addr) {
+                    let tls = tls.clone();
+                    let handshake = async move {
+                        let stream = tls.accept(stream).await?;
+                        let peer = Peer {
+                            addr: addr.to_string(),
+                            identity: tls::peer_identity(&stream),
+                        };
+                        let ws = accept_async(stream).await.map_err(std::io::Error::other)?;
+                        Ok::<_, std::io::Error>((ws, peer))
+                    };
+                    handshakes.spawn(addr.to_string(), handshake);
+                }
+                continue;
+            }
+            handshaken = handshakes.next() => handshaken,
+            reload = clients.reloaded() => {
+                clients.apply(reload);
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+        if let Err(retry) = clients.config.connect_limit.check(&limit_key(&peer)) {
+            tracing::info!(addr = %peer.addr, "connection rate limited");
+            handle_rate_limited(ws, retry).await;
+            continue;
         }
+        clients.admit(ws, peer).await;
     }
+    clients.shut_down().await;
+    Ok(())
+}
+
+/// Accept clients over QUIC. Each connection carries the same handshake
+/// and session as a WebSocket client.
+#[cfg(feature = "quic")]
+pub async fn run_quic(
+    endpoint: ghostwriter_core::quic::Endpoint,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+) -> tokio::io::Result<()> {
+    let config = AcceptorConfig::default();
+    run_quic_until(
+        endpoint,
+        workspace,
+        secret_hash,
+        config,
+        std::future::pending(),
+    )
+    .await
 }
 
-pub async fn run_uds(listener: UnixListener, secret_hash: Option<String>) -> tokio::io::Result<()> {
-    let active = Arc::new(AtomicBool::new(false));
-    let mut rl = RateLimiter::new(3, Duration::from_secs(60));
+/// Like [`run_quic`] with explicit tunables, until `shutdown` resolves;
+/// see [`run_tcp_until`].
+#[cfg(feature = "quic")]
+pub async fn run_quic_until(
+    endpoint: ghostwriter_core::quic::Endpoint,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    config: AcceptorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> tokio::io::Result<()> {
+    tokio::pin!(shutdown);
+    // `--connect auto` finds the WebSocket listeners; QUIC clients need
+    // the certificate anyway, so they are told the address.
+    let _announced = announce::listening(
+        ghostwriter_core::quic::SCHEME,
+        endpoint.local_addr()?,
+        false,
+    );
+    let mut clients = Clients::new(config, workspace, secret_hash);
+    let mut handshakes = Handshakes::new();
     loop {
-        let (stream, _) = listener.accept().await?;
-        if let Some(retry) = rl.check("local".into()) {
-            let ws = accept_async(stream).await.map_err(std::io::Error::other)?;
+        let (ws, peer) = tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else { break };
+                let addr = incoming.remote_address();
+                // A handshake only holds up its own client.
+                if !clients.refuses(addr) {
+                    let handshake = async move {
+                        let (addr, ws) = ghostwriter_core::quic::handshake(incoming).await?;
+                        let peer = Peer {
+                            addr: addr.to_string(),
+                            identity: None,
+                        };
+                        Ok::<_, std::io::Error>((ws, peer))
+                    };
+                    handshakes.spawn(addr.to_string(), handshake);
+                }
+                continue;
+            }
+            handshaken = handshakes.next() => handshaken,
+            reload = clients.reloaded() => {
+                clients.apply(reload);
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+        if let Err(retry) = clients.config.connect_limit.check(&limit_key(&peer)) {
+            tracing::info!(addr = %peer.addr, "connection rate limited");
             handle_rate_limited(ws, retry).await;
             continue;
         }
-        let ws = accept_async(stream).await.map_err(std::io::Error::other)?;
-        if active.load(Ordering::SeqCst) {
-            handle_busy(ws).await;
-        } else {
-            active.store(true, Ordering::SeqCst);
-            let active_clone = Arc::clone(&active);
-            let hash = secret_hash.clone();
-            tokio::spawn(async move { handle_connection(ws, active_clone, hash).await });
+        clients.admit(ws, peer).await;
+    }
+    clients.shut_down().await;
+    Ok(())
+}
+
+/// Like [`run_tcp`] for a Unix domain socket, which may also be inherited;
+/// see [`Activation::take_unix`](crate::activation::Activation::take_unix).
+pub async fn run_uds(
+    listener: UnixListener,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+) -> tokio::io::Result<()> {
+    let config = AcceptorConfig::default();
+    run_uds_until(
+        listener,
+        workspace,
+        secret_hash,
+        config,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Like [`run_uds`] with explicit tunables, until `shutdown` resolves;
+/// see [`run_tcp_until`].
+pub async fn run_uds_until(
+    listener: UnixListener,
+    workspace: Workspace,
+    secret_hash: Option<String>,
+    config: AcceptorConfig,
+    shutdown: impl Future<Output = ()>,
+) -> tokio::io::Result<()> {
+    tokio::pin!(shutdown);
+    let mut clients = Clients::new(config, workspace, secret_hash);
+    let mut handshakes = Handshakes::new();
+    loop {
+        let (ws, peer) = tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let peer = Peer {
+                    addr: "local".into(),
+                    identity: None,
+                };
+                let handshake = async move { accept_async(stream).await.map(|ws| (ws, peer)) };
+                handshakes.spawn("local".into(), handshake);
+                continue;
+            }
+            handshaken = handshakes.next() => handshaken,
+            reload = clients.reloaded() => {
+                clients.apply(reload);
+                continue;
+            }
+            _ = &mut shutdown => break,
+        };
+        if let Err(retry) = clients.config.connect_limit.check("local") {
+            tracing::info!("local connection rate limited");
+            handle_rate_limited(ws, retry).await;
+            continue;
         }
+        clients.admit(ws, peer).await;
     }
+    clients.shut_down().await;
+    Ok(())
 }
@@ -0,0 +1,148 @@
+//! Which remote addresses may connect at all, checked before any handshake
+//! so unwanted hosts cost the server as little as possible.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// An address block such as `10.0.0.0/8` or `fd00::/8`. A bare address is
+/// a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+/// Error parsing a [`Cidr`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCidr(String);
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid address block {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+impl Cidr {
+    /// Whether `ip` lies in this block. IPv4 addresses mapped into IPv6
+    /// match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Allow and deny lists for incoming connections. A denied address is
+/// always refused; when the allow list is not empty, only addresses in it
+/// get through. The default lets everyone in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Whether a connection from `addr` may proceed.
+    pub fn permits(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 7777)
+    }
+
+    #[test]
+    fn parses_and_matches_blocks() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains("192.168.1.77".parse().unwrap()));
+        assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+        assert!(lan.contains("::ffff:192.168.1.5".parse().unwrap()));
+        assert_eq!(lan.to_string(), "192.168.1.0/24");
+
+        let host: Cidr = "fd00::1".parse().unwrap();
+        assert!(host.contains("fd00::1".parse().unwrap()));
+        assert!(!host.contains("fd00::2".parse().unwrap()));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("8.8.8.8".parse().unwrap()));
+        assert!(!all.contains("::1".parse().unwrap()));
+
+        for bad in ["", "10.0.0.0/33", "10.0.0/8", "::/129", "lan"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let open = AccessList::default();
+        assert!(open.permits(addr("203.0.113.9")));
+
+        let access = AccessList {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.13".parse().unwrap()],
+        };
+        assert!(access.permits(addr("10.1.2.3")));
+        assert!(!access.permits(addr("10.0.0.13")));
+        assert!(!access.permits(addr("203.0.113.9")));
+
+        let deny_only = AccessList {
+            allow: Vec::new(),
+            deny: vec!["203.0.113.0/24".parse().unwrap()],
+        };
+        assert!(!deny_only.permits(addr("203.0.113.9")));
+        assert!(deny_only.permits(addr("10.0.0.13")));
+    }
+}
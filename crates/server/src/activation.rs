@@ -0,0 +1,145 @@
+//! Listeners inherited from systemd socket activation, so a socket unit can
+//! start the server on the first connection and keep the port bound across
+//! restarts. Pass them to [`run_tcp`](crate::acceptor::run_tcp) and
+//! [`run_uds`](crate::acceptor::run_uds) in place of freshly bound ones.
+
+use std::{
+    io, mem,
+    ops::Range,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::net::{TcpListener, UnixListener};
+
+/// First descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the passed descriptors have been claimed, so they are never
+/// owned twice.
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+/// Sockets passed by systemd that have not been taken yet.
+#[derive(Debug, Default)]
+pub struct Activation {
+    fds: Vec<OwnedFd>,
+}
+
+impl Activation {
+    /// Claim the sockets named by `LISTEN_PID` and `LISTEN_FDS`. Empty when
+    /// the server was not socket activated or they were claimed already.
+    pub fn from_env() -> Self {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        let range = listen_fds(pid.as_deref(), count.as_deref(), std::process::id());
+        if range.is_empty() || CLAIMED.swap(true, Ordering::SeqCst) {
+            return Self::default();
+        }
+        let fds = range
+            .map(|fd| {
+                // SAFETY: systemd hands these descriptors to this process,
+                // and `CLAIMED` makes sure they get a single owner.
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                // SAFETY: `fd` is open. Not inherited by anything the
+                // server might start.
+                unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+                fd
+            })
+            .collect();
+        Self { fds }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Take the first TCP socket. Must be called within a Tokio runtime.
+    pub fn take_tcp(&mut self) -> io::Result<Option<TcpListener>> {
+        let Some(fd) = self.take(|family| family == libc::AF_INET || family == libc::AF_INET6)
+        else {
+            return Ok(None);
+        };
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener).map(Some)
+    }
+
+    /// Take the first Unix domain socket. Must be called within a Tokio
+    /// runtime.
+    pub fn take_unix(&mut self) -> io::Result<Option<UnixListener>> {
+        let Some(fd) = self.take(|family| family == libc::AF_UNIX) else {
+            return Ok(None);
+        };
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener).map(Some)
+    }
+
+    fn take(&mut self, wanted: impl Fn(libc::c_int) -> bool) -> Option<OwnedFd> {
+        let at = self
+            .fds
+            .iter()
+            .position(|fd| socket_family(fd).is_some_and(&wanted))?;
+        Some(self.fds.remove(at))
+    }
+}
+
+/// Descriptors passed to process `pid` according to the `LISTEN_PID` and
+/// `LISTEN_FDS` variables; empty if they are for another process.
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Range<RawFd> {
+    let for_us = listen_pid.and_then(|p| p.parse::<u32>().ok()) == Some(pid);
+    let count = listen_fds.and_then(|n| n.parse::<RawFd>().ok());
+    match count {
+        Some(count) if for_us && count > 0 => LISTEN_FDS_START..LISTEN_FDS_START + count,
+        _ => LISTEN_FDS_START..LISTEN_FDS_START,
+    }
+}
+
+/// Address family of a socket, or `None` if `fd` is not one.
+fn socket_family(fd: &OwnedFd) -> Option<libc::c_int> {
+    // SAFETY: `addr` and `len` describe a buffer big enough for any
+    // socket address.
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        if libc::getsockname(fd.as_raw_fd(), (&raw mut addr).cast(), &mut len) != 0 {
+            return None;
+        }
+        Some(libc::c_int::from(addr.ss_family))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_listen_variables() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 3..5);
+        assert!(listen_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(listen_fds(None, Some("2"), 42).is_empty());
+        assert!(listen_fds(Some("42"), None, 42).is_empty());
+        assert!(listen_fds(Some("42"), Some("-1"), 42).is_empty());
+        assert!(listen_fds(Some("42"), Some("x"), 42).is_empty());
+    }
+
+    #[tokio::test]
+    async fn takes_listeners_by_family() {
+        let dir = tempfile::tempdir().unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let uds = std::os::unix::net::UnixListener::bind(dir.path().join("s")).unwrap();
+        let mut activation = Activation {
+            fds: vec![OwnedFd::from(uds), OwnedFd::from(tcp)],
+        };
+
+        let tcp = activation.take_tcp().unwrap().unwrap();
+        assert_eq!(tcp.local_addr().unwrap(), addr);
+        assert!(activation.take_tcp().unwrap().is_none());
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tcp.accept().await.unwrap();
+
+        assert!(activation.take_unix().unwrap().is_some());
+        assert!(activation.is_empty());
+    }
+}
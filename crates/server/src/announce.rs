@@ -0,0 +1,111 @@
+//! Telling users where to connect: a ready-to-copy URL for the address a
+//! listener is bound to and, with the `mdns` feature, an advertisement
+//! that `--connect auto` finds.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+/// The URL clients connect to a listener bound at `bound` with. An
+/// unspecified address such as `0.0.0.0` is replaced by this machine's
+/// LAN address, or loopback when it has none.
+pub fn connect_url(scheme: &str, bound: SocketAddr) -> String {
+    let ip = if bound.ip().is_unspecified() {
+        lan_address().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    } else {
+        bound.ip()
+    };
+    format!("{scheme}://{}", SocketAddr::new(ip, bound.port()))
+}
+
+/// The address this machine reaches other hosts from. Connecting a UDP
+/// socket only picks a route; nothing is sent.
+pub fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+}
+
+/// Held while a listener runs; dropping it withdraws the advertisement.
+pub(crate) struct Announced {
+    #[cfg(feature = "mdns")]
+    _advertisement: Option<ghostwriter_core::mdns::Advertisement>,
+}
+
+/// Log where a listener bound at `bound` is reached and, if asked to,
+/// advertise it. A failed advertisement is logged; the listener still
+/// runs.
+pub(crate) fn listening(scheme: &str, bound: SocketAddr, advertise: bool) -> Announced {
+    tracing::info!(url = %connect_url(scheme, bound), "listening");
+    #[cfg(feature = "mdns")]
+    {
+        let advertisement =
+            advertise
+                .then(|| self::advertise(scheme, bound))
+                .and_then(|advertised| {
+                    advertised
+                        .inspect_err(|e| tracing::warn!("cannot advertise over mDNS: {e}"))
+                        .ok()
+                });
+        Announced {
+            _advertisement: advertisement,
+        }
+    }
+    #[cfg(not(feature = "mdns"))]
+    {
+        if advertise {
+            tracing::warn!("mDNS advertising is not available; rebuild with `--features mdns`");
+        }
+        Announced {}
+    }
+}
+
+/// Advertise a listener bound at `bound` over mDNS until the returned
+/// value is dropped.
+#[cfg(feature = "mdns")]
+pub fn advertise(
+    scheme: &str,
+    bound: SocketAddr,
+) -> std::io::Result<ghostwriter_core::mdns::Advertisement> {
+    let host = hostname();
+    let ip = match bound.ip() {
+        ip if ip.is_unspecified() => lan_address().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        ip => ip,
+    };
+    let name = format!("{host}-{}", bound.port());
+    ghostwriter_core::mdns::Advertisement::start(&name, &host, &[ip], bound.port(), scheme)
+}
+
+/// This machine's host name, as a single DNS label.
+#[cfg(feature = "mdns")]
+fn hostname() -> String {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let label: String = (name.trim().split('.').next().unwrap_or_default().chars())
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    if label.is_empty() {
+        "ghostwriter".to_string()
+    } else {
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_unspecified_addresses() {
+        let url = connect_url("ws", "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(url, "ws://127.0.0.1:4000");
+        let url = connect_url("wss", "[::1]:4000".parse().unwrap());
+        assert_eq!(url, "wss://[::1]:4000");
+        let url = connect_url("ws", "0.0.0.0:4000".parse().unwrap());
+        let host = url.strip_prefix("ws://").unwrap();
+        let addr: SocketAddr = host.parse().unwrap();
+        assert!(!addr.ip().is_unspecified());
+        assert_eq!(addr.port(), 4000);
+    }
+}
@@ -0,0 +1,227 @@
+//! Audit trails of who connected to the server, one line per event:
+//! `<unix seconds> <event> peer=<address> identity=<name>`, and of the file
+//! operations clients made, as JSON lines.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Where audit events go; the default discards them.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+/// A connected client as the audit log names it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Remote address, or `local` for Unix sockets.
+    pub addr: String,
+    /// Subject of the client certificate under mutual TLS, or else
+    /// `token:<id>` for clients that logged in with a token.
+    pub identity: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// The client finished the handshake and authentication.
+    Connected,
+    /// The client gave a wrong secret.
+    AuthFailed,
+    /// An authenticated client went away.
+    Disconnected,
+    /// The address is not allowed to connect; the connection was dropped
+    /// before any handshake.
+    Refused,
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuditEvent::Connected => "connected",
+            AuditEvent::AuthFailed => "auth-failed",
+            AuditEvent::Disconnected => "disconnected",
+            AuditEvent::Refused => "refused",
+        })
+    }
+}
+
+/// File operations the [`FileAudit`] records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    /// A file was loaded into a session.
+    Read,
+    /// A session saved a file.
+    Write,
+    /// A folder was listed.
+    List,
+    /// A session asked for a file's write lock.
+    Lock,
+    Create,
+    Rename,
+    Delete,
+}
+
+impl fmt::Display for FileOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileOp::Read => "read",
+            FileOp::Write => "write",
+            FileOp::List => "list",
+            FileOp::Lock => "lock",
+            FileOp::Create => "create",
+            FileOp::Rename => "rename",
+            FileOp::Delete => "delete",
+        })
+    }
+}
+
+/// Append-only record of file operations, one JSON object per line with
+/// `time`, `peer`, `identity`, `op`, `path`, `bytes` and `result` (`ok`
+/// or the error). The default discards them.
+#[derive(Debug, Clone, Default)]
+pub struct FileAudit {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl FileAudit {
+    /// Append records to the file at `path`, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(file))),
+        })
+    }
+
+    /// Write one operation on workspace-relative `path` by `peer`, which is
+    /// `None` for the server itself. `bytes` is what was read or written.
+    /// Like [`AuditLog::record`], failed writes are dropped.
+    pub fn record(
+        &self,
+        peer: Option<&Peer>,
+        op: FileOp,
+        path: &str,
+        bytes: Option<u64>,
+        result: Result<(), String>,
+    ) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let record = serde_json::json!({
+            "time": unix_secs(),
+            "peer": peer.map(|p| &p.addr),
+            "identity": peer.and_then(|p| p.identity.as_ref()),
+            "op": op.to_string(),
+            "path": path,
+            "bytes": bytes,
+            "result": match &result {
+                Ok(()) => "ok",
+                Err(e) => e,
+            },
+        });
+        let line = format!("{record}\n");
+        let _ = file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl AuditLog {
+    /// Append events to the file at `path`, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(file))),
+        })
+    }
+
+    /// Write `event` for `peer`. Failed writes are dropped so a full disk
+    /// does not take clients down with it.
+    pub fn record(&self, event: AuditEvent, peer: &Peer) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let secs = unix_secs();
+        let identity = match &peer.identity {
+            Some(name) => format!("{name:?}"),
+            None => "-".into(),
+        };
+        let line = format!("{secs} {event} peer={} identity={identity}\n", peer.addr);
+        let _ = file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_one_line_per_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        std::fs::write(&path, "earlier\n").unwrap();
+        let log = AuditLog::open(&path).unwrap();
+        let alice = Peer {
+            addr: "127.0.0.1:4000".into(),
+            identity: Some("alice smith".into()),
+        };
+        let anonymous = Peer {
+            addr: "local".into(),
+            identity: None,
+        };
+        log.record(AuditEvent::Connected, &alice);
+        log.clone().record(AuditEvent::AuthFailed, &anonymous);
+        AuditLog::default().record(AuditEvent::Disconnected, &alice);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "earlier");
+        let (_, rest) = lines[1].split_once(' ').unwrap();
+        assert_eq!(
+            rest,
+            r#"connected peer=127.0.0.1:4000 identity="alice smith""#
+        );
+        assert!(lines[2].ends_with(" auth-failed peer=local identity=-"));
+    }
+
+    #[test]
+    fn records_file_operations_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("files.jsonl");
+        let audit = FileAudit::open(&path).unwrap();
+        let alice = Peer {
+            addr: "127.0.0.1:4000".into(),
+            identity: Some("alice".into()),
+        };
+        audit.record(Some(&alice), FileOp::Write, "src/a.rs", Some(12), Ok(()));
+        audit.record(None, FileOp::Delete, "b", None, Err("not found".into()));
+        FileAudit::default().record(None, FileOp::Read, "c", None, Ok(()));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["identity"], "alice");
+        assert_eq!(records[0]["peer"], "127.0.0.1:4000");
+        assert_eq!(records[0]["op"], "write");
+        assert_eq!(records[0]["path"], "src/a.rs");
+        assert_eq!(records[0]["bytes"], 12);
+        assert_eq!(records[0]["result"], "ok");
+        assert!(records[0]["time"].as_u64().unwrap() > 0);
+        assert!(records[1]["peer"].is_null());
+        assert_eq!(records[1]["op"], "delete");
+        assert_eq!(records[1]["result"], "not found");
+    }
+}
@@ -1,4 +1,17 @@
-use std::{fs, io, path::Path};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use rand::{Rng, distributions::Alphanumeric};
+
+/// Length of generated token secrets.
+const TOKEN_LEN: usize = 32;
 
 /// Load the Argon2 hash from `path`. Returns `Ok(None)` if the file does not
 /// exist.
@@ -10,11 +23,228 @@ pub fn load_hash<P: AsRef<Path>>(path: P) -> io::Result<Option<String>> {
     }
 }
 
+/// The Argon2 hash of `secret` with a fresh salt, as [`load_hash`] reads
+/// it.
+pub fn hash_secret(secret: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Whether `secret` matches the Argon2 `hash`. A malformed hash matches
+/// nothing.
+pub(crate) fn verify_secret(hash: &str, secret: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(secret.as_bytes(), &hash)
+        .is_ok()
+}
+
+/// Named tokens clients can authenticate with, so access can be granted
+/// and revoked per client instead of sharing one secret.
+///
+/// Tokens live in a text file, one `<id> <expiry> <argon2 hash>` line each,
+/// with the expiry in Unix seconds or `-` for none. The file is read on
+/// every check, so changes apply without restarting the server.
+#[derive(Debug, Clone)]
+pub struct TokenStore {
+    path: PathBuf,
+}
+
+/// A stored token, without its secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub id: String,
+    pub expires: Option<SystemTime>,
+}
+
+struct Entry {
+    info: TokenInfo,
+    hash: String,
+}
+
+impl TokenStore {
+    /// Store tokens in the file at `path`, which need not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Create token `id`, valid for `ttl` or forever, and return its
+    /// secret. The secret is only stored hashed, so it cannot be shown
+    /// again.
+    pub fn issue(&self, id: &str, ttl: Option<Duration>) -> io::Result<String> {
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "token ids must be non-empty and contain no whitespace",
+            ));
+        }
+        let mut entries = self.read()?;
+        if entries.iter().any(|e| e.info.id == id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("token {id} exists"),
+            ));
+        }
+        let secret: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        let hash = hash_secret(&secret)?;
+        entries.push(Entry {
+            info: TokenInfo {
+                id: id.to_string(),
+                expires: ttl.map(|ttl| SystemTime::now() + ttl),
+            },
+            hash,
+        });
+        self.write(&entries)?;
+        Ok(secret)
+    }
+
+    /// Delete token `id`. Returns whether it existed.
+    pub fn revoke(&self, id: &str) -> io::Result<bool> {
+        let mut entries = self.read()?;
+        let before = entries.len();
+        entries.retain(|e| e.info.id != id);
+        if entries.len() == before {
+            return Ok(false);
+        }
+        self.write(&entries)?;
+        Ok(true)
+    }
+
+    /// Every stored token, expired ones included.
+    pub fn list(&self) -> io::Result<Vec<TokenInfo>> {
+        Ok(self.read()?.into_iter().map(|e| e.info).collect())
+    }
+
+    /// Whether `secret` belongs to token `id` and the token has not
+    /// expired. An unreadable store rejects everything.
+    pub fn verify(&self, id: &str, secret: &str) -> bool {
+        let Ok(entries) = self.read() else {
+            return false;
+        };
+        let Some(entry) = entries.into_iter().find(|e| e.info.id == id) else {
+            return false;
+        };
+        if entry.info.expires.is_some_and(|at| at <= SystemTime::now()) {
+            return false;
+        }
+        verify_secret(&entry.hash, secret)
+    }
+
+    fn read(&self) -> io::Result<Vec<Entry>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut fields = line.split_whitespace();
+                let (Some(id), Some(expires), Some(hash), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(malformed(line));
+                };
+                let expires = match expires {
+                    "-" => None,
+                    secs => {
+                        let secs = secs.parse().map_err(|_| malformed(line))?;
+                        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                    }
+                };
+                Ok(Entry {
+                    info: TokenInfo {
+                        id: id.to_string(),
+                        expires,
+                    },
+                    hash: hash.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn write(&self, entries: &[Entry]) -> io::Result<()> {
+        let mut text = String::new();
+        for entry in entries {
+            let expires = match entry.info.expires {
+                Some(at) => {
+                    let secs = at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs());
+                    secs.to_string()
+                }
+                None => "-".into(),
+            };
+            text.push_str(&format!("{} {expires} {}\n", entry.info.id, entry.hash));
+        }
+        ghostwriter_core::atomic_write(&self.path, text.as_bytes())
+    }
+}
+
+fn malformed(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed token line: {line}"),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use tempfile::NamedTempFile;
 
+    #[test]
+    fn issues_verifies_and_revokes_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path().join("tokens"));
+        assert!(store.list().unwrap().is_empty());
+
+        let ci = store.issue("ci", None).unwrap();
+        let laptop = store
+            .issue("laptop", Some(Duration::from_secs(3600)))
+            .unwrap();
+        assert_eq!(ci.len(), TOKEN_LEN);
+        assert_ne!(ci, laptop);
+        let text = fs::read_to_string(dir.path().join("tokens")).unwrap();
+        assert!(!text.contains(&ci));
+
+        assert!(store.verify("ci", &ci));
+        assert!(store.verify("laptop", &laptop));
+        assert!(!store.verify("ci", &laptop));
+        assert!(!store.verify("nobody", &ci));
+        let ids: Vec<_> = store.list().unwrap().into_iter().map(|t| t.id).collect();
+        assert_eq!(ids, ["ci", "laptop"]);
+
+        assert_eq!(
+            store.issue("ci", None).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert!(store.issue("two words", None).is_err());
+
+        assert!(store.revoke("ci").unwrap());
+        assert!(!store.revoke("ci").unwrap());
+        assert!(!store.verify("ci", &ci));
+        assert!(store.verify("laptop", &laptop));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path().join("tokens"));
+        let secret = store.issue("old", Some(Duration::ZERO)).unwrap();
+        assert!(!store.verify("old", &secret));
+        assert!(store.list().unwrap()[0].expires.is_some());
+    }
+
     #[test]
     fn loads_existing_hash() {
         let mut file = NamedTempFile::new().unwrap();
@@ -0,0 +1,131 @@
+//! Running the server in the background, detached from the shell that
+//! started it, with a PID file for service managers and scripts.
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Detach from the terminal: fork twice so the server is neither a
+/// process group leader nor able to regain a controlling terminal, and
+/// point stdin, stdout and stderr at `/dev/null`. The original process
+/// exits. Call before starting any threads, such as the async runtime.
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: no other threads exist yet, so forking cannot leave locks
+    // held; parents exit at once without running destructors.
+    unsafe {
+        fork_and_exit_parent()?;
+        if libc::setsid() < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        fork_and_exit_parent()?;
+    }
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: both descriptors are open.
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Fork, continuing in the child.
+unsafe fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// A file holding the server's process id, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write this process's id to `path`. Fails with
+    /// [`io::ErrorKind::AlreadyExists`] while the process named in an
+    /// existing file is still running; a stale file is replaced.
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(pid) = read_pid(&path)
+            && pid != std::process::id()
+            && is_running(pid)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} names running process {pid}", path.display()),
+            ));
+        }
+        let mut file = fs::File::create(&path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_removes_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghostwriter.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path), Some(std::process::id()));
+        // Rewriting our own file is allowed.
+        drop(PidFile::create(&path).unwrap());
+        assert!(!path.exists());
+        drop(pid_file);
+    }
+
+    #[test]
+    fn refuses_pid_file_of_running_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghostwriter.pid");
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        fs::write(&path, format!("{}\n", child.id())).unwrap();
+        let err = PidFile::create(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        // The process is gone, so its file is stale.
+        let _pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path), Some(std::process::id()));
+    }
+}
@@ -0,0 +1,289 @@
+//! Directory change subscriptions. A single polling task scans every
+//! watched directory once per tick and fans the differences out to all of
+//! its subscribers.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use ghostwriter_proto::{ChangeKind, DirChange, WatchEvent};
+use tokio::sync::mpsc;
+
+use crate::workspace::{IGNORED, Workspace};
+
+/// Interval between scans of the watched directories.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches workspace directories on behalf of any number of clients.
+#[derive(Debug, Clone)]
+pub struct DirWatcher {
+    workspace: Workspace,
+    interval: Duration,
+    hub: Arc<Mutex<Hub>>,
+}
+
+#[derive(Debug, Default)]
+struct Hub {
+    next_id: u64,
+    subs: HashMap<u64, Sub>,
+    /// Last scan of each watched directory, shared by its subscribers.
+    snapshots: HashMap<Key, Snapshot>,
+    /// Whether the polling task is alive; it stops with the last
+    /// subscription.
+    polling: bool,
+}
+
+#[derive(Debug)]
+struct Sub {
+    key: Key,
+    /// Directory as the client named it.
+    rel: String,
+    tx: mpsc::Sender<WatchEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    dir: PathBuf,
+    recursive: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    is_dir: bool,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+type Snapshot = BTreeMap<PathBuf, Entry>;
+
+/// A client's interest in one directory, ended on drop.
+#[derive(Debug)]
+pub struct Subscription {
+    hub: Arc<Mutex<Hub>>,
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.hub.lock().unwrap().subs.remove(&self.id);
+    }
+}
+
+impl DirWatcher {
+    pub fn new(workspace: Workspace) -> Self {
+        Self::with_interval(workspace, POLL_INTERVAL)
+    }
+
+    /// Like [`new`](Self::new), scanning every `interval`.
+    pub fn with_interval(workspace: Workspace, interval: Duration) -> Self {
+        Self {
+            workspace,
+            interval,
+            hub: Arc::default(),
+        }
+    }
+
+    /// Report changes below the workspace directory `rel` on `tx` until the
+    /// subscription is dropped. Events are dropped while `tx` is full.
+    pub fn subscribe(
+        &self,
+        rel: &str,
+        recursive: bool,
+        tx: mpsc::Sender<WatchEvent>,
+    ) -> io::Result<Subscription> {
+        let dir = self.workspace.resolve(rel)?;
+        if !fs::metadata(&dir)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "not a directory",
+            ));
+        }
+        let key = Key { dir, recursive };
+        let baseline = scan(&key);
+        let mut hub = self.hub.lock().unwrap();
+        hub.snapshots.entry(key.clone()).or_insert(baseline);
+        let id = hub.next_id;
+        hub.next_id += 1;
+        let rel = rel.to_string();
+        hub.subs.insert(id, Sub { key, rel, tx });
+        if !hub.polling {
+            hub.polling = true;
+            tokio::spawn(self.clone().poll());
+        }
+        Ok(Subscription {
+            hub: Arc::clone(&self.hub),
+            id,
+        })
+    }
+
+    async fn poll(self) {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let keys: Vec<Key> = {
+                let mut hub = self.hub.lock().unwrap();
+                if hub.subs.is_empty() {
+                    hub.polling = false;
+                    hub.snapshots.clear();
+                    return;
+                }
+                let watched: HashSet<Key> = hub.subs.values().map(|s| s.key.clone()).collect();
+                hub.snapshots.retain(|key, _| watched.contains(key));
+                watched.into_iter().collect()
+            };
+            let scans = tokio::task::spawn_blocking(move || {
+                keys.into_iter()
+                    .map(|key| {
+                        let snapshot = scan(&key);
+                        (key, snapshot)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let Ok(scans) = scans.await else {
+                continue;
+            };
+            let mut hub = self.hub.lock().unwrap();
+            for (key, now) in scans {
+                let Some(before) = hub.snapshots.insert(key.clone(), now.clone()) else {
+                    continue;
+                };
+                let changes = diff(&self.workspace, &before, &now);
+                if changes.is_empty() {
+                    continue;
+                }
+                for sub in hub.subs.values().filter(|s| s.key == key) {
+                    let event = WatchEvent {
+                        path: sub.rel.clone(),
+                        changes: changes.clone(),
+                    };
+                    let _ = sub.tx.try_send(event);
+                }
+            }
+        }
+    }
+}
+
+/// Entries below `key.dir`, skipping the same names as listings.
+fn scan(key: &Key) -> Snapshot {
+    let mut snapshot = Snapshot::new();
+    let mut pending = vec![key.dir.clone()];
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if IGNORED.iter().any(|i| entry.file_name() == *i) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let path = entry.path();
+            if meta.is_dir() && key.recursive {
+                pending.push(path.clone());
+            }
+            let entry = Entry {
+                is_dir: meta.is_dir(),
+                modified: meta.modified().ok(),
+                len: meta.len(),
+            };
+            snapshot.insert(path, entry);
+        }
+    }
+    snapshot
+}
+
+/// Changes from `before` to `now`. Folders only count as modified when
+/// they turn into files or back, not whenever their children change.
+fn diff(ws: &Workspace, before: &Snapshot, now: &Snapshot) -> Vec<DirChange> {
+    let change = |path: &Path, kind, is_dir| DirChange {
+        path: ws.relative(path),
+        kind,
+        is_dir,
+    };
+    let mut changes = Vec::new();
+    for (path, entry) in now {
+        match before.get(path) {
+            None => changes.push(change(path, ChangeKind::Created, entry.is_dir)),
+            Some(old) if entry.is_dir && old.is_dir => {}
+            Some(old) if old != entry => {
+                changes.push(change(path, ChangeKind::Modified, entry.is_dir))
+            }
+            Some(_) => {}
+        }
+    }
+    for (path, entry) in before {
+        if !now.contains_key(path) {
+            changes.push(change(path, ChangeKind::Removed, entry.is_dir));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next(rx: &mut mpsc::Receiver<WatchEvent>) -> WatchEvent {
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn summary(event: &WatchEvent) -> Vec<(&str, ChangeKind)> {
+        event
+            .changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reports_changes_to_each_subscriber() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let watcher = DirWatcher::with_interval(ws, Duration::from_millis(20));
+        let (tx, mut shallow) = mpsc::channel(8);
+        let _root = watcher.subscribe("", false, tx).unwrap();
+        let (tx, mut deep) = mpsc::channel(8);
+        let deep_sub = watcher.subscribe("", true, tx).unwrap();
+        assert!(
+            watcher
+                .subscribe("a.txt", false, mpsc::channel(1).0)
+                .is_err()
+        );
+        assert!(watcher.subscribe("../", false, mpsc::channel(1).0).is_err());
+
+        fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        let event = next(&mut deep).await;
+        assert_eq!(event.path, "");
+        assert_eq!(summary(&event), [("src/lib.rs", ChangeKind::Created)]);
+
+        fs::write(dir.path().join("a.txt"), "longer").unwrap();
+        fs::remove_file(dir.path().join("src/lib.rs")).unwrap();
+        let event = next(&mut shallow).await;
+        assert_eq!(summary(&event), [("a.txt", ChangeKind::Modified)]);
+        let event = next(&mut deep).await;
+        assert_eq!(
+            summary(&event),
+            [
+                ("a.txt", ChangeKind::Modified),
+                ("src/lib.rs", ChangeKind::Removed)
+            ]
+        );
+
+        drop(deep_sub);
+        fs::write(dir.path().join("b.txt"), "").unwrap();
+        let event = next(&mut shallow).await;
+        assert_eq!(summary(&event), [("b.txt", ChangeKind::Created)]);
+        assert!(deep.recv().await.is_none());
+    }
+}
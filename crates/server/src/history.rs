@@ -0,0 +1,61 @@
+//! Undo history kept between sessions, in a file per document outside the
+//! workspace.
+
+use std::path::{Path, PathBuf};
+
+/// Bytes of undo history kept per document unless configured otherwise.
+pub const DEFAULT_UNDO_HISTORY_SIZE: u64 = 1 << 20;
+
+/// Where sessions keep the undo history of the files they edit, so edits
+/// made before a file was last saved and closed can still be undone once
+/// it is opened again.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UndoHistory {
+    /// Directory with a file per document; `None` keeps history for the
+    /// session only.
+    pub dir: Option<PathBuf>,
+    /// Bytes of edits kept per document, the oldest dropped first.
+    pub max_size: u64,
+}
+
+impl UndoHistory {
+    /// History of at most `max_size` bytes per document in the user's data
+    /// directory, like `~/.local/share/ghostwriter/undo`.
+    pub fn in_data_dir(max_size: u64) -> Self {
+        Self {
+            dir: dirs::data_dir().map(|dir| dir.join("ghostwriter").join("undo")),
+            max_size,
+        }
+    }
+
+    /// File holding the history of `path`, named after its absolute path
+    /// with separators turned into `%`, as in Vim's `undodir`.
+    pub(crate) fn file(&self, path: &Path) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        let path = std::path::absolute(path).ok()?;
+        let name: String = (path.to_string_lossy().chars())
+            .map(|c| match c {
+                ':' => '%',
+                c if std::path::is_separator(c) => '%',
+                c => c,
+            })
+            .collect();
+        Some(dir.join(name + ".undo"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_files_after_the_whole_path() {
+        let history = UndoHistory {
+            dir: Some(PathBuf::from("/data/undo")),
+            max_size: DEFAULT_UNDO_HISTORY_SIZE,
+        };
+        let file = history.file(Path::new("/home/ada/notes.txt")).unwrap();
+        assert_eq!(file, Path::new("/data/undo/%home%ada%notes.txt.undo"));
+        assert_eq!(UndoHistory::default().file(Path::new("/a")), None);
+    }
+}
@@ -0,0 +1,241 @@
+//! In-memory trigram index of workspace files, so literal searches only
+//! read the files that can contain the pattern. It is built in the
+//! background and refreshed whenever the directory watcher reports a
+//! change, re-reading only files whose size or modification time moved.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock, Weak},
+    time::SystemTime,
+};
+
+use ghostwriter_proto::{ErrorMsg, SearchRequest, WatchEvent};
+use ignore::WalkBuilder;
+use tokio::sync::{mpsc, watch};
+
+use crate::dirwatch::{DirWatcher, Subscription};
+use crate::search;
+use crate::session::picker_error;
+use crate::workspace::Workspace;
+
+type Trigram = [u8; 3];
+
+/// Shared handle to the index; the refresh task stops with the last one.
+#[derive(Debug, Clone)]
+pub struct SearchIndex {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// `None` until the first build finished.
+    files: RwLock<Option<Files>>,
+    /// Closed when the last handle goes away.
+    _alive: watch::Sender<()>,
+}
+
+type Files = BTreeMap<PathBuf, IndexedFile>;
+
+#[derive(Debug)]
+struct IndexedFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// Trigrams of the ASCII-lowercased contents; `None` for files that
+    /// are not UTF-8, which searches skip.
+    trigrams: Option<Arc<HashSet<Trigram>>>,
+}
+
+impl SearchIndex {
+    /// Index the files of `workspace` that a search with the `exclude`
+    /// globs would read, and keep them current with `watcher`.
+    pub fn spawn(
+        workspace: Workspace,
+        exclude: &[String],
+        watcher: &DirWatcher,
+    ) -> Result<Self, ErrorMsg> {
+        let walk = search::walker(&workspace, exclude, false)?;
+        // One queued event is enough: every refresh rescans everything.
+        let (tx, changes) = mpsc::channel(1);
+        let sub = watcher.subscribe("", true, tx).map_err(picker_error(""))?;
+        let (alive, closed) = watch::channel(());
+        let inner = Arc::new(Inner {
+            files: RwLock::new(None),
+            _alive: alive,
+        });
+        tokio::spawn(refresh_loop(
+            Arc::downgrade(&inner),
+            walk,
+            sub,
+            changes,
+            closed,
+        ));
+        Ok(Self { inner })
+    }
+
+    /// Whether the first build has finished.
+    pub fn is_ready(&self) -> bool {
+        self.inner.files.read().unwrap().is_some()
+    }
+
+    /// Files that may contain matches for `req`, in walk order. `None` when
+    /// the index cannot tell: before the first build, for regexes, and for
+    /// searches that include ignored files.
+    ///
+    /// Changes made since the last refresh may be missed.
+    pub fn candidates(&self, req: &SearchRequest) -> Option<Vec<PathBuf>> {
+        if req.regex || req.no_ignore {
+            return None;
+        }
+        let files = self.inner.files.read().unwrap();
+        let needed = query_trigrams(&req.pattern, req.case);
+        let candidates = files
+            .as_ref()?
+            .iter()
+            .filter(|(_, file)| {
+                file.trigrams
+                    .as_ref()
+                    .is_some_and(|have| needed.iter().all(|t| have.contains(t)))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        Some(candidates)
+    }
+}
+
+async fn refresh_loop(
+    inner: Weak<Inner>,
+    walk: WalkBuilder,
+    _sub: Subscription,
+    mut changes: mpsc::Receiver<WatchEvent>,
+    mut closed: watch::Receiver<()>,
+) {
+    loop {
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let walk = walk.clone();
+        if tokio::task::spawn_blocking(move || refresh(&inner, walk))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        tokio::select! {
+            _ = closed.changed() => return,
+            event = changes.recv() => if event.is_none() {
+                return;
+            },
+        }
+    }
+}
+
+/// Rescan the workspace, reusing the trigrams of unchanged files.
+fn refresh(inner: &Inner, walk: WalkBuilder) {
+    let mut fresh = Files::new();
+    for path in search::files(walk) {
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+        let (modified, len) = (meta.modified().ok(), meta.len());
+        let known = inner
+            .files
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|files| files.get(&path))
+            .filter(|file| file.modified == modified && file.len == len)
+            .map(|file| file.trigrams.clone());
+        let trigrams = known.unwrap_or_else(|| {
+            let text = fs::read_to_string(&path).ok()?;
+            Some(Arc::new(trigrams(text.as_bytes())))
+        });
+        let file = IndexedFile {
+            modified,
+            len,
+            trigrams,
+        };
+        fresh.insert(path, file);
+    }
+    *inner.files.write().unwrap() = Some(fresh);
+}
+
+fn trigrams(bytes: &[u8]) -> HashSet<Trigram> {
+    bytes
+        .windows(3)
+        .map(|w| [w[0], w[1], w[2]].map(|b| b.to_ascii_lowercase()))
+        .collect()
+}
+
+/// Trigrams every file containing `pattern` must have. Without `case`,
+/// Unicode case folding lets non-ASCII text match ASCII letters (`K` for
+/// `k`, `ſ` for `s`), so only trigrams free of those are required.
+fn query_trigrams(pattern: &str, case: bool) -> Vec<Trigram> {
+    let folds = |b: &u8| !b.is_ascii() || matches!(b.to_ascii_lowercase(), b'k' | b's');
+    trigrams(pattern.as_bytes())
+        .into_iter()
+        .filter(|t| case || !t.iter().any(folds))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use super::*;
+
+    fn literal(pattern: &str) -> SearchRequest {
+        SearchRequest {
+            pattern: pattern.into(),
+            regex: false,
+            case: false,
+            limit: 0,
+            no_ignore: false,
+            whole_word: false,
+        }
+    }
+
+    /// Wait until the candidates for `pattern`, relative to `root`, are
+    /// `expected`.
+    async fn expect(index: &SearchIndex, root: &Path, pattern: &str, expected: &[&str]) {
+        for _ in 0..250 {
+            let found = index.candidates(&literal(pattern));
+            if let Some(found) = found {
+                let found: Vec<_> = found
+                    .iter()
+                    .map(|p| p.strip_prefix(root).unwrap().to_str().unwrap())
+                    .collect();
+                if found == expected {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("candidates for {pattern:?} never became {expected:?}");
+    }
+
+    #[tokio::test]
+    async fn narrows_literal_searches_and_follows_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.path().join("a.txt"), "Needle here").unwrap();
+        fs::write(dir.path().join("b.txt"), "hay").unwrap();
+        fs::write(dir.path().join("target/c.txt"), "needle").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let root = ws.root().to_path_buf();
+        let watcher = DirWatcher::with_interval(ws.clone(), Duration::from_millis(20));
+        let index = SearchIndex::spawn(ws, &[], &watcher).unwrap();
+
+        expect(&index, &root, "needle", &["a.txt"]).await;
+        expect(&index, &root, "ha", &[".gitignore", "a.txt", "b.txt"]).await;
+        let mut regex = literal("needle");
+        regex.regex = true;
+        assert!(index.candidates(&regex).is_none());
+
+        fs::write(dir.path().join("b.txt"), "more needles").unwrap();
+        fs::remove_file(dir.path().join("a.txt")).unwrap();
+        expect(&index, &root, "needle", &["b.txt"]).await;
+    }
+}
@@ -1,18 +1,56 @@
 pub mod acceptor;
+pub mod access;
+#[cfg(unix)]
+pub mod activation;
+pub mod announce;
+pub mod audit;
 pub mod auth;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dirwatch;
+pub mod history;
+pub mod index;
+pub mod listen;
+pub mod lock;
+pub mod metrics;
+pub mod picker;
+#[cfg(unix)]
+pub mod privileges;
+pub mod quota;
+pub mod ratelimit;
+pub mod registry;
+#[cfg(target_os = "linux")]
+pub mod sandbox;
+pub mod search;
 pub mod session;
+pub mod snippets;
+pub mod workspace;
 
-/// Server entry point.
-pub fn run() -> &'static str {
-    "server"
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn run_returns_server() {
-        assert_eq!(run(), "server");
+/// Resolve on Ctrl-C or, on Unix, SIGTERM; pass to the `run_*_until`
+/// acceptors to shut down gracefully. SIGHUP is left for reloading
+/// settings through [`AcceptorConfig::reload`](acceptor::AcceptorConfig::reload).
+///
+/// The signals are caught from the call on, not only once the future is
+/// polled, so call it within a Tokio runtime before the server starts.
+pub fn shutdown_signal() -> impl std::future::Future<Output = ()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let interrupt = signal(SignalKind::interrupt());
+        let term = signal(SignalKind::terminate());
+        async move {
+            let (Ok(mut interrupt), Ok(mut term)) = (interrupt, term) else {
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            };
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = term.recv() => {}
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    async {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
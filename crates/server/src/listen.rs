@@ -0,0 +1,84 @@
+//! TCP listeners for the addresses a server binds, for
+//! [`run_tcp_all_until`](crate::acceptor::run_tcp_all_until).
+
+use std::{io, net::SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+/// Pending connections each listener queues.
+const BACKLOG: i32 = 1024;
+
+/// Bind a listener to every address in `addrs`; must be called within a
+/// Tokio runtime. An IPv6 address such as `[::]` also takes IPv4 clients
+/// (dual stack), unless an IPv4 address on the same port is bound as
+/// well, in which case the two split the traffic.
+pub fn bind_all(addrs: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let v6_only = addr.is_ipv6()
+                && (addrs.iter()).any(|other| other.is_ipv4() && other.port() == addr.port());
+            bind(*addr, v6_only).map_err(|e| io::Error::new(e.kind(), format!("{addr}: {e}")))
+        })
+        .collect()
+}
+
+fn bind(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // Like std's listeners, so a restarted server need not wait out
+    // TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn binds_ipv4_and_ipv6_on_one_port() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return; // No IPv6 here.
+        }
+        let port = free_port();
+        let addrs = [
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        ];
+        let listeners = bind_all(&addrs).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners[1].local_addr().unwrap().is_ipv6());
+        tokio::net::TcpStream::connect(("::1", port)).await.unwrap();
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        let err = bind_all(&addrs[..1]).unwrap_err();
+        assert!(err.to_string().starts_with("0.0.0.0:"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn ipv6_wildcard_alone_takes_ipv4_too() {
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let port = free_port();
+        let _listeners = bind_all(&[SocketAddr::from(([0u16; 8], port))]).unwrap();
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! Per-file write locks, so several clients can open a file while only one
+//! of them edits it.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Files currently held for writing, shared by every session of a server.
+#[derive(Debug, Clone, Default)]
+pub struct FileLocks {
+    held: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl FileLocks {
+    /// Take the write lock on `path`, or `None` while another session holds
+    /// it.
+    pub fn try_lock(&self, path: &Path) -> Option<FileLock> {
+        if !self.held.lock().unwrap().insert(path.to_path_buf()) {
+            return None;
+        }
+        Some(FileLock {
+            locks: self.clone(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Whether some session holds the write lock on `path`.
+    pub fn is_locked(&self, path: &Path) -> bool {
+        self.held.lock().unwrap().contains(path)
+    }
+}
+
+/// Write access to one file, released on drop.
+#[derive(Debug)]
+pub struct FileLock {
+    locks: FileLocks,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Locked file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_holder_per_path_until_dropped() {
+        let locks = FileLocks::default();
+        let a = Path::new("/w/a.txt");
+        let lock = locks.try_lock(a).unwrap();
+        assert_eq!(lock.path(), a);
+        assert!(locks.is_locked(a));
+        assert!(locks.clone().try_lock(a).is_none());
+        let other = locks.try_lock(Path::new("/w/b.txt"));
+        assert!(other.is_some());
+
+        drop(lock);
+        assert!(!locks.is_locked(a));
+        assert!(locks.try_lock(a).is_some());
+    }
+}
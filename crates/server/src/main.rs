@@ -1,5 +0,0 @@
-use ghostwriter_server::run;
-
-fn main() {
-    println!("{}", run());
-}
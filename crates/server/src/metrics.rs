@@ -0,0 +1,345 @@
+//! Server counters and histograms, served in the Prometheus text format
+//! by [`serve`].
+
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Largest request head [`serve`] reads before giving up on a scrape.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a scraper gets to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, so running out of file descriptors does
+/// not turn into a busy loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Metrics of one server, shared by clones. The default starts at zero.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    connections: AtomicI64,
+    frames_sent: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    edit_ops: AtomicU64,
+    auth_failures: AtomicU64,
+    save_seconds: Histogram,
+    search_seconds: Histogram,
+}
+
+/// Counts of observations at or below each of [`LATENCY_BUCKETS`].
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, took: Duration) {
+        let secs = took.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(took.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            let n = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {n}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// Counts a client as connected until dropped.
+pub(crate) struct Connected(Metrics);
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.0.inner.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Count an authenticated client until the guard is dropped.
+    pub(crate) fn connected(&self) -> Connected {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        Connected(self.clone())
+    }
+
+    pub(crate) fn frame_sent(&self) {
+        self.inner.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.inner
+            .bytes_in
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.inner
+            .bytes_out
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn edit_op(&self) {
+        self.inner.edit_ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn auth_failed(&self) {
+        self.inner.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_save(&self, took: Duration) {
+        self.inner.save_seconds.observe(took);
+    }
+
+    pub(crate) fn observe_search(&self, took: Duration) {
+        self.inner.search_seconds.observe(took);
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let inner = &self.inner;
+        let mut out = String::new();
+        let gauge = |out: &mut String, name: &str, help: &str, value: i64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        };
+        gauge(
+            &mut out,
+            "ghostwriter_connections",
+            "Authenticated clients connected.",
+            inner.connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "ghostwriter_frames_sent_total",
+            "Frames and frame diffs sent to clients.",
+            &inner.frames_sent,
+        );
+        counter(
+            &mut out,
+            "ghostwriter_received_bytes_total",
+            "Message bytes received from clients.",
+            &inner.bytes_in,
+        );
+        counter(
+            &mut out,
+            "ghostwriter_sent_bytes_total",
+            "Message bytes sent to clients.",
+            &inner.bytes_out,
+        );
+        counter(
+            &mut out,
+            "ghostwriter_edit_ops_total",
+            "Editing commands applied for clients.",
+            &inner.edit_ops,
+        );
+        counter(
+            &mut out,
+            "ghostwriter_auth_failures_total",
+            "Logins rejected for a wrong or rate limited secret.",
+            &inner.auth_failures,
+        );
+        inner.save_seconds.render(
+            &mut out,
+            "ghostwriter_save_duration_seconds",
+            "Time taken to write a file to disk.",
+        );
+        inner.search_seconds.render(
+            &mut out,
+            "ghostwriter_search_duration_seconds",
+            "Time taken by workspace searches.",
+        );
+        out
+    }
+}
+
+/// Answer `GET /metrics` on `listener` with [`Metrics::render`]; anything
+/// else gets a 404. Runs for good: failed accepts are logged and skipped.
+pub async fn serve(listener: TcpListener, metrics: Metrics) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "cannot accept a metrics scrape");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let _ = scrape(stream, &metrics).await;
+        });
+    }
+}
+
+async fn scrape(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let Ok(head) = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await else {
+        return Ok(());
+    };
+    let Some(head) = head? else {
+        return Ok(());
+    };
+    let line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let (status, body) = match line.strip_prefix(b"GET /metrics") {
+        Some(rest) if rest.is_empty() || rest.starts_with(b" ") || rest.starts_with(b"?") => {
+            ("200 OK", metrics.render())
+        }
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// The request head up to its blank line; `None` if the client hangs up
+/// or sends more than [`MAX_REQUEST`] first.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(head))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::default();
+        let guard = metrics.connected();
+        metrics.clone().connected();
+        metrics.frame_sent();
+        metrics.received(10);
+        metrics.sent(32);
+        metrics.edit_op();
+        metrics.auth_failed();
+        metrics.observe_save(Duration::from_millis(3));
+        metrics.observe_save(Duration::from_secs(2));
+
+        let text = metrics.render();
+        let has = |line: &str| text.lines().any(|l| l == line);
+        assert!(has("ghostwriter_connections 1"));
+        assert!(has("# TYPE ghostwriter_frames_sent_total counter"));
+        assert!(has("ghostwriter_frames_sent_total 1"));
+        assert!(has("ghostwriter_received_bytes_total 10"));
+        assert!(has("ghostwriter_sent_bytes_total 32"));
+        assert!(has("ghostwriter_edit_ops_total 1"));
+        assert!(has("ghostwriter_auth_failures_total 1"));
+        assert!(has(
+            "ghostwriter_save_duration_seconds_bucket{le=\"0.001\"} 0"
+        ));
+        assert!(has(
+            "ghostwriter_save_duration_seconds_bucket{le=\"0.005\"} 1"
+        ));
+        assert!(has("ghostwriter_save_duration_seconds_bucket{le=\"5\"} 2"));
+        assert!(has(
+            "ghostwriter_save_duration_seconds_bucket{le=\"+Inf\"} 2"
+        ));
+        assert!(has("ghostwriter_save_duration_seconds_sum 2.003"));
+        assert!(has("ghostwriter_search_duration_seconds_count 0"));
+        drop(guard);
+        assert!(metrics.render().contains("ghostwriter_connections 0\n"));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::default();
+        metrics.edit_op();
+        let server = tokio::spawn(serve(listener, metrics));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP "));
+        assert!(response.contains("\nghostwriter_edit_ops_total 1\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 "));
+
+        server.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_scrapers_that_stall() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, Metrics::default()));
+
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(b"GET /metrics").await.unwrap();
+        let mut response = Vec::new();
+        stalled.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        server.abort();
+    }
+}
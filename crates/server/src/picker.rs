@@ -0,0 +1,273 @@
+//! File picker state and frame composition, so remote clients render the
+//! picker like any other frame instead of reimplementing the tree.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use ghostwriter_proto::{Cursor, DirEntry, Frame, Line, StyleSpan};
+
+use crate::workspace::{Workspace, fuzzy_score};
+
+/// Bytes of a file read for its preview.
+const PREVIEW_BYTES: u64 = 64 * 1024;
+
+/// Key hints shown on the right of the picker's status line.
+const HINTS: &str = "Enter:Open  Esc:Close  Ctrl+N:New  Ctrl+D:Delete";
+
+/// A directory listing narrowed by a filter, with one entry selected.
+#[derive(Debug)]
+pub struct Picker {
+    /// Listed directory, workspace-relative; `""` is the root.
+    dir: String,
+    filter: String,
+    selected: usize,
+    /// Entries of `dir` matching `filter`, best match first.
+    entries: Vec<DirEntry>,
+}
+
+impl Picker {
+    /// List `dir` with no filter and the first entry selected.
+    pub fn new(ws: &Workspace, dir: &str) -> io::Result<Self> {
+        let mut picker = Self {
+            dir: dir.to_string(),
+            filter: String::new(),
+            selected: 0,
+            entries: Vec::new(),
+        };
+        picker.refresh(ws)?;
+        Ok(picker)
+    }
+
+    /// Listed directory, workspace-relative.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Currently selected entry, if the listing is not empty.
+    pub fn selected(&self) -> Option<&DirEntry> {
+        self.entries.get(self.selected)
+    }
+
+    /// Read the directory again, e.g. after a file operation changed it.
+    pub fn refresh(&mut self, ws: &Workspace) -> io::Result<()> {
+        let listing = ws.list_dir(&self.dir)?;
+        self.entries = if self.filter.is_empty() {
+            listing
+        } else {
+            let mut scored: Vec<_> = listing
+                .into_iter()
+                .filter_map(|e| Some((fuzzy_score(&self.filter, name(&e.path))?, e)))
+                .collect();
+            // Stable, so equal scores keep folders first.
+            scored.sort_by_key(|(score, _)| -score);
+            scored.into_iter().map(|(_, e)| e).collect()
+        };
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+        Ok(())
+    }
+
+    /// Narrow the listing to entries fuzzy-matching `filter` and select the
+    /// best match.
+    pub fn set_filter(&mut self, ws: &Workspace, filter: String) -> io::Result<()> {
+        self.filter = filter;
+        self.selected = 0;
+        self.refresh(ws)
+    }
+
+    /// Move the selection by `delta` entries, stopping at either end.
+    pub fn move_selection(&mut self, delta: i64) {
+        let last = self.entries.len().saturating_sub(1) as i64;
+        self.selected = (self.selected as i64).saturating_add(delta).clamp(0, last) as usize;
+    }
+
+    /// List another directory, clearing the filter.
+    pub fn enter(&mut self, ws: &Workspace, dir: &str) -> io::Result<()> {
+        *self = Self::new(ws, dir)?;
+        Ok(())
+    }
+
+    /// Compose the picker: the listing on the left, a preview of the
+    /// selected entry on the right.
+    pub fn compose(&self, ws: &Workspace, cols: u16, rows: u16, doc_v: u64) -> Frame {
+        let mut frame = Frame {
+            id: "picker".into(),
+            kind: "picker".into(),
+            doc_v,
+            first_line: 0,
+            first_col: 0,
+            cols,
+            rows,
+            lines: Vec::new(),
+            cursors: Vec::new(),
+            status_left: if self.filter.is_empty() {
+                format!("/{}", self.dir)
+            } else {
+                format!("/{}  > {}", self.dir, self.filter)
+            },
+            status_right: HINTS.into(),
+            status: None,
+            classes: Vec::new(),
+            checksum: None,
+        };
+        let rows = rows as usize;
+        let list_width = cols as usize / 2;
+        let first = (self.selected + 1).saturating_sub(rows.max(1));
+        let preview = self
+            .selected()
+            .map(|entry| preview(ws, entry, rows))
+            .unwrap_or_default();
+        for row in 0..rows {
+            let entry = self.entries.get(first + row);
+            if entry.is_none() && row >= preview.len() {
+                break;
+            }
+            let mut label = entry.map(label).unwrap_or_default();
+            label = label.chars().take(list_width).collect();
+            let label_len = label.chars().count() as u16;
+            let mut spans = Vec::new();
+            if entry.is_some_and(|e| e.is_dir) {
+                spans.push(StyleSpan {
+                    start_col: 0,
+                    end_col: label_len,
+                    class: frame.intern_class("dir"),
+                    user_id: None,
+                });
+            }
+            if entry.is_some() && first + row == self.selected {
+                spans.push(StyleSpan {
+                    start_col: 0,
+                    end_col: list_width as u16,
+                    class: frame.intern_class("picker-sel"),
+                    user_id: None,
+                });
+            }
+            let mut text = format!("{label:<list_width$}|");
+            let room = (cols as usize).saturating_sub(list_width + 1);
+            if let Some(line) = preview.get(row) {
+                text.extend(line.chars().take(room));
+            }
+            frame.lines.push(Line {
+                text,
+                spans,
+                more: false,
+                marked: false,
+            });
+        }
+        frame.first_line = first as u64;
+        if !self.entries.is_empty() {
+            frame.cursors.push(Cursor::new(self.selected as u64, 0));
+        }
+        frame
+    }
+}
+
+/// Last component of a workspace-relative path.
+fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Entry name as listed, folders marked with a trailing slash.
+fn label(entry: &DirEntry) -> String {
+    if entry.is_dir {
+        format!("{}/", name(&entry.path))
+    } else {
+        name(&entry.path).to_string()
+    }
+}
+
+/// Up to `rows` lines previewing `entry`: a folder's children or the start
+/// of a file. Unreadable and binary files get a one-line note.
+fn preview(ws: &Workspace, entry: &DirEntry, rows: usize) -> Vec<String> {
+    if entry.is_dir {
+        return match ws.list_dir(&entry.path) {
+            Ok(children) => children.iter().take(rows).map(label).collect(),
+            Err(_) => vec!["(unreadable)".into()],
+        };
+    }
+    let mut bytes = Vec::new();
+    let read = ws
+        .resolve(&entry.path)
+        .and_then(File::open)
+        .and_then(|file| file.take(PREVIEW_BYTES).read_to_end(&mut bytes));
+    if read.is_err() {
+        return vec!["(unreadable)".into()];
+    }
+    if bytes.contains(&0) {
+        return vec!["(binary file)".into()];
+    }
+    String::from_utf8_lossy(&bytes)
+        .lines()
+        .take(rows)
+        .map(|l| l.replace('\t', "    "))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn workspace() -> (tempfile::TempDir, Workspace) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]\nname = \"x\"\n").unwrap();
+        fs::write(dir.path().join("blob.bin"), [0u8, 1, 2]).unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        (dir, ws)
+    }
+
+    fn texts(frame: &Frame) -> Vec<&str> {
+        frame.lines.iter().map(|l| l.text.as_str()).collect()
+    }
+
+    #[test]
+    fn composes_listing_and_preview() {
+        let (_dir, ws) = workspace();
+        let mut picker = Picker::new(&ws, "").unwrap();
+        let frame = picker.compose(&ws, 24, 3, 0);
+        assert_eq!(frame.kind, "picker");
+        assert_eq!(
+            texts(&frame),
+            ["src/        |main.rs", "Cargo.toml  |", "blob.bin    |"]
+        );
+        let sel = &frame.lines[0].spans;
+        assert_eq!(frame.class_name(&sel[0]), Some("dir"));
+        assert_eq!(frame.class_name(&sel[1]), Some("picker-sel"));
+        assert_eq!((sel[1].start_col, sel[1].end_col), (0, 12));
+        assert_eq!(frame.status_left, "/");
+
+        picker.move_selection(1);
+        let frame = picker.compose(&ws, 24, 3, 0);
+        assert_eq!(frame.lines[0].text, "src/        |[package]");
+        assert_eq!(frame.lines[1].text, "Cargo.toml  |name = \"x\"");
+        assert_eq!(frame.cursors[0].line, 1);
+
+        picker.move_selection(5);
+        let frame = picker.compose(&ws, 24, 3, 0);
+        assert_eq!(frame.lines[0].text, "src/        |(binary fil");
+    }
+
+    #[test]
+    fn filter_narrows_and_scrolls_to_selection() {
+        let (_dir, ws) = workspace();
+        let mut picker = Picker::new(&ws, "").unwrap();
+        picker.set_filter(&ws, "toml".into()).unwrap();
+        assert_eq!(picker.selected().unwrap().path, "Cargo.toml");
+        let frame = picker.compose(&ws, 24, 3, 0);
+        assert_eq!(frame.lines.len(), 2);
+        assert_eq!(frame.status_left, "/  > toml");
+
+        picker.set_filter(&ws, String::new()).unwrap();
+        picker.move_selection(2);
+        let frame = picker.compose(&ws, 24, 2, 0);
+        assert_eq!(frame.first_line, 1);
+        assert_eq!(frame.lines[1].text, "blob.bin    |");
+
+        picker.enter(&ws, "src").unwrap();
+        assert_eq!(picker.dir(), "src");
+        assert_eq!(picker.selected().unwrap().path, "src/main.rs");
+    }
+}
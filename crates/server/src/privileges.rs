@@ -0,0 +1,160 @@
+//! Switching to an unprivileged user once the listeners are bound, so a
+//! server started as root for a low port does not serve clients as root.
+
+use std::{ffi::CString, io, mem, ptr};
+
+/// Initial buffer for `getpwnam_r` and friends; grown on `ERANGE`.
+const LOOKUP_BUF: usize = 1024;
+
+/// User and group to run as, each by name or numeric id. Without a group,
+/// the user's primary group is used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+impl RunAs {
+    /// Whether neither a user nor a group is set, so nothing changes.
+    pub fn is_empty(&self) -> bool {
+        self.user.is_none() && self.group.is_none()
+    }
+
+    /// The user and group ids to switch to; `None` keeps the current one.
+    pub fn resolve(&self) -> io::Result<(Option<libc::uid_t>, Option<libc::gid_t>)> {
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        let group = match &self.group {
+            Some(group) => Some(lookup_group(group)?),
+            None => match user {
+                Some((_, Some(primary))) => Some(primary),
+                Some((_, None)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "user has no primary group; name a group as well",
+                    ));
+                }
+                None => None,
+            },
+        };
+        Ok((user.map(|(uid, _)| uid), group))
+    }
+
+    /// Switch the whole process to the user and group for good: drop
+    /// supplementary groups, then set the group and user ids. Call after
+    /// binding listeners and before accepting connections.
+    pub fn apply(&self) -> io::Result<()> {
+        let (uid, gid) = self.resolve()?;
+        // SAFETY: plain id syscalls; the group list outlives `setgroups`.
+        unsafe {
+            if let Some(gid) = gid
+                && (libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0)
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // A saved root id would let a compromised server switch back.
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "root privileges could be regained",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uid and primary gid of `user`, a name or numeric id. The gid is `None`
+/// for numeric ids without a passwd entry.
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let by_id = user.parse::<libc::uid_t>().ok();
+    let name = CString::new(user).map_err(|_| not_found("user", user))?;
+    let entry = lookup(|pwd: &mut libc::passwd, buf, result| unsafe {
+        // SAFETY: `pwd`, `buf` and `result` are valid for the call.
+        match by_id {
+            Some(uid) => libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result),
+            None => libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result),
+        }
+    })?;
+    match (entry, by_id) {
+        (Some(pwd), _) => Ok((pwd.pw_uid, Some(pwd.pw_gid))),
+        (None, Some(uid)) => Ok((uid, None)),
+        (None, None) => Err(not_found("user", user)),
+    }
+}
+
+/// Gid of `group`, a name or numeric id.
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| not_found("group", group))?;
+    let entry = lookup(|grp: &mut libc::group, buf, result| unsafe {
+        // SAFETY: `grp`, `buf` and `result` are valid for the call.
+        libc::getgrnam_r(name.as_ptr(), grp, buf.as_mut_ptr(), buf.len(), result)
+    })?;
+    entry
+        .map(|grp| grp.gr_gid)
+        .ok_or_else(|| not_found("group", group))
+}
+
+/// Run a reentrant `get*_r` lookup, growing its buffer until it fits.
+/// Returns the entry, whose strings are not kept, or `None` if missing.
+fn lookup<T>(
+    mut call: impl FnMut(&mut T, &mut Vec<libc::c_char>, *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<T>> {
+    let mut buf = vec![0; LOOKUP_BUF];
+    loop {
+        // SAFETY: the entry structs are plain C data, valid when zeroed.
+        let mut entry: T = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        match call(&mut entry, &mut buf, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(entry)),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no {kind} named {name:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_as(user: Option<&str>, group: Option<&str>) -> RunAs {
+        RunAs {
+            user: user.map(String::from),
+            group: group.map(String::from),
+        }
+    }
+
+    #[test]
+    fn resolves_names_and_ids() {
+        assert!(RunAs::default().is_empty());
+        assert_eq!(RunAs::default().resolve().unwrap(), (None, None));
+        assert_eq!(
+            run_as(Some("root"), None).resolve().unwrap(),
+            (Some(0), Some(0))
+        );
+        assert_eq!(
+            run_as(Some("0"), Some("4321")).resolve().unwrap(),
+            (Some(0), Some(4321))
+        );
+        assert_eq!(run_as(None, Some("0")).resolve().unwrap(), (None, Some(0)));
+
+        let missing = run_as(Some("no-such-user-here"), None).resolve();
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let missing = run_as(None, Some("no-such-group-here")).resolve();
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        // A numeric id without a passwd entry needs an explicit group.
+        assert!(run_as(Some("4000000000"), None).resolve().is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Caps on how much clients may write, so one of them cannot fill the
+//! server's disk.
+
+use std::{
+    fs, io,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use ghostwriter_proto::{ErrorCode, ErrorMsg};
+
+/// Write limits of a workspace; `None` leaves that dimension unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteLimits {
+    /// Largest a file may grow through edits or be saved at.
+    pub max_file_size: Option<u64>,
+    /// Bytes one connection may save in total.
+    pub connection_quota: Option<u64>,
+    /// Largest the files of the workspace may add up to.
+    pub workspace_cap: Option<u64>,
+}
+
+/// [`WriteLimits`] with the usage they are checked against. Clones share
+/// the limits and workspace usage; [`Quota::for_client`] starts a
+/// connection's count.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Quota {
+    limits: Arc<Mutex<WriteLimits>>,
+    /// Bytes in the workspace, measured on the first save under a cap and
+    /// then kept up to date by saves.
+    usage: Arc<Mutex<Option<u64>>>,
+    /// Bytes saved by the connection this clone belongs to.
+    written: Arc<AtomicU64>,
+}
+
+impl Quota {
+    pub(crate) fn new(limits: WriteLimits) -> Self {
+        Self {
+            limits: Arc::new(Mutex::new(limits)),
+            ..Self::default()
+        }
+    }
+
+    fn limits(&self) -> WriteLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    /// Check saves against `limits` from now on, in every clone. Bytes
+    /// already written still count.
+    pub(crate) fn set_limits(&self, limits: WriteLimits) {
+        *self.limits.lock().unwrap() = limits;
+        // Saves made without a cap did not keep the usage up to date.
+        *self.usage.lock().unwrap() = None;
+    }
+
+    /// A clone counting writes of a new connection.
+    pub(crate) fn for_client(&self) -> Self {
+        Self {
+            written: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Refuse a document of `len` bytes if files may not be that large.
+    pub(crate) fn check_size(&self, len: u64) -> Result<(), ErrorMsg> {
+        match self.limits().max_file_size {
+            Some(max) if len > max => Err(ErrorMsg::new(
+                ErrorCode::TooLarge,
+                format!("files are limited to {max} bytes"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Account for saving `new_len` bytes over a file of `old_len` bytes
+    /// in the workspace with `roots`, or refuse the save if it breaks a
+    /// limit.
+    pub(crate) fn charge_write<'a>(
+        &self,
+        roots: impl IntoIterator<Item = &'a Path>,
+        old_len: u64,
+        new_len: u64,
+    ) -> Result<(), ErrorMsg> {
+        self.check_size(new_len)?;
+        let limits = self.limits();
+        if let Some(quota) = limits.connection_quota {
+            let written = self.written.load(Ordering::Relaxed);
+            if written.saturating_add(new_len) > quota {
+                return Err(ErrorMsg::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("write quota of {quota} bytes used up ({written} written)"),
+                ));
+            }
+        }
+        if let Some(cap) = limits.workspace_cap {
+            let mut usage = self.usage.lock().unwrap();
+            let used = match *usage {
+                Some(used) => used,
+                None => roots
+                    .into_iter()
+                    .map(disk_usage)
+                    .sum::<io::Result<u64>>()
+                    .map_err(|e| ErrorMsg::new(ErrorCode::Io, e.to_string()))?,
+            };
+            let after = (used + new_len).saturating_sub(old_len);
+            if new_len > old_len && after > cap {
+                *usage = Some(used);
+                return Err(ErrorMsg::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("workspace is limited to {cap} bytes ({used} used)"),
+                ));
+            }
+            *usage = Some(after);
+        }
+        self.written.fetch_add(new_len, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Total size of the files under `dir`. Symlinks are not followed.
+fn disk_usage(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        if kind.is_dir() {
+            total += disk_usage(&entry.path())?;
+        } else if kind.is_file() {
+            total += entry.metadata()?.len();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_file_size_and_connection_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = Quota::new(WriteLimits {
+            max_file_size: Some(100),
+            connection_quota: Some(150),
+            workspace_cap: None,
+        });
+        assert!(quota.check_size(100).is_ok());
+        assert_eq!(quota.check_size(101).unwrap_err().code, ErrorCode::TooLarge);
+        let err = quota.charge_write([dir.path()], 0, 101).unwrap_err();
+        assert_eq!(err.code, ErrorCode::TooLarge);
+
+        quota.charge_write([dir.path()], 0, 100).unwrap();
+        let err = quota.charge_write([dir.path()], 100, 60).unwrap_err();
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+        // Each connection gets its own quota.
+        quota
+            .for_client()
+            .charge_write([dir.path()], 100, 60)
+            .unwrap();
+    }
+
+    #[test]
+    fn caps_workspace_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/a.txt"), [0; 60]).unwrap();
+        let quota = Quota::new(WriteLimits {
+            workspace_cap: Some(100),
+            ..WriteLimits::default()
+        });
+        quota.charge_write([dir.path()], 60, 90).unwrap();
+        let err = quota
+            .for_client()
+            .charge_write([dir.path()], 0, 20)
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+        // Shrinking is always allowed, and frees room.
+        quota.charge_write([dir.path()], 90, 50).unwrap();
+        quota.charge_write([dir.path()], 0, 50).unwrap();
+    }
+
+    #[test]
+    fn changes_limits_of_running_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = Quota::new(WriteLimits::default());
+        let client = quota.for_client();
+        client.charge_write([dir.path()], 0, 100).unwrap();
+        quota.set_limits(WriteLimits {
+            max_file_size: Some(50),
+            connection_quota: Some(120),
+            workspace_cap: None,
+        });
+        assert_eq!(client.check_size(60).unwrap_err().code, ErrorCode::TooLarge);
+        // Bytes written before the change count against the new quota.
+        let err = client.charge_write([dir.path()], 0, 30).unwrap_err();
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+    }
+}
@@ -0,0 +1,138 @@
+//! Per-address token buckets that slow down connection floods and password
+//! guessing.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Addresses tracked before full buckets are forgotten.
+const PRUNE_AT: usize = 1024;
+
+/// Up to `burst` attempts at once, refilled steadily so that `burst` more
+/// are allowed every `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, period: Duration) -> Self {
+        Self { burst, period }
+    }
+
+    /// Time for one attempt to refill.
+    fn interval(&self) -> Duration {
+        self.period / self.burst.max(1)
+    }
+}
+
+/// A [`RateLimit`] applied to each address separately. Clones share their
+/// buckets and limit, so one limiter can cover several listeners.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: Arc<Mutex<RateLimit>>,
+    /// When each address's bucket will be full again; a bucket is full
+    /// once this is in the past.
+    full_at: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit: Arc::new(Mutex::new(limit)),
+            full_at: Arc::default(),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        *self.limit.lock().unwrap()
+    }
+
+    /// Apply `limit` from now on, keeping the attempts already counted.
+    pub fn set_limit(&self, limit: RateLimit) {
+        *self.limit.lock().unwrap() = limit;
+    }
+
+    /// Take an attempt from `key`'s bucket, or return how long until one
+    /// is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let limit = self.limit();
+        let mut full_at = self.full_at.lock().unwrap();
+        if full_at.len() >= PRUNE_AT {
+            full_at.retain(|_, at| *at > now);
+        }
+        let at = full_at.get(key).map_or(now, |&at| at.max(now));
+        if let Some(wait) = wait(limit, at, now) {
+            return Err(wait);
+        }
+        full_at.insert(key.to_string(), at + limit.interval());
+        Ok(())
+    }
+
+    /// How long until `key` may make another attempt, if it is out of
+    /// them, without taking one.
+    pub fn retry_after(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let at = *self.full_at.lock().unwrap().get(key)?;
+        wait(self.limit(), at.max(now), now)
+    }
+}
+
+/// Wait under `limit` before the bucket that is full at `at` has an
+/// attempt left.
+fn wait(limit: RateLimit, at: Instant, now: Instant) -> Option<Duration> {
+    let allowed = limit.period.saturating_sub(limit.interval());
+    (at - now)
+        .checked_sub(allowed)
+        .filter(|wait| !wait.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_per_address() {
+        let limiter = RateLimiter::new(RateLimit::new(3, Duration::from_secs(60)));
+        for _ in 0..3 {
+            assert_eq!(limiter.retry_after("10.0.0.1"), None);
+            limiter.check("10.0.0.1").unwrap();
+        }
+        let wait = limiter.check("10.0.0.1").unwrap_err();
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20));
+        assert!(limiter.retry_after("10.0.0.1").is_some());
+        limiter.check("10.0.0.2").unwrap();
+
+        let shared = limiter.clone();
+        assert!(shared.check("10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn refills_over_the_period() {
+        let limiter = RateLimiter::new(RateLimit::new(2, Duration::from_millis(100)));
+        limiter.check("a").unwrap();
+        limiter.check("a").unwrap();
+        assert!(limiter.check("a").is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        limiter.check("a").unwrap();
+        assert!(limiter.check("a").is_err());
+    }
+
+    #[test]
+    fn changes_limit_for_every_clone() {
+        let limiter = RateLimiter::new(RateLimit::new(1, Duration::from_secs(60)));
+        let shared = limiter.clone();
+        limiter.check("a").unwrap();
+        assert!(shared.check("a").is_err());
+        shared.set_limit(RateLimit::new(3, Duration::from_secs(180)));
+        assert_eq!(limiter.limit().burst, 3);
+        // The attempt already taken counts against the new limit.
+        limiter.check("a").unwrap();
+        limiter.check("a").unwrap();
+        assert!(limiter.check("a").is_err());
+    }
+}
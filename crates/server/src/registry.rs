@@ -0,0 +1,261 @@
+//! Sessions running on the server, so other connections can find them.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ghostwriter_proto::{ErrorCode, ErrorMsg, Frame, SessionInfo};
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::session::{SessionCmd, SessionEvent, SessionHandle};
+
+/// Identifies a session for the lifetime of the server.
+pub type SessionId = u64;
+
+/// Live sessions by id, shared by every connection of a server. Entries
+/// hold no strong handles unless detached, so a session still ends once
+/// its editor leaves normally.
+#[derive(Debug, Clone, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<Sessions>>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    next_id: SessionId,
+    /// Bumped on every detach, so an expiry only drops the detach it was
+    /// scheduled for.
+    detach_count: u64,
+    live: BTreeMap<SessionId, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    cmd: mpsc::WeakSender<SessionCmd>,
+    followers: broadcast::WeakSender<Frame>,
+    path: watch::Receiver<String>,
+    /// Identity of the client that opened the session; see [`register`].
+    ///
+    /// [`register`]: SessionRegistry::register
+    owner: Option<String>,
+    detached: Option<(u64, Detached)>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        self.cmd.strong_count() > 0
+    }
+
+    /// Whether a client authenticated as `owner`, which can read the files
+    /// `reaches` accepts, may see this session.
+    fn is_visible(&self, owner: Option<&str>, reaches: &impl Fn(&str) -> bool) -> bool {
+        self.owner.as_deref() == owner && reaches(&self.path.borrow())
+    }
+}
+
+/// The channels of a session whose client went away, keeping it running
+/// until a client attaches again.
+#[derive(Debug)]
+pub struct Detached {
+    pub cmd: mpsc::Sender<SessionCmd>,
+    pub frames: mpsc::Receiver<Frame>,
+    pub events: mpsc::Receiver<SessionEvent>,
+}
+
+/// A session watched by a follower.
+#[derive(Debug)]
+pub struct Followed {
+    pub id: SessionId,
+    /// Commands to the session, weak so following does not keep it alive.
+    pub cmd: mpsc::WeakSender<SessionCmd>,
+    /// Every frame the session renders.
+    pub frames: broadcast::Receiver<Frame>,
+}
+
+impl SessionRegistry {
+    /// Record a freshly opened session and return its id. `owner` is the
+    /// identity its client authenticated as, a certificate subject or a
+    /// token; `None` for the shared secret. Only clients with the same
+    /// owner may list and attach to it.
+    pub fn register(&self, handle: &SessionHandle, owner: Option<String>) -> SessionId {
+        let mut sessions = self.inner.lock().unwrap();
+        let id = sessions.next_id;
+        sessions.next_id += 1;
+        let entry = Entry {
+            cmd: handle.cmd.downgrade(),
+            followers: handle.followers.downgrade(),
+            path: handle.path.clone(),
+            owner,
+            detached: None,
+        };
+        sessions.live.insert(id, entry);
+        id
+    }
+
+    /// Start following the most recently opened session that is still
+    /// running on a file `reaches` accepts.
+    pub fn follow_latest(&self, reaches: impl Fn(&str) -> bool) -> Option<Followed> {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.live.retain(|_, e| e.is_live());
+        let (&id, entry) = (sessions.live.iter())
+            .rev()
+            .find(|(_, entry)| reaches(&entry.path.borrow()))?;
+        Some(Followed {
+            id,
+            cmd: entry.cmd.clone(),
+            frames: entry.followers.upgrade()?.subscribe(),
+        })
+    }
+
+    /// Running sessions of `owner` on files `reaches` accepts, by their
+    /// workspace-relative path, oldest first.
+    pub fn list(&self, owner: Option<&str>, reaches: impl Fn(&str) -> bool) -> Vec<SessionInfo> {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.live.retain(|_, e| e.is_live());
+        (sessions.live.iter())
+            .filter(|(_, entry)| entry.is_visible(owner, &reaches))
+            .map(|(&id, entry)| SessionInfo {
+                id,
+                path: entry.path.borrow().clone(),
+                attached: entry.detached.is_none(),
+            })
+            .collect()
+    }
+
+    /// Keep session `id` running without a client for up to `ttl`, after
+    /// which it saves and ends unless a client attached to it.
+    pub fn detach(&self, id: SessionId, detached: Detached, ttl: Duration) {
+        let mut sessions = self.inner.lock().unwrap();
+        sessions.detach_count += 1;
+        let count = sessions.detach_count;
+        let Some(entry) = sessions.live.get_mut(&id) else {
+            return;
+        };
+        entry.detached = Some((count, detached));
+        let registry = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            let expired = registry.take_detached(
+                id,
+                |entry| matches!(entry.detached, Some((detach, _)) if detach == count),
+            );
+            drop(expired);
+        });
+    }
+
+    /// Take over detached session `id` for `owner`, who must be able to
+    /// [`list`](Self::list) it.
+    pub fn attach(
+        &self,
+        id: SessionId,
+        owner: Option<&str>,
+        reaches: impl Fn(&str) -> bool,
+    ) -> Result<Detached, ErrorMsg> {
+        let visible = |entry: &Entry| entry.is_visible(owner, &reaches);
+        if let Some(detached) = self.take_detached(id, visible) {
+            return Ok(detached);
+        }
+        let sessions = self.inner.lock().unwrap();
+        match sessions.live.get(&id) {
+            Some(entry) if entry.is_live() && visible(entry) => Err(ErrorMsg::new(
+                ErrorCode::Conflict,
+                format!("session {id} has a client"),
+            )),
+            _ => Err(ErrorMsg::new(
+                ErrorCode::NotFound,
+                format!("no session {id}"),
+            )),
+        }
+    }
+
+    /// End every detached session, waiting until each has saved.
+    pub async fn close_detached(&self) {
+        let detached: Vec<_> = {
+            let mut sessions = self.inner.lock().unwrap();
+            sessions
+                .live
+                .values_mut()
+                .filter_map(|e| e.detached.take())
+                .map(|(_, d)| d)
+                .collect()
+        };
+        for Detached {
+            cmd, mut frames, ..
+        } in detached
+        {
+            drop(cmd);
+            // The session drops its frame sender once it has saved.
+            while frames.recv().await.is_some() {}
+        }
+    }
+
+    /// The channels of session `id` if it is detached and `accepts` it.
+    fn take_detached(&self, id: SessionId, accepts: impl Fn(&Entry) -> bool) -> Option<Detached> {
+        let mut sessions = self.inner.lock().unwrap();
+        let entry = sessions.live.get_mut(&id)?;
+        if !accepts(entry) {
+            return None;
+        }
+        entry.detached.take().map(|(_, d)| d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostwriter_core::RopeBuffer;
+
+    #[tokio::test]
+    async fn detached_sessions_can_be_attached_once() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let registry = SessionRegistry::default();
+        let handle = crate::session::spawn(
+            RopeBuffer::from_text("hi"),
+            file.path().to_path_buf(),
+            80,
+            24,
+        );
+        let id = registry.register(&handle, Some("ada".into()));
+        let anywhere = |_: &str| true;
+        let attach = |id, owner| registry.attach(id, owner, anywhere).map(drop);
+        assert_eq!(
+            attach(id, Some("ada")).unwrap_err().code,
+            ErrorCode::Conflict
+        );
+        assert_eq!(
+            attach(id + 1, Some("ada")).unwrap_err().code,
+            ErrorCode::NotFound
+        );
+
+        let SessionHandle {
+            cmd,
+            frames,
+            events,
+            ..
+        } = handle;
+        let detached = Detached {
+            cmd,
+            frames,
+            events,
+        };
+        registry.detach(id, detached, Duration::from_secs(60));
+        assert!(!registry.list(Some("ada"), anywhere)[0].attached);
+
+        // Other owners, and clients that cannot read the file, neither see
+        // nor take it.
+        assert!(registry.list(None, anywhere).is_empty());
+        assert!(registry.list(Some("ada"), |_| false).is_empty());
+        assert_eq!(attach(id, None).unwrap_err().code, ErrorCode::NotFound);
+        let elsewhere = registry.attach(id, Some("ada"), |_| false);
+        assert_eq!(elsewhere.unwrap_err().code, ErrorCode::NotFound);
+
+        let detached = registry.attach(id, Some("ada"), anywhere).unwrap();
+        assert!(registry.list(Some("ada"), anywhere)[0].attached);
+
+        // Once its last handle is gone the session drops off the list.
+        drop(detached);
+        assert!(registry.list(Some("ada"), anywhere).is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Kernel-enforced confinement of the server process on Linux, as a second
+//! line of defence behind [`Workspace::resolve`](crate::workspace::Workspace::resolve):
+//! Landlock limits the files it can reach and a seccomp filter refuses
+//! syscalls a file server never needs.
+
+use std::{
+    fs, io, mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+
+/// `LANDLOCK_ACCESS_FS_*` rights of Landlock ABI 1.
+const FS_EXECUTE: u64 = 1 << 0;
+const FS_WRITE_FILE: u64 = 1 << 1;
+const FS_READ_FILE: u64 = 1 << 2;
+const FS_READ_DIR: u64 = 1 << 3;
+const FS_MAKE_CHAR: u64 = 1 << 6;
+const FS_MAKE_BLOCK: u64 = 1 << 11;
+const FS_ABI_1: u64 = (1 << 13) - 1;
+/// Moving files between directories, from ABI 2.
+const FS_REFER: u64 = 1 << 13;
+/// Truncating files, from ABI 3.
+const FS_TRUNCATE: u64 = 1 << 14;
+/// Rights that apply to a file rather than a directory's children.
+const FS_FILE: u64 = FS_EXECUTE | FS_WRITE_FILE | FS_READ_FILE | FS_TRUNCATE;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+/// Syscalls refused with `EPERM`: running programs, inspecting other
+/// processes, and changing mounts, namespaces or the kernel.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_userfaultfd,
+    libc::SYS_personality,
+];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Paths the server may still use once sandboxed. Anything else on disk
+/// becomes unreachable, except through files that were already open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sandbox {
+    writable: Vec<PathBuf>,
+    readable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Allow reading and writing the workspace at `root` and the temporary
+    /// directory. Write-ahead logs live inside the workspace.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            writable: vec![root.into(), std::env::temp_dir()],
+            readable: Vec::new(),
+        }
+    }
+
+    /// Also allow reading and writing `path`, e.g. an audit log.
+    pub fn allow_write<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.writable.push(path.into());
+        self
+    }
+
+    /// Also allow reading `path`, e.g. a token file that is reloaded.
+    pub fn allow_read<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.readable.push(path.into());
+        self
+    }
+
+    /// Confine the process. Landlock only restricts the calling thread and
+    /// threads it starts later, so call this before starting the runtime;
+    /// the seccomp filter covers every thread. Returns whether Landlock is
+    /// enforced: kernels without it only get the seccomp filter.
+    pub fn enter(&self) -> io::Result<bool> {
+        let landlocked = self.landlock()?;
+        seccomp()?;
+        Ok(landlocked)
+    }
+
+    fn landlock(&self) -> io::Result<bool> {
+        // SAFETY: a null attribute with the version flag only queries the ABI.
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                ptr::null::<RulesetAttr>(),
+                0usize,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Ok(false);
+        }
+        let mut handled = FS_ABI_1;
+        if abi >= 2 {
+            handled |= FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= FS_TRUNCATE;
+        }
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: `attr` outlives the call, which copies it.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel just returned this descriptor to us.
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+        let write = handled & !(FS_EXECUTE | FS_MAKE_CHAR | FS_MAKE_BLOCK);
+        let read = FS_READ_FILE | FS_READ_DIR;
+        let rules = (self.writable.iter().map(|p| (p, write)))
+            .chain(self.readable.iter().map(|p| (p, read)));
+        for (path, access) in rules {
+            add_rule(&ruleset, path, access).map_err(|e| {
+                io::Error::new(e.kind(), format!("sandbox {}: {e}", path.display()))
+            })?;
+        }
+
+        // SAFETY: plain prctl and syscall calls with valid arguments.
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Grant `access` beneath `path`, or to `path` itself if it is a file.
+fn add_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)?;
+    let access = if file.metadata()?.is_dir() {
+        access
+    } else {
+        access & FS_FILE
+    };
+    let attr = PathBeneathAttr {
+        allowed_access: access,
+        parent_fd: file.as_raw_fd(),
+    };
+    // SAFETY: `attr` outlives the call and `file` keeps its descriptor open.
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr,
+            0u32,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Refuse [`DENIED_SYSCALLS`] in every thread of the process.
+fn seccomp() -> io::Result<()> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(io::Error::other)?;
+    let rules = DENIED_SYSCALLS
+        .iter()
+        // `c_long` is narrower than `i64` on 32-bit targets.
+        .map(|&nr| {
+            #[allow(clippy::unnecessary_cast)]
+            (nr as i64, Vec::new())
+        })
+        .collect();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .map_err(io::Error::other)?;
+    let program: BpfProgram = filter.try_into().map_err(io::Error::other)?;
+    seccompiler::apply_filter_all_threads(&program).map_err(io::Error::other)
+}
@@ -0,0 +1,353 @@
+//! Searching the contents of workspace files. Matches are streamed in
+//! chunks so clients can show them while the walk continues.
+//!
+//! Like ripgrep, the walk skips whatever `.gitignore` and `.ignore` files
+//! exclude, plus the server's exclude globs, unless the request asks for
+//! everything. With a [`SearchIndex`], literal searches only read the files
+//! that can contain the pattern.
+
+use std::{fs, path::PathBuf, time::Instant};
+
+use ghostwriter_proto::{ErrorCode, ErrorMsg, FileMatch, Range, SearchRequest, SearchResultChunk};
+use ignore::{WalkBuilder, overrides::OverrideBuilder};
+use regex::{Regex, RegexBuilder};
+use tokio::sync::mpsc;
+
+use crate::index::SearchIndex;
+use crate::workspace::{IGNORED, Workspace};
+
+/// Matches collected before a chunk is sent.
+const CHUNK_SIZE: usize = 64;
+
+/// Chunks a search may have queued before it waits for the client.
+const CHUNK_BACKLOG: usize = 4;
+
+/// Start searching `workspace` for `req` on a blocking thread, leaving out
+/// paths matching the gitignore-style `exclude` globs. `index` narrows the
+/// files read when it can. Dropping the receiver cancels the search.
+pub fn spawn(
+    workspace: Workspace,
+    exclude: &[String],
+    index: Option<&SearchIndex>,
+    req: SearchRequest,
+) -> Result<mpsc::Receiver<SearchResultChunk>, ErrorMsg> {
+    let re = matcher(&req)?;
+    let walk = walker(&workspace, exclude, req.no_ignore)?;
+    let files: Box<dyn Iterator<Item = PathBuf> + Send> = match index
+        .and_then(|index| index.candidates(&req))
+    {
+        Some(candidates) => {
+            let readable: Vec<PathBuf> = workspace.readable_roots().map(PathBuf::from).collect();
+            Box::new(
+                (candidates.into_iter())
+                    .filter(move |path| readable.iter().any(|root| path.starts_with(root))),
+            )
+        }
+        None => Box::new(files(walk)),
+    };
+    let (tx, rx) = mpsc::channel(CHUNK_BACKLOG);
+    let started = Instant::now();
+    tokio::task::spawn_blocking(move || {
+        let limit = match req.limit {
+            0 => usize::MAX,
+            n => n as usize,
+        };
+        let mut search = Search {
+            pattern: req.pattern,
+            tx,
+            pending: Vec::new(),
+            found: 0,
+            limit,
+            whole_word: req.whole_word,
+        };
+        search.run(&workspace, files, &re);
+        workspace.metrics().observe_search(started.elapsed());
+    });
+    Ok(rx)
+}
+
+/// Walk the workspace in name order, applying ignore files and `exclude`
+/// unless `no_ignore` is set. [`IGNORED`] directories are always skipped.
+pub(crate) fn walker(
+    workspace: &Workspace,
+    exclude: &[String],
+    no_ignore: bool,
+) -> Result<WalkBuilder, ErrorMsg> {
+    let mut roots = workspace.readable_roots();
+    let root = roots.next().expect("a workspace has a root");
+    let mut overrides = OverrideBuilder::new(root);
+    if !no_ignore {
+        for glob in exclude {
+            overrides
+                .add(&format!("!{glob}"))
+                .map_err(|e| ErrorMsg::new(ErrorCode::Invalid, e.to_string()))?;
+        }
+    }
+    let overrides = overrides
+        .build()
+        .map_err(|e| ErrorMsg::new(ErrorCode::Invalid, e.to_string()))?;
+    let mut walk = WalkBuilder::new(root);
+    for root in roots {
+        walk.add(root);
+    }
+    walk.standard_filters(false)
+        .git_ignore(!no_ignore)
+        .git_exclude(!no_ignore)
+        .ignore(!no_ignore)
+        // Ignore files apply whether or not the workspace is a repository,
+        // but none above its root.
+        .require_git(false)
+        .parents(false)
+        .overrides(overrides)
+        .filter_entry(|entry| !IGNORED.iter().any(|i| entry.file_name() == *i))
+        .sort_by_file_name(|a, b| a.cmp(b));
+    Ok(walk)
+}
+
+/// Regular files `walk` yields, in walk order.
+pub(crate) fn files(walk: WalkBuilder) -> impl Iterator<Item = PathBuf> {
+    walk.build()
+        .flatten()
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(ignore::DirEntry::into_path)
+}
+
+/// Compile the request's pattern, escaping it unless it is a regex.
+fn matcher(req: &SearchRequest) -> Result<Regex, ErrorMsg> {
+    if req.pattern.is_empty() {
+        return Err(ErrorMsg::new(ErrorCode::Invalid, "empty search pattern"));
+    }
+    let pattern = if req.regex {
+        req.pattern.clone()
+    } else {
+        regex::escape(&req.pattern)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!req.case)
+        .build()
+        .map_err(|e| ErrorMsg::new(ErrorCode::Invalid, e.to_string()))
+}
+
+/// Whether the match at `range` of `text` is not part of a longer word:
+/// a match starting or ending with a word character must not have another
+/// one just outside it.
+pub(crate) fn is_whole_word(text: &str, range: &std::ops::Range<usize>) -> bool {
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let (before, after) = (&text[..range.start], &text[range.end..]);
+    let matched = &text[range.clone()];
+    !(matched.chars().next().is_some_and(word) && before.chars().next_back().is_some_and(word)
+        || matched.chars().next_back().is_some_and(word) && after.chars().next().is_some_and(word))
+}
+
+struct Search {
+    pattern: String,
+    tx: mpsc::Sender<SearchResultChunk>,
+    /// Matches not sent yet.
+    pending: Vec<FileMatch>,
+    found: usize,
+    limit: usize,
+    whole_word: bool,
+}
+
+impl Search {
+    /// Search `files`, sending matches as chunks fill up and a final
+    /// chunk marked `done`. Returns early once the receiver is gone.
+    fn run(&mut self, workspace: &Workspace, files: impl Iterator<Item = PathBuf>, re: &Regex) {
+        for file in files {
+            if self.tx.is_closed() {
+                return;
+            }
+            // Binary and unreadable files are skipped.
+            let Ok(text) = fs::read_to_string(&file) else {
+                continue;
+            };
+            let path = workspace.relative(&file);
+            if !self.search_file(&path, &text, re) {
+                return;
+            }
+            if self.found >= self.limit {
+                break;
+            }
+        }
+        self.finish();
+    }
+
+    /// Collect the matches in `text`, flushing full chunks. Returns
+    /// `false` if the search was cancelled.
+    fn search_file(&mut self, path: &str, text: &str, re: &Regex) -> bool {
+        for (line, content) in text.lines().enumerate() {
+            let whole_word = self.whole_word;
+            let matches = re.find_iter(content).map(|m| m.range());
+            for m in matches.filter(|m| !whole_word || is_whole_word(content, m)) {
+                if self.found >= self.limit {
+                    return true;
+                }
+                self.found += 1;
+                self.pending.push(FileMatch {
+                    path: path.to_string(),
+                    line: line as u64,
+                    text: content.to_string(),
+                    range: Range {
+                        from: m.start as u64,
+                        to: m.end as u64,
+                    },
+                });
+                if self.pending.len() >= CHUNK_SIZE && !self.flush(false) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn finish(&mut self) {
+        self.flush(true);
+    }
+
+    /// Send the pending matches. Returns `false` if the receiver is gone.
+    fn flush(&mut self, done: bool) -> bool {
+        let chunk = SearchResultChunk {
+            pattern: self.pattern.clone(),
+            matches: std::mem::take(&mut self.pending),
+            done,
+        };
+        self.tx.blocking_send(chunk).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect(ws: &Workspace, req: SearchRequest) -> Vec<SearchResultChunk> {
+        collect_excluding(ws, &[], req).await
+    }
+
+    async fn collect_excluding(
+        ws: &Workspace,
+        exclude: &[String],
+        req: SearchRequest,
+    ) -> Vec<SearchResultChunk> {
+        let mut rx = spawn(ws.clone(), exclude, None, req).unwrap();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            let done = chunk.done;
+            chunks.push(chunk);
+            if done {
+                break;
+            }
+        }
+        chunks
+    }
+
+    fn request(pattern: &str, regex: bool, case: bool, limit: u32) -> SearchRequest {
+        SearchRequest {
+            pattern: pattern.into(),
+            regex,
+            case,
+            limit,
+            no_ignore: false,
+            whole_word: false,
+        }
+    }
+
+    fn paths(chunks: &[SearchResultChunk]) -> Vec<&str> {
+        chunks
+            .iter()
+            .flat_map(|c| &c.matches)
+            .map(|m| m.path.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streams_matches_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/.git")).unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "fn main() {}\nFN other\n").unwrap();
+        fs::write(dir.path().join("src/.git/HEAD"), "fn hidden").unwrap();
+        fs::write(dir.path().join("blob.bin"), [0xff, 0xfe, b'f', b'n']).unwrap();
+        let many = "fn\n".repeat(CHUNK_SIZE + 1);
+        fs::write(dir.path().join("many.txt"), many).unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+
+        let chunks = collect(&ws, request("fn", false, true, 0)).await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].matches.len(), CHUNK_SIZE);
+        assert!(chunks[1].done);
+        let last = chunks[1].matches.last().unwrap();
+        assert_eq!(last.path, "src/lib.rs");
+        assert_eq!((last.line, last.text.as_str()), (0, "fn main() {}"));
+        assert_eq!((last.range.from, last.range.to), (0, 2));
+
+        let chunks = collect(&ws, request(r"^FN \w+", true, true, 0)).await;
+        let matches: Vec<_> = chunks.iter().flat_map(|c| &c.matches).collect();
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].line, matches[0].range.to), (1, 8));
+
+        let watcher = crate::dirwatch::DirWatcher::new(ws.clone());
+        let index = SearchIndex::spawn(ws.clone(), &[], &watcher).unwrap();
+        while !index.is_ready() {
+            tokio::task::yield_now().await;
+        }
+        let mut rx = spawn(
+            ws.clone(),
+            &[],
+            Some(&index),
+            request("main", false, true, 0),
+        )
+        .unwrap();
+        let chunk = rx.recv().await.unwrap();
+        assert!(chunk.done);
+        assert_eq!(chunk.matches.len(), 1);
+        assert_eq!(chunk.matches[0].path, "src/lib.rs");
+
+        let mut word = request("fn", false, true, 0);
+        word.whole_word = true;
+        fs::write(dir.path().join("words.txt"), "fnord fn_x (fn) fn\n").unwrap();
+        let chunks = collect(&ws, word).await;
+        let ranges: Vec<_> = (chunks.iter().flat_map(|c| &c.matches))
+            .filter(|m| m.path == "words.txt")
+            .map(|m| (m.range.from, m.range.to))
+            .collect();
+        assert_eq!(ranges, [(12, 14), (16, 18)]);
+
+        let chunks = collect(&ws, request("fn", false, false, 3)).await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].matches.len(), 3);
+
+        assert!(spawn(ws.clone(), &[], None, request("", false, true, 0)).is_err());
+        assert!(spawn(ws, &[], None, request("(", true, true, 0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_ignored_and_excluded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in ["src", "target", "node_modules/pkg", "logs"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+            fs::write(dir.path().join(sub).join("f.txt"), "hit").unwrap();
+        }
+        fs::write(dir.path().join(".gitignore"), "target/\n").unwrap();
+        fs::write(dir.path().join("logs/.ignore"), "*.txt\n").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let exclude = ["node_modules".to_string()];
+
+        let chunks = collect_excluding(&ws, &exclude, request("hit", false, true, 0)).await;
+        assert_eq!(paths(&chunks), ["src/f.txt"]);
+
+        let mut all = request("hit", false, true, 0);
+        all.no_ignore = true;
+        let chunks = collect_excluding(&ws, &exclude, all).await;
+        assert_eq!(
+            paths(&chunks),
+            [
+                "logs/f.txt",
+                "node_modules/pkg/f.txt",
+                "src/f.txt",
+                "target/f.txt"
+            ]
+        );
+
+        let bad = ["a/{".to_string()];
+        assert!(spawn(ws, &bad, None, request("hit", false, true, 0)).is_err());
+    }
+}
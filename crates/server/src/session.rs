@@ -2,42 +2,462 @@ use std::{
     io,
     ops::Range,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
-use ghostwriter_core::{Debouncer, RopeBuffer, ViewportParams, compose_hex, compose_viewport};
-use ghostwriter_proto::Frame;
-use tokio::sync::mpsc;
+use ghostwriter_core::{
+    Debouncer, Edit, EditOp, EditRecord, Eol, FileChange, FileWatcher, HexFile, LinkState,
+    RopeBuffer, UndoStack, ViewportParams, Wal, compose_hex_window, compose_viewport, looks_binary,
+    move_cursor,
+};
+use ghostwriter_proto::{
+    Ack, BookmarkAction, BookmarkList, BufferList, ConnectionState, Copy, CursorTarget, DirList,
+    Direction, ErrorCode, ErrorMsg, ExternalChange, Frame, FrameDiff, Granularity, JumpDir,
+    LockState, Mark, PickerAction, ScrollUnit, SearchDir, SearchStatus, SelectMode, Status,
+};
+use regex::{Captures, Regex};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+use tracing::Instrument;
+
+use crate::{
+    audit::FileOp, lock::FileLock, picker::Picker, search, snippets::Snippet, workspace::Workspace,
+};
+
+/// Frames kept for a follower that falls behind before it skips ahead.
+const FOLLOWER_BACKLOG: usize = 16;
+
+/// Places the jump list keeps; older ones are forgotten.
+const MAX_JUMPS: usize = 100;
+
+/// Interval between checks for changes other processes made to the file.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Bytes of the document a search looks through at a time; the rest of a
+/// larger document is searched between commands.
+const SEARCH_CHUNK: usize = 1 << 20;
+
+/// Interval between frames showing the matches found while a search goes
+/// on.
+const SEARCH_FRAME_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Commands that can be sent to the session actor.
 pub enum SessionCmd {
-    /// Insert `text` at the current cursor position.
-    Insert { text: String },
+    /// Insert `text` at byte offset `pos`, or at the cursor when `pos` is
+    /// `None`. With more than one cursor, `text` replaces the selection at
+    /// each instead. When `seq` is set the session replies with
+    /// [`SessionEvent::Ack`].
+    Insert {
+        text: String,
+        pos: Option<usize>,
+        seq: Option<u64>,
+        /// Version `pos` was computed against; see [`SessionCmd::Delete`].
+        base_doc_v: Option<u64>,
+    },
+    /// Delete the bytes in `range`, or every selection when there is more
+    /// than one cursor. When `seq` is set the session replies
+    /// with [`SessionEvent::Ack`] on the handle's `events` channel. With
+    /// `base_doc_v` set, the edit is refused with a `Conflict` error if
+    /// the document changed since that version other than through such
+    /// edits, as `range` may then point elsewhere.
+    Delete {
+        range: Range<usize>,
+        seq: Option<u64>,
+        base_doc_v: Option<u64>,
+    },
+    /// Move every cursor, collapsing the selections unless `extend` is
+    /// set. With `block`, grow the rectangular selection by a line or a
+    /// character instead.
+    Move {
+        dir: Direction,
+        granularity: Granularity,
+        extend: bool,
+        block: bool,
+    },
+    /// Change the selection as described by `mode`; setting or clearing it
+    /// drops the cursors added with [`SessionCmd::AddCursor`].
+    Select {
+        anchor: usize,
+        head: usize,
+        mode: SelectMode,
+    },
+    /// Add a cursor above or below the others, or select the next
+    /// occurrence of the selected text.
+    AddCursor { target: CursorTarget },
+    /// Reply with the selected text as [`SessionEvent::Copy`].
+    Copy,
+    /// Scroll the viewport by `delta` lines or pages and `dx` columns.
+    Scroll {
+        delta: i64,
+        unit: ScrollUnit,
+        dx: i64,
+    },
+    /// Change the viewport size.
+    Resize { cols: u16, rows: u16 },
+    /// Move the cursor to byte `col` of a zero-based line, clamped to its
+    /// end; with `extend` the selection anchor stays, and with `block` too
+    /// the rectangle between them is selected, a cursor on each line.
+    GotoLine {
+        line: usize,
+        col: usize,
+        extend: bool,
+        block: bool,
+    },
+    /// Go back to where the cursor was before the last jump, or forward
+    /// again. Jumps are moves to a search match or line off the lines
+    /// shown, and switching files.
+    Jump { dir: JumpDir },
+    /// Toggle the bookmark on the cursor line, move to the next or previous
+    /// bookmarked line, or reply with every bookmark of the files opened as
+    /// [`SessionEvent::Bookmarks`]. Files with a write-ahead log keep their
+    /// bookmarks beside it between sessions.
+    Bookmark { action: BookmarkAction },
+    /// Select the next tab stop of the snippet expanded last, or else
+    /// replace the word before the cursor with the snippet it names for
+    /// files like this one, or else type a tab. With `back`, select the
+    /// previous tab stop instead.
+    Tab { back: bool },
+    /// Copy the lines touched by the selection below them.
+    DuplicateLine,
+    /// Delete the lines touched by the selection.
+    DeleteLine,
+    /// Revert the most recent edit.
+    Undo,
+    /// Reapply the most recently undone edit.
+    Redo,
+    /// Select the next or previous occurrence of `query`, wrapping around
+    /// at either end of the document, and highlight all of them. An empty
+    /// query ends the search. With `replacement`, the status previews what
+    /// replacing the selected match would insert. With `whole_word`, matches
+    /// inside longer words are skipped; with `in_selection`, those outside
+    /// the selection made when the search started. With `start_confirm`,
+    /// stepping through matches to ask about replacing each starts at the
+    /// selection and ends once it would come around past it.
+    /// Documents over a mebibyte are searched the lines shown
+    /// first, then a chunk at a time between commands, with frames showing
+    /// the matches found as they come.
+    Search {
+        query: String,
+        dir: SearchDir,
+        regex: bool,
+        replacement: Option<String>,
+        whole_word: bool,
+        in_selection: bool,
+        start_confirm: bool,
+    },
+    /// Replace the selection with `replacement` if it matches `query` and
+    /// select the next match, or replace every match with `all`, or those
+    /// from the selection on with `rest` too, only up to where asking
+    /// about each started. With `regex`, `$1` and
+    /// `${name}` in `replacement` insert capture groups. `whole_word` and
+    /// `in_selection` narrow the matches as for [`SessionCmd::Search`].
+    Replace {
+        query: String,
+        replacement: String,
+        regex: bool,
+        all: bool,
+        rest: bool,
+        whole_word: bool,
+        in_selection: bool,
+    },
     /// Request the current frame without modifying state.
     RequestFrame,
     /// Save the current buffer to disk immediately.
     Save,
+    /// Switch to the workspace-relative file `path`, saving the current one
+    /// and restoring the cursor and scroll position it was left at.
+    Open { path: String },
+    /// Reply with the opened files as [`SessionEvent::Buffers`].
+    ListBuffers,
+    /// Read the file again, dropping unsaved edits and the undo history.
+    Reload,
+    /// File picker request. Listings and failures are reported on the
+    /// handle's `events` channel; opening a file emits a frame.
+    Picker { action: PickerAction },
+    /// Another client took the session over; its file operations are now
+    /// made through `workspace`, tagged with that client.
+    Attached { workspace: Workspace },
+}
+
+impl SessionCmd {
+    /// Whether the command changes the document.
+    pub fn is_edit(&self) -> bool {
+        matches!(
+            self,
+            SessionCmd::Insert { .. }
+                | SessionCmd::Delete { .. }
+                | SessionCmd::DuplicateLine
+                | SessionCmd::DeleteLine
+                | SessionCmd::Undo
+                | SessionCmd::Redo
+                | SessionCmd::Tab { .. }
+        )
+    }
+
+    /// Whether the command may move the cursor without changing the
+    /// document, so that typing after it undoes on its own and the tab
+    /// stops of a snippet are left.
+    pub fn moves_cursor(&self) -> bool {
+        matches!(
+            self,
+            SessionCmd::Move { .. }
+                | SessionCmd::Select { .. }
+                | SessionCmd::AddCursor { .. }
+                | SessionCmd::GotoLine { .. }
+                | SessionCmd::Jump { .. }
+                | SessionCmd::Bookmark { .. }
+                | SessionCmd::Search { .. }
+        )
+    }
+}
+
+/// Replies from the session other than frames.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    Ack(Ack),
+    Buffers(BufferList),
+    Bookmarks(BookmarkList),
+    Copy(Copy),
+    DirList(DirList),
+    Error(ErrorMsg),
+    /// The file changed on disk; sent unprompted.
+    ExternalChange(ExternalChange),
+}
+
+/// Frame update for a remote client: a full frame or a diff against the last one sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameUpdate {
+    Full(Frame),
+    Diff(FrameDiff),
+}
+
+/// Tracks the last frame sent to a client and turns new frames into diffs.
+#[derive(Default)]
+pub struct FrameDiffer {
+    last: Option<Frame>,
+}
+
+impl FrameDiffer {
+    /// Produce the update to send for `frame`, diffing against the previous
+    /// frame when possible.
+    pub fn update(&mut self, frame: Frame) -> FrameUpdate {
+        let update = match self
+            .last
+            .as_ref()
+            .and_then(|last| FrameDiff::between(last, &frame))
+        {
+            Some(diff) => FrameUpdate::Diff(diff),
+            None => FrameUpdate::Full(frame.clone()),
+        };
+        self.last = Some(frame);
+        update
+    }
+
+    /// Forget the last frame so the next update is sent in full, e.g. after
+    /// the client reported a desync.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
 }
 
 /// Handle for interacting with a running session.
 pub struct SessionHandle {
     pub cmd: mpsc::Sender<SessionCmd>,
     pub frames: mpsc::Receiver<Frame>,
+    /// Acknowledgements, picker listings and errors.
+    pub events: mpsc::Receiver<SessionEvent>,
+    /// Copies of every frame, for clients following the session.
+    pub followers: broadcast::Sender<Frame>,
+    /// File being edited, workspace-relative when the session has one.
+    pub path: watch::Receiver<String>,
+}
+
+/// Where the cursor and viewport were in a file.
+#[derive(Clone, Copy, Default)]
+struct View {
+    anchor: usize,
+    head: usize,
+    first_line: usize,
+    hscroll: u16,
+}
+
+/// A place the cursor jumped from, in the jump list.
+#[derive(Clone)]
+struct Jump {
+    path: PathBuf,
+    view: View,
+}
+
+/// A file opened in the session, with its view as of when it was last
+/// switched away from.
+struct OpenFile {
+    path: PathBuf,
+    view: View,
+}
+
+/// Tab stops of the snippet expanded last, moved along by edits, until
+/// the last is reached or the cursor moves elsewhere.
+struct TabStops {
+    stops: Vec<Range<usize>>,
+    /// Index of the stop selected now.
+    at: usize,
+}
+
+/// A cursor besides the session's own, with its selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Caret {
+    anchor: usize,
+    head: usize,
+}
+
+impl Caret {
+    fn selection(&self) -> Range<usize> {
+        self.anchor.min(self.head)..self.anchor.max(self.head)
+    }
+}
+
+/// Corners of a rectangular selection, as zero-based lines and byte
+/// columns; the columns may lie past the end of their lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    anchor: (usize, usize),
+    head: (usize, usize),
+}
+
+/// What a [`SessionCmd::Search`] or [`SessionCmd::Replace`] looks for.
+struct Query {
+    text: String,
+    /// Whether `text` is a regex; `pattern` is it compiled either way.
+    regex: bool,
+    pattern: Regex,
+    whole_word: bool,
+    in_selection: bool,
+    /// Byte range matches are confined to with `in_selection`, or `None`
+    /// for the whole document.
+    within: Option<Range<usize>>,
+}
+
+/// The active search and where its query occurs.
+struct SearchState {
+    query: Query,
+    /// Text replacing the selected match would insert, to preview.
+    replacement: Option<String>,
+    /// Document version `matches` were found in.
+    doc_v: u64,
+    /// Matches found so far, in document order. Matches spanning lines
+    /// searched in different chunks are not found.
+    matches: Vec<Range<usize>>,
+    /// Runs of whole lines not searched yet, to search first to last.
+    unsearched: Vec<Range<usize>>,
+    /// When a frame last showed `matches`.
+    shown: Instant,
+    /// Where asking about each match to replace started, if it did.
+    confirm: Option<Confirm>,
+}
+
+/// Progress through the matches while asking about replacing each.
+#[derive(Debug, Clone, Copy)]
+struct Confirm {
+    /// Where asking started, moved along by edits.
+    origin: usize,
+    /// Whether stepping came around from the end of the document.
+    wrapped: bool,
+}
+
+impl Confirm {
+    /// Take the step to the match at `start`, having come around from the
+    /// end of the document on the way with `wraps`. Returns whether the
+    /// match is still to be asked about.
+    fn step(&mut self, start: usize, wraps: bool) -> bool {
+        if wraps && self.wrapped {
+            return false;
+        }
+        self.wrapped |= wraps;
+        !self.wrapped || start < self.origin
+    }
+
+    /// Whether a match at `start` is yet to be asked about, with the
+    /// selected one at `from`.
+    fn ahead(&self, start: usize, from: usize) -> bool {
+        if self.wrapped {
+            from <= start && start < self.origin
+        } else {
+            from <= start || start < self.origin
+        }
+    }
+}
+
+impl SearchState {
+    /// Whether all of `range` was searched.
+    fn searched(&self, range: &Range<usize>) -> bool {
+        !(self.unsearched.iter()).any(|r| r.start < range.end && range.start < r.end)
+    }
 }
 
 #[allow(dead_code)]
 struct Session {
     buffer: Arc<Mutex<RopeBuffer>>,
-    hex_bytes: Option<Vec<u8>>,
+    hex: Option<HexFile>,
     path: PathBuf,
+    /// Notices when something else writes `path`.
+    watcher: FileWatcher,
+    /// Another process changed `path` since it was loaded or last saved
+    /// on request; autosave stays off so its changes are not overwritten.
+    diverged: bool,
+    /// Root for picker actions; `None` for sessions opened on a bare file.
+    workspace: Option<Workspace>,
+    /// Write lock on `path`. Workspace sessions without it are read-only
+    /// because another session is editing the file.
+    lock: Option<FileLock>,
     doc_v: u64,
-    selection: Range<usize>,
+    /// Version of the last change not made by an edit with a `base_doc_v`.
+    /// Such edits based on an earlier version are refused.
+    changed_v: u64,
+    /// Document version most recently written to disk.
+    saved_v: Arc<AtomicU64>,
+    /// Edits not yet written to disk; `None` for hex views and buffers
+    /// without a backing file.
+    wal: Arc<Mutex<Option<LazyWal>>>,
+    /// Edits made in this session, for undo and redo.
+    undo: UndoStack,
+    search: Option<SearchState>,
+    /// Places the cursor jumped from, oldest first.
+    jumps: Vec<Jump>,
+    /// Where in `jumps` the cursor is: going back leads to the place before
+    /// it and forward to the one after. `jumps.len()` until a jump is gone
+    /// back through.
+    jump_at: usize,
+    /// Byte offsets in the bookmarked lines of the current file, in order,
+    /// moved along by edits.
+    marks: Vec<usize>,
+    tab_stops: Option<TabStops>,
+    /// Every file opened in this session, the current one included.
+    files: Vec<OpenFile>,
+    /// File picker shown instead of the editor, if any.
+    picker: Option<Picker>,
+    anchor: usize,
+    head: usize,
+    /// Cursors added besides `anchor` and `head`, moved along by edits.
+    carets: Vec<Caret>,
+    /// Rectangle the cursors were laid out in by the last block move,
+    /// until something else moves or edits them.
+    block: Option<Block>,
     debounce: Debouncer,
     cols: u16,
     rows: u16,
     first_line: usize,
     hscroll: u16,
     status: String,
+    followers: broadcast::Sender<Frame>,
+    /// Publishes `path` as shown to clients whenever it changes.
+    shown_path: watch::Sender<String>,
 }
 
 #[allow(dead_code)]
@@ -45,213 +465,4070 @@ impl Session {
     /// Open a file from `path` and spawn a session actor with the provided viewport size.
     pub fn open<P: AsRef<Path>>(path: P, cols: u16, rows: u16) -> io::Result<SessionHandle> {
         let path = path.as_ref().to_path_buf();
-        let buffer = match RopeBuffer::open(&path) {
-            Ok(b) => b,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => RopeBuffer::from_text(""),
-            Err(e) => return Err(e),
-        };
-        let hex_bytes = if buffer.has_invalid() {
-            std::fs::read(&path).ok()
-        } else {
-            None
-        };
-        Ok(Self::spawn_inner(buffer, hex_bytes, path, cols, rows))
+        let (mut buffer, hex) = load(&path)?;
+        let wal = recover(&path, &mut buffer, &hex);
+        Ok(Self::spawn_inner(buffer, hex, wal, path, None, cols, rows))
+    }
+
+    /// Open `rel` inside `workspace` and spawn a session actor that also
+    /// serves picker actions confined to the workspace.
+    pub fn open_in(
+        workspace: Workspace,
+        rel: &str,
+        cols: u16,
+        rows: u16,
+    ) -> io::Result<SessionHandle> {
+        let path = workspace.resolve(rel)?;
+        let (mut buffer, hex) = load_in(Some(&workspace), &path)?;
+        let wal = recover_in(Some(&workspace), &path, &mut buffer, &hex);
+        let lock = workspace.lock(&path);
+        let handle = Self::spawn_inner(buffer, hex, wal, path, Some((workspace, lock)), cols, rows);
+        Ok(handle)
     }
 
     /// Spawn a session actor with the provided buffer and viewport size.
     pub fn spawn(buffer: RopeBuffer, path: PathBuf, cols: u16, rows: u16) -> SessionHandle {
-        Self::spawn_inner(buffer, None, path, cols, rows)
+        Self::spawn_inner(buffer, None, None, path, None, cols, rows)
     }
 
+    /// `wal` is the open log with the document version recovered from it;
+    /// `workspace` comes with the write lock on `path`, if it was free.
     fn spawn_inner(
         buffer: RopeBuffer,
-        hex_bytes: Option<Vec<u8>>,
+        hex: Option<HexFile>,
+        wal: Option<(LazyWal, u64)>,
         path: PathBuf,
+        workspace: Option<(Workspace, Option<FileLock>)>,
         cols: u16,
         rows: u16,
     ) -> SessionHandle {
         let (cmd_tx, cmd_rx) = mpsc::channel(8);
         let (frame_tx, frame_rx) = mpsc::channel(8);
-        let session = Session {
+        let (event_tx, event_rx) = mpsc::channel(8);
+        let (followers, _) = broadcast::channel(FOLLOWER_BACKLOG);
+        let (wal, doc_v) = match wal {
+            Some((wal, doc_v)) => (Some(wal), doc_v),
+            None => (None, 0),
+        };
+        let files = vec![OpenFile {
+            path: path.clone(),
+            view: View::default(),
+        }];
+        let (workspace, lock) = workspace.unzip();
+        let shown = match &workspace {
+            Some(ws) => ws.relative(&path),
+            None => path.display().to_string(),
+        };
+        // Sessions outlive the connection that opened them, so their span
+        // stands alone.
+        let span = tracing::info_span!(parent: None, "session", path = %shown);
+        let (shown_path, path_rx) = watch::channel(shown);
+        let mut session = Session {
             buffer: Arc::new(Mutex::new(buffer)),
-            hex_bytes,
+            hex,
+            watcher: FileWatcher::new(&path),
+            diverged: false,
             path,
-            doc_v: 0,
-            selection: 0..0,
+            workspace,
+            lock: lock.flatten(),
+            doc_v,
+            changed_v: doc_v,
+            saved_v: Arc::new(AtomicU64::new(0)),
+            wal: Arc::new(Mutex::new(wal)),
+            undo: UndoStack::new(),
+            search: None,
+            jumps: Vec::new(),
+            jump_at: 0,
+            marks: Vec::new(),
+            tab_stops: None,
+            files,
+            picker: None,
+            anchor: 0,
+            head: 0,
+            carets: Vec::new(),
+            block: None,
             debounce: Debouncer::default(),
             cols,
             rows,
             first_line: 0,
             hscroll: 0,
             status: "server".into(),
+            followers: followers.clone(),
+            shown_path,
         };
-        tokio::spawn(async move {
-            session.run(cmd_rx, frame_tx).await;
-        });
+        session.load_marks();
+        session.load_undo();
+        tokio::spawn(session.run(cmd_rx, frame_tx, event_tx).instrument(span));
         SessionHandle {
             cmd: cmd_tx,
             frames: frame_rx,
+            events: event_rx,
+            followers,
+            path: path_rx,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<SessionCmd>,
+        tx: mpsc::Sender<Frame>,
+        events: mpsc::Sender<SessionEvent>,
+    ) {
+        let mut watch = tokio::time::interval(WATCH_INTERVAL);
+        watch.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                biased;
+                cmd = rx.recv() => match cmd {
+                    Some(cmd) => self.handle(cmd, &tx, &events).await,
+                    None => break,
+                },
+                _ = watch.tick() => self.check_external_change(&events).await,
+                () = std::future::ready(()), if self.searching() => self.search_more(&tx).await,
+            }
+        }
+        // Edits a refused save leaves behind stay in the WAL.
+        let _ = self.save_now();
+    }
+
+    /// Tell the client if another process changed the file.
+    async fn check_external_change(&mut self, events: &mpsc::Sender<SessionEvent>) {
+        let Some(change) = self.watcher.poll() else {
+            return;
+        };
+        self.diverged = true;
+        let change = ExternalChange {
+            path: self.display(&self.path),
+            removed: change == FileChange::Removed,
+            conflict: self.saved_v.load(Ordering::SeqCst) != self.doc_v,
+        };
+        let _ = events.send(SessionEvent::ExternalChange(change)).await;
+    }
+
+    /// Read the file again, dropping unsaved edits and the undo history.
+    fn reload(&mut self) -> io::Result<()> {
+        let (mut buffer, hex) = load_in(self.workspace.as_ref(), &self.path)?;
+        self.debounce.cancel();
+        if let Some(wal) = self.wal.lock().unwrap().as_mut() {
+            wal.compact();
         }
+        let wal = recover_in(self.workspace.as_ref(), &self.path, &mut buffer, &hex);
+        let wal = wal.map(|(wal, _)| wal);
+        let view = self.view();
+        let marks = self.marked_lines();
+        self.buffer = Arc::new(Mutex::new(buffer));
+        self.hex = hex;
+        self.wal = Arc::new(Mutex::new(wal));
+        self.undo = UndoStack::new();
+        self.tab_stops = None;
+        self.set_marks(&marks);
+        self.doc_v += 1;
+        self.changed_v = self.doc_v;
+        self.saved_v = Arc::new(AtomicU64::new(self.doc_v));
+        self.diverged = false;
+        self.restore_view(view);
+        Ok(())
     }
 
-    async fn run(mut self, mut rx: mpsc::Receiver<SessionCmd>, tx: mpsc::Sender<Frame>) {
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                SessionCmd::Insert { text } => {
-                    if self.hex_bytes.is_none() {
-                        let pos = self.selection.end;
-                        {
-                            let mut buf = self.buffer.lock().unwrap();
-                            buf.insert(pos, &text);
+    async fn handle(
+        &mut self,
+        cmd: SessionCmd,
+        tx: &mpsc::Sender<Frame>,
+        events: &mpsc::Sender<SessionEvent>,
+    ) {
+        if cmd.moves_cursor() {
+            self.undo.break_group();
+            self.tab_stops = None;
+        }
+        match cmd {
+            SessionCmd::Insert {
+                text,
+                pos,
+                seq,
+                base_doc_v,
+            } => {
+                if self.writable() {
+                    let checked = (self.check_base(base_doc_v, seq))
+                        .and_then(|()| self.check_growth(text.len()));
+                    if let Err(err) = checked {
+                        let _ = events.send(SessionEvent::Error(err)).await;
+                        return;
+                    }
+                    if !self.carets.is_empty() {
+                        let extra = text.len() * self.carets.len();
+                        if let Err(err) = self.check_growth(text.len() + extra) {
+                            let _ = events.send(SessionEvent::Error(err)).await;
+                            return;
+                        }
+                        self.edit_based(base_doc_v, |s| s.edit_at_cursors(&text));
+                    } else {
+                        let pos = match pos {
+                            Some(pos) => self.buffer.lock().unwrap().floor_char_boundary(pos),
+                            None => self.head,
+                        };
+                        self.apply_based(
+                            EditOp::Insert {
+                                idx: pos as u64,
+                                bytes: text.clone().into_bytes(),
+                            },
+                            base_doc_v,
+                        );
+                        self.set_cursor(pos + text.len());
+                    }
+                    if let Some(seq) = seq {
+                        let _ = events
+                            .send(SessionEvent::Ack(Ack {
+                                seq,
+                                doc_v: self.doc_v,
+                            }))
+                            .await;
+                    }
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::Delete {
+                range,
+                seq,
+                base_doc_v,
+            } => {
+                if self.writable() {
+                    if let Err(err) = self.check_base(base_doc_v, seq) {
+                        let _ = events.send(SessionEvent::Error(err)).await;
+                        return;
+                    }
+                    let range = {
+                        let buf = self.buffer.lock().unwrap();
+                        let start = buf.floor_char_boundary(range.start);
+                        start..buf.floor_char_boundary(range.end.max(range.start))
+                    };
+                    if !self.carets.is_empty() {
+                        self.edit_based(base_doc_v, |s| s.edit_at_cursors(""));
+                    } else if !range.is_empty() {
+                        self.apply_based(
+                            EditOp::Delete {
+                                range: range.start as u64..range.end as u64,
+                            },
+                            base_doc_v,
+                        );
+                        self.set_cursor(range.start);
+                    }
+                    if let Some(seq) = seq {
+                        let _ = events
+                            .send(SessionEvent::Ack(Ack {
+                                seq,
+                                doc_v: self.doc_v,
+                            }))
+                            .await;
+                    }
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::Move {
+                dir,
+                granularity,
+                extend,
+                block,
+            } => {
+                if self.hex.is_none() && block {
+                    self.move_block(dir);
+                } else if self.hex.is_none() {
+                    self.block = None;
+                    let (pos, moved) = {
+                        let buf = self.buffer.lock().unwrap();
+                        let rows = self.rows as usize;
+                        let moved: Vec<usize> = (self.carets.iter())
+                            .map(|c| move_cursor(&buf, c.head, dir, granularity, rows))
+                            .collect();
+                        (move_cursor(&buf, self.head, dir, granularity, rows), moved)
+                    };
+                    for (caret, pos) in self.carets.iter_mut().zip(moved) {
+                        caret.head = pos;
+                        if !extend {
+                            caret.anchor = pos;
                         }
-                        let new_pos = pos + text.len();
-                        self.selection = new_pos..new_pos;
-                        self.doc_v += 1;
-                        let buffer = Arc::clone(&self.buffer);
-                        let path = self.path.clone();
-                        self.debounce.call(move || {
-                            if let Ok(buf) = buffer.lock() {
-                                let _ = buf.save_to(&path);
-                            }
-                        });
-                        self.emit_frame(&tx).await;
                     }
+                    if extend {
+                        self.head = pos;
+                        self.scroll_to_cursor();
+                    } else {
+                        self.set_cursor(pos);
+                    }
+                    self.merge_carets();
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Select { anchor, head, mode } => {
+                if self.hex.is_none() {
+                    let buf = self.buffer.lock().unwrap();
+                    let anchor = buf.floor_char_boundary(anchor);
+                    let head = buf.floor_char_boundary(head);
+                    drop(buf);
+                    match mode {
+                        SelectMode::Set => (self.anchor, self.head) = (anchor, head),
+                        SelectMode::Extend => self.head = head,
+                        SelectMode::Clear => self.anchor = self.head,
+                    }
+                    if mode != SelectMode::Extend {
+                        self.carets.clear();
+                    }
+                    self.block = None;
+                    self.scroll_to_cursor();
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::AddCursor { target } => {
+                if self.hex.is_none() {
+                    self.block = None;
+                    match target {
+                        CursorTarget::Above => self.add_cursor(Direction::Up),
+                        CursorTarget::Below => self.add_cursor(Direction::Down),
+                        CursorTarget::NextMatch => self.add_next_match(),
+                    }
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Copy => {
+                let text = match &self.hex {
+                    Some(_) => String::new(),
+                    None => self.selected_text(),
+                };
+                let _ = events.send(SessionEvent::Copy(Copy { text })).await;
+            }
+            SessionCmd::Scroll { delta, unit, dx } => {
+                let lines = match unit {
+                    ScrollUnit::Line => delta,
+                    ScrollUnit::Page => delta.saturating_mul(self.rows.max(1) as i64),
+                };
+                let first = (self.first_line as i64).saturating_add(lines).max(0);
+                self.first_line = first as usize;
+                let hscroll = (self.hscroll as i64).saturating_add(dx);
+                self.hscroll = hscroll.clamp(0, u16::MAX as i64) as u16;
+                self.clamp_viewport();
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Resize { cols, rows } => {
+                self.cols = cols;
+                self.rows = rows;
+                self.scroll_to_cursor();
+                self.clamp_viewport();
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::GotoLine {
+                line,
+                col,
+                extend,
+                block,
+            } => {
+                if self.hex.is_none() && extend && block {
+                    let line = line.min(self.total_lines().saturating_sub(1));
+                    let mut corners = self.block.unwrap_or_else(|| self.block_corners());
+                    corners.head = (line, col);
+                    self.select_block(corners);
+                } else if self.hex.is_none() {
+                    self.block = None;
+                    if !extend {
+                        self.carets.clear();
+                    }
+                    let pos = {
+                        let buf = self.buffer.lock().unwrap();
+                        let line = line.min(buf.len_lines().saturating_sub(1));
+                        let col = col.min(buf.line_len(line));
+                        buf.floor_char_boundary(buf.line_col_to_byte(line, col))
+                    };
+                    if extend {
+                        self.head = pos;
+                        self.scroll_to_cursor();
+                    } else {
+                        self.jump_to(pos);
+                        self.set_cursor(pos);
+                    }
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Jump { dir } => {
+                if let Err(err) = self.jump(dir) {
+                    let _ = events.send(SessionEvent::Error(err)).await;
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Bookmark { action } => {
+                if action == BookmarkAction::List {
+                    let list = self.bookmark_list();
+                    let _ = events.send(SessionEvent::Bookmarks(list)).await;
+                    return;
+                }
+                if self.hex.is_none() {
+                    match action {
+                        BookmarkAction::Toggle => self.toggle_mark(),
+                        BookmarkAction::Next => self.next_mark(Direction::Down),
+                        _ => self.next_mark(Direction::Up),
+                    }
+                }
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Tab { back } => {
+                if self.writable() {
+                    if let Err(err) = self.tab(back) {
+                        let _ = events.send(SessionEvent::Error(err)).await;
+                        return;
+                    }
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::DuplicateLine => {
+                if self.writable() {
+                    // The copy may gain a newline.
+                    if let Err(err) = self.check_growth(self.selected_lines().len() + 1) {
+                        let _ = events.send(SessionEvent::Error(err)).await;
+                        return;
+                    }
+                    self.duplicate_lines();
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::DeleteLine => {
+                if self.writable() {
+                    self.delete_lines();
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::Undo | SessionCmd::Redo => {
+                if self.writable() {
+                    match cmd {
+                        SessionCmd::Undo => self.undo(),
+                        _ => self.redo(),
+                    }
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
                 }
-                SessionCmd::RequestFrame => {
-                    self.emit_frame(&tx).await;
+            }
+            SessionCmd::Search {
+                query,
+                dir,
+                regex,
+                replacement,
+                whole_word,
+                in_selection,
+                start_confirm,
+            } => {
+                if self.hex.is_none() && query.is_empty() {
+                    self.search = None;
+                } else if self.hex.is_none()
+                    && let Err(err) = Query::new(query, regex, whole_word, in_selection)
+                        .and_then(|query| self.search(query, dir, replacement, start_confirm))
+                {
+                    let _ = events.send(SessionEvent::Error(err)).await;
+                    return;
                 }
-                SessionCmd::Save => {
-                    if self.hex_bytes.is_none()
-                        && let Ok(buf) = self.buffer.lock()
-                    {
-                        let _ = buf.save_to(&self.path);
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Replace {
+                query,
+                replacement,
+                regex,
+                all,
+                rest,
+                whole_word,
+                in_selection,
+            } => {
+                if self.writable() {
+                    let replaced = Query::new(query, regex, whole_word, in_selection)
+                        .and_then(|query| self.replace(query, &replacement, all, rest));
+                    if let Err(err) = replaced {
+                        let _ = events.send(SessionEvent::Error(err)).await;
+                        return;
                     }
+                    self.emit_frame(tx).await;
+                } else {
+                    self.reject_readonly(events).await;
+                }
+            }
+            SessionCmd::RequestFrame => {
+                self.emit_frame(tx).await;
+            }
+            SessionCmd::Save => {
+                if let Err(err) = self.save_now() {
+                    let _ = events.send(SessionEvent::Error(err)).await;
+                }
+            }
+            SessionCmd::Reload => match self.reload() {
+                Ok(()) => self.emit_frame(tx).await,
+                Err(err) => {
+                    let err = ErrorMsg::new(ErrorCode::Io, err.to_string())
+                        .with_path(self.display(&self.path));
+                    let _ = events.send(SessionEvent::Error(err)).await;
+                }
+            },
+            SessionCmd::Open { path } => {
+                if let Err(err) = self.switch_to(&path, tx).await {
+                    let _ = events.send(SessionEvent::Error(err)).await;
+                }
+            }
+            SessionCmd::ListBuffers => {
+                let list = self.buffer_list();
+                let _ = events.send(SessionEvent::Buffers(list)).await;
+            }
+            SessionCmd::Picker { action } => {
+                let event = match self.picker(action, tx).await {
+                    Ok(Some(list)) => SessionEvent::DirList(list),
+                    Ok(None) => return,
+                    Err(err) => SessionEvent::Error(err),
+                };
+                let _ = events.send(event).await;
+            }
+            SessionCmd::Attached { workspace } => {
+                if self.workspace.is_some() {
+                    self.workspace = Some(workspace);
                 }
             }
         }
+    }
 
-        if self.hex_bytes.is_none()
-            && let Ok(buf) = self.buffer.lock()
-        {
-            let _ = buf.save_to(&self.path);
+    fn workspace(&self) -> Result<Workspace, ErrorMsg> {
+        self.workspace
+            .clone()
+            .ok_or_else(|| ErrorMsg::new(ErrorCode::Unsupported, "session has no workspace"))
+    }
+
+    /// Switch to the workspace-relative file `rel`, saving the current one,
+    /// and remember where the cursor was for going back.
+    async fn switch_to(&mut self, rel: &str, tx: &mpsc::Sender<Frame>) -> Result<(), ErrorMsg> {
+        let here = self.here();
+        self.open_file(rel)?;
+        if self.path != here.path {
+            self.remember_jump(here);
         }
+        self.emit_frame(tx).await;
+        Ok(())
     }
 
-    async fn emit_frame(&self, tx: &mpsc::Sender<Frame>) {
-        let selections = vec![self.selection.clone()];
-        let cursors = vec![self.selection.end];
-        let params = ViewportParams {
-            selections: &selections,
-            cursors: &cursors,
-            doc_v: self.doc_v,
-            status_left: &self.status,
-            status_right: "",
+    /// Switch to the workspace-relative file `rel`, saving the current one
+    /// and restoring the view it was left with.
+    fn open_file(&mut self, rel: &str) -> Result<(), ErrorMsg> {
+        let ws = self.workspace()?;
+        let resolved = ws.resolve(rel).map_err(picker_error(rel))?;
+        self.picker = None;
+        if resolved == self.path {
+            return Ok(());
+        }
+        let (mut buffer, hex) = load_in(Some(&ws), &resolved).map_err(picker_error(rel))?;
+        self.save_now()?;
+        // Let go of the old file before locking the new one.
+        self.lock = None;
+        self.lock = ws.lock(&resolved);
+        let view = self.view();
+        if let Some(file) = self.files.iter_mut().find(|f| f.path == self.path) {
+            file.view = view;
+        }
+        let (wal, doc_v) = match recover_in(Some(&ws), &resolved, &mut buffer, &hex) {
+            Some((wal, doc_v)) => (Some(wal), doc_v),
+            None => (None, 0),
         };
-        let frame = if let Some(bytes) = &self.hex_bytes {
-            compose_hex(
-                bytes,
-                self.first_line,
-                self.cols,
-                self.rows,
-                self.doc_v,
-                &self.status,
-                "",
-            )
-        } else {
-            let buf = self.buffer.lock().unwrap();
-            compose_viewport(
-                &buf,
-                self.first_line,
-                self.cols,
-                self.rows,
-                self.hscroll,
-                params,
-            )
+        // Fresh handles so a pending debounced save of the old file
+        // cannot write to, or mark clean, the new one.
+        self.buffer = Arc::new(Mutex::new(buffer));
+        self.hex = hex;
+        self.wal = Arc::new(Mutex::new(wal));
+        self.undo = UndoStack::new();
+        self.tab_stops = None;
+        self.search = None;
+        // Clean unless the log held unsaved edits.
+        let saved_v = self.doc_v + 1;
+        self.doc_v = saved_v + doc_v;
+        self.changed_v = self.doc_v;
+        self.saved_v = Arc::new(AtomicU64::new(saved_v));
+        let view = match self.files.iter().find(|f| f.path == resolved) {
+            Some(file) => file.view,
+            None => {
+                self.files.push(OpenFile {
+                    path: resolved.clone(),
+                    view: View::default(),
+                });
+                View::default()
+            }
         };
-        let _ = tx.send(frame).await;
+        self.watcher = FileWatcher::new(&resolved);
+        self.diverged = false;
+        self.path = resolved;
+        let shown = self.display(&self.path);
+        tracing::Span::current().record("path", shown.as_str());
+        self.shown_path.send_replace(shown);
+        self.load_marks();
+        self.load_undo();
+        self.restore_view(view);
+        Ok(())
     }
-}
-
-/// Open a file from `path` and spawn a session actor.
-pub fn open<P: AsRef<Path>>(path: P, cols: u16, rows: u16) -> io::Result<SessionHandle> {
-    Session::open(path, cols, rows)
-}
 
-/// Spawn a session with the provided `buffer` for testing purposes.
-pub fn spawn(buffer: RopeBuffer, path: PathBuf, cols: u16, rows: u16) -> SessionHandle {
-    Session::spawn(buffer, path, cols, rows)
-}
+    /// The file and view the cursor is in, as a jump list entry.
+    fn here(&self) -> Jump {
+        Jump {
+            path: self.path.clone(),
+            view: self.view(),
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+    /// Add `from` to the jump list as the place a jump left, forgetting
+    /// where going forward would have led.
+    fn remember_jump(&mut self, from: Jump) {
+        self.jumps.truncate(self.jump_at);
+        let last = self.jumps.last();
+        if last.is_none_or(|last| last.path != from.path || last.view.head != from.view.head) {
+            self.jumps.push(from);
+        }
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump_at = self.jumps.len();
+    }
 
-    #[tokio::test]
-    async fn insert_emits_frame() {
-        let file = NamedTempFile::new().unwrap();
-        let mut handle =
-            Session::spawn(RopeBuffer::from_text(""), file.path().to_path_buf(), 80, 24);
-        handle
-            .cmd
-            .send(SessionCmd::Insert { text: "hi".into() })
-            .await
-            .unwrap();
-        let frame = handle.frames.recv().await.unwrap();
-        assert_eq!(frame.doc_v, 1);
-        assert_eq!(frame.lines[0].text, "hi");
-        assert_eq!(frame.cursors[0].line, 0);
-        assert_eq!(frame.cursors[0].col, 2);
+    /// Remember where the cursor is if moving it to byte `pos` leaves the
+    /// lines shown.
+    fn jump_to(&mut self, pos: usize) {
+        let line = self.buffer.lock().unwrap().byte_to_line_col(pos).0;
+        if !(self.first_line..self.first_line + self.rows as usize).contains(&line) {
+            self.remember_jump(self.here());
+        }
+    }
 
-        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
-        let frame2 = handle.frames.recv().await.unwrap();
-        assert_eq!(frame2.doc_v, 1);
-        assert_eq!(frame2.lines[0].text, "hi");
+    /// Go back to where the cursor was before the last jump, or forward
+    /// again, switching files if it was in another one.
+    fn jump(&mut self, dir: JumpDir) -> Result<(), ErrorMsg> {
+        match dir {
+            JumpDir::Back => {
+                // Remember the newest place too, for going forward to it.
+                if self.jump_at == self.jumps.len() {
+                    self.remember_jump(self.here());
+                    self.jump_at = self.jumps.len().saturating_sub(1);
+                }
+                if self.jump_at == 0 {
+                    return Ok(());
+                }
+                self.jump_at -= 1;
+            }
+            JumpDir::Forward => {
+                if self.jump_at + 1 >= self.jumps.len() {
+                    return Ok(());
+                }
+                self.jump_at += 1;
+            }
+        }
+        let Jump { path, view } = self.jumps[self.jump_at].clone();
+        if path != self.path {
+            self.open_file(&self.display(&path))?;
+        }
+        self.restore_view(view);
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn open_and_save_roundtrip() {
-        let mut file = NamedTempFile::new().unwrap();
-        write!(file, "hi").unwrap();
-        let path = file.path().to_path_buf();
-        let mut handle = open(&path, 80, 24).unwrap();
-        handle
-            .cmd
-            .send(SessionCmd::Insert {
-                text: " there".into(),
-            })
-            .await
-            .unwrap();
-        let _ = handle.frames.recv().await.unwrap();
-        handle.cmd.send(SessionCmd::Save).await.unwrap();
-        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
-        let _ = handle.frames.recv().await.unwrap();
-        let contents = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(contents, " therehi");
+    /// Zero-based lines bookmarked in the current file, in order.
+    fn marked_lines(&self) -> Vec<usize> {
+        let buf = self.buffer.lock().unwrap();
+        let mut lines: Vec<usize> = (self.marks.iter())
+            .map(|&mark| buf.byte_to_line_col(mark).0)
+            .collect();
+        // Deleting the text between two marks puts them on one line.
+        lines.dedup();
+        lines
     }
 
-    #[tokio::test]
-    async fn banner_and_save_on_exit() {
-        use tokio::time::{Duration, sleep};
+    /// Bookmark `lines` of the current file instead, leaving out those
+    /// past its end.
+    fn set_marks(&mut self, lines: &[usize]) {
+        self.marks.clear();
+        if self.hex.is_some() {
+            return;
+        }
+        let buf = self.buffer.lock().unwrap();
+        let lines = lines.iter().filter(|&&line| line < buf.len_lines());
+        self.marks = lines.map(|&line| buf.line_to_byte(line)).collect();
+    }
 
-        let file = NamedTempFile::new().unwrap();
-        let path = file.path().to_path_buf();
-        let SessionHandle { cmd, mut frames } = open(&path, 80, 24).unwrap();
+    /// Bookmark the lines kept for the current file, if any.
+    fn load_marks(&mut self) {
+        let lines = marks_path(&self.path).map_or_else(Vec::new, |path| read_marks(&path));
+        self.set_marks(&lines);
+    }
 
-        cmd.send(SessionCmd::RequestFrame).await.unwrap();
-        let frame = frames.recv().await.unwrap();
-        assert_eq!(frame.status_left, "server");
+    /// Keep the bookmarks of the current file beside its write-ahead log.
+    /// Losing them is no reason to fail a save, so errors are only logged.
+    fn save_marks(&self) {
+        if !self.writable() || self.wal.lock().unwrap().is_none() {
+            return;
+        }
+        let Some(path) = marks_path(&self.path) else {
+            return;
+        };
+        if let Err(e) = write_marks(&path, &self.marked_lines()) {
+            tracing::warn!(path = %path.display(), error = %e, "saving bookmarks failed");
+        }
+    }
 
-        cmd.send(SessionCmd::Insert { text: "hi".into() })
-            .await
-            .unwrap();
-        drop(cmd); // close channel to end session
+    /// Bookmark the cursor line, or remove its bookmark.
+    fn toggle_mark(&mut self) {
+        let buf = self.buffer.lock().unwrap();
+        let line = buf.byte_to_line_col(self.head).0;
+        let before = self.marks.len();
+        self.marks
+            .retain(|&mark| buf.byte_to_line_col(mark).0 != line);
+        if self.marks.len() == before {
+            let start = buf.line_to_byte(line);
+            let at = self.marks.partition_point(|&mark| mark < start);
+            self.marks.insert(at, start);
+        }
+        drop(buf);
+        self.save_marks();
+    }
+
+    /// Move the cursor to the start of the first bookmarked line below its
+    /// own, or above it with `Direction::Up`, wrapping around at either end
+    /// of the document.
+    fn next_mark(&mut self, dir: Direction) {
+        let lines = self.marked_lines();
+        let line = self.buffer.lock().unwrap().byte_to_line_col(self.head).0;
+        let target = match dir {
+            Direction::Up => (lines.iter().rev().find(|&&l| l < line)).or(lines.last()),
+            _ => (lines.iter().find(|&&l| l > line)).or(lines.first()),
+        };
+        let Some(&target) = target else {
+            return;
+        };
+        let pos = self.buffer.lock().unwrap().line_to_byte(target);
+        self.carets.clear();
+        self.block = None;
+        self.jump_to(pos);
+        self.set_cursor(pos);
+    }
+
+    /// Bookmarks of every file opened, the current one's as edited and the
+    /// others' as last kept.
+    fn bookmark_list(&self) -> BookmarkList {
+        let mut marks = Vec::new();
+        for file in &self.files {
+            let path = self.display(&file.path);
+            let texts: Vec<(usize, String)> = if file.path == self.path {
+                let lines = self.marked_lines();
+                let buf = self.buffer.lock().unwrap();
+                let text = |&line: &usize| buf.slice_lines(line, 1).pop().unwrap_or_default();
+                lines.iter().map(|line| (*line, text(line))).collect()
+            } else {
+                let lines = marks_path(&file.path).map_or_else(Vec::new, |p| read_marks(&p));
+                if lines.is_empty() {
+                    continue;
+                }
+                let content = std::fs::read_to_string(&file.path).unwrap_or_default();
+                let content: Vec<&str> = content.lines().collect();
+                let text = |line: usize| content.get(line).map_or("", |t| t).to_string();
+                lines.into_iter().map(|line| (line, text(line))).collect()
+            };
+            marks.extend(texts.into_iter().map(|(line, text)| Mark {
+                path: path.clone(),
+                line: line as u64,
+                text,
+            }));
+        }
+        BookmarkList { marks }
+    }
+
+    fn view(&self) -> View {
+        View {
+            anchor: self.anchor,
+            head: self.head,
+            first_line: self.first_line,
+            hscroll: self.hscroll,
+        }
+    }
+
+    /// Return to `view`, clamped to the current buffer in case the file
+    /// changed on disk since.
+    fn restore_view(&mut self, view: View) {
+        let buf = self.buffer.lock().unwrap();
+        if self.hex.is_none() {
+            self.anchor = buf.floor_char_boundary(view.anchor);
+            self.head = buf.floor_char_boundary(view.head);
+        } else {
+            (self.anchor, self.head) = (0, 0);
+        }
+        drop(buf);
+        self.carets.clear();
+        self.block = None;
+        self.first_line = view.first_line;
+        self.hscroll = view.hscroll;
+        self.clamp_viewport();
+    }
+
+    /// `path` as shown to clients: workspace-relative when possible.
+    fn display(&self, path: &Path) -> String {
+        match &self.workspace {
+            Some(ws) => ws.relative(path),
+            None => path.display().to_string(),
+        }
+    }
+
+    fn buffer_list(&self) -> BufferList {
+        BufferList {
+            paths: self.files.iter().map(|f| self.display(&f.path)).collect(),
+            active: self
+                .files
+                .iter()
+                .position(|f| f.path == self.path)
+                .unwrap_or(0) as u32,
+        }
+    }
+
+    /// Run a picker action, returning the listing to send back, if any.
+    async fn picker(
+        &mut self,
+        action: PickerAction,
+        tx: &mpsc::Sender<Frame>,
+    ) -> Result<Option<DirList>, ErrorMsg> {
+        let ws = self.workspace()?;
+        let listing = |path: &str| -> Result<Option<DirList>, ErrorMsg> {
+            Ok(Some(DirList {
+                path: path.to_string(),
+                query: None,
+                entries: ws.list_dir(path).map_err(picker_error(path))?,
+            }))
+        };
+        match action {
+            PickerAction::Open { path } => self.switch_to(&path, tx).await.map(|()| None),
+            PickerAction::Expand { path } => listing(&path),
+            PickerAction::Create { path, dir } => {
+                ws.create(&path, dir).map_err(picker_error(&path))?;
+                self.refresh_picker(&ws, tx).await;
+                listing(parent_of(&path))
+            }
+            PickerAction::Rename { from, to } => {
+                ws.rename(&from, &to).map_err(picker_error(&from))?;
+                self.refresh_picker(&ws, tx).await;
+                listing(parent_of(&to))
+            }
+            PickerAction::Delete { path } => {
+                ws.delete(&path).map_err(picker_error(&path))?;
+                self.refresh_picker(&ws, tx).await;
+                listing(parent_of(&path))
+            }
+            PickerAction::Show { path } => {
+                self.picker = Some(Picker::new(&ws, &path).map_err(picker_error(&path))?);
+                self.emit_frame(tx).await;
+                Ok(None)
+            }
+            PickerAction::Filter { query } => {
+                let picker = self.shown_picker()?;
+                let dir = picker.dir().to_string();
+                picker.set_filter(&ws, query).map_err(picker_error(&dir))?;
+                self.emit_frame(tx).await;
+                Ok(None)
+            }
+            PickerAction::MoveSelection { delta } => {
+                self.shown_picker()?.move_selection(delta.into());
+                self.emit_frame(tx).await;
+                Ok(None)
+            }
+            PickerAction::Accept => {
+                let picker = self.shown_picker()?;
+                match picker.selected().cloned() {
+                    Some(entry) if entry.is_dir => {
+                        picker
+                            .enter(&ws, &entry.path)
+                            .map_err(picker_error(&entry.path))?;
+                    }
+                    Some(entry) => return self.switch_to(&entry.path, tx).await.map(|()| None),
+                    None => {}
+                }
+                self.emit_frame(tx).await;
+                Ok(None)
+            }
+            PickerAction::Close => {
+                self.picker = None;
+                self.emit_frame(tx).await;
+                Ok(None)
+            }
+            PickerAction::Search { query, limit } => Ok(Some(DirList {
+                path: String::new(),
+                entries: ws
+                    .search(&query, limit as usize)
+                    .map_err(picker_error(""))?,
+                query: Some(query),
+            })),
+        }
+    }
+
+    fn shown_picker(&mut self) -> Result<&mut Picker, ErrorMsg> {
+        self.picker
+            .as_mut()
+            .ok_or_else(|| ErrorMsg::new(ErrorCode::Invalid, "picker is not shown"))
+    }
+
+    /// Re-list the shown picker after a file operation and redraw it.
+    async fn refresh_picker(&mut self, ws: &Workspace, tx: &mpsc::Sender<Frame>) {
+        if let Some(picker) = &mut self.picker {
+            // A failed listing keeps the old entries; the action's own reply
+            // reports what went wrong.
+            let _ = picker.refresh(ws);
+            self.emit_frame(tx).await;
+        }
+    }
+
+    /// Whether edits may change the document: it is text and, in a
+    /// workspace, this session holds its write lock.
+    fn writable(&self) -> bool {
+        self.hex.is_none() && (self.workspace.is_none() || self.lock.is_some())
+    }
+
+    /// Tell the client an edit was dropped because the document is shown as
+    /// hex, the workspace is read-only or another session is editing it.
+    async fn reject_readonly(&self, events: &mpsc::Sender<SessionEvent>) {
+        let msg = if self.hex.is_some() {
+            "binary file is read-only"
+        } else if (self.workspace.as_ref()).is_some_and(|ws| !ws.can_write(&self.path)) {
+            "workspace is read-only"
+        } else {
+            "another client is editing this file"
+        };
+        let err = ErrorMsg::new(ErrorCode::Readonly, msg)
+            .with_path(self.path.display().to_string())
+            .with_doc_v(self.doc_v);
+        let _ = events.send(SessionEvent::Error(err)).await;
+    }
+
+    /// Write the buffer to disk unless it is shown as hex. Fails if the
+    /// workspace's write limits refuse the save.
+    fn save_now(&mut self) -> Result<(), ErrorMsg> {
+        self.save_marks();
+        if self.writable() {
+            save(
+                &self.buffer,
+                &self.wal,
+                &self.watcher,
+                &self.saved_v,
+                self.doc_v,
+                true,
+                self.workspace.as_ref(),
+            )
+            .map_err(|err| err.with_doc_v(self.doc_v))?;
+            self.diverged = false;
+            self.save_undo();
+        }
+        Ok(())
+    }
+
+    /// Where the undo history of the current file is kept between
+    /// sessions, if it is.
+    fn undo_file(&self) -> Option<PathBuf> {
+        let history = self.workspace.as_ref()?.undo_history();
+        history.file(&self.path).filter(|_| self.hex.is_none())
+    }
+
+    /// Take up the undo history kept for the current file, unless it was
+    /// kept for other text than the buffer holds.
+    fn load_undo(&mut self) {
+        let Some(file) = self.undo_file() else {
+            return;
+        };
+        let buf = self.buffer.lock().unwrap();
+        match UndoStack::load(&file, &buf) {
+            Ok(Some(undo)) => self.undo = undo,
+            Ok(None) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(path = %file.display(), error = %e, "undo history unreadable"),
+        }
+    }
+
+    /// Keep the undo history of the current file for the next session on
+    /// it. Losing it is no reason to fail a save, so errors are only
+    /// logged.
+    fn save_undo(&self) {
+        let (Some(file), Some(ws)) = (self.undo_file(), &self.workspace) else {
+            return;
+        };
+        let max_size = ws.undo_history().max_size as usize;
+        let buf = self.buffer.lock().unwrap();
+        let saved = (file.parent().map_or(Ok(()), std::fs::create_dir_all))
+            .and_then(|()| self.undo.save(&file, &buf, max_size));
+        if let Err(e) = saved {
+            tracing::warn!(path = %file.display(), error = %e, "saving undo history failed");
+        }
+    }
+
+    /// Refuse an edit whose offsets were computed against `base_doc_v` if
+    /// the document has changed in other ways since.
+    fn check_base(&self, base_doc_v: Option<u64>, seq: Option<u64>) -> Result<(), ErrorMsg> {
+        match base_doc_v {
+            Some(base) if base < self.changed_v => {
+                let err =
+                    ErrorMsg::new(ErrorCode::Conflict, "the document changed under this edit")
+                        .with_path(self.display(&self.path))
+                        .with_doc_v(self.doc_v);
+                Err(match seq {
+                    Some(seq) => err.with_seq(seq),
+                    None => err,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse growing the document by `extra` bytes past the workspace's
+    /// file size limit.
+    fn check_growth(&self, extra: usize) -> Result<(), ErrorMsg> {
+        let Some(ws) = &self.workspace else {
+            return Ok(());
+        };
+        let len = self.buffer.lock().unwrap().len_bytes() + extra;
+        ws.quota().check_size(len as u64).map_err(|err| {
+            err.with_path(self.display(&self.path))
+                .with_doc_v(self.doc_v)
+        })
+    }
+
+    /// [`Self::apply`] an edit a client based on `base_doc_v`, if set. The
+    /// client accounts for its own edits, so they do not refuse its next.
+    fn apply_based(&mut self, op: EditOp, base_doc_v: Option<u64>) {
+        self.edit_based(base_doc_v, |s| s.apply(op));
+    }
+
+    /// Make an edit a client based on `base_doc_v` with `edit`; see
+    /// [`Self::apply_based`].
+    fn edit_based(&mut self, base_doc_v: Option<u64>, edit: impl FnOnce(&mut Self)) {
+        let changed_v = self.changed_v;
+        edit(self);
+        if base_doc_v.is_some() {
+            self.changed_v = changed_v;
+        }
+    }
+
+    /// Log `op` to the WAL, then apply it to the buffer and schedule a save.
+    fn apply(&mut self, op: EditOp) {
+        self.commit(vec![op], |undo, buf, ops| match &ops[0] {
+            EditOp::Insert { idx, bytes } => {
+                let idx = buf.floor_char_boundary(*idx as usize);
+                undo.insert(buf, idx, &String::from_utf8_lossy(bytes));
+            }
+            EditOp::Delete { range } => {
+                let start = buf.floor_char_boundary(range.start as usize);
+                let end = buf.floor_char_boundary(range.end as usize).max(start);
+                if start < end {
+                    undo.delete(buf, start..end);
+                }
+            }
+        });
+    }
+
+    /// Replace `range` with `text` as one edit, logged as a delete and an
+    /// insert under the same document version.
+    fn apply_replace(&mut self, range: Range<usize>, text: &str) {
+        let old = self.buffer.lock().unwrap().slice(range.clone());
+        let ops = replace_ops(range.start, &old, text);
+        self.commit(ops, |undo, buf, _| undo.replace(buf, range, text));
+    }
+
+    /// Replace each range with its text as one edit, logged under one
+    /// document version. The ranges must be ordered and not overlap.
+    fn apply_all(&mut self, edits: Vec<(Range<usize>, String)>) {
+        let ops: Vec<EditOp> = {
+            let buf = self.buffer.lock().unwrap();
+            (edits.iter().rev())
+                .flat_map(|(range, text)| replace_ops(range.start, &buf.slice(range.clone()), text))
+                .collect()
+        };
+        let (anchor, head) = (ops.iter()).fold((self.anchor, self.head), |(a, h), op| {
+            (shift(a, op), shift(h, op))
+        });
+        self.commit(ops, |undo, buf, _| undo.replace_all(buf, &edits));
+        (self.anchor, self.head) = (anchor, head);
+        self.scroll_to_cursor();
+    }
+
+    /// Replace the selection at every cursor with `text` as one edit,
+    /// leaving each cursor after its copy. Overlapping selections are
+    /// replaced once. Text of as many lines as there are cursors, like a
+    /// copied block, goes a line to each.
+    fn edit_at_cursors(&mut self, text: &str) {
+        let mut ranges: Vec<Range<usize>> = std::iter::once(self.selection())
+            .chain(self.carets.iter().map(Caret::selection))
+            .collect();
+        ranges.sort_by_key(|r| (r.start, r.end));
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let lines: Vec<&str> = text.lines().collect();
+        let spread = merged.len() > 1 && lines.len() == merged.len();
+        let edits: Vec<(Range<usize>, String)> = (merged.into_iter().enumerate())
+            .map(|(i, range)| (range, if spread { lines[i] } else { text }.to_string()))
+            .filter(|(range, text)| !range.is_empty() || !text.is_empty())
+            .collect();
+        if edits.is_empty() {
+            return;
+        }
+        self.apply_all(edits);
+        self.anchor = self.head;
+        for caret in &mut self.carets {
+            caret.anchor = caret.head;
+        }
+        self.merge_carets();
+    }
+
+    /// Add a cursor a line above the topmost cursor or below the
+    /// bottommost one, keeping its column where the line allows.
+    fn add_cursor(&mut self, dir: Direction) {
+        let heads = std::iter::once(self.head).chain(self.carets.iter().map(|c| c.head));
+        let from = match dir {
+            Direction::Up => heads.min(),
+            _ => heads.max(),
+        };
+        let Some(from) = from else {
+            return;
+        };
+        let pos = {
+            let buf = self.buffer.lock().unwrap();
+            let pos = move_cursor(&buf, from, dir, Granularity::Grapheme, 1);
+            (buf.byte_to_line_col(pos).0 != buf.byte_to_line_col(from).0).then_some(pos)
+        };
+        if let Some(pos) = pos {
+            self.carets.push(Caret {
+                anchor: pos,
+                head: pos,
+            });
+            self.scroll_to(pos);
+        }
+    }
+
+    /// Select the word at the cursor if nothing is selected, otherwise add
+    /// a cursor selecting the next occurrence of the text selected last,
+    /// wrapping around and skipping those already selected.
+    fn add_next_match(&mut self) {
+        let sel = self.selection();
+        if sel.is_empty() && self.carets.is_empty() {
+            let word = self.buffer.lock().unwrap().word_at(self.head);
+            if !word.is_empty() {
+                (self.anchor, self.head) = (word.start, word.end);
+                self.scroll_to_cursor();
+            }
+            return;
+        }
+        let last = self.carets.last().map_or(sel, Caret::selection);
+        if last.is_empty() {
+            return;
+        }
+        let text = self.buffer.lock().unwrap().text();
+        let matches: Vec<Range<usize>> = (text.match_indices(&text[last.clone()]))
+            .map(|(i, m)| i..i + m.len())
+            .collect();
+        let selected: Vec<Range<usize>> = std::iter::once(self.selection())
+            .chain(self.carets.iter().map(Caret::selection))
+            .collect();
+        let found = (matches.iter())
+            .filter(|m| m.start >= last.end)
+            .chain(&matches)
+            .find(|m| !selected.iter().any(|s| s.start < m.end && m.start < s.end));
+        if let Some(m) = found {
+            self.carets.push(Caret {
+                anchor: m.start,
+                head: m.end,
+            });
+            self.scroll_to(m.end);
+        }
+    }
+
+    /// Text of every selection, in document order and a line each when
+    /// there is more than one cursor.
+    fn selected_text(&self) -> String {
+        let mut ranges: Vec<Range<usize>> = std::iter::once(self.selection())
+            .chain(self.carets.iter().map(Caret::selection))
+            .collect();
+        ranges.sort_by_key(|r| r.start);
+        let buf = self.buffer.lock().unwrap();
+        let texts: Vec<String> = ranges.into_iter().map(|r| buf.slice(r)).collect();
+        texts.join("\n")
+    }
+
+    /// The rectangle from the selection's anchor to its head.
+    fn block_corners(&self) -> Block {
+        let buf = self.buffer.lock().unwrap();
+        Block {
+            anchor: buf.byte_to_line_col(self.anchor),
+            head: buf.byte_to_line_col(self.head),
+        }
+    }
+
+    /// Grow or shrink the rectangular selection by moving its head corner
+    /// a line up or down, or a character left or right. Columns go past
+    /// the end of the head's line up to the widest line of the block.
+    fn move_block(&mut self, dir: Direction) {
+        let mut block = self.block.unwrap_or_else(|| self.block_corners());
+        {
+            let buf = self.buffer.lock().unwrap();
+            let (line, col) = &mut block.head;
+            let start = buf.line_to_byte(*line);
+            let len = buf.line_len(*line);
+            match dir {
+                Direction::Up => *line = line.saturating_sub(1),
+                Direction::Down => *line = (*line + 1).min(buf.len_lines().saturating_sub(1)),
+                Direction::Left if *col > len => *col -= 1,
+                Direction::Left if *col > 0 => {
+                    *col = buf.grapheme_left(start + *col).map_or(0, |pos| pos - start);
+                }
+                Direction::Left => {}
+                Direction::Right if *col < len => {
+                    *col = buf
+                        .grapheme_right(start + *col)
+                        .map_or(len, |pos| pos - start);
+                }
+                Direction::Right => {
+                    let lines = block.anchor.0.min(*line)..=block.anchor.0.max(*line);
+                    let widest = lines.map(|l| buf.line_len(l)).max().unwrap_or(0);
+                    *col = (*col + 1).min(widest.max(*col));
+                }
+            }
+        }
+        self.select_block(block);
+    }
+
+    /// Select the rectangle `block`: the cursor on its head's line and a
+    /// caret on each other line, each selecting the block's columns as far
+    /// as the line reaches.
+    fn select_block(&mut self, block: Block) {
+        let buf = self.buffer.lock().unwrap();
+        let (left, right) = (
+            block.anchor.1.min(block.head.1),
+            block.anchor.1.max(block.head.1),
+        );
+        let span = |line: usize| {
+            let start = buf.line_to_byte(line);
+            let len = buf.line_len(line);
+            let from = buf.floor_char_boundary(start + left.min(len));
+            let to = buf.floor_char_boundary(start + right.min(len));
+            if block.head.1 < block.anchor.1 {
+                Caret {
+                    anchor: to,
+                    head: from,
+                }
+            } else {
+                Caret {
+                    anchor: from,
+                    head: to,
+                }
+            }
+        };
+        let lines = block.anchor.0.min(block.head.0)..=block.anchor.0.max(block.head.0);
+        let carets = lines.filter(|l| *l != block.head.0).map(span).collect();
+        let cursor = span(block.head.0);
+        drop(buf);
+        self.carets = carets;
+        (self.anchor, self.head) = (cursor.anchor, cursor.head);
+        self.block = Some(block);
+        self.scroll_to_cursor();
+    }
+
+    /// Drop carets at the same spot as the cursor or an earlier caret.
+    fn merge_carets(&mut self) {
+        let mut seen = vec![self.head];
+        self.carets.retain(|caret| {
+            let new = !seen.contains(&caret.head);
+            seen.push(caret.head);
+            new
+        });
+    }
+
+    /// Revert the most recent edit and put the cursor where it happened,
+    /// with a cursor at each spot of an edit made at several.
+    fn undo(&mut self) {
+        let Some(edit) = self.undo.peek_undo() else {
+            return;
+        };
+        let mut ops = Vec::new();
+        let heads = revert_ops(edit, &mut ops);
+        self.tab_stops = None;
+        self.commit(ops, |undo, buf, _| {
+            undo.undo(buf);
+        });
+        self.set_cursors(heads);
+    }
+
+    /// Reapply the most recently undone edit, placing cursors as
+    /// [`Self::undo`] does.
+    fn redo(&mut self) {
+        let Some(edit) = self.undo.peek_redo() else {
+            return;
+        };
+        let mut ops = Vec::new();
+        let heads = reapply_ops(edit, &mut ops);
+        self.tab_stops = None;
+        self.commit(ops, |undo, buf, _| {
+            undo.redo(buf);
+        });
+        self.set_cursors(heads);
+    }
+
+    /// Put the cursor at the first of `heads` and a caret at each other,
+    /// dropping any selection.
+    fn set_cursors(&mut self, mut heads: Vec<usize>) {
+        heads.sort_unstable();
+        heads.dedup();
+        self.block = None;
+        self.carets = (heads.iter().skip(1))
+            .map(|&head| Caret { anchor: head, head })
+            .collect();
+        self.set_cursor(heads.first().copied().unwrap_or(0));
+    }
+
+    /// Select the next or previous tab stop of the snippet expanded last,
+    /// or else expand the one named by the word before the cursor, or else
+    /// type a tab at every cursor.
+    fn tab(&mut self, back: bool) -> Result<(), ErrorMsg> {
+        if let Some(tab_stops) = &mut self.tab_stops {
+            let at = match back {
+                true => tab_stops.at.saturating_sub(1),
+                false => tab_stops.at + 1,
+            };
+            self.select_stop(at);
+            return Ok(());
+        }
+        if back {
+            return Ok(());
+        }
+        if let Some((start, snippet)) = self.snippet_at_cursor() {
+            self.check_growth(snippet.text.len())?;
+            self.expand(start, &snippet);
+        } else if self.carets.is_empty() && self.anchor == self.head {
+            self.check_growth(1)?;
+            let pos = self.head;
+            self.apply(EditOp::Insert {
+                idx: pos as u64,
+                bytes: b"\t".to_vec(),
+            });
+            self.set_cursor(pos + 1);
+        } else {
+            self.check_growth(self.carets.len() + 1)?;
+            self.edit_at_cursors("\t");
+        }
+        Ok(())
+    }
+
+    /// The snippet named by the word the only cursor ends, and where that
+    /// word starts.
+    fn snippet_at_cursor(&self) -> Option<(usize, Snippet)> {
+        let snippets = self.workspace.as_ref()?.snippets();
+        if !self.carets.is_empty() || self.anchor != self.head || self.hex.is_some() {
+            return None;
+        }
+        let buf = self.buffer.lock().unwrap();
+        let col = buf.byte_to_line_col(self.head).1;
+        let before = buf.slice(self.head - col..self.head);
+        let word = before
+            .rsplit(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()?;
+        if word.is_empty() {
+            return None;
+        }
+        let snippet = snippets.get(&self.path, word)?;
+        Some((self.head - word.len(), snippet.clone()))
+    }
+
+    /// Replace the trigger word from `start` to the cursor with `snippet`
+    /// as one edit, its lines indented like the cursor line, and select
+    /// its first tab stop.
+    fn expand(&mut self, start: usize, snippet: &Snippet) {
+        let indent = {
+            let buf = self.buffer.lock().unwrap();
+            let line = buf.byte_to_line_col(start).0;
+            let text = buf.slice(buf.line_to_byte(line)..start);
+            let len = text.len() - text.trim_start_matches([' ', '\t']).len();
+            text[..len].to_string()
+        };
+        // Each line break before an offset moves it by the indent added.
+        let at =
+            |pos: usize| start + pos + indent.len() * snippet.text[..pos].matches('\n').count();
+        let text = snippet.text.replace('\n', &format!("\n{indent}"));
+        let stops = (snippet.stops.iter())
+            .map(|stop| at(stop.start)..at(stop.end))
+            .collect();
+        self.apply_replace(start..self.head, &text);
+        self.tab_stops = Some(TabStops { stops, at: 0 });
+        self.select_stop(0);
+    }
+
+    /// Select tab stop `at`, or the last one if there are fewer, which
+    /// ends the snippet.
+    fn select_stop(&mut self, at: usize) {
+        let Some(tab_stops) = &mut self.tab_stops else {
+            return;
+        };
+        let last = tab_stops.stops.len().saturating_sub(1);
+        tab_stops.at = at.min(last);
+        let Some(stop) = tab_stops.stops.get(tab_stops.at).cloned() else {
+            return;
+        };
+        if tab_stops.at == last {
+            self.tab_stops = None;
+        }
+        self.undo.break_group();
+        (self.anchor, self.head) = (stop.start, stop.end);
+        self.scroll_to_cursor();
+    }
+
+    /// Bump the document version, log `ops` and let `edit` change the
+    /// buffer accordingly. Added cursors move along with the text.
+    fn commit(
+        &mut self,
+        ops: Vec<EditOp>,
+        edit: impl FnOnce(&mut UndoStack, &mut RopeBuffer, &[EditOp]),
+    ) {
+        self.doc_v += 1;
+        self.changed_v = self.doc_v;
+        // Hold the log while editing so a concurrent save cannot truncate
+        // a record whose edit it did not write.
+        let mut wal = self.wal.lock().unwrap();
+        let mut logged = Vec::with_capacity(ops.len());
+        for op in ops {
+            let record = EditRecord {
+                doc_v: self.doc_v,
+                op,
+            };
+            if let Some(wal) = wal.as_mut() {
+                wal.append(&record);
+            }
+            logged.push(record.op);
+        }
+        edit(&mut self.undo, &mut self.buffer.lock().unwrap(), &logged);
+        drop(wal);
+        self.block = None;
+        let (mut within, mut confirm) = match &mut self.search {
+            Some(state) => (state.query.within.as_mut(), state.confirm.as_mut()),
+            None => (None, None),
+        };
+        let jumps = self.jumps.iter_mut().filter(|jump| jump.path == self.path);
+        let mut views: Vec<&mut View> = jumps.map(|jump| &mut jump.view).collect();
+        for op in &logged {
+            for caret in &mut self.carets {
+                caret.anchor = shift(caret.anchor, op);
+                caret.head = shift(caret.head, op);
+            }
+            for view in &mut views {
+                view.anchor = shift(view.anchor, op);
+                view.head = shift(view.head, op);
+            }
+            for mark in &mut self.marks {
+                *mark = shift(*mark, op);
+            }
+            for stop in self.tab_stops.iter_mut().flat_map(|t| &mut t.stops) {
+                *stop = shift_range(stop, op);
+            }
+            if let Some(within) = &mut within {
+                **within = shift_range(within, op);
+            }
+            // Text inserted where asking started was asked about already.
+            if let Some(confirm) = &mut confirm {
+                confirm.origin = shift_range(&(confirm.origin..confirm.origin), op).start;
+            }
+        }
+        self.merge_carets();
+        self.schedule_save();
+    }
+
+    /// Byte range of the whole lines touched by the selection, including
+    /// the trailing newline.
+    fn selected_lines(&self) -> Range<usize> {
+        let buf = self.buffer.lock().unwrap();
+        let sel = self.selection();
+        let first = buf.byte_to_line_col(sel.start).0;
+        let mut last = buf.byte_to_line_col(sel.end).0;
+        // A selection ending at a line start does not include that line.
+        if last > first && buf.line_to_byte(last) == sel.end {
+            last -= 1;
+        }
+        buf.line_to_byte(first)..buf.line_to_byte(last + 1)
+    }
+
+    fn duplicate_lines(&mut self) {
+        let lines = self.selected_lines();
+        let mut text = self.buffer.lock().unwrap().slice(lines.clone());
+        let at = lines.end;
+        // The last line has no newline to copy, so add one before it.
+        if !text.ends_with('\n') {
+            text.insert(0, '\n');
+        }
+        let len = text.len();
+        self.apply(EditOp::Insert {
+            idx: at as u64,
+            bytes: text.into_bytes(),
+        });
+        // Keep the selection on the copy below.
+        self.anchor += len;
+        self.head += len;
+        self.scroll_to_cursor();
+    }
+
+    fn delete_lines(&mut self) {
+        let mut lines = self.selected_lines();
+        let len = self.buffer.lock().unwrap().len_bytes();
+        // Deleting the last line also removes the newline before it.
+        if lines.end == len && lines.start > 0 && !self.buffer_ends_with_newline() {
+            lines.start -= 1;
+        }
+        if lines.is_empty() {
+            return;
+        }
+        self.apply(EditOp::Delete {
+            range: lines.start as u64..lines.end as u64,
+        });
+        let pos = {
+            let buf = self.buffer.lock().unwrap();
+            let line = buf.byte_to_line_col(lines.start.min(buf.len_bytes())).0;
+            buf.line_to_byte(line)
+        };
+        self.set_cursor(pos);
+    }
+
+    fn buffer_ends_with_newline(&self) -> bool {
+        let buf = self.buffer.lock().unwrap();
+        let len = buf.len_bytes();
+        len > 0 && buf.slice(len - 1..len) == "\n"
+    }
+
+    /// Make `query` the active search and select the match after (or
+    /// before) the selection, wrapping around. Leaves the selection alone
+    /// when there is none, unless asking about each match to replace has
+    /// come around past where it started.
+    fn search(
+        &mut self,
+        mut query: Query,
+        dir: SearchDir,
+        replacement: Option<String>,
+        start_confirm: bool,
+    ) -> Result<(), ErrorMsg> {
+        if (self.search.as_ref()).is_none_or(|s| !s.query.same(&query)) {
+            let sel = self.selection();
+            query.within = (query.in_selection && !sel.is_empty()).then_some(sel);
+            self.search = Some(SearchState {
+                query,
+                replacement: None,
+                doc_v: self.doc_v,
+                matches: Vec::new(),
+                unsearched: Vec::new(),
+                shown: Instant::now(),
+                confirm: None,
+            });
+            self.restart_search();
+        }
+        self.refresh_search();
+        let sel = self.selection();
+        if let Some(state) = &mut self.search {
+            if start_confirm {
+                state.confirm = Some(Confirm {
+                    origin: sel.end,
+                    wrapped: false,
+                });
+            } else if replacement.is_none() {
+                state.confirm = None;
+            }
+        }
+        let found = loop {
+            let Some(state) = &mut self.search else {
+                return Ok(());
+            };
+            state.replacement = replacement.clone();
+            // A match is only the nearest once the text up to it was
+            // searched, and wrapping around needs all of it searched.
+            let (found, wraps, settled) = match dir {
+                SearchDir::Next => match state.matches.iter().find(|m| m.start >= sel.end) {
+                    Some(m) => (Some(m), false, state.searched(&(sel.end..m.start))),
+                    None => (state.matches.first(), true, state.unsearched.is_empty()),
+                },
+                SearchDir::Prev => match state.matches.iter().rfind(|m| m.end <= sel.start) {
+                    Some(m) => (Some(m), false, state.searched(&(m.end..sel.start))),
+                    None => (state.matches.last(), true, state.unsearched.is_empty()),
+                },
+            };
+            if settled {
+                let found = found.cloned();
+                if dir == SearchDir::Next
+                    && let Some(m) = &found
+                    && let Some(confirm) = &mut state.confirm
+                    && !confirm.step(m.start, wraps)
+                {
+                    state.confirm = None;
+                    self.set_cursor(sel.end);
+                    return Ok(());
+                }
+                break found;
+            }
+            self.search_some();
+        };
+        if let Some(m) = found {
+            self.jump_to(m.start);
+            self.anchor = m.start;
+            self.head = m.end;
+            self.block = None;
+            self.scroll_to_cursor();
+        }
+        Ok(())
+    }
+
+    /// Look for the active search's matches again if the document changed
+    /// since they were found.
+    fn refresh_search(&mut self) {
+        if (self.search.as_ref()).is_some_and(|state| state.doc_v != self.doc_v) {
+            self.restart_search();
+        }
+    }
+
+    /// Look for the active search's matches from scratch: in the lines
+    /// shown first, then on to the end of the document and around from its
+    /// start. The first chunk is searched right away.
+    fn restart_search(&mut self) {
+        let Some(state) = &mut self.search else {
+            return;
+        };
+        let buf = self.buffer.lock().unwrap();
+        let last = (self.first_line + self.rows as usize).min(buf.len_lines());
+        let shown = buf.line_to_byte(self.first_line.min(last))..buf.line_to_byte(last);
+        let bounds = match &state.query.within {
+            Some(within) => whole_lines(&buf, within.clone()),
+            None => 0..buf.len_bytes(),
+        };
+        drop(buf);
+        let runs = [shown.clone(), shown.end..bounds.end, 0..shown.start];
+        state.unsearched = (runs.into_iter())
+            .map(|r| r.start.max(bounds.start)..r.end.min(bounds.end))
+            .filter(|r| r.start < r.end)
+            .collect();
+        state.matches.clear();
+        state.doc_v = self.doc_v;
+        self.search_some();
+    }
+
+    /// Search up to [`SEARCH_CHUNK`] more bytes for the active search's
+    /// matches.
+    fn search_some(&mut self) {
+        let Some(state) = &mut self.search else {
+            return;
+        };
+        let buf = self.buffer.lock().unwrap();
+        let mut budget = SEARCH_CHUNK;
+        while budget > 0
+            && let Some(run) = state.unsearched.first_mut()
+        {
+            let end = whole_lines(&buf, run.start..run.end.min(run.start + budget)).end;
+            let end = end.min(run.end);
+            let found = (state.query).find_in(&buf.slice(run.start..end), run.start);
+            let at = state.matches.partition_point(|m| m.start < run.start);
+            state.matches.splice(at..at, found);
+            budget = budget.saturating_sub(end - run.start);
+            run.start = end;
+            if run.start == run.end {
+                state.unsearched.remove(0);
+            }
+        }
+    }
+
+    /// Whether the active search has text left to search.
+    fn searching(&self) -> bool {
+        (self.search.as_ref()).is_some_and(|state| !state.unsearched.is_empty())
+    }
+
+    /// Search on for the active search's matches, showing them once all
+    /// were found or [`SEARCH_FRAME_INTERVAL`] after they last were.
+    async fn search_more(&mut self, tx: &mpsc::Sender<Frame>) {
+        self.search_some();
+        let due = (self.search.as_ref())
+            .is_some_and(|state| state.shown.elapsed() >= SEARCH_FRAME_INTERVAL);
+        if due || !self.searching() {
+            self.emit_frame(tx).await;
+        }
+    }
+
+    /// Position of the selection among the active search's matches, and
+    /// what replacing it would insert.
+    fn search_status(&self) -> Option<SearchStatus> {
+        let state = self.search.as_ref()?;
+        let sel = self.selection();
+        let current = state
+            .matches
+            .iter()
+            .position(|m| *m == sel)
+            .map_or(0, |i| i + 1);
+        let preview = (state.replacement.as_deref())
+            .filter(|_| current > 0)
+            .and_then(|replacement| {
+                let buf = self.buffer.lock().unwrap();
+                let lines = whole_lines(&buf, sel.clone());
+                let text = buf.slice(lines.clone());
+                let caps =
+                    (state.query).captures_from(&text, lines.start, sel.start - lines.start)?;
+                Some(expand(&caps, replacement, state.query.regex))
+            });
+        Some(SearchStatus {
+            current: current as u64,
+            total: state.matches.len() as u64,
+            preview,
+            searching: !state.unsearched.is_empty(),
+        })
+    }
+
+    /// Replace the selection with `replacement` if it is a match of `query`
+    /// and select the next match, or replace every match as one edit with
+    /// `all`. With `in_selection`, matches are confined to the range the
+    /// same active search is, or else to the selection. Fails when the file
+    /// would grow past its size limit.
+    fn replace(
+        &mut self,
+        mut query: Query,
+        replacement: &str,
+        all: bool,
+        rest: bool,
+    ) -> Result<(), ErrorMsg> {
+        let sel = self.selection();
+        query.within = match &self.search {
+            _ if !query.in_selection => None,
+            Some(state) if state.query.same(&query) => state.query.within.clone(),
+            _ => (!sel.is_empty()).then_some(sel),
+        };
+        let text = self.buffer.lock().unwrap().text();
+        let sel = self.selection();
+        if all {
+            // Replacing the rest ends asking about each match.
+            let confirm = (self.search.as_mut()).and_then(|state| state.confirm.take());
+            // Rebuild the text from the first match to the last, so the
+            // offsets of later matches need no adjusting.
+            let mut span: Option<Range<usize>> = None;
+            let mut new = String::new();
+            let replaces = |start: usize| match confirm {
+                Some(confirm) if rest => confirm.ahead(start, sel.start),
+                _ => !rest || start >= sel.start,
+            };
+            for caps in query.captures_iter(&text) {
+                let m = caps.get(0).unwrap();
+                if !replaces(m.start()) {
+                    continue;
+                }
+                let span = span.get_or_insert(m.start()..m.start());
+                new.push_str(&text[span.end..m.start()]);
+                new.push_str(&expand(&caps, replacement, query.regex));
+                span.end = m.end();
+            }
+            let Some(span) = span else {
+                return Ok(());
+            };
+            self.check_growth(new.len().saturating_sub(span.len()))?;
+            let end = span.start + new.len();
+            self.apply_replace(span, &new);
+            self.set_cursor(end);
+            return Ok(());
+        }
+        let mut from = sel.end;
+        if let Some(caps) = query.captures_from(&text, 0, sel.start)
+            && caps.get(0).is_some_and(|m| m.range() == sel)
+        {
+            let new = expand(&caps, replacement, query.regex);
+            self.check_growth(new.len().saturating_sub(sel.len()))?;
+            from = sel.start + new.len();
+            if let Some(within) = &mut query.within {
+                within.end = (within.end + new.len()).saturating_sub(sel.len());
+            }
+            self.apply_replace(sel, &new);
+        }
+        // Look again in the edited text, from the end of the replacement.
+        let text = self.buffer.lock().unwrap().text();
+        let (next, wraps) = match query.captures_from(&text, 0, from) {
+            Some(caps) => (Some(caps), false),
+            None => (query.captures_from(&text, 0, 0), true),
+        };
+        let confirm = (self.search.as_mut()).and_then(|state| state.confirm.as_mut());
+        let next = next
+            .and_then(|caps| caps.get(0))
+            .filter(|m| confirm.is_none_or(|confirm| confirm.step(m.start(), wraps)));
+        match next {
+            Some(m) => {
+                self.anchor = m.start();
+                self.head = m.end();
+                self.scroll_to_cursor();
+            }
+            None => {
+                if let Some(state) = &mut self.search {
+                    state.confirm = None;
+                }
+                self.set_cursor(from);
+            }
+        }
+        Ok(())
+    }
+
+    /// Current selection as an ordered byte range.
+    fn selection(&self) -> Range<usize> {
+        self.anchor.min(self.head)..self.anchor.max(self.head)
+    }
+
+    /// Collapse the selection to `pos` and keep it in view.
+    fn set_cursor(&mut self, pos: usize) {
+        self.anchor = pos;
+        self.head = pos;
+        self.scroll_to_cursor();
+    }
+
+    /// Adjust `first_line` so the cursor line is inside the viewport.
+    fn scroll_to_cursor(&mut self) {
+        self.scroll_to(self.head);
+    }
+
+    /// Adjust `first_line` so the line of byte `pos` is inside the
+    /// viewport.
+    fn scroll_to(&mut self, pos: usize) {
+        let (line, _) = self.buffer.lock().unwrap().byte_to_line_col(pos);
+        let rows = (self.rows as usize).max(1);
+        if line < self.first_line {
+            self.first_line = line;
+        } else if line >= self.first_line + rows {
+            self.first_line = line + 1 - rows;
+        }
+    }
+
+    /// Keep the viewport inside the document: the first line must exist and
+    /// horizontal scrolling stops once the widest visible line fits, with a
+    /// column left for a cursor after its end.
+    fn clamp_viewport(&mut self) {
+        self.first_line = self.first_line.min(self.total_lines().saturating_sub(1));
+        let widest = match &self.hex {
+            Some(_) => 0,
+            None => self
+                .buffer
+                .lock()
+                .unwrap()
+                .slice_lines(self.first_line, self.rows as usize)
+                .iter()
+                .map(String::len)
+                .max()
+                .unwrap_or(0),
+        };
+        let max = (widest + 1)
+            .saturating_sub(self.cols as usize)
+            .min(u16::MAX as usize);
+        self.hscroll = self.hscroll.min(max as u16);
+    }
+
+    fn total_lines(&self) -> usize {
+        match &self.hex {
+            Some(hex) => hex.rows(),
+            None => self.buffer.lock().unwrap().len_lines(),
+        }
+    }
+
+    fn schedule_save(&mut self) {
+        // Edits still reach the WAL; the client decides between reloading
+        // and saving over the other process's changes.
+        if self.diverged {
+            return;
+        }
+        let buffer = Arc::clone(&self.buffer);
+        let wal = Arc::clone(&self.wal);
+        let watcher = self.watcher.clone();
+        let saved_v = Arc::clone(&self.saved_v);
+        let doc_v = self.doc_v;
+        let workspace = self.workspace.clone();
+        self.debounce.call(move || {
+            let ws = workspace.as_ref();
+            // A refused autosave is reported by the next explicit save.
+            let _ = save(&buffer, &wal, &watcher, &saved_v, doc_v, false, ws);
+        });
+    }
+
+    async fn emit_frame(&mut self, tx: &mpsc::Sender<Frame>) {
+        if let (Some(picker), Some(ws)) = (&self.picker, &self.workspace) {
+            let _ = tx
+                .send(picker.compose(ws, self.cols, self.rows, self.doc_v))
+                .await;
+            return;
+        }
+        self.refresh_search();
+        if let Some(state) = &mut self.search {
+            state.shown = Instant::now();
+        }
+        let selections: Vec<Range<usize>> = std::iter::once(self.selection())
+            .chain(self.carets.iter().map(Caret::selection))
+            .collect();
+        let cursors: Vec<usize> = std::iter::once(self.head)
+            .chain(self.carets.iter().map(|c| c.head))
+            .collect();
+        let status = self.status_info();
+        let status_right = (status.search.as_ref())
+            .map(|s| {
+                let more = if s.searching { "+" } else { "" };
+                format!("{} of {}{more}", s.current, s.total)
+            })
+            .unwrap_or_default();
+        let marks = self.marked_lines();
+        let params = ViewportParams {
+            selections: &selections,
+            matches: self.search.as_ref().map_or(&[], |s| &s.matches),
+            cursors: &cursors,
+            marks: &marks,
+            peers: &[],
+            doc_v: self.doc_v,
+            status_left: &self.status,
+            status_right: &status_right,
+        };
+        let mut frame = if let Some(hex) = &self.hex {
+            let (first, cols, rows, doc_v) = (self.first_line, self.cols, self.rows, self.doc_v);
+            hex.compose(first, cols, rows, doc_v, &self.status, "")
+                .unwrap_or_else(|_| {
+                    compose_hex_window(&[], first, cols, rows, doc_v, &self.status, "read failed")
+                })
+        } else {
+            let buf = self.buffer.lock().unwrap();
+            compose_viewport(
+                &buf,
+                self.first_line,
+                self.cols,
+                self.rows,
+                self.hscroll,
+                params,
+            )
+        };
+        frame.status = Some(status);
+        frame.checksum = Some(frame.content_checksum());
+        if self.followers.receiver_count() > 0 {
+            let _ = self.followers.send(frame.clone());
+        }
+        let _ = tx.send(frame).await;
+    }
+
+    fn status_info(&self) -> Status {
+        let search = self.search_status();
+        let buf = self.buffer.lock().unwrap();
+        let (line, col) = if self.hex.is_some() {
+            (self.first_line, 0)
+        } else {
+            buf.byte_to_line_col(self.head)
+        };
+        Status {
+            path: self.display(&self.path),
+            dirty: self.saved_v.load(Ordering::SeqCst) != self.doc_v,
+            lock: if self.writable() {
+                LockState::Writable
+            } else {
+                LockState::ReadOnly
+            },
+            connection: ConnectionState::Connected,
+            doc_v: self.doc_v,
+            line: line as u64,
+            col: col as u64,
+            encoding: if self.hex.is_some() {
+                "binary".into()
+            } else {
+                "UTF-8".into()
+            },
+            eol: match buf.eol() {
+                Eol::Lf => "LF".into(),
+                Eol::CrLf => "CRLF".into(),
+            },
+            search,
+            selection: self.hex.is_none().then_some(ghostwriter_proto::Range {
+                from: self.anchor as u64,
+                to: self.head as u64,
+            }),
+        }
+    }
+}
+
+/// Load `path` as text, falling back to a paged hex view when it is not
+/// valid UTF-8. Files that look binary from their first bytes are never
+/// read whole. A missing file opens as an empty buffer.
+fn load(path: &Path) -> io::Result<(RopeBuffer, Option<HexFile>)> {
+    match looks_binary(path) {
+        Ok(true) => return Ok((RopeBuffer::from_text(""), Some(HexFile::open(path)?))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok((RopeBuffer::from_text(""), None));
+        }
+        _ => {}
+    }
+    let buffer = RopeBuffer::open(path)?;
+    if buffer.has_invalid() {
+        return Ok((RopeBuffer::from_text(""), Some(HexFile::open(path)?)));
+    }
+    Ok((buffer, None))
+}
+
+/// [`load`] a file of `workspace`, recording the read in its audit log.
+fn load_in(
+    workspace: Option<&Workspace>,
+    path: &Path,
+) -> io::Result<(RopeBuffer, Option<HexFile>)> {
+    let loaded = load(path);
+    if let Some(ws) = workspace {
+        let bytes = std::fs::metadata(path).map_or(0, |m| m.len());
+        ws.record(FileOp::Read, &ws.relative(path), Some(bytes), &loaded);
+    }
+    loaded
+}
+
+/// Write `buffer` to the watched file, then mark it clean at `doc_v` and drop the
+/// logged edits it now contains. Unless `force` is set, a file another
+/// process changed is left alone so the client can decide what to keep.
+/// Writes to files of `workspace` are recorded in its audit log and must
+/// fit its write limits.
+fn save(
+    buffer: &Mutex<RopeBuffer>,
+    wal: &Mutex<Option<LazyWal>>,
+    watcher: &FileWatcher,
+    saved_v: &AtomicU64,
+    doc_v: u64,
+    force: bool,
+    workspace: Option<&Workspace>,
+) -> Result<(), ErrorMsg> {
+    let mut wal = wal.lock().unwrap();
+    let Ok(buf) = buffer.lock() else {
+        return Ok(());
+    };
+    if let Some(ws) = workspace {
+        let old_len = std::fs::metadata(watcher.path()).map_or(0, |m| m.len());
+        let new_len = buf.len_bytes() as u64;
+        if let Err(err) = ws.quota().charge_write(ws.roots(), old_len, new_len) {
+            let rel = ws.relative(watcher.path());
+            tracing::warn!(path = %rel, "save refused: {}", err.msg);
+            let refused: io::Result<()> = Err(io::Error::other(err.msg.clone()));
+            ws.record(FileOp::Write, &rel, None, &refused);
+            return Err(err.with_path(rel));
+        }
+    }
+    let started = Instant::now();
+    let write = || buf.save_to(watcher.path());
+    let written = if force {
+        Some(watcher.write_with(write))
+    } else {
+        watcher.write_if_unchanged(write)
+    };
+    if let Some(Err(e)) = &written {
+        tracing::warn!(path = %watcher.path().display(), error = %e, "save failed");
+    }
+    if let (Some(result), Some(ws)) = (&written, workspace) {
+        ws.metrics().observe_save(started.elapsed());
+        let rel = ws.relative(watcher.path());
+        ws.record(FileOp::Write, &rel, Some(buf.len_bytes() as u64), result);
+    }
+    if let Some(Ok(())) = written {
+        saved_v.store(doc_v, Ordering::SeqCst);
+        if let Some(wal) = wal.as_mut() {
+            wal.compact();
+        }
+    }
+    Ok(())
+}
+
+/// Directory beside edited files holding their write-ahead logs.
+pub const WAL_DIR: &str = ".ghostwriter";
+
+/// Write-ahead log of `path`: `.ghostwriter/<name>.wal` in its directory.
+pub fn wal_path(path: &Path) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".wal");
+    Some(path.parent()?.join(WAL_DIR).join(name))
+}
+
+/// Bookmarks of `path`: `.ghostwriter/<name>.marks` in its directory, one
+/// zero-based line number per line.
+fn marks_path(path: &Path) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".marks");
+    Some(path.parent()?.join(WAL_DIR).join(name))
+}
+
+/// Lines listed in the marks file at `path`, in order; none if it is
+/// missing.
+fn read_marks(path: &Path) -> Vec<usize> {
+    let text = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<usize> = text.lines().filter_map(|l| l.parse().ok()).collect();
+    lines.sort_unstable();
+    lines.dedup();
+    lines
+}
+
+/// Write `lines` to the marks file at `path`, removing it when there are
+/// none.
+fn write_marks(path: &Path, lines: &[usize]) -> io::Result<()> {
+    if lines.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    std::fs::write(path, text)
+}
+
+/// Write-ahead log of a text document. The log and the directory holding
+/// it are only created by the first edit, so viewing a file leaves nothing
+/// behind.
+struct LazyWal {
+    path: PathBuf,
+    wal: Option<Wal>,
+}
+
+impl LazyWal {
+    /// Log `record`, creating the log first if need be. A failure costs
+    /// only crash recovery, so it is logged and the edit goes ahead.
+    fn append(&mut self, record: &EditRecord) {
+        let wal = match self.wal.take() {
+            Some(wal) => Ok(wal),
+            None => (self.path.parent())
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|()| Wal::new(&self.path)),
+        };
+        let appended = wal.and_then(|wal| self.wal.insert(wal).append(record));
+        if let Err(e) = appended {
+            tracing::warn!(path = %self.path.display(), error = %e, "logging an edit failed");
+        }
+    }
+
+    /// Drop the logged edits once they are on disk.
+    fn compact(&mut self) {
+        let Some(wal) = self.wal.as_mut() else {
+            return;
+        };
+        if let Err(e) = wal.compact_if_needed(0) {
+            tracing::warn!(path = %self.path.display(), error = %e, "emptying the edit log failed");
+        }
+    }
+}
+
+/// Replay the edits the write-ahead log of a text document holds that
+/// never reached disk, e.g. after a crash. Returns the log and the
+/// document version of its last record, or `None` for hex views and when
+/// the log cannot be read.
+fn recover(path: &Path, buffer: &mut RopeBuffer, hex: &Option<HexFile>) -> Option<(LazyWal, u64)> {
+    if hex.is_some() {
+        return None;
+    }
+    let wal_path = wal_path(path)?;
+    let records = Wal::replay(&wal_path).ok()?;
+    for record in &records {
+        apply_op(buffer, &record.op);
+    }
+    let doc_v = records.last().map_or(0, |r| r.doc_v);
+    // Replayed edits stay logged until they are saved.
+    let wal = if records.is_empty() {
+        None
+    } else {
+        Some(Wal::new(&wal_path).ok()?)
+    };
+    Some((
+        LazyWal {
+            path: wal_path,
+            wal,
+        },
+        doc_v,
+    ))
+}
+
+/// Like [`recover`] for a file of `workspace`, leaving the logs of files
+/// that may not be changed alone.
+fn recover_in(
+    workspace: Option<&Workspace>,
+    path: &Path,
+    buffer: &mut RopeBuffer,
+    hex: &Option<HexFile>,
+) -> Option<(LazyWal, u64)> {
+    if workspace.is_some_and(|ws| !ws.can_write(path)) {
+        return None;
+    }
+    recover(path, buffer, hex)
+}
+
+/// Apply a logged edit, clamping offsets to the buffer.
+fn apply_op(buf: &mut RopeBuffer, op: &EditOp) {
+    match op {
+        EditOp::Insert { idx, bytes } => {
+            let idx = buf.floor_char_boundary(*idx as usize);
+            buf.insert(idx, &String::from_utf8_lossy(bytes));
+        }
+        EditOp::Delete { range } => {
+            let start = buf.floor_char_boundary(range.start as usize);
+            let end = buf.floor_char_boundary(range.end as usize).max(start);
+            buf.delete(start..end);
+        }
+    }
+}
+
+impl Query {
+    /// Compile `text`, escaping it unless it is a regex. The query is not
+    /// confined to a range yet.
+    fn new(
+        text: String,
+        regex: bool,
+        whole_word: bool,
+        in_selection: bool,
+    ) -> Result<Self, ErrorMsg> {
+        if text.is_empty() {
+            return Err(ErrorMsg::new(ErrorCode::Invalid, "empty search pattern"));
+        }
+        let pattern = if regex {
+            Regex::new(&text)
+        } else {
+            Regex::new(&regex::escape(&text))
+        };
+        Ok(Self {
+            pattern: pattern.map_err(|e| ErrorMsg::new(ErrorCode::Invalid, e.to_string()))?,
+            text,
+            regex,
+            whole_word,
+            in_selection,
+            within: None,
+        })
+    }
+
+    /// Whether `other` asks for the same matches, wherever either is
+    /// confined to.
+    fn same(&self, other: &Query) -> bool {
+        (self.text == other.text)
+            && self.regex == other.regex
+            && self.whole_word == other.whole_word
+            && self.in_selection == other.in_selection
+    }
+
+    /// Whether the match at `range` of `text`, which starts at byte
+    /// `offset` of the document, counts.
+    fn accepts(&self, text: &str, offset: usize, range: &Range<usize>) -> bool {
+        let (start, end) = (offset + range.start, offset + range.end);
+        (!self.whole_word || search::is_whole_word(text, range))
+            && (self.within.as_ref()).is_none_or(|w| w.start <= start && end <= w.end)
+    }
+
+    /// Non-overlapping matches in the whole document `text` that count.
+    fn captures_iter<'t>(&'t self, text: &'t str) -> impl Iterator<Item = Captures<'t>> {
+        (self.pattern.captures_iter(text))
+            .filter(|caps| self.accepts(text, 0, &caps.get(0).unwrap().range()))
+    }
+
+    /// First match that counts starting at byte `at` of `text` or later,
+    /// where `text` starts at byte `offset` of the document.
+    fn captures_from<'t>(
+        &self,
+        text: &'t str,
+        offset: usize,
+        mut at: usize,
+    ) -> Option<Captures<'t>> {
+        loop {
+            let caps = self.pattern.captures_at(text, at)?;
+            let m = caps.get(0).unwrap();
+            if self.accepts(text, offset, &m.range()) {
+                return Some(caps);
+            }
+            let next = text[m.start()..].chars().next()?;
+            at = m.start() + next.len_utf8();
+        }
+    }
+
+    /// Document byte ranges of the non-overlapping, non-empty matches that
+    /// count in `text`, whole lines starting at byte `offset`.
+    fn find_in(&self, text: &str, offset: usize) -> Vec<Range<usize>> {
+        (self.pattern.find_iter(text))
+            .map(|m| m.range())
+            .filter(|m| !m.is_empty() && self.accepts(text, offset, m))
+            .map(|m| offset + m.start..offset + m.end)
+            .collect()
+    }
+}
+
+/// `range` widened to whole lines, the last one's newline included.
+fn whole_lines(buf: &RopeBuffer, range: Range<usize>) -> Range<usize> {
+    let first = buf.byte_to_line_col(range.start).0;
+    let last = buf.byte_to_line_col(range.end).0;
+    buf.line_to_byte(first)..buf.line_to_byte(last + 1)
+}
+
+/// `replacement` for the match `caps`, with capture groups inserted if
+/// the query was a regex.
+fn expand(caps: &Captures, replacement: &str, regex: bool) -> String {
+    let mut out = String::new();
+    if regex {
+        caps.expand(replacement, &mut out);
+    } else {
+        out.push_str(replacement);
+    }
+    out
+}
+
+/// Where byte offset `pos` ends up after `op`. Text inserted at `pos` goes
+/// before it.
+fn shift(pos: usize, op: &EditOp) -> usize {
+    match op {
+        EditOp::Insert { idx, bytes } if pos >= *idx as usize => pos + bytes.len(),
+        EditOp::Delete { range } if pos > range.start as usize => {
+            let len = (range.end - range.start) as usize;
+            pos.saturating_sub(len).max(range.start as usize)
+        }
+        _ => pos,
+    }
+}
+
+/// `range` moved along by `op`. Text inserted at either end goes inside.
+fn shift_range(range: &Range<usize>, op: &EditOp) -> Range<usize> {
+    let start = match op {
+        EditOp::Insert { idx, .. } if *idx as usize == range.start => range.start,
+        _ => shift(range.start, op),
+    };
+    start..shift(range.end, op)
+}
+
+/// Ops reverting `edit`, and where a cursor goes after each part of it.
+/// A group is reverted from its first edit, which the others do not move.
+fn revert_ops(edit: &Edit, ops: &mut Vec<EditOp>) -> Vec<usize> {
+    match edit {
+        Edit::Insert { idx, text } => {
+            ops.push(delete_op(*idx, text));
+            vec![*idx]
+        }
+        Edit::Delete { idx, text } => {
+            ops.push(insert_op(*idx, text));
+            vec![idx + text.len()]
+        }
+        Edit::Replace { idx, old, new } => {
+            ops.extend(replace_ops(*idx, new, old));
+            vec![idx + old.len()]
+        }
+        Edit::Group(edits) => (edits.iter().rev())
+            .flat_map(|edit| revert_ops(edit, ops))
+            .collect(),
+    }
+}
+
+/// Ops reapplying `edit`, and where a cursor goes after each part of it;
+/// see [`revert_ops`]. A group is reapplied from its last edit, so the
+/// cursors of those done already move along with the ones after.
+fn reapply_ops(edit: &Edit, ops: &mut Vec<EditOp>) -> Vec<usize> {
+    match edit {
+        Edit::Insert { idx, text } => {
+            ops.push(insert_op(*idx, text));
+            vec![idx + text.len()]
+        }
+        Edit::Delete { idx, text } => {
+            ops.push(delete_op(*idx, text));
+            vec![*idx]
+        }
+        Edit::Replace { idx, old, new } => {
+            ops.extend(replace_ops(*idx, old, new));
+            vec![idx + new.len()]
+        }
+        Edit::Group(edits) => {
+            let mut heads: Vec<usize> = Vec::new();
+            for edit in edits {
+                let done = ops.len();
+                let at = reapply_ops(edit, ops);
+                for head in &mut heads {
+                    *head = ops[done..].iter().fold(*head, shift);
+                }
+                heads.extend(at);
+            }
+            heads
+        }
+    }
+}
+
+/// Ops replacing `old` at `idx` with `new`, leaving out empty ones.
+fn replace_ops(idx: usize, old: &str, new: &str) -> Vec<EditOp> {
+    let delete = (!old.is_empty()).then(|| delete_op(idx, old));
+    let insert = (!new.is_empty()).then(|| insert_op(idx, new));
+    delete.into_iter().chain(insert).collect()
+}
+
+fn insert_op(idx: usize, text: &str) -> EditOp {
+    EditOp::Insert {
+        idx: idx as u64,
+        bytes: text.as_bytes().to_vec(),
+    }
+}
+
+fn delete_op(idx: usize, text: &str) -> EditOp {
+    EditOp::Delete {
+        range: idx as u64..(idx + text.len()) as u64,
+    }
+}
+
+/// Workspace-relative parent directory of `path`.
+pub(crate) fn parent_of(path: &str) -> &str {
+    Path::new(path)
+        .parent()
+        .and_then(Path::to_str)
+        .unwrap_or("")
+}
+
+/// Map a filesystem error for `path` to the error sent to the client.
+pub(crate) fn picker_error(path: &str) -> impl Fn(io::Error) -> ErrorMsg + '_ {
+    move |err| {
+        let code = match err.kind() {
+            io::ErrorKind::PermissionDenied => ErrorCode::Sandbox,
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::AlreadyExists => ErrorCode::Conflict,
+            io::ErrorKind::ReadOnlyFilesystem => ErrorCode::Readonly,
+            _ => ErrorCode::Io,
+        };
+        ErrorMsg::new(code, err.to_string()).with_path(path)
+    }
+}
+
+/// Open a file from `path` and spawn a session actor.
+pub fn open<P: AsRef<Path>>(path: P, cols: u16, rows: u16) -> io::Result<SessionHandle> {
+    Session::open(path, cols, rows)
+}
+
+/// Open `rel` inside `workspace` and spawn a session actor.
+pub fn open_in(workspace: Workspace, rel: &str, cols: u16, rows: u16) -> io::Result<SessionHandle> {
+    Session::open_in(workspace, rel, cols, rows)
+}
+
+/// Spawn a session with the provided `buffer` for testing purposes.
+pub fn spawn(buffer: RopeBuffer, path: PathBuf, cols: u16, rows: u16) -> SessionHandle {
+    Session::spawn(buffer, path, cols, rows)
+}
+
+/// Save the session once the client link drops, so edits survive a dead
+/// connection. `link` comes from [`ghostwriter_core::Transport::link_state`].
+pub fn autosave_on_disconnect(
+    cmd: mpsc::Sender<SessionCmd>,
+    mut link: watch::Receiver<LinkState>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // A closed watch means the transport is gone too.
+        let _ = link.wait_for(|s| *s != LinkState::Connected).await;
+        let _ = cmd.send(SessionCmd::Save).await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn insert_emits_frame() {
+        let file = NamedTempFile::new().unwrap();
+        let mut handle =
+            Session::spawn(RopeBuffer::from_text(""), file.path().to_path_buf(), 80, 24);
+        handle
+            .cmd
+            .send(SessionCmd::Insert {
+                text: "hi".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
+            .await
+            .unwrap();
+        let frame = handle.frames.recv().await.unwrap();
+        assert_eq!(frame.doc_v, 1);
+        assert_eq!(frame.lines[0].text, "hi");
+        assert_eq!(frame.cursors[0].line, 0);
+        assert_eq!(frame.cursors[0].col, 2);
+
+        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
+        let frame2 = handle.frames.recv().await.unwrap();
+        assert_eq!(frame2.doc_v, 1);
+        assert_eq!(frame2.lines[0].text, "hi");
+    }
+
+    #[tokio::test]
+    async fn open_and_save_roundtrip() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "hi").unwrap();
+        let path = file.path().to_path_buf();
+        let mut handle = open(&path, 80, 24).unwrap();
+        handle
+            .cmd
+            .send(SessionCmd::Insert {
+                text: " there".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
+            .await
+            .unwrap();
+        let _ = handle.frames.recv().await.unwrap();
+        handle.cmd.send(SessionCmd::Save).await.unwrap();
+        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
+        let _ = handle.frames.recv().await.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, " therehi");
+    }
+
+    #[tokio::test]
+    async fn banner_and_save_on_exit() {
+        use tokio::time::{Duration, sleep};
+
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let SessionHandle {
+            cmd, mut frames, ..
+        } = open(&path, 80, 24).unwrap();
+
+        cmd.send(SessionCmd::RequestFrame).await.unwrap();
+        let frame = frames.recv().await.unwrap();
+        assert_eq!(frame.status_left, "server");
+
+        cmd.send(SessionCmd::Insert {
+            text: "hi".into(),
+            pos: None,
+            seq: None,
+            base_doc_v: None,
+        })
+        .await
+        .unwrap();
+        drop(cmd); // close channel to end session
 
         sleep(Duration::from_millis(20)).await;
 
-        let contents = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(contents, "hi");
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, "hi");
+    }
+
+    async fn request(handle: &mut SessionHandle, cmd: SessionCmd) -> Frame {
+        handle.cmd.send(cmd).await.unwrap();
+        handle.frames.recv().await.unwrap()
+    }
+
+    /// Wait for the next [`SessionEvent::Error`], skipping acks.
+    async fn next_error(handle: &mut SessionHandle) -> ErrorMsg {
+        loop {
+            match handle.events.recv().await.unwrap() {
+                SessionEvent::Ack(_) => {}
+                SessionEvent::Error(err) => return err,
+                other => panic!("expected error, got {other:?}"),
+            }
+        }
+    }
+
+    /// Wait for the next [`SessionEvent::ExternalChange`].
+    async fn next_change(handle: &mut SessionHandle) -> ExternalChange {
+        let event = tokio::time::timeout(Duration::from_secs(5), handle.events.recv());
+        match event.await.unwrap().unwrap() {
+            SessionEvent::ExternalChange(change) => change,
+            other => panic!("expected external change, got {other:?}"),
+        }
+    }
+
+    fn spawn_text(text: &str, rows: u16) -> (SessionHandle, NamedTempFile) {
+        let file = NamedTempFile::new().unwrap();
+        let handle = Session::spawn(
+            RopeBuffer::from_text(text),
+            file.path().to_path_buf(),
+            80,
+            rows,
+        );
+        (handle, file)
+    }
+
+    #[tokio::test]
+    async fn delete_bumps_doc_v_and_acks() {
+        let (mut handle, _file) = spawn_text("hello world", 24);
+        let frame = request(
+            &mut handle,
+            SessionCmd::Delete {
+                range: 5..11,
+                seq: Some(7),
+                base_doc_v: None,
+            },
+        )
+        .await;
+        assert_eq!(frame.lines[0].text, "hello");
+        assert_eq!(frame.doc_v, 1);
+        assert_eq!(frame.cursors[0].col, 5);
+        assert_eq!(
+            handle.events.recv().await,
+            Some(SessionEvent::Ack(Ack { seq: 7, doc_v: 1 }))
+        );
+
+        // Out-of-range deletes are clamped and still acknowledged.
+        let frame = request(
+            &mut handle,
+            SessionCmd::Delete {
+                range: 50..60,
+                seq: Some(8),
+                base_doc_v: None,
+            },
+        )
+        .await;
+        assert_eq!(frame.doc_v, 1);
+        assert_eq!(
+            handle.events.recv().await,
+            Some(SessionEvent::Ack(Ack { seq: 8, doc_v: 1 }))
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_edits_based_on_a_changed_document() {
+        let (mut handle, _file) = spawn_text("abc", 24);
+        let insert = |pos, seq, base_doc_v| SessionCmd::Insert {
+            text: "x".into(),
+            pos: Some(pos),
+            seq: Some(seq),
+            base_doc_v: Some(base_doc_v),
+        };
+        // Edits based on a version the client's own edits moved past apply.
+        request(&mut handle, insert(0, 1, 0)).await;
+        let frame = request(&mut handle, insert(2, 2, 0)).await;
+        assert_eq!(frame.lines[0].text, "xaxbc");
+        assert_eq!(frame.doc_v, 2);
+
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.doc_v, 3);
+        handle.cmd.send(insert(0, 3, 2)).await.unwrap();
+        let err = next_error(&mut handle).await;
+        assert_eq!(err.code, ErrorCode::Conflict);
+        assert_eq!((err.seq, err.doc_v), (Some(3), Some(3)));
+        let delete = SessionCmd::Delete {
+            range: 0..1,
+            seq: Some(4),
+            base_doc_v: Some(2),
+        };
+        handle.cmd.send(delete).await.unwrap();
+        let err = next_error(&mut handle).await;
+        assert_eq!(err.seq, Some(4));
+
+        let frame = request(&mut handle, insert(0, 5, 3)).await;
+        assert_eq!(frame.lines[0].text, "xxabc");
+    }
+
+    #[tokio::test]
+    async fn frames_carry_structured_status() {
+        let (mut handle, file) = spawn_text("ab\ncd", 24);
+        let frame = request(&mut handle, goto(1)).await;
+        let status = frame.status.unwrap();
+        assert_eq!(status.path, file.path().display().to_string());
+        assert!(!status.dirty);
+        assert_eq!(status.lock, LockState::Writable);
+        assert_eq!((status.line, status.col), (1, 0));
+        assert_eq!(status.encoding, "UTF-8");
+        assert_eq!(status.eol, "LF");
+
+        let frame = request(
+            &mut handle,
+            SessionCmd::Insert {
+                text: "x".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+        let status = frame.status.unwrap();
+        assert!(status.dirty);
+        assert_eq!((status.doc_v, status.col), (1, 1));
+
+        handle.cmd.send(SessionCmd::Save).await.unwrap();
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert!(!frame.status.unwrap().dirty);
+    }
+
+    #[tokio::test]
+    async fn status_shows_the_workspace_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/a.txt"), "alpha").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "src/a.txt", 80, 24).unwrap();
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.status.unwrap().path, "src/a.txt");
+    }
+
+    #[tokio::test]
+    async fn typing_undoes_by_word_until_the_cursor_moves() {
+        let (mut handle, _file) = spawn_text("", 24);
+        let typed = |c: char| SessionCmd::Insert {
+            text: c.to_string(),
+            pos: None,
+            seq: None,
+            base_doc_v: None,
+        };
+        for c in "hi there".chars() {
+            request(&mut handle, typed(c)).await;
+        }
+        let moved = SessionCmd::Move {
+            dir: Direction::Left,
+            granularity: Granularity::Document,
+            extend: false,
+            block: false,
+        };
+        request(&mut handle, moved).await;
+        request(&mut handle, typed('!')).await;
+        for text in ["hi there", "hi", ""] {
+            let frame = request(&mut handle, SessionCmd::Undo).await;
+            assert_eq!(frame.lines[0].text, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn move_updates_cursor_and_scrolls() {
+        let (mut handle, _file) = spawn_text("one two\nthree\nfour", 2);
+        let frame = request(
+            &mut handle,
+            SessionCmd::Move {
+                dir: Direction::Right,
+                granularity: Granularity::Word,
+                extend: false,
+                block: false,
+            },
+        )
+        .await;
+        assert_eq!(frame.cursors[0].col, 3);
+        assert_eq!(frame.doc_v, 0);
+
+        let frame = request(
+            &mut handle,
+            SessionCmd::Move {
+                dir: Direction::Down,
+                granularity: Granularity::Document,
+                extend: false,
+                block: false,
+            },
+        )
+        .await;
+        assert_eq!(frame.cursors[0].line, 2);
+        assert_eq!(frame.first_line, 1);
+    }
+
+    #[tokio::test]
+    async fn select_emits_selection_span() {
+        let (mut handle, _file) = spawn_text("hello world", 24);
+        let frame = request(
+            &mut handle,
+            SessionCmd::Select {
+                anchor: 5,
+                head: 0,
+                mode: SelectMode::Set,
+            },
+        )
+        .await;
+        assert_eq!(frame.cursors[0].col, 0);
+        let sel = &frame.lines[0].spans[0];
+        assert_eq!((sel.start_col, sel.end_col), (0, 5));
+        assert_eq!(frame.class_name(sel), Some("sel"));
+
+        let frame = request(
+            &mut handle,
+            SessionCmd::Insert {
+                text: "X".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+        assert_eq!(frame.lines[0].text, "Xhello world");
+        assert!(frame.lines[0].spans.is_empty());
+    }
+
+    #[tokio::test]
+    async fn select_extends_clears_and_copies() {
+        let (mut handle, _file) = spawn_text("hello world", 24);
+        request(
+            &mut handle,
+            SessionCmd::Select {
+                anchor: 6,
+                head: 8,
+                mode: SelectMode::Set,
+            },
+        )
+        .await;
+        let frame = request(
+            &mut handle,
+            SessionCmd::Select {
+                anchor: 0,
+                head: 11,
+                mode: SelectMode::Extend,
+            },
+        )
+        .await;
+        let sel = &frame.lines[0].spans[0];
+        assert_eq!((sel.start_col, sel.end_col), (6, 11));
+
+        handle.cmd.send(SessionCmd::Copy).await.unwrap();
+        assert_eq!(
+            handle.events.recv().await,
+            Some(SessionEvent::Copy(Copy {
+                text: "world".into()
+            }))
+        );
+
+        let frame = request(
+            &mut handle,
+            SessionCmd::Select {
+                anchor: 0,
+                head: 0,
+                mode: SelectMode::Clear,
+            },
+        )
+        .await;
+        assert!(frame.lines[0].spans.is_empty());
+        assert_eq!(frame.cursors[0].col, 11);
+        handle.cmd.send(SessionCmd::Copy).await.unwrap();
+        assert_eq!(
+            handle.events.recv().await,
+            Some(SessionEvent::Copy(Copy {
+                text: String::new()
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn scroll_clamps_to_document() {
+        let (mut handle, _file) = spawn_text("a\nb\nc", 2);
+        let frame = request(&mut handle, scroll(10, ScrollUnit::Line, 0)).await;
+        assert_eq!(frame.first_line, 2);
+        assert_eq!(frame.lines[0].text, "c");
+        let frame = request(&mut handle, scroll(-5, ScrollUnit::Line, 0)).await;
+        assert_eq!(frame.first_line, 0);
+    }
+
+    fn goto(line: usize) -> SessionCmd {
+        SessionCmd::GotoLine {
+            line,
+            col: 0,
+            extend: false,
+            block: false,
+        }
+    }
+
+    fn scroll(delta: i64, unit: ScrollUnit, dx: i64) -> SessionCmd {
+        SessionCmd::Scroll { delta, unit, dx }
+    }
+
+    #[tokio::test]
+    async fn scroll_by_pages_and_columns() {
+        let text = (0..10)
+            .map(|i| format!("line {i} {}", "x".repeat(i)))
+            .collect::<Vec<_>>();
+        let (mut handle, _file) = spawn_text(&text.join("\n"), 3);
+        // Every line fits in 80 columns, so there is nothing to scroll.
+        let frame = request(&mut handle, scroll(0, ScrollUnit::Line, 5)).await;
+        assert_eq!(frame.lines[0].text, "line 0 ");
+
+        request(&mut handle, SessionCmd::Resize { cols: 10, rows: 3 }).await;
+        let frame = request(&mut handle, scroll(2, ScrollUnit::Page, 0)).await;
+        assert_eq!(frame.first_line, 6);
+        assert_eq!(frame.lines[0].text, "line 6 xxx");
+        let frame = request(&mut handle, scroll(0, ScrollUnit::Line, 3)).await;
+        assert_eq!(frame.lines[0].text, "e 6 xxxxxx");
+
+        // Stops once the widest visible line, "line 8 xxxxxxxx", fits with
+        // a column to spare for the cursor after it.
+        let frame = request(&mut handle, scroll(0, ScrollUnit::Line, 50)).await;
+        assert_eq!(frame.first_col, 6);
+        assert_eq!(frame.lines[0].text, " xxxxxx");
+        assert_eq!(frame.lines[2].text, " xxxxxxxx");
+        assert!(!frame.lines[2].more);
+
+        let frame = request(&mut handle, scroll(-1, ScrollUnit::Page, -50)).await;
+        assert_eq!(frame.first_line, 3);
+        assert_eq!(frame.lines[0].text, "line 3 xxx");
+        let frame = request(&mut handle, scroll(99, ScrollUnit::Page, 0)).await;
+        assert_eq!(frame.first_line, 9);
+
+        // Growing the window pulls the horizontal offset back in.
+        request(&mut handle, scroll(-1, ScrollUnit::Line, 4)).await;
+        let frame = request(&mut handle, SessionCmd::Resize { cols: 40, rows: 3 }).await;
+        assert_eq!(frame.first_line, 0);
+        assert_eq!(frame.lines[2].text, "line 2 xx");
+    }
+
+    #[tokio::test]
+    async fn resize_and_goto_line() {
+        let (mut handle, _file) = spawn_text("a\nb\nc\nd", 4);
+        let frame = request(&mut handle, SessionCmd::Resize { cols: 40, rows: 2 }).await;
+        assert_eq!((frame.cols, frame.rows), (40, 2));
+        assert_eq!(frame.lines.len(), 2);
+
+        let frame = request(&mut handle, goto(3)).await;
+        assert_eq!(frame.cursors[0].line, 3);
+        assert_eq!(frame.first_line, 2);
+
+        let frame = request(&mut handle, goto(99)).await;
+        assert_eq!(frame.cursors[0].line, 3);
+    }
+
+    #[tokio::test]
+    async fn goto_line_and_column() {
+        let (mut handle, _file) = spawn_text("héllo\nworld", 4);
+        let at = |line, col, extend| SessionCmd::GotoLine {
+            line,
+            col,
+            extend,
+            block: false,
+        };
+        // Byte 2 is inside "é"; past the end clamps to it.
+        let frame = request(&mut handle, at(0, 2, false)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 1));
+        let frame = request(&mut handle, at(1, 99, true)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 5));
+        let selection = frame.status.and_then(|s| s.selection).unwrap();
+        assert_eq!((selection.from, selection.to), (1, 12));
+    }
+
+    #[tokio::test]
+    async fn duplicate_and_delete_lines() {
+        let (mut handle, _file) = spawn_text("a\nb\nc", 24);
+        request(&mut handle, goto(1)).await;
+        let frame = request(&mut handle, SessionCmd::DuplicateLine).await;
+        let text: Vec<&str> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(text, ["a", "b", "b", "c"]);
+        assert_eq!(frame.cursors[0].line, 2);
+        assert_eq!(frame.doc_v, 1);
+
+        // The last line has no trailing newline.
+        request(&mut handle, goto(3)).await;
+        let frame = request(&mut handle, SessionCmd::DuplicateLine).await;
+        let text: Vec<&str> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(text, ["a", "b", "b", "c", "c"]);
+        assert_eq!(frame.cursors[0].line, 4);
+
+        // A selection spanning two lines deletes both.
+        request(
+            &mut handle,
+            SessionCmd::Select {
+                anchor: 0,
+                head: 3,
+                mode: SelectMode::Set,
+            },
+        )
+        .await;
+        let frame = request(&mut handle, SessionCmd::DeleteLine).await;
+        let text: Vec<&str> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(text, ["b", "c", "c"]);
+        assert_eq!(frame.doc_v, 3);
+
+        request(&mut handle, goto(2)).await;
+        let frame = request(&mut handle, SessionCmd::DeleteLine).await;
+        let text: Vec<&str> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(text, ["b", "c"]);
+        assert_eq!(frame.cursors[0].line, 1);
+    }
+
+    #[tokio::test]
+    async fn search_selects_next_match_and_wraps() {
+        let (mut handle, _file) = spawn_text("foo bar\nbar foo", 24);
+        let search = |query: &str| SessionCmd::Search {
+            query: query.into(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: None,
+            whole_word: false,
+            in_selection: false,
+            start_confirm: false,
+        };
+        let frame = request(&mut handle, search("foo")).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 3));
+        let frame = request(&mut handle, search("foo")).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 7));
+        let sel = &frame.lines[1].spans[0];
+        assert_eq!((sel.start_col, sel.end_col), (4, 7));
+        let frame = request(&mut handle, search("foo")).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 3));
+
+        // No match leaves the selection alone.
+        let frame = request(&mut handle, search("zzz")).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 3));
+        assert_eq!(frame.doc_v, 0);
+    }
+
+    #[tokio::test]
+    async fn search_highlights_matches_and_counts_them() {
+        let (mut handle, _file) = spawn_text("ab\nab ab\nx", 24);
+        let search = |query: &str, dir| SessionCmd::Search {
+            query: query.into(),
+            dir,
+            regex: false,
+            replacement: None,
+            whole_word: false,
+            in_selection: false,
+            start_confirm: false,
+        };
+        let matches = |frame: &Frame, line: usize| -> Vec<(u16, u16)> {
+            frame.lines[line]
+                .spans
+                .iter()
+                .filter(|s| frame.class_name(s) == Some("match"))
+                .map(|s| (s.start_col, s.end_col))
+                .collect()
+        };
+        let frame = request(&mut handle, search("ab", SearchDir::Next)).await;
+        assert_eq!(matches(&frame, 0), [(0, 2)]);
+        assert_eq!(matches(&frame, 1), [(0, 2), (3, 5)]);
+        let status = frame.status.clone().unwrap().search.unwrap();
+        assert_eq!((status.current, status.total), (1, 3));
+        assert_eq!(frame.status_right, "1 of 3");
+
+        // Prev wraps from the first match to the last.
+        let frame = request(&mut handle, search("ab", SearchDir::Prev)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 5));
+        assert_eq!(frame.status_right, "3 of 3");
+        let frame = request(&mut handle, search("ab", SearchDir::Prev)).await;
+        assert_eq!(frame.status_right, "2 of 3");
+
+        // Edits refresh the matches; moving off a match reports 0.
+        let frame = request(
+            &mut handle,
+            SessionCmd::Insert {
+                text: "ab".into(),
+                pos: Some(10),
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+        assert_eq!(matches(&frame, 2), [(1, 3)]);
+        assert_eq!(frame.status_right, "0 of 4");
+
+        // An empty query ends the search.
+        let frame = request(&mut handle, search("", SearchDir::Next)).await;
+        assert!(matches(&frame, 1).is_empty());
+        assert_eq!(frame.status.unwrap().search, None);
+        assert_eq!(frame.status_right, "");
+    }
+
+    fn replace(query: &str, replacement: &str, regex: bool, all: bool) -> SessionCmd {
+        SessionCmd::Replace {
+            query: query.into(),
+            replacement: replacement.into(),
+            regex,
+            all,
+            rest: false,
+            whole_word: false,
+            in_selection: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn replace_current_selects_the_next_match() {
+        let (mut handle, _file) = spawn_text("cat cat\ncat", 24);
+        // Without a selected match, the first press only selects one.
+        let frame = request(&mut handle, replace("cat", "horse", false, false)).await;
+        assert_eq!(frame.doc_v, 0);
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 3));
+
+        // The next match is found after the longer replacement.
+        let frame = request(&mut handle, replace("cat", "horse", false, false)).await;
+        assert_eq!(frame.lines[0].text, "horse cat");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 9));
+        let frame = request(&mut handle, replace("cat", "$0", false, false)).await;
+        assert_eq!(frame.lines[0].text, "horse $0");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 3));
+
+        // Looking for the next match wraps around, and the cursor stays
+        // after the last replacement once there is none.
+        let frame = request(&mut handle, replace("horse", "pony", false, false)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 5));
+        let frame = request(&mut handle, replace("horse", "pony", false, false)).await;
+        assert_eq!(frame.lines[0].text, "pony $0");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 4));
+        assert_eq!(frame.doc_v, 3);
+    }
+
+    #[tokio::test]
+    async fn replace_expands_capture_groups() {
+        let (mut handle, _file) = spawn_text("key=value\nname=ada", 24);
+        let swap = || replace(r"(\w+)=(?<val>\w+)", "${val}: $1", true, false);
+        request(&mut handle, swap()).await;
+        let frame = request(&mut handle, swap()).await;
+        assert_eq!(frame.lines[0].text, "value: key");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 8));
+
+        handle
+            .cmd
+            .send(replace("(", "", true, false))
+            .await
+            .unwrap();
+        assert_eq!(next_error(&mut handle).await.code, ErrorCode::Invalid);
+    }
+
+    #[tokio::test]
+    async fn previews_replacements_and_replaces_the_rest() {
+        let (mut handle, _file) = spawn_text("a1 a22 a333", 24);
+        let step = || SessionCmd::Search {
+            query: r"a(\d+)".into(),
+            dir: SearchDir::Next,
+            regex: true,
+            replacement: Some("b$1".into()),
+            whole_word: false,
+            in_selection: false,
+            start_confirm: false,
+        };
+        let frame = request(&mut handle, step()).await;
+        let status = frame.status.unwrap().search.unwrap();
+        assert_eq!((status.current, status.total), (1, 3));
+        assert_eq!(status.preview.as_deref(), Some("b1"));
+        let frame = request(&mut handle, step()).await;
+        let status = frame.status.unwrap().search.unwrap();
+        assert_eq!(status.preview.as_deref(), Some("b22"));
+
+        // The first match, before the selection, is left alone.
+        let rest = SessionCmd::Replace {
+            query: r"a(\d+)".into(),
+            replacement: "b$1".into(),
+            regex: true,
+            all: true,
+            rest: true,
+            whole_word: false,
+            in_selection: false,
+        };
+        let frame = request(&mut handle, rest).await;
+        assert_eq!(frame.lines[0].text, "a1 b22 b333");
+        let status = frame.status.unwrap().search.unwrap();
+        assert_eq!((status.current, status.total, status.preview), (0, 1, None));
+    }
+
+    #[tokio::test]
+    async fn asking_about_each_match_ends_where_it_started() {
+        let step = |start_confirm| SessionCmd::Search {
+            query: "foo".into(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: Some("foobar".into()),
+            whole_word: false,
+            in_selection: false,
+            start_confirm,
+        };
+        let selected = |frame: Frame| frame.status.unwrap().search.unwrap().current;
+
+        // Replacements that match again are not come back to.
+        let (mut handle, _file) = spawn_text("foo foo", 24);
+        assert_eq!(selected(request(&mut handle, step(true)).await), 1);
+        let frame = request(&mut handle, replace("foo", "foobar", false, false)).await;
+        assert_eq!(selected(frame), 2);
+        let frame = request(&mut handle, replace("foo", "foobar", false, false)).await;
+        assert_eq!(frame.lines[0].text, "foobar foobar");
+        assert_eq!(selected(frame), 0);
+
+        // Skipping comes around to the matches before where it started,
+        // and replacing the rest covers those still to be asked about.
+        let (mut handle, _file) = spawn_text("foo\nfoo\nfoo", 24);
+        request(&mut handle, goto(1)).await;
+        request(&mut handle, step(true)).await;
+        let frame = request(&mut handle, step(false)).await;
+        assert_eq!(selected(frame), 3);
+        let frame = request(&mut handle, step(false)).await;
+        assert_eq!(selected(frame), 1);
+        assert_eq!(selected(request(&mut handle, step(false)).await), 0);
+
+        request(&mut handle, goto(1)).await;
+        request(&mut handle, step(true)).await;
+        request(&mut handle, step(false)).await;
+        let rest = SessionCmd::Replace {
+            query: "foo".into(),
+            replacement: "bar".into(),
+            regex: false,
+            all: true,
+            rest: true,
+            whole_word: false,
+            in_selection: false,
+        };
+        let frame = request(&mut handle, rest).await;
+        let lines: Vec<_> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(lines, ["bar", "foo", "bar"]);
+    }
+
+    #[tokio::test]
+    async fn matches_whole_words_inside_the_selection() {
+        let (mut handle, _file) = spawn_text("cat concat cat\ncat cat", 24);
+        let search = |in_selection| SessionCmd::Search {
+            query: "cat".into(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: None,
+            whole_word: true,
+            in_selection,
+            start_confirm: false,
+        };
+        let frame = request(&mut handle, search(false)).await;
+        assert_eq!(frame.status_right, "1 of 4");
+
+        // Only the whole words from "concat" to the end of line 1 count,
+        // and the search wraps within them.
+        let select = SessionCmd::Select {
+            anchor: 4,
+            head: 18,
+            mode: SelectMode::Set,
+        };
+        request(&mut handle, select).await;
+        let frame = request(&mut handle, search(true)).await;
+        assert_eq!(frame.status_right, "1 of 2");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 14));
+
+        // Replacing every match keeps to the range the search started in.
+        let all = SessionCmd::Replace {
+            query: "cat".into(),
+            replacement: "dog".into(),
+            regex: false,
+            all: true,
+            rest: false,
+            whole_word: true,
+            in_selection: true,
+        };
+        let frame = request(&mut handle, all).await;
+        assert_eq!(frame.lines[0].text, "cat concat dog");
+        assert_eq!(frame.lines[1].text, "dog cat");
+        assert_eq!(frame.status_right, "0 of 0");
+    }
+
+    #[tokio::test]
+    async fn searches_large_documents_a_chunk_at_a_time() {
+        let filler = "x\n".repeat(SEARCH_CHUNK);
+        let (mut handle, _file) = spawn_text(&format!("needle\n{filler}needle\n{filler}"), 24);
+        let search = |query: &str| SessionCmd::Search {
+            query: query.into(),
+            dir: SearchDir::Next,
+            regex: false,
+            replacement: None,
+            whole_word: false,
+            in_selection: false,
+            start_confirm: false,
+        };
+        // The lines shown are searched first, and the match there selected
+        // before the rest of the document is searched.
+        let mut frame = request(&mut handle, search("needle")).await;
+        assert_eq!(frame.status_right, "1 of 1+");
+        while frame.status_right.ends_with('+') {
+            frame = handle.frames.recv().await.unwrap();
+        }
+        assert_eq!(frame.status_right, "1 of 2");
+
+        // A new query starts over, searching on only until the next match.
+        let frame = request(&mut handle, search("need")).await;
+        assert_eq!(frame.cursors[0].line as usize, SEARCH_CHUNK + 1);
+        assert_eq!(frame.status_right, "2 of 2+");
+    }
+
+    #[tokio::test]
+    async fn replace_all_is_undone_in_one_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "a1 b22 c333").unwrap();
+        let mut handle = open(&path, 80, 24).unwrap();
+        let frame = request(&mut handle, replace(r"\d+", "<$0>", true, true)).await;
+        assert_eq!(frame.lines[0].text, "a<1> b<22> c<333>");
+        assert_eq!((frame.doc_v, frame.cursors[0].col), (1, 17));
+
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[0].text, "a1 b22 c333");
+        let frame = request(&mut handle, SessionCmd::Redo).await;
+        assert_eq!(frame.lines[0].text, "a<1> b<22> c<333>");
+
+        // Each step is logged as a delete and an insert of one version.
+        let records = Wal::replay(wal_path(&path).unwrap()).unwrap();
+        let versions: Vec<_> = records.iter().map(|r| r.doc_v).collect();
+        assert_eq!(versions, [1, 1, 2, 2, 3, 3]);
+        let mut buf = RopeBuffer::from_text("a1 b22 c333");
+        for record in &records {
+            apply_op(&mut buf, &record.op);
+        }
+        assert_eq!(buf.text(), "a<1> b<22> c<333>");
+    }
+
+    fn insert(text: &str) -> SessionCmd {
+        SessionCmd::Insert {
+            text: text.into(),
+            pos: Some(0),
+            seq: None,
+            base_doc_v: None,
+        }
+    }
+
+    fn add_cursor(target: CursorTarget) -> SessionCmd {
+        SessionCmd::AddCursor { target }
+    }
+
+    #[tokio::test]
+    async fn undo_and_redo_put_back_every_cursor() {
+        let (mut handle, _file) = spawn_text("ab\ncd\nef", 24);
+        request(&mut handle, add_cursor(CursorTarget::Below)).await;
+        request(&mut handle, add_cursor(CursorTarget::Below)).await;
+        request(&mut handle, insert("xy")).await;
+        let select = SessionCmd::Select {
+            anchor: 0,
+            head: 0,
+            mode: SelectMode::Set,
+        };
+        let frame = request(&mut handle, select).await;
+        assert_eq!(frame.cursors.len(), 1);
+
+        let cursors = |frame: &Frame| -> Vec<(u64, u16)> {
+            frame.cursors.iter().map(|c| (c.line, c.col)).collect()
+        };
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[2].text, "ef");
+        assert_eq!(cursors(&frame), [(0, 0), (1, 0), (2, 0)]);
+        let frame = request(&mut handle, SessionCmd::Redo).await;
+        assert_eq!(frame.lines[2].text, "xyef");
+        assert_eq!(cursors(&frame), [(0, 2), (1, 2), (2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn types_and_deletes_at_every_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "ab\ncd\nef").unwrap();
+        let mut handle = open(&path, 80, 24).unwrap();
+        request(&mut handle, add_cursor(CursorTarget::Below)).await;
+        let frame = request(&mut handle, add_cursor(CursorTarget::Below)).await;
+        assert_eq!(frame.cursors.len(), 3);
+        // Typing ignores where the client thinks the cursor is.
+        let frame = request(&mut handle, insert("x")).await;
+        let texts: Vec<_> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["xab", "xcd", "xef"]);
+        let cursors: Vec<_> = frame.cursors.iter().map(|c| (c.line, c.col)).collect();
+        assert_eq!(cursors, [(0, 1), (1, 1), (2, 1)]);
+
+        let mv = SessionCmd::Move {
+            dir: Direction::Right,
+            granularity: Granularity::Grapheme,
+            extend: true,
+            block: false,
+        };
+        request(&mut handle, mv).await;
+        let delete = SessionCmd::Delete {
+            range: 0..0,
+            seq: None,
+            base_doc_v: None,
+        };
+        let frame = request(&mut handle, delete).await;
+        let texts: Vec<_> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["xb", "xd", "xf"]);
+
+        // Each edit at every cursor is undone in one step.
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[2].text, "xef");
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[2].text, "ef");
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 0));
+        let frame = request(&mut handle, SessionCmd::Redo).await;
+        assert_eq!(frame.lines[1].text, "xcd");
+
+        let clear = SessionCmd::Select {
+            anchor: 0,
+            head: 0,
+            mode: SelectMode::Clear,
+        };
+        let frame = request(&mut handle, clear).await;
+        assert_eq!(frame.cursors.len(), 1);
+
+        let records = Wal::replay(wal_path(&path).unwrap()).unwrap();
+        let mut buf = RopeBuffer::from_text("ab\ncd\nef");
+        for record in &records {
+            apply_op(&mut buf, &record.op);
+        }
+        assert_eq!(buf.text(), "xab\nxcd\nxef");
+    }
+
+    #[tokio::test]
+    async fn adds_cursors_at_next_matches() {
+        let (mut handle, _file) = spawn_text("foo bar foo\nfoo", 24);
+        let frame = request(&mut handle, add_cursor(CursorTarget::NextMatch)).await;
+        let selection = frame.status.unwrap().selection.unwrap();
+        assert_eq!((selection.from, selection.to), (0, 3));
+        request(&mut handle, add_cursor(CursorTarget::NextMatch)).await;
+        request(&mut handle, add_cursor(CursorTarget::NextMatch)).await;
+        // Every occurrence is selected already.
+        let frame = request(&mut handle, add_cursor(CursorTarget::NextMatch)).await;
+        let cursors: Vec<_> = frame.cursors.iter().map(|c| (c.line, c.col)).collect();
+        assert_eq!(cursors, [(0, 3), (0, 11), (1, 3)]);
+
+        let frame = request(&mut handle, insert("x")).await;
+        assert_eq!(frame.lines[0].text, "x bar x");
+        assert_eq!(frame.lines[1].text, "x");
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[0].text, "foo bar foo");
+    }
+
+    async fn copy(handle: &mut SessionHandle) -> String {
+        handle.cmd.send(SessionCmd::Copy).await.unwrap();
+        match handle.events.recv().await {
+            Some(SessionEvent::Copy(Copy { text })) => text,
+            other => panic!("expected copy, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn selects_types_and_pastes_blocks() {
+        let (mut handle, _file) = spawn_text("abcd\nef\nghij", 24);
+        let block = |dir| SessionCmd::Move {
+            dir,
+            granularity: Granularity::Grapheme,
+            extend: true,
+            block: true,
+        };
+        request(&mut handle, block(Direction::Right)).await;
+        for dir in [Direction::Down, Direction::Down, Direction::Right] {
+            request(&mut handle, block(dir)).await;
+        }
+        // The middle line is too short to reach the right edge.
+        assert_eq!(copy(&mut handle).await, "ab\nef\ngh");
+        request(&mut handle, block(Direction::Left)).await;
+        assert_eq!(copy(&mut handle).await, "a\ne\ng");
+
+        let frame = request(&mut handle, insert("X")).await;
+        let texts: Vec<_> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["Xbcd", "Xf", "Xhij"]);
+        let frame = request(&mut handle, insert("1\n2\n3\n")).await;
+        let texts: Vec<_> = frame.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["X1bcd", "X2f", "X3hij"]);
+
+        // A click drops the block; dragging with Alt selects one past line ends.
+        let goto = |line, col, extend| SessionCmd::GotoLine {
+            line,
+            col,
+            extend,
+            block: true,
+        };
+        let frame = request(&mut handle, goto(2, 3, false)).await;
+        assert_eq!(frame.cursors.len(), 1);
+        let frame = request(&mut handle, goto(1, 9, true)).await;
+        assert_eq!(frame.cursors.len(), 2);
+        assert_eq!(copy(&mut handle).await, "\nij");
+    }
+
+    #[tokio::test]
+    async fn edits_go_through_the_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "abc").unwrap();
+        let wal = wal_path(&path).unwrap();
+
+        // Edits left in the log by a crashed session are replayed on open.
+        std::fs::create_dir_all(wal.parent().unwrap()).unwrap();
+        let mut log = Wal::new(&wal).unwrap();
+        log.append(&EditRecord {
+            doc_v: 1,
+            op: EditOp::Insert {
+                idx: 3,
+                bytes: b"d".to_vec(),
+            },
+        })
+        .unwrap();
+        drop(log);
+        let mut handle = open(&path, 80, 24).unwrap();
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.lines[0].text, "abcd");
+        assert_eq!(frame.doc_v, 1);
+        assert!(frame.status.unwrap().dirty);
+
+        request(
+            &mut handle,
+            SessionCmd::Delete {
+                range: 0..1,
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+        assert_eq!(Wal::replay(&wal).unwrap().len(), 2);
+
+        // Saving writes the edits out and empties the log.
+        handle.cmd.send(SessionCmd::Save).await.unwrap();
+        request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "bcd");
+        assert!(Wal::replay(&wal).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn creates_the_wal_on_the_first_edit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "abc").unwrap();
+        let mut handle = open(&path, 80, 24).unwrap();
+        request(&mut handle, SessionCmd::RequestFrame).await;
+        assert!(!dir.path().join(WAL_DIR).exists());
+
+        let insert = SessionCmd::Insert {
+            pos: Some(0),
+            text: "x".into(),
+            seq: None,
+            base_doc_v: None,
+        };
+        request(&mut handle, insert).await;
+        assert_eq!(Wal::replay(wal_path(&path).unwrap()).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn undo_and_redo_bump_doc_v_and_reach_the_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let mut handle = open(&path, 80, 24).unwrap();
+        for (i, c) in " world".chars().enumerate() {
+            let cmd = SessionCmd::Insert {
+                text: c.to_string(),
+                pos: Some(5 + i),
+                seq: None,
+                base_doc_v: None,
+            };
+            request(&mut handle, cmd).await;
+        }
+        request(
+            &mut handle,
+            SessionCmd::Delete {
+                range: 0..1,
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[0].text, "hello world");
+        assert_eq!(frame.cursors[0].col, 1);
+        assert_eq!(frame.doc_v, 8);
+        // Typed characters are undone together.
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.lines[0].text, "hello");
+        assert_eq!(frame.cursors[0].col, 5);
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(frame.doc_v, 9);
+
+        let frame = request(&mut handle, SessionCmd::Redo).await;
+        assert_eq!(frame.lines[0].text, "hello world");
+        assert_eq!(frame.cursors[0].col, 11);
+        assert_eq!(frame.doc_v, 10);
+
+        // Both are logged like any other edit.
+        let records = Wal::replay(wal_path(&path).unwrap()).unwrap();
+        assert_eq!(records.len(), 10);
+        assert!(matches!(
+            &records[9].op,
+            EditOp::Insert { idx: 5, bytes } if bytes == b" world"
+        ));
+    }
+
+    #[tokio::test]
+    async fn differ_sends_diffs_after_first_frame() {
+        let file = NamedTempFile::new().unwrap();
+        let mut handle =
+            Session::spawn(RopeBuffer::from_text(""), file.path().to_path_buf(), 80, 24);
+        let mut differ = FrameDiffer::default();
+
+        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
+        let first = handle.frames.recv().await.unwrap();
+        assert!(matches!(differ.update(first.clone()), FrameUpdate::Full(_)));
+
+        handle
+            .cmd
+            .send(SessionCmd::Insert {
+                text: "hi".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
+            .await
+            .unwrap();
+        let second = handle.frames.recv().await.unwrap();
+        match differ.update(second.clone()) {
+            FrameUpdate::Diff(diff) => {
+                assert_eq!(diff.changes.len(), 1);
+                assert_eq!(diff.apply(&first), Some(second.clone()));
+            }
+            other => panic!("expected diff, got {other:?}"),
+        }
+
+        differ.reset();
+        assert!(matches!(differ.update(second), FrameUpdate::Full(_)));
+    }
+
+    #[tokio::test]
+    async fn picker_actions_list_and_open_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "alpha").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "a.txt", 80, 24).unwrap();
+
+        let picker = |action| SessionCmd::Picker { action };
+        handle
+            .cmd
+            .send(picker(PickerAction::Create {
+                path: "docs".into(),
+                dir: true,
+            }))
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::DirList(list) => {
+                assert_eq!(list.path, "");
+                assert_eq!(list.entries[0].path, "docs");
+                assert!(list.entries[0].is_dir);
+            }
+            other => panic!("expected listing, got {other:?}"),
+        }
+
+        handle
+            .cmd
+            .send(picker(PickerAction::Search {
+                query: "bt".into(),
+                limit: 5,
+            }))
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::DirList(list) => {
+                assert_eq!(list.query.as_deref(), Some("bt"));
+                assert_eq!(list.entries[0].path, "b.txt");
+            }
+            other => panic!("expected results, got {other:?}"),
+        }
+
+        let frame = request(
+            &mut handle,
+            picker(PickerAction::Open {
+                path: "b.txt".into(),
+            }),
+        )
+        .await;
+        assert_eq!(frame.lines[0].text, "beta");
+        assert!(!frame.status.unwrap().dirty);
+
+        handle
+            .cmd
+            .send(picker(PickerAction::Delete {
+                path: "../a.txt".into(),
+            }))
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => {
+                assert_eq!(err.code, ErrorCode::Sandbox);
+                assert_eq!(err.path.as_deref(), Some("../a.txt"));
+            }
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn second_session_on_a_file_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "alpha").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut writer = open_in(ws.clone(), "a.txt", 80, 24).unwrap();
+        let mut reader = open_in(ws.clone(), "a.txt", 80, 24).unwrap();
+        let insert = || SessionCmd::Insert {
+            text: "x".into(),
+            pos: Some(0),
+            seq: None,
+            base_doc_v: None,
+        };
+
+        let frame = request(&mut reader, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.status.unwrap().lock, LockState::ReadOnly);
+        reader.cmd.send(insert()).await.unwrap();
+        match reader.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => assert_eq!(err.code, ErrorCode::Readonly),
+            other => panic!("expected error, got {other:?}"),
+        }
+        let frame = request(&mut writer, insert()).await;
+        assert_eq!(frame.lines[0].text, "xalpha");
+        assert_eq!(frame.status.unwrap().lock, LockState::Writable);
+
+        // Other files stay free, and switching away releases the lock.
+        let open = |path: &str| SessionCmd::Open { path: path.into() };
+        let frame = request(&mut reader, open("b.txt")).await;
+        assert_eq!(frame.status.unwrap().lock, LockState::Writable);
+        request(&mut writer, open("b.txt")).await;
+        let frame = request(&mut reader, open("a.txt")).await;
+        assert_eq!(frame.lines[0].text, "xalpha");
+        assert_eq!(frame.status.unwrap().lock, LockState::Writable);
+    }
+
+    #[tokio::test]
+    async fn reports_external_changes_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "one").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "a.txt", 80, 24).unwrap();
+        let insert = || SessionCmd::Insert {
+            text: "x".into(),
+            pos: Some(0),
+            seq: None,
+            base_doc_v: None,
+        };
+
+        std::fs::write(&path, "two").unwrap();
+        let change = next_change(&mut handle).await;
+        assert_eq!(change.path, "a.txt");
+        assert!(!change.removed && !change.conflict);
+
+        // Autosave holds off rather than overwrite the other process.
+        let frame = request(&mut handle, insert()).await;
+        assert_eq!(frame.lines[0].text, "xone");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "two");
+        std::fs::write(&path, "three").unwrap();
+        assert!(next_change(&mut handle).await.conflict);
+
+        let frame = request(&mut handle, SessionCmd::Reload).await;
+        assert_eq!(frame.lines[0].text, "three");
+        request(&mut handle, insert()).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xthree");
+    }
+
+    #[tokio::test]
+    async fn jumps_back_and_forward_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text: String = (0..100).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(dir.path().join("a.txt"), text).unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "a.txt", 80, 10).unwrap();
+        let jump = |dir| SessionCmd::Jump { dir };
+        let line = |frame: &Frame| frame.cursors[0].line;
+
+        // Going to a line shown is no jump; switching files is one.
+        request(&mut handle, goto(50)).await;
+        request(&mut handle, goto(45)).await;
+        let frame = request(
+            &mut handle,
+            SessionCmd::Open {
+                path: "b.txt".into(),
+            },
+        )
+        .await;
+        assert_eq!(frame.lines[0].text, "beta");
+
+        let frame = request(&mut handle, jump(JumpDir::Back)).await;
+        assert_eq!(
+            (line(&frame), frame.lines[0].text.as_str()),
+            (45, "line 41")
+        );
+        let frame = request(&mut handle, jump(JumpDir::Back)).await;
+        assert_eq!((line(&frame), frame.lines[0].text.as_str()), (0, "line 0"));
+        let frame = request(&mut handle, jump(JumpDir::Back)).await;
+        assert_eq!(line(&frame), 0);
+        request(&mut handle, jump(JumpDir::Forward)).await;
+        let frame = request(&mut handle, jump(JumpDir::Forward)).await;
+        assert_eq!(frame.lines[0].text, "beta");
+        assert_eq!(*handle.path.borrow(), "b.txt");
+        let frame = request(&mut handle, jump(JumpDir::Forward)).await;
+        assert_eq!(frame.lines[0].text, "beta");
+
+        // Jumping after going back forgets where forward led.
+        request(&mut handle, jump(JumpDir::Back)).await;
+        request(&mut handle, goto(90)).await;
+        let frame = request(&mut handle, jump(JumpDir::Forward)).await;
+        assert_eq!(line(&frame), 90);
+        let frame = request(&mut handle, jump(JumpDir::Back)).await;
+        assert_eq!(line(&frame), 45);
+    }
+
+    #[tokio::test]
+    async fn bookmarks_follow_edits_and_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let text: String = (0..10).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(dir.path().join("a.txt"), text).unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta\ngamma").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws.clone(), "a.txt", 80, 20).unwrap();
+        let mark = |action| SessionCmd::Bookmark { action };
+        let marked = |frame: &Frame| -> Vec<u64> {
+            let lines = frame.lines.iter().enumerate();
+            lines
+                .filter(|(_, l)| l.marked)
+                .map(|(i, _)| i as u64)
+                .collect()
+        };
+
+        request(&mut handle, goto(2)).await;
+        request(&mut handle, mark(BookmarkAction::Toggle)).await;
+        request(&mut handle, goto(7)).await;
+        let frame = request(&mut handle, mark(BookmarkAction::Toggle)).await;
+        assert_eq!(marked(&frame), [2, 7]);
+        let frame = request(&mut handle, insert("new\n")).await;
+        assert_eq!(marked(&frame), [3, 8]);
+
+        request(&mut handle, goto(5)).await;
+        let mut lines = Vec::new();
+        for action in [
+            BookmarkAction::Next,
+            BookmarkAction::Next,
+            BookmarkAction::Prev,
+        ] {
+            let frame = request(&mut handle, mark(action)).await;
+            lines.push(frame.cursors[0].line);
+        }
+        assert_eq!(lines, [8, 3, 8]);
+
+        let open = |path: &str| SessionCmd::Open { path: path.into() };
+        request(&mut handle, open("b.txt")).await;
+        request(&mut handle, goto(1)).await;
+        request(&mut handle, mark(BookmarkAction::Toggle)).await;
+        handle.cmd.send(mark(BookmarkAction::List)).await.unwrap();
+        let SessionEvent::Bookmarks(list) = handle.events.recv().await.unwrap() else {
+            panic!("expected bookmarks");
+        };
+        let marks: Vec<_> = (list.marks.iter())
+            .map(|m| (m.path.as_str(), m.line, m.text.as_str()))
+            .collect();
+        assert_eq!(
+            marks,
+            [
+                ("a.txt", 3, "line 2"),
+                ("a.txt", 8, "line 7"),
+                ("b.txt", 1, "gamma")
+            ]
+        );
+
+        // Another session on the file finds them where they were left.
+        let marks = std::fs::read_to_string(dir.path().join(".ghostwriter/a.txt.marks"));
+        assert_eq!(marks.unwrap(), "3\n8\n");
+        let mut other = open_in(ws, "a.txt", 80, 20).unwrap();
+        let frame = request(&mut other, SessionCmd::RequestFrame).await;
+        assert_eq!(marked(&frame), [3, 8]);
+
+        let frame = request(&mut handle, mark(BookmarkAction::Toggle)).await;
+        assert_eq!(marked(&frame), [] as [u64; 0]);
+        assert!(!dir.path().join(".ghostwriter/b.txt.marks").exists());
+    }
+
+    #[tokio::test]
+    async fn undo_history_outlives_the_session() {
+        use crate::history::{DEFAULT_UNDO_HISTORY_SIZE, UndoHistory};
+
+        let dir = tempfile::tempdir().unwrap();
+        let history = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let kept = ws.clone().with_undo_history(UndoHistory {
+            dir: Some(history.path().to_path_buf()),
+            max_size: DEFAULT_UNDO_HISTORY_SIZE,
+        });
+        let open = |path: &str| SessionCmd::Open { path: path.into() };
+        let reopen = async |ws: &Workspace| {
+            let mut handle = open_in(ws.clone(), "a.txt", 80, 5).unwrap();
+            let frame = request(&mut handle, SessionCmd::Undo).await;
+            request(&mut handle, open("b.txt")).await;
+            frame.lines[0].text.clone()
+        };
+
+        // Switching files saves the one left, with its history.
+        let mut handle = open_in(kept.clone(), "a.txt", 80, 5).unwrap();
+        request(&mut handle, insert("oh ")).await;
+        request(&mut handle, insert("say ")).await;
+        request(&mut handle, open("b.txt")).await;
+        assert_eq!(reopen(&kept).await, "oh hello");
+        assert_eq!(reopen(&kept).await, "hello");
+
+        // Without it, or once the file changed, there is nothing to undo.
+        request(&mut handle, open("a.txt")).await;
+        request(&mut handle, insert("oh ")).await;
+        request(&mut handle, open("b.txt")).await;
+        assert_eq!(reopen(&ws).await, "oh hello");
+        std::fs::write(dir.path().join("a.txt"), "oh hello!").unwrap();
+        assert_eq!(reopen(&kept).await, "oh hello!");
+    }
+
+    #[tokio::test]
+    async fn tab_expands_snippets_and_steps_through_their_stops() {
+        use crate::snippets::Snippets;
+
+        let dir = tempfile::tempdir().unwrap();
+        let snippets = tempfile::tempdir().unwrap();
+        let body = "fn = \"fn ${1:name}($2) {\\n    $0\\n}\"\n";
+        std::fs::write(snippets.path().join("rs.toml"), body).unwrap();
+        std::fs::write(dir.path().join("a.rs"), "    fn").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "fn").unwrap();
+        let snippets = Snippets::load(snippets.path()).unwrap();
+        let ws = Workspace::new(dir.path())
+            .unwrap()
+            .with_snippets(Arc::new(snippets));
+        let end = |col| SessionCmd::GotoLine {
+            line: 0,
+            col,
+            extend: false,
+            block: false,
+        };
+        let tab = |back| SessionCmd::Tab { back };
+        let texts =
+            |frame: &Frame| -> Vec<String> { frame.lines.iter().map(|l| l.text.clone()).collect() };
+        let selected = |frame: &Frame| {
+            let sel = frame.lines[0].spans.iter();
+            let mut sel = sel.filter(|span| frame.class_name(span) == Some("sel"));
+            sel.next().map(|span| (span.start_col, span.end_col))
+        };
+
+        let mut handle = open_in(ws.clone(), "a.rs", 80, 5).unwrap();
+        request(&mut handle, end(6)).await;
+        let frame = request(&mut handle, tab(false)).await;
+        assert_eq!(texts(&frame), ["    fn name() {", "        ", "    }"]);
+        assert_eq!(selected(&frame), Some((7, 11)));
+        // Typing over a stop moves the ones after it along.
+        let delete = SessionCmd::Delete {
+            range: 7..11,
+            seq: None,
+            base_doc_v: None,
+        };
+        request(&mut handle, delete).await;
+        let typed = SessionCmd::Insert {
+            text: "run".into(),
+            pos: Some(7),
+            seq: None,
+            base_doc_v: None,
+        };
+        request(&mut handle, typed).await;
+        let frame = request(&mut handle, tab(false)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (0, 11));
+        assert_eq!(selected(&frame), None);
+        let frame = request(&mut handle, tab(true)).await;
+        assert_eq!(selected(&frame), Some((7, 10)));
+        request(&mut handle, tab(false)).await;
+        let frame = request(&mut handle, tab(false)).await;
+        assert_eq!((frame.cursors[0].line, frame.cursors[0].col), (1, 8));
+        // Past the last stop, Tab types a tab again.
+        let frame = request(&mut handle, tab(false)).await;
+        assert_eq!(frame.lines[1].text, "        \t");
+        for _ in 0..3 {
+            request(&mut handle, SessionCmd::Undo).await;
+        }
+        let frame = request(&mut handle, SessionCmd::Undo).await;
+        assert_eq!(texts(&frame), ["    fn"]);
+
+        // Snippets only expand in files of their language.
+        let mut handle = open_in(ws, "b.txt", 80, 5).unwrap();
+        request(&mut handle, end(2)).await;
+        let frame = request(&mut handle, tab(false)).await;
+        assert_eq!(frame.lines[0].text, "fn\t");
+    }
+
+    #[tokio::test]
+    async fn open_switches_files_and_restores_their_view() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "one\ntwo\nthree").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "beta").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "a.txt", 80, 2).unwrap();
+        let open = |path: &str| SessionCmd::Open { path: path.into() };
+
+        request(&mut handle, goto(2)).await;
+        let frame = request(&mut handle, open("b.txt")).await;
+        assert_eq!(frame.lines[0].text, "beta");
+        assert_eq!((frame.first_line, frame.cursors[0].line), (0, 0));
+        request(
+            &mut handle,
+            SessionCmd::Insert {
+                text: "x".into(),
+                pos: Some(0),
+                seq: None,
+                base_doc_v: None,
+            },
+        )
+        .await;
+
+        // Switching back saves b.txt and returns to where a.txt was left.
+        let frame = request(&mut handle, open("a.txt")).await;
+        assert_eq!(frame.lines[1].text, "three");
+        assert_eq!((frame.first_line, frame.cursors[0].line), (1, 2));
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("b.txt")).unwrap(),
+            "xbeta"
+        );
+        let frame = request(&mut handle, open("b.txt")).await;
+        assert_eq!(frame.cursors[0].col, 1);
+
+        handle.cmd.send(SessionCmd::ListBuffers).await.unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Buffers(list) => {
+                assert_eq!(list.paths, ["a.txt", "b.txt"]);
+                assert_eq!(list.active, 1);
+            }
+            other => panic!("expected buffer list, got {other:?}"),
+        }
+
+        handle.cmd.send(open("../c.txt")).await.unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => assert_eq!(err.code, ErrorCode::Sandbox),
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn picker_frames_drive_file_selection() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/guide.md"), "# Guide").unwrap();
+        std::fs::write(dir.path().join("a.txt"), "alpha").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        let mut handle = open_in(ws, "a.txt", 20, 4).unwrap();
+        let picker = |action| SessionCmd::Picker { action };
+
+        handle
+            .cmd
+            .send(picker(PickerAction::Filter { query: "x".into() }))
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => assert_eq!(err.code, ErrorCode::Invalid),
+            other => panic!("expected error, got {other:?}"),
+        }
+
+        let frame = request(&mut handle, picker(PickerAction::Show { path: "".into() })).await;
+        assert_eq!(frame.kind, "picker");
+        assert_eq!(frame.lines[0].text, "docs/     |guide.md");
+        assert_eq!(frame.lines[1].text, "a.txt     |");
+
+        // Editor commands keep showing the picker.
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.kind, "picker");
+
+        let frame = request(&mut handle, picker(PickerAction::Accept)).await;
+        assert_eq!(frame.status_left, "/docs");
+        assert_eq!(frame.lines[0].text, "guide.md  |# Guide");
+        let frame = request(&mut handle, picker(PickerAction::Accept)).await;
+        assert_eq!(frame.kind, "editor");
+        assert_eq!(frame.lines[0].text, "# Guide");
+
+        request(&mut handle, picker(PickerAction::Show { path: "".into() })).await;
+        request(
+            &mut handle,
+            picker(PickerAction::MoveSelection { delta: 1 }),
+        )
+        .await;
+        let frame = request(
+            &mut handle,
+            picker(PickerAction::Filter { query: "zz".into() }),
+        )
+        .await;
+        assert!(frame.lines.is_empty());
+        let frame = request(&mut handle, picker(PickerAction::Close)).await;
+        assert_eq!(frame.lines[0].text, "# Guide");
+    }
+
+    #[tokio::test]
+    async fn picker_without_workspace_is_unsupported() {
+        let (mut handle, _file) = spawn_text("", 24);
+        handle
+            .cmd
+            .send(SessionCmd::Picker {
+                action: PickerAction::Expand { path: "".into() },
+            })
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => assert_eq!(err.code, ErrorCode::Unsupported),
+            other => panic!("expected error, got {other:?}"),
+        }
     }
 
     #[tokio::test]
@@ -267,5 +4544,81 @@ mod tests {
             frame.lines[0].text,
             "FF 00 41                                         |..A",
         );
+
+        handle
+            .cmd
+            .send(SessionCmd::Insert {
+                text: "x".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
+            .await
+            .unwrap();
+        match handle.events.recv().await.unwrap() {
+            SessionEvent::Error(err) => {
+                assert_eq!(err.code, ErrorCode::Readonly);
+                assert_eq!(err.doc_v, Some(0));
+            }
+            other => panic!("expected error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_through_hex_views() {
+        let mut file = NamedTempFile::new().unwrap();
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        file.write_all(&bytes).unwrap();
+        let mut handle = open(file.path(), 80, 4).unwrap();
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.kind, "hex");
+        assert!(frame.lines[0].text.starts_with("00 01 02"));
+
+        let frame = request(&mut handle, scroll(1, ScrollUnit::Page, 0)).await;
+        assert_eq!(frame.first_line, 4);
+        assert!(frame.lines[0].text.starts_with("40 41 42"));
+        let frame = request(&mut handle, scroll(99, ScrollUnit::Page, 0)).await;
+        assert_eq!(frame.first_line, 62);
+        assert_eq!(frame.lines.len(), 1);
+        assert!(frame.lines[0].text.starts_with("E0 E1"));
+
+        // Invalid UTF-8 past the sniffed prefix still ends up in hex.
+        let mut late = NamedTempFile::new().unwrap();
+        late.write_all(&vec![b'a'; 20_000]).unwrap();
+        late.write_all(&[0xFF]).unwrap();
+        let mut handle = open(late.path(), 80, 4).unwrap();
+        let frame = request(&mut handle, SessionCmd::RequestFrame).await;
+        assert_eq!(frame.kind, "hex");
+        assert_eq!(frame.status.unwrap().encoding, "binary");
+    }
+
+    #[tokio::test]
+    async fn autosaves_when_link_drops() {
+        use ghostwriter_core::DisconnectReason;
+
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let mut handle = Session::spawn(RopeBuffer::from_text(""), path.clone(), 80, 24);
+        handle
+            .cmd
+            .send(SessionCmd::Insert {
+                text: "kept".into(),
+                pos: None,
+                seq: None,
+                base_doc_v: None,
+            })
+            .await
+            .unwrap();
+        let _ = handle.frames.recv().await.unwrap();
+
+        let (link_tx, link_rx) = watch::channel(LinkState::Connected);
+        let task = autosave_on_disconnect(handle.cmd.clone(), link_rx);
+        link_tx
+            .send(LinkState::Disconnected(DisconnectReason::PongTimeout))
+            .unwrap();
+        task.await.unwrap();
+        handle.cmd.send(SessionCmd::RequestFrame).await.unwrap();
+        let _ = handle.frames.recv().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "kept");
     }
 }
@@ -0,0 +1,177 @@
+//! User-defined snippets, expanded by typing their trigger word and Tab.
+//!
+//! The snippets directory holds a TOML file per language, named after the
+//! extension of the files it applies to, mapping triggers to bodies:
+//!
+//! ```toml
+//! # rs.toml
+//! fn = "fn ${1:name}($2) {\n    $0\n}"
+//! ```
+//!
+//! `$1`, `$2` and so on are tab stops, visited in order with Tab; `${1:text}`
+//! fills one with text to type over. `$0` is where the cursor ends up, the
+//! end of the snippet if left out. `\$` stands for a dollar sign.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// A snippet body with its tab stops taken out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub text: String,
+    /// Byte ranges of `text` Tab selects in turn; the last is where the
+    /// cursor ends up.
+    pub stops: Vec<Range<usize>>,
+}
+
+impl Snippet {
+    /// Take the tab stops out of `body`. A `$` followed by neither a
+    /// number nor `{` is kept as it is.
+    pub fn parse(body: &str) -> Result<Self, String> {
+        let mut text = String::with_capacity(body.len());
+        let mut stops: BTreeMap<u32, Range<usize>> = BTreeMap::new();
+        let mut rest = body;
+        while let Some(i) = rest.find(['$', '\\']) {
+            text.push_str(&rest[..i]);
+            let (c, after) = (rest.as_bytes()[i], &rest[i + 1..]);
+            if c == b'\\' {
+                match after.strip_prefix(['$', '\\']) {
+                    Some(escaped) => {
+                        text.push_str(&after[..1]);
+                        rest = escaped;
+                    }
+                    None => {
+                        text.push('\\');
+                        rest = after;
+                    }
+                }
+                continue;
+            }
+            let (number, default, len) = match after.strip_prefix('{') {
+                Some(inner) => {
+                    let end = inner.find('}').ok_or("`${` without `}`")?;
+                    let (number, default) =
+                        inner[..end].split_once(':').unwrap_or((&inner[..end], ""));
+                    (number, default, end + 2)
+                }
+                None => {
+                    let end = after
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(after.len());
+                    (&after[..end], "", end)
+                }
+            };
+            if len == 0 {
+                text.push('$');
+                rest = after;
+                continue;
+            }
+            let number: u32 =
+                (number.parse()).map_err(|_| format!("`{number}` is no tab stop number"))?;
+            let start = text.len();
+            text.push_str(default);
+            stops.entry(number).or_insert(start..text.len());
+            rest = &after[len..];
+        }
+        text.push_str(rest);
+        let end = stops.remove(&0).unwrap_or(text.len()..text.len());
+        let stops = stops.into_values().chain([end]).collect();
+        Ok(Snippet { text, stops })
+    }
+}
+
+/// The snippets of every language, by file extension and trigger.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snippets {
+    languages: HashMap<String, BTreeMap<String, Snippet>>,
+}
+
+impl Snippets {
+    /// Read the snippets in `dir`, none if it does not exist. A file that
+    /// does not parse fails the whole load.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let mut languages = HashMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "toml") {
+                continue;
+            }
+            let Some(language) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let invalid = |err: String| {
+                let msg = format!("{}: {err}", path.display());
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+            let bodies: BTreeMap<String, String> = toml::from_str(&std::fs::read_to_string(&path)?)
+                .map_err(|e| invalid(e.to_string()))?;
+            let snippets = (bodies.into_iter())
+                .map(|(trigger, body)| {
+                    let snippet =
+                        Snippet::parse(&body).map_err(|e| invalid(format!("{trigger}: {e}")))?;
+                    Ok((trigger, snippet))
+                })
+                .collect::<io::Result<_>>()?;
+            languages.insert(language.to_lowercase(), snippets);
+        }
+        Ok(Self { languages })
+    }
+
+    /// The `snippets` directory of the user's config directory, like
+    /// `~/.config/ghostwriter/snippets`.
+    pub fn config_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("ghostwriter").join("snippets"))
+    }
+
+    /// The snippet `trigger` names in files with the extension of `path`.
+    pub fn get(&self, path: &Path, trigger: &str) -> Option<&Snippet> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        self.languages.get(&ext)?.get(trigger)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.languages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_out_tab_stops() {
+        let snippet = Snippet::parse("fn ${1:name}($2) {\n    $0\n}").unwrap();
+        assert_eq!(snippet.text, "fn name() {\n    \n}");
+        assert_eq!(snippet.stops, [3..7, 8..8, 16..16]);
+
+        let snippet = Snippet::parse("\\$HOME costs $5${2:x}$").unwrap();
+        assert_eq!(snippet.text, "$HOME costs x$");
+        assert_eq!(snippet.stops, [12..13, 12..12, 14..14]);
+        assert!(Snippet::parse("${x}").is_err());
+        assert!(Snippet::parse("${1").is_err());
+    }
+
+    #[test]
+    fn loads_a_file_per_language() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rs.toml"), "p = \"println!($1);\"\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not snippets").unwrap();
+        let snippets = Snippets::load(dir.path()).unwrap();
+        let snippet = snippets.get(Path::new("src/main.RS"), "p").unwrap();
+        assert_eq!(snippet.text, "println!();");
+        assert_eq!(snippets.get(Path::new("main.py"), "p"), None);
+        assert_eq!(snippets.get(Path::new("Makefile"), "p"), None);
+
+        std::fs::write(dir.path().join("py.toml"), "p = \"${\"\n").unwrap();
+        let err = Snippets::load(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("py.toml: p:"));
+        assert!(Snippets::load(&dir.path().join("none")).unwrap().is_empty());
+    }
+}
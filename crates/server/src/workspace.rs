@@ -0,0 +1,853 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use ghostwriter_proto::DirEntry;
+
+use crate::audit::{FileAudit, FileOp, Peer};
+use crate::auth::verify_secret;
+use crate::history::UndoHistory;
+use crate::lock::{FileLock, FileLocks};
+use crate::metrics::Metrics;
+use crate::quota::{Quota, WriteLimits};
+use crate::session::WAL_DIR;
+use crate::snippets::Snippets;
+
+/// Directory names never shown in listings or searches.
+pub(crate) const IGNORED: &[&str] = &[".git", WAL_DIR];
+
+/// Prefix of paths in a workspace with named roots, as in
+/// `workspace:name/rel/path`.
+pub const ROOT_PREFIX: &str = "workspace:";
+
+/// Who may use a named root, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RootAccess {
+    /// Argon2 hash of the secret that unlocks the root. Without one, every
+    /// client admitted to the server may use it.
+    pub secret_hash: Option<String>,
+    /// Whether clients may only browse and view its files.
+    pub readonly: bool,
+}
+
+/// A directory served by a workspace; named when there are several.
+#[derive(Debug, Clone)]
+struct Root {
+    name: Option<String>,
+    path: PathBuf,
+    access: RootAccess,
+}
+
+/// What a client may do in a root, weakest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Grant {
+    None,
+    Read,
+    Write,
+}
+
+/// Workspace root, or named roots, to which all file operations are
+/// confined.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    roots: Arc<[Root]>,
+    /// Write locks of files in the workspace, shared by its clones.
+    locks: FileLocks,
+    audit: FileAudit,
+    metrics: Metrics,
+    quota: Quota,
+    /// Where sessions keep undo history between them.
+    undo_history: UndoHistory,
+    /// Snippets sessions expand on Tab.
+    snippets: Arc<Snippets>,
+    /// Whether files may only be viewed: creating, renaming, deleting and
+    /// locking them for editing all fail.
+    readonly: bool,
+    /// What this clone's client may do in each root, decided by the secret
+    /// it authenticated with. `None` for the server's own use, which
+    /// reaches every root.
+    grants: Option<Arc<[Grant]>>,
+    /// Client on whose behalf this clone works, as named in `audit`.
+    client: Option<Peer>,
+}
+
+impl Workspace {
+    /// Open the workspace rooted at `root`, which must be an existing
+    /// directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = Root {
+            name: None,
+            path: canonical_dir(root.as_ref())?,
+            access: RootAccess::default(),
+        };
+        Ok(Self::with_roots(vec![root]))
+    }
+
+    /// Serve several directories, each addressed as
+    /// `workspace:name/rel/path`. The top-level listing lists the names.
+    pub fn named<N, P>(roots: impl IntoIterator<Item = (N, P)>) -> io::Result<Self>
+    where
+        N: Into<String>,
+        P: AsRef<Path>,
+    {
+        let mut named: Vec<Root> = Vec::new();
+        for (name, path) in roots {
+            let name = name.into();
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid workspace name {name:?}"),
+                ));
+            }
+            if named.iter().any(|r| r.name.as_ref() == Some(&name)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("workspace {name:?} is given twice"),
+                ));
+            }
+            let path = canonical_dir(path.as_ref())?;
+            named.push(Root {
+                name: Some(name),
+                path,
+                access: RootAccess::default(),
+            });
+        }
+        if named.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no workspaces given",
+            ));
+        }
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self::with_roots(named))
+    }
+
+    fn with_roots(roots: Vec<Root>) -> Self {
+        Self {
+            roots: roots.into(),
+            locks: FileLocks::default(),
+            audit: FileAudit::default(),
+            metrics: Metrics::default(),
+            quota: Quota::default(),
+            undo_history: UndoHistory::default(),
+            snippets: Arc::default(),
+            readonly: false,
+            grants: None,
+            client: None,
+        }
+    }
+
+    /// Restrict the root named `name` to clients holding its secret, or to
+    /// viewing only, as `access` says.
+    pub fn with_root_access(mut self, name: &str, access: RootAccess) -> io::Result<Self> {
+        let mut roots = self.roots.to_vec();
+        let root = (roots.iter_mut())
+            .find(|root| root.name.as_deref() == Some(name))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no workspace named {name:?}"),
+                )
+            })?;
+        root.access = access;
+        self.roots = roots.into();
+        Ok(self)
+    }
+
+    /// Whether any root needs a secret of its own, so every client has to
+    /// authenticate.
+    pub(crate) fn has_root_secrets(&self) -> bool {
+        self.roots
+            .iter()
+            .any(|root| root.access.secret_hash.is_some())
+    }
+
+    /// Whether `secret` unlocks at least one root.
+    pub(crate) fn unlocks(&self, secret: &str) -> bool {
+        (self.roots.iter())
+            .filter_map(|root| root.access.secret_hash.as_deref())
+            .any(|hash| verify_secret(hash, secret))
+    }
+
+    /// A clone limited to the roots a client authenticated with `secret`
+    /// may use: those `secret` unlocks and, if the client was `admitted`
+    /// by the server's own secret or tokens (or needed none), those
+    /// without a secret. A root's secret thus opens that root alone.
+    pub(crate) fn for_secret(&self, secret: Option<&str>, admitted: bool) -> Self {
+        let grants = (self.roots.iter())
+            .map(|root| {
+                let unlocked = match (&root.access.secret_hash, secret) {
+                    (None, _) => admitted,
+                    (Some(hash), Some(secret)) => verify_secret(hash, secret),
+                    (Some(_), None) => false,
+                };
+                match (unlocked, root.access.readonly) {
+                    (false, _) => Grant::None,
+                    (true, true) => Grant::Read,
+                    (true, false) => Grant::Write,
+                }
+            })
+            .collect();
+        Self {
+            grants: Some(grants),
+            ..self.clone()
+        }
+    }
+
+    /// What this clone may do in the root at `index`.
+    fn grant(&self, index: usize) -> Grant {
+        match &self.grants {
+            Some(grants) => grants[index],
+            None if self.roots[index].access.readonly => Grant::Read,
+            None => Grant::Write,
+        }
+    }
+
+    /// The root at `index` as clients name it in messages.
+    fn label(&self, index: usize) -> String {
+        match &self.roots[index].name {
+            Some(name) => format!("{ROOT_PREFIX}{name}"),
+            None => "workspace".to_string(),
+        }
+    }
+
+    /// Record file operations in `audit`.
+    pub fn with_audit(mut self, audit: FileAudit) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Count saves and searches in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Enforce `limits` on saves and edits in sessions of this workspace.
+    pub fn with_limits(mut self, limits: WriteLimits) -> Self {
+        self.quota = Quota::new(limits);
+        self
+    }
+
+    pub(crate) fn quota(&self) -> &Quota {
+        &self.quota
+    }
+
+    /// Keep the undo history of files edited in sessions of this workspace
+    /// as `history` says.
+    pub fn with_undo_history(mut self, history: UndoHistory) -> Self {
+        self.undo_history = history;
+        self
+    }
+
+    pub(crate) fn undo_history(&self) -> &UndoHistory {
+        &self.undo_history
+    }
+
+    /// Let sessions of this workspace expand `snippets`.
+    pub fn with_snippets(mut self, snippets: Arc<Snippets>) -> Self {
+        self.snippets = snippets;
+        self
+    }
+
+    pub(crate) fn snippets(&self) -> &Snippets {
+        &self.snippets
+    }
+
+    /// Serve the workspace for browsing and viewing only when `readonly`
+    /// is set.
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// Fail with [`io::ErrorKind::ReadOnlyFilesystem`] unless `rel` may be
+    /// changed: the workspace is writable and so is its root for this
+    /// clone.
+    fn check_writable(&self, rel: &str) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "workspace is read-only",
+            ));
+        }
+        let (index, _) = self.split(rel)?;
+        match self.grant(index) {
+            Grant::Write => Ok(()),
+            Grant::Read => Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                format!("{} is read-only", self.label(index)),
+            )),
+            Grant::None => Err(self.no_access(index)),
+        }
+    }
+
+    /// Whether the resolved `path` may be changed; see
+    /// [`check_writable`](Self::check_writable).
+    pub(crate) fn can_write(&self, path: &Path) -> bool {
+        self.check_writable(&self.relative(path)).is_ok()
+    }
+
+    fn no_access(&self, index: usize) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("no access to {}", self.label(index)),
+        )
+    }
+
+    /// A clone whose file operations are recorded as made by `peer`, with
+    /// a write quota of its own.
+    pub fn for_client(&self, peer: &Peer) -> Self {
+        Self {
+            client: Some(peer.clone()),
+            quota: self.quota.for_client(),
+            ..self.clone()
+        }
+    }
+
+    /// Record `op` on `rel` in the audit log with the outcome `result`.
+    pub(crate) fn record<T>(
+        &self,
+        op: FileOp,
+        rel: &str,
+        bytes: Option<u64>,
+        result: &io::Result<T>,
+    ) {
+        let result = match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+        tracing::debug!(%op, path = rel, bytes, error = result.as_ref().err(), "file operation");
+        self.audit
+            .record(self.client.as_ref(), op, rel, bytes, result);
+    }
+
+    /// Canonical workspace root; the first one when there are several.
+    pub fn root(&self) -> &Path {
+        &self.roots[0].path
+    }
+
+    /// Canonical paths of every root.
+    pub fn roots(&self) -> impl Iterator<Item = &Path> {
+        self.roots.iter().map(|root| root.path.as_path())
+    }
+
+    /// Canonical paths of the roots this clone may read.
+    pub(crate) fn readable_roots(&self) -> impl Iterator<Item = &Path> {
+        (self.roots.iter().enumerate())
+            .filter(|&(index, _)| self.grant(index) >= Grant::Read)
+            .map(|(_, root)| root.path.as_path())
+    }
+
+    /// Whether the workspace has named roots.
+    pub fn is_named(&self) -> bool {
+        self.roots[0].name.is_some()
+    }
+
+    /// Index of the root `rel` lies in and the rest of the path below it.
+    fn split<'a>(&self, rel: &'a str) -> io::Result<(usize, &'a str)> {
+        if !self.is_named() {
+            return Ok((0, rel));
+        }
+        let not_found = || {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no workspace for {rel:?}; paths start with {ROOT_PREFIX}name"),
+            )
+        };
+        let named = rel.strip_prefix(ROOT_PREFIX).ok_or_else(not_found)?;
+        let (name, rest) = named.split_once('/').unwrap_or((named, ""));
+        let index = (self.roots.iter())
+            .position(|root| root.name.as_deref() == Some(name))
+            .ok_or_else(not_found)?;
+        Ok((index, rest))
+    }
+
+    /// Take the write lock on a resolved path, or `None` while another
+    /// session is editing it or it may not be changed.
+    pub fn lock(&self, path: &Path) -> Option<FileLock> {
+        let rel = self.relative(path);
+        let (lock, result) = match self.check_writable(&rel) {
+            Ok(()) => {
+                let lock = self.locks.try_lock(path);
+                let result = match lock {
+                    Some(_) => Ok(()),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "locked by another session",
+                    )),
+                };
+                (lock, result)
+            }
+            Err(e) => (None, Err(e)),
+        };
+        self.record(FileOp::Lock, &rel, None, &result);
+        lock
+    }
+
+    /// Resolve a workspace-relative path to an absolute one, rejecting
+    /// anything that escapes its root or lies in a root this clone may not
+    /// read. The path itself need not exist, but its parent must.
+    pub fn resolve(&self, rel: &str) -> io::Result<PathBuf> {
+        let (index, rel) = self.split(rel)?;
+        if self.grant(index) < Grant::Read {
+            return Err(self.no_access(index));
+        }
+        let root = &self.roots[index];
+        let rel_path = Path::new(rel);
+        if rel_path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(escape_error());
+        }
+        let joined = root.path.join(rel_path);
+        let resolved = match joined.canonicalize() {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let parent = joined.parent().ok_or_else(escape_error)?.canonicalize()?;
+                let name = joined.file_name().ok_or_else(escape_error)?;
+                parent.join(name)
+            }
+            Err(e) => return Err(e),
+        };
+        if resolved.starts_with(&root.path) {
+            Ok(resolved)
+        } else {
+            Err(escape_error())
+        }
+    }
+
+    /// Convert an absolute path inside the workspace back to a relative one.
+    pub fn relative(&self, path: &Path) -> String {
+        let inside = (self.roots.iter())
+            .filter_map(|root| Some((root, path.strip_prefix(&root.path).ok()?)))
+            .max_by_key(|(root, _)| root.path.as_os_str().len());
+        let Some((root, rest)) = inside else {
+            return path.to_string_lossy().into_owned();
+        };
+        let rest = rest.to_string_lossy();
+        match &root.name {
+            None => rest.into_owned(),
+            Some(name) if rest.is_empty() => format!("{ROOT_PREFIX}{name}"),
+            Some(name) => format!("{ROOT_PREFIX}{name}/{rest}"),
+        }
+    }
+
+    /// List the children of `rel`, folders first, each group sorted by name.
+    pub fn list_dir(&self, rel: &str) -> io::Result<Vec<DirEntry>> {
+        let listed = self.read_dir(rel);
+        self.record(FileOp::List, rel, None, &listed);
+        listed
+    }
+
+    fn read_dir(&self, rel: &str) -> io::Result<Vec<DirEntry>> {
+        if rel.is_empty() && self.is_named() {
+            return Ok(self
+                .readable_roots()
+                .map(|root| DirEntry {
+                    path: self.relative(root),
+                    is_dir: true,
+                })
+                .collect());
+        }
+        let dir = self.resolve(rel)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if IGNORED.iter().any(|i| name == *i) {
+                continue;
+            }
+            entries.push(DirEntry {
+                path: self.relative(&entry.path()),
+                is_dir: entry.file_type()?.is_dir(),
+            });
+        }
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.path.cmp(&b.path)));
+        Ok(entries)
+    }
+
+    /// Create an empty file, or a folder when `dir` is set.
+    pub fn create(&self, rel: &str, dir: bool) -> io::Result<()> {
+        let created = self.check_writable(rel).and_then(|()| {
+            let path = self.resolve_child(rel)?;
+            if dir {
+                fs::create_dir(path)
+            } else {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .map(|_| ())
+            }
+        });
+        self.record(FileOp::Create, rel, None, &created);
+        created
+    }
+
+    /// Rename `from` to `to`, refusing to overwrite an existing entry.
+    pub fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let renamed = self.move_entry(from, to);
+        self.record(FileOp::Rename, &format!("{from} -> {to}"), None, &renamed);
+        renamed
+    }
+
+    fn move_entry(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check_writable(from)?;
+        self.check_writable(to)?;
+        let from = self.resolve_child(from)?;
+        let to = self.resolve_child(to)?;
+        if to.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "destination exists",
+            ));
+        }
+        fs::rename(from, to)
+    }
+
+    /// Delete a file or a folder with its contents.
+    pub fn delete(&self, rel: &str) -> io::Result<()> {
+        let deleted = self.check_writable(rel).and_then(|()| {
+            let path = self.resolve_child(rel)?;
+            if fs::symlink_metadata(&path)?.is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            }
+        });
+        self.record(FileOp::Delete, rel, None, &deleted);
+        deleted
+    }
+
+    /// Return up to `limit` file paths fuzzy-matching `query`, best first.
+    pub fn search(&self, query: &str, limit: usize) -> io::Result<Vec<DirEntry>> {
+        let mut scored = Vec::new();
+        let mut stack: Vec<PathBuf> = self.readable_roots().map(Path::to_path_buf).collect();
+        while let Some(dir) = stack.pop() {
+            // Folders and entries that cannot be read are left out rather
+            // than failing the whole search.
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if IGNORED.iter().any(|i| entry.file_name() == *i) {
+                    continue;
+                }
+                let Ok(file_type) = entry.file_type() else {
+                    continue;
+                };
+                if file_type.is_dir() {
+                    stack.push(entry.path());
+                } else if file_type.is_file() {
+                    let rel = self.relative(&entry.path());
+                    if let Some(score) = fuzzy_score(query, &rel) {
+                        scored.push((score, rel));
+                    }
+                }
+            }
+        }
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        Ok(scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, path)| DirEntry {
+                path,
+                is_dir: false,
+            })
+            .collect())
+    }
+
+    /// Resolve a path that must name an entry below a root, not the root
+    /// itself.
+    fn resolve_child(&self, rel: &str) -> io::Result<PathBuf> {
+        let path = self.resolve(rel)?;
+        if self.roots().any(|root| root == path) {
+            return Err(escape_error());
+        }
+        Ok(path)
+    }
+}
+
+/// Canonical form of `path`, which must be an existing directory.
+fn canonical_dir(path: &Path) -> io::Result<PathBuf> {
+    let path = path.canonicalize()?;
+    if !path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            "workspace root is not a directory",
+        ));
+    }
+    Ok(path)
+}
+
+fn escape_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "path escapes workspace")
+}
+
+/// Score `candidate` against `query` as a case-insensitive subsequence
+/// match, rewarding consecutive characters and matches after separators.
+/// Returns `None` if `query` is not a subsequence of `candidate`.
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut score = 0i64;
+    let mut prev_match: Option<usize> = None;
+    let mut chars = candidate.char_indices();
+    let mut prev_char: Option<char> = None;
+    for q in query.chars().flat_map(char::to_lowercase) {
+        loop {
+            let (idx, c) = chars.next()?;
+            let before = prev_char;
+            prev_char = Some(c);
+            if c.to_lowercase().eq(std::iter::once(q)) {
+                score += 1;
+                if prev_match.is_some_and(|p| p + c.len_utf8() >= idx && idx > p) {
+                    score += 5;
+                }
+                if before.is_none_or(|b| matches!(b, '/' | '_' | '-' | '.' | ' ')) {
+                    score += 3;
+                }
+                prev_match = Some(idx);
+                break;
+            }
+        }
+    }
+    // Prefer shorter paths for equal matches.
+    Some(score * 100 - candidate.len() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn workspace() -> (tempfile::TempDir, Workspace) {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src")).unwrap();
+        fs::create_dir_all(dir.path().join(".git")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(dir.path().join("README.md"), "hi").unwrap();
+        let ws = Workspace::new(dir.path()).unwrap();
+        (dir, ws)
+    }
+
+    #[test]
+    fn lists_directories_first_and_skips_git() {
+        let (_dir, ws) = workspace();
+        let entries = ws.list_dir("").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                DirEntry {
+                    path: "src".into(),
+                    is_dir: true,
+                },
+                DirEntry {
+                    path: "README.md".into(),
+                    is_dir: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_paths_escaping_root() {
+        let (dir, ws) = workspace();
+        assert_eq!(
+            ws.resolve("../etc").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            ws.resolve("/etc").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        std::os::unix::fs::symlink("/", dir.path().join("escape")).unwrap();
+        assert_eq!(
+            ws.resolve("escape/etc").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(ws.delete("").is_err());
+    }
+
+    #[test]
+    fn create_rename_delete() {
+        let (dir, ws) = workspace();
+        ws.create("docs", true).unwrap();
+        ws.create("docs/a.txt", false).unwrap();
+        assert!(ws.create("docs/a.txt", false).is_err());
+        ws.rename("docs/a.txt", "docs/b.txt").unwrap();
+        assert!(dir.path().join("docs/b.txt").exists());
+        assert!(ws.rename("docs/b.txt", "README.md").is_err());
+        ws.delete("docs").unwrap();
+        assert!(!dir.path().join("docs").exists());
+    }
+
+    #[test]
+    fn readonly_refuses_changes() {
+        let (dir, ws) = workspace();
+        let ws = ws.with_readonly(true);
+        let readonly =
+            |r: io::Result<()>| r.unwrap_err().kind() == io::ErrorKind::ReadOnlyFilesystem;
+        assert!(readonly(ws.create("docs", true)));
+        assert!(readonly(ws.rename("README.md", "b.md")));
+        assert!(readonly(ws.delete("README.md")));
+        assert!(ws.lock(&dir.path().join("README.md")).is_none());
+        assert!(dir.path().join("README.md").exists());
+        assert_eq!(ws.list_dir("").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn addresses_named_roots() {
+        let (a, _) = workspace();
+        let (b, _) = workspace();
+        let ws = Workspace::named([("b", b.path()), ("a", a.path())]).unwrap();
+        assert!(ws.is_named());
+        let top: Vec<_> = ws
+            .list_dir("")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(top, ["workspace:a", "workspace:b"]);
+        let src = ws.list_dir("workspace:b/src").unwrap();
+        assert_eq!(src[0].path, "workspace:b/src/main.rs");
+
+        let main = ws.resolve("workspace:a/src/main.rs").unwrap();
+        assert!(main.starts_with(a.path().canonicalize().unwrap()));
+        assert_eq!(ws.relative(&main), "workspace:a/src/main.rs");
+        ws.create("workspace:b/new.txt", false).unwrap();
+        assert!(b.path().join("new.txt").exists());
+
+        let not_found = |rel: &str| ws.resolve(rel).unwrap_err().kind() == io::ErrorKind::NotFound;
+        assert!(not_found("src/main.rs"));
+        assert!(not_found("workspace:c/src"));
+        // Each root confines its own paths.
+        assert_eq!(
+            ws.resolve("workspace:a/../b").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(ws.delete("workspace:a").is_err());
+        assert!(a.path().exists());
+
+        let found = ws.search("main", 10).unwrap();
+        let mut paths: Vec<_> = found.into_iter().map(|e| e.path).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["workspace:a/src/main.rs", "workspace:b/src/main.rs"]
+        );
+
+        let dup = Workspace::named([("a", a.path()), ("a", b.path())]);
+        assert_eq!(dup.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(Workspace::named([("a/b", a.path())]).is_err());
+    }
+
+    #[test]
+    fn grants_roots_by_secret() {
+        use argon2::{Argon2, PasswordHasher, password_hash::SaltString};
+
+        let (a, _) = workspace();
+        let (b, _) = workspace();
+        let salt = SaltString::generate(&mut rand_core::OsRng);
+        let hash = (Argon2::default().hash_password(b"b-key", &salt))
+            .unwrap()
+            .to_string();
+        let readonly = RootAccess {
+            readonly: true,
+            ..RootAccess::default()
+        };
+        let locked = RootAccess {
+            secret_hash: Some(hash),
+            readonly: false,
+        };
+        let ws = Workspace::named([("a", a.path()), ("b", b.path())])
+            .unwrap()
+            .with_root_access("a", readonly)
+            .unwrap()
+            .with_root_access("b", locked)
+            .unwrap();
+        assert!(ws.has_root_secrets());
+        assert!(ws.unlocks("b-key"));
+        assert!(!ws.unlocks("wrong"));
+        let missing = ws.clone().with_root_access("c", RootAccess::default());
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        let kind = |r: io::Result<()>| r.unwrap_err().kind();
+        let guest = ws.for_secret(None, true);
+        let top: Vec<_> = (guest.list_dir("").unwrap().into_iter())
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(top, ["workspace:a"]);
+        assert_eq!(
+            guest.resolve("workspace:b/README.md").unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            kind(guest.create("workspace:a/new", false)),
+            io::ErrorKind::ReadOnlyFilesystem
+        );
+        assert!(guest.lock(&a.path().join("README.md")).is_none());
+        assert_eq!(guest.search("main", 10).unwrap().len(), 1);
+
+        let holder = ws.for_secret(Some("b-key"), true);
+        assert_eq!(holder.list_dir("").unwrap().len(), 2);
+        holder.create("workspace:b/new", false).unwrap();
+        assert!(holder.can_write(&b.path().join("new")));
+        assert!(!holder.can_write(&a.path().join("README.md")));
+        assert_eq!(
+            kind(holder.rename("workspace:b/new", "workspace:a/new")),
+            io::ErrorKind::ReadOnlyFilesystem
+        );
+
+        // Without the server's own secret, the key reaches `b` alone.
+        let key_only = ws.for_secret(Some("b-key"), false);
+        let top: Vec<_> = (key_only.list_dir("").unwrap().into_iter())
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(top, ["workspace:b"]);
+        assert_eq!(
+            key_only
+                .resolve("workspace:a/README.md")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn searches_by_fuzzy_score() {
+        let (_dir, ws) = workspace();
+        let results = ws.search("mn", 10).unwrap();
+        assert_eq!(results[0].path, "src/main.rs");
+        assert!(ws.search("zzz", 10).unwrap().is_empty());
+        assert!(ws.search("", 1).unwrap().len() == 1);
+    }
+
+    #[test]
+    fn searches_past_folders_it_cannot_read() {
+        let (a, _) = workspace();
+        let (b, _) = workspace();
+        let ws = Workspace::named([("a", a.path()), ("b", b.path())]).unwrap();
+        std::fs::remove_dir_all(b.path()).unwrap();
+        let results = ws.search("mn", 10).unwrap();
+        assert_eq!(results[0].path, "workspace:a/src/main.rs");
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_consecutive_and_boundaries() {
+        assert!(fuzzy_score("abc", "xaxbxc").is_some());
+        assert_eq!(fuzzy_score("abd", "abc"), None);
+        assert!(fuzzy_score("main", "src/main.rs") > fuzzy_score("main", "src/my_aint.rs"));
+        assert!(fuzzy_score("Rd", "README.md").is_some());
+    }
+}
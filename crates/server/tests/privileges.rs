@@ -0,0 +1,32 @@
+//! Changing ids affects every thread of the test process, so this gets a
+//! test binary of its own with a single test.
+#![cfg(unix)]
+
+use std::net::{TcpListener, TcpStream};
+
+use ghostwriter_server::privileges::RunAs;
+
+#[test]
+#[ignore = "needs root; run with `cargo test -- --ignored` as root"]
+fn drops_root_after_binding() {
+    // SAFETY: geteuid cannot fail.
+    assert_eq!(unsafe { libc::geteuid() }, 0, "not running as root");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let run_as = RunAs {
+        user: Some("65534".into()),
+        group: Some("65534".into()),
+    };
+    run_as.apply().unwrap();
+
+    // SAFETY: plain id queries.
+    unsafe {
+        assert_eq!(libc::getuid(), 65534);
+        assert_eq!(libc::geteuid(), 65534);
+        assert_eq!(libc::getegid(), 65534);
+        assert_ne!(libc::setuid(0), 0);
+    }
+    // The listener bound as root keeps working.
+    let addr = listener.local_addr().unwrap();
+    let _client = TcpStream::connect(addr).unwrap();
+    listener.accept().unwrap();
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "quic")]
+
+use std::time::Duration;
+
+use ghostwriter_core::{Transport, quic};
+use ghostwriter_proto::{
+    Envelope, ErrorCode, ErrorMsg, Hello, HelloAck, MessageType, Role, SUPPORTED_VERSIONS, decode,
+    encode,
+};
+use ghostwriter_server::{acceptor, workspace::Workspace};
+
+fn hello() -> Vec<u8> {
+    let hello = Hello {
+        client_name: "c".into(),
+        client_ver: "1".into(),
+        cols: 80,
+        rows: 24,
+        truecolor: true,
+        versions: SUPPORTED_VERSIONS.to_vec(),
+        features: Vec::new(),
+        caps: 0,
+        role: Role::Editor,
+    };
+    encode(&Envelope::new(MessageType::Hello, hello)).unwrap()
+}
+
+#[tokio::test]
+async fn handshakes_over_quic_and_rejects_second_client() {
+    let (cert, key) = quic::self_signed_cert(vec!["localhost".into()]).unwrap();
+    let endpoint = quic::server_endpoint(([127, 0, 0, 1], 0).into(), cert.clone(), key).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    let server = tokio::spawn(acceptor::run_quic(endpoint, workspace, None));
+    let client = quic::client_endpoint(&[cert]).unwrap();
+
+    let ws = quic::connect(&client, addr, "localhost").await.unwrap();
+    let mut first = Transport::new(ws, Duration::from_secs(60));
+    first.send(&hello()).await.unwrap();
+    let env: Envelope<HelloAck> = decode(&first.recv().await.unwrap()).unwrap();
+    assert_eq!(env.ty, MessageType::HelloAck);
+
+    let ws = quic::connect(&client, addr, "localhost").await.unwrap();
+    let mut second = Transport::new(ws, Duration::from_secs(60));
+    second.send(&hello()).await.unwrap();
+    let env: Envelope<ErrorMsg> = decode(&second.recv().await.unwrap()).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Busy);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn serves_others_while_a_client_stalls() {
+    let (cert, key) = quic::self_signed_cert(vec!["localhost".into()]).unwrap();
+    let endpoint = quic::server_endpoint(([127, 0, 0, 1], 0).into(), cert.clone(), key).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    let server = tokio::spawn(acceptor::run_quic(endpoint, workspace, None));
+    let client = quic::client_endpoint(&[cert]).unwrap();
+
+    // The server never sees the stream of a client that sends nothing.
+    let _stalled = quic::connect(&client, addr, "localhost").await.unwrap();
+
+    let ws = quic::connect(&client, addr, "localhost").await.unwrap();
+    let mut t = Transport::new(ws, Duration::from_secs(60));
+    t.send(&hello()).await.unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(5), t.recv()).await;
+    let env: Envelope<HelloAck> = decode(&reply.expect("no reply").unwrap()).unwrap();
+    assert_eq!(env.ty, MessageType::HelloAck);
+
+    server.abort();
+}
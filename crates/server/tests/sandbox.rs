@@ -0,0 +1,30 @@
+//! Sandboxing confines the whole test process, so it gets a test binary of
+//! its own with a single test.
+#![cfg(target_os = "linux")]
+
+use std::{io, path::Path, process::Command};
+
+use ghostwriter_server::sandbox::Sandbox;
+
+#[test]
+fn confines_files_and_syscalls() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+    assert!(std::fs::read(&outside).is_ok());
+
+    let landlocked = Sandbox::new(dir.path()).enter().unwrap();
+
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    std::fs::rename(dir.path().join("a.txt"), dir.path().join("src/a.txt")).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("src/a.txt")).unwrap(),
+        "hello"
+    );
+    if landlocked {
+        let err = std::fs::read(&outside).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+    let err = Command::new("true").status().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+}
@@ -0,0 +1,155 @@
+use std::{path::Path, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use ghostwriter_core::tls;
+use ghostwriter_proto::{
+    Envelope, Hello, HelloAck, MessageType, Role, SUPPORTED_VERSIONS, decode, encode,
+};
+use ghostwriter_server::{acceptor, audit::AuditLog, workspace::Workspace};
+use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{Connector, connect_async_tls_with_config, tungstenite::Message};
+
+fn hello() -> Message {
+    let hello = Hello {
+        client_name: "c".into(),
+        client_ver: "1".into(),
+        cols: 80,
+        rows: 24,
+        truecolor: true,
+        versions: SUPPORTED_VERSIONS.to_vec(),
+        features: Vec::new(),
+        caps: 0,
+        role: Role::Editor,
+    };
+    let data = encode(&Envelope::new(MessageType::Hello, hello)).unwrap();
+    Message::Binary(data.into())
+}
+
+/// Write `cert` and `key` as PEM files named `<name>.pem` and
+/// `<name>.key` in `dir`.
+fn write_pem(dir: &Path, name: &str, cert: &rcgen::Certificate, key: &KeyPair) {
+    std::fs::write(dir.join(format!("{name}.pem")), cert.pem()).unwrap();
+    std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem()).unwrap();
+}
+
+#[tokio::test]
+async fn handshakes_over_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    write_pem(dir.path(), "server", &certified.cert, &certified.key_pair);
+    let cert = dir.path().join("server.pem");
+    let tls = tls::acceptor(&cert, &dir.path().join("server.key"), None).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    let server = tokio::spawn(acceptor::run_tls(listener, tls, workspace, None));
+
+    // A client speaking plain WebSocket fails the TLS handshake without
+    // taking the acceptor down.
+    let plain = format!("ws://localhost:{port}");
+    assert!(tokio_tungstenite::connect_async(&plain).await.is_err());
+    // Nor does one that never starts it hold up the others.
+    let _stalled = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+
+    let config = tls::client_config(Some(&[certified.cert.der().clone()]), None).unwrap();
+    let url = format!("wss://localhost:{port}");
+    let connect = connect_async_tls_with_config(&url, None, false, Some(Connector::Rustls(config)));
+    let (mut ws, _) = tokio::time::timeout(Duration::from_secs(5), connect)
+        .await
+        .expect("handshake held up")
+        .unwrap();
+    ws.send(hello()).await.unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    let env: Envelope<HelloAck> = decode(&msg.into_data()).unwrap();
+    assert_eq!(env.ty, MessageType::HelloAck);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn requires_client_certificates_and_audits_their_subject() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    write_pem(dir.path(), "ca", &ca, &ca_key);
+
+    let server_key = KeyPair::generate().unwrap();
+    let server_cert = CertificateParams::new(vec!["localhost".into()])
+        .unwrap()
+        .signed_by(&server_key, &ca, &ca_key)
+        .unwrap();
+    write_pem(dir.path(), "server", &server_cert, &server_key);
+
+    let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    client_params
+        .distinguished_name
+        .push(DnType::CommonName, "alice");
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_key = KeyPair::generate().unwrap();
+    let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+    write_pem(dir.path(), "client", &client_cert, &client_key);
+
+    let pem = |name: &str| dir.path().join(name);
+    let tls = tls::acceptor(&pem("server.pem"), &pem("server.key"), Some(&pem("ca.pem"))).unwrap();
+    let audit_path = dir.path().join("audit.log");
+    let config = acceptor::AcceptorConfig {
+        audit: AuditLog::open(&audit_path).unwrap(),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tls_until(listener, tls, workspace, None, config, shutdown).await
+    });
+    let url = format!("wss://localhost:{port}");
+    let roots = tls::load_certs(&pem("ca.pem")).unwrap();
+
+    // Without a certificate the server ends the handshake. TLS 1.3 clients
+    // only learn of it on their first read.
+    let config = tls::client_config(Some(&roots), None).unwrap();
+    if let Ok((mut ws, _)) =
+        connect_async_tls_with_config(&url, None, false, Some(Connector::Rustls(config))).await
+    {
+        let _ = ws.send(hello()).await;
+        assert!(!matches!(ws.next().await, Some(Ok(Message::Binary(_)))));
+    }
+
+    let identity = tls::ClientIdentity::load(&pem("client.pem"), &pem("client.key")).unwrap();
+    let config = tls::client_config(Some(&roots), Some(identity)).unwrap();
+    let (mut ws, _) =
+        connect_async_tls_with_config(&url, None, false, Some(Connector::Rustls(config)))
+            .await
+            .unwrap();
+    ws.send(hello()).await.unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    let env: Envelope<HelloAck> = decode(&msg.into_data()).unwrap();
+    assert_eq!(env.ty, MessageType::HelloAck);
+    ws.close(None).await.unwrap();
+
+    let mut log = String::new();
+    for _ in 0..100 {
+        log = std::fs::read_to_string(&audit_path).unwrap();
+        if log.contains(" disconnected ") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let events: Vec<_> = log
+        .lines()
+        .map(|line| line.split(' ').skip(1).collect::<Vec<_>>())
+        .collect();
+    assert_eq!(events.len(), 2, "{log}");
+    assert_eq!(events[0][0], "connected");
+    assert_eq!(events[0][2], r#"identity="alice""#);
+    assert_eq!(events[1][0], "disconnected");
+
+    server.abort();
+}
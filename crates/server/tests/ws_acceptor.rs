@@ -1,27 +1,80 @@
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHasher};
 use futures_util::{SinkExt, StreamExt};
-use ghostwriter_proto::{Auth, Envelope, ErrorCode, ErrorMsg, Hello, MessageType, decode, encode};
-use ghostwriter_server::acceptor;
+use ghostwriter_proto::{
+    Auth, Envelope, ErrorCode, ErrorMsg, Hello, HelloAck, MessageType, Role, SUPPORTED_VERSIONS,
+    decode, encode,
+};
+use ghostwriter_server::{acceptor, workspace::Workspace};
 use rand_core::OsRng;
+use std::net::SocketAddr;
 use tokio::net::TcpListener;
 use tokio_tungstenite::tungstenite::Message;
 
+type Client =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+fn workspace() -> (tempfile::TempDir, Workspace) {
+    let dir = tempfile::tempdir().unwrap();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    (dir, workspace)
+}
+
+/// Hello of a client in `role` on an 80x24 truecolor terminal.
+fn hello(role: Role) -> Hello {
+    Hello {
+        client_name: "c".into(),
+        client_ver: "1".into(),
+        cols: 80,
+        rows: 24,
+        truecolor: true,
+        versions: SUPPORTED_VERSIONS.to_vec(),
+        features: Vec::new(),
+        caps: 0,
+        role,
+    }
+}
+
+/// Open a WebSocket to the acceptor at `addr` without saying Hello.
+async fn dial(addr: SocketAddr) -> Client {
+    let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+        .await
+        .unwrap();
+    ws
+}
+
+/// Connect to the acceptor at `addr` as `role` and finish the Hello
+/// exchange.
+async fn connect(addr: SocketAddr, role: Role) -> Client {
+    let mut ws = dial(addr).await;
+    send_env(&mut ws, MessageType::Hello, hello(role)).await;
+    expect_hello_ack(&mut ws).await;
+    ws
+}
+
+async fn expect_hello_ack(ws: &mut Client) -> HelloAck {
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => {
+            let env: Envelope<HelloAck> = decode(&data).unwrap();
+            assert_eq!(env.ty, MessageType::HelloAck);
+            env.data
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+}
+
 #[tokio::test]
 async fn rejects_second_client_with_busy() {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
     let server = tokio::spawn(async move {
-        acceptor::run_tcp(listener, None).await.unwrap();
+        acceptor::run_tcp(listener, workspace, None).await.unwrap();
     });
 
-    let (mut ws1, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws1 = dial(addr).await;
 
-    let (mut ws2, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws2 = dial(addr).await;
 
     match ws2.next().await.unwrap().unwrap() {
         Message::Binary(data) => {
@@ -37,14 +90,60 @@ async fn rejects_second_client_with_busy() {
     }
 
     ws1.close(None).await.unwrap();
-    let (mut ws3, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws3 = dial(addr).await;
     ws3.close(None).await.unwrap();
 
     server.abort();
 }
 
+#[tokio::test]
+async fn queues_clients_beyond_capacity() {
+    use ghostwriter_proto::Queued;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let config = acceptor::AcceptorConfig {
+        max_clients: 1,
+        wait_queue: 1,
+        queue_notice_interval: std::time::Duration::from_millis(50),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws1 = connect(addr, Role::Editor).await;
+
+    // The second client waits and is told its place, repeatedly.
+    let mut ws2 = dial(addr).await;
+    for _ in 0..2 {
+        let env: Envelope<Queued> = decode(&next_binary(&mut ws2).await).unwrap();
+        assert_eq!(env.ty, MessageType::Queued);
+        assert_eq!(env.data.position, 1);
+    }
+    send_env(&mut ws2, MessageType::Hello, hello(Role::Editor)).await;
+
+    // The queue is full, so the third is turned away.
+    let mut ws3 = dial(addr).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws3).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Busy);
+
+    // Once the first client leaves, the waiting one is served.
+    ws1.close(None).await.unwrap();
+    loop {
+        let data = next_binary(&mut ws2).await;
+        let env: Envelope<HelloAck> = match decode(&data) {
+            Ok(env) if env.ty == MessageType::HelloAck => env,
+            _ => continue,
+        };
+        assert!(SUPPORTED_VERSIONS.contains(&env.data.version));
+        break;
+    }
+
+    server.abort();
+}
+
 #[tokio::test]
 async fn rejects_invalid_auth() {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -57,36 +156,23 @@ async fn rejects_invalid_auth() {
         .unwrap()
         .to_string();
 
+    let (_dir, workspace) = workspace();
+
     let server = tokio::spawn(async move {
-        acceptor::run_tcp(listener, Some(hash)).await.unwrap();
+        acceptor::run_tcp(listener, workspace, Some(hash))
+            .await
+            .unwrap();
     });
 
-    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws = dial(addr).await;
 
     // Send Hello
-    let hello = Hello {
-        client_name: "c".into(),
-        client_ver: "1".into(),
-        cols: 80,
-        rows: 24,
-        truecolor: true,
-    };
-    let env = Envelope::new(MessageType::Hello, hello);
-    ws.send(Message::Binary(encode(&env).unwrap().into()))
-        .await
-        .unwrap();
+    send_env(&mut ws, MessageType::Hello, hello(Role::Editor)).await;
 
     // Send wrong Auth
-    let auth = Auth {
-        secret: "bad".into(),
-    };
-    let env = Envelope::new(MessageType::Auth, auth);
-    ws.send(Message::Binary(encode(&env).unwrap().into()))
-        .await
-        .unwrap();
+    send_env(&mut ws, MessageType::Auth, Auth::shared_secret("bad")).await;
 
+    expect_hello_ack(&mut ws).await;
     match ws.next().await.unwrap().unwrap() {
         Message::Binary(data) => {
             let env: Envelope<ErrorMsg> = decode(&data).unwrap();
@@ -112,35 +198,23 @@ async fn accepts_valid_auth() {
         .unwrap()
         .to_string();
 
+    let (_dir, workspace) = workspace();
+
     let server = tokio::spawn(async move {
-        acceptor::run_tcp(listener, Some(hash)).await.unwrap();
+        acceptor::run_tcp(listener, workspace, Some(hash))
+            .await
+            .unwrap();
     });
 
-    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws = dial(addr).await;
 
     // Hello
-    let hello = Hello {
-        client_name: "c".into(),
-        client_ver: "1".into(),
-        cols: 80,
-        rows: 24,
-        truecolor: true,
-    };
-    let env = Envelope::new(MessageType::Hello, hello);
-    ws.send(Message::Binary(encode(&env).unwrap().into()))
-        .await
-        .unwrap();
+    send_env(&mut ws, MessageType::Hello, hello(Role::Editor)).await;
+
+    assert!(expect_hello_ack(&mut ws).await.auth);
 
     // Correct Auth
-    let auth = Auth {
-        secret: "s3cr3t".into(),
-    };
-    let env = Envelope::new(MessageType::Auth, auth);
-    ws.send(Message::Binary(encode(&env).unwrap().into()))
-        .await
-        .unwrap();
+    send_env(&mut ws, MessageType::Auth, Auth::shared_secret("s3cr3t")).await;
 
     // Ensure no error is sent within 100ms
     assert!(
@@ -153,41 +227,146 @@ async fn accepts_valid_auth() {
     server.abort();
 }
 
+#[tokio::test]
+async fn rate_limits_failed_logins() {
+    use ghostwriter_server::ratelimit::{RateLimit, RateLimiter};
+    use tokio::time::Duration;
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password("s3cr3t".as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+    let (_dir, workspace) = workspace();
+    let config = acceptor::AcceptorConfig {
+        auth_limit: RateLimiter::new(RateLimit::new(1, Duration::from_secs(60))),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, Some(hash), config, shutdown).await
+    });
+    // Once the failures are used up, even the right secret is turned away.
+    for (secret, code) in [
+        ("guess", ErrorCode::Unauthorized),
+        ("s3cr3t", ErrorCode::RateLimit),
+    ] {
+        let mut ws = connect(addr, Role::Editor).await;
+        send_env(&mut ws, MessageType::Auth, Auth::shared_secret(secret)).await;
+        let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+        assert_eq!(env.data.code, code);
+    }
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn authenticates_named_tokens() {
+    use ghostwriter_server::auth::TokenStore;
+    use tokio::time::{Duration, timeout};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let state = tempfile::tempdir().unwrap();
+    let tokens = TokenStore::new(state.path().join("tokens"));
+    let ci = tokens.issue("ci", None).unwrap();
+    let laptop = tokens.issue("laptop", None).unwrap();
+    tokens.revoke("laptop").unwrap();
+    let config = acceptor::AcceptorConfig {
+        tokens: Some(tokens),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    // Stay within the per-address connection rate limit.
+    for (auth, ok) in [
+        (Auth::token("ci", &ci), true),
+        (Auth::token("ci", &laptop), false),
+        (Auth::token("laptop", &laptop), false),
+    ] {
+        let mut ws = connect(addr, Role::Editor).await;
+        send_env(&mut ws, MessageType::Auth, auth).await;
+        if ok {
+            assert!(
+                timeout(Duration::from_millis(100), ws.next())
+                    .await
+                    .is_err()
+            );
+        } else {
+            let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+            assert_eq!(env.data.code, ErrorCode::Unauthorized);
+        }
+    }
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn refuses_addresses_outside_the_allow_list() {
+    use ghostwriter_server::{access::AccessList, audit::AuditLog};
+    use tokio::time::{Duration, sleep};
+
+    let (_dir, workspace) = workspace();
+    let state = tempfile::tempdir().unwrap();
+    let audit_path = state.path().join("audit.log");
+    let config = acceptor::AcceptorConfig {
+        audit: AuditLog::open(&audit_path).unwrap(),
+        access: AccessList {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: Vec::new(),
+        },
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+
+    let connected = tokio_tungstenite::connect_async(format!("ws://{addr}")).await;
+    assert!(connected.is_err());
+    let mut log = String::new();
+    for _ in 0..100 {
+        log = std::fs::read_to_string(&audit_path).unwrap();
+        if !log.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(log.contains(" refused peer=127.0.0.1:"), "{log}");
+    assert!(!server.is_finished());
+
+    server.abort();
+}
+
 #[tokio::test]
 async fn rate_limits_connections() {
     use tokio::time::{Duration, sleep, timeout};
 
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
     let server = tokio::spawn(async move {
-        acceptor::run_tcp(listener, None).await.unwrap();
+        acceptor::run_tcp(listener, workspace, None).await.unwrap();
     });
 
     // Three quick connections should succeed
     for _ in 0..3 {
-        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-            .await
-            .unwrap();
-        let hello = Hello {
-            client_name: "c".into(),
-            client_ver: "1".into(),
-            cols: 80,
-            rows: 24,
-            truecolor: true,
-        };
-        let env = Envelope::new(MessageType::Hello, hello);
-        ws.send(Message::Binary(encode(&env).unwrap().into()))
-            .await
-            .unwrap();
+        let mut ws = dial(addr).await;
+        send_env(&mut ws, MessageType::Hello, hello(Role::Editor)).await;
         ws.close(None).await.unwrap();
         // Give the server a moment to clean up
         sleep(Duration::from_millis(10)).await;
     }
 
     // Fourth connection should be rate-limited
-    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
-        .await
-        .unwrap();
+    let mut ws = dial(addr).await;
 
     match timeout(Duration::from_millis(200), ws.next()).await {
         Ok(Some(Ok(Message::Binary(data)))) => {
@@ -195,6 +374,7 @@ async fn rate_limits_connections() {
             assert_eq!(env.ty, MessageType::Error);
             assert_eq!(env.data.code, ErrorCode::RateLimit);
             assert!(env.data.msg.contains("retry"));
+            assert!(env.data.retry_after().is_some_and(|d| d > Duration::ZERO));
         }
         other => panic!("unexpected message: {other:?}"),
     }
@@ -205,3 +385,1229 @@ async fn rate_limits_connections() {
 
     server.abort();
 }
+
+#[tokio::test]
+async fn acknowledges_hello_with_negotiated_version() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let server = tokio::spawn(async move {
+        acceptor::run_tcp(listener, workspace, None).await.unwrap();
+    });
+
+    let mut ws = dial(addr).await;
+    let hello = Hello {
+        versions: vec![1, 2],
+        features: vec!["frame_diff".into()],
+        ..hello(Role::Editor)
+    };
+    send_env(&mut ws, MessageType::Hello, hello).await;
+
+    let ack = expect_hello_ack(&mut ws).await;
+    assert_eq!(ack.version, 1);
+    assert_eq!(ack.features, vec!["frame_diff".to_string()]);
+    assert!(!ack.auth);
+
+    ws.close(None).await.unwrap();
+    server.abort();
+}
+
+#[tokio::test]
+async fn rejects_hello_without_common_version() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let server = tokio::spawn(async move {
+        acceptor::run_tcp(listener, workspace, None).await.unwrap();
+    });
+
+    let mut ws = dial(addr).await;
+    let hello = Hello {
+        client_ver: "9".into(),
+        versions: vec![9],
+        ..hello(Role::Editor)
+    };
+    send_env(&mut ws, MessageType::Hello, hello).await;
+
+    match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => {
+            let env: Envelope<ErrorMsg> = decode(&data).unwrap();
+            assert_eq!(env.data.code, ErrorCode::ProtocolMismatch);
+            assert!(env.data.msg.contains("protocol version"));
+        }
+        other => panic!("unexpected message: {other:?}"),
+    }
+
+    server.abort();
+}
+
+async fn next_binary(ws: &mut Client) -> Vec<u8> {
+    loop {
+        match ws.next().await.unwrap().unwrap() {
+            Message::Binary(data) => return data.to_vec(),
+            Message::Ping(_) | Message::Pong(_) => {}
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+}
+
+async fn send_env<T: serde::Serialize>(ws: &mut Client, ty: MessageType, data: T) {
+    ws.send(Message::Binary(
+        encode(&Envelope::new(ty, data)).unwrap().into(),
+    ))
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn routes_messages_into_a_session() {
+    use ghostwriter_proto::{Ack, Frame, Insert, Open, RequestFrame};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let server = tokio::spawn(async move {
+        acceptor::run_tcp(listener, workspace, None).await.unwrap();
+    });
+
+    let mut ws = connect(addr, Role::Editor).await;
+
+    // Editing before a file is open is rejected.
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert.clone()).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Invalid);
+
+    // Paths outside the workspace are refused.
+    let open = Open {
+        path: "../etc/passwd".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Sandbox);
+
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.ty, MessageType::Frame);
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    let env: Envelope<Ack> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.ty, MessageType::Ack);
+    assert_eq!((env.data.seq, env.data.doc_v), (1, 1));
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "xhello");
+
+    send_env(&mut ws, MessageType::Save, ()).await;
+    let req = RequestFrame {
+        reason: "sync".into(),
+    };
+    send_env(&mut ws, MessageType::RequestFrame, req).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.doc_v, 1);
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+        "xhello"
+    );
+
+    ws.close(None).await.unwrap();
+    server.abort();
+}
+
+#[tokio::test]
+async fn shutdown_saves_and_notifies_clients() {
+    use ghostwriter_proto::{Ack, Frame, Insert, Open};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let shutdown = async {
+            let _ = stopped.await;
+        };
+        acceptor::run_tcp_until(listener, workspace, None, Default::default(), shutdown).await
+    });
+
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    let _: Envelope<Ack> = decode(&next_binary(&mut ws).await).unwrap();
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+
+    stop.send(()).unwrap();
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::ShuttingDown);
+    assert!(env.data.code.is_transient());
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+            Some(Ok(_)) => {}
+        }
+    }
+    server.await.unwrap().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+        "xhello"
+    );
+}
+
+#[tokio::test]
+async fn compresses_everything_after_the_hello_once_agreed() {
+    use ghostwriter_core::transport::{compress_payload, decompress_payload};
+    use ghostwriter_proto::{Frame, Open, caps};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = (Argon2::default().hash_password(b"s3cr3t", &salt))
+        .unwrap()
+        .to_string();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        let config = acceptor::AcceptorConfig::default();
+        acceptor::run_tcp_until(listener, workspace, Some(hash), config, shutdown).await
+    });
+    let compressed = || async {
+        let mut ws = dial(addr).await;
+        let hello = Hello {
+            caps: caps::COMPRESSION,
+            ..hello(Role::Editor)
+        };
+        send_env(&mut ws, MessageType::Hello, hello).await;
+        assert!(expect_hello_ack(&mut ws).await.has(caps::COMPRESSION));
+        ws
+    };
+    async fn send_packed<T: serde::Serialize>(ws: &mut Client, ty: MessageType, data: T) {
+        let data = encode(&Envelope::new(ty, data)).unwrap();
+        let data = compress_payload(&data, 0).unwrap();
+        ws.send(Message::Binary(data.into())).await.unwrap();
+    }
+
+    let mut ws = compressed().await;
+    send_packed(&mut ws, MessageType::Auth, Auth::shared_secret("wrong")).await;
+    let data = decompress_payload(&next_binary(&mut ws).await).unwrap();
+    let env: Envelope<ErrorMsg> = decode(&data).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Unauthorized);
+
+    let mut ws = compressed().await;
+    send_packed(&mut ws, MessageType::Auth, Auth::shared_secret("s3cr3t")).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_packed(&mut ws, MessageType::Open, open).await;
+    let data = decompress_payload(&next_binary(&mut ws).await).unwrap();
+    let env: Envelope<Frame> = decode(&data).unwrap();
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn followers_receive_the_editors_frames() {
+    use ghostwriter_proto::{Frame, Insert, Open, RequestFrame};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let config = acceptor::AcceptorConfig {
+        max_clients: 2,
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let request = RequestFrame {
+        reason: "initial".into(),
+    };
+
+    // Nothing to follow yet.
+    let mut follower = connect(addr, Role::Follower).await;
+    send_env(&mut follower, MessageType::RequestFrame, request.clone()).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::NotFound);
+
+    let mut editor = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut editor, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut editor).await).unwrap();
+
+    send_env(&mut follower, MessageType::RequestFrame, request).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut editor, MessageType::Insert, insert.clone()).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "xhello");
+
+    // Followers cannot edit.
+    send_env(&mut follower, MessageType::Insert, insert).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Readonly);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn audits_file_operations() {
+    use ghostwriter_proto::{Open, PickerAction, peek_type};
+    use ghostwriter_server::audit::FileAudit;
+    use tokio::time::{Duration, sleep};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "").unwrap();
+    let state = tempfile::tempdir().unwrap();
+    let audit_path = state.path().join("files.jsonl");
+    let config = acceptor::AcceptorConfig {
+        file_audit: FileAudit::open(&audit_path).unwrap(),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    next_binary(&mut ws).await;
+    send_env(&mut ws, MessageType::Save, ()).await;
+    let actions = [
+        PickerAction::Rename {
+            from: "b.txt".into(),
+            to: "c.txt".into(),
+        },
+        PickerAction::Delete {
+            path: "c.txt".into(),
+        },
+    ];
+    for action in actions {
+        send_env(&mut ws, MessageType::PickerAction, action).await;
+        while peek_type(&next_binary(&mut ws).await).unwrap() != MessageType::DirList {}
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..100 {
+        let text = std::fs::read_to_string(&audit_path).unwrap();
+        records = text
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if records.iter().any(|r| r["op"] == "delete") {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    let ops: Vec<_> = records
+        .iter()
+        .map(|r| {
+            format!(
+                "{} {}",
+                r["op"].as_str().unwrap(),
+                r["path"].as_str().unwrap()
+            )
+        })
+        .collect();
+    assert_eq!(
+        ops,
+        [
+            "read a.txt",
+            "lock a.txt",
+            "write a.txt",
+            "rename b.txt -> c.txt",
+            "list ",
+            "delete c.txt",
+            "list ",
+        ]
+    );
+    assert_eq!(records[0]["bytes"], 5);
+    assert_eq!(records[2]["bytes"], 5);
+    assert!(records.iter().all(|r| r["result"] == "ok"));
+    let from_client = |r: &serde_json::Value| r["peer"].as_str().unwrap().starts_with("127.0.0.1:");
+    assert!(records.iter().all(from_client));
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn counts_connections_and_edits_in_metrics() {
+    use ghostwriter_proto::{Frame, Insert, Open, peek_type};
+    use ghostwriter_server::metrics::Metrics;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let metrics = Metrics::default();
+    let config = acceptor::AcceptorConfig {
+        metrics: metrics.clone(),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    while peek_type(&next_binary(&mut ws).await).unwrap() != MessageType::Frame {}
+
+    let text = metrics.render();
+    let value = |name: &str| -> u64 {
+        let line = text.lines().find(|l| l.starts_with(&format!("{name} ")));
+        line.unwrap().rsplit(' ').next().unwrap().parse().unwrap()
+    };
+    assert_eq!(value("ghostwriter_connections"), 1);
+    assert_eq!(value("ghostwriter_edit_ops_total"), 1);
+    assert!(value("ghostwriter_frames_sent_total") >= 2);
+    assert!(value("ghostwriter_received_bytes_total") > 0);
+    assert!(value("ghostwriter_sent_bytes_total") > 0);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn reattaches_to_a_session_after_the_link_drops() {
+    use ghostwriter_proto::{Attach, Frame, Insert, Open, SessionList};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let config = acceptor::AcceptorConfig {
+        max_clients: 2,
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    let _: Envelope<ghostwriter_proto::Ack> = decode(&next_binary(&mut ws).await).unwrap();
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    // Drop the socket without a closing handshake, as a lost link would.
+    drop(ws);
+
+    let mut ws = connect(addr, Role::Editor).await;
+    let list = loop {
+        send_env(&mut ws, MessageType::ListSessions, ()).await;
+        let env: Envelope<SessionList> = decode(&next_binary(&mut ws).await).unwrap();
+        assert_eq!(env.ty, MessageType::SessionList);
+        if env.data.sessions.iter().any(|s| !s.attached) {
+            break env.data;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+    assert_eq!(list.sessions.len(), 1);
+    assert_eq!(list.sessions[0].path, "a.txt");
+
+    let id = list.sessions[0].id;
+    send_env(&mut ws, MessageType::Attach, Attach { id }).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "xhello");
+    assert_eq!(env.data.cursors[0].col, 1);
+
+    // The undo history came along.
+    send_env(&mut ws, MessageType::Undo, ()).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    // Attached sessions cannot be taken over.
+    send_env(&mut ws, MessageType::Attach, Attach { id }).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Invalid);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn keeps_sessions_to_the_client_that_opened_them() {
+    use ghostwriter_proto::{Attach, Frame, Open, SessionList};
+    use ghostwriter_server::{auth::TokenStore, ratelimit::RateLimit, ratelimit::RateLimiter};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let state = tempfile::tempdir().unwrap();
+    let tokens = TokenStore::new(state.path().join("tokens"));
+    let ada = tokens.issue("ada", None).unwrap();
+    let bob = tokens.issue("bob", None).unwrap();
+    let config = acceptor::AcceptorConfig {
+        max_clients: 3,
+        tokens: Some(tokens),
+        connect_limit: RateLimiter::new(RateLimit::new(10, std::time::Duration::from_secs(60))),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let login = |auth: Auth| async move {
+        let mut ws = connect(addr, Role::Editor).await;
+        send_env(&mut ws, MessageType::Auth, auth).await;
+        ws
+    };
+    async fn list(ws: &mut Client) -> SessionList {
+        send_env(ws, MessageType::ListSessions, ()).await;
+        let env: Envelope<SessionList> = decode(&next_binary(ws).await).unwrap();
+        env.data
+    }
+
+    let mut ws = login(Auth::token("ada", &ada)).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    drop(ws);
+
+    let mut ada_again = login(Auth::token("ada", &ada)).await;
+    let id = loop {
+        let list = list(&mut ada_again).await;
+        if let Some(session) = list.sessions.iter().find(|s| !s.attached) {
+            break session.id;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+
+    // Another token holder neither sees nor takes over the session.
+    let mut other = login(Auth::token("bob", &bob)).await;
+    assert!(list(&mut other).await.sessions.is_empty());
+    send_env(&mut other, MessageType::Attach, Attach { id }).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut other).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::NotFound);
+
+    send_env(&mut ada_again, MessageType::Attach, Attach { id }).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ada_again).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn reports_directory_changes_to_watchers() {
+    use ghostwriter_proto::{ChangeKind, WatchEvent, WatchRequest};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    let server = tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+    let mut ws = connect(addr, Role::Editor).await;
+
+    let watch = WatchRequest {
+        path: "".into(),
+        recursive: true,
+    };
+    send_env(&mut ws, MessageType::Watch, watch).await;
+    let missing = WatchRequest {
+        path: "missing".into(),
+        recursive: false,
+    };
+    send_env(&mut ws, MessageType::Watch, missing).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::NotFound);
+
+    std::fs::write(dir.path().join("src/main.rs"), "").unwrap();
+    let env: Envelope<WatchEvent> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.ty, MessageType::WatchEvent);
+    assert_eq!(env.data.changes.len(), 1);
+    assert_eq!(env.data.changes[0].path, "src/main.rs");
+    assert_eq!(env.data.changes[0].kind, ChangeKind::Created);
+    assert!(!env.data.changes[0].is_dir);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn streams_workspace_search_results() {
+    use ghostwriter_proto::{SearchRequest, SearchResultChunk};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "one\ntwo needle\n").unwrap();
+    std::fs::write(dir.path().join("b.txt"), "Needle").unwrap();
+    let server = tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+    let mut ws = connect(addr, Role::Editor).await;
+
+    let req = SearchRequest {
+        pattern: "needle".into(),
+        regex: false,
+        case: true,
+        limit: 0,
+        no_ignore: false,
+        whole_word: false,
+    };
+    send_env(&mut ws, MessageType::SearchFiles, req).await;
+    let env: Envelope<SearchResultChunk> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.ty, MessageType::SearchResults);
+    assert!(env.data.done);
+    assert_eq!(env.data.pattern, "needle");
+    assert_eq!(env.data.matches.len(), 1);
+    assert_eq!(env.data.matches[0].path, "a.txt");
+    assert_eq!(env.data.matches[0].line, 1);
+
+    let bad = SearchRequest {
+        pattern: "[".into(),
+        regex: true,
+        case: true,
+        limit: 0,
+        no_ignore: false,
+        whole_word: false,
+    };
+    send_env(&mut ws, MessageType::SearchFiles, bad).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Invalid);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn answers_pings_before_a_file_is_open() {
+    use ghostwriter_proto::peek_type;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let server = tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+    let mut ws = connect(addr, Role::Follower).await;
+
+    send_env(&mut ws, MessageType::Ping, ()).await;
+    let data = next_binary(&mut ws).await;
+    assert_eq!(peek_type(&data).unwrap(), MessageType::Pong);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn accepts_clients_on_every_bound_address() {
+    use ghostwriter_server::{acceptor::AcceptorConfig, listen};
+
+    let mut addrs = vec!["127.0.0.1:0".parse().unwrap()];
+    if std::net::TcpListener::bind("[::1]:0").is_ok() {
+        addrs.push("[::1]:0".parse().unwrap());
+    }
+    let listeners = listen::bind_all(&addrs).unwrap();
+    let bound: Vec<_> = (listeners.iter())
+        .map(|l| l.local_addr().unwrap())
+        .collect();
+    let (_dir, workspace) = workspace();
+    let config = AcceptorConfig {
+        max_clients: bound.len(),
+        ..AcceptorConfig::default()
+    };
+    let server = tokio::spawn(acceptor::run_tcp_all_until(
+        listeners,
+        workspace,
+        None,
+        config,
+        std::future::pending(),
+    ));
+
+    let mut clients = Vec::new();
+    for addr in bound {
+        clients.push(connect(addr, Role::Editor).await);
+    }
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn survives_failed_and_stalled_handshakes() {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (_dir, workspace) = workspace();
+    let server = tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+
+    // One client never finishes its handshake, another botches it.
+    let _stalled = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut botched = tokio::net::TcpStream::connect(addr).await.unwrap();
+    botched.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    drop(botched);
+
+    let _ws = connect(addr, Role::Editor).await;
+    assert!(!server.is_finished());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn enforces_file_size_and_write_quota() {
+    use ghostwriter_proto::{Insert, Open, peek_type};
+    use ghostwriter_server::quota::WriteLimits;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let config = acceptor::AcceptorConfig {
+        write_limits: WriteLimits {
+            max_file_size: Some(8),
+            connection_quota: Some(12),
+            workspace_cap: None,
+        },
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    next_binary(&mut ws).await;
+
+    async fn next_error(ws: &mut Client) -> ErrorMsg {
+        loop {
+            let data = next_binary(ws).await;
+            if peek_type(&data).unwrap() == MessageType::Error {
+                let env: Envelope<ErrorMsg> = decode(&data).unwrap();
+                return env.data;
+            }
+        }
+    }
+    for (seq, text) in [(1, "xyz"), (2, "!")] {
+        let insert = Insert {
+            pos: 0,
+            text: text.into(),
+            seq,
+            base_doc_v: None,
+        };
+        send_env(&mut ws, MessageType::Insert, insert).await;
+    }
+    let err = next_error(&mut ws).await;
+    assert_eq!(err.code, ErrorCode::TooLarge);
+    assert_eq!(err.path.as_deref(), Some("a.txt"));
+
+    // The first save uses 8 of the 12 bytes the connection may write.
+    send_env(&mut ws, MessageType::Save, ()).await;
+    send_env(&mut ws, MessageType::Save, ()).await;
+    let err = next_error(&mut ws).await;
+    assert_eq!(err.code, ErrorCode::QuotaExceeded);
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+        "xyzhello"
+    );
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn readonly_workspace_refuses_changes() {
+    use ghostwriter_proto::{Frame, Insert, Open, PickerAction, peek_type};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let config = acceptor::AcceptorConfig {
+        readonly: true,
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "hello");
+
+    let insert = Insert {
+        pos: 0,
+        text: "x".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Readonly);
+    assert_eq!(env.data.msg, "workspace is read-only");
+
+    let actions = [
+        PickerAction::Create {
+            path: "b.txt".into(),
+            dir: false,
+        },
+        PickerAction::Rename {
+            from: "a.txt".into(),
+            to: "b.txt".into(),
+        },
+        PickerAction::Delete {
+            path: "a.txt".into(),
+        },
+    ];
+    for action in actions {
+        send_env(&mut ws, MessageType::PickerAction, action).await;
+        let data = next_binary(&mut ws).await;
+        assert_eq!(peek_type(&data).unwrap(), MessageType::Error);
+        let env: Envelope<ErrorMsg> = decode(&data).unwrap();
+        assert_eq!(env.data.code, ErrorCode::Readonly);
+    }
+    // Browsing still works.
+    let expand = PickerAction::Expand { path: "".into() };
+    send_env(&mut ws, MessageType::PickerAction, expand).await;
+    assert_eq!(
+        peek_type(&next_binary(&mut ws).await).unwrap(),
+        MessageType::DirList
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+        "hello"
+    );
+    assert!(!dir.path().join("b.txt").exists());
+    assert!(!dir.path().join(".ghostwriter").exists());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn manages_files_before_one_is_open() {
+    use ghostwriter_proto::{DirList, Frame, PickerAction};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let workspace = Workspace::new(dir.path()).unwrap();
+    let server = tokio::spawn(acceptor::run_tcp(listener, workspace, None));
+    let mut ws = connect(addr, Role::Editor).await;
+
+    let actions = [
+        PickerAction::Create {
+            path: "notes".into(),
+            dir: true,
+        },
+        PickerAction::Create {
+            path: "notes/a.txt".into(),
+            dir: false,
+        },
+        PickerAction::Rename {
+            from: "notes/a.txt".into(),
+            to: "notes/b.txt".into(),
+        },
+    ];
+    let mut listed = Vec::new();
+    for action in actions {
+        send_env(&mut ws, MessageType::PickerAction, action).await;
+        let env: Envelope<DirList> = decode(&next_binary(&mut ws).await).unwrap();
+        let paths: Vec<_> = env.data.entries.into_iter().map(|e| e.path).collect();
+        listed.push((env.data.path, paths));
+    }
+    let listing = |dir: &str, paths: &[&str]| {
+        let paths = paths.iter().map(|p| p.to_string()).collect();
+        (dir.to_string(), paths)
+    };
+    assert_eq!(
+        listed,
+        [
+            listing("", &["notes"]),
+            listing("notes", &["notes/a.txt"]),
+            listing("notes", &["notes/b.txt"]),
+        ]
+    );
+
+    let open = PickerAction::Open {
+        path: "notes/b.txt".into(),
+    };
+    send_env(&mut ws, MessageType::PickerAction, open).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.kind, "editor");
+    assert!(dir.path().join("notes/b.txt").exists());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn serves_named_workspace_roots() {
+    use ghostwriter_proto::{DirList, Frame, Open, PickerAction, peek_type};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let a = tempfile::tempdir().unwrap();
+    let b = tempfile::tempdir().unwrap();
+    std::fs::write(b.path().join("notes.txt"), "from b").unwrap();
+    let workspace = Workspace::named([("a", a.path()), ("b", b.path())]).unwrap();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        let config = acceptor::AcceptorConfig::default();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "workspace:b/notes.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "from b");
+
+    let expand = PickerAction::Expand { path: "".into() };
+    send_env(&mut ws, MessageType::PickerAction, expand).await;
+    let data = next_binary(&mut ws).await;
+    assert_eq!(peek_type(&data).unwrap(), MessageType::DirList);
+    let env: Envelope<DirList> = decode(&data).unwrap();
+    let names: Vec<_> = env.data.entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(names, ["workspace:a", "workspace:b"]);
+
+    let open = Open {
+        path: "notes.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::NotFound);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn limits_workspace_roots_to_their_secrets() {
+    use ghostwriter_proto::{DirList, Open, PickerAction, peek_type};
+    use ghostwriter_server::workspace::RootAccess;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let a = tempfile::tempdir().unwrap();
+    let b = tempfile::tempdir().unwrap();
+    std::fs::write(b.path().join("notes.txt"), "from b").unwrap();
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(b"b-key", &salt)
+        .unwrap()
+        .to_string();
+    let readonly = RootAccess {
+        readonly: true,
+        ..RootAccess::default()
+    };
+    let locked = RootAccess {
+        secret_hash: Some(hash),
+        readonly: false,
+    };
+    let workspace = Workspace::named([("a", a.path()), ("b", b.path())])
+        .unwrap()
+        .with_root_access("a", readonly)
+        .unwrap()
+        .with_root_access("b", locked)
+        .unwrap();
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        let config = acceptor::AcceptorConfig::default();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+
+    let login = |secret: &'static str| async move {
+        let mut ws = connect(addr, Role::Editor).await;
+        send_env(&mut ws, MessageType::Auth, Auth::shared_secret(secret)).await;
+        ws
+    };
+    let top = |data: Vec<u8>| {
+        assert_eq!(peek_type(&data).unwrap(), MessageType::DirList);
+        let env: Envelope<DirList> = decode(&data).unwrap();
+        env.data
+            .entries
+            .into_iter()
+            .map(|e| e.path)
+            .collect::<Vec<_>>()
+    };
+
+    // The key unlocks `b`; `a` stays read-only.
+    let mut ws = login("b-key").await;
+    let open = Open {
+        path: "workspace:b/notes.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    assert_eq!(
+        peek_type(&next_binary(&mut ws).await).unwrap(),
+        MessageType::Frame
+    );
+    let expand = PickerAction::Expand { path: "".into() };
+    send_env(&mut ws, MessageType::PickerAction, expand).await;
+    assert_eq!(
+        top(next_binary(&mut ws).await),
+        ["workspace:a", "workspace:b"]
+    );
+    let create = PickerAction::Create {
+        path: "workspace:a/new.txt".into(),
+        dir: false,
+    };
+    send_env(&mut ws, MessageType::PickerAction, create).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Readonly);
+    assert!(!a.path().join("new.txt").exists());
+    ws.close(None).await.unwrap();
+
+    // Any other secret only reaches the roots without one.
+    let mut ws = login("wrong").await;
+    let open = Open {
+        path: "workspace:b/notes.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.msg, "no access to workspace:b");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn keeps_roots_without_a_key_behind_the_server_secret() {
+    use ghostwriter_proto::{DirList, Open, PickerAction, peek_type};
+    use ghostwriter_server::workspace::RootAccess;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let a = tempfile::tempdir().unwrap();
+    let b = tempfile::tempdir().unwrap();
+    std::fs::write(a.path().join("notes.txt"), "from a").unwrap();
+    let hash = |secret: &[u8]| {
+        let salt = SaltString::generate(&mut OsRng);
+        (Argon2::default().hash_password(secret, &salt))
+            .unwrap()
+            .to_string()
+    };
+    let locked = RootAccess {
+        secret_hash: Some(hash(b"b-key")),
+        readonly: false,
+    };
+    let workspace = Workspace::named([("a", a.path()), ("b", b.path())])
+        .unwrap()
+        .with_root_access("b", locked)
+        .unwrap();
+    let server_hash = Some(hash(b"server-key"));
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        let config = acceptor::AcceptorConfig::default();
+        acceptor::run_tcp_until(listener, workspace, server_hash, config, shutdown).await
+    });
+
+    let login = |secret: &'static str| async move {
+        let mut ws = connect(addr, Role::Editor).await;
+        send_env(&mut ws, MessageType::Auth, Auth::shared_secret(secret)).await;
+        ws
+    };
+    async fn top(ws: &mut Client) -> Vec<String> {
+        let expand = PickerAction::Expand { path: "".into() };
+        send_env(ws, MessageType::PickerAction, expand).await;
+        let data = next_binary(ws).await;
+        assert_eq!(peek_type(&data).unwrap(), MessageType::DirList);
+        let env: Envelope<DirList> = decode(&data).unwrap();
+        (env.data.entries.into_iter()).map(|e| e.path).collect()
+    }
+
+    // The key of `b` lets a client in, but only to `b`.
+    let mut ws = login("b-key").await;
+    assert_eq!(top(&mut ws).await, ["workspace:b"]);
+    let open = Open {
+        path: "workspace:a/notes.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.msg, "no access to workspace:a");
+    ws.close(None).await.unwrap();
+
+    // The server secret opens `a` but not `b`.
+    let mut ws = login("server-key").await;
+    assert_eq!(top(&mut ws).await, ["workspace:a"]);
+    ws.close(None).await.unwrap();
+
+    let mut ws = login("wrong").await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut ws).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::Unauthorized);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn followers_only_follow_sessions_they_may_read() {
+    use ghostwriter_proto::{Frame, Open, RequestFrame};
+    use ghostwriter_server::workspace::RootAccess;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let a = tempfile::tempdir().unwrap();
+    let b = tempfile::tempdir().unwrap();
+    std::fs::write(a.path().join("notes.txt"), "from a").unwrap();
+    let hash = |secret: &[u8]| {
+        let salt = SaltString::generate(&mut OsRng);
+        (Argon2::default().hash_password(secret, &salt))
+            .unwrap()
+            .to_string()
+    };
+    let locked = RootAccess {
+        secret_hash: Some(hash(b"b-key")),
+        readonly: false,
+    };
+    let workspace = Workspace::named([("a", a.path()), ("b", b.path())])
+        .unwrap()
+        .with_root_access("b", locked)
+        .unwrap();
+    let server_hash = Some(hash(b"server-key"));
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        let config = acceptor::AcceptorConfig {
+            max_clients: 3,
+            ..Default::default()
+        };
+        acceptor::run_tcp_until(listener, workspace, server_hash, config, shutdown).await
+    });
+    let login = |role: Role, secret: &'static str| async move {
+        let mut ws = connect(addr, role).await;
+        send_env(&mut ws, MessageType::Auth, Auth::shared_secret(secret)).await;
+        ws
+    };
+    let request = RequestFrame {
+        reason: "initial".into(),
+    };
+
+    let mut editor = login(Role::Editor, "server-key").await;
+    let open = Open {
+        path: "workspace:a/notes.txt".into(),
+    };
+    send_env(&mut editor, MessageType::Open, open).await;
+    let _: Envelope<Frame> = decode(&next_binary(&mut editor).await).unwrap();
+
+    // The key of `b` does not let a follower watch a file in `a`.
+    let mut follower = login(Role::Follower, "b-key").await;
+    send_env(&mut follower, MessageType::RequestFrame, request.clone()).await;
+    let env: Envelope<ErrorMsg> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.code, ErrorCode::NotFound);
+    follower.close(None).await.unwrap();
+
+    let mut follower = login(Role::Follower, "server-key").await;
+    send_env(&mut follower, MessageType::RequestFrame, request).await;
+    let env: Envelope<Frame> = decode(&next_binary(&mut follower).await).unwrap();
+    assert_eq!(env.data.lines[0].text, "from a");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn reloads_settings_without_dropping_clients() {
+    use ghostwriter_proto::{Insert, Open, peek_type};
+    use ghostwriter_server::{access::AccessList, quota::WriteLimits};
+    use tokio::time::{Duration, sleep};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (dir, workspace) = workspace();
+    std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    let settings = acceptor::Reload {
+        tokens: None,
+        access: AccessList::default(),
+        connect_limit: acceptor::DEFAULT_CONNECT_LIMIT,
+        auth_limit: acceptor::DEFAULT_AUTH_LIMIT,
+        write_limits: WriteLimits::default(),
+    };
+    let (reload, reloads) = tokio::sync::watch::channel(settings.clone());
+    let config = acceptor::AcceptorConfig {
+        reload: Some(reloads),
+        ..Default::default()
+    };
+    let server = tokio::spawn(async move {
+        let shutdown = std::future::pending();
+        acceptor::run_tcp_until(listener, workspace, None, config, shutdown).await
+    });
+    let mut ws = connect(addr, Role::Editor).await;
+    let open = Open {
+        path: "a.txt".into(),
+    };
+    send_env(&mut ws, MessageType::Open, open).await;
+    next_binary(&mut ws).await;
+
+    reload.send_replace(acceptor::Reload {
+        access: AccessList {
+            allow: Vec::new(),
+            deny: vec!["127.0.0.0/8".parse().unwrap()],
+        },
+        write_limits: WriteLimits {
+            max_file_size: Some(6),
+            ..WriteLimits::default()
+        },
+        ..settings
+    });
+    sleep(Duration::from_millis(100)).await;
+    let connected = tokio_tungstenite::connect_async(format!("ws://{addr}")).await;
+    assert!(connected.is_err());
+
+    // The connected client stays, under the new limits.
+    let insert = Insert {
+        pos: 0,
+        text: "xyz".into(),
+        seq: 1,
+        base_doc_v: None,
+    };
+    send_env(&mut ws, MessageType::Insert, insert).await;
+    let err = loop {
+        let data = next_binary(&mut ws).await;
+        if peek_type(&data).unwrap() == MessageType::Error {
+            break decode::<ErrorMsg>(&data).unwrap().data;
+        }
+    };
+    assert_eq!(err.code, ErrorCode::TooLarge);
+
+    server.abort();
+}
@@ -1,74 +1,898 @@
 use anyhow::{Result, anyhow};
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use ghostwriter_proto::Auth;
+use ghostwriter_server::access::{AccessList, Cidr};
+use ghostwriter_server::history::{DEFAULT_UNDO_HISTORY_SIZE, UndoHistory};
+use ghostwriter_server::quota::WriteLimits;
+use ghostwriter_server::ratelimit::RateLimit;
+use ghostwriter_server::snippets::Snippets;
+use std::io::{self, BufRead};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{ConfigFile, load_keymap, load_status_layout};
+use crate::logfile::{DEFAULT_LOG_KEEP, LogRotation, RotatingFile};
+
+/// Port `--bind` listens on when the address has none.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Where `--server` listens without `--bind`.
+pub const DEFAULT_BIND: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT);
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("reads_config").args(["server", "connect", "dump_keys"]).multiple(true)))]
 pub struct Args {
     /// Run in server mode hosting the given workspace directory
     #[arg(long, value_name = "DIR", conflicts_with = "connect")]
     pub server: Option<PathBuf>,
 
-    /// Connect to a remote server at the given URL
+    /// Connect to a remote server at the given URL (`ws://`, `wss://`,
+    /// `ssh://user@host/path/to/workspace` through an SSH tunnel, or
+    /// `quic://` with the `quic` feature), or `auto` to use the first
+    /// server advertised on the local network (with the `mdns` feature)
     #[arg(long, value_name = "URL", conflicts_with = "server")]
     pub connect: Option<String>,
 
+    /// With `--connect`, watch the session another client is editing
+    /// instead of opening one
+    #[arg(long, requires = "connect")]
+    pub follow: bool,
+
+    /// With `--connect`, resume the session with this id, left running
+    /// when an earlier connection dropped
+    #[arg(
+        long,
+        value_name = "ID",
+        requires = "connect",
+        conflicts_with = "follow"
+    )]
+    pub attach: Option<u64>,
+
+    /// With `--connect`, repaint the screen at most this many times a
+    /// second, for slow links like serial consoles; frames in between are
+    /// skipped
+    #[arg(
+        long,
+        value_name = "PER_SECOND",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "connect"
+    )]
+    pub render_budget: Option<u32>,
+
+    /// With `--connect`, place the cursor, select and pick files with the
+    /// mouse; the terminal's own selection then needs a modifier, usually
+    /// Shift
+    #[arg(long, requires = "connect")]
+    pub mouse: bool,
+
+    /// With `--server`, serve `wss://` using the PEM certificate chain in
+    /// this file
+    #[arg(long, value_name = "FILE", requires_all = ["server", "tls_key"])]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key for `--tls-cert`, as PEM
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// With `--tls-cert`, only accept clients presenting a certificate
+    /// issued by a CA in this PEM file (mutual TLS)
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// With `--tls-cert`, also serve `quic://` on this UDP address, like
+    /// `0.0.0.0:4433` (needs the `quic` feature). QUIC clients cannot
+    /// present certificates, so this excludes `--tls-client-ca`
+    #[arg(
+        long,
+        value_name = "ADDR",
+        value_parser = parse_addr,
+        requires = "tls_cert",
+        conflicts_with = "tls_client_ca"
+    )]
+    pub quic_bind: Option<SocketAddr>,
+
+    /// With a `wss://` `--connect` URL, trust only the PEM certificates in
+    /// this file instead of the system roots
+    #[arg(long, value_name = "FILE", requires = "connect")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// With a `wss://` `--connect` URL, authenticate with the PEM
+    /// certificate in this file
+    #[arg(long, value_name = "FILE", requires_all = ["connect", "client_key"])]
+    pub client_cert: Option<PathBuf>,
+
+    /// Private key for `--client-cert`, as PEM
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// With `--server`, also accept the named tokens in this file, as
+    /// managed by `ghostwriter token`
+    #[arg(long, value_name = "FILE", requires = "server")]
+    pub tokens: Option<PathBuf>,
+
+    /// With `--server`, only accept connections from this address or
+    /// block, like `192.168.1.0/24`; repeat to allow several
+    #[arg(long, value_name = "CIDR", requires = "server")]
+    pub allow: Vec<Cidr>,
+
+    /// With `--server`, refuse connections from this address or block,
+    /// even if `--allow` lets it in; repeatable
+    #[arg(long, value_name = "CIDR", requires = "server")]
+    pub deny: Vec<Cidr>,
+
+    /// With `--server`, connection attempts allowed per address, as
+    /// `<count>/<period>` like `3/1m` (the default)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate_limit, requires = "server")]
+    pub connect_limit: Option<RateLimit>,
+
+    /// With `--server`, failed logins allowed per address before logins
+    /// are refused for a while, like `5/5m` (the default)
+    #[arg(long, value_name = "RATE", value_parser = parse_rate_limit, requires = "server")]
+    pub auth_limit: Option<RateLimit>,
+
+    /// With `--server`, read tokens, access lists, limits and the log level
+    /// from this TOML file, and again on SIGHUP; with `--connect`, read
+    /// key bindings from its `[keys]` table
+    #[arg(long, value_name = "FILE", requires = "reads_config")]
+    pub config: Option<PathBuf>,
+
+    /// Print the key bindings, with those of `--config`, as a `[keys]`
+    /// table to copy into a config file
+    #[arg(long, conflicts_with_all = ["server", "connect"])]
+    pub dump_keys: bool,
+
+    /// With `--server`, listen on this address, like `0.0.0.0`, `[::1]` or
+    /// `[::]:9000`; repeat to listen on several [default: 127.0.0.1:8080]
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr, requires = "server")]
+    pub bind: Vec<SocketAddr>,
+
+    /// With `--server`, also listen on the Unix socket `.ghostwriter.sock`
+    /// in the workspace, which `--connect ssh://host/<workspace>` reaches
+    /// (Unix only)
+    #[arg(long, requires = "server")]
+    pub ssh_socket: bool,
+
+    /// With `--server`, advertise the server on the local network over
+    /// mDNS so `--connect auto` finds it (needs the `mdns` feature)
+    #[arg(long, requires = "server")]
+    pub mdns: bool,
+
+    /// With `--server`, append a JSON line for every file clients read,
+    /// write, list, lock, create, rename or delete to this file
+    #[arg(long, value_name = "FILE", requires = "server")]
+    pub file_audit_log: Option<PathBuf>,
+
+    /// With `--server`, serve Prometheus metrics at
+    /// `http://<ADDR>/metrics`
+    #[arg(long, value_name = "ADDR", requires = "server")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// With `--server`, refuse edits and saves that make a file larger
+    /// than this, like `10M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "server")]
+    pub max_file_size: Option<u64>,
+
+    /// With `--server`, bytes each connection may save in total
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "server")]
+    pub write_quota: Option<u64>,
+
+    /// With `--server`, refuse saves that grow the workspace past this
+    /// total size
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "server")]
+    pub workspace_cap: Option<u64>,
+
+    /// With `--server`, let clients browse, search and view files but
+    /// refuse every change
+    #[arg(long, requires = "server")]
+    pub readonly: bool,
+
+    /// With `--server`, keep up to this much undo history per file in the
+    /// user's data directory, so edits made before a file was closed can
+    /// be undone once it is opened again [default: 1M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "server")]
+    pub undo_history_size: Option<u64>,
+
+    /// With `--server`, forget the undo history of files once they are
+    /// closed
+    #[arg(long, requires = "server", conflicts_with = "undo_history_size")]
+    pub no_undo_history: bool,
+
+    /// With `--server`, directory of snippets Tab expands, a TOML file per
+    /// file extension mapping trigger words to text [default: the
+    /// `ghostwriter/snippets` directory in the user's config directory]
+    #[arg(long, value_name = "DIR", requires = "server")]
+    pub snippets: Option<PathBuf>,
+
+    /// With `--server`, confine the process to the workspace with Landlock
+    /// and a seccomp filter (Linux only)
+    #[arg(long, requires = "server")]
+    pub sandbox: bool,
+
+    /// With `--server`, switch to this user, by name or id, once the
+    /// listener is bound
+    #[arg(long, value_name = "USER", requires = "server")]
+    pub user: Option<String>,
+
+    /// With `--server`, switch to this group instead of the user's primary
+    /// group
+    #[arg(long, value_name = "GROUP", requires = "server")]
+    pub group: Option<String>,
+
+    /// With `--server`, also serve DIR, addressed as `workspace:NAME/...`;
+    /// the `--server` directory is then named after its last component
+    #[arg(
+        long = "workspace",
+        value_name = "NAME=DIR",
+        value_parser = parse_named_root,
+        requires = "server"
+    )]
+    pub workspaces: Vec<(String, PathBuf)>,
+
+    /// With `--server`, only let clients authenticating with the secret
+    /// whose Argon2 hash is in FILE use workspace NAME; every client must
+    /// then authenticate
+    #[arg(
+        long = "workspace-key",
+        value_name = "NAME=FILE",
+        value_parser = parse_workspace_key,
+        requires = "server"
+    )]
+    pub workspace_keys: Vec<(String, PathBuf)>,
+
+    /// With `--server`, let clients view but not change workspace NAME
+    #[arg(long = "workspace-readonly", value_name = "NAME", requires = "server")]
+    pub readonly_workspaces: Vec<String>,
+
+    /// With `--connect`, authenticate as this token, using `--secret` as
+    /// its secret
+    #[arg(long, value_name = "ID", requires_all = ["connect", "secret_source"])]
+    pub token_id: Option<String>,
+
     /// Shared secret for authentication
-    #[arg(long, env = "GHOSTWRITER_SECRET")]
+    #[arg(long, env = "GHOSTWRITER_SECRET", group = "secret_source")]
     pub secret: Option<String>,
+
+    /// Read the secret from the first line of standard input instead of
+    /// `--secret`
+    #[arg(long, group = "secret_source")]
+    pub secret_stdin: bool,
+
+    /// Ask for the secret on the terminal without echoing it
+    #[arg(long, group = "secret_source")]
+    pub secret_prompt: bool,
+
+    /// With `--connect`, save the secret in the OS keyring for this URL;
+    /// without a secret, `--connect` uses the one saved for its URL
+    #[arg(long, requires_all = ["connect", "secret_source"])]
+    pub remember_secret: bool,
+
+    /// How log lines are written; `json` emits one object per line with
+    /// the connection and session spans, for log aggregation
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Append log lines to this file instead of writing them to stdout
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// With `--log-file`, move the file aside once it would grow past this
+    /// size, like `10M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "log_file")]
+    pub log_max_size: Option<u64>,
+
+    /// With `--log-max-size`, how many rotated files to keep, as
+    /// `FILE.1` (the newest) to `FILE.N` [default: 5]
+    #[arg(long, value_name = "N", requires = "log_max_size")]
+    pub log_keep: Option<usize>,
+
+    /// With `--server`, detach from the terminal and run in the background
+    /// (Unix only), logging to `--log-file`; SIGTERM stops it and SIGHUP
+    /// reloads `--config`
+    #[arg(long, requires_all = ["server", "log_file"])]
+    pub daemon: bool,
+
+    /// With `--daemon`, write the server's process id to this file
+    #[arg(long, value_name = "FILE", requires = "daemon")]
+    pub pid_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+pub enum Command {
+    /// Print JSON Schemas for all protocol messages
+    ProtoSchema,
+    /// Manage the named tokens a server accepts with `--tokens`
+    Token {
+        /// Token file to manage
+        #[arg(long, value_name = "FILE")]
+        store: PathBuf,
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand, PartialEq, Eq)]
+pub enum TokenAction {
+    /// Create a token and print its secret, which is not shown again
+    Issue {
+        id: String,
+        /// Expire the token after this long, e.g. `90m`, `12h` or `30d`
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        expires_in: Option<Duration>,
+    },
+    /// Delete a token
+    Revoke { id: String },
+    /// List tokens and when they expire
+    List,
 }
 
+// Built once per run, so the size of the server variant does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum Mode {
     Local,
-    Server { root: PathBuf },
-    Connect { url: String },
+    Server {
+        root: PathBuf,
+        tls: Option<TlsFiles>,
+        /// CA that client certificates must be issued by.
+        client_ca: Option<PathBuf>,
+        /// Named token file accepted alongside the shared secret.
+        tokens: Option<PathBuf>,
+        /// Addresses allowed to connect.
+        access: AccessList,
+        connect_limit: Option<RateLimit>,
+        auth_limit: Option<RateLimit>,
+        /// Where clients' file operations are recorded.
+        file_audit_log: Option<PathBuf>,
+        /// Address of the metrics endpoint.
+        metrics_addr: Option<SocketAddr>,
+        /// Limits on how much clients may write.
+        limits: WriteLimits,
+        /// Whether clients may only view files.
+        readonly: bool,
+        /// Whether to confine the process to the workspace.
+        sandbox: bool,
+        /// User and group to run as after binding.
+        user: Option<String>,
+        group: Option<String>,
+        /// Further named roots served next to `root`.
+        workspaces: Vec<(String, PathBuf)>,
+        /// Files holding the hash of the secret each named root needs.
+        workspace_keys: Vec<(String, PathBuf)>,
+        /// Named roots clients may only view.
+        readonly_workspaces: Vec<String>,
+        /// File with settings that override the flags and are reloaded on
+        /// SIGHUP.
+        config: Option<PathBuf>,
+        /// Whether to advertise the server over mDNS.
+        mdns: bool,
+        /// Addresses to listen on.
+        bind: Vec<SocketAddr>,
+        /// UDP address to serve QUIC on.
+        quic_bind: Option<SocketAddr>,
+        /// Whether to listen on the workspace's socket for SSH tunnels.
+        ssh_socket: bool,
+    },
+    Connect {
+        url: String,
+        follow: bool,
+        attach: Option<u64>,
+        ca: Option<PathBuf>,
+        /// Client certificate for mutual TLS.
+        identity: Option<TlsFiles>,
+        /// Token `--secret` belongs to, if it is not the shared secret.
+        token_id: Option<String>,
+    },
+    DumpKeys,
+    ProtoSchema,
+    Token {
+        store: PathBuf,
+        action: TokenAction,
+    },
+}
+
+/// A PEM certificate chain and its private key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
 }
 
 impl Args {
     pub fn mode(&self) -> Result<Mode> {
+        match &self.command {
+            Some(Command::ProtoSchema) => return Ok(Mode::ProtoSchema),
+            Some(Command::Token { store, action }) => {
+                return Ok(Mode::Token {
+                    store: store.clone(),
+                    action: action.clone(),
+                });
+            }
+            None => {}
+        }
+        if self.dump_keys {
+            return Ok(Mode::DumpKeys);
+        }
         match (&self.server, &self.connect) {
             (Some(_), Some(_)) => Err(anyhow!("--server and --connect are mutually exclusive")),
-            (Some(root), None) => Ok(Mode::Server { root: root.clone() }),
-            (None, Some(url)) => Ok(Mode::Connect { url: url.clone() }),
+            (Some(_), None) if self.sandbox && !cfg!(target_os = "linux") => {
+                Err(anyhow!("--sandbox is only supported on Linux"))
+            }
+            (Some(_), None) if (self.user.is_some() || self.group.is_some()) && !cfg!(unix) => {
+                Err(anyhow!("--user and --group are only supported on Unix"))
+            }
+            (Some(_), None) if self.ssh_socket && !cfg!(unix) => {
+                Err(anyhow!("--ssh-socket is only supported on Unix"))
+            }
+            (Some(_), None) if self.mdns && !cfg!(feature = "mdns") => Err(anyhow!(
+                "mDNS advertising is not available; rebuild with `--features mdns`"
+            )),
+            (Some(_), None) if self.quic_bind.is_some() && !cfg!(feature = "quic") => Err(anyhow!(
+                "QUIC transport is not available; rebuild with `--features quic`"
+            )),
+            (Some(root), None) => Ok(Mode::Server {
+                root: root.clone(),
+                tls: self.tls_files(),
+                client_ca: self.tls_client_ca.clone(),
+                tokens: self.tokens.clone(),
+                access: AccessList {
+                    allow: self.allow.clone(),
+                    deny: self.deny.clone(),
+                },
+                connect_limit: self.connect_limit,
+                auth_limit: self.auth_limit,
+                file_audit_log: self.file_audit_log.clone(),
+                metrics_addr: self.metrics_addr,
+                limits: WriteLimits {
+                    max_file_size: self.max_file_size,
+                    connection_quota: self.write_quota,
+                    workspace_cap: self.workspace_cap,
+                },
+                readonly: self.readonly,
+                sandbox: self.sandbox,
+                user: self.user.clone(),
+                group: self.group.clone(),
+                workspaces: self.workspaces.clone(),
+                workspace_keys: self.workspace_keys.clone(),
+                readonly_workspaces: self.readonly_workspaces.clone(),
+                config: self.config.clone(),
+                mdns: self.mdns,
+                bind: match self.bind.as_slice() {
+                    [] => vec![DEFAULT_BIND],
+                    bind => bind.to_vec(),
+                },
+                quic_bind: self.quic_bind,
+                ssh_socket: self.ssh_socket,
+            }),
+            (None, Some(url)) => {
+                check_scheme(url)?;
+                let custom_tls = self.tls_ca.is_some() || self.client_cert.is_some();
+                if custom_tls && !url.starts_with("wss://") {
+                    return Err(anyhow!("--tls-ca and --client-cert need a wss:// URL"));
+                }
+                Ok(Mode::Connect {
+                    url: url.clone(),
+                    follow: self.follow,
+                    attach: self.attach,
+                    ca: self.tls_ca.clone(),
+                    identity: self.client_identity(),
+                    token_id: self.token_id.clone(),
+                })
+            }
             (None, None) => Ok(Mode::Local),
         }
     }
+
+    /// Rotation of `--log-file` from the flags, overridden by the config
+    /// file. Unlike the other settings there, it is only read at startup.
+    pub fn log_rotation(&self) -> Result<LogRotation> {
+        let flags = LogRotation {
+            max_size: self.log_max_size,
+            keep: self.log_keep.unwrap_or(DEFAULT_LOG_KEEP),
+        };
+        match &self.config {
+            Some(path) => ConfigFile::load(path)?.log_rotation(flags),
+            None => Ok(flags),
+        }
+    }
+
+    /// Undo history kept between sessions, from the flags overridden by
+    /// the config file. Like log rotation, it is only read at startup.
+    pub fn undo_history(&self) -> Result<UndoHistory> {
+        let flags = (!self.no_undo_history)
+            .then(|| self.undo_history_size.unwrap_or(DEFAULT_UNDO_HISTORY_SIZE));
+        let size = match &self.config {
+            Some(path) => ConfigFile::load(path)?.undo_history(flags)?,
+            None => flags,
+        };
+        Ok(size.map_or_else(UndoHistory::default, UndoHistory::in_data_dir))
+    }
+
+    /// Snippets from the directory the config file or the flags name, or
+    /// else the user's own; none if it does not exist. Only read at
+    /// startup.
+    pub fn snippets(&self) -> Result<Snippets> {
+        match self.snippets_dir()? {
+            Some(dir) => Ok(Snippets::load(&dir)?),
+            None => Ok(Snippets::default()),
+        }
+    }
+
+    /// The directory [`snippets`](Self::snippets) reads.
+    pub fn snippets_dir(&self) -> Result<Option<PathBuf>> {
+        let file = match &self.config {
+            Some(path) => ConfigFile::load(path)?.snippets,
+            None => None,
+        };
+        Ok(file
+            .or_else(|| self.snippets.clone())
+            .or_else(Snippets::config_dir))
+    }
+
+    fn tls_files(&self) -> Option<TlsFiles> {
+        Some(TlsFiles {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+        })
+    }
+
+    fn client_identity(&self) -> Option<TlsFiles> {
+        Some(TlsFiles {
+            cert: self.client_cert.clone()?,
+            key: self.client_key.clone()?,
+        })
+    }
+}
+
+/// Parse `N` followed by a unit of `s`, `m`, `h` or `d`.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s.len() - s.chars().last().map_or(0, char::len_utf8);
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n
+        .parse()
+        .map_err(|_| anyhow!("expected a number and a unit, like 12h"))?;
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("unknown unit {unit:?}; use s, m, h or d")),
+    };
+    Ok(Duration::from_secs(n * secs))
+}
+
+/// Parse `<count>/<period>`, with the period as for [`parse_duration`].
+pub(crate) fn parse_rate_limit(s: &str) -> Result<RateLimit> {
+    let (burst, period) = s
+        .split_once('/')
+        .ok_or_else(|| anyhow!("expected <count>/<period>, like 3/1m"))?;
+    let burst = burst
+        .parse()
+        .map_err(|_| anyhow!("expected a count before the /"))?;
+    Ok(RateLimit::new(burst, parse_duration(period)?))
+}
+
+/// Parse an IP address with an optional port, bracketing IPv6 addresses
+/// that have one, like `[::1]:9000`.
+fn parse_addr(s: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    let ip = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| anyhow!("expected an IP address and optional port, like [::]:8080"))?;
+    Ok(SocketAddr::new(ip, DEFAULT_PORT))
+}
+
+/// Parse a `NAME=DIR` workspace root.
+fn parse_named_root(s: &str) -> Result<(String, PathBuf)> {
+    split_named(s).ok_or_else(|| anyhow!("expected NAME=DIR, like docs=/srv/docs"))
+}
+
+/// Parse a `NAME=FILE` workspace secret hash.
+fn parse_workspace_key(s: &str) -> Result<(String, PathBuf)> {
+    split_named(s).ok_or_else(|| anyhow!("expected NAME=FILE, like docs=/etc/docs.hash"))
+}
+
+/// Split `NAME=PATH`, with a workspace name and a path that are not empty.
+fn split_named(s: &str) -> Option<(String, PathBuf)> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !name.contains('/') && !path.is_empty() => {
+            Some((name.to_string(), PathBuf::from(path)))
+        }
+        _ => None,
+    }
 }
 
-pub fn init_logging() {
-    use tracing_subscriber::{EnvFilter, fmt};
+/// Parse a byte count with an optional `K`, `M` or `G` suffix (powers of
+/// 1024).
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let (n, shift) = match s.strip_suffix(['K', 'k']) {
+        Some(n) => (n, 10),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(n) => (n, 20),
+            None => match s.strip_suffix(['G', 'g']) {
+                Some(n) => (n, 30),
+                None => (s, 0),
+            },
+        },
+    };
+    let n: u64 = n
+        .parse()
+        .map_err(|_| anyhow!("expected a byte count, like 512K or 10M"))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("{s} is too large"))
+}
 
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    let subscriber = fmt().with_env_filter(filter).finish();
-    let _ = tracing::subscriber::set_global_default(subscriber);
+/// Reject `--connect` URLs whose transport this build does not support.
+fn check_scheme(url: &str) -> Result<()> {
+    if url == "auto" && !cfg!(feature = "mdns") {
+        return Err(anyhow!(
+            "server discovery is not available; rebuild with `--features mdns`"
+        ));
+    }
+    let scheme = url.split_once("://").map_or(url, |(scheme, _)| scheme);
+    match scheme {
+        "ws" | "wss" | "ssh" | "auto" => Ok(()),
+        "quic" if cfg!(feature = "quic") => Ok(()),
+        "quic" => Err(anyhow!(
+            "QUIC transport is not available; rebuild with `--features quic`"
+        )),
+        _ => Err(anyhow!("unsupported URL scheme in {url}")),
+    }
+}
+
+pub fn init_logging(format: LogFormat, file: Option<&Path>, rotation: LogRotation) -> Result<()> {
+    use tracing_subscriber::{fmt, fmt::writer::BoxMakeWriter};
+
+    let writer = match file {
+        Some(path) => {
+            let file = RotatingFile::open(path, rotation)
+                .map_err(|e| anyhow!("cannot open log file {}: {e}", path.display()))?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let builder = fmt()
+        .with_env_filter(default_filter())
+        .with_writer(writer)
+        .with_ansi(file.is_none());
+    let _ = match format {
+        LogFormat::Text => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = SET_FILTER.set(Box::new(move |filter| Ok(handle.reload(filter)?)));
+            tracing::subscriber::set_global_default(builder.finish())
+        }
+        LogFormat::Json => {
+            let builder = builder.json().with_span_list(true).with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = SET_FILTER.set(Box::new(move |filter| Ok(handle.reload(filter)?)));
+            tracing::subscriber::set_global_default(builder.finish())
+        }
+    };
+    Ok(())
+}
+
+/// Swaps the filter of the subscriber [`init_logging`] installed.
+type SetFilter = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+static SET_FILTER: OnceLock<SetFilter> = OnceLock::new();
+
+/// `RUST_LOG`, or `info` without it.
+fn default_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Log what `directives` select from now on, like `debug` or
+/// `info,ghostwriter_server=trace`; `None` goes back to the default. Does
+/// nothing before [`init_logging`].
+pub fn set_log_level(directives: Option<&str>) -> Result<()> {
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives)
+            .map_err(|e| anyhow!("invalid log level {directives:?}: {e}"))?,
+        None => default_filter(),
+    };
+    match SET_FILTER.get() {
+        Some(set) => set(filter),
+        None => Ok(()),
+    }
 }
 
-pub async fn run() -> Result<()> {
-    run_with_args(Args::parse()).await.map(|_| ())
+/// Parse the command line and run it. With `--daemon` the process detaches
+/// before the async runtime starts, since forking a threaded process is
+/// unsafe; `--sandbox` is entered then too, so it covers every thread.
+pub fn run() -> Result<()> {
+    let args = Args::parse();
+    init_logging(
+        args.log_format,
+        args.log_file.as_deref(),
+        args.log_rotation()?,
+    )?;
+    let _pid_file = if args.daemon {
+        daemonize(args.pid_file.as_deref())?
+    } else {
+        None
+    };
+    #[cfg(target_os = "linux")]
+    if args.sandbox {
+        crate::server::enter_sandbox(&args.mode()?, &args)?;
+    }
+    tokio::runtime::Runtime::new()?
+        .block_on(run_with_args(args))
+        .map(|_| ())
+}
+
+#[cfg(unix)]
+fn daemonize(pid_file: Option<&Path>) -> Result<Option<ghostwriter_server::daemon::PidFile>> {
+    use ghostwriter_server::daemon;
+
+    daemon::daemonize()?;
+    let pid_file = pid_file.map(daemon::PidFile::create).transpose();
+    if let Err(e) = &pid_file {
+        tracing::error!("cannot write PID file: {e}");
+    }
+    Ok(pid_file?)
+}
+
+#[cfg(not(unix))]
+fn daemonize(_pid_file: Option<&Path>) -> Result<Option<()>> {
+    Err(anyhow!("--daemon is only supported on Unix"))
 }
 
 async fn run_with_args(args: Args) -> Result<&'static str> {
-    init_logging();
-    let output = dispatch(args.mode()?, args.secret);
+    let mode = args.mode()?;
+    let secret = read_secret(&args, io::stdin().lock())?;
+    if mode == Mode::ProtoSchema {
+        println!("{}", proto_schema()?);
+        return Ok("proto-schema");
+    }
+    if mode == Mode::DumpKeys {
+        print!("{}", load_keymap(args.config.as_deref())?);
+        return Ok("dump-keys");
+    }
+    if let Mode::Token { store, action } = &mode {
+        print!("{}", manage_tokens(store, action)?);
+        return Ok("token");
+    }
+    if let Mode::Connect {
+        url,
+        follow,
+        attach,
+        ca,
+        identity,
+        token_id,
+    } = mode
+    {
+        tracing::info!(follow, ?attach, "mode = connect");
+        let secret = match secret {
+            Some(secret) if args.remember_secret => {
+                ghostwriter_client::secret::store(&url, &secret)?;
+                Some(secret)
+            }
+            Some(secret) => Some(secret),
+            None => ghostwriter_client::secret::load(&url).unwrap_or_else(|e| {
+                tracing::debug!("no secret from the OS keyring: {e:#}");
+                None
+            }),
+        };
+        let options = ghostwriter_client::app::ConnectOptions {
+            url,
+            follow,
+            attach,
+            auth: secret.map(|secret| Auth { secret, token_id }),
+            ca,
+            identity: identity.map(|files| (files.cert, files.key)),
+            keymap: load_keymap(args.config.as_deref())?,
+            status_layout: load_status_layout(args.config.as_deref())?,
+            render_budget: args.render_budget,
+            mouse: args.mouse,
+        };
+        ghostwriter_client::app::run(options).await?;
+        return Ok("client");
+    }
+    if let Mode::Server { .. } = mode {
+        crate::server::run(mode, &args, secret).await?;
+        return Ok("server");
+    }
+    let output = dispatch(mode, secret);
     println!("{output}");
     Ok(output)
 }
 
+/// The secret from `--secret`, the first line of `stdin` or a prompt, as
+/// the flags say.
+fn read_secret(args: &Args, mut stdin: impl BufRead) -> Result<Option<String>> {
+    if args.secret_stdin {
+        let mut line = String::new();
+        stdin.read_line(&mut line)?;
+        let secret = line.trim_end_matches(['\r', '\n']);
+        if secret.is_empty() {
+            return Err(anyhow!("no secret on standard input"));
+        }
+        return Ok(Some(secret.to_string()));
+    }
+    if args.secret_prompt {
+        return ghostwriter_client::secret::prompt("Secret: ").map(Some);
+    }
+    Ok(args.secret.clone())
+}
+
+/// Run a `token` subcommand, returning what to print.
+fn manage_tokens(store: &Path, action: &TokenAction) -> Result<String> {
+    let tokens = ghostwriter_server::auth::TokenStore::new(store);
+    match action {
+        TokenAction::Issue { id, expires_in } => {
+            Ok(format!("{}\n", tokens.issue(id, *expires_in)?))
+        }
+        TokenAction::Revoke { id } => {
+            if !tokens.revoke(id)? {
+                return Err(anyhow!("no token named {id}"));
+            }
+            Ok(String::new())
+        }
+        TokenAction::List => {
+            let mut out = String::new();
+            for token in tokens.list()? {
+                let expires = match token.expires {
+                    Some(at) => {
+                        let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                        format!("expires {secs}")
+                    }
+                    None => "never expires".to_string(),
+                };
+                out += &format!("{} {expires}\n", token.id);
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+fn proto_schema() -> Result<String> {
+    Ok(format!(
+        "{:#}",
+        ghostwriter_proto::schema::protocol_schema()
+    ))
+}
+
+#[cfg(not(feature = "schema"))]
+fn proto_schema() -> Result<String> {
+    Err(anyhow!(
+        "protocol schemas are not available; rebuild with `--features schema`"
+    ))
+}
+
 fn dispatch(mode: Mode, _secret: Option<String>) -> &'static str {
     match mode {
         Mode::Local => {
             tracing::info!("mode = local");
             ghostwriter_client::run()
         }
-        Mode::Server { .. } => {
-            tracing::info!("mode = server");
-            ghostwriter_server::run()
-        }
-        Mode::Connect { .. } => {
-            tracing::info!("mode = connect");
+        Mode::Server { .. } => "server",
+        Mode::Connect { follow, attach, .. } => {
+            tracing::info!(follow, ?attach, "mode = connect");
             ghostwriter_client::run()
         }
+        Mode::DumpKeys => "dump-keys",
+        Mode::ProtoSchema => "proto-schema",
+        Mode::Token { .. } => "token",
     }
 }
 
@@ -99,7 +923,28 @@ mod tests {
         assert_eq!(
             parse_mode(&["--server", "/tmp"]),
             Mode::Server {
-                root: PathBuf::from("/tmp")
+                root: PathBuf::from("/tmp"),
+                tls: None,
+                client_ca: None,
+                tokens: None,
+                access: AccessList::default(),
+                connect_limit: None,
+                auth_limit: None,
+                file_audit_log: None,
+                metrics_addr: None,
+                limits: WriteLimits::default(),
+                readonly: false,
+                sandbox: false,
+                user: None,
+                group: None,
+                workspaces: Vec::new(),
+                workspace_keys: Vec::new(),
+                readonly_workspaces: Vec::new(),
+                config: None,
+                mdns: false,
+                bind: vec![DEFAULT_BIND],
+                quic_bind: None,
+                ssh_socket: false,
             }
         );
     }
@@ -109,9 +954,669 @@ mod tests {
         assert_eq!(
             parse_mode(&["--connect", "ws://localhost"]),
             Mode::Connect {
-                url: "ws://localhost".into()
+                url: "ws://localhost".into(),
+                follow: false,
+                attach: None,
+                ca: None,
+                identity: None,
+                token_id: None,
+            }
+        );
+        assert_eq!(
+            parse_mode(&["--connect", "ws://localhost", "--follow"]),
+            Mode::Connect {
+                url: "ws://localhost".into(),
+                follow: true,
+                attach: None,
+                ca: None,
+                identity: None,
+                token_id: None,
+            }
+        );
+        assert!(Args::try_parse_from(["ghostwriter", "--follow"]).is_err());
+        assert_eq!(
+            parse_mode(&["--connect", "ws://localhost", "--attach", "3"]),
+            Mode::Connect {
+                url: "ws://localhost".into(),
+                follow: false,
+                attach: Some(3),
+                ca: None,
+                identity: None,
+                token_id: None,
+            }
+        );
+        let args = [
+            "ghostwriter",
+            "--connect",
+            "ws://h",
+            "--follow",
+            "--attach",
+            "3",
+        ];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn parses_render_budget() {
+        let args = ["ghostwriter", "--connect", "ws://h", "--render-budget", "4"];
+        assert_eq!(Args::try_parse_from(args).unwrap().render_budget, Some(4));
+        let args = ["ghostwriter", "--connect", "ws://h", "--render-budget", "0"];
+        assert!(Args::try_parse_from(args).is_err());
+        assert!(Args::try_parse_from(["ghostwriter", "--render-budget", "4"]).is_err());
+    }
+
+    #[test]
+    fn parses_tls_options() {
+        assert_eq!(
+            parse_mode(&[
+                "--server",
+                "/tmp",
+                "--tls-cert",
+                "c.pem",
+                "--tls-key",
+                "k.pem"
+            ]),
+            Mode::Server {
+                root: PathBuf::from("/tmp"),
+                tls: Some(TlsFiles {
+                    cert: PathBuf::from("c.pem"),
+                    key: PathBuf::from("k.pem"),
+                }),
+                client_ca: None,
+                tokens: None,
+                access: AccessList::default(),
+                connect_limit: None,
+                auth_limit: None,
+                file_audit_log: None,
+                metrics_addr: None,
+                limits: WriteLimits::default(),
+                readonly: false,
+                sandbox: false,
+                user: None,
+                group: None,
+                workspaces: Vec::new(),
+                workspace_keys: Vec::new(),
+                readonly_workspaces: Vec::new(),
+                config: None,
+                mdns: false,
+                bind: vec![DEFAULT_BIND],
+                quic_bind: None,
+                ssh_socket: false,
+            }
+        );
+        let cert_only = ["ghostwriter", "--server", "/tmp", "--tls-cert", "c.pem"];
+        assert!(Args::try_parse_from(cert_only).is_err());
+        let no_server = ["ghostwriter", "--tls-cert", "c.pem", "--tls-key", "k.pem"];
+        assert!(Args::try_parse_from(no_server).is_err());
+
+        assert_eq!(
+            parse_mode(&["--connect", "wss://h", "--tls-ca", "ca.pem"]),
+            Mode::Connect {
+                url: "wss://h".into(),
+                follow: false,
+                attach: None,
+                ca: Some(PathBuf::from("ca.pem")),
+                identity: None,
+                token_id: None,
+            }
+        );
+        let cli = Args::parse_from(["ghostwriter", "--connect", "ws://h", "--tls-ca", "ca.pem"]);
+        assert!(cli.mode().is_err());
+
+        let mode = parse_mode(&[
+            "--server",
+            "/tmp",
+            "--tls-cert",
+            "c.pem",
+            "--tls-key",
+            "k.pem",
+            "--tls-client-ca",
+            "ca.pem",
+        ]);
+        let Mode::Server { client_ca, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(client_ca, Some(PathBuf::from("ca.pem")));
+        let plain_server = [
+            "ghostwriter",
+            "--server",
+            "/tmp",
+            "--tls-client-ca",
+            "ca.pem",
+        ];
+        assert!(Args::try_parse_from(plain_server).is_err());
+
+        let mode = parse_mode(&[
+            "--connect",
+            "wss://h",
+            "--client-cert",
+            "me.pem",
+            "--client-key",
+            "me.key",
+        ]);
+        let Mode::Connect { identity, .. } = mode else {
+            panic!("expected connect mode");
+        };
+        let identity = identity.unwrap();
+        assert_eq!(identity.cert, PathBuf::from("me.pem"));
+        assert_eq!(identity.key, PathBuf::from("me.key"));
+        let no_key = [
+            "ghostwriter",
+            "--connect",
+            "wss://h",
+            "--client-cert",
+            "me.pem",
+        ];
+        assert!(Args::try_parse_from(no_key).is_err());
+    }
+
+    #[test]
+    fn parses_access_lists() {
+        let mode = parse_mode(&[
+            "--server",
+            "/tmp",
+            "--allow",
+            "192.168.1.0/24",
+            "--allow",
+            "10.0.0.1",
+            "--deny",
+            "192.168.1.13",
+        ]);
+        let Mode::Server { access, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(access.allow.len(), 2);
+        assert_eq!(access.deny, vec!["192.168.1.13".parse().unwrap()]);
+        let bad = ["ghostwriter", "--server", "/tmp", "--allow", "lan"];
+        assert!(Args::try_parse_from(bad).is_err());
+        let no_server = ["ghostwriter", "--deny", "10.0.0.0/8"];
+        assert!(Args::try_parse_from(no_server).is_err());
+    }
+
+    #[test]
+    fn parses_rate_limits() {
+        let mode = parse_mode(&["--server", "/tmp", "--auth-limit", "10/1h"]);
+        let Mode::Server {
+            connect_limit,
+            auth_limit,
+            ..
+        } = mode
+        else {
+            panic!("expected server mode");
+        };
+        assert_eq!(connect_limit, None);
+        assert_eq!(
+            auth_limit,
+            Some(RateLimit::new(10, Duration::from_secs(60 * 60)))
+        );
+        for bad in ["10", "x/1h", "10/1"] {
+            let args = ["ghostwriter", "--server", "/tmp", "--connect-limit", bad];
+            assert!(Args::try_parse_from(args).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_file_audit_log() {
+        let mode = parse_mode(&["--server", "/tmp", "--file-audit-log", "files.jsonl"]);
+        let Mode::Server { file_audit_log, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(file_audit_log, Some(PathBuf::from("files.jsonl")));
+        let no_server = ["ghostwriter", "--file-audit-log", "files.jsonl"];
+        assert!(Args::try_parse_from(no_server).is_err());
+    }
+
+    #[test]
+    fn parses_metrics_addr() {
+        let mode = parse_mode(&["--server", "/tmp", "--metrics-addr", "127.0.0.1:9184"]);
+        let Mode::Server { metrics_addr, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(metrics_addr, Some("127.0.0.1:9184".parse().unwrap()));
+        let no_port = [
+            "ghostwriter",
+            "--server",
+            "/tmp",
+            "--metrics-addr",
+            "localhost",
+        ];
+        assert!(Args::try_parse_from(no_port).is_err());
+    }
+
+    #[test]
+    fn parses_log_format() {
+        let cli = Args::parse_from(["ghostwriter"]);
+        assert_eq!(cli.log_format, LogFormat::Text);
+        let cli = Args::parse_from(["ghostwriter", "--log-format", "json"]);
+        assert_eq!(cli.log_format, LogFormat::Json);
+        assert!(Args::try_parse_from(["ghostwriter", "--log-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn parses_write_limits() {
+        let mode = parse_mode(&[
+            "--server",
+            "/tmp",
+            "--max-file-size",
+            "10M",
+            "--write-quota",
+            "512k",
+            "--workspace-cap",
+            "2G",
+        ]);
+        let Mode::Server { limits, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(
+            limits,
+            WriteLimits {
+                max_file_size: Some(10 << 20),
+                connection_quota: Some(512 << 10),
+                workspace_cap: Some(2 << 30),
+            }
+        );
+        for bad in ["ten", "10T", "-1", "99999999999G"] {
+            let args = ["ghostwriter", "--server", "/tmp", "--max-file-size", bad];
+            assert!(Args::try_parse_from(args).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_readonly() {
+        let mode = parse_mode(&["--server", "/tmp", "--readonly"]);
+        assert!(matches!(mode, Mode::Server { readonly: true, .. }));
+        let no_server = ["ghostwriter", "--readonly"];
+        assert!(Args::try_parse_from(no_server).is_err());
+    }
+
+    #[test]
+    fn parses_sandbox() {
+        let args = ["ghostwriter", "--server", "/tmp", "--sandbox"];
+        let mode = Args::parse_from(args).mode();
+        if cfg!(target_os = "linux") {
+            assert!(matches!(mode, Ok(Mode::Server { sandbox: true, .. })));
+        } else {
+            assert!(mode.is_err());
+        }
+        let no_server = ["ghostwriter", "--sandbox"];
+        assert!(Args::try_parse_from(no_server).is_err());
+    }
+
+    #[test]
+    fn parses_user_and_group() {
+        let mode = parse_mode(&["--server", "/tmp", "--user", "nobody", "--group", "65534"]);
+        let Mode::Server { user, group, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(user.as_deref(), Some("nobody"));
+        assert_eq!(group.as_deref(), Some("65534"));
+        let no_server = ["ghostwriter", "--user", "nobody"];
+        assert!(Args::try_parse_from(no_server).is_err());
+    }
+
+    #[test]
+    fn parses_daemon_options() {
+        let args = [
+            "ghostwriter",
+            "--server",
+            "/srv",
+            "--daemon",
+            "--pid-file",
+            "/run/ghostwriter.pid",
+            "--log-file",
+            "/var/log/ghostwriter.log",
+        ];
+        let cli = Args::parse_from(args);
+        assert!(cli.daemon);
+        assert_eq!(cli.pid_file, Some(PathBuf::from("/run/ghostwriter.pid")));
+        assert_eq!(
+            cli.log_file,
+            Some(PathBuf::from("/var/log/ghostwriter.log"))
+        );
+        let no_server = ["ghostwriter", "--daemon"];
+        assert!(Args::try_parse_from(no_server).is_err());
+        let no_daemon = ["ghostwriter", "--server", "/srv", "--pid-file", "x.pid"];
+        assert!(Args::try_parse_from(no_daemon).is_err());
+        // Detached from the terminal, its logs would go nowhere.
+        let no_log = ["ghostwriter", "--server", "/srv", "--daemon"];
+        assert!(Args::try_parse_from(no_log).is_err());
+    }
+
+    #[test]
+    fn parses_named_workspaces() {
+        let mode = parse_mode(&[
+            "--server",
+            "/home/me/projects/a",
+            "--workspace",
+            "b=/home/me/projects/b",
+        ]);
+        let Mode::Server { workspaces, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(
+            workspaces,
+            [("b".to_string(), PathBuf::from("/home/me/projects/b"))]
+        );
+        for bad in ["b", "=/srv", "b=", "a/b=/srv"] {
+            let args = ["ghostwriter", "--server", "/tmp", "--workspace", bad];
+            assert!(Args::try_parse_from(args).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_workspace_access() {
+        let mode = parse_mode(&[
+            "--server",
+            "/srv/a",
+            "--workspace",
+            "b=/srv/b",
+            "--workspace-key",
+            "b=/etc/b.hash",
+            "--workspace-readonly",
+            "a",
+        ]);
+        let Mode::Server {
+            workspace_keys,
+            readonly_workspaces,
+            ..
+        } = mode
+        else {
+            panic!("expected server mode");
+        };
+        assert_eq!(
+            workspace_keys,
+            [("b".to_string(), PathBuf::from("/etc/b.hash"))]
+        );
+        assert_eq!(readonly_workspaces, ["a"]);
+        let no_server = ["ghostwriter", "--workspace-readonly", "a"];
+        assert!(Args::try_parse_from(no_server).is_err());
+        let args = ["ghostwriter", "--server", "/tmp", "--workspace-key", "b"];
+        assert!(Args::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn parses_undo_history() {
+        let server = |flags: &[&str]| {
+            let args = ["ghostwriter", "--server", "/tmp"].iter().chain(flags);
+            Args::parse_from(args).undo_history().unwrap()
+        };
+        let history = server(&["--undo-history-size", "64K"]);
+        assert_eq!(history.max_size, 64 << 10);
+        assert_eq!(server(&[]).max_size, DEFAULT_UNDO_HISTORY_SIZE);
+        assert_eq!(server(&["--no-undo-history"]), UndoHistory::default());
+        let both = ["--no-undo-history", "--undo-history-size", "1M"];
+        let args = ["ghostwriter", "--server", "/tmp"].iter().chain(&both);
+        assert!(Args::try_parse_from(args).is_err());
+        assert!(Args::try_parse_from(["ghostwriter", "--no-undo-history"]).is_err());
+    }
+
+    #[test]
+    fn loads_snippets() {
+        let dir = tempfile::tempdir().unwrap();
+        let snippets = dir.path().join("snippets");
+        std::fs::create_dir(&snippets).unwrap();
+        std::fs::write(snippets.join("md.toml"), "h = \"# $1\"\n").unwrap();
+        let config = dir.path().join("config.toml");
+        std::fs::write(&config, "snippets = \"nowhere\"\n").unwrap();
+        let server = |flags: &[&str]| {
+            let args = ["ghostwriter", "--server", "/tmp"].iter().chain(flags);
+            Args::parse_from(args).snippets().unwrap()
+        };
+        let flag = snippets.to_str().unwrap();
+        let loaded = server(&["--snippets", flag]);
+        assert!(loaded.get(Path::new("a.md"), "h").is_some());
+        // The config file wins, and a directory that is not there has none.
+        let config = config.to_str().unwrap();
+        assert!(server(&["--snippets", flag, "--config", config]).is_empty());
+        std::fs::write(snippets.join("md.toml"), "h = 1\n").unwrap();
+        let args = ["ghostwriter", "--server", "/tmp", "--snippets", flag];
+        assert!(Args::parse_from(args).snippets().is_err());
+    }
+
+    #[test]
+    fn parses_log_rotation() {
+        let args = [
+            "ghostwriter",
+            "--log-file",
+            "server.log",
+            "--log-max-size",
+            "10M",
+            "--log-keep",
+            "3",
+        ];
+        let rotation = Args::parse_from(args).log_rotation().unwrap();
+        assert_eq!(rotation.max_size, Some(10 << 20));
+        assert_eq!(rotation.keep, 3);
+        let defaults = Args::parse_from(["ghostwriter"]).log_rotation().unwrap();
+        assert_eq!(defaults, LogRotation::default());
+        let no_file = ["ghostwriter", "--log-max-size", "10M"];
+        assert!(Args::try_parse_from(no_file).is_err());
+    }
+
+    #[test]
+    fn parses_config_file() {
+        let mode = parse_mode(&["--server", "/tmp", "--config", "/etc/ghostwriter.toml"]);
+        let settings = crate::config::flag_settings(&mode).unwrap();
+        assert_eq!(
+            settings.connect_limit,
+            ghostwriter_server::acceptor::DEFAULT_CONNECT_LIMIT
+        );
+        let Mode::Server { config, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(config, Some(PathBuf::from("/etc/ghostwriter.toml")));
+        let no_server = ["ghostwriter", "--config", "x.toml"];
+        assert!(Args::try_parse_from(no_server).is_err());
+        let connect = ["ghostwriter", "--connect", "ws://h", "--config", "x.toml"];
+        assert!(Args::try_parse_from(connect).is_ok());
+        assert_eq!(
+            parse_mode(&["--dump-keys", "--config", "x.toml"]),
+            Mode::DumpKeys
+        );
+        let server = ["ghostwriter", "--server", "/tmp", "--dump-keys"];
+        assert!(Args::try_parse_from(server).is_err());
+    }
+
+    #[test]
+    fn parses_token_options() {
+        let mode = parse_mode(&["--server", "/tmp", "--tokens", "tokens"]);
+        let Mode::Server { tokens, .. } = mode else {
+            panic!("expected server mode");
+        };
+        assert_eq!(tokens, Some(PathBuf::from("tokens")));
+        let mode = parse_mode(&["--connect", "ws://h", "--token-id", "ci", "--secret", "s"]);
+        let Mode::Connect { token_id, .. } = mode else {
+            panic!("expected connect mode");
+        };
+        assert_eq!(token_id.as_deref(), Some("ci"));
+        let no_secret = ["ghostwriter", "--connect", "ws://h", "--token-id", "ci"];
+        assert!(Args::try_parse_from(no_secret).is_err());
+        let prompted = ["--connect", "ws://h", "--token-id", "ci", "--secret-prompt"];
+        assert!(Args::try_parse_from(std::iter::once("ghostwriter").chain(prompted)).is_ok());
+
+        assert_eq!(
+            parse_mode(&[
+                "token",
+                "--store",
+                "t",
+                "issue",
+                "ci",
+                "--expires-in",
+                "12h"
+            ]),
+            Mode::Token {
+                store: PathBuf::from("t"),
+                action: TokenAction::Issue {
+                    id: "ci".into(),
+                    expires_in: Some(Duration::from_secs(12 * 60 * 60)),
+                },
             }
         );
+        let bad_duration = [
+            "ghostwriter",
+            "token",
+            "--store",
+            "t",
+            "issue",
+            "ci",
+            "--expires-in",
+            "12",
+        ];
+        assert!(Args::try_parse_from(bad_duration).is_err());
+    }
+
+    #[test]
+    fn manages_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("tokens");
+        let issue = TokenAction::Issue {
+            id: "ci".into(),
+            expires_in: None,
+        };
+        let secret = manage_tokens(&store, &issue).unwrap();
+        assert_eq!(secret.trim().len(), 32);
+        assert!(manage_tokens(&store, &issue).is_err());
+        let list = manage_tokens(&store, &TokenAction::List).unwrap();
+        assert_eq!(list, "ci never expires\n");
+        let revoke = TokenAction::Revoke { id: "ci".into() };
+        manage_tokens(&store, &revoke).unwrap();
+        assert!(manage_tokens(&store, &revoke).is_err());
+        assert_eq!(manage_tokens(&store, &TokenAction::List).unwrap(), "");
+    }
+
+    #[test]
+    fn checks_connect_scheme() {
+        let cli = Args::parse_from(["ghostwriter", "--connect", "quic://localhost:4433"]);
+        assert_eq!(cli.mode().is_ok(), cfg!(feature = "quic"));
+        let cli = Args::parse_from(["ghostwriter", "--connect", "ssh://host/srv/app"]);
+        assert!(cli.mode().is_ok());
+        let cli = Args::parse_from(["ghostwriter", "--connect", "http://localhost"]);
+        assert!(cli.mode().is_err());
+        let cli = Args::parse_from(["ghostwriter", "--connect", "auto"]);
+        assert_eq!(cli.mode().is_ok(), cfg!(feature = "mdns"));
+    }
+
+    #[test]
+    fn reads_the_secret_from_stdin() {
+        let args = Args::parse_from(["ghostwriter", "--connect", "ws://h", "--secret-stdin"]);
+        let secret = read_secret(&args, &b"s3cret\r\nrest\n"[..]).unwrap();
+        assert_eq!(secret.as_deref(), Some("s3cret"));
+        assert!(read_secret(&args, &b""[..]).is_err());
+        let args = Args::parse_from(["ghostwriter", "--connect", "ws://h", "--secret", "s"]);
+        assert_eq!(
+            read_secret(&args, &b"x\n"[..]).unwrap().as_deref(),
+            Some("s")
+        );
+
+        let both = ["ghostwriter", "--secret", "s", "--secret-stdin"];
+        assert!(Args::try_parse_from(both).is_err());
+        let remember = ["ghostwriter", "--connect", "ws://h", "--remember-secret"];
+        assert!(Args::try_parse_from(remember).is_err());
+    }
+
+    #[test]
+    fn parses_bind_addresses() {
+        let cli = Args::parse_from([
+            "ghostwriter",
+            "--server",
+            "/tmp",
+            "--bind",
+            "0.0.0.0",
+            "--bind",
+            "::",
+            "--bind",
+            "[::1]",
+            "--bind",
+            "[::1]:9000",
+            "--bind",
+            "10.0.0.1:81",
+        ]);
+        let Mode::Server { bind, .. } = cli.mode().unwrap() else {
+            panic!("expected server mode");
+        };
+        let bind: Vec<String> = bind.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            bind,
+            [
+                "0.0.0.0:8080",
+                "[::]:8080",
+                "[::1]:8080",
+                "[::1]:9000",
+                "10.0.0.1:81"
+            ]
+        );
+        for bad in ["[::1", "::1:9000x", "localhost", "[10.0.0.1]:80"] {
+            let args = ["ghostwriter", "--server", "/tmp", "--bind", bad];
+            assert!(Args::try_parse_from(args).is_err(), "{bad}");
+        }
+        assert!(Args::try_parse_from(["ghostwriter", "--bind", "::"]).is_err());
+    }
+
+    #[test]
+    fn parses_mdns() {
+        let cli = Args::parse_from(["ghostwriter", "--server", "/tmp", "--mdns"]);
+        let mode = cli.mode();
+        assert_eq!(mode.is_ok(), cfg!(feature = "mdns"));
+        if let Ok(mode) = mode {
+            assert!(matches!(mode, Mode::Server { mdns: true, .. }));
+        }
+        assert!(Args::try_parse_from(["ghostwriter", "--mdns"]).is_err());
+    }
+
+    #[test]
+    fn parses_quic_bind() {
+        let tls = [
+            "--server",
+            "/tmp",
+            "--tls-cert",
+            "c.pem",
+            "--tls-key",
+            "k.pem",
+        ];
+        let cli = Args::parse_from(
+            ["ghostwriter"]
+                .iter()
+                .chain(&tls)
+                .chain(&["--quic-bind", "[::]:4433"]),
+        );
+        let mode = cli.mode();
+        assert_eq!(mode.is_ok(), cfg!(feature = "quic"));
+        if let Ok(Mode::Server { quic_bind, .. }) = mode {
+            assert_eq!(quic_bind, Some("[::]:4433".parse().unwrap()));
+        }
+        let plain = [
+            "ghostwriter",
+            "--server",
+            "/tmp",
+            "--quic-bind",
+            "[::]:4433",
+        ];
+        assert!(Args::try_parse_from(plain).is_err());
+        let mutual = ["--tls-client-ca", "ca.pem", "--quic-bind", "[::]:4433"];
+        let mutual = ["ghostwriter"].iter().chain(&tls).chain(&mutual);
+        assert!(Args::try_parse_from(mutual).is_err());
+    }
+
+    #[test]
+    fn parses_ssh_socket() {
+        let cli = Args::parse_from(["ghostwriter", "--server", "/tmp", "--ssh-socket"]);
+        let mode = cli.mode();
+        assert_eq!(mode.is_ok(), cfg!(unix));
+        if let Ok(mode) = mode {
+            assert!(matches!(
+                mode,
+                Mode::Server {
+                    ssh_socket: true,
+                    ..
+                }
+            ));
+        }
+        assert!(Args::try_parse_from(["ghostwriter", "--ssh-socket"]).is_err());
+    }
+
+    #[test]
+    fn parses_proto_schema() {
+        assert_eq!(parse_mode(&["proto-schema"]), Mode::ProtoSchema);
     }
 
     #[test]
@@ -119,7 +1624,54 @@ mod tests {
         let args = Args {
             server: Some(PathBuf::from("/tmp")),
             connect: Some("ws://localhost".into()),
+            follow: false,
+            attach: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            quic_bind: None,
+            ssh_socket: false,
+            tls_ca: None,
+            client_cert: None,
+            client_key: None,
+            tokens: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            connect_limit: None,
+            auth_limit: None,
+            file_audit_log: None,
+            metrics_addr: None,
+            max_file_size: None,
+            write_quota: None,
+            workspace_cap: None,
+            readonly: false,
+            undo_history_size: None,
+            no_undo_history: false,
+            snippets: None,
+            sandbox: false,
+            user: None,
+            group: None,
+            workspaces: Vec::new(),
+            workspace_keys: Vec::new(),
+            readonly_workspaces: Vec::new(),
+            config: None,
+            dump_keys: false,
+            render_budget: None,
+            mouse: false,
+            mdns: false,
+            bind: Vec::new(),
+            token_id: None,
             secret: None,
+            secret_stdin: false,
+            secret_prompt: false,
+            remember_secret: false,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_max_size: None,
+            log_keep: None,
+            daemon: false,
+            pid_file: None,
+            command: None,
         };
         assert!(args.mode().is_err());
     }
@@ -134,7 +1686,28 @@ mod tests {
         assert_eq!(
             dispatch(
                 Mode::Server {
-                    root: PathBuf::from("/tmp")
+                    root: PathBuf::from("/tmp"),
+                    tls: None,
+                    client_ca: None,
+                    tokens: None,
+                    access: AccessList::default(),
+                    connect_limit: None,
+                    auth_limit: None,
+                    file_audit_log: None,
+                    metrics_addr: None,
+                    limits: WriteLimits::default(),
+                    readonly: false,
+                    sandbox: false,
+                    user: None,
+                    group: None,
+                    workspaces: Vec::new(),
+                    workspace_keys: Vec::new(),
+                    readonly_workspaces: Vec::new(),
+                    config: None,
+                    mdns: false,
+                    bind: vec![DEFAULT_BIND],
+                    quic_bind: None,
+                    ssh_socket: false,
                 },
                 None
             ),
@@ -147,7 +1720,12 @@ mod tests {
         assert_eq!(
             dispatch(
                 Mode::Connect {
-                    url: "ws://localhost".into()
+                    url: "ws://localhost".into(),
+                    follow: true,
+                    attach: None,
+                    ca: None,
+                    identity: None,
+                    token_id: None,
                 },
                 None
             ),
@@ -161,34 +1739,173 @@ mod tests {
             run_args(Args {
                 server: None,
                 connect: None,
+                follow: false,
+                attach: None,
+                tls_cert: None,
+                tls_key: None,
+                tls_client_ca: None,
+                quic_bind: None,
+                ssh_socket: false,
+                tls_ca: None,
+                client_cert: None,
+                client_key: None,
+                tokens: None,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                connect_limit: None,
+                auth_limit: None,
+                file_audit_log: None,
+                metrics_addr: None,
+                max_file_size: None,
+                write_quota: None,
+                workspace_cap: None,
+                readonly: false,
+                undo_history_size: None,
+                no_undo_history: false,
+                snippets: None,
+                sandbox: false,
+                user: None,
+                group: None,
+                workspaces: Vec::new(),
+                workspace_keys: Vec::new(),
+                readonly_workspaces: Vec::new(),
+                config: None,
+                dump_keys: false,
+                render_budget: None,
+                mouse: false,
+                mdns: false,
+                bind: Vec::new(),
+                token_id: None,
                 secret: None,
+                secret_stdin: false,
+                secret_prompt: false,
+                remember_secret: false,
+                log_format: LogFormat::Text,
+                log_file: None,
+                log_max_size: None,
+                log_keep: None,
+                daemon: false,
+                pid_file: None,
+                command: None,
             }),
             "client"
         );
     }
 
     #[test]
-    fn run_with_args_server() {
-        assert_eq!(
-            run_args(Args {
-                server: Some(PathBuf::from("/tmp")),
-                connect: None,
-                secret: None,
-            }),
-            "server"
-        );
+    fn run_with_args_server_fails_without_the_workspace() {
+        let args = Args {
+            server: Some(PathBuf::from("/nonexistent/ghostwriter")),
+            connect: None,
+            follow: false,
+            attach: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            quic_bind: None,
+            ssh_socket: false,
+            tls_ca: None,
+            client_cert: None,
+            client_key: None,
+            tokens: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            connect_limit: None,
+            auth_limit: None,
+            file_audit_log: None,
+            metrics_addr: None,
+            max_file_size: None,
+            write_quota: None,
+            workspace_cap: None,
+            readonly: false,
+            undo_history_size: None,
+            no_undo_history: false,
+            snippets: None,
+            sandbox: false,
+            user: None,
+            group: None,
+            workspaces: Vec::new(),
+            workspace_keys: Vec::new(),
+            readonly_workspaces: Vec::new(),
+            config: None,
+            dump_keys: false,
+            render_budget: None,
+            mouse: false,
+            mdns: false,
+            bind: Vec::new(),
+            token_id: None,
+            secret: None,
+            secret_stdin: false,
+            secret_prompt: false,
+            remember_secret: false,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_max_size: None,
+            log_keep: None,
+            daemon: false,
+            pid_file: None,
+            command: None,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime.block_on(run_with_args(args)).is_err());
     }
 
     #[test]
-    fn run_with_args_connect() {
-        assert_eq!(
-            run_args(Args {
-                server: None,
-                connect: Some("ws://localhost".into()),
-                secret: None,
-            }),
-            "client"
-        );
+    fn run_with_args_connect_fails_before_the_terminal() {
+        let args = Args {
+            server: None,
+            connect: Some("quic://localhost:1".into()),
+            follow: true,
+            attach: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            quic_bind: None,
+            ssh_socket: false,
+            tls_ca: None,
+            client_cert: None,
+            client_key: None,
+            tokens: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            connect_limit: None,
+            auth_limit: None,
+            file_audit_log: None,
+            metrics_addr: None,
+            max_file_size: None,
+            write_quota: None,
+            workspace_cap: None,
+            readonly: false,
+            undo_history_size: None,
+            no_undo_history: false,
+            snippets: None,
+            sandbox: false,
+            user: None,
+            group: None,
+            workspaces: Vec::new(),
+            workspace_keys: Vec::new(),
+            readonly_workspaces: Vec::new(),
+            config: None,
+            dump_keys: false,
+            render_budget: None,
+            mouse: false,
+            mdns: false,
+            bind: Vec::new(),
+            token_id: None,
+            secret: None,
+            secret_stdin: false,
+            secret_prompt: false,
+            remember_secret: false,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_max_size: None,
+            log_keep: None,
+            daemon: false,
+            pid_file: None,
+            command: None,
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime.block_on(run_with_args(args)).is_err());
     }
 
     #[test]
@@ -197,7 +1914,54 @@ mod tests {
             run_args(Args {
                 server: None,
                 connect: None,
+                follow: false,
+                attach: None,
+                tls_cert: None,
+                tls_key: None,
+                tls_client_ca: None,
+                quic_bind: None,
+                ssh_socket: false,
+                tls_ca: None,
+                client_cert: None,
+                client_key: None,
+                tokens: None,
+                allow: Vec::new(),
+                deny: Vec::new(),
+                connect_limit: None,
+                auth_limit: None,
+                file_audit_log: None,
+                metrics_addr: None,
+                max_file_size: None,
+                write_quota: None,
+                workspace_cap: None,
+                readonly: false,
+                undo_history_size: None,
+                no_undo_history: false,
+                snippets: None,
+                sandbox: false,
+                user: None,
+                group: None,
+                workspaces: Vec::new(),
+                workspace_keys: Vec::new(),
+                readonly_workspaces: Vec::new(),
+                config: None,
+                dump_keys: false,
+                render_budget: None,
+                mouse: false,
+                mdns: false,
+                bind: Vec::new(),
+                token_id: None,
                 secret: None,
+                secret_stdin: false,
+                secret_prompt: false,
+                remember_secret: false,
+                log_format: LogFormat::Text,
+                log_file: None,
+                log_max_size: None,
+                log_keep: None,
+                daemon: false,
+                pid_file: None,
+                command: None,
             }),
             "client",
         );
@@ -0,0 +1,407 @@
+//! The config file. It holds the settings a running server can change,
+//! and is read again on SIGHUP so new tokens, access lists, limits and log
+//! levels apply without dropping connected clients, and the client's key
+//! bindings and status bar.
+//!
+//! ```toml
+//! log-level = "info,ghostwriter_server=debug"
+//! tokens = "/etc/ghostwriter/tokens"
+//! allow = ["192.168.1.0/24"]
+//! deny = []
+//! connect-limit = "3/1m"
+//! auth-limit = "5/5m"
+//! max-file-size = "10M"
+//! write-quota = "100M"
+//! workspace-cap = "1G"
+//! log-max-size = "10M"
+//! log-keep = 5
+//! undo-history-size = "1M"
+//! snippets = "/etc/ghostwriter/snippets"
+//!
+//! [keys]
+//! quit = ["ctrl+w"]
+//! find-next = ["f3", "ctrl+n"]
+//!
+//! [status]
+//! left = "{path}{dirty}"
+//! right = "{conn}Ln {line}, Col {col}"
+//! ```
+//!
+//! Every key is optional; a missing one keeps the command line's value.
+//! Log rotation, undo history and snippets are only read at startup, and
+//! `undo-history = false` turns undo history off. The `[keys]` and
+//! `[status]` tables are for `--connect`; `--dump-keys` prints every action
+//! `[keys]` can bind, and `[status]` takes the templates of
+//! `StatusLayout`, each side defaulting to the built-in one.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use ghostwriter_client::keymap::Keymap;
+use ghostwriter_client::status::StatusLayout;
+use ghostwriter_server::acceptor::{DEFAULT_AUTH_LIMIT, DEFAULT_CONNECT_LIMIT, Reload};
+use ghostwriter_server::access::Cidr;
+use ghostwriter_server::history::DEFAULT_UNDO_HISTORY_SIZE;
+use serde::Deserialize;
+
+use crate::cli::{Mode, parse_rate_limit, parse_size, set_log_level};
+use crate::logfile::LogRotation;
+
+/// Contents of the config file, before its values are checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Log filter directives, like `debug`.
+    pub log_level: Option<String>,
+    pub tokens: Option<PathBuf>,
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub connect_limit: Option<String>,
+    pub auth_limit: Option<String>,
+    pub max_file_size: Option<String>,
+    pub write_quota: Option<String>,
+    pub workspace_cap: Option<String>,
+    pub log_max_size: Option<String>,
+    pub log_keep: Option<usize>,
+    /// Whether to keep the undo history of files between sessions.
+    pub undo_history: Option<bool>,
+    pub undo_history_size: Option<String>,
+    /// Directory of the snippets Tab expands.
+    pub snippets: Option<PathBuf>,
+    /// Keys to bind editor actions to, by action.
+    pub keys: Option<BTreeMap<String, Chords>>,
+    /// Templates of the client's status bar.
+    pub status: Option<StatusTemplates>,
+}
+
+/// The `[status]` table: templates for either side of the status bar.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusTemplates {
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// The keys bound to an action: one, like `"ctrl+s"`, or a list.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Chords {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("cannot read {}: {e}", path.display()))?;
+        toml::from_str(&text).map_err(|e| anyhow!("{}: {e}", path.display()))
+    }
+
+    /// `flags` with the settings this file gives replaced.
+    pub fn apply(&self, flags: &Reload) -> Result<Reload> {
+        let mut settings = flags.clone();
+        if let Some(tokens) = &self.tokens {
+            settings.tokens = Some(ghostwriter_server::auth::TokenStore::new(tokens));
+        }
+        if let Some(allow) = &self.allow {
+            settings.access.allow = parse_cidrs(allow)?;
+        }
+        if let Some(deny) = &self.deny {
+            settings.access.deny = parse_cidrs(deny)?;
+        }
+        if let Some(limit) = &self.connect_limit {
+            settings.connect_limit = parse_rate_limit(limit)?;
+        }
+        if let Some(limit) = &self.auth_limit {
+            settings.auth_limit = parse_rate_limit(limit)?;
+        }
+        let limits = &mut settings.write_limits;
+        if let Some(size) = &self.max_file_size {
+            limits.max_file_size = Some(parse_size(size)?);
+        }
+        if let Some(size) = &self.write_quota {
+            limits.connection_quota = Some(parse_size(size)?);
+        }
+        if let Some(size) = &self.workspace_cap {
+            limits.workspace_cap = Some(parse_size(size)?);
+        }
+        Ok(settings)
+    }
+
+    /// The default keymap with the `[keys]` this file gives.
+    pub fn keymap(&self) -> Result<Keymap> {
+        let Some(keys) = &self.keys else {
+            return Ok(Keymap::default());
+        };
+        let keys = keys
+            .iter()
+            .map(|(action, chords)| {
+                let chords = match chords {
+                    Chords::One(chord) => vec![chord.clone()],
+                    Chords::Many(chords) => chords.clone(),
+                };
+                (action.clone(), chords)
+            })
+            .collect();
+        Keymap::with_keys(&keys).map_err(|e| anyhow!("[keys]: {e}"))
+    }
+
+    /// The default status layout with the `[status]` templates this file
+    /// gives.
+    pub fn status_layout(&self) -> StatusLayout {
+        let mut layout = StatusLayout::default();
+        if let Some(status) = &self.status {
+            if let Some(left) = &status.left {
+                layout.left = left.clone();
+            }
+            if let Some(right) = &status.right {
+                layout.right = right.clone();
+            }
+        }
+        layout
+    }
+
+    /// `flags` with the log rotation this file gives replaced.
+    pub fn log_rotation(&self, flags: LogRotation) -> Result<LogRotation> {
+        let mut rotation = flags;
+        if let Some(size) = &self.log_max_size {
+            rotation.max_size = Some(parse_size(size)?);
+        }
+        if let Some(keep) = self.log_keep {
+            rotation.keep = keep;
+        }
+        Ok(rotation)
+    }
+
+    /// `flags`, the bytes of undo history kept per file or `None` for
+    /// none, with what this file gives replaced.
+    pub fn undo_history(&self, flags: Option<u64>) -> Result<Option<u64>> {
+        let mut size = flags;
+        if let Some(max) = &self.undo_history_size {
+            size = Some(parse_size(max)?);
+        }
+        Ok(match self.undo_history {
+            Some(false) => None,
+            Some(true) => size.or(Some(DEFAULT_UNDO_HISTORY_SIZE)),
+            None => size,
+        })
+    }
+}
+
+fn parse_cidrs(blocks: &[String]) -> Result<Vec<Cidr>> {
+    blocks
+        .iter()
+        .map(|block| block.parse().map_err(|e| anyhow!("{e}")))
+        .collect()
+}
+
+/// The reloadable settings the command line gives a server, which the
+/// config file overrides. `None` in other modes.
+pub fn flag_settings(mode: &Mode) -> Option<Reload> {
+    let Mode::Server {
+        tokens,
+        access,
+        connect_limit,
+        auth_limit,
+        limits,
+        ..
+    } = mode
+    else {
+        return None;
+    };
+    Some(Reload {
+        tokens: tokens
+            .as_ref()
+            .map(ghostwriter_server::auth::TokenStore::new),
+        access: access.clone(),
+        connect_limit: connect_limit.unwrap_or(DEFAULT_CONNECT_LIMIT),
+        auth_limit: auth_limit.unwrap_or(DEFAULT_AUTH_LIMIT),
+        write_limits: *limits,
+    })
+}
+
+/// Read `path` over `flags` and switch to its log level. The settings are
+/// for [`AcceptorConfig::reload`](ghostwriter_server::acceptor::AcceptorConfig::reload).
+pub fn load(path: &Path, flags: &Reload) -> Result<Reload> {
+    let file = ConfigFile::load(path)?;
+    let settings = file.apply(flags)?;
+    set_log_level(file.log_level.as_deref())?;
+    Ok(settings)
+}
+
+/// The keymap `path` gives, or the default one without a config file.
+pub fn load_keymap(path: Option<&Path>) -> Result<Keymap> {
+    match path {
+        Some(path) => ConfigFile::load(path)?
+            .keymap()
+            .map_err(|e| anyhow!("{}: {e}", path.display())),
+        None => Ok(Keymap::default()),
+    }
+}
+
+/// The status layout `path` gives, or the default one without a config
+/// file.
+pub fn load_status_layout(path: Option<&Path>) -> Result<StatusLayout> {
+    match path {
+        Some(path) => Ok(ConfigFile::load(path)?.status_layout()),
+        None => Ok(StatusLayout::default()),
+    }
+}
+
+/// Read `path` again on every SIGHUP and send the settings to `reload`.
+/// A file that fails to load is reported and leaves the current settings
+/// in place. SIGHUP is caught from the call on; spawn the returned future
+/// to act on it.
+#[cfg(unix)]
+pub fn reload_on_hangup(
+    path: PathBuf,
+    flags: Reload,
+    reload: tokio::sync::watch::Sender<Reload>,
+) -> Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    Ok(async move {
+        while hangups.recv().await.is_some() {
+            match load(&path, &flags) {
+                Ok(settings) => {
+                    tracing::info!(path = %path.display(), "config reloaded");
+                    reload.send_replace(settings);
+                }
+                Err(e) => tracing::error!("keeping the current settings: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use ghostwriter_server::access::AccessList;
+    use ghostwriter_server::quota::WriteLimits;
+    use ghostwriter_server::ratelimit::RateLimit;
+
+    fn flags() -> Reload {
+        Reload {
+            tokens: None,
+            access: AccessList::default(),
+            connect_limit: DEFAULT_CONNECT_LIMIT,
+            auth_limit: DEFAULT_AUTH_LIMIT,
+            write_limits: WriteLimits {
+                max_file_size: Some(1024),
+                ..WriteLimits::default()
+            },
+        }
+    }
+
+    #[test]
+    fn overrides_flags_with_the_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            log-level = "debug"
+            deny = ["10.0.0.0/8"]
+            connect-limit = "10/1m"
+            write-quota = "1M"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.log_level.as_deref(), Some("debug"));
+        let settings = file.apply(&flags()).unwrap();
+        assert_eq!(settings.access.deny, ["10.0.0.0/8".parse().unwrap()]);
+        assert!(settings.access.allow.is_empty());
+        assert_eq!(
+            settings.connect_limit,
+            RateLimit::new(10, Duration::from_secs(60))
+        );
+        assert_eq!(settings.auth_limit, DEFAULT_AUTH_LIMIT);
+        assert_eq!(
+            settings.write_limits,
+            WriteLimits {
+                max_file_size: Some(1024),
+                connection_quota: Some(1 << 20),
+                workspace_cap: None,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghostwriter.toml");
+        assert!(ConfigFile::load(&path).is_err());
+        std::fs::write(&path, "max-clients = 3\n").unwrap();
+        assert!(ConfigFile::load(&path).is_err());
+        std::fs::write(&path, "auth-limit = \"often\"\n").unwrap();
+        let file = ConfigFile::load(&path).unwrap();
+        assert!(file.apply(&flags()).is_err());
+        std::fs::write(&path, "allow = [\"10.0.0.0/99\"]\n").unwrap();
+        assert!(load(&path, &flags()).is_err());
+    }
+
+    #[test]
+    fn reads_key_bindings() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [keys]
+            quit = "ctrl+w"
+            find-next = ["f3", "ctrl+n"]
+            "#,
+        )
+        .unwrap();
+        let dump = file.keymap().unwrap().to_string();
+        assert!(dump.contains("quit = [\"ctrl+w\"]\n"), "{dump}");
+        assert!(
+            dump.contains("find-next = [\"f3\", \"ctrl+n\"]\n"),
+            "{dump}"
+        );
+        let file: ConfigFile = toml::from_str("[keys]\nquit = \"ctrl+s\"\n").unwrap();
+        let err = file.keymap().unwrap_err().to_string();
+        assert_eq!(err, "[keys]: ctrl+s is bound to both save and quit");
+        assert!(toml::from_str::<ConfigFile>("[keys]\nquit = 3\n").is_err());
+    }
+
+    #[test]
+    fn reads_status_templates() {
+        let file: ConfigFile = toml::from_str("[status]\nleft = \"{path}\"\n").unwrap();
+        let layout = file.status_layout();
+        assert_eq!(layout.left, "{path}");
+        assert_eq!(layout.right, StatusLayout::default().right);
+        assert_eq!(
+            ConfigFile::default().status_layout(),
+            StatusLayout::default()
+        );
+        assert!(toml::from_str::<ConfigFile>("[status]\ncenter = \"\"\n").is_err());
+    }
+
+    #[test]
+    fn reads_undo_history() {
+        let file: ConfigFile = toml::from_str("undo-history-size = \"2M\"\n").unwrap();
+        assert_eq!(file.undo_history(None).unwrap(), Some(2 << 20));
+        let file: ConfigFile = toml::from_str("undo-history = false\n").unwrap();
+        assert_eq!(file.undo_history(Some(100)).unwrap(), None);
+        let file: ConfigFile = toml::from_str("undo-history = true\n").unwrap();
+        assert_eq!(file.undo_history(Some(100)).unwrap(), Some(100));
+        let default = Some(DEFAULT_UNDO_HISTORY_SIZE);
+        assert_eq!(file.undo_history(None).unwrap(), default);
+        let file: ConfigFile = toml::from_str("undo-history-size = \"lots\"\n").unwrap();
+        assert!(file.undo_history(None).is_err());
+    }
+
+    #[test]
+    fn reads_log_rotation() {
+        let file: ConfigFile = toml::from_str("log-max-size = \"1M\"\n").unwrap();
+        let rotation = file.log_rotation(LogRotation::default()).unwrap();
+        assert_eq!(rotation.max_size, Some(1 << 20));
+        assert_eq!(rotation.keep, crate::logfile::DEFAULT_LOG_KEEP);
+        let file: ConfigFile = toml::from_str("log-keep = 2\n").unwrap();
+        let flags = LogRotation {
+            max_size: Some(100),
+            keep: 9,
+        };
+        let rotation = file.log_rotation(flags).unwrap();
+        assert_eq!((rotation.max_size, rotation.keep), (Some(100), 2));
+    }
+}
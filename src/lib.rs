@@ -1 +1,4 @@
 pub mod cli;
+pub mod config;
+pub mod logfile;
+pub mod server;
@@ -0,0 +1,148 @@
+//! Log files rotated by size, for servers that run for a long time: once
+//! `server.log` would grow past the limit it becomes `server.log.1`, older
+//! files move up one number, and those past the retention count are
+//! deleted.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Rotated files kept unless configured otherwise.
+pub const DEFAULT_LOG_KEEP: usize = 5;
+
+/// When to rotate a log file and how many old ones to keep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogRotation {
+    /// Size a file may reach before it is rotated; `None` never rotates.
+    pub max_size: Option<u64>,
+    /// Rotated files kept next to the current one.
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size: None,
+            keep: DEFAULT_LOG_KEEP,
+        }
+    }
+}
+
+/// A log file appended to and rotated as [`LogRotation`] says.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    /// Bytes in `file`.
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open<P: Into<PathBuf>>(path: P, rotation: LogRotation) -> io::Result<Self> {
+        let path = path.into();
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+        })
+    }
+
+    /// Shift the rotated files up, dropping the oldest, move the current
+    /// file to `.1` and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        match fs::remove_file(numbered(&self.path, keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..keep).rev() {
+            match fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if keep > 0 {
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let full = (self.rotation.max_size)
+            .is_some_and(|max| self.size > 0 && self.size + buf.len() as u64 > max);
+        if full {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `path` with `.n` appended, as in `server.log.2`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_a_few() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        fs::write(&path, "old\n").unwrap();
+        let rotation = LogRotation {
+            max_size: Some(10),
+            keep: 2,
+        };
+        let mut log = RotatingFile::open(&path, rotation).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |n| fs::read_to_string(numbered(&path, n)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+        assert_eq!(read(1), "two\nthree\n");
+        assert_eq!(read(2), "old\none\n");
+        assert!(!numbered(&path, 3).exists());
+    }
+
+    #[test]
+    fn writes_oversized_lines_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        let rotation = LogRotation {
+            max_size: Some(4),
+            keep: 0,
+        };
+        let mut log = RotatingFile::open(&path, rotation).unwrap();
+        log.write_all(b"a long line\n").unwrap();
+        log.write_all(b"next\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next\n");
+        assert!(!numbered(&path, 1).exists());
+    }
+}
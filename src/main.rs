@@ -1,6 +1,5 @@
 use anyhow::Result;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    ghostwriter::cli::run().await
+fn main() -> Result<()> {
+    ghostwriter::cli::run()
 }
@@ -0,0 +1,360 @@
+//! `--server`: serve the workspace the flags describe until Ctrl-C or
+//! SIGTERM.
+
+use anyhow::{Result, anyhow};
+use ghostwriter_server::acceptor::{self, AcceptorConfig};
+use ghostwriter_server::audit::FileAudit;
+use ghostwriter_server::auth;
+use ghostwriter_server::listen;
+use ghostwriter_server::metrics::{self, Metrics};
+#[cfg(unix)]
+use ghostwriter_server::privileges::RunAs;
+use ghostwriter_server::ratelimit::RateLimiter;
+#[cfg(target_os = "linux")]
+use ghostwriter_server::sandbox::Sandbox;
+use ghostwriter_server::shutdown_signal;
+use ghostwriter_server::workspace::{RootAccess, Workspace};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::cli::{Args, Mode};
+use crate::config;
+
+/// Serve `mode`, which must be [`Mode::Server`], letting in clients that
+/// authenticate with `secret` when one is given.
+pub async fn run(mode: Mode, args: &Args, secret: Option<String>) -> Result<()> {
+    let flags = config::flag_settings(&mode).ok_or_else(|| anyhow!("not a server mode"))?;
+    let Mode::Server {
+        root,
+        tls,
+        client_ca,
+        file_audit_log,
+        metrics_addr,
+        readonly,
+        workspaces,
+        workspace_keys,
+        readonly_workspaces,
+        config,
+        mdns,
+        bind,
+        quic_bind,
+        ssh_socket,
+        user,
+        group,
+        ..
+    } = mode
+    else {
+        unreachable!("flag_settings is only Some for Mode::Server");
+    };
+    tracing::info!(root = %root.display(), "mode = server");
+    let shutdown = shutdown_signal();
+    let settings = match &config {
+        Some(path) => config::load(path, &flags)?,
+        None => flags.clone(),
+    };
+    let (reload, reloaded) = watch::channel(settings.clone());
+    // Unhandled, SIGHUP would end the process, as it does a daemon's
+    // whenever someone asks it to reload.
+    #[cfg(unix)]
+    match config {
+        Some(path) => tokio::spawn(config::reload_on_hangup(path, flags, reload)?),
+        None => tokio::spawn(ignore_hangups()?),
+    };
+    #[cfg(not(unix))]
+    drop(reload);
+    let workspace = workspace(&root, workspaces, workspace_keys, &readonly_workspaces)?;
+    let file_audit = match &file_audit_log {
+        Some(path) => {
+            FileAudit::open(path).map_err(|e| anyhow!("cannot open {}: {e}", path.display()))?
+        }
+        None => FileAudit::default(),
+    };
+    let acceptor = AcceptorConfig {
+        file_audit,
+        metrics: Metrics::default(),
+        tokens: settings.tokens,
+        access: settings.access,
+        connect_limit: RateLimiter::new(settings.connect_limit),
+        auth_limit: RateLimiter::new(settings.auth_limit),
+        write_limits: settings.write_limits,
+        readonly,
+        undo_history: args.undo_history()?,
+        snippets: Arc::new(args.snippets()?),
+        reload: Some(reloaded),
+        advertise: mdns,
+        ..AcceptorConfig::default()
+    };
+    let secret_hash = secret.as_deref().map(auth::hash_secret).transpose()?;
+    // Keys are often only readable by root, so load them before `--user`
+    // takes effect.
+    #[cfg(feature = "quic")]
+    let quic = match (quic_bind, &tls) {
+        (Some(addr), Some(tls)) => Some(
+            ghostwriter_core::quic::server_endpoint_pem(addr, &tls.cert, &tls.key)
+                .map_err(|e| anyhow!("cannot serve QUIC on {addr}: {e}"))?,
+        ),
+        _ => None,
+    };
+    // `Args::mode` refuses `--quic-bind` without the feature.
+    #[cfg(not(feature = "quic"))]
+    let _ = quic_bind;
+    let tls = match tls {
+        Some(tls) => Some(ghostwriter_core::tls::acceptor(
+            &tls.cert,
+            &tls.key,
+            client_ca.as_deref(),
+        )?),
+        None => None,
+    };
+    let listeners = listen::bind_all(&bind)?;
+    #[cfg(unix)]
+    let ssh_socket = ssh_socket.then(|| bind_ssh_socket(&root)).transpose()?;
+    // `Args::mode` refuses `--ssh-socket` elsewhere.
+    #[cfg(not(unix))]
+    let _ = ssh_socket;
+    if let Some(addr) = metrics_addr {
+        let listener = (TcpListener::bind(addr).await)
+            .map_err(|e| anyhow!("cannot serve metrics on {addr}: {e}"))?;
+        tracing::info!(addr = %listener.local_addr()?, "serving metrics");
+        tokio::spawn(metrics::serve(listener, acceptor.metrics.clone()));
+    }
+    #[cfg(unix)]
+    {
+        let run_as = RunAs { user, group };
+        if !run_as.is_empty() {
+            (run_as.apply()).map_err(|e| anyhow!("cannot switch user: {e}"))?;
+            tracing::info!(user = ?run_as.user, group = ?run_as.group, "switched user");
+        }
+    }
+
+    // Each acceptor takes one kind of listener; they share the limits and
+    // stop together.
+    let (stop, stopped) = watch::channel(());
+    let until_stopped = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.changed().await;
+        }
+    };
+    let mut servers = JoinSet::new();
+    match tls {
+        None => {
+            servers.spawn(acceptor::run_tcp_all_until(
+                listeners,
+                workspace.clone(),
+                secret_hash.clone(),
+                acceptor.clone(),
+                until_stopped(),
+            ));
+        }
+        Some(tls) => {
+            for listener in listeners {
+                servers.spawn(acceptor::run_tls_until(
+                    listener,
+                    tls.clone(),
+                    workspace.clone(),
+                    secret_hash.clone(),
+                    acceptor.clone(),
+                    until_stopped(),
+                ));
+            }
+        }
+    }
+    #[cfg(unix)]
+    let socket = match ssh_socket {
+        Some((listener, path)) => {
+            servers.spawn(acceptor::run_uds_until(
+                listener,
+                workspace.clone(),
+                secret_hash.clone(),
+                acceptor.clone(),
+                until_stopped(),
+            ));
+            Some(path)
+        }
+        None => None,
+    };
+    #[cfg(feature = "quic")]
+    if let Some(endpoint) = quic {
+        servers.spawn(acceptor::run_quic_until(
+            endpoint,
+            workspace,
+            secret_hash,
+            acceptor,
+            until_stopped(),
+        ));
+    }
+    tokio::select! {
+        _ = shutdown => {}
+        Some(served) = servers.join_next() => served??,
+    }
+    stop.send_replace(());
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
+    #[cfg(unix)]
+    if let Some(path) = socket
+        && let Err(e) = std::fs::remove_file(&path)
+    {
+        tracing::warn!(path = %path.display(), error = %e, "cannot remove the socket");
+    }
+    Ok(())
+}
+
+/// Listen on the socket in `root` that `ssh://` tunnels lead to, in place
+/// of one a server that is gone left behind. Only this user may connect.
+#[cfg(unix)]
+fn bind_ssh_socket(root: &Path) -> Result<(tokio::net::UnixListener, PathBuf)> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let path = root.join(ghostwriter_client::ssh::SOCKET_NAME);
+    if let Ok(meta) = std::fs::symlink_metadata(&path) {
+        if !meta.file_type().is_socket() {
+            return Err(anyhow!("{} is not a socket", path.display()));
+        }
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(anyhow!("another server listens on {}", path.display()));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = (tokio::net::UnixListener::bind(&path))
+        .map_err(|e| anyhow!("cannot listen on {}: {e}", path.display()))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    tracing::info!(path = %path.display(), "listening");
+    Ok((listener, path))
+}
+
+/// Confine the process to the workspaces of `mode` and the files the
+/// flags name, failing if the kernel refuses. Call before the runtime
+/// starts its threads; see [`Sandbox::enter`].
+#[cfg(target_os = "linux")]
+pub fn enter_sandbox(mode: &Mode, args: &Args) -> Result<()> {
+    let Mode::Server {
+        root,
+        tls,
+        client_ca,
+        tokens,
+        file_audit_log,
+        workspaces,
+        workspace_keys,
+        config,
+        user,
+        group,
+        ..
+    } = mode
+    else {
+        return Ok(());
+    };
+    let mut sandbox = Sandbox::new(root);
+    for (_, dir) in workspaces {
+        sandbox = sandbox.allow_write(dir);
+    }
+    // Logs are rotated and the PID file removed, so their directories
+    // stay writable.
+    for file in [file_audit_log, &args.log_file, &args.pid_file]
+        .into_iter()
+        .flatten()
+    {
+        sandbox = sandbox.allow_write(parent(file));
+    }
+    if let Some(dir) = args.undo_history()?.dir {
+        std::fs::create_dir_all(&dir)?;
+        sandbox = sandbox.allow_write(dir);
+    }
+    let snippets = args.snippets_dir()?;
+    let read = (tls.iter())
+        .flat_map(|tls| [&tls.cert, &tls.key])
+        .chain(client_ca)
+        .chain(tokens)
+        .chain(config)
+        .chain(workspace_keys.iter().map(|(_, file)| file))
+        .chain(&snippets);
+    // `--user` and `--group` are looked up once the listeners are bound.
+    let users = (user.is_some() || group.is_some())
+        .then(|| ["/etc/passwd", "/etc/group", "/etc/nsswitch.conf"].map(Path::new))
+        .into_iter()
+        .flatten()
+        .filter(|path| path.exists());
+    for path in users {
+        sandbox = sandbox.allow_read(path);
+    }
+    for path in read {
+        // A token file need not exist yet; it may appear beside the path.
+        // Neither existing, there is nothing to read.
+        if path.exists() {
+            sandbox = sandbox.allow_read(path);
+        } else if parent(path).exists() {
+            sandbox = sandbox.allow_read(parent(path));
+        }
+    }
+    let landlocked = (sandbox.enter()).map_err(|e| anyhow!("cannot enter the sandbox: {e}"))?;
+    if !landlocked {
+        tracing::warn!("Landlock is not available; only the seccomp filter applies");
+    }
+    Ok(())
+}
+
+/// The directory holding `file`.
+#[cfg(target_os = "linux")]
+fn parent(file: &Path) -> &Path {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Log SIGHUPs instead of letting them end the server, as there is no
+/// config file to reload.
+#[cfg(unix)]
+fn ignore_hangups() -> Result<impl std::future::Future<Output = ()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    Ok(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!("SIGHUP without --config; nothing to reload");
+        }
+    })
+}
+
+/// The workspace of `root` alone or, with further named roots, of all of
+/// them with `root` named after its last component.
+fn workspace(
+    root: &Path,
+    workspaces: Vec<(String, PathBuf)>,
+    keys: Vec<(String, PathBuf)>,
+    readonly: &[String],
+) -> Result<Workspace> {
+    if workspaces.is_empty() {
+        if let Some((name, _)) = keys.first() {
+            return Err(anyhow!("--workspace-key {name}=… needs --workspace"));
+        }
+        if let Some(name) = readonly.first() {
+            return Err(anyhow!("--workspace-readonly {name} needs --workspace"));
+        }
+        return Ok(Workspace::new(root)?);
+    }
+    let canonical = root.canonicalize()?;
+    let name = (canonical.file_name())
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("cannot name workspace {}", root.display()))?
+        .to_string();
+    let mut access: BTreeMap<String, RootAccess> = BTreeMap::new();
+    for (name, file) in keys {
+        let secret_hash = auth::load_hash(&file)?
+            .ok_or_else(|| anyhow!("no secret hash in {}", file.display()))?;
+        access.entry(name).or_default().secret_hash = Some(secret_hash);
+    }
+    for name in readonly {
+        access.entry(name.clone()).or_default().readonly = true;
+    }
+    let mut workspace = Workspace::named(std::iter::once((name, canonical)).chain(workspaces))?;
+    for (name, access) in access {
+        workspace = workspace.with_root_access(&name, access)?;
+    }
+    Ok(workspace)
+}
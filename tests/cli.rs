@@ -1,5 +1,10 @@
 use assert_cmd::Command;
 use predicates::prelude::*;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::time::Duration;
 
 #[test]
 fn shows_help() {
@@ -20,3 +25,348 @@ fn shows_version() {
         .success()
         .stdout(predicate::str::contains(env!("CARGO_PKG_VERSION")));
 }
+
+/// Start `--server` on `dir` on a free port with further `args`.
+#[cfg(unix)]
+fn spawn_server(dir: &Path, args: &[&OsStr]) -> Child {
+    std::process::Command::new(assert_cmd::cargo::cargo_bin("ghostwriter"))
+        .arg("--server")
+        .arg(dir)
+        .args(["--bind", "127.0.0.1:0"])
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Stop the process `pid` as a service manager would.
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    signal(pid, "TERM");
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, name: &str) {
+    let sent = std::process::Command::new("kill")
+        .args([&format!("-{name}"), &pid.to_string()])
+        .status()
+        .unwrap();
+    assert!(sent.success());
+}
+
+/// What the log file at `path` holds once it tells where the server
+/// listens.
+fn wait_for_listening(path: &Path) -> String {
+    let mut logged = String::new();
+    for _ in 0..200 {
+        logged = std::fs::read_to_string(path).unwrap_or_default();
+        if logged.contains("listening") {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    logged
+}
+
+#[cfg(unix)]
+#[test]
+fn serves_until_terminated() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut server = spawn_server(dir.path(), &["--log-format".as_ref(), "json".as_ref()]);
+    let mut stdout = BufReader::new(server.stdout.take().unwrap());
+    let mut logged = String::new();
+    while !logged.contains("listening") {
+        assert_ne!(stdout.read_line(&mut logged).unwrap(), 0, "{logged}");
+    }
+    assert!(logged.contains(r#""level":"INFO""#), "{logged}");
+    assert!(logged.contains(r#""message":"mode = server""#), "{logged}");
+    // A connection without a WebSocket handshake leaves the server running.
+    let url = logged.split(r#""url":"ws://"#).nth(1).unwrap();
+    let addr = &url[..url.find('"').unwrap()];
+    drop(std::net::TcpStream::connect(addr).unwrap());
+
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn serves_the_ssh_socket() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    let args = [
+        "--ssh-socket".as_ref(),
+        "--log-file".as_ref(),
+        log.as_os_str(),
+    ];
+    let mut server = spawn_server(dir.path(), &args);
+    let logged = wait_for_listening(&log);
+    let socket = dir.path().join(".ghostwriter.sock");
+    assert!(logged.contains(&socket.display().to_string()), "{logged}");
+
+    let mut client = UnixStream::connect(&socket).unwrap();
+    client
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+              Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = [0; 12];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 101");
+    drop(client);
+
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+    assert!(!socket.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn serves_metrics() {
+    use std::io::{Read, Write};
+
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    let mut server = spawn_server(
+        dir.path(),
+        &[
+            "--metrics-addr".as_ref(),
+            "127.0.0.1:0".as_ref(),
+            "--log-file".as_ref(),
+            log.as_os_str(),
+        ],
+    );
+    let logged = wait_for_listening(&log);
+    let line = logged
+        .lines()
+        .find(|l| l.contains("serving metrics"))
+        .unwrap();
+    let addr = line.rsplit("addr=").next().unwrap().trim();
+    let mut scrape = std::net::TcpStream::connect(addr).unwrap();
+    scrape.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    scrape.read_to_string(&mut response).unwrap();
+    assert!(response.contains("ghostwriter_connections 0"), "{response}");
+
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn reloads_the_config_on_hangup() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    let config = dir.path().join("ghostwriter.toml");
+    std::fs::write(&config, "deny = []\n").unwrap();
+    let mut server = spawn_server(
+        dir.path(),
+        &[
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--log-file".as_ref(),
+            log.as_os_str(),
+        ],
+    );
+    wait_for_listening(&log);
+    std::fs::write(&config, "deny = [\"127.0.0.0/8\"]\n").unwrap();
+    signal(server.id(), "HUP");
+    let mut logged = String::new();
+    for _ in 0..100 {
+        logged = std::fs::read_to_string(&log).unwrap();
+        if logged.contains("settings reloaded") {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(logged.contains("config reloaded"), "{logged}");
+    assert!(logged.contains("settings reloaded"), "{logged}");
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn serves_sandboxed() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    let mut server = spawn_server(
+        dir.path(),
+        &[
+            "--sandbox".as_ref(),
+            "--no-undo-history".as_ref(),
+            "--log-file".as_ref(),
+            log.as_os_str(),
+        ],
+    );
+    let logged = wait_for_listening(&log);
+    assert!(logged.contains("listening"), "{logged}");
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn fails_to_switch_to_an_unknown_user() {
+    let dir = tempfile::tempdir().unwrap();
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--server")
+        .arg(dir.path())
+        .args(["--bind", "127.0.0.1:0", "--user", "no-such-user-here"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot switch user"));
+}
+
+#[test]
+fn fails_without_the_workspace() {
+    let dir = tempfile::tempdir().unwrap();
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--server")
+        .arg(dir.path().join("missing"))
+        .assert()
+        .failure();
+}
+
+#[cfg(unix)]
+#[test]
+fn daemonizes_with_log_and_pid_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    let pid = dir.path().join("server.pid");
+    // The foreground process exits as soon as the daemon has detached.
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--server")
+        .arg(dir.path())
+        .args(["--bind", "127.0.0.1:0"])
+        .arg("--daemon")
+        .arg("--pid-file")
+        .arg(&pid)
+        .arg("--log-file")
+        .arg(&log)
+        .assert()
+        .success()
+        .stdout("");
+
+    let logged = wait_for_listening(&log);
+    assert!(logged.contains("mode = server"), "{logged}");
+    assert!(!logged.contains('\u{1b}'), "log file has colour codes");
+    let daemon = std::fs::read_to_string(&pid)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    signal(daemon, "HUP");
+    for _ in 0..100 {
+        if std::fs::read_to_string(&log)
+            .unwrap()
+            .contains("nothing to reload")
+        {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let logged = std::fs::read_to_string(&log).unwrap();
+    assert!(logged.contains("nothing to reload"), "{logged}");
+    assert!(pid.exists(), "SIGHUP leaves the daemon running");
+    terminate(daemon);
+    for _ in 0..100 {
+        if !pid.exists() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!pid.exists(), "PID file is removed on exit");
+}
+
+#[cfg(feature = "schema")]
+#[test]
+fn prints_proto_schema() {
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("proto-schema")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"$defs\""))
+        .stdout(predicate::str::contains("\"FrameDiff\""));
+}
+
+#[cfg(not(feature = "schema"))]
+#[test]
+fn proto_schema_requires_feature() {
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("proto-schema")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--features schema"));
+}
+
+#[cfg(unix)]
+#[test]
+fn rotates_the_log_file_by_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("server.log");
+    std::fs::write(&log, "earlier\n".repeat(200)).unwrap();
+    let config = dir.path().join("ghostwriter.toml");
+    std::fs::write(&config, "log-max-size = \"1K\"\nlog-keep = 1\n").unwrap();
+    let mut server = spawn_server(
+        dir.path(),
+        &[
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--log-file".as_ref(),
+            log.as_os_str(),
+        ],
+    );
+    let current = wait_for_listening(&log);
+    terminate(server.id());
+    assert!(server.wait().unwrap().success());
+
+    let rotated = std::fs::read_to_string(dir.path().join("server.log.1")).unwrap();
+    assert!(rotated.starts_with("earlier\n"), "{rotated}");
+    assert!(current.contains("mode = server"), "{current}");
+    assert!(!dir.path().join("server.log.2").exists());
+}
+
+#[test]
+fn dumps_key_bindings() {
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--dump-keys")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("[keys]\nsave = [\"ctrl+s\"]\n"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("ghostwriter.toml");
+    std::fs::write(&config, "[keys]\nquit = \"ctrl+w\"\n").unwrap();
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--dump-keys")
+        .arg("--config")
+        .arg(&config)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("quit = [\"ctrl+w\"]\n"));
+
+    std::fs::write(&config, "[keys]\nquit = \"ctrl+s\"\n").unwrap();
+    Command::cargo_bin("ghostwriter")
+        .unwrap()
+        .arg("--dump-keys")
+        .arg("--config")
+        .arg(&config)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "ctrl+s is bound to both save and quit",
+        ));
+}